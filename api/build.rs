@@ -5,6 +5,7 @@ fn main() {
     let dest_path = Path::new(&out_dir).join("concat.rs");
 
     let combinations = [
+        (1, 2),
         (1, 8),
         (1, 9),
         (2, 1),
@@ -26,6 +27,48 @@ fn main() {
         (124, 1),
         (125, 1),
         (126, 1),
+        (127, 8),
+        (135, 1),
+        (136, 1),
+        (137, 1),
+        (138, 2),
+        (140, 9),
+        (149, 1),
+        (150, 10),
+        (160, 1),
+        (161, 1),
+        (162, 6),
+        (168, 1),
+        (169, 8),
+        (177, 9),
+        (186, 1),
+        (187, 8),
+        (195, 8),
+        (203, 10),
+        (213, 10),
+        (223, 2),
+        (225, 2),
+        (227, 4),
+        (231, 1),
+        (232, 9),
+        (241, 9),
+        (250, 2),
+        (252, 10),
+        (262, 3),
+        (265, 1),
+        (266, 1),
+        (267, 1),
+        (268, 1),
+        (269, 1),
+        (270, 1),
+        (271, 1),
+        (272, 1),
+        (273, 1),
+        (274, 1),
+        (275, 2),
+        (277, 4),
+        (281, 1),
+        (282, 1),
     ];
 
     let mut code = String::new();
@@ -21,7 +21,8 @@ pub struct BootloaderConfig {
     ///
     /// The bootloader starts the kernel with a valid stack pointer. This setting defines
     /// the stack size that the bootloader should allocate and map. The stack is created
-    /// with a guard page, so a stack overflow will lead to a page fault.
+    /// with an unmapped guard page below it, so a stack overflow will lead to a page fault.
+    /// The bounds of the stack are reported in [`BootInfo::kernel_stack`](crate::BootInfo::kernel_stack).
     pub kernel_stack_size: u64,
 
     /// Configuration for the frame buffer that can be used by the kernel to display pixels
@@ -30,6 +31,11 @@ pub struct BootloaderConfig {
 
     /// Configuration for changing the level of the filter of the messages that are shown in the
     /// screen when booting. The default is 'Trace'.
+    ///
+    /// `Info` shows the boot progress, `Debug` adds the individual kernel segments, and `Trace`
+    /// adds dumps of the firmware memory map, the program headers of the kernel, the
+    /// relocation tables, and the memory map that is passed to the kernel. Set it to `Warn` or
+    /// `Error` for quiet boots, or to `Off` to disable the bootloader's log output completely.
     pub log_level: LevelFilter,
 
     /// Whether the bootloader should print log messages to the framebuffer when booting.
@@ -39,8 +45,342 @@ pub struct BootloaderConfig {
 
     /// Whether the bootloader should print log messages to the serial port when booting.
     ///
-    /// Enabled by default.
+    /// The port and its baud rate are set through [`Self::serial_port`] and
+    /// [`Self::serial_baud_rate`]. Enabled by default.
     pub serial_logger_status: LoggerStatus,
+
+    /// The size of the heap that the bootloader uses for its own temporary allocations (in
+    /// bytes).
+    ///
+    /// The UEFI bootloader allocates the firmware memory map and the boot device path from this
+    /// heap. The peak usage is printed to the boot log and reported in the
+    /// [`BootInfo`](crate::BootInfo), so it can be used to size this setting. Defaults to
+    /// 128kiB.
+    pub bootloader_heap_size: u64,
+
+    /// Whether the error screen that is shown on boot failures should include a QR code.
+    ///
+    /// The QR code encodes the error code and description, which makes it easy to report
+    /// errors from machines without a serial console. Disabled by default.
+    pub error_qr_code: bool,
+
+    /// The language of the messages on the error screen.
+    ///
+    /// Defaults to [`Language::English`].
+    pub language: Language,
+
+    /// Whether the bootloader should render its messages in high contrast and with a larger
+    /// font.
+    ///
+    /// Applies to the boot log and the error screen on the framebuffer. On UEFI systems,
+    /// this setting can also be toggled by pressing F1 while the bootloader loads the kernel.
+    /// Disabled by default.
+    pub accessible_display: bool,
+
+    /// The number of consecutive failed boots after which the bootloader starts the recovery
+    /// kernel instead of the regular kernel.
+    ///
+    /// If set, the UEFI bootloader counts boot attempts in the non-volatile
+    /// `BootloaderFailedBoots` EFI variable. The kernel is expected to reset the variable to `0`
+    /// once the system booted successfully. The current count is reported in the
+    /// [`BootInfo`](crate::BootInfo). The BIOS bootloader has no persistent storage and ignores
+    /// this setting. Defaults to `None`.
+    pub boot_failure_limit: Option<u8>,
+
+    /// The value that the bootloader should write to the `IA32_SPEC_CTRL` MSR before
+    /// entering the kernel.
+    ///
+    /// This allows security-focused kernels to start with speculation mitigations such as
+    /// IBRS (bit 0), STIBP (bit 1), or SSBD (bit 2) enabled. The value is ignored with a
+    /// warning if the CPU doesn't support the MSR. The resulting state is reported in
+    /// [`BootInfo::cpu_state`](crate::BootInfo::cpu_state). Defaults to `None`, which keeps
+    /// the firmware setting.
+    pub spec_ctrl: Option<u64>,
+
+    /// Whether the bootloader should issue an indirect branch prediction barrier (IBPB)
+    /// through the `IA32_PRED_CMD` MSR before entering the kernel.
+    ///
+    /// Disabled by default.
+    pub indirect_branch_barrier: bool,
+
+    /// Specifies where the register sets of the IOMMUs should be mapped in virtual memory.
+    ///
+    /// The register sets of all units are mapped next to each other, starting at the given
+    /// address. Their addresses are reported in
+    /// [`BootInfo::iommus`](crate::BootInfo::iommus). Defaults to `None`, i.e. the registers
+    /// are not mapped.
+    pub iommu_registers: Option<Mapping>,
+
+    /// Whether the bootloader should print log messages to a virtio console device when
+    /// booting.
+    ///
+    /// This is useful in virtual machines without an emulated serial port. The device is
+    /// detected by probing the PCI bus, so nothing is logged if there is no virtio console.
+    /// Since the probe scans the whole bus, this is disabled by default.
+    pub virtio_console_logger_status: LoggerStatus,
+
+    /// Whether the bootloader should print log messages to the debug console of Bochs and
+    /// QEMU (I/O port `0xE9`) when booting.
+    ///
+    /// Disabled by default.
+    pub debugcon_logger_status: LoggerStatus,
+
+    /// The maximum log levels of the individual log outputs.
+    ///
+    /// Messages are only written to an output if their level passes both [`Self::log_level`]
+    /// and the level of the output. Defaults to `Trace` for all outputs. The levels can be
+    /// replaced without rebuilding the kernel through the boot metadata block, see
+    /// [`BootMetadata::log_levels`](crate::info::BootMetadata::log_levels).
+    pub log_levels: LogLevels,
+
+    /// Whether the bootloader should keep its log messages in a memory buffer and pass them
+    /// to the kernel.
+    ///
+    /// The most recent 16kiB of the log are reported in
+    /// [`BootInfo::boot_log`](crate::BootInfo::boot_log). Disabled by default.
+    pub memory_logger_status: LoggerStatus,
+
+    /// The physical address above which the bootloader allocates the frames for the kernel
+    /// segments, page tables, stack, and boot info.
+    ///
+    /// Memory below this address is only used if there is not enough memory above it. This
+    /// keeps memory that is reachable by legacy DMA engines free for kernels with legacy
+    /// device drivers, e.g. set it to 4GiB to keep the memory for 32-bit DMA engines free.
+    /// Defaults to 16MiB, which keeps the memory for ISA DMA free.
+    pub min_frame_address: u64,
+
+    /// The physical address below which the ramdisk and the boot info must be placed.
+    ///
+    /// If set, the bootloader guarantees that the ramdisk and the boot info (including the
+    /// memory map) are each physically contiguous and end below this address, so that they
+    /// can be accessed by devices with limited DMA engines before the kernel sets up an
+    /// IOMMU. The disk image builder fails if the ramdisk can't fit below the limit, and the
+    /// bootloader fails with an error screen if the memory below the limit is in use.
+    /// Defaults to `None`, i.e. no limit.
+    pub dma_address_limit: Option<u64>,
+
+    /// The executable format of the kernel.
+    ///
+    /// Selects the loader that the bootloader uses to map the kernel into memory. Defaults to
+    /// [`PayloadFormat::Elf`].
+    pub payload_format: PayloadFormat,
+
+    /// The virtual address at which a [`PayloadFormat::FlatBinary`] kernel is mapped.
+    ///
+    /// Must be page-aligned. Ignored for other formats. Defaults to `0xffff_ffff_8000_0000`.
+    pub flat_binary_load_address: u64,
+
+    /// The offset of the entry point of a [`PayloadFormat::FlatBinary`] kernel from its start.
+    ///
+    /// Ignored for other formats. Defaults to `0`.
+    pub flat_binary_entry_offset: u64,
+
+    /// Specifies where the registers of the local APIC, the I/O APICs, and the HPET should be
+    /// mapped in virtual memory.
+    ///
+    /// The registers are mapped next to each other, starting at the given address, and
+    /// their addresses are reported in
+    /// [`BootInfo::platform_registers`](crate::BootInfo::platform_registers). Defaults to
+    /// `None`, i.e. the registers are not mapped.
+    pub platform_registers: Option<Mapping>,
+
+    /// Specifies where the memory regions of the UEFI runtime services should be mapped in
+    /// virtual memory.
+    ///
+    /// Each region is mapped at the given offset plus its physical address, so the kernel can
+    /// pass these addresses to `SetVirtualAddressMap`. Runtime code is mapped read-only and
+    /// runtime data non-executable, following the `EFI_MEMORY_ATTRIBUTES_TABLE` if the
+    /// firmware provides one (see [`BootInfo::uefi_runtime`](crate::BootInfo::uefi_runtime)).
+    /// Ignored when booting through BIOS. Defaults to `None`, i.e. the regions are not mapped.
+    pub uefi_runtime_services: Option<Mapping>,
+
+    /// The timeout of the UEFI watchdog timer in seconds while the bootloader loads files.
+    ///
+    /// The UEFI bootloader restarts the watchdog timer with this timeout before each file load
+    /// and after each chunk that it reads from a virtio disk, so only a stalled load resets the
+    /// machine. The timer is disabled while waiting for a kernel on the serial port and before
+    /// exiting the boot services. Since the config is only known after the kernel is loaded,
+    /// the kernel itself is always loaded with the default timeout. A value of `0` disables
+    /// the watchdog timer. Ignored when booting through BIOS. Defaults to `600`.
+    pub uefi_watchdog_timeout: u16,
+
+    /// The time in seconds during which the UEFI bootloader retries a failed TFTP transfer of
+    /// a boot file.
+    ///
+    /// If the transfer still fails when the deadline passes, the bootloader loads the file from
+    /// the first local file system that contains a copy of it, or reports a load deadline
+    /// error otherwise. Files that the server reports as missing are not retried. Like the
+    /// watchdog timeout, the deadline for the kernel itself is always the default. A value of
+    /// `0` disables the retries. Ignored when booting through BIOS. Defaults to `60`.
+    pub network_load_deadline: u16,
+
+    /// The security version of the kernel, which is compared against the minimum version
+    /// that the bootloader stores for [`Self::rollback_protection`].
+    ///
+    /// Increase this value whenever a kernel release fixes a vulnerability, so that older
+    /// kernels can no longer be booted. Defaults to `0`.
+    pub security_version: u32,
+
+    /// How the bootloader reacts to kernels whose [`Self::security_version`] is lower than the
+    /// stored minimum version.
+    ///
+    /// The UEFI bootloader stores the minimum version in the non-volatile
+    /// `BootloaderMinVersion` EFI variable, which is not accessible at runtime, and raises it
+    /// whenever it boots a kernel with a higher version. It also stores the strictest policy
+    /// that a kernel requested, so that an older kernel can't disable the protection. The
+    /// BIOS bootloader has no persistent storage and ignores this setting. Defaults to
+    /// [`RollbackProtection::Disabled`].
+    pub rollback_protection: RollbackProtection,
+
+    /// Specifies where the files of a ramdisk archive should be extracted to in virtual
+    /// memory.
+    ///
+    /// If set and the ramdisk is a cpio or tar archive, the bootloader copies every regular
+    /// file of the archive to its own page-aligned frames. The files are mapped read-only and
+    /// non-executable next to each other, starting at the given address, with an unmapped
+    /// guard page after each file. They are reported in
+    /// [`BootInfo::modules`](crate::BootInfo::modules), while the archive itself stays
+    /// mapped as the ramdisk. Defaults to `None`, i.e. the files are not extracted.
+    pub ramdisk_modules: Option<Mapping>,
+
+    /// The maximum number of `Info`, `Debug`, and `Trace` messages that the bootloader prints
+    /// to the framebuffer and to the serial port.
+    ///
+    /// Drawing text on a large framebuffer or sending it over a 9600-baud serial console can
+    /// take longer than the rest of the boot, so verbose logging is best limited on these
+    /// outputs. Once the limit is reached, further messages of these levels are dropped on
+    /// both outputs, while warnings and errors are still printed. The other outputs are not
+    /// limited. Before starting the kernel, the bootloader prints the number of dropped
+    /// messages to each output that dropped any. Defaults to `None`, i.e. no limit.
+    pub log_message_limit: Option<u16>,
+
+    /// Whether the bootloader should color the level of its log messages.
+    ///
+    /// Errors are shown in red, warnings in yellow, info messages in green, debug messages in
+    /// cyan, and trace messages in gray. The framebuffer draws the colors directly, while the
+    /// serial port, the virtio console, and the debug console receive ANSI escape codes. The
+    /// memory log stays uncolored. Disabled by default.
+    pub log_colors: bool,
+
+    /// Whether the bootloader should prefix its log messages with the time since the logger
+    /// was started, in seconds with millisecond precision.
+    ///
+    /// The time is measured through the time stamp counter, whose frequency is read from
+    /// CPUID or measured against the PIT when the logger starts. Messages have no timestamp if
+    /// the frequency can't be determined. Disabled by default.
+    pub log_timestamps: bool,
+
+    /// The format of the log messages on the serial port.
+    ///
+    /// With [`LogFormat::JsonLines`], test harnesses can parse the serial output of the
+    /// bootloader instead of matching on message texts. Defaults to [`LogFormat::Text`].
+    pub serial_log_format: LogFormat,
+
+    /// Whether the bootloader should load the kernel at a random virtual address.
+    ///
+    /// Unlike [`Mappings::aslr`], this only randomizes the kernel base and leaves the other
+    /// dynamic mappings (e.g. the boot info and the physical memory mapping) at their
+    /// deterministic addresses. The kernel has to be a position-independent executable, whose
+    /// relocations the bootloader applies after choosing the address; other kernels are
+    /// refused with boot error `E0102`. The random numbers are seeded from `RDSEED`,
+    /// `RDRAND`, and, when booting through UEFI, the `EFI_RNG_PROTOCOL` of the firmware.
+    /// Disabled by default.
+    pub kernel_aslr: bool,
+
+    /// Whether the bootloader should refuse kernels whose memory can't be mapped with
+    /// write-xor-execute permissions.
+    ///
+    /// The bootloader always maps ELF segments and PE sections with the permissions of their
+    /// headers, i.e. read-only unless writable and non-executable unless executable. By
+    /// default, segments that are both writable and executable are mapped as such with a
+    /// warning, and flat binaries, which have no headers, are mapped writable and executable.
+    /// If this option is set, such kernels are refused with boot error `E0105` instead.
+    /// Disabled by default during the transition period, later releases will enable it.
+    pub strict_segment_permissions: bool,
+
+    /// Whether the bootloader should record the firmware inputs of the boot for replaying it
+    /// on the host.
+    ///
+    /// The inputs (the memory map, the framebuffer location, the hashes of the loaded files,
+    /// the pressed keys, and the seed of the random number generator) are written as `input`
+    /// events to the outputs that use [`LogFormat::JsonLines`], e.g. the serial port with
+    /// [`serial_log_format`](Self::serial_log_format). Disabled by default.
+    pub record_inputs: bool,
+
+    /// Whether the bootloader should switch the UEFI runtime services to virtual addressing
+    /// by calling `SetVirtualAddressMap` right before jumping to the kernel.
+    ///
+    /// The runtime services are switched to the addresses at which
+    /// [`uefi_runtime_services`](Self::uefi_runtime_services) maps them, so that option must
+    /// be set too. The virtual address of the system table is then reported in
+    /// [`UefiRuntime::virtual_system_table`](crate::info::UefiRuntime::virtual_system_table),
+    /// and the kernel can call the runtime services through it, e.g. to access EFI variables.
+    /// Since `SetVirtualAddressMap` can only be called once, the kernel can't relocate the
+    /// runtime services afterwards. Ignored when booting through BIOS. Disabled by default.
+    pub set_uefi_virtual_address_map: bool,
+
+    /// Whether the bootloader should enable 5-level paging (`CR4.LA57`) for the kernel if the
+    /// CPU supports it.
+    ///
+    /// The mappings keep their addresses: the level 5 table points to the lower and the higher
+    /// half of the usual level 4 table. A [`Mapping::Dynamic`] physical memory mapping gets its
+    /// own level 5 entry instead, which covers 256TiB of physical memory at
+    /// `0xfffe_0000_0000_0000`. The kernel can check `CR4.LA57` to see whether 5-level paging
+    /// is active. Not supported together with a recursive page table mapping. Disabled by
+    /// default.
+    pub five_level_paging: bool,
+
+    /// Whether the bootloader should log a summary of the kernel environment right before
+    /// jumping to the kernel, and whether it should wait for a key press afterwards.
+    ///
+    /// The summary lists the entry point, the stack range, the physical memory offset, the
+    /// memory totals, the load offset of the kernel, and the framebuffer mode, which helps to
+    /// debug kernels that fail before they print anything. To only pause in debug builds of
+    /// the kernel, set it to `if cfg!(debug_assertions) { PreKernelSummary::Pause } else {
+    /// PreKernelSummary::Disabled }`. Defaults to [`PreKernelSummary::Disabled`].
+    pub pre_kernel_summary: PreKernelSummary,
+
+    /// Whether the bootloader should resume the system through the ACPI waking vector when it
+    /// wakes up from the S3 sleep state (suspend-to-RAM), instead of loading the kernel.
+    ///
+    /// The bootloader resumes if the `WAK_STS` bit of the PM1 status register is set and the
+    /// kernel stored a waking vector in the FACS before it suspended the system. Only the
+    /// 64-bit waking vector is supported, i.e. the kernel must set the `64BIT_WAKE_F` OSPM
+    /// flag of the FACS. The vector is called in long mode with interrupts disabled and the
+    /// identity-mapped page table of the bootloader. Disabled by default.
+    pub acpi_s3_resume: bool,
+
+    /// The I/O port of the 16550 UART that the bootloader logs to, e.g. `0x2F8` for `COM2`.
+    ///
+    /// The port is used for the log output of the BIOS and UEFI bootloaders, the error
+    /// report, and the keyboard input of the error screen over the serial console. Defaults
+    /// to `0x3F8` (`COM1`).
+    pub serial_port: u16,
+
+    /// The baud rate of the [`Self::serial_port`].
+    ///
+    /// Must divide 115200, e.g. 115200, 57600, 38400, or 9600. The bootloader always uses
+    /// 8 data bits, no parity, and one stop bit. Defaults to 38400.
+    pub serial_baud_rate: u32,
+
+    /// Whether the UEFI bootloader should jump to the kernel before it exits the boot
+    /// services, so that the kernel can load additional files from the boot device.
+    ///
+    /// The kernel then finds a [`UefiCallback`](crate::info::UefiCallback) in
+    /// [`BootInfo::uefi_callback`](crate::BootInfo::uefi_callback), which loads files through
+    /// the firmware and must be used to exit the boot services before the kernel uses
+    /// firmware memory or enables interrupts. See its documentation for the memory map and the
+    /// other restrictions of this mode. Not compatible with
+    /// [`set_uefi_virtual_address_map`](Self::set_uefi_virtual_address_map), which is ignored
+    /// in this mode. Ignored when booting through BIOS. Disabled by default.
+    pub uefi_callback: bool,
+
+    /// Whether the bootloader should send its log messages over the network when booting.
+    ///
+    /// Each line is sent as a UDP broadcast to port 6666 through the first virtio network
+    /// device on the PCI bus, so this is mainly useful in virtual machines. Like the virtio
+    /// console, the device is found by scanning the whole bus, so this is disabled by default.
+    pub network_logger_status: LoggerStatus,
 }
 
 impl BootloaderConfig {
@@ -49,11 +389,12 @@ impl BootloaderConfig {
         0x3D,
     ];
     #[doc(hidden)]
-    pub const SERIALIZED_LEN: usize = 127;
+    pub const SERIALIZED_LEN: usize = 283;
 
     /// Creates a new default configuration with the following values:
     ///
     /// - `kernel_stack_size`: 80kiB
+    /// - `bootloader_heap_size`: 128kiB
     /// - `mappings`: See [`Mappings::new_default()`]
     /// - `frame_buffer`: See [`FrameBuffer::new_default()`]
     pub const fn new_default() -> Self {
@@ -65,6 +406,45 @@ impl BootloaderConfig {
             log_level: LevelFilter::Trace,
             frame_buffer_logger_status: LoggerStatus::Enable,
             serial_logger_status: LoggerStatus::Enable,
+            bootloader_heap_size: 128 * 1024,
+            error_qr_code: false,
+            language: Language::English,
+            accessible_display: false,
+            boot_failure_limit: Option::None,
+            spec_ctrl: Option::None,
+            indirect_branch_barrier: false,
+            iommu_registers: Option::None,
+            virtio_console_logger_status: LoggerStatus::Disable,
+            debugcon_logger_status: LoggerStatus::Disable,
+            log_levels: LogLevels::new_default(),
+            memory_logger_status: LoggerStatus::Disable,
+            min_frame_address: 16 * 1024 * 1024,
+            dma_address_limit: Option::None,
+            payload_format: PayloadFormat::Elf,
+            flat_binary_load_address: 0xffff_ffff_8000_0000,
+            flat_binary_entry_offset: 0,
+            platform_registers: Option::None,
+            uefi_runtime_services: Option::None,
+            uefi_watchdog_timeout: 600,
+            network_load_deadline: 60,
+            security_version: 0,
+            rollback_protection: RollbackProtection::Disabled,
+            ramdisk_modules: Option::None,
+            log_message_limit: Option::None,
+            log_colors: false,
+            log_timestamps: false,
+            serial_log_format: LogFormat::Text,
+            kernel_aslr: false,
+            strict_segment_permissions: false,
+            record_inputs: false,
+            set_uefi_virtual_address_map: false,
+            five_level_paging: false,
+            pre_kernel_summary: PreKernelSummary::Disabled,
+            acpi_s3_resume: false,
+            serial_port: 0x3F8,
+            serial_baud_rate: 38400,
+            uefi_callback: false,
+            network_logger_status: LoggerStatus::Disable,
         }
     }
 
@@ -81,6 +461,45 @@ impl BootloaderConfig {
             log_level,
             frame_buffer_logger_status,
             serial_logger_status,
+            bootloader_heap_size,
+            error_qr_code,
+            language,
+            accessible_display,
+            boot_failure_limit,
+            spec_ctrl,
+            indirect_branch_barrier,
+            iommu_registers,
+            virtio_console_logger_status,
+            debugcon_logger_status,
+            log_levels,
+            memory_logger_status,
+            min_frame_address,
+            dma_address_limit,
+            payload_format,
+            flat_binary_load_address,
+            flat_binary_entry_offset,
+            platform_registers,
+            uefi_runtime_services,
+            uefi_watchdog_timeout,
+            network_load_deadline,
+            security_version,
+            rollback_protection,
+            ramdisk_modules,
+            log_message_limit,
+            log_colors,
+            log_timestamps,
+            serial_log_format,
+            kernel_aslr,
+            strict_segment_permissions,
+            record_inputs,
+            set_uefi_virtual_address_map,
+            five_level_paging,
+            pre_kernel_summary,
+            acpi_s3_resume,
+            serial_port,
+            serial_baud_rate,
+            uefi_callback,
+            network_logger_status,
         } = self;
         let ApiVersion {
             version_major,
@@ -102,6 +521,9 @@ impl BootloaderConfig {
         let FrameBuffer {
             minimum_framebuffer_height,
             minimum_framebuffer_width,
+            framebuffer_width,
+            framebuffer_height,
+            framebuffer_bpp,
         } = frame_buffer;
 
         let version = {
@@ -169,10 +591,166 @@ impl BootloaderConfig {
         let frame_buffer_logger_status =
             concat_125_1(log_level, (*frame_buffer_logger_status as u8).to_le_bytes());
 
-        concat_126_1(
+        let serial_logger_status = concat_126_1(
             frame_buffer_logger_status,
             (*serial_logger_status as u8).to_le_bytes(),
-        )
+        );
+
+        let bootloader_heap_size =
+            concat_127_8(serial_logger_status, bootloader_heap_size.to_le_bytes());
+
+        let error_qr_code = concat_135_1(bootloader_heap_size, [*error_qr_code as u8]);
+
+        let language = concat_136_1(error_qr_code, [*language as u8]);
+
+        let accessible_display = concat_137_1(language, [*accessible_display as u8]);
+
+        let boot_failure_limit = concat_138_2(
+            accessible_display,
+            match boot_failure_limit {
+                Option::None => [0; 2],
+                Option::Some(limit) => [1, *limit],
+            },
+        );
+
+        let spec_ctrl = concat_140_9(
+            boot_failure_limit,
+            match spec_ctrl {
+                Option::None => [0; 9],
+                Option::Some(value) => concat_1_8([1], value.to_le_bytes()),
+            },
+        );
+
+        let indirect_branch_barrier = concat_149_1(spec_ctrl, [*indirect_branch_barrier as u8]);
+
+        let iommu_registers = concat_150_10(
+            indirect_branch_barrier,
+            match iommu_registers {
+                Option::None => [0; 10],
+                Option::Some(m) => concat_1_9([1], m.serialize()),
+            },
+        );
+
+        let virtio_console_logger_status = concat_160_1(
+            iommu_registers,
+            (*virtio_console_logger_status as u8).to_le_bytes(),
+        );
+
+        let debugcon_logger_status = concat_161_1(
+            virtio_console_logger_status,
+            (*debugcon_logger_status as u8).to_le_bytes(),
+        );
+
+        let log_levels = concat_162_6(debugcon_logger_status, log_levels.serialize());
+
+        let memory_logger_status =
+            concat_168_1(log_levels, (*memory_logger_status as u8).to_le_bytes());
+
+        let min_frame_address = concat_169_8(memory_logger_status, min_frame_address.to_le_bytes());
+
+        let dma_address_limit = concat_177_9(
+            min_frame_address,
+            match dma_address_limit {
+                Option::None => [0; 9],
+                Option::Some(addr) => concat_1_8([1], addr.to_le_bytes()),
+            },
+        );
+
+        let payload_format = concat_186_1(dma_address_limit, [*payload_format as u8]);
+
+        let flat_binary_load_address =
+            concat_187_8(payload_format, flat_binary_load_address.to_le_bytes());
+
+        let flat_binary_entry_offset = concat_195_8(
+            flat_binary_load_address,
+            flat_binary_entry_offset.to_le_bytes(),
+        );
+
+        let platform_registers = concat_203_10(
+            flat_binary_entry_offset,
+            match platform_registers {
+                Option::None => [0; 10],
+                Option::Some(m) => concat_1_9([1], m.serialize()),
+            },
+        );
+
+        let uefi_runtime_services = concat_213_10(
+            platform_registers,
+            match uefi_runtime_services {
+                Option::None => [0; 10],
+                Option::Some(m) => concat_1_9([1], m.serialize()),
+            },
+        );
+
+        let uefi_watchdog_timeout =
+            concat_223_2(uefi_runtime_services, uefi_watchdog_timeout.to_le_bytes());
+
+        let network_load_deadline =
+            concat_225_2(uefi_watchdog_timeout, network_load_deadline.to_le_bytes());
+
+        let security_version = concat_227_4(network_load_deadline, security_version.to_le_bytes());
+
+        let rollback_protection = concat_231_1(security_version, [*rollback_protection as u8]);
+
+        let framebuffer_width = concat_232_9(
+            rollback_protection,
+            match framebuffer_width {
+                Option::None => [0; 9],
+                Option::Some(width) => concat_1_8([1], width.to_le_bytes()),
+            },
+        );
+
+        let framebuffer_height = concat_241_9(
+            framebuffer_width,
+            match framebuffer_height {
+                Option::None => [0; 9],
+                Option::Some(height) => concat_1_8([1], height.to_le_bytes()),
+            },
+        );
+
+        let framebuffer_bpp = concat_250_2(
+            framebuffer_height,
+            match framebuffer_bpp {
+                Option::None => [0; 2],
+                Option::Some(bpp) => [1, *bpp],
+            },
+        );
+
+        let ramdisk_modules = concat_252_10(
+            framebuffer_bpp,
+            match ramdisk_modules {
+                Option::None => [0; 10],
+                Option::Some(m) => concat_1_9([1], m.serialize()),
+            },
+        );
+
+        let log_message_limit = concat_262_3(
+            ramdisk_modules,
+            match log_message_limit {
+                Option::None => [0; 3],
+                Option::Some(limit) => concat_1_2([1], limit.to_le_bytes()),
+            },
+        );
+
+        let log_colors = concat_265_1(log_message_limit, [*log_colors as u8]);
+
+        let log_timestamps = concat_266_1(log_colors, [*log_timestamps as u8]);
+
+        let serial_log_format = concat_267_1(log_timestamps, [*serial_log_format as u8]);
+        let kernel_aslr = concat_268_1(serial_log_format, [*kernel_aslr as u8]);
+        let strict_segment_permissions =
+            concat_269_1(kernel_aslr, [*strict_segment_permissions as u8]);
+        let record_inputs = concat_270_1(strict_segment_permissions, [*record_inputs as u8]);
+        let set_uefi_virtual_address_map =
+            concat_271_1(record_inputs, [*set_uefi_virtual_address_map as u8]);
+        let five_level_paging =
+            concat_272_1(set_uefi_virtual_address_map, [*five_level_paging as u8]);
+        let pre_kernel_summary = concat_273_1(five_level_paging, [*pre_kernel_summary as u8]);
+        let acpi_s3_resume = concat_274_1(pre_kernel_summary, [*acpi_s3_resume as u8]);
+        let serial_port = concat_275_2(acpi_s3_resume, serial_port.to_le_bytes());
+        let serial_baud_rate = concat_277_4(serial_port, serial_baud_rate.to_le_bytes());
+        let uefi_callback = concat_281_1(serial_baud_rate, [*uefi_callback as u8]);
+        concat_282_1(uefi_callback, [*network_logger_status as u8])
     }
 
     /// Tries to deserialize a config byte array that was created using [`Self::serialize`].
@@ -266,7 +844,7 @@ impl BootloaderConfig {
             (mappings, s)
         };
 
-        let (frame_buffer, s) = {
+        let (mut frame_buffer, s) = {
             let (&min_framebuffer_height_some, s) = split_array_ref(s);
             let (&min_framebuffer_height, s) = split_array_ref(s);
             let (&min_framebuffer_width_some, s) = split_array_ref(s);
@@ -283,6 +861,7 @@ impl BootloaderConfig {
                     [1] => Option::Some(u64::from_le_bytes(min_framebuffer_width)),
                     _ => return Err("minimum_framebuffer_width invalid"),
                 },
+                ..FrameBuffer::new_default()
             };
             (frame_buffer, s)
         };
@@ -309,6 +888,243 @@ impl BootloaderConfig {
             Option::None => return Err("serial_logger_status invalid"),
         };
 
+        let (&bootloader_heap_size, s) = split_array_ref(s);
+
+        let (&[error_qr_code], s) = split_array_ref(s);
+        let error_qr_code = match error_qr_code {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid error_qr_code value"),
+        };
+
+        let (&[language], s) = split_array_ref(s);
+        let language = match Language::from_u8(language) {
+            Option::Some(language) => language,
+            Option::None => return Err("language invalid"),
+        };
+
+        let (&[accessible_display], s) = split_array_ref(s);
+        let accessible_display = match accessible_display {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid accessible_display value"),
+        };
+
+        let (&[boot_failure_limit_some, boot_failure_limit], s) = split_array_ref(s);
+        let boot_failure_limit = match boot_failure_limit_some {
+            0 if boot_failure_limit == 0 => Option::None,
+            1 => Option::Some(boot_failure_limit),
+            _ => return Err("boot_failure_limit invalid"),
+        };
+
+        let (&spec_ctrl_some, s) = split_array_ref(s);
+        let (&spec_ctrl, s) = split_array_ref(s);
+        let spec_ctrl = match spec_ctrl_some {
+            [0] if spec_ctrl == [0; 8] => Option::None,
+            [1] => Option::Some(u64::from_le_bytes(spec_ctrl)),
+            _ => return Err("spec_ctrl invalid"),
+        };
+
+        let (&[indirect_branch_barrier], s) = split_array_ref(s);
+        let indirect_branch_barrier = match indirect_branch_barrier {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid indirect_branch_barrier value"),
+        };
+
+        let (&iommu_registers_some, s) = split_array_ref(s);
+        let (&iommu_registers, s) = split_array_ref(s);
+        let iommu_registers = match iommu_registers_some {
+            [0] if iommu_registers == [0; 9] => Option::None,
+            [1] => Option::Some(Mapping::deserialize(&iommu_registers)?),
+            _ => return Err("invalid iommu_registers value"),
+        };
+
+        let (&virtio_console_logger_status, s) = split_array_ref(s);
+        let virtio_console_logger_status =
+            LoggerStatus::from_u8(u8::from_le_bytes(virtio_console_logger_status));
+        let virtio_console_logger_status = match virtio_console_logger_status {
+            Option::Some(status) => status,
+            Option::None => return Err("virtio_console_logger_status invalid"),
+        };
+
+        let (&debugcon_logger_status, s) = split_array_ref(s);
+        let debugcon_logger_status =
+            LoggerStatus::from_u8(u8::from_le_bytes(debugcon_logger_status));
+        let debugcon_logger_status = match debugcon_logger_status {
+            Option::Some(status) => status,
+            Option::None => return Err("debugcon_logger_status invalid"),
+        };
+
+        let (log_levels, s) = split_array_ref(s);
+        let log_levels = LogLevels::deserialize(log_levels)?;
+
+        let (&memory_logger_status, s) = split_array_ref(s);
+        let memory_logger_status = LoggerStatus::from_u8(u8::from_le_bytes(memory_logger_status));
+        let memory_logger_status = match memory_logger_status {
+            Option::Some(status) => status,
+            Option::None => return Err("memory_logger_status invalid"),
+        };
+
+        let (&min_frame_address, s) = split_array_ref(s);
+
+        let (&dma_address_limit_some, s) = split_array_ref(s);
+        let (&dma_address_limit, s) = split_array_ref(s);
+        let dma_address_limit = match dma_address_limit_some {
+            [0] if dma_address_limit == [0; 8] => Option::None,
+            [1] => Option::Some(u64::from_le_bytes(dma_address_limit)),
+            _ => return Err("dma_address_limit invalid"),
+        };
+
+        let (&[payload_format], s) = split_array_ref(s);
+        let payload_format = match PayloadFormat::from_u8(payload_format) {
+            Option::Some(format) => format,
+            Option::None => return Err("payload_format invalid"),
+        };
+
+        let (&flat_binary_load_address, s) = split_array_ref(s);
+        let (&flat_binary_entry_offset, s) = split_array_ref(s);
+
+        let (&platform_registers_some, s) = split_array_ref(s);
+        let (&platform_registers, s) = split_array_ref(s);
+        let platform_registers = match platform_registers_some {
+            [0] if platform_registers == [0; 9] => Option::None,
+            [1] => Option::Some(Mapping::deserialize(&platform_registers)?),
+            _ => return Err("invalid platform_registers value"),
+        };
+
+        let (&uefi_runtime_services_some, s) = split_array_ref(s);
+        let (&uefi_runtime_services, s) = split_array_ref(s);
+        let uefi_runtime_services = match uefi_runtime_services_some {
+            [0] if uefi_runtime_services == [0; 9] => Option::None,
+            [1] => Option::Some(Mapping::deserialize(&uefi_runtime_services)?),
+            _ => return Err("invalid uefi_runtime_services value"),
+        };
+
+        let (&uefi_watchdog_timeout, s) = split_array_ref(s);
+        let (&network_load_deadline, s) = split_array_ref(s);
+
+        let (&security_version, s) = split_array_ref(s);
+
+        let (&[rollback_protection], s) = split_array_ref(s);
+        let rollback_protection = match RollbackProtection::from_u8(rollback_protection) {
+            Option::Some(protection) => protection,
+            Option::None => return Err("rollback_protection invalid"),
+        };
+
+        let (&framebuffer_width_some, s) = split_array_ref(s);
+        let (&framebuffer_width, s) = split_array_ref(s);
+        frame_buffer.framebuffer_width = match framebuffer_width_some {
+            [0] if framebuffer_width == [0; 8] => Option::None,
+            [1] => Option::Some(u64::from_le_bytes(framebuffer_width)),
+            _ => return Err("framebuffer_width invalid"),
+        };
+
+        let (&framebuffer_height_some, s) = split_array_ref(s);
+        let (&framebuffer_height, s) = split_array_ref(s);
+        frame_buffer.framebuffer_height = match framebuffer_height_some {
+            [0] if framebuffer_height == [0; 8] => Option::None,
+            [1] => Option::Some(u64::from_le_bytes(framebuffer_height)),
+            _ => return Err("framebuffer_height invalid"),
+        };
+
+        let (&[framebuffer_bpp_some, framebuffer_bpp], s) = split_array_ref(s);
+        frame_buffer.framebuffer_bpp = match framebuffer_bpp_some {
+            0 if framebuffer_bpp == 0 => Option::None,
+            1 => Option::Some(framebuffer_bpp),
+            _ => return Err("framebuffer_bpp invalid"),
+        };
+
+        let (&ramdisk_modules_some, s) = split_array_ref(s);
+        let (&ramdisk_modules, s) = split_array_ref(s);
+        let ramdisk_modules = match ramdisk_modules_some {
+            [0] if ramdisk_modules == [0; 9] => Option::None,
+            [1] => Option::Some(Mapping::deserialize(&ramdisk_modules)?),
+            _ => return Err("invalid ramdisk_modules value"),
+        };
+
+        let (&log_message_limit_some, s) = split_array_ref(s);
+        let (&log_message_limit, s) = split_array_ref(s);
+        let log_message_limit = match log_message_limit_some {
+            [0] if log_message_limit == [0; 2] => Option::None,
+            [1] => Option::Some(u16::from_le_bytes(log_message_limit)),
+            _ => return Err("log_message_limit invalid"),
+        };
+
+        let (&[log_colors], s) = split_array_ref(s);
+        let log_colors = match log_colors {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid log_colors value"),
+        };
+
+        let (&[log_timestamps], s) = split_array_ref(s);
+        let log_timestamps = match log_timestamps {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid log_timestamps value"),
+        };
+
+        let (&[serial_log_format], s) = split_array_ref(s);
+        let serial_log_format =
+            LogFormat::from_u8(serial_log_format).ok_or("invalid serial_log_format value")?;
+        let (&[kernel_aslr], s) = split_array_ref(s);
+        let kernel_aslr = match kernel_aslr {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid kernel_aslr value"),
+        };
+        let (&[strict_segment_permissions], s) = split_array_ref(s);
+        let strict_segment_permissions = match strict_segment_permissions {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid strict_segment_permissions value"),
+        };
+        let (&[record_inputs], s) = split_array_ref(s);
+        let record_inputs = match record_inputs {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid record_inputs value"),
+        };
+        let (&[set_uefi_virtual_address_map], s) = split_array_ref(s);
+        let set_uefi_virtual_address_map = match set_uefi_virtual_address_map {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid set_uefi_virtual_address_map value"),
+        };
+        let (&[five_level_paging], s) = split_array_ref(s);
+        let five_level_paging = match five_level_paging {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid five_level_paging value"),
+        };
+        let (&[pre_kernel_summary], s) = split_array_ref(s);
+        let pre_kernel_summary = PreKernelSummary::from_u8(pre_kernel_summary)
+            .ok_or("invalid pre_kernel_summary value")?;
+        let (&[acpi_s3_resume], s) = split_array_ref(s);
+        let acpi_s3_resume = match acpi_s3_resume {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid acpi_s3_resume value"),
+        };
+        let (&serial_port, s) = split_array_ref(s);
+        let (&serial_baud_rate, s) = split_array_ref(s);
+        let serial_baud_rate = u32::from_le_bytes(serial_baud_rate);
+        if serial_baud_rate == 0 || 115_200 % serial_baud_rate != 0 {
+            return Err("invalid serial_baud_rate value");
+        }
+        let (&[uefi_callback], s) = split_array_ref(s);
+        let uefi_callback = match uefi_callback {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid uefi_callback value"),
+        };
+        let (&[network_logger_status], s) = split_array_ref(s);
+        let network_logger_status = match LoggerStatus::from_u8(network_logger_status) {
+            Option::Some(status) => status,
+            Option::None => return Err("network_logger_status invalid"),
+        };
+
         if !s.is_empty() {
             return Err("unexpected rest");
         }
@@ -321,9 +1137,59 @@ impl BootloaderConfig {
             log_level,
             frame_buffer_logger_status,
             serial_logger_status,
+            bootloader_heap_size: u64::from_le_bytes(bootloader_heap_size),
+            error_qr_code,
+            language,
+            accessible_display,
+            boot_failure_limit,
+            spec_ctrl,
+            indirect_branch_barrier,
+            iommu_registers,
+            virtio_console_logger_status,
+            debugcon_logger_status,
+            log_levels,
+            memory_logger_status,
+            min_frame_address: u64::from_le_bytes(min_frame_address),
+            dma_address_limit,
+            payload_format,
+            flat_binary_load_address: u64::from_le_bytes(flat_binary_load_address),
+            flat_binary_entry_offset: u64::from_le_bytes(flat_binary_entry_offset),
+            platform_registers,
+            uefi_runtime_services,
+            uefi_watchdog_timeout: u16::from_le_bytes(uefi_watchdog_timeout),
+            network_load_deadline: u16::from_le_bytes(network_load_deadline),
+            security_version: u32::from_le_bytes(security_version),
+            rollback_protection,
+            ramdisk_modules,
+            log_message_limit,
+            log_colors,
+            log_timestamps,
+            serial_log_format,
+            kernel_aslr,
+            strict_segment_permissions,
+            record_inputs,
+            set_uefi_virtual_address_map,
+            five_level_paging,
+            pre_kernel_summary,
+            acpi_s3_resume,
+            serial_port: u16::from_le_bytes(serial_port),
+            serial_baud_rate,
+            uefi_callback,
+            network_logger_status,
         })
     }
 
+    /// Searches the given kernel executable for a serialized config.
+    ///
+    /// This is used for kernel formats that have no dedicated config section, e.g. flat
+    /// binaries. Returns the first occurrence of the config UUID that is followed by a valid
+    /// config.
+    pub fn find_serialized(executable: &[u8]) -> Option<&[u8]> {
+        executable
+            .windows(Self::SERIALIZED_LEN)
+            .find(|window| window.starts_with(&Self::UUID) && Self::deserialize(window).is_ok())
+    }
+
     #[cfg(test)]
     fn random() -> Self {
         Self {
@@ -334,6 +1200,77 @@ impl BootloaderConfig {
             log_level: LevelFilter::Trace,
             frame_buffer_logger_status: LoggerStatus::Enable,
             serial_logger_status: LoggerStatus::Enable,
+            bootloader_heap_size: rand::random(),
+            error_qr_code: rand::random(),
+            language: Language::from_u8(rand::random::<u8>() % 4).unwrap(),
+            accessible_display: rand::random(),
+            boot_failure_limit: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            spec_ctrl: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            indirect_branch_barrier: rand::random(),
+            iommu_registers: if rand::random() {
+                Option::Some(Mapping::random())
+            } else {
+                Option::None
+            },
+            virtio_console_logger_status: LoggerStatus::Enable,
+            debugcon_logger_status: LoggerStatus::Enable,
+            log_levels: LogLevels::random(),
+            memory_logger_status: LoggerStatus::Enable,
+            min_frame_address: rand::random(),
+            dma_address_limit: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            payload_format: PayloadFormat::from_u8(rand::random::<u8>() % 3).unwrap(),
+            flat_binary_load_address: rand::random(),
+            flat_binary_entry_offset: rand::random(),
+            platform_registers: if rand::random() {
+                Option::Some(Mapping::random())
+            } else {
+                Option::None
+            },
+            uefi_runtime_services: if rand::random() {
+                Option::Some(Mapping::random())
+            } else {
+                Option::None
+            },
+            uefi_watchdog_timeout: rand::random(),
+            network_load_deadline: rand::random(),
+            security_version: rand::random(),
+            rollback_protection: RollbackProtection::from_u8(rand::random::<u8>() % 3).unwrap(),
+            ramdisk_modules: if rand::random() {
+                Option::Some(Mapping::random())
+            } else {
+                Option::None
+            },
+            log_message_limit: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            log_colors: rand::random(),
+            log_timestamps: rand::random(),
+            serial_log_format: LogFormat::from_u8(rand::random::<u8>() % 2).unwrap(),
+            kernel_aslr: rand::random(),
+            strict_segment_permissions: rand::random(),
+            record_inputs: rand::random(),
+            set_uefi_virtual_address_map: rand::random(),
+            five_level_paging: rand::random(),
+            pre_kernel_summary: PreKernelSummary::from_u8(rand::random::<u8>() % 3).unwrap(),
+            acpi_s3_resume: rand::random(),
+            serial_port: rand::random(),
+            serial_baud_rate: [115_200, 57_600, 38_400, 9_600][rand::random::<usize>() % 4],
+            uefi_callback: rand::random(),
+            network_logger_status: LoggerStatus::Enable,
         }
     }
 }
@@ -371,8 +1308,22 @@ impl ApiVersion {
         }
     }
 
+    pub(crate) const fn from_parts(
+        version_major: u16,
+        version_minor: u16,
+        version_patch: u16,
+        pre_release: bool,
+    ) -> Self {
+        Self {
+            version_major,
+            version_minor,
+            version_patch,
+            pre_release,
+        }
+    }
+
     #[cfg(test)]
-    fn random() -> ApiVersion {
+    pub(crate) fn random() -> ApiVersion {
         Self {
             version_major: rand::random(),
             version_minor: rand::random(),
@@ -382,22 +1333,22 @@ impl ApiVersion {
     }
 
     /// Returns the major version number.
-    pub fn version_major(&self) -> u16 {
+    pub const fn version_major(&self) -> u16 {
         self.version_major
     }
 
     /// Returns the minor version number.
-    pub fn version_minor(&self) -> u16 {
+    pub const fn version_minor(&self) -> u16 {
         self.version_minor
     }
 
     /// Returns the patch version number.
-    pub fn version_patch(&self) -> u16 {
+    pub const fn version_patch(&self) -> u16 {
         self.version_patch
     }
 
     /// Returns whether this version is a pre-release, e.g., an alpha version.
-    pub fn pre_release(&self) -> bool {
+    pub const fn pre_release(&self) -> bool {
         self.pre_release
     }
 }
@@ -413,6 +1364,9 @@ impl Default for ApiVersion {
 #[non_exhaustive]
 pub struct Mappings {
     /// Configures how the kernel stack should be mapped.
+    ///
+    /// A fixed address is the lowest address of the stack. The page below it is reserved as
+    /// the guard page, so it must not be used by other mappings.
     pub kernel_stack: Mapping,
     /// Specifies where the [`crate::BootInfo`] struct should be placed in virtual memory.
     pub boot_info: Mapping,
@@ -422,7 +1376,13 @@ pub struct Mappings {
     /// space at some offset. This is useful for accessing and modifying the page tables set
     /// up by the bootloader.
     ///
+    /// The memory is mapped with 1GiB pages if the CPU supports them, and with 2MiB pages
+    /// otherwise. A fixed address must thus be aligned to at least 2MiB, and to 1GiB for the
+    /// larger pages (see [`BootInfo::physical_memory_page_size`]).
+    ///
     /// Defaults to `None`, i.e. no mapping of the physical memory.
+    ///
+    /// [`BootInfo::physical_memory_page_size`]: crate::BootInfo::physical_memory_page_size
     pub physical_memory: Option<Mapping>,
     /// As an alternative to mapping the whole physical memory (see [`Self::physical_memory`]),
     /// the bootloader also has support for setting up a
@@ -513,6 +1473,34 @@ pub struct FrameBuffer {
     ///
     /// If this is not possible, the bootloader will fall back to a smaller format.
     pub minimum_framebuffer_width: Option<u64>,
+    /// The preferred width of the framebuffer in pixels.
+    ///
+    /// If any of the preferred width, height, or bits per pixel is set, the UEFI bootloader
+    /// iterates the graphics modes of the firmware and picks the closest match in this order:
+    ///
+    /// 1. Modes with an unsupported pixel format or below the minimum width or height are
+    ///    skipped.
+    /// 2. The mode with the smallest sum of the width and height differences to the
+    ///    preferred resolution is picked. Unset dimensions don't count.
+    /// 3. On ties, the mode with the resolution that the firmware set up is kept, otherwise
+    ///    the first mode in firmware order is picked.
+    /// 4. If no mode remains, the firmware mode is kept.
+    ///
+    /// The BIOS bootloader always picks a VESA mode with a linear framebuffer. It skips modes
+    /// below the minimums, then prefers modes with the preferred bits per pixel, modes with
+    /// a known pixel format, and finally the mode closest to the preferred resolution, where
+    /// unset dimensions default to 1280x720. It fails if no mode remains. The disk image
+    /// builder passes these settings to the BIOS stages, so they must fit into 16 bits.
+    ///
+    /// Defaults to `None`, i.e. no preference.
+    pub framebuffer_width: Option<u64>,
+    /// The preferred height of the framebuffer in pixels, see [`Self::framebuffer_width`].
+    pub framebuffer_height: Option<u64>,
+    /// The preferred number of bits per pixel, see [`Self::framebuffer_width`].
+    ///
+    /// The UEFI bootloader only supports 32-bit RGB and BGR modes, so other values can't be
+    /// matched there. The BIOS bootloader also supports 24-bit and other VESA modes.
+    pub framebuffer_bpp: Option<u8>,
 }
 
 impl FrameBuffer {
@@ -521,6 +1509,9 @@ impl FrameBuffer {
         Self {
             minimum_framebuffer_height: Option::None,
             minimum_framebuffer_width: Option::None,
+            framebuffer_width: Option::None,
+            framebuffer_height: Option::None,
+            framebuffer_bpp: Option::None,
         }
     }
 
@@ -537,8 +1528,102 @@ impl FrameBuffer {
             } else {
                 Option::None
             },
+            framebuffer_width: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            framebuffer_height: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+            framebuffer_bpp: if rand::random() {
+                Option::Some(rand::random())
+            } else {
+                Option::None
+            },
+        }
+    }
+}
+
+/// The maximum log levels of the individual log outputs of the bootloader.
+///
+/// An output is only used if it is enabled through its `*_logger_status` option in
+/// [`BootloaderConfig`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+#[repr(C)]
+pub struct LogLevels {
+    /// The maximum level of the messages that are printed to the framebuffer.
+    pub frame_buffer: LevelFilter,
+    /// The maximum level of the messages that are printed to the serial port.
+    pub serial: LevelFilter,
+    /// The maximum level of the messages that are printed to the virtio console.
+    pub virtio_console: LevelFilter,
+    /// The maximum level of the messages that are printed to the Bochs/QEMU debug console.
+    pub debugcon: LevelFilter,
+    /// The maximum level of the messages that are kept in the memory buffer.
+    pub memory: LevelFilter,
+    /// The maximum level of the messages that are sent over the network.
+    pub network: LevelFilter,
+}
+
+impl LogLevels {
+    /// Creates a default configuration that doesn't filter any messages.
+    pub const fn new_default() -> Self {
+        Self {
+            frame_buffer: LevelFilter::Trace,
+            serial: LevelFilter::Trace,
+            virtio_console: LevelFilter::Trace,
+            debugcon: LevelFilter::Trace,
+            memory: LevelFilter::Trace,
+            network: LevelFilter::Trace,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn random() -> LogLevels {
+        let level = || LevelFilter::from_u8(rand::random::<u8>() % 6).unwrap();
+        Self {
+            frame_buffer: level(),
+            serial: level(),
+            virtio_console: level(),
+            debugcon: level(),
+            memory: level(),
+            network: level(),
         }
     }
+
+    pub(crate) const fn serialize(&self) -> [u8; 6] {
+        [
+            self.frame_buffer as u8,
+            self.serial as u8,
+            self.virtio_console as u8,
+            self.debugcon as u8,
+            self.memory as u8,
+            self.network as u8,
+        ]
+    }
+
+    pub(crate) fn deserialize(serialized: &[u8; 6]) -> Result<Self, &'static str> {
+        let level = |value| LevelFilter::from_u8(value).ok_or("invalid log level");
+        let &[frame_buffer, serial, virtio_console, debugcon, memory, network] = serialized;
+        Ok(Self {
+            frame_buffer: level(frame_buffer)?,
+            serial: level(serial)?,
+            virtio_console: level(virtio_console)?,
+            debugcon: level(debugcon)?,
+            memory: level(memory)?,
+            network: level(network)?,
+        })
+    }
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        Self::new_default()
+    }
 }
 
 /// Specifies how the bootloader should map a memory region into the virtual address space.
@@ -659,6 +1744,150 @@ impl LoggerStatus {
     }
 }
 
+/// The formats of the bootloader log messages.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogFormat {
+    /// One line of text per message.
+    Text,
+    /// One JSON object per line.
+    ///
+    /// Log messages are written as `{"type":"log","level":"INFO","target":"...","msg":"..."}`.
+    /// In addition, the bootloader writes `event` objects when it reaches a stage
+    /// (`{"type":"event","event":"stage","name":"uefi"}`), has loaded the kernel or ramdisk
+    /// (`{"type":"event","event":"loaded","name":"kernel","bytes":123}`), and jumps to the
+    /// kernel (`{"type":"event","event":"jump","entry_point":123}`). Messages that were dropped
+    /// because of [`BootloaderConfig::log_message_limit`] are reported through a `suppressed`
+    /// event with a `count` field, and a failed boot through an `error` object with the text
+    /// of the error report in its `report` field. With
+    /// [`BootloaderConfig::log_timestamps`], every object has an `ms` field with the time in
+    /// milliseconds. Colors are never used in this format.
+    ///
+    /// Output from before the bootloader logger starts, e.g. from the firmware or the early
+    /// BIOS stages, is not JSON, so parsers should skip lines that don't start with `{`.
+    JsonLines,
+}
+
+impl LogFormat {
+    /// Converts an u8 into a Option<LogFormat>
+    pub fn from_u8(value: u8) -> Option<LogFormat> {
+        match value {
+            0 => Some(Self::Text),
+            1 => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the bootloader shows a summary before jumping to the kernel, see
+/// [`BootloaderConfig::pre_kernel_summary`].
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PreKernelSummary {
+    /// Jump to the kernel without a summary.
+    Disabled,
+    /// Log the summary and jump to the kernel.
+    Log,
+    /// Log the summary and wait until a key is pressed on the keyboard or the serial port.
+    Pause,
+}
+
+impl PreKernelSummary {
+    /// Converts an u8 into a Option<PreKernelSummary>
+    pub fn from_u8(value: u8) -> Option<PreKernelSummary> {
+        match value {
+            0 => Some(Self::Disabled),
+            1 => Some(Self::Log),
+            2 => Some(Self::Pause),
+            _ => None,
+        }
+    }
+}
+
+/// The languages of the bootloader messages.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Language {
+    /// English
+    English,
+    /// German
+    German,
+    /// French
+    French,
+    /// Spanish
+    Spanish,
+}
+
+impl Language {
+    /// Converts an u8 into a Option<Language>
+    pub fn from_u8(value: u8) -> Option<Language> {
+        match value {
+            0 => Some(Self::English),
+            1 => Some(Self::German),
+            2 => Some(Self::French),
+            3 => Some(Self::Spanish),
+            _ => None,
+        }
+    }
+}
+
+/// The executable formats of the kernel.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PayloadFormat {
+    /// An ELF executable, optionally position independent.
+    Elf,
+    /// A raw binary image that is mapped at
+    /// [`flat_binary_load_address`](BootloaderConfig::flat_binary_load_address).
+    ///
+    /// The whole image is mapped writable and executable. It must reserve its own zeroed
+    /// memory, e.g. by including its `.bss` in the image.
+    FlatBinary,
+    /// A 64-bit PE executable that is mapped at its preferred image base.
+    ///
+    /// The sections must be page-aligned. Base relocations and TLS are not supported.
+    Pe,
+}
+
+impl PayloadFormat {
+    /// Converts an u8 into a Option<PayloadFormat>
+    pub fn from_u8(value: u8) -> Option<PayloadFormat> {
+        match value {
+            0 => Some(Self::Elf),
+            1 => Some(Self::FlatBinary),
+            2 => Some(Self::Pe),
+            _ => None,
+        }
+    }
+}
+
+/// The reactions to kernels that are older than the stored minimum version, see
+/// [`BootloaderConfig::rollback_protection`].
+///
+/// The variants are ordered by strictness.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RollbackProtection {
+    /// The security version is neither checked nor stored.
+    Disabled,
+    /// Older kernels are booted with a warning.
+    Warn,
+    /// Older kernels are refused with an error screen.
+    Enforce,
+}
+
+impl RollbackProtection {
+    /// Converts an u8 into a Option<RollbackProtection>
+    pub fn from_u8(value: u8) -> Option<RollbackProtection> {
+        match value {
+            0 => Some(Self::Disabled),
+            1 => Some(Self::Warn),
+            2 => Some(Self::Enforce),
+            _ => None,
+        }
+    }
+}
+
 /// Taken from https://github.com/rust-lang/rust/blob/e100ec5bc7cd768ec17d75448b29c9ab4a39272b/library/core/src/slice/mod.rs#L1673-L1677
 ///
 /// TODO replace with `split_array` feature in stdlib as soon as it's stabilized,
@@ -681,6 +1910,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn find_serialized_config() {
+        let config = BootloaderConfig::random();
+        let mut executable = [0x90; 4096];
+        executable[17..][..16].copy_from_slice(&BootloaderConfig::UUID);
+        executable[1000..][..BootloaderConfig::SERIALIZED_LEN].copy_from_slice(&config.serialize());
+        let found = BootloaderConfig::find_serialized(&executable).unwrap();
+        assert_eq!(BootloaderConfig::deserialize(found), Ok(config));
+        assert_eq!(BootloaderConfig::find_serialized(&[0x90; 4096]), None);
+    }
+
     #[test]
     fn config_serde() {
         for _ in 0..10000 {
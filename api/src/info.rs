@@ -1,6 +1,6 @@
-use core::{ops, slice};
+use core::{fmt, ops, ptr, slice, str};
 
-use crate::config::ApiVersion;
+use crate::config::{ApiVersion, LevelFilter, LogLevels};
 
 /// This structure represents the information that the bootloader passes to the kernel.
 ///
@@ -22,100 +22,1925 @@ use crate::config::ApiVersion;
 pub struct BootInfo {
     /// The version of the `bootloader_api` crate. Must match the `bootloader` version.
     pub api_version: ApiVersion,
+    /// The [layout hash](BootInfoAbi::layout_hash) of the `bootloader_api` crate that the
+    /// bootloader was compiled with.
+    ///
+    /// Kernels can compare this field against `BootInfoAbi::current().layout_hash` to verify
+    /// that they interpret the boot information with the same memory layout. This field
+    /// directly follows `api_version`, so its offset is the same for all layouts.
+    pub abi_layout_hash: u64,
     /// A map of the physical memory regions of the underlying machine.
     ///
-    /// The bootloader queries this information from the BIOS/UEFI firmware and translates this
-    /// information to Rust types. It also marks any memory regions that the bootloader uses in
-    /// the memory map before passing it to the kernel. Regions marked as usable can be freely
-    /// used by the kernel.
-    pub memory_regions: MemoryRegions,
-    /// Information about the framebuffer for screen output if available.
-    pub framebuffer: Optional<FrameBuffer>,
-    /// The virtual address at which the mapping of the physical memory starts.
+    /// The bootloader queries this information from the BIOS/UEFI firmware and translates this
+    /// information to Rust types. It also marks any memory regions that the bootloader uses in
+    /// the memory map before passing it to the kernel. Regions marked as usable can be freely
+    /// used by the kernel.
+    ///
+    /// The regions are sorted by their start address, don't overlap, and are never empty. They
+    /// start and end at 4KiB page boundaries, and adjacent regions of the same kind are merged.
+    /// Usable regions that the firmware reports with unaligned bounds are shrunk to whole
+    /// pages, all other regions are grown to whole pages.
+    ///
+    /// The bootloader allocates its frames from the memory above the `min_frame_address`
+    /// config option first (16MiB by default), so the usable memory below that address stays
+    /// untouched unless the memory above is exhausted. On BIOS systems, the kernel and ramdisk
+    /// are loaded by the real-mode stages. They are placed below 4GiB if they fit there, and
+    /// otherwise above 4GiB, but always below 10GiB.
+    pub memory_regions: MemoryRegions,
+    /// Information about the framebuffer for screen output if available.
+    pub framebuffer: Optional<FrameBuffer>,
+    /// The virtual address at which the mapping of the physical memory starts.
+    ///
+    /// Physical addresses can be converted to virtual addresses by adding this offset to them.
+    ///
+    /// The mapping of the physical memory allows to access arbitrary physical frames. Accessing
+    /// frames that are also mapped at other virtual addresses can easily break memory safety and
+    /// cause undefined behavior. Only frames reported as `USABLE` by the memory map in the `BootInfo`
+    /// can be safely accessed.
+    ///
+    /// Only available if the `map-physical-memory` config option is enabled.
+    pub physical_memory_offset: Optional<u64>,
+    /// The virtual address of the recursively mapped level 4 page table.
+    ///
+    /// Only available if the `map-page-table-recursively` config option is enabled.
+    pub recursive_index: Optional<u16>,
+    /// The address of the `RSDP` data structure, which can be use to find the ACPI tables.
+    ///
+    /// This field is `None` if no `RSDP` was found (for BIOS) or reported (for UEFI).
+    pub rsdp_addr: Optional<u64>,
+    /// The thread local storage (TLS) template of the kernel executable, if present.
+    pub tls_template: Optional<TlsTemplate>,
+    /// Ramdisk address, if loaded
+    pub ramdisk_addr: Optional<u64>,
+    /// Ramdisk image size, set to 0 if addr is None
+    pub ramdisk_len: u64,
+    /// The disk and partition that the kernel was loaded from.
+    ///
+    /// This field is `None` if the kernel was not loaded from a disk, e.g. when booting
+    /// over the network.
+    pub boot_device: Optional<BootDevice>,
+    /// Identifies the bootloader version, configuration, and kernel of the boot image.
+    ///
+    /// This field is only set if the boot image contains a metadata block that matches the
+    /// loaded kernel.
+    pub boot_metadata: Optional<BootMetadata>,
+    /// The size and peak usage of the heap that the bootloader used for its own allocations.
+    ///
+    /// This field is `None` if the bootloader did not use a heap.
+    pub bootloader_heap: Optional<BootloaderHeap>,
+    /// The persistent boot failure counter.
+    ///
+    /// This field is only set if the `boot_failure_limit` config option is set and the
+    /// firmware supports non-volatile variables.
+    pub boot_counter: Optional<BootCounter>,
+    /// The speculation control features of the CPU and the mitigation state at kernel entry.
+    ///
+    /// The bootloader applies the `spec_ctrl` and `indirect_branch_barrier` config options
+    /// before reporting this state.
+    pub cpu_state: Optional<CpuState>,
+    /// The firmware configuration of virtualization and SMM related MSRs.
+    pub msr_state: Optional<MsrState>,
+    /// The DMA remapping hardware units (IOMMUs) described by the ACPI DMAR or IVRS table.
+    ///
+    /// This field is `None` if the firmware doesn't provide either table.
+    pub iommus: Optional<Iommus>,
+    /// The most recent log messages of the bootloader.
+    ///
+    /// Only available if the `memory_logger_status` config option is enabled. The log ends
+    /// with the creation of the boot info, so the last few messages before the kernel entry
+    /// are missing.
+    pub boot_log: Optional<FfiStr>,
+    /// The frames that only contain page tables and the GDT created by the bootloader.
+    ///
+    /// These frames are also reported as [`MemoryRegionKind::BootloaderReclaimable`] in the
+    /// memory map. The kernel can reuse them once it has switched to its own page tables and
+    /// loaded its own GDT. Frames that don't fit into the list are reported as
+    /// [`MemoryRegionKind::Bootloader`] instead.
+    pub reclaimable_frames: Optional<FrameExtents>,
+    /// The registers of the local APIC, the I/O APICs, and the HPET, as described by the ACPI
+    /// MADT and HPET tables.
+    ///
+    /// This field is `None` if the firmware doesn't provide a MADT.
+    pub platform_registers: Optional<PlatformRegisters>,
+    /// The UEFI system table and the memory regions of the runtime services.
+    ///
+    /// This field is `None` when booting through BIOS.
+    pub uefi_runtime: Optional<UefiRuntime>,
+    /// The A/B slot that the bootloader selected for over-the-air updates.
+    ///
+    /// This field is only set if the `BootloaderSlot` EFI variable exists and the kernel of
+    /// the selected slot was found, see [`BootSlot`].
+    pub boot_slot: Optional<BootSlot>,
+    /// The command line that the boot image was created with.
+    ///
+    /// The command line is stored in the [`boot_metadata`](Self::boot_metadata) block, so it
+    /// is only available if the block matches the loaded kernel. This field is `None` if the
+    /// command line is empty.
+    pub command_line: Optional<FfiStr>,
+    /// The index of the files in the ramdisk, if the ramdisk is a cpio or tar archive.
+    ///
+    /// The bootloader leaves the archive intact and only records where the contents of its
+    /// regular files are located, so kernels can access them without a file system driver.
+    pub ramdisk_archive: Optional<RamdiskArchive>,
+    /// The files of the ramdisk archive, extracted into separate page-aligned memory regions.
+    ///
+    /// Only available if the `ramdisk_modules` config option is set and the ramdisk is a cpio
+    /// or tar archive. The modules are listed in the same order as in
+    /// [`ramdisk_archive`](Self::ramdisk_archive).
+    pub modules: Optional<Modules>,
+    /// The timer features of the CPU, for choosing the tick source of the kernel.
+    pub timer_caps: Optional<TimerCaps>,
+    /// The power management and reset registers of the ACPI FADT.
+    ///
+    /// This field is `None` if the firmware doesn't provide a FADT.
+    pub acpi_fast_info: Optional<AcpiFastInfo>,
+    /// The virtual address range of the kernel stack and its guard page.
+    pub kernel_stack: Optional<KernelStack>,
+    /// The thread local storage block of the boot CPU, which `FS` points to at kernel entry.
+    ///
+    /// This field is only set if the kernel has a TLS segment, see
+    /// [`tls_template`](Self::tls_template).
+    pub tls_block: Optional<TlsBlock>,
+    /// The physical address of the SMBIOS entry point structure, which can be used to find
+    /// the SMBIOS (DMI) tables.
+    ///
+    /// The 64-bit SMBIOS 3 entry point (anchor `_SM3_`) is preferred over the 32-bit one
+    /// (anchor `_SM_`), the anchor at the address tells which one it is. This field is `None`
+    /// if no entry point was found (for BIOS) or reported (for UEFI).
+    pub smbios_addr: Optional<u64>,
+    /// The size of the pages that map the physical memory at
+    /// [`physical_memory_offset`](Self::physical_memory_offset).
+    ///
+    /// The bootloader uses 1GiB pages if the CPU supports them and the offset is aligned to
+    /// 1GiB, and 2MiB pages otherwise. The mapping covers the physical address space up to the
+    /// end of the last page, so it can extend past the end of the physical memory. Only
+    /// available if the `map-physical-memory` config option is enabled.
+    pub physical_memory_page_size: Optional<u64>,
+    /// The keys of the encrypted partitions that the bootloader unlocked.
+    ///
+    /// The BIOS bootloader asks for the passphrase of every encrypted partition of the boot
+    /// disk, see `MbrPartition::set_passphrase` of the `bootloader` crate. Partitions whose
+    /// passphrase was not entered correctly are missing from the list. This field is `None`
+    /// if the boot disk has no encrypted partitions or none was unlocked. The kernel should
+    /// overwrite the keys once it has set up the decryption.
+    pub partition_keys: Optional<PartitionKeys>,
+    /// Loads files through the UEFI firmware and exits the boot services.
+    ///
+    /// Only set if the `uefi_callback` config option is enabled and the kernel was booted
+    /// through UEFI. See [`UefiCallback`] for the restrictions that apply until the kernel
+    /// calls [`UefiCallback::exit_boot_services`].
+    pub uefi_callback: Optional<UefiCallback>,
+}
+
+impl BootInfo {
+    /// Create a new boot info structure with the given memory map.
+    ///
+    /// The other fields are initialized with default values.
+    pub fn new(memory_regions: MemoryRegions) -> Self {
+        Self {
+            api_version: ApiVersion::new_default(),
+            abi_layout_hash: BootInfoAbi::current().layout_hash,
+            memory_regions,
+            framebuffer: Optional::None,
+            physical_memory_offset: Optional::None,
+            recursive_index: Optional::None,
+            rsdp_addr: Optional::None,
+            tls_template: Optional::None,
+            ramdisk_addr: Optional::None,
+            ramdisk_len: 0,
+            boot_device: Optional::None,
+            boot_metadata: Optional::None,
+            bootloader_heap: Optional::None,
+            boot_counter: Optional::None,
+            cpu_state: Optional::None,
+            msr_state: Optional::None,
+            iommus: Optional::None,
+            boot_log: Optional::None,
+            reclaimable_frames: Optional::None,
+            platform_registers: Optional::None,
+            uefi_runtime: Optional::None,
+            boot_slot: Optional::None,
+            command_line: Optional::None,
+            ramdisk_archive: Optional::None,
+            modules: Optional::None,
+            timer_caps: Optional::None,
+            acpi_fast_info: Optional::None,
+            kernel_stack: Optional::None,
+            tls_block: Optional::None,
+            smbios_addr: Optional::None,
+            physical_memory_page_size: Optional::None,
+            partition_keys: Optional::None,
+            uefi_callback: Optional::None,
+        }
+    }
+}
+
+/// FFI-safe slice of [`MemoryRegion`] structs, semantically equivalent to
+/// `&'static mut [MemoryRegion]`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] and [`DerefMut`][core::ops::DerefMut]
+/// traits, so it can be used like a `&mut [MemoryRegion]` slice. It also implements [`From`]
+/// and [`Into`] for easy conversions from and to `&'static mut [MemoryRegion]`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct MemoryRegions {
+    pub(crate) ptr: *mut MemoryRegion,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for MemoryRegions {
+    type Target = [MemoryRegion];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl ops::DerefMut for MemoryRegions {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static mut [MemoryRegion]> for MemoryRegions {
+    fn from(regions: &'static mut [MemoryRegion]) -> Self {
+        MemoryRegions {
+            ptr: regions.as_mut_ptr(),
+            len: regions.len(),
+        }
+    }
+}
+
+impl From<MemoryRegions> for &'static mut [MemoryRegion] {
+    fn from(regions: MemoryRegions) -> &'static mut [MemoryRegion] {
+        unsafe { slice::from_raw_parts_mut(regions.ptr, regions.len) }
+    }
+}
+
+/// Identifies the disk and partition that the kernel was loaded from.
+///
+/// Kernels can use this information to find their root file system without guessing.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootDevice {
+    /// The one-based number of the boot partition in the partition table of the disk.
+    pub partition_number: u32,
+    /// The signature of the boot partition (GPT) or of the disk containing it (MBR).
+    pub partition_signature: PartitionSignature,
+    /// Textual representation of the EFI device path of the boot partition, e.g.
+    /// `PciRoot(0x0)/Pci(0x3,0x0)/HD(1,GPT,...)`.
+    ///
+    /// Only available when booting through UEFI.
+    pub device_path: Optional<FfiStr>,
+}
+
+/// Signature that identifies a partition or the disk that contains it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum PartitionSignature {
+    /// The partition table does not provide a signature.
+    None,
+    /// The 32-bit disk signature of an MBR partitioned disk, as stored at offset 440 of the
+    /// master boot record.
+    Mbr(u32),
+    /// The unique partition GUID of a GPT partition, in its on-disk byte order.
+    Gpt([u8; 16]),
+}
+
+/// Usage statistics of the heap that the bootloader used for its own allocations.
+///
+/// The heap size is set through the `bootloader_heap_size` config option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootloaderHeap {
+    /// The size of the heap in bytes.
+    pub size: u64,
+    /// The maximum number of bytes that were allocated from the heap at the same time.
+    pub peak_usage: u64,
+    /// The physical start address of the heap.
+    ///
+    /// The heap memory is reported as [`MemoryRegionKind::BootloaderHeap`] in the memory map.
+    pub start: u64,
+}
+
+/// The virtual address range of the kernel stack.
+///
+/// The size and the address of the stack are set through the `kernel_stack_size` config
+/// option and the `kernel_stack` mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelStack {
+    /// The lowest address of the stack.
+    pub start: u64,
+    /// The end address of the stack (exclusive), which is the initial stack pointer of the
+    /// kernel.
+    pub end: u64,
+    /// The start address of the unmapped page below the stack.
+    ///
+    /// A stack overflow runs into this page and causes a page fault instead of silently
+    /// overwriting other memory.
+    pub guard_page: u64,
+}
+
+/// The state of the persistent boot failure counter.
+///
+/// The bootloader increments the counter before every boot attempt. The kernel should reset
+/// it by setting the `BootloaderFailedBoots` EFI variable (vendor GUID
+/// `d6f9a6b4-5a3e-4c7f-9e1b-2f8a0c3d4e51`) to `0` once the system booted successfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootCounter {
+    /// The number of boot attempts since the counter was last reset, excluding this one.
+    pub failed_boots: u8,
+    /// The configured number of failed boots after which the recovery kernel is started.
+    pub limit: u8,
+    /// Whether the bootloader started the recovery kernel instead of the regular kernel.
+    pub recovery: bool,
+}
+
+/// The A/B slot selection of the bootloader.
+///
+/// The update agent of the OS selects the slot to boot in the `BootloaderSlot` EFI variable
+/// (vendor GUID `d6f9a6b4-5a3e-4c7f-9e1b-2f8a0c3d4e51`). The variable consists of two bytes:
+/// the [`Slot`] and the number of remaining boot attempts for it. The bootloader starts the
+/// `kernel-x86_64-a` or `kernel-x86_64-b` kernel with the `ramdisk-a` or `ramdisk-b` ramdisk
+/// of the slot and decrements the remaining attempts. Once no attempts are left, it starts
+/// the other slot instead. After a successful boot, the agent should set the remaining
+/// attempts to `0xff`, which the bootloader never decrements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootSlot {
+    /// The slot that the update agent selected.
+    pub requested: Slot,
+    /// The slot whose kernel the bootloader started.
+    ///
+    /// This is the other slot if the requested slot has no boot attempts left.
+    pub booted: Slot,
+    /// The remaining boot attempts of the requested slot, after this boot.
+    pub attempts_remaining: u8,
+}
+
+/// A slot for A/B updates, see [`BootSlot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Slot {
+    /// Stored as `0` in the `BootloaderSlot` EFI variable.
+    A = 0,
+    /// Stored as `1` in the `BootloaderSlot` EFI variable.
+    B = 1,
+}
+
+impl Slot {
+    /// Returns the other slot.
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// Mitigation-relevant CPU features and model-specific registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CpuState {
+    /// The `EDX` register of CPUID leaf `7`, subleaf `0`.
+    ///
+    /// Enumerates e.g. `MD_CLEAR` (bit 10), `IBRS_IBPB` (bit 26), `STIBP` (bit 27),
+    /// `L1D_FLUSH` (bit 28), `ARCH_CAPABILITIES` (bit 29), and `SSBD` (bit 31) on Intel CPUs.
+    pub cpuid_7_edx: u32,
+    /// The `EBX` register of CPUID leaf `0x8000_0008`.
+    ///
+    /// Enumerates e.g. `IBPB` (bit 12), `IBRS` (bit 14), `STIBP` (bit 15), and `SSBD`
+    /// (bit 24) on AMD CPUs.
+    pub cpuid_8000_0008_ebx: u32,
+    /// The value of the `IA32_ARCH_CAPABILITIES` MSR, if supported.
+    pub arch_capabilities: Optional<u64>,
+    /// The value of the `IA32_SPEC_CTRL` MSR at kernel entry, if supported.
+    pub spec_ctrl: Optional<u64>,
+    /// Whether the bootloader issued an indirect branch prediction barrier.
+    pub indirect_branch_barrier: bool,
+}
+
+/// Virtualization and SMM related MSRs, as configured by the firmware.
+///
+/// Many of these MSRs are locked by the firmware, so the kernel can't change them. Checking
+/// them early helps to diagnose e.g. a failing `VMXON` instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MsrState {
+    /// Whether the CPU supports Intel VMX (`CPUID.1:ECX[5]`).
+    pub vmx_supported: bool,
+    /// Whether the CPU supports AMD SVM (`CPUID.8000_0001:ECX[2]`).
+    pub svm_supported: bool,
+    /// The value of the `IA32_FEATURE_CONTROL` MSR, if supported.
+    ///
+    /// Bit 0 is the lock bit, bit 2 enables VMX outside of SMX operation.
+    pub feature_control: Optional<u64>,
+    /// The value of the AMD `VM_CR` MSR, if SVM is supported.
+    ///
+    /// Bit 3 is the lock bit, bit 4 (`SVMDIS`) disables SVM.
+    pub vm_cr: Optional<u64>,
+    /// The value of the `IA32_MTRRCAP` MSR, if MTRRs are supported.
+    ///
+    /// Bit 11 indicates support for the system management range registers (SMRR). The SMRR
+    /// MSRs themselves are only accessible in SMM.
+    pub mtrr_cap: Optional<u64>,
+}
+
+impl MsrState {
+    /// Returns whether the `IA32_FEATURE_CONTROL` MSR is locked.
+    pub fn feature_control_locked(&self) -> bool {
+        matches!(self.feature_control, Optional::Some(value) if value & 1 != 0)
+    }
+
+    /// Returns whether `VMXON` can succeed outside of SMX operation, i.e. VMX is supported and
+    /// either enabled in `IA32_FEATURE_CONTROL` or not locked yet.
+    pub fn vmx_usable(&self) -> bool {
+        match self.feature_control {
+            Optional::Some(value) => {
+                self.vmx_supported && (value & 1 == 0 || value & (1 << 2) != 0)
+            }
+            Optional::None => false,
+        }
+    }
+
+    /// Returns whether SVM is supported and not disabled through the `VM_CR` MSR.
+    pub fn svm_usable(&self) -> bool {
+        match self.vm_cr {
+            Optional::Some(value) => self.svm_supported && value & (1 << 4) == 0,
+            Optional::None => false,
+        }
+    }
+
+    /// Returns whether the CPU supports system management range registers.
+    pub fn smrr_supported(&self) -> bool {
+        matches!(self.mtrr_cap, Optional::Some(value) if value & (1 << 11) != 0)
+    }
+}
+
+/// Timer features of the CPU, as reported by CPUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimerCaps {
+    /// Whether the time stamp counter runs at a constant rate in all power states
+    /// (`CPUID.8000_0007:EDX[8]`).
+    pub invariant_tsc: bool,
+    /// Whether the local APIC timer supports the TSC-deadline mode (`CPUID.1:ECX[24]`).
+    pub tsc_deadline: bool,
+    /// Whether the local APIC timer keeps running in deep C-states (`CPUID.6:EAX[2]`).
+    pub arat: bool,
+}
+
+impl TimerCaps {
+    /// Returns whether the TSC-deadline mode of the local APIC timer can be used as the only
+    /// tick source, i.e. the TSC is invariant and the timer keeps running in deep C-states.
+    pub fn tsc_deadline_usable(&self) -> bool {
+        self.invariant_tsc && self.tsc_deadline && self.arat
+    }
+}
+
+/// A fixed-capacity list of the DMA remapping hardware units of the system.
+///
+/// This type implements the [`Deref`][core::ops::Deref] and [`DerefMut`][core::ops::DerefMut]
+/// traits, so it can be used like a `&mut [Iommu]` slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Iommus {
+    units: [Iommu; Iommus::MAX_UNITS],
+    len: usize,
+}
+
+impl Iommus {
+    /// The maximum number of units. Further units are ignored by the bootloader.
+    pub const MAX_UNITS: usize = 16;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            units: [Iommu {
+                kind: IommuKind::IntelVtd,
+                pci_segment: 0,
+                register_base: 0,
+                register_len: 0,
+                register_addr: Optional::None,
+            }; Self::MAX_UNITS],
+            len: 0,
+        }
+    }
+
+    /// Appends the given unit, or returns it back if the list is full.
+    pub fn push(&mut self, unit: Iommu) -> Result<(), Iommu> {
+        match self.units.get_mut(self.len) {
+            Some(slot) => {
+                *slot = unit;
+                self.len += 1;
+                Ok(())
+            }
+            None => Err(unit),
+        }
+    }
+}
+
+impl Default for Iommus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for Iommus {
+    type Target = [Iommu];
+
+    fn deref(&self) -> &Self::Target {
+        &self.units[..self.len]
+    }
+}
+
+impl ops::DerefMut for Iommus {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.units[..self.len]
+    }
+}
+
+/// A DMA remapping hardware unit (IOMMU).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Iommu {
+    /// The type of the unit.
+    pub kind: IommuKind,
+    /// The PCI segment of the devices that the unit translates for.
+    pub pci_segment: u16,
+    /// The physical base address of the register set.
+    pub register_base: u64,
+    /// The size of the register set in bytes.
+    pub register_len: u64,
+    /// The virtual address at which the bootloader mapped the register set, if the
+    /// `iommu_registers` config option is set.
+    ///
+    /// The registers are mapped as uncacheable and non-executable.
+    pub register_addr: Optional<u64>,
+}
+
+/// The type of a DMA remapping hardware unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+#[non_exhaustive]
+pub enum IommuKind {
+    /// An Intel VT-d remapping hardware unit, described by a DRHD structure of the DMAR table.
+    IntelVtd,
+    /// An AMD IOMMU, described by an IVHD block of the IVRS table.
+    AmdVi,
+}
+
+/// The interrupt controller and timer registers of the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PlatformRegisters {
+    /// The registers of the local APIC of the bootstrap processor.
+    pub local_apic: MmioRegisters,
+    /// The I/O APICs.
+    pub io_apics: IoApics,
+    /// The registers of the HPET, if the firmware provides an HPET table.
+    pub hpet: Optional<MmioRegisters>,
+}
+
+/// A memory-mapped register set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MmioRegisters {
+    /// The physical base address of the registers.
+    pub base: u64,
+    /// The size of the register set in bytes.
+    pub len: u64,
+    /// The virtual address at which the bootloader mapped the registers, if the
+    /// `platform_registers` config option is set.
+    ///
+    /// The registers are mapped as uncacheable and non-executable.
+    pub addr: Optional<u64>,
+}
+
+impl MmioRegisters {
+    /// Creates an unmapped register set.
+    pub const fn new(base: u64, len: u64) -> Self {
+        Self {
+            base,
+            len,
+            addr: Optional::None,
+        }
+    }
+}
+
+/// A fixed-capacity list of the I/O APICs of the system.
+///
+/// This type implements the [`Deref`][core::ops::Deref] and [`DerefMut`][core::ops::DerefMut]
+/// traits, so it can be used like a `&mut [IoApic]` slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct IoApics {
+    io_apics: [IoApic; IoApics::MAX_IO_APICS],
+    len: usize,
+}
+
+impl IoApics {
+    /// The maximum number of I/O APICs. Further I/O APICs are ignored by the bootloader.
+    pub const MAX_IO_APICS: usize = 16;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            io_apics: [IoApic {
+                id: 0,
+                gsi_base: 0,
+                registers: MmioRegisters::new(0, 0),
+            }; Self::MAX_IO_APICS],
+            len: 0,
+        }
+    }
+
+    /// Appends the given I/O APIC, or returns it back if the list is full.
+    pub fn push(&mut self, io_apic: IoApic) -> Result<(), IoApic> {
+        match self.io_apics.get_mut(self.len) {
+            Some(slot) => {
+                *slot = io_apic;
+                self.len += 1;
+                Ok(())
+            }
+            None => Err(io_apic),
+        }
+    }
+}
+
+impl Default for IoApics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for IoApics {
+    type Target = [IoApic];
+
+    fn deref(&self) -> &Self::Target {
+        &self.io_apics[..self.len]
+    }
+}
+
+impl ops::DerefMut for IoApics {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.io_apics[..self.len]
+    }
+}
+
+/// An I/O APIC, described by an entry of the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct IoApic {
+    /// The I/O APIC ID.
+    pub id: u8,
+    /// The first global system interrupt that the I/O APIC handles.
+    pub gsi_base: u32,
+    /// The registers of the I/O APIC.
+    pub registers: MmioRegisters,
+}
+
+/// The fields of the ACPI FADT (fixed ACPI description table) that are needed to power off
+/// or reset the system and to read the century from the RTC, without an AML interpreter.
+///
+/// To power off, write `SLP_TYPx << 10 | 1 << 13` (`SLP_EN`) to the PM1a and, if present,
+/// PM1b control blocks, using the values of [`sleep_type_s5`](Self::sleep_type_s5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AcpiFastInfo {
+    /// The PM1a control block (`X_PM1a_CNT_BLK`, or `PM1a_CNT_BLK` on older firmware).
+    pub pm1a_control_block: GenericAddress,
+    /// The PM1b control block, if the system has one.
+    pub pm1b_control_block: Optional<GenericAddress>,
+    /// The `SLP_TYPa` and `SLP_TYPb` values of the `\_S5` (soft off) sleep state.
+    ///
+    /// The bootloader doesn't interpret AML, it only recognizes the common encoding of the
+    /// `\_S5` package in the DSDT. This field is `None` if the package isn't found.
+    pub sleep_type_s5: Optional<[u8; 2]>,
+    /// The reset register, if the FADT sets the `RESET_REG_SUP` flag.
+    pub reset: Optional<ResetRegister>,
+    /// The index of the century register in the RTC CMOS RAM, if the RTC has one.
+    pub century: Optional<u8>,
+}
+
+/// A register that resets the system when [`value`](Self::value) is written to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ResetRegister {
+    /// The location of the register.
+    pub register: GenericAddress,
+    /// The value that triggers the reset.
+    pub value: u8,
+}
+
+/// An ACPI generic address structure, which describes the location of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct GenericAddress {
+    /// The address space of the register, e.g. [`SYSTEM_IO`](Self::SYSTEM_IO).
+    pub address_space: u8,
+    /// The size of the register in bits.
+    pub bit_width: u8,
+    /// The offset of the register within the addressed location in bits.
+    pub bit_offset: u8,
+    /// The access size (`1` = byte, `2` = word, `3` = dword, `4` = qword, `0` = undefined).
+    pub access_size: u8,
+    /// The physical address or I/O port of the register.
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// The address space ID of memory-mapped registers.
+    pub const SYSTEM_MEMORY: u8 = 0;
+    /// The address space ID of I/O ports.
+    pub const SYSTEM_IO: u8 = 1;
+    /// The address space ID of the PCI configuration space.
+    pub const PCI_CONFIGURATION: u8 = 2;
+}
+
+/// The UEFI runtime services, which stay available after the bootloader exited the boot
+/// services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct UefiRuntime {
+    /// The physical address of the UEFI system table.
+    pub system_table: u64,
+    /// Whether the firmware provides an `EFI_MEMORY_ATTRIBUTES_TABLE`.
+    ///
+    /// If it does, the runtime services code regions in [`regions`](Self::regions) are
+    /// replaced by the table entries, which split them into read-only code and non-executable
+    /// data. Otherwise, runtime services code regions are writable and executable as a whole.
+    pub memory_attributes_table: bool,
+    /// The offset of the virtual addresses of the regions from their physical addresses.
+    ///
+    /// Only set if the `uefi_runtime_services` config option is set.
+    pub virtual_offset: Optional<u64>,
+    /// The virtual address of the UEFI system table after the bootloader switched the runtime
+    /// services to virtual addressing.
+    ///
+    /// Only set if the `set_uefi_virtual_address_map` config option is set and the
+    /// `SetVirtualAddressMap` call succeeded. The runtime services must then be called through
+    /// this table, at the addresses given by [`virtual_offset`](Self::virtual_offset).
+    pub virtual_system_table: Optional<u64>,
+    /// The memory regions that the runtime services use.
+    pub regions: UefiRuntimeRegions,
+}
+
+/// A fixed-capacity list of the memory regions of the UEFI runtime services.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[UefiRuntimeRegion]` slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct UefiRuntimeRegions {
+    regions: [UefiRuntimeRegion; UefiRuntimeRegions::MAX_REGIONS],
+    len: usize,
+}
+
+impl UefiRuntimeRegions {
+    /// The maximum number of regions. Further regions are ignored by the bootloader.
+    pub const MAX_REGIONS: usize = 128;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            regions: [UefiRuntimeRegion {
+                phys_start: 0,
+                page_count: 0,
+                memory_type: 0,
+                attribute: 0,
+                writable: false,
+                executable: false,
+            }; Self::MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Appends the given region, or returns it back if the list is full.
+    pub fn push(&mut self, region: UefiRuntimeRegion) -> Result<(), UefiRuntimeRegion> {
+        match self.regions.get_mut(self.len) {
+            Some(slot) => {
+                *slot = region;
+                self.len += 1;
+                Ok(())
+            }
+            None => Err(region),
+        }
+    }
+}
+
+impl Default for UefiRuntimeRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for UefiRuntimeRegions {
+    type Target = [UefiRuntimeRegion];
+
+    fn deref(&self) -> &Self::Target {
+        &self.regions[..self.len]
+    }
+}
+
+/// A memory region of the UEFI runtime services, described by an EFI memory descriptor of the
+/// memory map or the `EFI_MEMORY_ATTRIBUTES_TABLE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct UefiRuntimeRegion {
+    /// The physical start address of the region, which is page-aligned.
+    pub phys_start: u64,
+    /// The number of 4KiB pages of the region.
+    pub page_count: u64,
+    /// The EFI memory type, e.g. `5` for runtime services code or `11` for memory-mapped I/O.
+    pub memory_type: u32,
+    /// The EFI memory attributes, e.g. `EFI_MEMORY_XP` (`0x4000`) or `EFI_MEMORY_RO`
+    /// (`0x20000`).
+    pub attribute: u64,
+    /// Whether the region is mapped writable.
+    pub writable: bool,
+    /// Whether the region is mapped executable.
+    pub executable: bool,
+}
+
+/// A fixed-capacity list of physical frame extents, sorted by address.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[FrameExtent]` slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FrameExtents {
+    extents: [FrameExtent; FrameExtents::MAX_EXTENTS],
+    len: usize,
+}
+
+impl FrameExtents {
+    /// The maximum number of extents.
+    pub const MAX_EXTENTS: usize = 64;
+    /// The size of a frame in bytes.
+    pub const FRAME_SIZE: u64 = 4096;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            extents: [FrameExtent {
+                start: 0,
+                frame_count: 0,
+            }; Self::MAX_EXTENTS],
+            len: 0,
+        }
+    }
+
+    /// Adds the frame that starts at the given address, merging it with adjacent extents.
+    ///
+    /// Returns `false` if the frame doesn't border an existing extent and the list is full.
+    pub fn insert_frame(&mut self, addr: u64) -> bool {
+        let end = addr + Self::FRAME_SIZE;
+        let index = self.extents[..self.len]
+            .iter()
+            .position(|extent| extent.start > addr)
+            .unwrap_or(self.len);
+        let merge_prev = match index.checked_sub(1).map(|i| self.extents[i]) {
+            Some(prev) if prev.end() > addr => return true,
+            Some(prev) => prev.end() == addr,
+            None => false,
+        };
+        let merge_next = index < self.len && self.extents[index].start == end;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.extents[index - 1].frame_count += 1 + self.extents[index].frame_count;
+                self.extents.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+            }
+            (true, false) => self.extents[index - 1].frame_count += 1,
+            (false, true) => {
+                self.extents[index].start = addr;
+                self.extents[index].frame_count += 1;
+            }
+            (false, false) => {
+                if self.len == Self::MAX_EXTENTS {
+                    return false;
+                }
+                self.extents.copy_within(index..self.len, index + 1);
+                self.extents[index] = FrameExtent {
+                    start: addr,
+                    frame_count: 1,
+                };
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    /// Returns the total number of frames in all extents.
+    pub fn frame_count(&self) -> u64 {
+        self.iter().map(|extent| extent.frame_count).sum()
+    }
+}
+
+impl Default for FrameExtents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for FrameExtents {
+    type Target = [FrameExtent];
+
+    fn deref(&self) -> &Self::Target {
+        &self.extents[..self.len]
+    }
+}
+
+/// A range of physically contiguous 4KiB frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct FrameExtent {
+    /// The physical start address of the first frame.
+    pub start: u64,
+    /// The number of frames.
+    pub frame_count: u64,
+}
+
+impl FrameExtent {
+    /// Returns the physical end address (exclusive).
+    pub fn end(&self) -> u64 {
+        self.start + self.frame_count * FrameExtents::FRAME_SIZE
+    }
+}
+
+/// Identifies the bootloader and kernel that a boot image was created with.
+///
+/// The disk image builder stores this information in a small metadata block next to the
+/// kernel. The bootloader verifies the block against the loaded kernel before passing it on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootMetadata {
+    /// The version of the bootloader that created the boot image.
+    pub bootloader_version: ApiVersion,
+    /// SHA-256 hash of the serialized bootloader configuration of the kernel.
+    pub config_hash: [u8; 32],
+    /// SHA-256 hash of the kernel executable.
+    pub kernel_hash: [u8; 32],
+    /// Additional virtual mappings that the bootloader set up for the kernel.
+    ///
+    /// These are read from the `extra-mappings` array of the `[package.metadata.bootloader]`
+    /// table of the kernel's `Cargo.toml`.
+    pub extra_mappings: ExtraMappings,
+    /// The command line for the kernel, also reported in
+    /// [`BootInfo::command_line`].
+    pub command_line: CommandLine,
+    /// The log levels that replace [`BootloaderConfig::log_levels`] once the block is
+    /// verified.
+    ///
+    /// These are read from the `log-levels` table of the `[package.metadata.bootloader]`
+    /// table of the kernel's `Cargo.toml`.
+    ///
+    /// [`BootloaderConfig::log_levels`]: crate::BootloaderConfig::log_levels
+    pub log_levels: Optional<LogLevels>,
+}
+
+impl BootMetadata {
+    /// The length of the serialized metadata block, in bytes.
+    pub const SERIALIZED_LEN: usize = 1024;
+
+    const MAGIC: [u8; 8] = *b"BLMETA02";
+    const CHECKSUM_OFFSET: usize = Self::SERIALIZED_LEN - 4;
+    const LOG_LEVELS_OFFSET: usize = 81;
+    const EXTRA_MAPPINGS_OFFSET: usize = 88;
+    const EXTRA_MAPPING_LEN: usize = 32;
+    const COMMAND_LINE_LEN_OFFSET: usize = 344;
+    const COMMAND_LINE_OFFSET: usize = 352;
+
+    /// Creates metadata for the given hashes, using the version of this crate.
+    pub fn new(config_hash: [u8; 32], kernel_hash: [u8; 32]) -> Self {
+        Self {
+            bootloader_version: ApiVersion::new_default(),
+            config_hash,
+            kernel_hash,
+            extra_mappings: ExtraMappings::new(),
+            command_line: CommandLine::new(),
+            log_levels: Optional::None,
+        }
+    }
+
+    /// Serializes the metadata to a checksummed block.
+    pub fn serialize(&self) -> [u8; Self::SERIALIZED_LEN] {
+        let version = &self.bootloader_version;
+        let mut block = [0; Self::SERIALIZED_LEN];
+        block[0..8].copy_from_slice(&Self::MAGIC);
+        block[8..10].copy_from_slice(&version.version_major().to_le_bytes());
+        block[10..12].copy_from_slice(&version.version_minor().to_le_bytes());
+        block[12..14].copy_from_slice(&version.version_patch().to_le_bytes());
+        block[14] = version.pre_release() as u8;
+        block[16..48].copy_from_slice(&self.config_hash);
+        block[48..80].copy_from_slice(&self.kernel_hash);
+        block[80] = self.extra_mappings.len() as u8;
+        if let Optional::Some(levels) = self.log_levels {
+            block[Self::LOG_LEVELS_OFFSET] = 1;
+            block[Self::LOG_LEVELS_OFFSET + 1..][..6].copy_from_slice(&levels.serialize());
+        }
+        for (i, mapping) in self.extra_mappings.iter().enumerate() {
+            let entry = &mut block[Self::EXTRA_MAPPINGS_OFFSET + i * Self::EXTRA_MAPPING_LEN..]
+                [..Self::EXTRA_MAPPING_LEN];
+            entry[0..8].copy_from_slice(&mapping.phys_start.to_le_bytes());
+            entry[8..16].copy_from_slice(&mapping.virt_start.to_le_bytes());
+            entry[16..24].copy_from_slice(&mapping.len.to_le_bytes());
+            entry[24] = mapping.writable as u8 | (mapping.executable as u8) << 1;
+            entry[25] = mapping.caching as u8;
+        }
+        let command_line = self.command_line.as_bytes();
+        block[Self::COMMAND_LINE_LEN_OFFSET..][..2]
+            .copy_from_slice(&(command_line.len() as u16).to_le_bytes());
+        block[Self::COMMAND_LINE_OFFSET..][..command_line.len()].copy_from_slice(command_line);
+        let checksum = crc32(&block[..Self::CHECKSUM_OFFSET]);
+        block[Self::CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+        block
+    }
+
+    /// Parses a serialized metadata block and verifies its checksum.
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, &'static str> {
+        if serialized.len() != Self::SERIALIZED_LEN {
+            return Err("invalid len");
+        }
+        if serialized[0..8] != Self::MAGIC {
+            return Err("invalid magic");
+        }
+        let (data, checksum) = serialized.split_at(Self::CHECKSUM_OFFSET);
+        if checksum != crc32(data).to_le_bytes() {
+            return Err("invalid checksum");
+        }
+
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u64 = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let pre_release = match data[14] {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid pre version"),
+        };
+        let mut config_hash = [0; 32];
+        config_hash.copy_from_slice(&data[16..48]);
+        let mut kernel_hash = [0; 32];
+        kernel_hash.copy_from_slice(&data[48..80]);
+
+        let log_levels = match data[Self::LOG_LEVELS_OFFSET] {
+            0 => Optional::None,
+            1 => {
+                let mut levels = [0; 6];
+                levels.copy_from_slice(&data[Self::LOG_LEVELS_OFFSET + 1..][..6]);
+                Optional::Some(LogLevels::deserialize(&levels)?)
+            }
+            _ => return Err("invalid log levels flag"),
+        };
+
+        let mut extra_mappings = ExtraMappings::new();
+        if usize::from(data[80]) > ExtraMappings::MAX_MAPPINGS {
+            return Err("too many extra mappings");
+        }
+        for i in 0..usize::from(data[80]) {
+            let offset = Self::EXTRA_MAPPINGS_OFFSET + i * Self::EXTRA_MAPPING_LEN;
+            let flags = data[offset + 24];
+            if flags & !0b11 != 0 {
+                return Err("invalid extra mapping flags");
+            }
+            let caching = match data[offset + 25] {
+                0 => Caching::WriteBack,
+                1 => Caching::WriteThrough,
+                2 => Caching::Uncacheable,
+                _ => return Err("invalid extra mapping caching"),
+            };
+            extra_mappings.push(ExtraMapping {
+                phys_start: read_u64(offset),
+                virt_start: read_u64(offset + 8),
+                len: read_u64(offset + 16),
+                writable: flags & 0b01 != 0,
+                executable: flags & 0b10 != 0,
+                caching,
+            });
+        }
+
+        let command_line_len = usize::from(read_u16(Self::COMMAND_LINE_LEN_OFFSET));
+        if command_line_len > CommandLine::MAX_LEN {
+            return Err("command line too long");
+        }
+        let command_line = str::from_utf8(&data[Self::COMMAND_LINE_OFFSET..][..command_line_len])
+            .map_err(|_| "command line is not valid UTF-8")?;
+        let mut command_line_buf = CommandLine::new();
+        command_line_buf.set(command_line);
+
+        Ok(Self {
+            bootloader_version: ApiVersion::from_parts(
+                read_u16(8),
+                read_u16(10),
+                read_u16(12),
+                pre_release,
+            ),
+            config_hash,
+            kernel_hash,
+            extra_mappings,
+            command_line: command_line_buf,
+            log_levels,
+        })
+    }
+}
+
+/// A fixed-capacity UTF-8 string that holds the kernel command line.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&str`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CommandLine {
+    bytes: [u8; CommandLine::MAX_LEN],
+    len: usize,
+}
+
+impl CommandLine {
+    /// The maximum length of the command line in bytes.
+    pub const MAX_LEN: usize = 512;
+
+    /// Creates an empty command line.
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; Self::MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// Replaces the command line with the given string.
+    ///
+    /// Returns `false` and leaves the command line unchanged if the string is longer than
+    /// [`Self::MAX_LEN`].
+    pub fn set(&mut self, line: &str) -> bool {
+        if line.len() > Self::MAX_LEN {
+            return false;
+        }
+        self.bytes = [0; Self::MAX_LEN];
+        self.bytes[..line.len()].copy_from_slice(line.as_bytes());
+        self.len = line.len();
+        true
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for CommandLine {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        // only set from `&str` values
+        unsafe { str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+impl fmt::Debug for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A fixed-capacity list of additional virtual mappings.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[ExtraMapping]` slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExtraMappings {
+    mappings: [ExtraMapping; ExtraMappings::MAX_MAPPINGS],
+    len: usize,
+}
+
+impl ExtraMappings {
+    /// The maximum number of mappings.
+    pub const MAX_MAPPINGS: usize = 8;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            mappings: [ExtraMapping {
+                phys_start: 0,
+                virt_start: 0,
+                len: 0,
+                writable: false,
+                executable: false,
+                caching: Caching::WriteBack,
+            }; Self::MAX_MAPPINGS],
+            len: 0,
+        }
+    }
+
+    /// Appends the given mapping.
+    ///
+    /// Returns `false` if the list is full.
+    pub fn push(&mut self, mapping: ExtraMapping) -> bool {
+        if self.len == Self::MAX_MAPPINGS {
+            return false;
+        }
+        self.mappings[self.len] = mapping;
+        self.len += 1;
+        true
+    }
+}
+
+impl Default for ExtraMappings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for ExtraMappings {
+    type Target = [ExtraMapping];
+
+    fn deref(&self) -> &Self::Target {
+        &self.mappings[..self.len]
+    }
+}
+
+/// The unlocked keys of encrypted partitions, see [`BootInfo::partition_keys`].
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[PartitionKey]` slice.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PartitionKeys {
+    keys: [PartitionKey; PartitionKeys::MAX_KEYS],
+    len: usize,
+}
+
+impl PartitionKeys {
+    /// The maximum number of keys, which is the number of partitions that the BIOS
+    /// bootloader leaves for other uses.
+    pub const MAX_KEYS: usize = 2;
+
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            keys: [PartitionKey {
+                partition_number: 0,
+                payload_offset: 0,
+                payload_sectors: 0,
+                master_key: [0; 64],
+            }; Self::MAX_KEYS],
+            len: 0,
+        }
+    }
+
+    /// Appends the given key.
+    ///
+    /// Returns `false` if the list is full.
+    pub fn push(&mut self, key: PartitionKey) -> bool {
+        if self.len == Self::MAX_KEYS {
+            return false;
+        }
+        self.keys[self.len] = key;
+        self.len += 1;
+        true
+    }
+}
+
+impl Default for PartitionKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ops::Deref for PartitionKeys {
+    type Target = [PartitionKey];
+
+    fn deref(&self) -> &Self::Target {
+        &self.keys[..self.len]
+    }
+}
+
+impl fmt::Debug for PartitionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// The master key of an encrypted partition.
+///
+/// The payload of the partition is encrypted with AES-256-XTS in 512-byte sectors, using the
+/// sector number relative to the payload start as tweak (`plain64` in dm-crypt terms).
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PartitionKey {
+    /// The one-based index of the partition in the MBR partition table.
+    pub partition_number: u32,
+    /// The start of the encrypted payload, in 512-byte sectors from the partition start.
+    pub payload_offset: u32,
+    /// The length of the encrypted payload in 512-byte sectors.
+    pub payload_sectors: u64,
+    /// The AES-256-XTS key: the 32-byte data key followed by the 32-byte tweak key.
+    pub master_key: [u8; 64],
+}
+
+impl fmt::Debug for PartitionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartitionKey")
+            .field("partition_number", &self.partition_number)
+            .field("payload_offset", &self.payload_offset)
+            .field("payload_sectors", &self.payload_sectors)
+            .field("master_key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Loads files from the boot device before the UEFI boot services are exited, see the
+/// `uefi_callback` config option.
+///
+/// With that option, the UEFI bootloader jumps to the kernel while the firmware still owns
+/// its drivers and most of the memory. Each call switches back to the page tables, GDT, IDT,
+/// and stack of the firmware, runs a routine of the bootloader, and then restores the state
+/// of the kernel. Until the kernel calls [`exit_boot_services`](Self::exit_boot_services), it
+/// must therefore:
+///
+/// - keep interrupts disabled and leave the interrupt controllers and the devices of the
+///   firmware alone,
+/// - keep the identity mapping of the callback that the bootloader created in the kernel page
+///   tables,
+/// - only use memory of the kinds [`MemoryRegionKind::Usable`],
+///   [`MemoryRegionKind::BootInfo`], [`MemoryRegionKind::Ramdisk`], and
+///   [`MemoryRegionKind::BootloaderHeap`], and not reclaim the
+///   [`reclaimable_frames`](BootInfo::reclaimable_frames).
+///
+/// The memory map is created before the boot services are exited. Memory that the firmware
+/// can still allocate from is reported as [`MemoryRegionKind::UnknownUefi`] with its UEFI
+/// memory type, see [`Self::RECLAIMABLE_UEFI_MEMORY_TYPES`]. The rest of the memory of the
+/// bootloader is reported as [`MemoryRegionKind::Bootloader`] and stays in use, because the
+/// callback needs it. Usable memory is limited to a pool that the bootloader allocated for the
+/// kernel, so the kernel should exit the boot services early.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct UefiCallback {
+    context: u64,
+    entry: unsafe extern "sysv64" fn(u64, u64, *const u8, usize) -> LoadedFile,
+}
+
+impl fmt::Debug for UefiCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UefiCallback")
+            .field("context", &format_args!("{:#x}", self.context))
+            .field("entry", &(self.entry as *const u8))
+            .finish()
+    }
+}
+
+impl UefiCallback {
+    /// The maximum length of a file name in bytes.
+    pub const MAX_FILE_NAME_LEN: usize = 255;
+    /// The UEFI memory types that the kernel can use after it exited the boot services:
+    /// boot services code and data, and conventional memory.
     ///
-    /// Physical addresses can be converted to virtual addresses by adding this offset to them.
+    /// Regions of these types are reported as [`MemoryRegionKind::UnknownUefi`]. They also
+    /// contain the files returned by [`load_file`](Self::load_file).
+    pub const RECLAIMABLE_UEFI_MEMORY_TYPES: [u32; 3] = [3, 4, 7];
+
+    #[doc(hidden)]
+    pub const LOAD_FILE: u64 = 0;
+    #[doc(hidden)]
+    pub const EXIT_BOOT_SERVICES: u64 = 1;
+
+    /// Creates a callback that calls `entry` with the given context.
     ///
-    /// The mapping of the physical memory allows to access arbitrary physical frames. Accessing
-    /// frames that are also mapped at other virtual addresses can easily break memory safety and
-    /// cause undefined behavior. Only frames reported as `USABLE` by the memory map in the `BootInfo`
-    /// can be safely accessed.
+    /// ## Safety
     ///
-    /// Only available if the `map-physical-memory` config option is enabled.
-    pub physical_memory_offset: Optional<u64>,
-    /// The virtual address of the recursively mapped level 4 page table.
+    /// The `entry` function must be callable from the kernel with the given context and must
+    /// preserve the state of the kernel.
+    pub unsafe fn new(
+        context: u64,
+        entry: unsafe extern "sysv64" fn(u64, u64, *const u8, usize) -> LoadedFile,
+    ) -> Self {
+        Self { context, entry }
+    }
+
+    /// Loads the file with the given name from the device that the kernel was loaded from.
     ///
-    /// Only available if the `map-page-table-recursively` config option is enabled.
-    pub recursive_index: Optional<u16>,
-    /// The address of the `RSDP` data structure, which can be use to find the ACPI tables.
+    /// The name is the path on the boot device, e.g. `drivers/net.bin`, and must not be
+    /// longer than [`MAX_FILE_NAME_LEN`](Self::MAX_FILE_NAME_LEN). The file is loaded into
+    /// memory that the firmware allocates, or stays in the bootloader image if the files are
+    /// embedded into it, and the kernel can access it through the physical memory mapping.
+    /// Returns `None` if the file doesn't exist, or
+    /// if the boot services were already exited. The bootloader doesn't verify the file, even
+    /// if the kernel was signed.
     ///
-    /// This field is `None` if no `RSDP` was found (for BIOS) or reported (for UEFI).
-    pub rsdp_addr: Optional<u64>,
-    /// The thread local storage (TLS) template of the kernel executable, if present.
-    pub tls_template: Optional<TlsTemplate>,
-    /// Ramdisk address, if loaded
-    pub ramdisk_addr: Optional<u64>,
-    /// Ramdisk image size, set to 0 if addr is None
-    pub ramdisk_len: u64,
+    /// ## Safety
+    ///
+    /// The restrictions listed in the [type documentation](Self) must be fulfilled.
+    pub unsafe fn load_file(&self, name: &str) -> Option<LoadedFile> {
+        if name.is_empty() || name.len() > Self::MAX_FILE_NAME_LEN {
+            return None;
+        }
+        let file =
+            unsafe { (self.entry)(self.context, Self::LOAD_FILE, name.as_ptr(), name.len()) };
+        (file.addr != 0).then_some(file)
+    }
+
+    /// Exits the UEFI boot services.
+    ///
+    /// Afterwards, the kernel can use the memory of the
+    /// [`RECLAIMABLE_UEFI_MEMORY_TYPES`](Self::RECLAIMABLE_UEFI_MEMORY_TYPES), except for the
+    /// loaded files that it still needs, and the restrictions of the
+    /// [type documentation](Self) no longer apply. Returns `false` if exiting the boot
+    /// services failed or if they were already exited.
+    ///
+    /// ## Safety
+    ///
+    /// The restrictions listed in the [type documentation](Self) must be fulfilled.
+    pub unsafe fn exit_boot_services(&self) -> bool {
+        let result =
+            unsafe { (self.entry)(self.context, Self::EXIT_BOOT_SERVICES, ptr::null(), 0) };
+        result.addr != 0
+    }
 }
 
-impl BootInfo {
-    /// Create a new boot info structure with the given memory map.
+/// A file that [`UefiCallback::load_file`] loaded into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct LoadedFile {
+    /// The physical start address of the file content.
+    pub addr: u64,
+    /// The length of the file in bytes.
+    pub len: u64,
+}
+
+/// A physical address range that is mapped at a fixed virtual address for the kernel, e.g.
+/// the registers of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExtraMapping {
+    /// The page-aligned physical start address.
+    pub phys_start: u64,
+    /// The page-aligned virtual start address.
+    pub virt_start: u64,
+    /// The length of the range in bytes.
+    pub len: u64,
+    /// Whether the kernel can write to the mapping.
+    pub writable: bool,
+    /// Whether the kernel can execute code from the mapping.
+    pub executable: bool,
+    /// The caching mode of the mapping.
+    pub caching: Caching,
+}
+
+/// The caching mode of an [`ExtraMapping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Caching {
+    /// Normal cached memory.
+    WriteBack,
+    /// Reads are cached, writes go directly to memory.
+    WriteThrough,
+    /// No caching, for device registers.
+    Uncacheable,
+}
+
+/// Bitwise CRC-32 (IEEE), which is fast enough for a single metadata block.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Describes the binary interface of [`BootInfo`].
+///
+/// The bootloader embeds the serialized ABI description of the `bootloader_api` version that it
+/// was compiled with, which allows external tools to check whether a bootloader executable is
+/// compatible with a kernel without booting it. On UEFI, it is stored in the `.bootabi` PE
+/// section of the bootloader executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfoAbi {
+    /// The version of the `bootloader_api` crate.
+    pub api_version: ApiVersion,
+    /// The size of the `BootInfo` struct in bytes.
+    pub boot_info_size: u32,
+    /// The alignment of the `BootInfo` struct in bytes.
+    pub boot_info_align: u32,
+    /// A hash over the sizes and alignments of all fields of `BootInfo` and the types that it
+    /// references.
     ///
-    /// The other fields are initialized with default values.
-    pub fn new(memory_regions: MemoryRegions) -> Self {
+    /// Since all these types are `#[repr(C)]`, the field offsets follow from this information,
+    /// so two crate versions with equal hashes agree on the memory layout.
+    pub layout_hash: u64,
+}
+
+impl BootInfoAbi {
+    /// The length of the serialized ABI description, in bytes.
+    pub const SERIALIZED_LEN: usize = 32;
+
+    const MAGIC: [u8; 8] = *b"BOOTABI1";
+
+    /// Returns the ABI description of this crate version.
+    pub const fn current() -> Self {
         Self {
             api_version: ApiVersion::new_default(),
-            memory_regions,
-            framebuffer: Optional::None,
-            physical_memory_offset: Optional::None,
-            recursive_index: Optional::None,
-            rsdp_addr: Optional::None,
-            tls_template: Optional::None,
-            ramdisk_addr: Optional::None,
-            ramdisk_len: 0,
+            boot_info_size: core::mem::size_of::<BootInfo>() as u32,
+            boot_info_align: core::mem::align_of::<BootInfo>() as u32,
+            layout_hash: layout_hash(),
+        }
+    }
+
+    /// Serializes the ABI description to a byte array.
+    pub const fn serialize(&self) -> [u8; Self::SERIALIZED_LEN] {
+        let version = &self.api_version;
+        let fields: [&[u8]; 8] = [
+            &Self::MAGIC,
+            &version.version_major().to_le_bytes(),
+            &version.version_minor().to_le_bytes(),
+            &version.version_patch().to_le_bytes(),
+            &[version.pre_release() as u8, 0],
+            &self.boot_info_size.to_le_bytes(),
+            &self.boot_info_align.to_le_bytes(),
+            &self.layout_hash.to_le_bytes(),
+        ];
+
+        let mut serialized = [0; Self::SERIALIZED_LEN];
+        let mut offset = 0;
+        let mut field = 0;
+        while field < fields.len() {
+            let mut i = 0;
+            while i < fields[field].len() {
+                serialized[offset] = fields[field][i];
+                offset += 1;
+                i += 1;
+            }
+            field += 1;
+        }
+        serialized
+    }
+
+    /// Parses a serialized ABI description.
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, &'static str> {
+        if serialized.len() != Self::SERIALIZED_LEN {
+            return Err("invalid len");
         }
+        if serialized[0..8] != Self::MAGIC {
+            return Err("invalid magic");
+        }
+        let read_u16 =
+            |offset: usize| u16::from_le_bytes([serialized[offset], serialized[offset + 1]]);
+        let read_u32 = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&serialized[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let pre_release = match serialized[14] {
+            0 => false,
+            1 => true,
+            _ => return Err("invalid pre version"),
+        };
+        let mut layout_hash = [0; 8];
+        layout_hash.copy_from_slice(&serialized[24..32]);
+
+        Ok(Self {
+            api_version: ApiVersion::from_parts(
+                read_u16(8),
+                read_u16(10),
+                read_u16(12),
+                pre_release,
+            ),
+            boot_info_size: read_u32(16),
+            boot_info_align: read_u32(20),
+            layout_hash: u64::from_le_bytes(layout_hash),
+        })
     }
 }
 
-/// FFI-safe slice of [`MemoryRegion`] structs, semantically equivalent to
-/// `&'static mut [MemoryRegion]`.
+/// Computes an FNV-1a hash over the field layouts of `BootInfo` and the types it references.
 ///
-/// This type implements the [`Deref`][core::ops::Deref] and [`DerefMut`][core::ops::DerefMut]
-/// traits, so it can be used like a `&mut [MemoryRegion]` slice. It also implements [`From`]
-/// and [`Into`] for easy conversions from and to `&'static mut [MemoryRegion]`.
-#[derive(Debug)]
+/// The field lists must be kept in sync with the struct definitions in this module.
+const fn layout_hash() -> u64 {
+    macro_rules! layouts {
+        ($($ty:ty),* $(,)?) => {
+            [$((core::mem::size_of::<$ty>(), core::mem::align_of::<$ty>())),*]
+        };
+    }
+    let layouts = layouts![
+        // BootInfo
+        BootInfo,
+        ApiVersion,
+        u64,
+        MemoryRegions,
+        Optional<FrameBuffer>,
+        Optional<u64>,
+        Optional<u16>,
+        Optional<u64>,
+        Optional<TlsTemplate>,
+        Optional<u64>,
+        u64,
+        Optional<BootDevice>,
+        Optional<BootMetadata>,
+        Optional<BootloaderHeap>,
+        Optional<BootCounter>,
+        Optional<CpuState>,
+        Optional<MsrState>,
+        Optional<Iommus>,
+        Optional<FfiStr>,
+        Optional<FrameExtents>,
+        Optional<PlatformRegisters>,
+        Optional<UefiRuntime>,
+        Optional<BootSlot>,
+        Optional<FfiStr>,
+        Optional<RamdiskArchive>,
+        Optional<Modules>,
+        Optional<TimerCaps>,
+        Optional<AcpiFastInfo>,
+        Optional<KernelStack>,
+        Optional<TlsBlock>,
+        Optional<u64>,
+        Optional<u64>,
+        Optional<PartitionKeys>,
+        Optional<UefiCallback>,
+        // MemoryRegions
+        *mut MemoryRegion,
+        usize,
+        // MemoryRegion
+        MemoryRegion,
+        u64,
+        u64,
+        MemoryRegionKind,
+        // FrameBuffer
+        FrameBuffer,
+        u64,
+        FrameBufferInfo,
+        // FrameBufferInfo
+        usize,
+        usize,
+        usize,
+        PixelFormat,
+        usize,
+        usize,
+        // TlsTemplate
+        TlsTemplate,
+        u64,
+        u64,
+        u64,
+        u64,
+        // TlsBlock
+        TlsBlock,
+        u64,
+        u64,
+        u64,
+        // BootDevice
+        BootDevice,
+        u32,
+        PartitionSignature,
+        Optional<FfiStr>,
+        // FfiStr
+        FfiStr,
+        *const u8,
+        usize,
+        // BootMetadata
+        BootMetadata,
+        ApiVersion,
+        [u8; 32],
+        [u8; 32],
+        ExtraMappings,
+        CommandLine,
+        Optional<LogLevels>,
+        // LogLevels
+        LogLevels,
+        LevelFilter,
+        LevelFilter,
+        LevelFilter,
+        LevelFilter,
+        LevelFilter,
+        LevelFilter,
+        // CommandLine
+        CommandLine,
+        [u8; CommandLine::MAX_LEN],
+        usize,
+        // ExtraMappings
+        ExtraMappings,
+        [ExtraMapping; ExtraMappings::MAX_MAPPINGS],
+        usize,
+        // PartitionKeys
+        PartitionKeys,
+        [PartitionKey; PartitionKeys::MAX_KEYS],
+        usize,
+        // PartitionKey
+        PartitionKey,
+        u32,
+        u32,
+        u64,
+        [u8; 64],
+        // UefiCallback
+        UefiCallback,
+        u64,
+        usize,
+        // ExtraMapping
+        ExtraMapping,
+        u64,
+        u64,
+        u64,
+        bool,
+        bool,
+        Caching,
+        // BootloaderHeap
+        BootloaderHeap,
+        u64,
+        u64,
+        u64,
+        // KernelStack
+        KernelStack,
+        u64,
+        u64,
+        u64,
+        // BootCounter
+        BootCounter,
+        u8,
+        u8,
+        bool,
+        // BootSlot
+        BootSlot,
+        Slot,
+        Slot,
+        u8,
+        // CpuState
+        CpuState,
+        u32,
+        u32,
+        Optional<u64>,
+        Optional<u64>,
+        bool,
+        // MsrState
+        MsrState,
+        bool,
+        bool,
+        Optional<u64>,
+        Optional<u64>,
+        Optional<u64>,
+        // TimerCaps
+        TimerCaps,
+        bool,
+        bool,
+        bool,
+        // Iommus
+        Iommus,
+        [Iommu; Iommus::MAX_UNITS],
+        usize,
+        // Iommu
+        Iommu,
+        IommuKind,
+        u16,
+        u64,
+        u64,
+        Optional<u64>,
+        // FrameExtents
+        FrameExtents,
+        [FrameExtent; FrameExtents::MAX_EXTENTS],
+        usize,
+        // FrameExtent
+        FrameExtent,
+        u64,
+        u64,
+        // PlatformRegisters
+        PlatformRegisters,
+        MmioRegisters,
+        IoApics,
+        Optional<MmioRegisters>,
+        // MmioRegisters
+        MmioRegisters,
+        u64,
+        u64,
+        Optional<u64>,
+        // IoApics
+        IoApics,
+        [IoApic; IoApics::MAX_IO_APICS],
+        usize,
+        // IoApic
+        IoApic,
+        u8,
+        u32,
+        MmioRegisters,
+        // AcpiFastInfo
+        AcpiFastInfo,
+        GenericAddress,
+        Optional<GenericAddress>,
+        Optional<[u8; 2]>,
+        Optional<ResetRegister>,
+        Optional<u8>,
+        // ResetRegister
+        ResetRegister,
+        GenericAddress,
+        u8,
+        // GenericAddress
+        GenericAddress,
+        u8,
+        u8,
+        u8,
+        u8,
+        u64,
+        // UefiRuntime
+        UefiRuntime,
+        u64,
+        bool,
+        Optional<u64>,
+        Optional<u64>,
+        UefiRuntimeRegions,
+        // UefiRuntimeRegions
+        UefiRuntimeRegions,
+        [UefiRuntimeRegion; UefiRuntimeRegions::MAX_REGIONS],
+        usize,
+        // UefiRuntimeRegion
+        UefiRuntimeRegion,
+        u64,
+        u64,
+        u32,
+        u64,
+        bool,
+        bool,
+        // RamdiskArchive
+        RamdiskArchive,
+        ArchiveFormat,
+        ArchiveFiles,
+        // ArchiveFiles
+        *const ArchiveFile,
+        usize,
+        // ArchiveFile
+        ArchiveFile,
+        FfiStr,
+        u64,
+        u64,
+        // Modules
+        *const Module,
+        usize,
+        // Module
+        Module,
+        FfiStr,
+        u64,
+        u64,
+    ];
+
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut i = 0;
+    while i < layouts.len() {
+        let (size, align) = layouts[i];
+        let bytes = ((size as u64) << 32 | align as u64).to_le_bytes();
+        let mut j = 0;
+        while j < bytes.len() {
+            hash ^= bytes[j] as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            j += 1;
+        }
+        i += 1;
+    }
+    hash
+}
+
+/// FFI-safe UTF-8 string slice, semantically equivalent to `&'static str`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a `&str`.
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
-pub struct MemoryRegions {
-    pub(crate) ptr: *mut MemoryRegion,
+pub struct FfiStr {
+    pub(crate) ptr: *const u8,
     pub(crate) len: usize,
 }
 
-impl ops::Deref for MemoryRegions {
-    type Target = [MemoryRegion];
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+impl FfiStr {
+    /// Creates a new string slice from the given raw parts.
+    ///
+    /// ## Safety
+    ///
+    /// The given pointer must point to `len` bytes of valid UTF-8 that stay valid and
+    /// unmodified for the `'static` lifetime.
+    pub unsafe fn from_raw_parts(ptr: *const u8, len: usize) -> Self {
+        Self { ptr, len }
     }
 }
 
-impl ops::DerefMut for MemoryRegions {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
-    }
-}
+impl ops::Deref for FfiStr {
+    type Target = str;
 
-impl From<&'static mut [MemoryRegion]> for MemoryRegions {
-    fn from(regions: &'static mut [MemoryRegion]) -> Self {
-        MemoryRegions {
-            ptr: regions.as_mut_ptr(),
-            len: regions.len(),
-        }
+    fn deref(&self) -> &Self::Target {
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len)) }
     }
 }
 
-impl From<MemoryRegions> for &'static mut [MemoryRegion] {
-    fn from(regions: MemoryRegions) -> &'static mut [MemoryRegion] {
-        unsafe { slice::from_raw_parts_mut(regions.ptr, regions.len) }
+impl From<&'static str> for FfiStr {
+    fn from(s: &'static str) -> Self {
+        FfiStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
     }
 }
 
@@ -151,7 +1976,8 @@ impl MemoryRegion {
 pub enum MemoryRegionKind {
     /// Unused conventional memory, can be used by the kernel.
     Usable,
-    /// Memory mappings created by the bootloader, including the page table and boot info mappings.
+    /// Other memory used by the bootloader, e.g. the kernel stack, the TLS block, and memory of
+    /// the earlier boot stages.
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
@@ -161,6 +1987,29 @@ pub enum MemoryRegionKind {
     UnknownUefi(u32),
     /// An unknown memory region reported by the BIOS firmware.
     UnknownBios(u32),
+    /// Page tables and the GDT created by the bootloader.
+    ///
+    /// The kernel can use this memory once it has switched to its own page tables and loaded
+    /// its own GDT. See [`BootInfo::reclaimable_frames`] for the exact list of frames.
+    BootloaderReclaimable,
+    /// The kernel executable and the memory of its loaded segments.
+    ///
+    /// The segments are mapped directly from the executable where possible, so this memory
+    /// must not be used by the kernel unless it has moved itself elsewhere.
+    KernelImage,
+    /// The boot info, the memory map, and the data that the boot info points to.
+    ///
+    /// The kernel can reuse this memory once it no longer needs the [`BootInfo`].
+    BootInfo,
+    /// The ramdisk and the [`Module`]s that were extracted from it.
+    ///
+    /// The kernel can reuse this memory once it no longer needs the ramdisk.
+    Ramdisk,
+    /// The heap of the bootloader, see [`BootInfo::bootloader_heap`].
+    ///
+    /// The boot info doesn't point into the heap, so the kernel can reuse this memory right
+    /// away.
+    BootloaderHeap,
 }
 
 /// A pixel-based framebuffer that controls the screen output.
@@ -287,6 +2136,163 @@ pub struct TlsTemplate {
     ///
     /// Corresponds to the combined length of the `.tdata` and `.tbss` sections.
     pub mem_size: u64,
+    /// The required alignment of the TLS segment in memory.
+    ///
+    /// On x86_64, the thread pointer must be aligned to this value and the TLS segment
+    /// ends at the thread pointer, i.e. it starts `mem_size` rounded up to `align` bytes
+    /// below it.
+    pub align: u64,
+}
+
+/// The thread local storage block that the bootloader set up for the boot CPU.
+///
+/// The block is laid out according to the x86_64 ELF TLS ABI: the initialized TLS segment
+/// is followed by the thread control block (TCB), whose first word contains its own address.
+/// The `FS` base register points to the TCB, so `#[thread_local]` statics work right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TlsBlock {
+    /// The virtual start address of the block.
+    pub start: u64,
+    /// The size of the block in bytes, including the TCB.
+    pub len: u64,
+    /// The thread pointer, i.e. the address of the TCB and the initial `FS` base.
+    pub thread_pointer: u64,
+}
+
+/// The index of a ramdisk that is a cpio or tar archive.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RamdiskArchive {
+    /// The format of the archive.
+    pub format: ArchiveFormat,
+    /// The regular files of the archive, in the order in which they appear in it.
+    pub files: ArchiveFiles,
+}
+
+/// The archive formats that the bootloader recognizes in ramdisks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+#[non_exhaustive]
+pub enum ArchiveFormat {
+    /// The "new" portable cpio format, as produced by `cpio -H newc`.
+    Cpio,
+    /// The POSIX ustar format, as produced by `tar --format=ustar`.
+    Ustar,
+}
+
+/// FFI-safe slice of [`ArchiveFile`] structs, semantically equivalent to
+/// `&'static [ArchiveFile]`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[ArchiveFile]` slice.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ArchiveFiles {
+    pub(crate) ptr: *const ArchiveFile,
+    pub(crate) len: usize,
+}
+
+impl ArchiveFiles {
+    /// Returns the file with the given path, e.g. `etc/hostname`.
+    ///
+    /// Paths are compared as stored in the archive, so a leading `./` is not ignored.
+    pub fn find(&self, name: &str) -> Option<&ArchiveFile> {
+        self.iter().find(|file| &*file.name == name)
+    }
+}
+
+impl ops::Deref for ArchiveFiles {
+    type Target = [ArchiveFile];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static [ArchiveFile]> for ArchiveFiles {
+    fn from(files: &'static [ArchiveFile]) -> Self {
+        ArchiveFiles {
+            ptr: files.as_ptr(),
+            len: files.len(),
+        }
+    }
+}
+
+/// A regular file in a ramdisk archive.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ArchiveFile {
+    /// The path of the file, as stored in the archive.
+    pub name: FfiStr,
+    /// The offset of the file contents from the start of the ramdisk.
+    pub offset: u64,
+    /// The length of the file contents in bytes.
+    pub len: u64,
+}
+
+impl ArchiveFile {
+    /// Returns the contents of the file, given the ramdisk that contains the archive.
+    ///
+    /// The ramdisk is located at [`BootInfo::ramdisk_addr`] and is
+    /// [`BootInfo::ramdisk_len`] bytes long. Returns `None` if the file doesn't fit into the
+    /// given slice.
+    pub fn contents<'a>(&self, ramdisk: &'a [u8]) -> Option<&'a [u8]> {
+        let start = usize::try_from(self.offset).ok()?;
+        let len = usize::try_from(self.len).ok()?;
+        ramdisk.get(start..start.checked_add(len)?)
+    }
+}
+
+/// FFI-safe slice of [`Module`] structs, semantically equivalent to `&'static [Module]`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a
+/// `&[Module]` slice.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Modules {
+    pub(crate) ptr: *const Module,
+    pub(crate) len: usize,
+}
+
+impl Modules {
+    /// Returns the module with the given path, see [`ArchiveFiles::find`].
+    pub fn find(&self, name: &str) -> Option<&Module> {
+        self.iter().find(|module| &*module.name == name)
+    }
+}
+
+impl ops::Deref for Modules {
+    type Target = [Module];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl From<&'static [Module]> for Modules {
+    fn from(modules: &'static [Module]) -> Self {
+        Modules {
+            ptr: modules.as_ptr(),
+            len: modules.len(),
+        }
+    }
+}
+
+/// A file of the ramdisk archive that the bootloader copied to its own memory region.
+///
+/// The region starts at a page boundary and is mapped read-only and non-executable. It is
+/// followed by an unmapped guard page. The backing frames are reported as
+/// [`MemoryRegionKind::Ramdisk`] in the memory map.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Module {
+    /// The path of the file, as stored in the archive.
+    pub name: FfiStr,
+    /// The virtual start address of the file contents.
+    pub addr: u64,
+    /// The length of the file contents in bytes.
+    pub len: u64,
 }
 
 /// FFI-safe variant of [`Option`].
@@ -350,3 +2356,149 @@ impl<T> From<Optional<T>> for Option<T> {
 
 /// Check that bootinfo is FFI-safe
 extern "C" fn _assert_ffi(_boot_info: BootInfo) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_metadata() {
+        for _ in 0..10000 {
+            let mut extra_mappings = ExtraMappings::new();
+            for _ in 0..rand::random::<usize>() % (ExtraMappings::MAX_MAPPINGS + 1) {
+                extra_mappings.push(ExtraMapping {
+                    phys_start: rand::random(),
+                    virt_start: rand::random(),
+                    len: rand::random(),
+                    writable: rand::random(),
+                    executable: rand::random(),
+                    caching: [
+                        Caching::WriteBack,
+                        Caching::WriteThrough,
+                        Caching::Uncacheable,
+                    ][rand::random::<usize>() % 3],
+                });
+            }
+            let mut command_line = CommandLine::new();
+            let line = "console=ttyS0 root=/dev/sda1 log=trace";
+            command_line.set(&line[..rand::random::<usize>() % 3 * 15]);
+            let metadata = BootMetadata {
+                bootloader_version: ApiVersion::random(),
+                config_hash: rand::random(),
+                kernel_hash: rand::random(),
+                extra_mappings,
+                command_line,
+                log_levels: if rand::random() {
+                    Optional::Some(LogLevels::random())
+                } else {
+                    Optional::None
+                },
+            };
+            let serialized = metadata.serialize();
+            assert_eq!(BootMetadata::deserialize(&serialized), Ok(metadata));
+
+            let mut corrupted = serialized;
+            corrupted[rand::random::<usize>() % BootMetadata::CHECKSUM_OFFSET] ^= 1;
+            assert!(BootMetadata::deserialize(&corrupted).is_err());
+        }
+    }
+
+    #[test]
+    fn command_line_max_len() {
+        let mut command_line = CommandLine::new();
+        assert!(command_line.set("quiet"));
+        assert!(!command_line.set(&"x".repeat(CommandLine::MAX_LEN + 1)));
+        assert_eq!(&*command_line, "quiet");
+        assert!(command_line.set(&"x".repeat(CommandLine::MAX_LEN)));
+        assert_eq!(command_line.len(), CommandLine::MAX_LEN);
+    }
+
+    #[test]
+    fn msr_state_vmx_usable() {
+        let state = |feature_control| MsrState {
+            vmx_supported: true,
+            svm_supported: false,
+            feature_control,
+            vm_cr: Optional::None,
+            mtrr_cap: Optional::None,
+        };
+        // unlocked, so the kernel can still enable VMX
+        assert!(state(Optional::Some(0)).vmx_usable());
+        // locked with VMX outside SMX enabled
+        assert!(state(Optional::Some(0b101)).vmx_usable());
+        // locked with VMX disabled
+        assert!(!state(Optional::Some(0b001)).vmx_usable());
+        assert!(state(Optional::Some(0b001)).feature_control_locked());
+        assert!(!state(Optional::None).vmx_usable());
+    }
+
+    #[test]
+    fn frame_extents_merge() {
+        let frame = |n: u64| n * FrameExtents::FRAME_SIZE;
+        let mut extents = FrameExtents::new();
+        for n in [5, 3, 7, 4, 4, 6, 10] {
+            assert!(extents.insert_frame(frame(n)));
+        }
+        assert_eq!(
+            *extents,
+            [
+                FrameExtent {
+                    start: frame(3),
+                    frame_count: 5
+                },
+                FrameExtent {
+                    start: frame(10),
+                    frame_count: 1
+                },
+            ]
+        );
+        assert_eq!(extents.frame_count(), 6);
+
+        let mut extents = FrameExtents::new();
+        for n in 0..FrameExtents::MAX_EXTENTS as u64 {
+            assert!(extents.insert_frame(frame(2 * n)));
+        }
+        assert!(!extents.insert_frame(frame(1000)));
+        // adjacent frames can still be merged into a full list
+        assert!(extents.insert_frame(frame(1)));
+        assert_eq!(extents.len(), FrameExtents::MAX_EXTENTS - 1);
+    }
+
+    #[test]
+    fn boot_info_abi() {
+        let abi = BootInfoAbi::current();
+        assert_eq!(BootInfoAbi::deserialize(&abi.serialize()), Ok(abi));
+        assert_eq!(
+            abi.boot_info_size as usize,
+            core::mem::size_of::<BootInfo>()
+        );
+    }
+
+    #[test]
+    fn archive_files_find() {
+        let files = Box::leak(Box::new([
+            ArchiveFile {
+                name: "init".into(),
+                offset: 512,
+                len: 3,
+            },
+            ArchiveFile {
+                name: "etc/hostname".into(),
+                offset: 1536,
+                len: 5,
+            },
+        ]));
+        let files = ArchiveFiles::from(&files[..]);
+        let mut ramdisk = [0; 2048];
+        ramdisk[1536..1541].copy_from_slice(b"host\n");
+        let hostname = files.find("etc/hostname").unwrap();
+        assert_eq!(hostname.contents(&ramdisk), Some(&b"host\n"[..]));
+        assert_eq!(files[0].contents(&ramdisk[..514]), None);
+        assert!(files.find("etc").is_none());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
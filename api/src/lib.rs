@@ -22,7 +22,9 @@ mod version_info {
 
 /// Defines the entry point function.
 ///
-/// The function must have the signature `fn(&'static mut BootInfo) -> !`.
+/// The function must have the signature `fn(&'static mut BootInfo) -> !`. Functions that are
+/// declared as `extern "C" fn(&'static mut BootInfo) -> !` are supported by prefixing the path
+/// with `extern "C"`, e.g. `entry_point!(extern "C" kernel_main)`.
 ///
 /// This macro just creates a function named `_start`, which the linker will use as the entry
 /// point. The advantage of using this macro instead of providing an own `_start` function is
@@ -91,6 +93,62 @@ mod version_info {
 ///   # #[lang = "eh_personality"] fn eh_personality() {} // not needed when disabling unwinding
 ///   ```
 ///
+/// - With an `extern "C"` function, e.g. one that is also called from assembly code:
+///
+///   ```no_run
+///   #![no_std]
+///   #![no_main]
+///   # #![feature(lang_items)]
+///  
+///   bootloader_api::entry_point!(extern "C" main);
+///  
+///   extern "C" fn main(bootinfo: &'static mut bootloader_api::BootInfo) -> ! {
+///       loop {}
+///   }
+///
+///   #[panic_handler]
+///   fn panic(_info: &core::panic::PanicInfo) -> ! {
+///       loop {}
+///   }
+///
+///   # #[lang = "eh_personality"] fn eh_personality() {} // not needed when disabling unwinding
+///   ```
+///
+/// ## Signature errors
+///
+/// If the function has a different signature, compilation fails with a type mismatch error
+/// that points at the macro invocation and lists the expected and the found signature, e.g.
+/// for a function without the `-> !` return type:
+///
+/// ```text
+/// error[E0308]: mismatched types
+///   |
+///   | bootloader_api::entry_point!(main);
+///   | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^ expected `!`, found `()`
+///   |
+///   = note: expected fn pointer `fn(&'static mut BootInfo) -> !`
+///                 found fn item `fn(&'static mut BootInfo) {main}`
+/// ```
+///
+/// ```compile_fail,E0308
+/// # #![no_std]
+/// # #![no_main]
+/// # #![feature(lang_items)]
+/// bootloader_api::entry_point!(main);
+///
+/// fn main(bootinfo: &'static mut bootloader_api::BootInfo) {}
+/// # #[panic_handler]
+/// # fn panic(_info: &core::panic::PanicInfo) -> ! {
+/// #     loop {}
+/// # }
+/// # #[lang = "eh_personality"] fn eh_personality() {}
+/// ```
+///
+/// An `extern "C"` function that is passed without the `extern "C"` prefix, or the other way
+/// around, fails the same way with the ABIs in the expected and found signatures differing.
+/// Invocations that don't match any of the supported forms fail with an error that lists
+/// them.
+///
 /// ## Implementation Notes
 ///
 /// - **Start function:** The `entry_point` macro generates a small wrapper function named
@@ -108,10 +166,39 @@ mod version_info {
 ///   automatically read it when loading the kernel.
 #[macro_export]
 macro_rules! entry_point {
+    (extern "C" $path:path) => {
+        $crate::entry_point!(
+            extern "C" $path,
+            config = &$crate::BootloaderConfig::new_default()
+        );
+    };
+    (extern "C" $path:path, config = $config:expr) => {
+        $crate::__entry_point!(
+            $path,
+            extern "C" fn(&'static mut $crate::BootInfo) -> !,
+            $config
+        );
+    };
     ($path:path) => {
         $crate::entry_point!($path, config = &$crate::BootloaderConfig::new_default());
     };
     ($path:path, config = $config:expr) => {
+        $crate::__entry_point!($path, fn(&'static mut $crate::BootInfo) -> !, $config);
+    };
+    ($($invalid:tt)*) => {
+        ::core::compile_error!(
+            "invalid `entry_point!` invocation, expected one of `entry_point!(path)`, \
+            `entry_point!(path, config = &CONFIG)`, `entry_point!(extern \"C\" path)`, or \
+            `entry_point!(extern \"C\" path, config = &CONFIG)`, where `path` is the path to a \
+            `fn(&'static mut BootInfo) -> !`"
+        );
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __entry_point {
+    ($path:path, $signature:ty, $config:expr) => {
         const _: () = {
             #[link_section = ".bootloader-config"]
             pub static __BOOTLOADER_CONFIG: [u8; $crate::BootloaderConfig::SERIALIZED_LEN] = {
@@ -120,15 +207,15 @@ macro_rules! entry_point {
                 config.serialize()
             };
 
+            // validate the signature of the program entry point
+            const __ENTRY_POINT: $signature = $path;
+
             #[export_name = "_start"]
             pub extern "C" fn __impl_start(boot_info: &'static mut $crate::BootInfo) -> ! {
-                // validate the signature of the program entry point
-                let f: fn(&'static mut $crate::BootInfo) -> ! = $path;
-
                 // ensure that the config is used so that the linker keeps it
                 $crate::__force_use(&__BOOTLOADER_CONFIG);
 
-                f(boot_info)
+                __ENTRY_POINT(boot_info)
             }
         };
     };
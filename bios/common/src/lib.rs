@@ -2,15 +2,62 @@
 
 pub mod racy_cell;
 
+/// The stack of the BIOS stages, which the boot sector sets up below its load address.
+pub const STACK_START: u32 = 0x500;
+pub const STACK_END: u32 = 0x7c00;
+/// The value that the second stage fills the unused stack with, the same as
+/// `bootloader_x86_64_common::stack::POISON`.
+pub const STACK_POISON: u32 = 0x5354_4b21;
+/// The end of the physical memory that the third stage identity-maps. The payload and the
+/// frames that the fourth stage works with must be located below.
+pub const IDENTITY_MAPPED_END: u64 = 10 * 1024 * 1024 * 1024;
+
 #[cfg_attr(feature = "debug", derive(Debug))]
 #[repr(C)]
 pub struct BiosInfo {
     pub stage_4: Region,
     pub kernel: Region,
     pub ramdisk: Region,
+    /// The metadata block of the boot image, with length 0 if the image has none.
+    pub boot_metadata: Region,
+    /// The headers of the encrypted partitions to unlock, with length 0 if there are none.
+    pub partition_keys: Region,
     pub framebuffer: BiosFramebufferInfo,
     pub memory_map_addr: u32,
     pub memory_map_len: u16,
+    /// The BIOS function that the memory map was created with.
+    pub memory_map_source: MemoryMapSource,
+    pub boot_partition: BootPartition,
+    /// Developer mode: the fourth stage receives the kernel over the serial port and uses it
+    /// instead of the kernel loaded from disk.
+    pub serial_kernel_load: bool,
+}
+
+/// The BIOS functions that the second stage uses to detect the available memory.
+///
+/// The functions are tried in order. Only `INT 0x15, EAX=0xE820` reports a complete memory
+/// map; for the older functions, the memory map is synthesized from the reported sizes.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum MemoryMapSource {
+    /// `INT 0x15, EAX=0xE820`
+    E820,
+    /// `INT 0x15, AX=0xE801`, which reports the memory up to 4GiB.
+    E801,
+    /// `INT 0x15, AH=0x88`, which reports the memory up to 64MiB.
+    Legacy88,
+}
+
+/// The MBR partition that the kernel was loaded from.
+#[cfg_attr(feature = "debug", derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct BootPartition {
+    pub disk_signature: u32,
+    /// One-based index into the MBR partition table, `0` if the second stage was loaded over
+    /// the network.
+    pub partition_number: u32,
 }
 
 #[cfg_attr(feature = "debug", derive(Debug))]
@@ -7,9 +7,16 @@ use crate::{
         copy_to_protected_mode, enter_protected_mode_and_jump_to_stage_3, enter_unreal_mode,
     },
 };
-use bootloader_x86_64_bios_common::{hlt, BiosFramebufferInfo, BiosInfo, Region};
+use bootloader_x86_64_bios_common::{
+    hlt, BiosFramebufferInfo, BiosInfo, BootPartition, Region, IDENTITY_MAPPED_END, STACK_POISON,
+    STACK_START,
+};
 use byteorder::{ByteOrder, LittleEndian};
-use core::{fmt::Write as _, slice};
+use core::{
+    arch::{asm, global_asm},
+    fmt::Write as _,
+    ptr, slice,
+};
 use disk::AlignedArrayBuffer;
 use mbr_nostd::{PartitionTableEntry, PartitionType};
 
@@ -18,27 +25,67 @@ mod disk;
 mod fat;
 mod memory_map;
 mod protected_mode;
+mod pxe;
 mod screen;
 mod vesa;
 
 /// We use this partition type to store the second bootloader stage;
 const BOOTLOADER_SECOND_STAGE_PARTITION_TYPE: u8 = 0x20;
+/// Hybrid images mark the boot partition as EFI system partition, which is a FAT file system.
+const EFI_SYSTEM_PARTITION_TYPE: u8 = 0xef;
 
 const STAGE_3_DST: *mut u8 = 0x0010_0000 as *mut u8; // 1MiB (typically 14MiB accessible here)
 const STAGE_4_DST: *mut u8 = 0x0020_0000 as *mut u8; // 2MiB (typically still 13MiB accessible here)
-const KERNEL_DST: *mut u8 = 0x0100_0000 as *mut u8; // 16MiB
+/// The lowest address at which the kernel, ramdisk, and metadata block are placed.
+const KERNEL_MIN_DST: u64 = 0x0100_0000; // 16MiB
+/// The payload is placed above this address only if it doesn't fit below, since it has to
+/// be copied through a paging window there.
+const HIGH_MEMORY_START: u64 = 0x1_0000_0000; // 4GiB
+/// The files that are placed next to each other, starting with the kernel.
+const PAYLOAD_FILES: [&str; 5] = [
+    "kernel-x86_64",
+    "ramdisk",
+    "boot-metadata",
+    "partition-keys",
+    "video-mode",
+];
+/// If this file exists, the fourth stage receives the kernel over the serial port.
+const SERIAL_LOAD_FILE: &str = "serial-load";
 
 static mut DISK_BUFFER: AlignedArrayBuffer<0x4000> = AlignedArrayBuffer {
     buffer: [0; 0x4000],
 };
 
+global_asm!(include_str!("pxe_start.s"));
+
 #[no_mangle]
 #[link_section = ".start"]
 pub extern "C" fn _start(disk_number: u16, partition_table_start: *const u8) -> ! {
     start(disk_number, partition_table_start)
 }
 
+/// Entered through `_pxe_start` when the second stage was downloaded as a PXE network boot
+/// program.
+#[no_mangle]
+pub extern "C" fn pxe_start() -> ! {
+    poison_stack();
+    screen::Writer
+        .write_str(" -> SECOND STAGE (PXE)\n")
+        .unwrap();
+
+    enter_unreal_mode();
+
+    let tftp = pxe::Tftp::init().unwrap_or_else(|err| panic!("PXE boot failed: {err}"));
+    // there is no boot partition, which the fourth stage reports as unknown boot device
+    let boot_partition = BootPartition {
+        disk_signature: 0,
+        partition_number: 0,
+    };
+    load_files_and_boot(FileSource::Pxe(tftp), boot_partition)
+}
+
 fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
+    poison_stack();
     screen::Writer.write_str(" -> SECOND STAGE\n").unwrap();
 
     enter_unreal_mode();
@@ -59,6 +106,11 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
         }
         entries
     };
+    // the disk signature is stored right in front of the partition table (at offset 440)
+    let disk_signature = {
+        let raw = unsafe { slice::from_raw_parts(partition_table_start.wrapping_sub(6), 4) };
+        LittleEndian::read_u32(raw)
+    };
     // look for second stage partition
     let second_stage_partition_idx = partitions
         .iter()
@@ -71,38 +123,151 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
     let fat_partition = partitions.get(second_stage_partition_idx + 1).unwrap();
     assert!(matches!(
         fat_partition.partition_type,
-        PartitionType::Fat12(_) | PartitionType::Fat16(_) | PartitionType::Fat32(_)
+        PartitionType::Fat12(_)
+            | PartitionType::Fat16(_)
+            | PartitionType::Fat32(_)
+            | PartitionType::Unknown(EFI_SYSTEM_PARTITION_TYPE)
     ));
 
     // load fat partition
-    let mut disk = disk::DiskAccess {
+    let disk = disk::DiskAccess {
         disk_number,
         base_offset: u64::from(fat_partition.logical_block_address) * 512,
         current_offset: 0,
     };
 
-    let mut fs = fat::FileSystem::parse(disk.clone());
+    let fs = fat::FileSystem::parse(disk.clone());
+
+    let boot_partition = BootPartition {
+        disk_signature,
+        partition_number: (second_stage_partition_idx + 2).try_into().unwrap(),
+    };
+    load_files_and_boot(FileSource::Disk { fs, disk }, boot_partition)
+}
+
+/// Fills the unused part of the stack with [`STACK_POISON`], so that the fourth stage can
+/// report the peak stack usage of the BIOS stages.
+#[inline(never)]
+fn poison_stack() {
+    let sp: u16;
+    unsafe { asm!("mov {:x}, sp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+    // leave the stack frame of this function alone
+    let end = u32::from(sp - 0x100) & !3;
+    let mut addr = STACK_START;
+    while addr < end {
+        unsafe { ptr::write_volatile(addr as *mut u32, STACK_POISON) };
+        addr += 4;
+    }
+}
 
-    let disk_buffer = unsafe { &mut DISK_BUFFER };
+/// Where the second stage loads the other stages and the kernel from.
+enum FileSource {
+    Disk {
+        fs: fat::FileSystem<disk::DiskAccess>,
+        disk: disk::DiskAccess,
+    },
+    Pxe(pxe::Tftp),
+}
+
+impl FileSource {
+    fn file_size(
+        &mut self,
+        name: &str,
+        disk_buffer: &mut AlignedArrayBuffer<16384>,
+    ) -> Option<u64> {
+        match self {
+            FileSource::Disk { fs, .. } => fs
+                .find_file_in_root_dir(name, disk_buffer)
+                .map(|file| file.file_size().into()),
+            FileSource::Pxe(tftp) => tftp.file_size(name),
+        }
+    }
+
+    fn try_load_file(
+        &mut self,
+        name: &str,
+        dst: u64,
+        disk_buffer: &mut AlignedArrayBuffer<16384>,
+    ) -> Option<u64> {
+        match self {
+            FileSource::Disk { fs, disk } => try_load_file(name, dst, fs, disk, disk_buffer),
+            FileSource::Pxe(tftp) => tftp.try_load_file(name, dst, disk_buffer),
+        }
+    }
 
-    let stage_3_len = load_file("boot-stage-3", STAGE_3_DST, &mut fs, &mut disk, disk_buffer);
+    fn load_file(
+        &mut self,
+        name: &str,
+        dst: u64,
+        disk_buffer: &mut AlignedArrayBuffer<16384>,
+    ) -> u64 {
+        self.try_load_file(name, dst, disk_buffer)
+            .expect("file not found")
+    }
+
+    /// Releases the firmware resources that were used for loading files.
+    fn close(self) {
+        if let FileSource::Pxe(tftp) = self {
+            tftp.shutdown();
+        }
+    }
+}
+
+fn load_files_and_boot(mut files: FileSource, boot_partition: BootPartition) -> ! {
+    // hide the address of the buffer from the compiler, which would otherwise access fields
+    // at constant offsets through 16-bit absolute addresses that can't reach the buffer
+    let disk_buffer = core::hint::black_box(unsafe { &mut DISK_BUFFER });
+    let stage_3_len = files.load_file("boot-stage-3", STAGE_3_DST as u64, disk_buffer);
     writeln!(screen::Writer, "stage 3 loaded at {STAGE_3_DST:#p}").unwrap();
     let stage_4_dst = {
         let stage_3_end = STAGE_3_DST.wrapping_add(usize::try_from(stage_3_len).unwrap());
         assert!(STAGE_4_DST > stage_3_end);
         STAGE_4_DST
     };
-    let stage_4_len = load_file("boot-stage-4", stage_4_dst, &mut fs, &mut disk, disk_buffer);
+    let stage_4_len = files.load_file("boot-stage-4", stage_4_dst as u64, disk_buffer);
     writeln!(screen::Writer, "stage 4 loaded at {stage_4_dst:#p}").unwrap();
 
+    let (memory_map, memory_map_source) = unsafe { memory_map::query_memory_map() }.unwrap();
+    // the fourth stage logs the regions, formatting them here takes too much code
+    writeln!(
+        screen::Writer,
+        "memory map ({memory_map_source:?}): {} regions",
+        memory_map.len()
+    )
+    .unwrap();
+
+    let payload_len: u64 = PAYLOAD_FILES
+        .iter()
+        .filter_map(|name| files.file_size(name, disk_buffer))
+        .map(|size| (size + 4095) / 4096 * 4096)
+        .sum();
+    let kernel_dst =
+        memory_map::find_payload_region(memory_map, KERNEL_MIN_DST, HIGH_MEMORY_START, payload_len)
+            .unwrap_or_else(|| {
+                let tables = memory_map::find_payload_region(
+                    memory_map,
+                    KERNEL_MIN_DST,
+                    HIGH_MEMORY_START,
+                    3 * 4096,
+                )
+                .expect("no memory for the paging window");
+                unsafe { protected_mode::init_high_memory_access(tables as u32) };
+                // the third stage only identity-maps the memory below IDENTITY_MAPPED_END
+                memory_map::find_payload_region(
+                    memory_map,
+                    HIGH_MEMORY_START,
+                    IDENTITY_MAPPED_END,
+                    payload_len,
+                )
+                .unwrap_or_else(|| panic!("kernel and ramdisk ({payload_len:#x} bytes) don't fit"))
+            });
+
     writeln!(screen::Writer, "loading kernel...").unwrap();
-    let kernel_len = load_file("kernel-x86_64", KERNEL_DST, &mut fs, &mut disk, disk_buffer);
-    writeln!(screen::Writer, "kernel loaded at {KERNEL_DST:#p}").unwrap();
-    let kernel_page_size = (((kernel_len - 1) / 4096) + 1) as usize;
-    let ramdisk_start = KERNEL_DST.wrapping_add(kernel_page_size * 4096);
+    let kernel_len = files.load_file(PAYLOAD_FILES[0], kernel_dst, disk_buffer);
+    writeln!(screen::Writer, "kernel loaded at {kernel_dst:#x}").unwrap();
+    let ramdisk_start = kernel_dst + (kernel_len + 4095) / 4096 * 4096;
     writeln!(screen::Writer, "Loading ramdisk...").unwrap();
-    let ramdisk_len = match try_load_file("ramdisk", ramdisk_start, &mut fs, &mut disk, disk_buffer)
-    {
+    let ramdisk_len = match files.try_load_file(PAYLOAD_FILES[1], ramdisk_start, disk_buffer) {
         Some(s) => s,
         None => 0u64,
     };
@@ -110,20 +275,30 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
     if ramdisk_len == 0 {
         writeln!(screen::Writer, "No ramdisk found, skipping.").unwrap();
     } else {
-        writeln!(screen::Writer, "Loaded ramdisk at {ramdisk_start:#p}").unwrap();
+        writeln!(screen::Writer, "Loaded ramdisk at {ramdisk_start:#x}").unwrap();
     }
-
-    let memory_map = unsafe { memory_map::query_memory_map() }.unwrap();
-    writeln!(screen::Writer, "{memory_map:x?}").unwrap();
-
-    // TODO: load these from the kernel's config instead of hardcoding
-    let max_width = 1280;
-    let max_height = 720;
+    let boot_metadata_start = ramdisk_start + (ramdisk_len + 4095) / 4096 * 4096;
+    let boot_metadata_len = files
+        .try_load_file(PAYLOAD_FILES[2], boot_metadata_start, disk_buffer)
+        .unwrap_or(0);
+    let partition_keys_start = boot_metadata_start + (boot_metadata_len + 4095) / 4096 * 4096;
+    let partition_keys_len = files
+        .try_load_file(PAYLOAD_FILES[3], partition_keys_start, disk_buffer)
+        .unwrap_or(0);
+    let video_mode_start = partition_keys_start + (partition_keys_len + 4095) / 4096 * 4096;
+    // images without the file use the default mode
+    let mut video_mode = [0; 9];
+    if files.try_load_file(PAYLOAD_FILES[4], video_mode_start, disk_buffer) == Some(9) {
+        unsafe {
+            protected_mode::read_bytes_from_protected_mode(video_mode_start, &mut video_mode)
+        };
+    }
+    let serial_kernel_load = files.file_size(SERIAL_LOAD_FILE, disk_buffer).is_some();
+    files.close();
 
     let mut vesa_info = vesa::VesaInfo::query(disk_buffer).unwrap();
     let vesa_mode = vesa_info
-        .get_best_mode(max_width, max_height)
-        .unwrap()
+        .get_best_mode(&vesa::VideoMode::parse(video_mode))
         .expect("no suitable VESA mode found");
     writeln!(
         screen::Writer,
@@ -140,15 +315,25 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
             len: stage_4_len,
         },
         kernel: Region {
-            start: KERNEL_DST as u64,
+            start: kernel_dst,
             len: kernel_len,
         },
         ramdisk: Region {
-            start: ramdisk_start as u64,
+            start: ramdisk_start,
             len: ramdisk_len,
         },
+        boot_metadata: Region {
+            start: boot_metadata_start,
+            len: boot_metadata_len,
+        },
+        partition_keys: Region {
+            start: partition_keys_start,
+            len: partition_keys_len,
+        },
         memory_map_addr: memory_map.as_mut_ptr() as u32,
         memory_map_len: memory_map.len().try_into().unwrap(),
+        memory_map_source,
+        serial_kernel_load,
         framebuffer: BiosFramebufferInfo {
             region: Region {
                 start: vesa_mode.framebuffer_start.into(),
@@ -160,6 +345,7 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
             stride: vesa_mode.bytes_per_scanline / u16::from(vesa_mode.bytes_per_pixel),
             pixel_format: vesa_mode.pixel_format,
         },
+        boot_partition,
     };
 
     enter_protected_mode_and_jump_to_stage_3(STAGE_3_DST, &mut info);
@@ -171,7 +357,7 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
 
 fn try_load_file(
     file_name: &str,
-    dst: *mut u8,
+    dst: u64,
     fs: &mut fat::FileSystem<disk::DiskAccess>,
     disk: &mut disk::DiskAccess,
     disk_buffer: &mut AlignedArrayBuffer<16384>,
@@ -203,28 +389,16 @@ fn try_load_file(
             disk.read_exact_into(disk_buffer_size, disk_buffer);
 
             let slice = &disk_buffer.buffer[..usize::try_from(len).unwrap()];
-            unsafe { copy_to_protected_mode(dst.wrapping_add(total_offset), slice) };
-            let written =
-                unsafe { protected_mode::read_from_protected_mode(dst.wrapping_add(total_offset)) };
-            assert_eq!(slice[0], written);
+            // the copy checks the written bytes
+            unsafe { copy_to_protected_mode(dst + total_offset, slice) };
 
             offset += len;
-            total_offset += usize::try_from(len).unwrap();
+            total_offset += len;
         }
     }
     Some(file_size)
 }
 
-fn load_file(
-    file_name: &str,
-    dst: *mut u8,
-    fs: &mut fat::FileSystem<disk::DiskAccess>,
-    disk: &mut disk::DiskAccess,
-    disk_buffer: &mut AlignedArrayBuffer<16384>,
-) -> u64 {
-    try_load_file(file_name, dst, fs, disk, disk_buffer).expect("file not found")
-}
-
 /// Taken from https://github.com/rust-lang/rust/blob/e100ec5bc7cd768ec17d75448b29c9ab4a39272b/library/core/src/slice/mod.rs#L1673-L1677
 ///
 /// TODO replace with `split_array` feature in stdlib as soon as it's stabilized,
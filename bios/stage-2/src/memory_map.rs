@@ -1,9 +1,14 @@
-// From http://wiki.osdev.org/Detecting_Memory_(x86)#Getting_an_E820_Memory_Map
+// From http://wiki.osdev.org/Detecting_Memory_(x86)
 
 use crate::split_array_ref;
-use bootloader_x86_64_bios_common::{racy_cell::RacyCell, E820MemoryRegion};
+use bootloader_x86_64_bios_common::{racy_cell::RacyCell, E820MemoryRegion, MemoryMapSource};
 use core::arch::asm;
 
+/// The E820 region type of usable RAM.
+const E820_USABLE: u32 = 1;
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
 static MEMORY_MAP: RacyCell<[E820MemoryRegion; 100]> = RacyCell::new(
     [E820MemoryRegion {
         start_addr: 0,
@@ -13,12 +18,75 @@ static MEMORY_MAP: RacyCell<[E820MemoryRegion; 100]> = RacyCell::new(
     }; 100],
 );
 
+/// Queries the memory map from the BIOS.
+///
+/// Uses the INT 0x15, eax=0xE820 BIOS function if available. On older or buggy firmware,
+/// the memory map is synthesized from the memory sizes reported by the E801 or 88 functions.
+pub unsafe fn query_memory_map() -> Result<(&'static mut [E820MemoryRegion], MemoryMapSource), ()> {
+    let memory_map = unsafe { MEMORY_MAP.get_mut() };
+
+    match unsafe { query_e820(memory_map) } {
+        Ok(len) if len != 0 => return Ok((&mut memory_map[..len], MemoryMapSource::E820)),
+        _ => {}
+    }
+
+    let low_memory = unsafe { query_low_memory() };
+    if let Some((below_16m, above_16m)) = unsafe { query_e801() } {
+        let regions = [(0, low_memory), (MIB, below_16m), (16 * MIB, above_16m)];
+        let len = synthesize(memory_map, &regions);
+        return Ok((&mut memory_map[..len], MemoryMapSource::E801));
+    }
+    if let Some(above_1m) = unsafe { query_88() } {
+        let regions = [(0, low_memory), (MIB, above_1m)];
+        let len = synthesize(memory_map, &regions);
+        return Ok((&mut memory_map[..len], MemoryMapSource::Legacy88));
+    }
+
+    Err(())
+}
+
+/// Returns the lowest page-aligned address at or above `min_addr` at which `len` bytes of
+/// usable memory below `max_addr` are available.
+pub fn find_payload_region(
+    memory_map: &[E820MemoryRegion],
+    min_addr: u64,
+    max_addr: u64,
+    len: u64,
+) -> Option<u64> {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == E820_USABLE)
+        .filter_map(|region| {
+            let start = (u64::max(region.start_addr, min_addr) + 4095) / 4096 * 4096;
+            let end = u64::min(region.start_addr + region.len, max_addr);
+            (start + len <= end).then_some(start)
+        })
+        .min()
+}
+
+/// Writes the given `(start, len)` pairs as usable regions to the memory map.
+///
+/// Returns the number of written regions.
+fn synthesize(memory_map: &mut [E820MemoryRegion], regions: &[(u64, u64)]) -> usize {
+    let mut i = 0;
+    for &(start_addr, len) in regions.iter().filter(|(_, len)| *len != 0) {
+        memory_map[i] = E820MemoryRegion {
+            start_addr,
+            len,
+            region_type: E820_USABLE,
+            acpi_extended_attributes: 0,
+        };
+        i += 1;
+    }
+    i
+}
+
 /// use the INT 0x15, eax= 0xE820 BIOS function to get a memory map
-pub unsafe fn query_memory_map() -> Result<&'static mut [E820MemoryRegion], ()> {
+///
+/// Returns the number of regions written to `memory_map`.
+unsafe fn query_e820(memory_map: &mut [E820MemoryRegion]) -> Result<usize, ()> {
     const SMAP: u32 = 0x534D4150;
 
-    let memory_map = unsafe { MEMORY_MAP.get_mut() };
-
     let mut i = 0;
 
     let mut offset = 0;
@@ -69,5 +137,63 @@ pub unsafe fn query_memory_map() -> Result<&'static mut [E820MemoryRegion], ()>
         }
     }
 
-    Ok(&mut memory_map[..i])
+    Ok(i)
+}
+
+/// Uses the INT 0x15, ax=0xE801 BIOS function to get the size of the memory between 1MiB
+/// and 16MiB and above 16MiB (in bytes).
+unsafe fn query_e801() -> Option<(u64, u64)> {
+    let (ax, bx, cx, dx): (u16, u16, u16, u16);
+    let carry: u8;
+    unsafe {
+        asm!(
+            "push ebx",
+            "xor ebx, ebx",
+            "int 0x15",
+            "setc {carry}",
+            "mov {bx:x}, bx",
+            "pop ebx",
+            carry = out(reg_byte) carry,
+            bx = out(reg) bx,
+            inout("ax") 0xe801u16 => ax,
+            inout("cx") 0u16 => cx,
+            inout("dx") 0u16 => dx,
+        )
+    };
+    if carry != 0 {
+        return None;
+    }
+    // some BIOSes only report the sizes in ax/bx, others only in cx/dx
+    let (below_16m, above_16m) = if cx != 0 || dx != 0 {
+        (cx, dx)
+    } else {
+        (ax, bx)
+    };
+    if below_16m == 0 {
+        return None;
+    }
+    Some((u64::from(below_16m) * KIB, u64::from(above_16m) * 64 * KIB))
+}
+
+/// Uses the INT 0x15, ah=0x88 BIOS function to get the size of the memory above 1MiB (in
+/// bytes).
+unsafe fn query_88() -> Option<u64> {
+    let kib: u16;
+    let carry: u8;
+    unsafe {
+        asm!(
+            "int 0x15",
+            "setc {carry}",
+            carry = out(reg_byte) carry,
+            inout("ax") 0x8800u16 => kib,
+        )
+    };
+    (carry == 0 && kib != 0).then(|| u64::from(kib) * KIB)
+}
+
+/// Uses the INT 0x12 BIOS function to get the size of the conventional memory (in bytes).
+unsafe fn query_low_memory() -> u64 {
+    let kib: u16;
+    unsafe { asm!("int 0x12", out("ax") kib) };
+    u64::from(kib) * KIB
 }
@@ -1,4 +1,4 @@
-use bootloader_x86_64_bios_common::BiosInfo;
+use bootloader_x86_64_bios_common::{racy_cell::RacyCell, BiosInfo};
 use core::{arch::asm, mem::size_of};
 
 static GDT: GdtProtectedMode = GdtProtectedMode::new();
@@ -82,8 +82,43 @@ pub fn enter_unreal_mode() {
     }
 }
 
+/// The end of the memory that unreal mode can address.
+const UNREAL_MODE_END: u64 = 1 << 32;
+/// The virtual address of the 4MiB paging window for memory above 4GiB.
+const WINDOW: u32 = 0x4000_0000;
+const TWO_MIB: u64 = 2 * 1024 * 1024;
+
+/// The address of the paging structures for the window, or 0 if they weren't set up.
+static HIGH_MEMORY_TABLES: RacyCell<u32> = RacyCell::new(0);
+
+/// Sets up the PAE paging structures for accessing memory above 4GiB in the three pages at
+/// the given page-aligned address below 4GiB.
+///
+/// The first table is the page directory pointer table. The second one identity-maps the
+/// first 2MiB, which contain the code, the stack, and the buffers of this stage. The third
+/// one maps [`WINDOW`] to the memory that is accessed.
+pub unsafe fn init_high_memory_access(tables: u32) {
+    for offset in (0..3 * 4096).step_by(4) {
+        unsafe { write_u32(tables + offset, 0) };
+    }
+    unsafe {
+        // PAE page directory pointers only have a present bit
+        write_u32(tables, (tables + 4096) | 1);
+        write_u32(tables + 8, (tables + 2 * 4096) | 1);
+        // present, writable, 2MiB page
+        write_u32(tables + 4096, 0x83);
+        *high_memory_tables() = tables;
+    }
+}
+
+/// Copies `bytes` to the given physical address, which may be above 4GiB.
 #[no_mangle]
-pub unsafe fn copy_to_protected_mode(target: *mut u8, bytes: &[u8]) {
+pub unsafe fn copy_to_protected_mode(target: u64, bytes: &[u8]) {
+    if target + bytes.len() as u64 > UNREAL_MODE_END {
+        unsafe { copy_through_window(target, bytes.as_ptr() as *mut u8, bytes.len(), true) };
+        return;
+    }
+    let target = target as u32 as *mut u8;
     for (offset, byte) in bytes.iter().enumerate() {
         let dst = target.wrapping_add(offset);
         // we need to do the write in inline assembly because the compiler
@@ -95,6 +130,18 @@ pub unsafe fn copy_to_protected_mode(target: *mut u8, bytes: &[u8]) {
     }
 }
 
+/// Fills `buf` from the given physical address, which may be above 4GiB.
+pub unsafe fn read_bytes_from_protected_mode(source: u64, buf: &mut [u8]) {
+    if source + buf.len() as u64 > UNREAL_MODE_END {
+        unsafe { copy_through_window(source, buf.as_mut_ptr(), buf.len(), false) };
+        return;
+    }
+    for (offset, byte) in buf.iter_mut().enumerate() {
+        *byte =
+            unsafe { read_from_protected_mode((source as u32 as *mut u8).wrapping_add(offset)) };
+    }
+}
+
 #[no_mangle]
 pub unsafe fn read_from_protected_mode(ptr: *mut u8) -> u8 {
     let res;
@@ -106,6 +153,83 @@ pub unsafe fn read_from_protected_mode(ptr: *mut u8) -> u8 {
     res
 }
 
+/// Copies `len` bytes between `buf` and the given physical address through the paging
+/// window, in the direction given by `to_physical`.
+///
+/// Unreal mode can't reach memory above 4GiB, so this briefly enables protected mode and PAE
+/// paging. The code keeps running with the cached 16-bit code segment, and the data segments
+/// keep their flat 4GiB limit, so no segment registers need to be reloaded.
+unsafe fn copy_through_window(phys: u64, buf: *mut u8, len: usize, to_physical: bool) {
+    let tables = unsafe { *high_memory_tables() };
+    assert!(tables != 0 && len as u64 <= TWO_MIB);
+    if len == 0 {
+        return;
+    }
+    // map the two 2MiB pages that contain the range
+    let page = phys / TWO_MIB * TWO_MIB;
+    for (i, page) in [page, page + TWO_MIB].into_iter().enumerate() {
+        let entry = tables + 2 * 4096 + 8 * i as u32;
+        unsafe {
+            write_u32(entry, page as u32 | 0x83);
+            write_u32(entry + 4, (page >> 32) as u32);
+        }
+    }
+    let window = WINDOW + (phys % TWO_MIB) as u32;
+    let (src, dst) = match to_physical {
+        true => (buf as u32, window),
+        false => (window, buf as u32),
+    };
+    let remaining: u32;
+    unsafe {
+        asm!(
+            "cli",
+            "mov eax, cr4",
+            "or eax, 1 << 5",
+            "mov cr4, eax",
+            "mov cr3, {tables:e}",
+            "mov eax, cr0",
+            "or eax, 0x80000001",
+            "mov cr0, eax",
+            "2:",
+            "mov al, [{src:e}]",
+            "mov [{dst:e}], al",
+            "cmp al, [{dst:e}]",
+            "jne 3f",
+            "inc {src:e}",
+            "inc {dst:e}",
+            "dec {len:e}",
+            "jnz 2b",
+            "3:",
+            "mov eax, cr0",
+            "and eax, 0x7ffffffe",
+            "mov cr0, eax",
+            "mov eax, cr4",
+            "and eax, ~(1 << 5)",
+            "mov cr4, eax",
+            "sti",
+            tables = in(reg) tables,
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            len = inout(reg) len as u32 => remaining,
+            out("eax") _,
+            options(nostack),
+        );
+    }
+    assert_eq!(remaining, 0, "copy above 4GiB failed");
+}
+
+unsafe fn write_u32(addr: u32, value: u32) {
+    unsafe {
+        asm!("mov [{}], {:e}", in(reg) addr, in(reg) value, options(nostack, preserves_flags))
+    };
+}
+
+/// Returns the static without letting the compiler access it through a 16-bit absolute
+/// address, which can't reach it.
+fn high_memory_tables() -> *mut u32 {
+    core::hint::black_box(unsafe { HIGH_MEMORY_TABLES.get_mut() } as *mut u32)
+}
+
 pub fn enter_protected_mode_and_jump_to_stage_3(entry_point: *const u8, info: &mut BiosInfo) {
     unsafe { asm!("cli") };
     set_protected_mode_bit();
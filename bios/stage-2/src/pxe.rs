@@ -0,0 +1,318 @@
+use crate::{
+    disk::AlignedArrayBuffer,
+    protected_mode::{copy_to_protected_mode, enter_unreal_mode, read_from_protected_mode},
+    screen,
+};
+use core::{arch::asm, fmt::Write as _};
+
+const UNDI_SHUTDOWN: u16 = 0x0005;
+const STOP_UNDI: u16 = 0x0015;
+const TFTP_OPEN: u16 = 0x0020;
+const TFTP_CLOSE: u16 = 0x0021;
+const TFTP_READ: u16 = 0x0022;
+const TFTP_GET_FSIZE: u16 = 0x0025;
+const UNLOAD_STACK: u16 = 0x0070;
+const GET_CACHED_INFO: u16 = 0x0071;
+
+/// The reply of the DHCP or proxyDHCP server that named the boot file.
+const PACKET_TYPE_CACHED_REPLY: u16 = 3;
+/// Offsets into the BOOTP part of the cached DHCP packet.
+const BOOTP_SERVER_ADDRESS: u32 = 20;
+const BOOTP_GATEWAY_ADDRESS: u32 = 24;
+const BOOTP_FILE: u32 = 108;
+
+const TFTP_PORT: u16 = 69;
+/// The requested TFTP block size, the server may negotiate a smaller one.
+const TFTP_PACKET_SIZE: u16 = 1432;
+const FILE_NAME_LEN: usize = 128;
+
+/// A real-mode `segment:offset` pointer.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SegOff16 {
+    offset: u16,
+    segment: u16,
+}
+
+impl SegOff16 {
+    fn from_linear(address: u32) -> Self {
+        Self {
+            offset: (address & 0xf) as u16,
+            segment: (address >> 4) as u16,
+        }
+    }
+
+    fn linear(self) -> u32 {
+        u32::from(self.segment) * 16 + u32::from(self.offset)
+    }
+}
+
+#[repr(C, packed)]
+struct Status {
+    status: u16,
+}
+
+#[repr(C, packed)]
+struct UnloadStack {
+    status: u16,
+    reserved: [u8; 10],
+}
+
+#[repr(C, packed)]
+struct GetCachedInfo {
+    status: u16,
+    packet_type: u16,
+    buffer_size: u16,
+    buffer: SegOff16,
+    buffer_limit: u16,
+}
+
+#[repr(C, packed)]
+struct TftpOpen {
+    status: u16,
+    server_address: [u8; 4],
+    gateway_address: [u8; 4],
+    file_name: [u8; FILE_NAME_LEN],
+    /// In network byte order.
+    port: u16,
+    packet_size: u16,
+}
+
+#[repr(C, packed)]
+struct TftpRead {
+    status: u16,
+    packet_number: u16,
+    buffer_size: u16,
+    buffer: SegOff16,
+}
+
+#[repr(C, packed)]
+struct TftpGetFsize {
+    status: u16,
+    server_address: [u8; 4],
+    gateway_address: [u8; 4],
+    file_name: [u8; FILE_NAME_LEN],
+    file_size: u32,
+}
+
+/// Loads files from the TFTP server that the second stage was downloaded from.
+///
+/// File names are resolved against the directory of the boot file that the DHCP server
+/// announced, so the boot files can be placed next to the network boot program.
+pub struct Tftp {
+    /// The real-mode entry point of the `!PXE` structure.
+    entry_point: SegOff16,
+    server_address: [u8; 4],
+    gateway_address: [u8; 4],
+    directory: [u8; FILE_NAME_LEN],
+    directory_len: usize,
+}
+
+impl Tftp {
+    /// Locates the PXE stack of the firmware through the `INT 0x1A` installation check.
+    pub fn init() -> Result<Self, &'static str> {
+        let (ax, segment, offset): (u16, u16, u16);
+        unsafe {
+            asm!(
+                "push es",
+                "push bx",
+                "int 0x1a",
+                "mov {segment:x}, es",
+                "mov {offset:x}, bx",
+                "pop bx",
+                "pop es",
+                segment = out(reg) segment,
+                offset = out(reg) offset,
+                inout("ax") 0x5650u16 => ax,
+            );
+        }
+        enter_unreal_mode();
+        let pxenv = SegOff16 { offset, segment }.linear();
+        let mut signature = [0; 6];
+        read_bytes(pxenv, &mut signature);
+        if ax != 0x564e || signature != *b"PXENV+" {
+            return Err("no PXE stack found");
+        }
+        if read_u16(pxenv + 6) < 0x0201 {
+            return Err("PXE 2.1 or later is required");
+        }
+        let pxe = read_segoff(pxenv + 0x28).linear();
+        read_bytes(pxe, &mut signature[..4]);
+        if signature[..4] != *b"!PXE" {
+            return Err("invalid !PXE structure");
+        }
+
+        let mut tftp = Self {
+            entry_point: read_segoff(pxe + 0x10),
+            server_address: [0; 4],
+            gateway_address: [0; 4],
+            directory: [0; FILE_NAME_LEN],
+            directory_len: 0,
+        };
+        let mut info = GetCachedInfo {
+            status: 0,
+            packet_type: PACKET_TYPE_CACHED_REPLY,
+            buffer_size: 0,
+            buffer: SegOff16 {
+                offset: 0,
+                segment: 0,
+            },
+            buffer_limit: 0,
+        };
+        tftp.call(GET_CACHED_INFO, &mut info);
+        if info.status != 0 {
+            return Err("failed to read the cached DHCP reply");
+        }
+        let packet = info.buffer.linear();
+        read_bytes(packet + BOOTP_SERVER_ADDRESS, &mut tftp.server_address);
+        read_bytes(packet + BOOTP_GATEWAY_ADDRESS, &mut tftp.gateway_address);
+        if tftp.server_address == [0; 4] {
+            return Err("the DHCP reply names no boot server");
+        }
+        // the boot file name is NUL-terminated, so only a slash before the first NUL counts
+        read_bytes(packet + BOOTP_FILE, &mut tftp.directory);
+        let boot_file_len = tftp.directory.iter().position(|&b| b == 0).unwrap_or(0);
+        tftp.directory_len = tftp.directory[..boot_file_len]
+            .iter()
+            .rposition(|&b| b == b'/')
+            .map_or(0, |slash| slash + 1);
+        Ok(tftp)
+    }
+
+    /// Returns the size of the given file, or `None` if the server doesn't have it.
+    ///
+    /// The server must support the `tsize` option.
+    pub fn file_size(&self, name: &str) -> Option<u64> {
+        let mut request = TftpGetFsize {
+            status: 0,
+            server_address: self.server_address,
+            gateway_address: self.gateway_address,
+            file_name: self.file_name(name),
+            file_size: 0,
+        };
+        self.call(TFTP_GET_FSIZE, &mut request);
+        (request.status == 0).then(|| request.file_size.into())
+    }
+
+    /// Downloads the given file to `dst`, using `buffer` for the received packets.
+    ///
+    /// Returns the file size, or `None` if the server doesn't have the file.
+    pub fn try_load_file(
+        &self,
+        name: &str,
+        dst: u64,
+        buffer: &mut AlignedArrayBuffer<0x4000>,
+    ) -> Option<u64> {
+        let mut open = TftpOpen {
+            status: 0,
+            server_address: self.server_address,
+            gateway_address: self.gateway_address,
+            file_name: self.file_name(name),
+            port: TFTP_PORT.to_be(),
+            packet_size: TFTP_PACKET_SIZE,
+        };
+        self.call(TFTP_OPEN, &mut open);
+        if open.status != 0 {
+            return None;
+        }
+        let packet_size = usize::from(open.packet_size);
+        assert!(packet_size <= buffer.buffer.len());
+
+        let mut total_len = 0;
+        loop {
+            let mut read = TftpRead {
+                status: 0,
+                packet_number: 0,
+                buffer_size: 0,
+                buffer: SegOff16::from_linear(buffer.buffer.as_ptr() as u32),
+            };
+            self.call(TFTP_READ, &mut read);
+            if read.status != 0 {
+                panic!("failed to read `{name}` over TFTP");
+            }
+            let len = usize::from(read.buffer_size);
+            unsafe { copy_to_protected_mode(dst + total_len as u64, &buffer.buffer[..len]) };
+            total_len += len;
+            if len < packet_size {
+                break;
+            }
+        }
+        self.call(TFTP_CLOSE, &mut Status { status: 0 });
+        Some(total_len as u64)
+    }
+
+    /// Shuts down the network card and removes the PXE stack from memory, like `pxelinux`
+    /// does before it starts a kernel.
+    pub fn shutdown(self) {
+        let mut status = Status { status: 0 };
+        self.call(UNDI_SHUTDOWN, &mut status);
+        let mut unload = UnloadStack {
+            status: 0,
+            reserved: [0; 10],
+        };
+        self.call(UNLOAD_STACK, &mut unload);
+        let mut stop = Status { status: 0 };
+        self.call(STOP_UNDI, &mut stop);
+        if status.status != 0 || unload.status != 0 || stop.status != 0 {
+            writeln!(screen::Writer, "failed to unload the PXE stack").unwrap();
+        }
+    }
+
+    /// Returns the NUL-terminated path of the given file on the TFTP server.
+    fn file_name(&self, name: &str) -> [u8; FILE_NAME_LEN] {
+        let mut path = [0; FILE_NAME_LEN];
+        let len = self.directory_len + name.len();
+        assert!(len < FILE_NAME_LEN, "TFTP path is too long");
+        path[..self.directory_len].copy_from_slice(&self.directory[..self.directory_len]);
+        path[self.directory_len..len].copy_from_slice(name.as_bytes());
+        path
+    }
+
+    /// Calls the `!PXE` API with the given opcode and parameter structure.
+    ///
+    /// The API preserves the registers, but may leave unreal mode, so it is entered again
+    /// afterwards.
+    fn call<T>(&self, opcode: u16, params: &mut T) {
+        let params = SegOff16::from_linear(params as *mut T as u32);
+        unsafe {
+            asm!(
+                "pushal",
+                "pushw %ds",
+                "pushw %es",
+                "pushw {segment:x}",
+                "pushw {offset:x}",
+                "pushw {opcode:x}",
+                "lcallw *({entry_point:e})",
+                "addw $6, %sp",
+                "popw %es",
+                "popw %ds",
+                "popal",
+                entry_point = in(reg) &self.entry_point as *const SegOff16 as u32,
+                segment = in(reg) params.segment,
+                offset = in(reg) params.offset,
+                opcode = in(reg) opcode,
+                options(att_syntax),
+            );
+        }
+        enter_unreal_mode();
+    }
+}
+
+fn read_bytes(address: u32, bytes: &mut [u8]) {
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { read_from_protected_mode((address as usize + offset) as *mut u8) };
+    }
+}
+
+fn read_u16(address: u32) -> u16 {
+    let mut bytes = [0; 2];
+    read_bytes(address, &mut bytes);
+    u16::from_le_bytes(bytes)
+}
+
+fn read_segoff(address: u32) -> SegOff16 {
+    SegOff16 {
+        offset: read_u16(address),
+        segment: read_u16(address + 2),
+    }
+}
@@ -0,0 +1,38 @@
+.section .pxe_start, "awx"
+.global _pxe_start
+.code16
+
+# The PXE firmware loads the network boot program to 0x7c00 and jumps to it. The first sector
+# of the network boot program only jumps here, because the second stage starts at 0x7e00.
+
+_pxe_start:
+    cli
+    # zero segment registers
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    cld
+
+    # initialize stack below the network boot program, like the boot sector does
+    mov sp, 0x7c00
+    sti
+
+enable_a20:
+    # enable A20-Line via IO-Port 92, might not work on all motherboards
+    in al, 0x92
+    test al, 2
+    jnz enable_a20_after
+    or al, 2
+    and al, 0xFE
+    out 0x92, al
+enable_a20_after:
+
+    call pxe_start
+
+spin:
+    hlt
+    jmp spin
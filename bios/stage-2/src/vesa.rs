@@ -52,12 +52,13 @@ impl<'a> VesaInfo<'a> {
         }
     }
 
-    pub fn get_best_mode(
-        &mut self,
-        max_width: u16,
-        max_height: u16,
-    ) -> Result<Option<VesaModeInfo>, u16> {
-        let mut best: Option<VesaModeInfo> = None;
+    /// Returns the linear framebuffer mode that matches the given preferences most closely.
+    ///
+    /// Modes below the minimum resolution are skipped. Modes with the preferred bits per
+    /// pixel come first, then modes with a known pixel format, then the modes whose
+    /// resolution is closest to the preferred one. The first of equal modes wins.
+    pub fn get_best_mode(&mut self, preferred: &VideoMode) -> Option<VesaModeInfo> {
+        let mut best: Option<((bool, bool, u32), VesaModeInfo)> = None;
         for i in 0.. {
             let mode = match self.get_mode(i) {
                 Some(mode) => mode,
@@ -79,24 +80,22 @@ impl<'a> VesaInfo<'a> {
                 continue;
             }
 
-            if mode_info.width > max_width || mode_info.height > max_height {
+            if mode_info.width < preferred.min_width || mode_info.height < preferred.min_height {
                 continue;
             }
 
-            let replace = match &best {
-                None => true,
-                Some(best) => {
-                    best.pixel_format.is_unknown()
-                        || best.width < mode_info.width
-                        || (best.width == mode_info.width && best.height < mode_info.height)
-                }
-            };
-
-            if replace {
-                best = Some(mode_info);
+            let score = (
+                preferred.bits_per_pixel != 0
+                    && mode_info.bits_per_pixel != preferred.bits_per_pixel,
+                mode_info.pixel_format.is_unknown(),
+                u32::from(mode_info.width.abs_diff(preferred.width))
+                    + u32::from(mode_info.height.abs_diff(preferred.height)),
+            );
+            if best.as_ref().map_or(true, |(best, _)| score < *best) {
+                best = Some((score, mode_info));
             }
         }
-        Ok(best)
+        best.map(|(_, mode_info)| mode_info)
     }
 
     fn get_mode(&self, index: usize) -> Option<u16> {
@@ -117,6 +116,34 @@ impl<'a> VesaInfo<'a> {
     }
 }
 
+/// The framebuffer settings of the kernel config, as written by the disk image builder.
+pub struct VideoMode {
+    min_width: u16,
+    min_height: u16,
+    width: u16,
+    height: u16,
+    bits_per_pixel: u8,
+}
+
+impl VideoMode {
+    /// Parses the `video-mode` file, where `0` stands for unset values.
+    ///
+    /// Without a preferred resolution, the mode closest to 1280x720 is picked.
+    pub fn parse(bytes: [u8; 9]) -> Self {
+        let value = |i: usize, default| match u16::from_le_bytes([bytes[i], bytes[i + 1]]) {
+            0 => default,
+            value => value,
+        };
+        Self {
+            min_width: value(0, 0),
+            min_height: value(2, 0),
+            width: value(4, 1280),
+            height: value(6, 720),
+            bits_per_pixel: bytes[8],
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct VesaModeInfo {
     mode: u16,
@@ -127,6 +154,7 @@ pub struct VesaModeInfo {
     pub bytes_per_pixel: u8,
     pub pixel_format: PixelFormat,
 
+    bits_per_pixel: u8,
     memory_model: u8,
     attributes: u16,
 }
@@ -199,6 +227,7 @@ impl VesaModeInfo {
                     framebuffer_start: block.framebuffer,
                     bytes_per_scanline: block.bytes_per_scanline,
                     bytes_per_pixel: block.bits_per_pixel / 8,
+                    bits_per_pixel: block.bits_per_pixel,
                     pixel_format: match (
                         block.red_position,
                         block.green_position,
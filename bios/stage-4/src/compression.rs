@@ -0,0 +1,27 @@
+use crate::memory_descriptor::find_region;
+use bootloader_x86_64_bios_common::E820MemoryRegion;
+use bootloader_x86_64_common::{
+    compression,
+    error::{self, BootError},
+};
+use core::slice;
+use x86_64::structures::paging::PhysFrame;
+
+/// Decompresses the given kernel to the first page-aligned address at or above `min_frame`
+/// that is backed by usable memory in the identity-mapped range.
+///
+/// Returns `None` if the kernel is not compressed.
+pub fn decompress_kernel(
+    memory_map: &[E820MemoryRegion],
+    min_frame: PhysFrame,
+    kernel: &[u8],
+) -> Option<&'static [u8]> {
+    let len = compression::decompressed_len(kernel)?;
+    let start = find_region(memory_map, min_frame.start_address(), len as u64)
+        .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the decompressed kernel")));
+    let out = unsafe { slice::from_raw_parts_mut(start.as_u64() as *mut u8, len) };
+    if let Err(reason) = compression::decompress(kernel, out) {
+        error::fail(BootError::InvalidKernel(reason));
+    }
+    Some(out)
+}
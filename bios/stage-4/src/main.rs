@@ -3,14 +3,19 @@
 
 use crate::memory_descriptor::MemoryRegion;
 use bootloader_api::{
-    config::{LevelFilter, LoggerStatus},
-    info::{FrameBufferInfo, PixelFormat},
+    info::{BootDevice, FrameBufferInfo, Optional, PartitionSignature, PixelFormat},
+    BootloaderConfig,
+};
+use bootloader_x86_64_bios_common::{
+    BiosFramebufferInfo, BiosInfo, E820MemoryRegion, IDENTITY_MAPPED_END, STACK_END, STACK_START,
 };
-use bootloader_x86_64_bios_common::{BiosFramebufferInfo, BiosInfo, E820MemoryRegion};
 use bootloader_x86_64_common::RawFrameBufferInfo;
 use bootloader_x86_64_common::{
-    legacy_memory_region::LegacyFrameAllocator, load_and_switch_to_kernel, Kernel, PageTables,
-    SystemInfo,
+    error::{self, BootError, BootStage},
+    legacy_memory_region::LegacyFrameAllocator,
+    load_and_switch_to_kernel,
+    logger::{self, Event},
+    partition_key, power, stack, verify_boot_metadata, Kernel, PageTables, SystemInfo,
 };
 use core::{cmp, slice};
 use usize_conversions::usize_from;
@@ -22,11 +27,19 @@ use x86_64::{PhysAddr, VirtAddr};
 
 const GIGABYTE: u64 = 4096 * 512 * 512;
 
+mod compression;
 mod memory_descriptor;
+mod serial_load;
+mod signature;
 
 #[no_mangle]
 #[link_section = ".start"]
 pub extern "C" fn _start(info: &mut BiosInfo) -> ! {
+    error::set_stage(BootStage::BiosStage4);
+    // the second stage poisoned the stack, which stage 3 and 4 still use
+    unsafe { stack::watch(STACK_START.into(), STACK_END.into()) };
+    let rsdp_addr = detect_rsdp();
+    power::set_rsdp_addr(rsdp_addr);
     let memory_map: &mut [E820MemoryRegion] = unsafe {
         core::slice::from_raw_parts_mut(
             info.memory_map_addr as *mut _,
@@ -44,7 +57,7 @@ pub extern "C" fn _start(info: &mut BiosInfo) -> ! {
                 r.start_addr + r.len
             })
             .max()
-            .expect("no physical memory regions found");
+            .unwrap_or_else(|| error::fail(BootError::MemoryMapUnavailable));
         // Don't consider addresses > 4GiB when determining the maximum physical
         // address for the bootloader, as we are in protected mode and cannot
         // address more than 4 GiB of memory anyway.
@@ -56,18 +69,45 @@ pub extern "C" fn _start(info: &mut BiosInfo) -> ! {
         PhysAddr::new(info.kernel.start)
     };
     let kernel_size = info.kernel.len;
-    let next_free_frame = match info.ramdisk.len {
-        0 => PhysFrame::containing_address(kernel_start + kernel_size - 1u64) + 1,
-        _ => {
-            PhysFrame::containing_address(PhysAddr::new(
-                info.ramdisk.start + info.ramdisk.len - 1u64,
-            )) + 1
-        }
+    // the payload files are loaded back to back, the frames behind the last one are free
+    let payload_end = [info.ramdisk, info.boot_metadata, info.partition_keys]
+        .iter()
+        .filter(|region| region.len != 0)
+        .map(|region| region.start + region.len)
+        .fold(kernel_start.as_u64() + kernel_size, cmp::max);
+    let mut next_free_frame = PhysFrame::containing_address(PhysAddr::new(payload_end - 1)) + 1;
+    let kernel_slice = if info.serial_kernel_load {
+        // the kernel loaded from disk stays in memory, but is not used
+        let kernel = serial_load::receive_kernel(memory_map, next_free_frame);
+        let kernel_end = PhysAddr::new(kernel.as_ptr_range().end as u64);
+        next_free_frame = PhysFrame::containing_address(kernel_end.align_up(4096u64));
+        kernel
+    } else {
+        let ptr = kernel_start.as_u64() as *const u8;
+        unsafe { slice::from_raw_parts(ptr, usize_from(kernel_size)) }
     };
+    // check the loaded bytes before anything of the kernel is decompressed or parsed
+    if let Err(reason) = signature::verify(kernel_slice) {
+        error::fail(BootError::UntrustedKernel(reason));
+    }
+    // the compressed kernel stays in memory, but is not used
+    let compressed_len = kernel_slice.len();
+    // compressed kernels are signed after compression
+    let compressed = bootloader_x86_64_common::signature::content(kernel_slice);
+    let decompressed = compression::decompress_kernel(memory_map, next_free_frame, compressed);
+    if let Some(kernel) = decompressed {
+        let kernel_end = PhysAddr::new(kernel.as_ptr_range().end as u64);
+        next_free_frame = PhysFrame::containing_address(kernel_end.align_up(4096u64));
+    }
+    let kernel_slice = decompressed.unwrap_or(kernel_slice);
+    let kernel = Kernel::parse(kernel_slice);
+    error::configure(&kernel.config);
+
     let mut frame_allocator = LegacyFrameAllocator::new_starting_at(
         next_free_frame,
         memory_map.iter().copied().map(MemoryRegion),
     );
+    frame_allocator.prefer_frames_above(PhysAddr::new(kernel.config.min_frame_address));
 
     // We identity-mapped all memory, so the offset between physical and virtual addresses is 0
     let phys_offset = VirtAddr::new(0);
@@ -80,7 +120,7 @@ pub extern "C" fn _start(info: &mut BiosInfo) -> ! {
     // identity-map remaining physical memory (first 10 gigabytes are already identity-mapped)
     {
         let start_frame: PhysFrame<Size2MiB> =
-            PhysFrame::containing_address(PhysAddr::new(GIGABYTE * 10));
+            PhysFrame::containing_address(PhysAddr::new(IDENTITY_MAPPED_END));
         let end_frame = PhysFrame::containing_address(PhysAddr::new(max_phys_addr - 1));
         for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
             let flusher = unsafe {
@@ -107,45 +147,82 @@ pub extern "C" fn _start(info: &mut BiosInfo) -> ! {
 
     let page_tables = create_page_tables(&mut frame_allocator);
 
-    let kernel_slice = {
-        let ptr = kernel_start.as_u64() as *const u8;
-        unsafe { slice::from_raw_parts(ptr, usize_from(kernel_size)) }
-    };
-    let kernel = Kernel::parse(kernel_slice);
-
-    let framebuffer_info = init_logger(
-        info.framebuffer,
-        kernel.config.log_level,
-        kernel.config.frame_buffer_logger_status,
-        kernel.config.serial_logger_status,
-    );
+    let framebuffer_info = init_logger(info.framebuffer, &kernel.config);
 
+    logger::event(Event::Stage("bios-stage-4"));
     log::info!("4th Stage");
     log::info!("{info:x?}");
     log::info!("BIOS boot");
+    log::info!("Memory map detected via {:?}", info.memory_map_source);
+    for region in memory_map.iter() {
+        log::trace!("{region:x?}");
+    }
+    if info.serial_kernel_load {
+        log::info!("Kernel received over serial at {:p}", kernel_slice.as_ptr());
+    }
+    if decompressed.is_some() {
+        log::info!(
+            "Decompressed the kernel from {compressed_len} to {} bytes at {:p}",
+            kernel_slice.len(),
+            kernel_slice.as_ptr()
+        );
+    }
+    let boot_metadata = match info.boot_metadata.len {
+        0 => None,
+        // the metadata on disk belongs to the kernel on disk
+        _ if info.serial_kernel_load => {
+            log::error!(
+                "Kernel received over serial has no boot metadata, so it gets no command line \
+                and extra mappings"
+            );
+            None
+        }
+        len => {
+            let ptr = info.boot_metadata.start as *const u8;
+            let raw = unsafe { slice::from_raw_parts(ptr, usize_from(len)) };
+            verify_boot_metadata(raw, &kernel)
+        }
+    };
+
+    let partition_keys = match info.partition_keys.len {
+        0 => None,
+        len => {
+            let ptr = info.partition_keys.start as *const u8;
+            let file = unsafe { slice::from_raw_parts(ptr, usize_from(len)) };
+            Some(partition_key::unlock_partitions(file))
+        }
+    };
 
     let system_info = SystemInfo {
         framebuffer: Some(RawFrameBufferInfo {
             addr: PhysAddr::new(info.framebuffer.region.start),
             info: framebuffer_info,
         }),
-        rsdp_addr: detect_rsdp(),
+        rsdp_addr,
+        smbios_addr: detect_smbios(),
         ramdisk_addr: match info.ramdisk.len {
             0 => None,
             _ => Some(info.ramdisk.start),
         },
         ramdisk_len: info.ramdisk.len,
+        boot_device: (info.boot_partition.partition_number != 0).then(|| BootDevice {
+            partition_number: info.boot_partition.partition_number,
+            partition_signature: PartitionSignature::Mbr(info.boot_partition.disk_signature),
+            device_path: Optional::None,
+        }),
+        boot_metadata,
+        bootloader_heap: None,
+        boot_counter: None,
+        boot_slot: None,
+        partition_keys,
+        uefi_runtime: None,
+        uefi_callback: None,
     };
 
     load_and_switch_to_kernel(kernel, frame_allocator, page_tables, system_info);
 }
 
-fn init_logger(
-    info: BiosFramebufferInfo,
-    log_level: LevelFilter,
-    frame_buffer_logger_status: LoggerStatus,
-    serial_logger_status: LoggerStatus,
-) -> FrameBufferInfo {
+fn init_logger(info: BiosFramebufferInfo, config: &BootloaderConfig) -> FrameBufferInfo {
     let framebuffer_info = FrameBufferInfo {
         byte_len: info.region.len.try_into().unwrap(),
         width: info.width.into(),
@@ -174,13 +251,7 @@ fn init_logger(
         )
     };
 
-    bootloader_x86_64_common::init_logger(
-        framebuffer,
-        framebuffer_info,
-        log_level,
-        frame_buffer_logger_status,
-        serial_logger_status,
-    );
+    bootloader_x86_64_common::init_logger(framebuffer, framebuffer_info, config);
 
     framebuffer_info
 }
@@ -200,7 +271,9 @@ fn create_page_tables(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Pa
     // create a new page table hierarchy for the kernel
     let (kernel_page_table, kernel_level_4_frame) = {
         // get an unused frame for new level 4 page table
-        let frame: PhysFrame = frame_allocator.allocate_frame().expect("no unused frames");
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the page tables")));
         log::info!("New page table at: {frame:#?}");
         // get the corresponding virtual address
         let addr = phys_offset + frame.start_address().as_u64();
@@ -255,16 +328,38 @@ fn detect_rsdp() -> Option<PhysAddr> {
     }
 }
 
+/// Searches the BIOS area for the SMBIOS entry point, preferring the SMBIOS 3 one.
+///
+/// The entry points are located at a 16-byte boundary between `0xF0000` and `0xFFFFF`.
+fn detect_smbios() -> Option<PhysAddr> {
+    // the length of the entry point structure is stored in the structure itself
+    fn checksum_ok(addr: usize, len: usize) -> bool {
+        let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+    let anchor = |addr: usize, anchor: &[u8]| {
+        let bytes = unsafe { slice::from_raw_parts(addr as *const u8, anchor.len()) };
+        bytes == anchor
+    };
+    let length =
+        |addr: usize, offset: usize| usize::from(unsafe { *((addr + offset) as *const u8) });
+
+    let candidates = || (0xF0000..0x100000).step_by(16);
+    let smbios3 =
+        candidates().find(|&addr| anchor(addr, b"_SM3_") && checksum_ok(addr, length(addr, 6)));
+    let smbios = smbios3.or_else(|| {
+        candidates().find(|&addr| {
+            anchor(addr, b"_SM_")
+                && checksum_ok(addr, length(addr, 5))
+                && anchor(addr + 0x10, b"_DMI_")
+        })
+    });
+    smbios.map(|addr| PhysAddr::new(addr as u64))
+}
+
 #[cfg(target_os = "none")]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    unsafe {
-        bootloader_x86_64_common::logger::LOGGER
-            .get()
-            .map(|l| l.force_unlock())
-    };
-    log::error!("{info}");
-    loop {
-        unsafe { core::arch::asm!("cli; hlt") };
-    }
+    error::report(BootError::Internal, Some(info));
+    power::wait_for_action()
 }
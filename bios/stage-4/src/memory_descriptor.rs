@@ -1,8 +1,25 @@
 use bootloader_api::info::MemoryRegionKind;
-use bootloader_x86_64_bios_common::E820MemoryRegion;
+use bootloader_x86_64_bios_common::{E820MemoryRegion, IDENTITY_MAPPED_END};
 use bootloader_x86_64_common::legacy_memory_region::LegacyMemoryRegion;
 use x86_64::PhysAddr;
 
+/// Returns the first page-aligned address at or above `min_addr` that starts `len` bytes
+/// of usable memory in the identity-mapped range.
+pub fn find_region(
+    memory_map: &[E820MemoryRegion],
+    min_addr: PhysAddr,
+    len: u64,
+) -> Option<PhysAddr> {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == 1)
+        .find_map(|region| {
+            let start = PhysAddr::new(region.start_addr.max(min_addr.as_u64())).align_up(4096u64);
+            let end = (region.start_addr + region.len).min(IDENTITY_MAPPED_END);
+            (start.as_u64().checked_add(len)? <= end).then_some(start)
+        })
+}
+
 impl LegacyMemoryRegion for MemoryRegion {
     fn start(&self) -> PhysAddr {
         PhysAddr::new(self.0.start_addr)
@@ -0,0 +1,68 @@
+use crate::memory_descriptor::find_region;
+use bootloader_x86_64_bios_common::E820MemoryRegion;
+use bootloader_x86_64_common::{
+    error::{self, BootError},
+    serial_load::{self, SerialLink},
+};
+use core::slice;
+use x86_64::{instructions::port::Port, structures::paging::PhysFrame};
+
+const COM1: u16 = 0x3f8;
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The number of line status polls without new data after which a read times out.
+const TIMEOUT_POLLS: u32 = 1_000_000;
+
+/// Waits for the host to send a kernel over COM1 and places it at the first page-aligned
+/// address at or above `min_frame` that is backed by usable memory in the identity-mapped range.
+pub fn receive_kernel(memory_map: &[E820MemoryRegion], min_frame: PhysFrame) -> &'static [u8] {
+    serial_load::receive_kernel(&mut SerialPort::init(), |len| {
+        let start = find_region(memory_map, min_frame.start_address(), len as u64)
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the serial kernel")));
+        unsafe { slice::from_raw_parts_mut(start.as_u64() as *mut u8, len) }
+    })
+}
+
+struct SerialPort {
+    data: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    /// Configures COM1 for 115200 baud, 8 data bits, no parity, and one stop bit.
+    fn init() -> Self {
+        let configuration: [(u16, u8); 7] = [
+            (1, 0x00), // disable interrupts
+            (3, 0x80), // enable the divisor latch
+            (0, 0x01), // divisor 1, i.e. 115200 baud
+            (1, 0x00),
+            (3, 0x03), // 8N1
+            (2, 0xc7), // enable and clear the FIFOs
+            (4, 0x03), // DTR and RTS
+        ];
+        for (offset, value) in configuration {
+            unsafe { Port::new(COM1 + offset).write(value) };
+        }
+        Self {
+            data: Port::new(COM1),
+            line_status: Port::new(COM1 + 5),
+        }
+    }
+}
+
+impl SerialLink for SerialPort {
+    fn read(&mut self) -> Option<u8> {
+        for _ in 0..TIMEOUT_POLLS {
+            if unsafe { self.line_status.read() } & DATA_READY != 0 {
+                return Some(unsafe { self.data.read() });
+            }
+        }
+        None
+    }
+
+    fn write(&mut self, byte: u8) {
+        while unsafe { self.line_status.read() } & TRANSMIT_EMPTY == 0 {}
+        unsafe { self.data.write(byte) };
+    }
+}
@@ -0,0 +1,44 @@
+use bootloader_x86_64_common::signature::{self, KeyRole, KEY_ENTRY_LEN};
+use core::ptr;
+
+/// The maximum number of trusted keys, keep in sync with `src/signing.rs` of the `bootloader`
+/// crate.
+const MAX_TRUSTED_KEYS: usize = 8;
+
+/// The trusted keys, which the disk image builder writes into its copy of this stage.
+///
+/// The builder finds the structure through its magic, which must not appear anywhere else in
+/// the binary.
+#[repr(C)]
+struct TrustedKeys {
+    magic: [u8; 8],
+    /// The number of key entries.
+    len: u32,
+    keys: [u8; MAX_TRUSTED_KEYS * KEY_ENTRY_LEN],
+}
+
+static TRUSTED_KEYS: TrustedKeys = TrustedKeys {
+    magic: *b"BLTRUSTK",
+    len: 0,
+    keys: [0; MAX_TRUSTED_KEYS * KEY_ENTRY_LEN],
+};
+
+/// Checks the signature of the kernel against the trusted keys that the builder embedded into
+/// this stage.
+///
+/// Kernels are accepted without a signature if there are no trusted keys. There is no
+/// developer mode on BIOS systems, so kernels signed with a developer key are rejected.
+/// Returns the reason if the kernel is rejected.
+pub fn verify(kernel: &[u8]) -> Result<(), &'static str> {
+    // the builder patches the binary, so the initial value must not be constant-folded
+    let trusted_keys = unsafe { ptr::read_volatile(&TRUSTED_KEYS) };
+    let len = match usize::try_from(trusted_keys.len) {
+        Ok(0) => return Ok(()),
+        Ok(len) if len <= MAX_TRUSTED_KEYS => len,
+        _ => return Err("invalid trusted keys"),
+    };
+    match signature::verify(kernel, &trusted_keys.keys[..len * KEY_ENTRY_LEN])? {
+        KeyRole::Production => Ok(()),
+        KeyRole::Developer => Err("kernel is signed with a developer key, which BIOS can't boot"),
+    }
+}
@@ -4,6 +4,17 @@ use futures_concurrency::future::Join;
 use std::path::{Path, PathBuf};
 const BOOTLOADER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// The boot sector code must end before the partition table at offset 446.
+const BOOT_SECTOR_CODE_LIMIT: u64 = 446;
+/// The second stage must end before the end marker in `stage-2-link.ld`, which marks the end
+/// of the conventional memory that is reserved for it.
+const STAGE_2_END_LIMIT: u64 = 0x0007_FFFF - 2;
+/// The default size limit of the UEFI executable, which can be overridden through the
+/// `BOOTLOADER_UEFI_SIZE_LIMIT` environment variable.
+const DEFAULT_UEFI_SIZE_LIMIT: u64 = 2 * 1024 * 1024;
+/// The number of symbols that are listed when a stage exceeds its size budget.
+const LARGEST_SYMBOLS: usize = 10;
+
 fn main() {
     block_on((uefi_main(), bios_main()).join());
 }
@@ -70,11 +81,15 @@ async fn build_uefi_bootloader(out_dir: &Path) -> PathBuf {
     }
     cmd.arg("--locked");
     cmd.arg("--target").arg("x86_64-unknown-uefi");
+    cmd.arg("--profile")
+        .arg(stage_profile("release", "min-size-uefi"));
     cmd.arg("-Zbuild-std=core")
         .arg("-Zbuild-std-features=compiler-builtins-mem");
     cmd.arg("--root").arg(out_dir);
     cmd.env_remove("RUSTFLAGS");
     cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    set_min_size_rustflags(&mut cmd, "/OPT:REF", &[]);
+    set_coverage_feature(&mut cmd);
     let status = cmd
         .status()
         .await
@@ -85,6 +100,17 @@ async fn build_uefi_bootloader(out_dir: &Path) -> PathBuf {
             path.exists(),
             "uefi bootloader executable does not exist after building"
         );
+        println!("cargo:rerun-if-env-changed=BOOTLOADER_UEFI_SIZE_LIMIT");
+        let limit = match std::env::var("BOOTLOADER_UEFI_SIZE_LIMIT") {
+            Ok(limit) => limit
+                .parse()
+                .expect("BOOTLOADER_UEFI_SIZE_LIMIT must be a number of bytes"),
+            Err(_) => DEFAULT_UEFI_SIZE_LIMIT,
+        };
+        let size = std::fs::metadata(&path)
+            .expect("failed to read size of uefi bootloader executable")
+            .len();
+        check_size_budget("UEFI bootloader", &path, size, limit).await;
         path
     } else {
         panic!("failed to build uefi bootloader");
@@ -132,6 +158,15 @@ async fn build_bios_boot_sector(out_dir: &Path) -> PathBuf {
     } else {
         panic!("failed to build bios boot sector");
     };
+    let code_len =
+        symbol_address(&elf_path, "_mbr_end").await - symbol_address(&elf_path, "_mbr_start").await;
+    check_size_budget(
+        "BIOS boot sector",
+        &elf_path,
+        code_len,
+        BOOT_SECTOR_CODE_LIMIT,
+    )
+    .await;
     convert_elf_to_bin(elf_path).await
 }
 
@@ -157,12 +192,14 @@ async fn build_bios_stage_2(out_dir: &Path) -> PathBuf {
     }
     cmd.arg("--locked");
     cmd.arg("--target").arg("i386-code16-stage-2.json");
-    cmd.arg("--profile").arg("stage-2");
+    cmd.arg("--profile")
+        .arg(stage_profile("stage-2", "min-size"));
     cmd.arg("-Zbuild-std=core")
         .arg("-Zbuild-std-features=compiler-builtins-mem");
     cmd.arg("--root").arg(out_dir);
     cmd.env_remove("RUSTFLAGS");
     cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    set_min_size_rustflags(&mut cmd, "--gc-sections", &[]);
     cmd.env_remove("RUSTC_WORKSPACE_WRAPPER"); // used by clippy
     let status = cmd
         .status()
@@ -178,6 +215,20 @@ async fn build_bios_stage_2(out_dir: &Path) -> PathBuf {
     } else {
         panic!("failed to build bios second stage");
     };
+    let start = symbol_address(&elf_path, "_start").await;
+    let len = symbol_address(&elf_path, "_second_stage_end").await - start;
+    check_size_budget(
+        "BIOS second stage",
+        &elf_path,
+        len,
+        STAGE_2_END_LIMIT - start,
+    )
+    .await;
+    // the flat binary is padded up to the end marker, so report the used length separately
+    println!("cargo:rustc-env=BIOS_STAGE_2_LEN={len}");
+    // the first sector of the PXE network boot program jumps to this entry point
+    let pxe_entry = symbol_address(&elf_path, "_pxe_start").await;
+    println!("cargo:rustc-env=BIOS_STAGE_2_PXE_ENTRY={pxe_entry}");
     convert_elf_to_bin(elf_path).await
 }
 
@@ -199,12 +250,14 @@ async fn build_bios_stage_3(out_dir: &Path) -> PathBuf {
     }
     cmd.arg("--locked");
     cmd.arg("--target").arg("i686-stage-3.json");
-    cmd.arg("--profile").arg("stage-3");
+    cmd.arg("--profile")
+        .arg(stage_profile("stage-3", "min-size"));
     cmd.arg("-Zbuild-std=core")
         .arg("-Zbuild-std-features=compiler-builtins-mem");
     cmd.arg("--root").arg(out_dir);
     cmd.env_remove("RUSTFLAGS");
     cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    set_min_size_rustflags(&mut cmd, "--gc-sections", &[]);
     cmd.env_remove("RUSTC_WORKSPACE_WRAPPER"); // used by clippy
     let status = cmd
         .status()
@@ -241,12 +294,16 @@ async fn build_bios_stage_4(out_dir: &Path) -> PathBuf {
     }
     cmd.arg("--locked");
     cmd.arg("--target").arg("x86_64-stage-4.json");
-    cmd.arg("--profile").arg("stage-4");
+    cmd.arg("--profile")
+        .arg(stage_profile("stage-4", "min-size"));
     cmd.arg("-Zbuild-std=core")
         .arg("-Zbuild-std-features=compiler-builtins-mem");
     cmd.arg("--root").arg(out_dir);
     cmd.env_remove("RUSTFLAGS");
     cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    // the stage is built without SSE, which the AES-NI code of the `aes` crate requires
+    set_min_size_rustflags(&mut cmd, "--gc-sections", &["--cfg", "aes_force_soft"]);
+    set_coverage_feature(&mut cmd);
     cmd.env_remove("RUSTC_WORKSPACE_WRAPPER"); // used by clippy
     let status = cmd
         .status()
@@ -295,6 +352,112 @@ async fn convert_elf_to_bin(elf_path: PathBuf) -> PathBuf {
     flat_binary_path
 }
 
+/// Returns the cargo profile for building a boot stage.
+///
+/// The size-optimized profile is used when the `min-size` feature is enabled.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(any(feature = "bios", feature = "uefi"))]
+fn stage_profile(default: &'static str, min_size: &'static str) -> &'static str {
+    if cfg!(feature = "min-size") {
+        min_size
+    } else {
+        default
+    }
+}
+
+/// Passes the given flags to the compiler and tells the linker to discard unreferenced
+/// sections when the `min-size` feature is enabled.
+///
+/// The flags are passed through `CARGO_ENCODED_RUSTFLAGS` because the stage crates can't set
+/// profile rustflags when they're installed from crates.io.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(any(feature = "bios", feature = "uefi"))]
+fn set_min_size_rustflags(cmd: &mut Command, gc_sections_flag: &str, flags: &[&str]) {
+    let mut flags = flags.to_vec();
+    let gc_sections = format!("-Clink-arg={gc_sections_flag}");
+    if cfg!(feature = "min-size") {
+        flags.push(&gc_sections);
+    }
+    if !flags.is_empty() {
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
+    }
+}
+
+/// Enables the coverage counters of a boot stage when the `coverage` feature is enabled.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(any(feature = "bios", feature = "uefi"))]
+fn set_coverage_feature(cmd: &mut Command) {
+    if cfg!(feature = "coverage") {
+        cmd.arg("--features").arg("coverage");
+    }
+}
+
+/// Fails the build if the given size of a boot stage exceeds its limit.
+///
+/// The panic message contains the overage and the largest symbols of the stage executable.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(any(feature = "bios", feature = "uefi"))]
+async fn check_size_budget(stage: &str, executable: &Path, size: u64, limit: u64) {
+    if size <= limit {
+        return;
+    }
+    let symbols = llvm_nm(executable, &["--print-size", "--size-sort", "--demangle"]).await;
+    let mut largest: Vec<_> = symbols
+        .lines()
+        .rev()
+        .take(LARGEST_SYMBOLS)
+        .filter_map(|line| {
+            let mut columns = line.splitn(4, ' ');
+            let _address = columns.next()?;
+            let size = u64::from_str_radix(columns.next()?, 16).ok()?;
+            let name = columns.nth(1)?;
+            Some(format!("  {size:>8} bytes  {name}"))
+        })
+        .collect();
+    if largest.is_empty() {
+        largest.push("  (the executable has no symbol table)".into());
+    }
+    panic!(
+        "{stage} is {size} bytes, which exceeds its budget of {limit} bytes by {} bytes\n\
+        largest symbols:\n{}",
+        size - limit,
+        largest.join("\n")
+    );
+}
+
+/// Returns the address of the given symbol in the given ELF executable.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(feature = "bios")]
+async fn symbol_address(elf_path: &Path, symbol: &str) -> u64 {
+    let symbols = llvm_nm(elf_path, &[]).await;
+    symbols
+        .lines()
+        .find_map(|line| {
+            let (address, rest) = line.split_once(' ')?;
+            (rest.split(' ').nth(1)? == symbol).then(|| u64::from_str_radix(address, 16).ok())?
+        })
+        .unwrap_or_else(|| panic!("symbol `{symbol}` not found in {}", elf_path.display()))
+}
+
+/// Runs `llvm-nm` with the given arguments and returns its output.
+#[cfg(not(docsrs_dummy_build))]
+#[cfg(any(feature = "bios", feature = "uefi"))]
+async fn llvm_nm(executable: &Path, args: &[&str]) -> String {
+    let llvm_tools = llvm_tools::LlvmTools::new().expect("failed to get llvm tools");
+    let nm = llvm_tools
+        .tool(&llvm_tools::exe("llvm-nm"))
+        .expect("LlvmNmNotFound");
+
+    let output = Command::new(nm)
+        .args(args)
+        .arg(executable)
+        .output()
+        .await
+        .expect("failed to execute llvm-nm command");
+    // `llvm-nm` fails for executables without a symbol table, which is fine for the size report
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
 // dummy implementations because docsrs builds have no network access
 #[cfg(any(not(feature = "bios"), docsrs_dummy_build))]
 async fn bios_main() {}
@@ -0,0 +1,1016 @@
+use bootloader_api::info::{
+    AcpiFastInfo, GenericAddress, IoApic, IoApics, Iommu, IommuKind, Iommus, MmioRegisters,
+    PlatformRegisters, ResetRegister,
+};
+use core::{ptr, slice};
+use x86_64::PhysAddr;
+
+/// The size of the common header of all system description tables.
+const SDT_HEADER_LEN: usize = 36;
+/// The offset of the remapping structures in the DMAR table.
+const DMAR_STRUCTURES_OFFSET: usize = 48;
+/// The offset of the IVHD blocks in the IVRS table.
+const IVRS_BLOCKS_OFFSET: usize = 48;
+/// The size of the register set of an AMD IOMMU.
+const AMD_IOMMU_REGISTER_LEN: u64 = 0x4000;
+
+/// The offset of the interrupt controller structures in the MADT.
+const MADT_STRUCTURES_OFFSET: usize = 44;
+/// The offset of the base address structure in the HPET table.
+const HPET_ADDRESS_OFFSET: usize = 40;
+/// The size of the register sets of the local APIC and the I/O APICs.
+const APIC_REGISTER_LEN: u64 = 0x1000;
+/// The size of the register set of the HPET.
+const HPET_REGISTER_LEN: u64 = 0x400;
+
+/// The offsets of the FADT fields, see section 5.2.9 of the ACPI specification.
+const FADT_FIRMWARE_CTRL_OFFSET: usize = 36;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_PM1A_EVT_BLK_OFFSET: usize = 56;
+const FADT_PM1B_EVT_BLK_OFFSET: usize = 60;
+const FADT_PM1A_CNT_BLK_OFFSET: usize = 64;
+const FADT_PM1B_CNT_BLK_OFFSET: usize = 68;
+const FADT_PM1_EVT_LEN_OFFSET: usize = 88;
+const FADT_PM1_CNT_LEN_OFFSET: usize = 89;
+const FADT_CENTURY_OFFSET: usize = 108;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_RESET_REG_OFFSET: usize = 116;
+const FADT_RESET_VALUE_OFFSET: usize = 128;
+const FADT_X_FIRMWARE_CTRL_OFFSET: usize = 132;
+const FADT_X_DSDT_OFFSET: usize = 140;
+const FADT_X_PM1A_EVT_BLK_OFFSET: usize = 148;
+const FADT_X_PM1B_EVT_BLK_OFFSET: usize = 160;
+const FADT_X_PM1A_CNT_BLK_OFFSET: usize = 172;
+const FADT_X_PM1B_CNT_BLK_OFFSET: usize = 184;
+/// The `RESET_REG_SUP` bit of the FADT flags.
+const FADT_FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The offsets of the FACS fields, see section 5.2.10 of the ACPI specification.
+const FACS_LEN_OFFSET: usize = 4;
+const FACS_FIRMWARE_WAKING_VECTOR_OFFSET: usize = 12;
+const FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET: usize = 24;
+const FACS_VERSION_OFFSET: usize = 32;
+const FACS_OSPM_FLAGS_OFFSET: usize = 36;
+/// The size of the FACS up to the OSPM flags.
+const FACS_MIN_LEN: usize = 40;
+/// The `64BIT_WAKE_F` bit of the OSPM flags of the FACS.
+const FACS_OSPM_FLAG_64BIT_WAKE: u32 = 1 << 0;
+
+/// The MADT structure types of I/O APICs and of the 64-bit local APIC address override.
+const MADT_TYPE_IO_APIC: u8 = 1;
+const MADT_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+/// The type of a DMA remapping hardware unit definition in the DMAR table.
+const DMAR_TYPE_DRHD: u16 = 0;
+/// The IVHD block types of the IVRS table.
+const IVRS_TYPES_IVHD: [u8; 3] = [0x10, 0x11, 0x40];
+
+/// Finds the DMA remapping hardware units in the ACPI DMAR (Intel) or IVRS (AMD) table.
+///
+/// Returns `None` if neither table exists.
+///
+/// ## Safety
+///
+/// The ACPI tables must be identity-mapped in the current address space.
+pub unsafe fn find_iommus(rsdp_addr: PhysAddr) -> Option<Iommus> {
+    let mut iommus = None;
+    for table in unsafe { tables(rsdp_addr) } {
+        match &table[..4] {
+            b"DMAR" => parse_dmar(table).for_each(|unit| add(&mut iommus, unit)),
+            b"IVRS" => parse_ivrs(table).for_each(|unit| add(&mut iommus, unit)),
+            _ => {}
+        }
+    }
+    iommus
+}
+
+/// Finds the local APIC and I/O APICs in the ACPI MADT and the HPET in the HPET table.
+///
+/// Returns `None` if there is no MADT.
+///
+/// ## Safety
+///
+/// The ACPI tables must be identity-mapped in the current address space.
+pub unsafe fn find_platform_registers(rsdp_addr: PhysAddr) -> Option<PlatformRegisters> {
+    let mut registers = None;
+    let mut hpet = None;
+    for table in unsafe { tables(rsdp_addr) } {
+        match &table[..4] {
+            b"APIC" => registers = parse_madt(table),
+            b"HPET" => hpet = parse_hpet(table),
+            _ => {}
+        }
+    }
+    registers.map(|registers| PlatformRegisters {
+        hpet: hpet.into(),
+        ..registers
+    })
+}
+
+/// Reads the power management and reset registers of the ACPI FADT, and the `\_S5` sleep
+/// types from the DSDT.
+///
+/// Returns `None` if there is no FADT.
+///
+/// ## Safety
+///
+/// The ACPI tables must be identity-mapped in the current address space.
+pub unsafe fn find_fast_info(rsdp_addr: PhysAddr) -> Option<AcpiFastInfo> {
+    let fadt = unsafe { tables(rsdp_addr) }.find(|table| &table[..4] == b"FACP")?;
+    let mut info = parse_fadt(fadt)?;
+
+    let x_dsdt = fadt
+        .get(FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + 8)
+        .and_then(|addr| Some(u64::from_le_bytes(addr.try_into().ok()?)));
+    let dsdt = match x_dsdt {
+        Some(addr) if addr != 0 => addr,
+        _ => u64::from(u32_at(fadt, FADT_DSDT_OFFSET)?),
+    };
+    if let Some(dsdt) = unsafe { table(dsdt) }.filter(|table| &table[..4] == b"DSDT") {
+        info.sleep_type_s5 = find_s5_sleep_type(dsdt).into();
+    }
+    Some(info)
+}
+
+/// The PM1 status registers and the waking vector that the OS stored in the FACS before it
+/// put the system to sleep.
+#[derive(Debug, Clone, Copy)]
+pub struct WakeInfo {
+    /// The status register in the first half of the PM1a event block.
+    pub pm1a_status: GenericAddress,
+    /// The status register in the first half of the PM1b event block, if there is one.
+    pub pm1b_status: Option<GenericAddress>,
+    /// The waking vector, or `None` if the OS didn't set one.
+    pub waking_vector: Option<WakingVector>,
+}
+
+/// The address where the firmware or the bootloader resumes the OS after a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakingVector {
+    /// The `Firmware_Waking_Vector`, called in real mode with `CS` set to the address divided
+    /// by 16.
+    RealMode(u32),
+    /// The `X_Firmware_Waking_Vector` without the `64BIT_WAKE_F` flag, called in 32-bit
+    /// protected mode with paging disabled.
+    ProtectedMode(u64),
+    /// The `X_Firmware_Waking_Vector` with the `64BIT_WAKE_F` flag, called in long mode with
+    /// identity-mapped page tables.
+    LongMode(u64),
+}
+
+/// Reads the PM1 status registers from the FADT and the waking vector from the FACS.
+///
+/// Returns `None` if there is no FADT or FACS.
+///
+/// ## Safety
+///
+/// The ACPI tables must be identity-mapped in the current address space.
+pub unsafe fn find_wake_info(rsdp_addr: PhysAddr) -> Option<WakeInfo> {
+    let fadt = unsafe { tables(rsdp_addr) }.find(|table| &table[..4] == b"FACP")?;
+    let (pm1a_status, pm1b_status) = parse_pm1_status(fadt)?;
+
+    let x_facs = fadt
+        .get(FADT_X_FIRMWARE_CTRL_OFFSET..FADT_X_FIRMWARE_CTRL_OFFSET + 8)
+        .and_then(|addr| Some(u64::from_le_bytes(addr.try_into().ok()?)));
+    let facs = match x_facs {
+        Some(addr) if addr != 0 => addr,
+        _ => u64::from(u32_at(fadt, FADT_FIRMWARE_CTRL_OFFSET)?),
+    };
+    if facs == 0 {
+        return None;
+    }
+    // unlike the other tables, the FACS has no checksum, so only the fields up to the OSPM
+    // flags are read
+    let facs = unsafe { slice::from_raw_parts(facs as *const u8, FACS_MIN_LEN) };
+
+    Some(WakeInfo {
+        pm1a_status,
+        pm1b_status,
+        waking_vector: parse_facs(facs)?,
+    })
+}
+
+/// Reads the PM1a and PM1b status registers, which are the first half of the event blocks
+/// of the FADT.
+fn parse_pm1_status(fadt: &[u8]) -> Option<(GenericAddress, Option<GenericAddress>)> {
+    let status_len = fadt
+        .get(FADT_PM1_EVT_LEN_OFFSET)
+        .copied()
+        .unwrap_or(4)
+        .saturating_mul(4);
+    let status_register = |offset, x_offset| match generic_address(fadt, x_offset) {
+        Some(address) if address.address != 0 => Some(GenericAddress {
+            bit_width: address.bit_width / 2,
+            ..address
+        }),
+        _ => {
+            let port = u32_at(fadt, offset)?;
+            (port != 0).then_some(GenericAddress {
+                address_space: GenericAddress::SYSTEM_IO,
+                bit_width: status_len,
+                bit_offset: 0,
+                access_size: 2,
+                address: u64::from(port),
+            })
+        }
+    };
+    Some((
+        status_register(FADT_PM1A_EVT_BLK_OFFSET, FADT_X_PM1A_EVT_BLK_OFFSET)?,
+        status_register(FADT_PM1B_EVT_BLK_OFFSET, FADT_X_PM1B_EVT_BLK_OFFSET),
+    ))
+}
+
+/// Reads the waking vector of the FACS.
+///
+/// Returns `None` if the FACS is invalid and `Some(None)` if the OS didn't set a waking
+/// vector.
+fn parse_facs(facs: &[u8]) -> Option<Option<WakingVector>> {
+    if facs.get(..4)? != b"FACS"
+        || usize::try_from(u32_at(facs, FACS_LEN_OFFSET)?).ok()? < FACS_MIN_LEN
+    {
+        return None;
+    }
+    let x_waking_vector = u64::from_le_bytes(
+        facs.get(FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET..FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET + 8)?
+            .try_into()
+            .ok()?,
+    );
+    let version = *facs.get(FACS_VERSION_OFFSET)?;
+    let ospm_flags = if version >= 2 {
+        u32_at(facs, FACS_OSPM_FLAGS_OFFSET)?
+    } else {
+        0
+    };
+    Some(match x_waking_vector {
+        0 => match u32_at(facs, FACS_FIRMWARE_WAKING_VECTOR_OFFSET)? {
+            0 => None,
+            vector => Some(WakingVector::RealMode(vector)),
+        },
+        vector if ospm_flags & FACS_OSPM_FLAG_64BIT_WAKE != 0 => {
+            Some(WakingVector::LongMode(vector))
+        }
+        vector => Some(WakingVector::ProtectedMode(vector)),
+    })
+}
+
+fn add(iommus: &mut Option<Iommus>, unit: Iommu) {
+    let iommus = iommus.get_or_insert_with(Iommus::new);
+    // the IVRS table usually describes each IOMMU with several IVHD block types
+    if iommus
+        .iter()
+        .any(|existing| existing.register_base == unit.register_base)
+    {
+        return;
+    }
+    if iommus.push(unit).is_err() {
+        log::warn!(
+            "Ignoring IOMMU at {:#x}: too many units",
+            unit.register_base
+        );
+    }
+}
+
+/// Returns an iterator over the valid system description tables listed in the RSDT or XSDT.
+unsafe fn tables(rsdp_addr: PhysAddr) -> impl Iterator<Item = &'static [u8]> {
+    let rsdp = rsdp_addr.as_u64();
+    let revision: u8 = unsafe { read(rsdp + 15) };
+    let (root_table, entry_len) = if revision >= 2 {
+        (unsafe { read::<u64>(rsdp + 24) }, 8)
+    } else {
+        (u64::from(unsafe { read::<u32>(rsdp + 16) }), 4)
+    };
+    let root_table = unsafe { table(root_table) }.unwrap_or(&[]);
+
+    root_table
+        .get(SDT_HEADER_LEN..)
+        .unwrap_or(&[])
+        .chunks_exact(entry_len)
+        .filter_map(|entry| {
+            let mut addr = [0; 8];
+            addr[..entry.len()].copy_from_slice(entry);
+            unsafe { table(u64::from_le_bytes(addr)) }
+        })
+}
+
+/// Returns the system description table at the given physical address if its checksum is
+/// valid.
+unsafe fn table(addr: u64) -> Option<&'static [u8]> {
+    if addr == 0 {
+        return None;
+    }
+    let len: u32 = unsafe { read(addr + 4) };
+    let len = usize::try_from(len).ok()?;
+    if len < SDT_HEADER_LEN {
+        return None;
+    }
+    let table = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    let checksum = table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    (checksum == 0).then_some(table)
+}
+
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    unsafe { ptr::read_unaligned(addr as *const T) }
+}
+
+/// Reads the DRHD structures of the DMAR table.
+fn parse_dmar(table: &[u8]) -> impl Iterator<Item = Iommu> + '_ {
+    structures(table, DMAR_STRUCTURES_OFFSET, |s| {
+        let kind = u16::from_le_bytes(s.get(0..2)?.try_into().ok()?);
+        let len = u16::from_le_bytes(s.get(2..4)?.try_into().ok()?);
+        Some((kind == DMAR_TYPE_DRHD, usize::from(len)))
+    })
+    .filter_map(|drhd| {
+        // bits 3:0 of the size field encode the number of 4KiB register pages as a power
+        // of two; older firmware leaves the field zero
+        let size = drhd.get(5)? & 0xf;
+        Some(Iommu {
+            kind: IommuKind::IntelVtd,
+            pci_segment: u16::from_le_bytes(drhd.get(6..8)?.try_into().ok()?),
+            register_base: u64::from_le_bytes(drhd.get(8..16)?.try_into().ok()?),
+            register_len: 0x1000 << size,
+            register_addr: None.into(),
+        })
+    })
+}
+
+/// Reads the IVHD blocks of the IVRS table.
+fn parse_ivrs(table: &[u8]) -> impl Iterator<Item = Iommu> + '_ {
+    structures(table, IVRS_BLOCKS_OFFSET, |s| {
+        let kind = *s.first()?;
+        let len = u16::from_le_bytes(s.get(2..4)?.try_into().ok()?);
+        Some((IVRS_TYPES_IVHD.contains(&kind), usize::from(len)))
+    })
+    .filter_map(|ivhd| {
+        Some(Iommu {
+            kind: IommuKind::AmdVi,
+            pci_segment: u16::from_le_bytes(ivhd.get(16..18)?.try_into().ok()?),
+            register_base: u64::from_le_bytes(ivhd.get(8..16)?.try_into().ok()?),
+            register_len: AMD_IOMMU_REGISTER_LEN,
+            register_addr: None.into(),
+        })
+    })
+}
+
+/// Reads the local APIC address and the I/O APIC structures of the MADT.
+fn parse_madt(table: &[u8]) -> Option<PlatformRegisters> {
+    let mut local_apic_base = u64::from(u32::from_le_bytes(table.get(36..40)?.try_into().ok()?));
+    let mut io_apics = IoApics::new();
+    let entries = structures(table, MADT_STRUCTURES_OFFSET, |s| {
+        Some((true, usize::from(*s.get(1)?)))
+    });
+    for entry in entries {
+        match entry[0] {
+            MADT_TYPE_IO_APIC => {
+                let Some(io_apic) = parse_io_apic(entry) else {
+                    continue;
+                };
+                if io_apics.push(io_apic).is_err() {
+                    log::warn!(
+                        "Ignoring I/O APIC at {:#x}: too many I/O APICs",
+                        io_apic.registers.base
+                    );
+                }
+            }
+            MADT_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                if let Some(addr) = entry.get(4..12) {
+                    local_apic_base = u64::from_le_bytes(addr.try_into().ok()?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(PlatformRegisters {
+        local_apic: MmioRegisters::new(local_apic_base, APIC_REGISTER_LEN),
+        io_apics,
+        hpet: None.into(),
+    })
+}
+
+fn parse_io_apic(entry: &[u8]) -> Option<IoApic> {
+    Some(IoApic {
+        id: *entry.get(2)?,
+        gsi_base: u32::from_le_bytes(entry.get(8..12)?.try_into().ok()?),
+        registers: MmioRegisters::new(
+            u64::from(u32::from_le_bytes(entry.get(4..8)?.try_into().ok()?)),
+            APIC_REGISTER_LEN,
+        ),
+    })
+}
+
+/// Reads the base address of the HPET table, which is a generic address structure.
+fn parse_hpet(table: &[u8]) -> Option<MmioRegisters> {
+    let address = table.get(HPET_ADDRESS_OFFSET..HPET_ADDRESS_OFFSET + 12)?;
+    // only memory-mapped HPETs (address space ID 0) are supported
+    if address[0] != 0 {
+        return None;
+    }
+    let base = u64::from_le_bytes(address[4..12].try_into().ok()?);
+    Some(MmioRegisters::new(base, HPET_REGISTER_LEN))
+}
+
+/// Reads the fields of the FADT, preferring the 64-bit `X_` variants if they are set.
+fn parse_fadt(table: &[u8]) -> Option<AcpiFastInfo> {
+    let control_len = table.get(FADT_PM1_CNT_LEN_OFFSET).copied().unwrap_or(2);
+    let control_block = |offset, x_offset| match generic_address(table, x_offset) {
+        Some(address) if address.address != 0 => Some(address),
+        _ => {
+            let port = u32_at(table, offset)?;
+            (port != 0).then_some(GenericAddress {
+                address_space: GenericAddress::SYSTEM_IO,
+                bit_width: control_len.saturating_mul(8),
+                bit_offset: 0,
+                access_size: 2,
+                address: u64::from(port),
+            })
+        }
+    };
+
+    // the fields after the century register only exist since ACPI 2.0
+    let flags = u32_at(table, FADT_FLAGS_OFFSET).unwrap_or(0);
+    // a table that is too short for the reset register is treated as if the flag was clear
+    let reset = generic_address(table, FADT_RESET_REG_OFFSET)
+        .zip(table.get(FADT_RESET_VALUE_OFFSET))
+        .filter(|_| flags & FADT_FLAG_RESET_REG_SUP != 0)
+        .map(|(register, &value)| ResetRegister { register, value });
+
+    Some(AcpiFastInfo {
+        pm1a_control_block: control_block(FADT_PM1A_CNT_BLK_OFFSET, FADT_X_PM1A_CNT_BLK_OFFSET)?,
+        pm1b_control_block: control_block(FADT_PM1B_CNT_BLK_OFFSET, FADT_X_PM1B_CNT_BLK_OFFSET)
+            .into(),
+        sleep_type_s5: None.into(),
+        reset: reset.into(),
+        century: table
+            .get(FADT_CENTURY_OFFSET)
+            .copied()
+            .filter(|&index| index != 0)
+            .into(),
+    })
+}
+
+fn u32_at(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        table.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Reads the generic address structure at the given offset.
+fn generic_address(table: &[u8], offset: usize) -> Option<GenericAddress> {
+    let address = table.get(offset..offset + 12)?;
+    Some(GenericAddress {
+        address_space: address[0],
+        bit_width: address[1],
+        bit_offset: address[2],
+        access_size: address[3],
+        address: u64::from_le_bytes(address[4..12].try_into().ok()?),
+    })
+}
+
+/// Finds the `SLP_TYPa` and `SLP_TYPb` values in the `\_S5` package of the DSDT.
+///
+/// Only recognizes a `Name(_S5, Package() {...})` object whose first two elements are integer
+/// constants, which is how virtually all firmware defines it.
+fn find_s5_sleep_type(dsdt: &[u8]) -> Option<[u8; 2]> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0a;
+
+    let aml = dsdt.get(SDT_HEADER_LEN..)?;
+    let name = aml.windows(4).position(|name| name == b"_S5_")?;
+    // the name is either preceded directly by the NameOp or by a root prefix `\`
+    let name_op = match aml.get(name.checked_sub(1)?)? {
+        b'\\' => name.checked_sub(2)?,
+        _ => name - 1,
+    };
+    if aml[name_op] != NAME_OP {
+        return None;
+    }
+    let mut package = aml.get(name + 4..)?.iter().copied();
+    if package.next()? != PACKAGE_OP {
+        return None;
+    }
+    // bits 7:6 of the first PkgLength byte are the number of following PkgLength bytes,
+    // which are skipped together with the element count
+    let pkg_length = package.next()?;
+    package.nth(usize::from(pkg_length >> 6))?;
+    let mut integer = || match package.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => package.next(),
+        _ => None,
+    };
+    Some([integer()?, integer()?])
+}
+
+/// Iterates over the variable-length structures that follow the fixed part of a table.
+///
+/// The `header` closure returns whether a structure is relevant and its length.
+fn structures(
+    table: &[u8],
+    offset: usize,
+    header: impl Fn(&[u8]) -> Option<(bool, usize)>,
+) -> impl Iterator<Item = &[u8]> {
+    let mut rest = table.get(offset..).unwrap_or(&[]);
+    core::iter::from_fn(move || loop {
+        let (relevant, len) = header(rest)?;
+        if len == 0 || len > rest.len() {
+            return None;
+        }
+        let (structure, next) = rest.split_at(len);
+        rest = next;
+        if relevant {
+            return Some(structure);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    /// Builds a table with the given signature whose structures start at `offset`.
+    fn sdt(signature: &[u8; 4], offset: usize, structures: &[Vec<u8>]) -> Vec<u8> {
+        let mut table = vec![0; offset];
+        table[..4].copy_from_slice(signature);
+        structures.iter().for_each(|s| table.extend_from_slice(s));
+        let len = table.len() as u32;
+        table[4..8].copy_from_slice(&len.to_le_bytes());
+        table
+    }
+
+    fn drhd(size: u8, segment: u16, base: u64) -> Vec<u8> {
+        let mut drhd = vec![0, 0, 16, 0, 0, size];
+        drhd.extend_from_slice(&segment.to_le_bytes());
+        drhd.extend_from_slice(&base.to_le_bytes());
+        drhd
+    }
+
+    fn ivhd(kind: u8, len: u16, segment: u16, base: u64) -> Vec<u8> {
+        let mut ivhd = vec![kind, 0];
+        ivhd.extend_from_slice(&len.to_le_bytes());
+        ivhd.extend_from_slice(&[0; 4]);
+        ivhd.extend_from_slice(&base.to_le_bytes());
+        ivhd.extend_from_slice(&segment.to_le_bytes());
+        ivhd.resize(usize::from(len), 0);
+        ivhd
+    }
+
+    #[test]
+    fn dmar_units() {
+        // a reserved memory region reporting structure between the two units
+        let rmrr = vec![
+            1, 0, 24, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let dmar = sdt(
+            b"DMAR",
+            DMAR_STRUCTURES_OFFSET,
+            &[drhd(0, 0, 0xfed9_0000), rmrr, drhd(2, 1, 0xfed9_1000)],
+        );
+        let units: Vec<_> = parse_dmar(&dmar).collect();
+        assert_eq!(
+            units,
+            [
+                Iommu {
+                    kind: IommuKind::IntelVtd,
+                    pci_segment: 0,
+                    register_base: 0xfed9_0000,
+                    register_len: 0x1000,
+                    register_addr: None.into(),
+                },
+                Iommu {
+                    kind: IommuKind::IntelVtd,
+                    pci_segment: 1,
+                    register_base: 0xfed9_1000,
+                    register_len: 0x4000,
+                    register_addr: None.into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_dmar() {
+        let dmar = sdt(b"DMAR", DMAR_STRUCTURES_OFFSET, &[drhd(0, 0, 0xfed9_0000)]);
+        assert_eq!(parse_dmar(&dmar[..dmar.len() - 1]).count(), 0);
+        assert_eq!(parse_dmar(&dmar[..DMAR_STRUCTURES_OFFSET - 1]).count(), 0);
+        assert_eq!(parse_dmar(&[]).count(), 0);
+    }
+
+    #[test]
+    fn malformed_dmar_structures() {
+        // a zero length must not make the iterator loop forever
+        let mut empty = drhd(0, 0, 0xfed9_0000);
+        empty[2] = 0;
+        let dmar = sdt(
+            b"DMAR",
+            DMAR_STRUCTURES_OFFSET,
+            &[empty, drhd(0, 0, 0x1000)],
+        );
+        assert_eq!(parse_dmar(&dmar).count(), 0);
+
+        // a structure that claims to be longer than the table
+        let mut long = drhd(0, 0, 0xfed9_0000);
+        long[2] = 200;
+        let dmar = sdt(b"DMAR", DMAR_STRUCTURES_OFFSET, &[long]);
+        assert_eq!(parse_dmar(&dmar).count(), 0);
+
+        // a DRHD that is too short for its base address
+        let mut short = drhd(0, 0, 0xfed9_0000);
+        short[2] = 12;
+        short.truncate(12);
+        let dmar = sdt(
+            b"DMAR",
+            DMAR_STRUCTURES_OFFSET,
+            &[short, drhd(0, 0, 0xfed9_1000)],
+        );
+        let units: Vec<_> = parse_dmar(&dmar).map(|unit| unit.register_base).collect();
+        assert_eq!(units, [0xfed9_1000]);
+    }
+
+    #[test]
+    fn ivrs_units() {
+        // the same IOMMU described by two IVHD types, with a memory definition in between
+        let ivmd = ivhd(0x20, 32, 0, 0);
+        let ivrs = sdt(
+            b"IVRS",
+            IVRS_BLOCKS_OFFSET,
+            &[
+                ivhd(0x10, 24, 0, 0xfeb8_0000),
+                ivmd,
+                ivhd(0x11, 40, 0, 0xfeb8_0000),
+                ivhd(0x40, 40, 2, 0xfeb9_0000),
+            ],
+        );
+        let units: Vec<_> = parse_ivrs(&ivrs).collect();
+        assert_eq!(units.len(), 3);
+        assert_eq!(
+            units[2],
+            Iommu {
+                kind: IommuKind::AmdVi,
+                pci_segment: 2,
+                register_base: 0xfeb9_0000,
+                register_len: AMD_IOMMU_REGISTER_LEN,
+                register_addr: None.into(),
+            }
+        );
+
+        let mut iommus = None;
+        units.into_iter().for_each(|unit| add(&mut iommus, unit));
+        let bases: Vec<_> = iommus.unwrap().iter().map(|u| u.register_base).collect();
+        assert_eq!(bases, [0xfeb8_0000, 0xfeb9_0000]);
+    }
+
+    #[test]
+    fn malformed_ivrs() {
+        // too short for the PCI segment
+        let ivrs = sdt(
+            b"IVRS",
+            IVRS_BLOCKS_OFFSET,
+            &[
+                ivhd(0x10, 16, 0, 0xfeb8_0000),
+                ivhd(0x10, 24, 0, 0xfeb9_0000),
+            ],
+        );
+        let units: Vec<_> = parse_ivrs(&ivrs).map(|unit| unit.register_base).collect();
+        assert_eq!(units, [0xfeb9_0000]);
+
+        let ivrs = sdt(b"IVRS", IVRS_BLOCKS_OFFSET, &[ivhd(0x10, 24, 0, 1)]);
+        assert_eq!(parse_ivrs(&ivrs[..ivrs.len() - 4]).count(), 0);
+        assert_eq!(parse_ivrs(&ivrs[..IVRS_BLOCKS_OFFSET + 2]).count(), 0);
+    }
+
+    #[test]
+    fn too_many_iommus() {
+        let mut iommus = None;
+        for i in 0..Iommus::MAX_UNITS as u64 + 2 {
+            let unit = Iommu {
+                kind: IommuKind::IntelVtd,
+                pci_segment: 0,
+                register_base: i * 0x1000,
+                register_len: 0x1000,
+                register_addr: None.into(),
+            };
+            add(&mut iommus, unit);
+        }
+        assert_eq!(iommus.unwrap().len(), Iommus::MAX_UNITS);
+    }
+
+    fn madt(local_apic: u32, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut madt = sdt(b"APIC", MADT_STRUCTURES_OFFSET, entries);
+        madt[36..40].copy_from_slice(&local_apic.to_le_bytes());
+        madt
+    }
+
+    fn io_apic(id: u8, addr: u32, gsi_base: u32) -> Vec<u8> {
+        let mut entry = vec![MADT_TYPE_IO_APIC, 12, id, 0];
+        entry.extend_from_slice(&addr.to_le_bytes());
+        entry.extend_from_slice(&gsi_base.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn madt_registers() {
+        let local_apic = vec![0, 8, 0, 0, 1, 0, 0, 0];
+        let madt = madt(
+            0xfee0_0000,
+            &[
+                local_apic,
+                io_apic(0, 0xfec0_0000, 0),
+                io_apic(1, 0xfec0_1000, 24),
+            ],
+        );
+        let registers = parse_madt(&madt).unwrap();
+        assert_eq!(
+            registers.local_apic,
+            MmioRegisters::new(0xfee0_0000, APIC_REGISTER_LEN)
+        );
+        assert_eq!(
+            &*registers.io_apics,
+            &[
+                IoApic {
+                    id: 0,
+                    gsi_base: 0,
+                    registers: MmioRegisters::new(0xfec0_0000, APIC_REGISTER_LEN),
+                },
+                IoApic {
+                    id: 1,
+                    gsi_base: 24,
+                    registers: MmioRegisters::new(0xfec0_1000, APIC_REGISTER_LEN),
+                },
+            ]
+        );
+        assert_eq!(registers.hpet, None.into());
+    }
+
+    #[test]
+    fn madt_local_apic_override() {
+        let mut entry = vec![MADT_TYPE_LOCAL_APIC_ADDRESS_OVERRIDE, 12, 0, 0];
+        entry.extend_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        let registers = parse_madt(&madt(0xfee0_0000, &[entry])).unwrap();
+        assert_eq!(registers.local_apic.base, 0x1_0000_0000);
+        assert!(registers.io_apics.is_empty());
+    }
+
+    #[test]
+    fn malformed_madt() {
+        assert!(parse_madt(&[0; 39]).is_none());
+
+        // an I/O APIC entry that is too short is skipped, an overlong one ends the list
+        let mut short = io_apic(0, 0xfec0_0000, 0);
+        short[1] = 8;
+        short.truncate(8);
+        let mut long = io_apic(2, 0xfec0_2000, 48);
+        long[1] = 100;
+        let madt = madt(0xfee0_0000, &[short, io_apic(1, 0xfec0_1000, 24), long]);
+        let registers = parse_madt(&madt).unwrap();
+        let ids: Vec<_> = registers
+            .io_apics
+            .iter()
+            .map(|io_apic| io_apic.id)
+            .collect();
+        assert_eq!(ids, [1]);
+
+        // a truncated table still reports the local APIC
+        let registers = parse_madt(&madt[..MADT_STRUCTURES_OFFSET + 2]).unwrap();
+        assert!(registers.io_apics.is_empty());
+    }
+
+    #[test]
+    fn too_many_io_apics() {
+        let entries: Vec<_> = (0..IoApics::MAX_IO_APICS as u8 + 1)
+            .map(|id| io_apic(id, 0xfec0_0000 + u32::from(id) * 0x1000, 0))
+            .collect();
+        let registers = parse_madt(&madt(0xfee0_0000, &entries)).unwrap();
+        assert_eq!(registers.io_apics.len(), IoApics::MAX_IO_APICS);
+    }
+
+    fn hpet(address_space: u8, base: u64) -> Vec<u8> {
+        let mut hpet = sdt(b"HPET", HPET_ADDRESS_OFFSET, &[]);
+        hpet.extend_from_slice(&[address_space, 64, 0, 0]);
+        hpet.extend_from_slice(&base.to_le_bytes());
+        // the HPET number and minimum tick
+        hpet.extend_from_slice(&[0; 4]);
+        hpet
+    }
+
+    #[test]
+    fn hpet_registers() {
+        assert_eq!(
+            parse_hpet(&hpet(0, 0xfed0_0000)),
+            Some(MmioRegisters::new(0xfed0_0000, HPET_REGISTER_LEN))
+        );
+        // HPETs in I/O space are not supported
+        assert_eq!(parse_hpet(&hpet(1, 0xfed0_0000)), None);
+        assert_eq!(
+            parse_hpet(&hpet(0, 0xfed0_0000)[..HPET_ADDRESS_OFFSET + 11]),
+            None
+        );
+        assert_eq!(parse_hpet(&[]), None);
+    }
+
+    /// Builds an FADT of the given length with the PM1a control block at I/O port `0x404`.
+    fn fadt(len: usize) -> Vec<u8> {
+        let mut fadt = sdt(b"FACP", len, &[]);
+        fadt[FADT_PM1A_CNT_BLK_OFFSET..][..4].copy_from_slice(&0x404u32.to_le_bytes());
+        fadt[FADT_PM1_CNT_LEN_OFFSET] = 2;
+        fadt
+    }
+
+    fn io_port(port: u64, bit_width: u8) -> GenericAddress {
+        GenericAddress {
+            address_space: GenericAddress::SYSTEM_IO,
+            bit_width,
+            bit_offset: 0,
+            access_size: 2,
+            address: port,
+        }
+    }
+
+    #[test]
+    fn acpi_1_fadt() {
+        let mut fadt = fadt(116);
+        fadt[FADT_PM1B_CNT_BLK_OFFSET..][..4].copy_from_slice(&0x504u32.to_le_bytes());
+        fadt[FADT_CENTURY_OFFSET] = 0x32;
+        assert_eq!(
+            parse_fadt(&fadt),
+            Some(AcpiFastInfo {
+                pm1a_control_block: io_port(0x404, 16),
+                pm1b_control_block: Some(io_port(0x504, 16)).into(),
+                sleep_type_s5: None.into(),
+                reset: None.into(),
+                century: Some(0x32).into(),
+            })
+        );
+    }
+
+    #[test]
+    fn fadt_extended_fields() {
+        let mut fadt = fadt(244);
+        let pm1a = [0, 16, 0, 2, 0x04, 0x08, 0xd8, 0xfe, 0, 0, 0, 0];
+        fadt[FADT_X_PM1A_CNT_BLK_OFFSET..][..12].copy_from_slice(&pm1a);
+        fadt[FADT_FLAGS_OFFSET..][..4].copy_from_slice(&FADT_FLAG_RESET_REG_SUP.to_le_bytes());
+        let reset = [1, 8, 0, 1, 0xf9, 0x0c, 0, 0, 0, 0, 0, 0];
+        fadt[FADT_RESET_REG_OFFSET..][..12].copy_from_slice(&reset);
+        fadt[FADT_RESET_VALUE_OFFSET] = 6;
+
+        let info = parse_fadt(&fadt).unwrap();
+        assert_eq!(
+            info.pm1a_control_block,
+            GenericAddress {
+                address_space: 0,
+                bit_width: 16,
+                bit_offset: 0,
+                access_size: 2,
+                address: 0xfed8_0804,
+            }
+        );
+        assert_eq!(info.pm1b_control_block, None.into());
+        assert_eq!(
+            info.reset,
+            Some(ResetRegister {
+                register: GenericAddress {
+                    address_space: GenericAddress::SYSTEM_IO,
+                    bit_width: 8,
+                    bit_offset: 0,
+                    access_size: 1,
+                    address: 0xcf9,
+                },
+                value: 6,
+            })
+            .into()
+        );
+        assert_eq!(info.century, None.into());
+    }
+
+    #[test]
+    fn malformed_fadt() {
+        // no PM1a control block
+        let mut fadt = fadt(116);
+        fadt[FADT_PM1A_CNT_BLK_OFFSET..][..4].fill(0);
+        assert_eq!(parse_fadt(&fadt), None);
+        assert_eq!(parse_fadt(&[]), None);
+
+        // a reset register flag in a table that is too short for the register
+        let mut fadt = self::fadt(124);
+        fadt[FADT_FLAGS_OFFSET..][..4].copy_from_slice(&FADT_FLAG_RESET_REG_SUP.to_le_bytes());
+        let info = parse_fadt(&fadt).unwrap();
+        assert_eq!(info.pm1a_control_block, io_port(0x404, 16));
+        assert_eq!(info.reset, None.into());
+
+        // truncated in the middle of the PM1 control block fields
+        assert_eq!(parse_fadt(&fadt[..FADT_PM1A_CNT_BLK_OFFSET + 2]), None);
+    }
+
+    fn dsdt(aml: &[u8]) -> Vec<u8> {
+        let mut dsdt = sdt(b"DSDT", SDT_HEADER_LEN, &[]);
+        dsdt.extend_from_slice(aml);
+        dsdt
+    }
+
+    #[test]
+    fn s5_sleep_types() {
+        // Name(_S5, Package(4) {5, 5, 0, 0}) inside a scope
+        let aml = [
+            0x10, 0x0b, b'\\', b'_', b'S', b'B', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0a,
+            0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00,
+        ];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), Some([5, 5]));
+
+        // Name(\_S5, Package() {Zero, One}) with a two-byte PkgLength
+        let aml = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x44, 0x00, 0x02, 0x00, 0x01,
+        ];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), Some([0, 1]));
+    }
+
+    #[test]
+    fn malformed_s5() {
+        assert_eq!(find_s5_sleep_type(&dsdt(&[])), None);
+        assert_eq!(find_s5_sleep_type(&[]), None);
+        // the name at the very start of the AML, without a NameOp
+        assert_eq!(find_s5_sleep_type(&dsdt(b"_S5_\x12\x06\x02\x00\x00")), None);
+        // a method instead of a package
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x14, 0x06, 0x00, 0x0a, 0x05];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), None);
+        // a word constant
+        let aml = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0b, 0x05, 0x00,
+        ];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), None);
+        // truncated after the first element
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a, 0x05];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), None);
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x46];
+        assert_eq!(find_s5_sleep_type(&dsdt(&aml)), None);
+    }
+
+    #[test]
+    fn pm1_status_registers() {
+        let mut fadt = fadt(244);
+        fadt[FADT_PM1A_EVT_BLK_OFFSET..][..4].copy_from_slice(&0x400u32.to_le_bytes());
+        fadt[FADT_PM1_EVT_LEN_OFFSET] = 4;
+        let pm1b = [1, 32, 0, 2, 0x00, 0x05, 0, 0, 0, 0, 0, 0];
+        fadt[FADT_X_PM1B_EVT_BLK_OFFSET..][..12].copy_from_slice(&pm1b);
+        assert_eq!(
+            parse_pm1_status(&fadt),
+            Some((io_port(0x400, 16), Some(io_port(0x500, 16))))
+        );
+
+        fadt[FADT_PM1A_EVT_BLK_OFFSET..][..4].fill(0);
+        assert_eq!(parse_pm1_status(&fadt), None);
+        assert_eq!(parse_pm1_status(&[]), None);
+    }
+
+    fn facs(version: u8, ospm_flags: u32, vector: u32, x_vector: u64) -> Vec<u8> {
+        let mut facs = vec![0; 64];
+        facs[..4].copy_from_slice(b"FACS");
+        facs[FACS_LEN_OFFSET..][..4].copy_from_slice(&64u32.to_le_bytes());
+        facs[FACS_FIRMWARE_WAKING_VECTOR_OFFSET..][..4].copy_from_slice(&vector.to_le_bytes());
+        facs[FACS_X_FIRMWARE_WAKING_VECTOR_OFFSET..][..8].copy_from_slice(&x_vector.to_le_bytes());
+        facs[FACS_VERSION_OFFSET] = version;
+        facs[FACS_OSPM_FLAGS_OFFSET..][..4].copy_from_slice(&ospm_flags.to_le_bytes());
+        facs
+    }
+
+    #[test]
+    fn waking_vectors() {
+        assert_eq!(parse_facs(&facs(2, 0, 0, 0)), Some(None));
+        assert_eq!(
+            parse_facs(&facs(2, 0, 0x9_a000, 0)),
+            Some(Some(WakingVector::RealMode(0x9_a000)))
+        );
+        assert_eq!(
+            parse_facs(&facs(2, 0, 0x9_a000, 0x1_0000)),
+            Some(Some(WakingVector::ProtectedMode(0x1_0000)))
+        );
+        assert_eq!(
+            parse_facs(&facs(2, FACS_OSPM_FLAG_64BIT_WAKE, 0, 0x1_0000)),
+            Some(Some(WakingVector::LongMode(0x1_0000)))
+        );
+        // the OSPM flags only exist since version 2
+        assert_eq!(
+            parse_facs(&facs(1, FACS_OSPM_FLAG_64BIT_WAKE, 0, 0x1_0000)),
+            Some(Some(WakingVector::ProtectedMode(0x1_0000)))
+        );
+    }
+
+    #[test]
+    fn malformed_facs() {
+        let mut wrong_signature = facs(2, 0, 0x9_a000, 0);
+        wrong_signature[0] = b'X';
+        assert_eq!(parse_facs(&wrong_signature), None);
+
+        let mut too_short = facs(2, 0, 0x9_a000, 0);
+        too_short[FACS_LEN_OFFSET] = 32;
+        assert_eq!(parse_facs(&too_short), None);
+
+        assert_eq!(
+            parse_facs(&facs(2, 0, 0x9_a000, 0)[..FACS_MIN_LEN - 1]),
+            None
+        );
+        assert_eq!(parse_facs(&[]), None);
+    }
+}
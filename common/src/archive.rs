@@ -0,0 +1,405 @@
+use bootloader_api::info::ArchiveFormat;
+use core::str;
+
+const CPIO_HEADER_LEN: usize = 110;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+const CPIO_MODE_TYPE_MASK: u32 = 0o170000;
+const CPIO_MODE_REGULAR: u32 = 0o100000;
+
+const USTAR_BLOCK_LEN: usize = 512;
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+/// A regular file in an archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// The directory part of the path, only used by ustar archives for long paths.
+    pub prefix: &'a str,
+    /// The path of the file, relative to `prefix` if that is not empty.
+    pub name: &'a str,
+    /// The offset of the file contents from the start of the archive.
+    pub offset: usize,
+    /// The length of the file contents.
+    pub len: usize,
+}
+
+impl Entry<'_> {
+    /// Returns the length of the full path of the file.
+    pub fn path_len(&self) -> usize {
+        if self.prefix.is_empty() {
+            self.name.len()
+        } else {
+            self.prefix.len() + 1 + self.name.len()
+        }
+    }
+
+    /// Writes the full path of the file into `dst`, which must be [`path_len`](Self::path_len)
+    /// bytes long.
+    pub fn write_path(&self, dst: &mut [u8]) {
+        if self.prefix.is_empty() {
+            dst.copy_from_slice(self.name.as_bytes());
+        } else {
+            let (prefix, rest) = dst.split_at_mut(self.prefix.len());
+            prefix.copy_from_slice(self.prefix.as_bytes());
+            rest[0] = b'/';
+            rest[1..].copy_from_slice(self.name.as_bytes());
+        }
+    }
+}
+
+/// Returns the format of the given archive, or `None` if it is not an archive.
+pub fn detect(archive: &[u8]) -> Option<ArchiveFormat> {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        Some(ArchiveFormat::Cpio)
+    } else if archive.get(USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 5) == Some(b"ustar") {
+        Some(ArchiveFormat::Ustar)
+    } else {
+        None
+    }
+}
+
+/// Returns an iterator over the regular files of the given archive.
+///
+/// The iteration stops with a warning at the first malformed header.
+pub fn files(archive: &[u8], format: ArchiveFormat) -> Files<'_> {
+    Files {
+        archive,
+        format,
+        position: 0,
+    }
+}
+
+/// An iterator over the regular files of an archive, see [`files`].
+pub struct Files<'a> {
+    archive: &'a [u8],
+    format: ArchiveFormat,
+    position: usize,
+}
+
+impl<'a> Iterator for Files<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let result = match self.format {
+                ArchiveFormat::Cpio => self.next_cpio(),
+                ArchiveFormat::Ustar => self.next_ustar(),
+                _ => return None,
+            };
+            match result {
+                Ok(Some(Header::File(entry))) => return Some(entry),
+                Ok(Some(Header::Other)) => continue,
+                Ok(None) => return None,
+                Err(err) => {
+                    log::warn!(
+                        "Stopping at malformed archive header at offset {:#x}: {}",
+                        self.position,
+                        err
+                    );
+                    self.position = self.archive.len();
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+enum Header<'a> {
+    File(Entry<'a>),
+    /// A directory, link, or other file that has no name or contents to report.
+    Other,
+}
+
+impl<'a> Files<'a> {
+    fn next_cpio(&mut self) -> Result<Option<Header<'a>>, &'static str> {
+        let header = self
+            .archive
+            .get(self.position..self.position + CPIO_HEADER_LEN)
+            .ok_or("truncated cpio header")?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return Err("invalid cpio magic");
+        }
+        let mode = hex_field(&header[14..22])?;
+        let file_size = hex_field(&header[54..62])? as usize;
+        let name_size = hex_field(&header[94..102])? as usize;
+
+        let name_start = self.position + CPIO_HEADER_LEN;
+        let name = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or("truncated cpio file name")?;
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        if name == CPIO_TRAILER {
+            return Ok(None);
+        }
+        let offset = align_up(name_start + name_size, 4);
+        let end = offset
+            .checked_add(file_size)
+            .filter(|&end| end <= self.archive.len())
+            .ok_or("truncated cpio file contents")?;
+        self.position = align_up(end, 4);
+
+        if mode & CPIO_MODE_TYPE_MASK != CPIO_MODE_REGULAR {
+            return Ok(Some(Header::Other));
+        }
+        Ok(file_entry("", name, offset, file_size))
+    }
+
+    fn next_ustar(&mut self) -> Result<Option<Header<'a>>, &'static str> {
+        let Some(header) = self
+            .archive
+            .get(self.position..self.position + USTAR_BLOCK_LEN)
+        else {
+            // the archive may be truncated after the last file
+            return Ok(None);
+        };
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        if &header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 5] != b"ustar" {
+            return Err("invalid ustar magic");
+        }
+        let file_size = octal_field(&header[124..136])?;
+        let type_flag = header[156];
+        let name = nul_terminated(&header[0..100]);
+        let prefix = nul_terminated(&header[345..500]);
+
+        let offset = self.position + USTAR_BLOCK_LEN;
+        let end = offset
+            .checked_add(file_size)
+            .filter(|&end| end <= self.archive.len())
+            .ok_or("truncated ustar file contents")?;
+        self.position = align_up(end, USTAR_BLOCK_LEN);
+
+        if !matches!(type_flag, b'0' | b'\0' | b'7') {
+            return Ok(Some(Header::Other));
+        }
+        let Ok(prefix) = str::from_utf8(prefix) else {
+            log::warn!("Skipping archive file with a non-UTF-8 path");
+            return Ok(Some(Header::Other));
+        };
+        Ok(file_entry(prefix, name, offset, file_size))
+    }
+}
+
+fn file_entry<'a>(
+    prefix: &'a str,
+    name: &'a [u8],
+    offset: usize,
+    len: usize,
+) -> Option<Header<'a>> {
+    match str::from_utf8(name) {
+        Ok(name) => Some(Header::File(Entry {
+            prefix,
+            name,
+            offset,
+            len,
+        })),
+        Err(_) => {
+            log::warn!("Skipping archive file with a non-UTF-8 path");
+            Some(Header::Other)
+        }
+    }
+}
+
+fn hex_field(field: &[u8]) -> Result<u32, &'static str> {
+    let digits = str::from_utf8(field).map_err(|_| "invalid cpio header field")?;
+    u32::from_str_radix(digits, 16).map_err(|_| "invalid cpio header field")
+}
+
+/// Parses an octal number that is padded with leading zeros or spaces and terminated by a
+/// NUL byte or a space.
+fn octal_field(field: &[u8]) -> Result<usize, &'static str> {
+    let digits = nul_terminated(field);
+    let digits = str::from_utf8(digits).map_err(|_| "invalid ustar size field")?;
+    usize::from_str_radix(digits.trim_matches(' '), 8).map_err(|_| "invalid ustar size field")
+}
+
+fn nul_terminated(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{format, vec, vec::Vec};
+
+    fn cpio_entry(name: &str, mode: u32, file_size: u32, contents: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        entry.extend_from_slice(b"070701");
+        let name_size = name.len() as u32 + 1;
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor,
+        // rdevminor, namesize, check
+        for field in [0, mode, 0, 0, 1, 0, file_size, 0, 0, 0, 0, name_size, 0] {
+            entry.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(0);
+        entry.resize(align_up(entry.len(), 4), 0);
+        entry.extend_from_slice(contents);
+        entry.resize(align_up(entry.len(), 4), 0);
+        entry
+    }
+
+    fn cpio_file(name: &str, contents: &[u8]) -> Vec<u8> {
+        cpio_entry(
+            name,
+            CPIO_MODE_REGULAR | 0o644,
+            contents.len() as u32,
+            contents,
+        )
+    }
+
+    fn cpio_trailer() -> Vec<u8> {
+        cpio_entry("TRAILER!!!", 0, 0, &[])
+    }
+
+    fn ustar_header(prefix: &str, name: &str, type_flag: u8, size: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; USTAR_BLOCK_LEN];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[156] = type_flag;
+        header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 6].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header
+    }
+
+    fn ustar_file(prefix: &str, name: &str, contents: &[u8]) -> Vec<u8> {
+        let size = format!("{:011o}\0", contents.len());
+        let mut file = ustar_header(prefix, name, b'0', size.as_bytes());
+        file.extend_from_slice(contents);
+        file.resize(align_up(file.len(), USTAR_BLOCK_LEN), 0);
+        file
+    }
+
+    /// Returns the full path and contents of each listed file.
+    fn list(archive: &[u8], format: ArchiveFormat) -> Vec<(std::string::String, &[u8])> {
+        files(archive, format)
+            .map(|entry| {
+                let mut path = vec![0; entry.path_len()];
+                entry.write_path(&mut path);
+                let path = std::string::String::from_utf8(path).unwrap();
+                (path, &archive[entry.offset..][..entry.len])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detect_formats() {
+        assert_eq!(detect(&cpio_trailer()), Some(ArchiveFormat::Cpio));
+        assert_eq!(
+            detect(&ustar_file("", "a", b"")),
+            Some(ArchiveFormat::Ustar)
+        );
+        assert_eq!(detect(b"\x7fELF"), None);
+        assert_eq!(detect(&[]), None);
+    }
+
+    #[test]
+    fn cpio_stops_at_trailer() {
+        let mut archive = cpio_file("etc/motd", b"hello");
+        archive.extend(cpio_entry("etc", 0o040755, 0, &[]));
+        archive.extend(cpio_file("init", b"\x7fELF"));
+        archive.extend(cpio_trailer());
+        // padding and data after the trailer are not part of the archive
+        archive.extend(cpio_file("ignored", b"after the trailer"));
+
+        assert_eq!(
+            list(&archive, ArchiveFormat::Cpio),
+            [
+                ("etc/motd".into(), &b"hello"[..]),
+                ("init".into(), &b"\x7fELF"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn cpio_truncated_header() {
+        let mut archive = cpio_file("init", b"\x7fELF");
+        let next = cpio_file("lib", b"");
+        archive.extend_from_slice(&next[..CPIO_HEADER_LEN - 1]);
+
+        assert_eq!(
+            list(&archive, ArchiveFormat::Cpio),
+            [("init".into(), &b"\x7fELF"[..])]
+        );
+    }
+
+    #[test]
+    fn cpio_truncated_name() {
+        let archive = cpio_file("a-long-file-name", b"");
+        assert!(list(&archive[..CPIO_HEADER_LEN + 4], ArchiveFormat::Cpio).is_empty());
+    }
+
+    #[test]
+    fn cpio_oversized_length() {
+        let mut archive = cpio_entry("init", CPIO_MODE_REGULAR, u32::MAX, b"\x7fELF");
+        archive.extend(cpio_trailer());
+        assert!(list(&archive, ArchiveFormat::Cpio).is_empty());
+    }
+
+    #[test]
+    fn cpio_invalid_header_field() {
+        let mut archive = cpio_file("init", b"");
+        archive[54..62].copy_from_slice(b"0000000g");
+        assert!(list(&archive, ArchiveFormat::Cpio).is_empty());
+    }
+
+    #[test]
+    fn ustar_stops_at_end_of_archive_blocks() {
+        let mut archive = ustar_file("", "init", b"\x7fELF");
+        archive.extend(ustar_header("", "etc/", b'5', b"00000000000\0"));
+        archive.extend(ustar_file("usr/share/doc", "README", &[b'x'; 600]));
+        archive.extend([0; 2 * USTAR_BLOCK_LEN]);
+        archive.extend(ustar_file("", "ignored", b"after the end"));
+
+        let files = list(&archive, ArchiveFormat::Ustar);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], ("init".into(), &b"\x7fELF"[..]));
+        assert_eq!(files[1].0, "usr/share/doc/README");
+        assert_eq!(files[1].1, &[b'x'; 600]);
+    }
+
+    #[test]
+    fn ustar_without_end_of_archive_blocks() {
+        // some tools omit the trailing zero blocks, or the archive ends in a partial block
+        let mut archive = ustar_file("", "init", b"\x7fELF");
+        archive.extend_from_slice(&[0; 100]);
+
+        assert_eq!(
+            list(&archive, ArchiveFormat::Ustar),
+            [("init".into(), &b"\x7fELF"[..])]
+        );
+    }
+
+    #[test]
+    fn ustar_truncated_contents() {
+        let archive = ustar_file("", "init", &[1; 1000]);
+        assert!(list(&archive[..USTAR_BLOCK_LEN + 600], ArchiveFormat::Ustar).is_empty());
+    }
+
+    #[test]
+    fn ustar_oversized_length() {
+        let mut archive = ustar_header("", "init", b'0', b"77777777777\0");
+        archive.extend([0; 2 * USTAR_BLOCK_LEN]);
+        assert!(list(&archive, ArchiveFormat::Ustar).is_empty());
+    }
+
+    #[test]
+    fn ustar_invalid_magic() {
+        let mut archive = ustar_file("", "init", b"");
+        archive.extend(ustar_file("", "lib", b""));
+        archive[USTAR_BLOCK_LEN + USTAR_MAGIC_OFFSET] = b'x';
+
+        assert_eq!(
+            list(&archive, ArchiveFormat::Ustar),
+            [("init".into(), &b""[..])]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use bootloader_api::{config::LevelFilter, BootloaderConfig};
+use core::fmt;
+
+/// The maximum number of reserved memory ranges of a board config.
+pub const MAX_RESERVED_RANGES: usize = 8;
+/// The maximum length of a board config file name, see [`file_name`].
+pub const MAX_FILE_NAME_LEN: usize = 96;
+
+/// The directory of the board configs on the boot partition.
+const DIRECTORY: &str = "boards\\";
+const EXTENSION: &str = ".toml";
+
+/// The machine-specific config overrides of a `boards/<product>.toml` file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoardConfig {
+    /// Overrides [`FrameBuffer::framebuffer_width`](bootloader_api::config::FrameBuffer).
+    pub framebuffer_width: Option<u64>,
+    /// Overrides [`FrameBuffer::framebuffer_height`](bootloader_api::config::FrameBuffer).
+    pub framebuffer_height: Option<u64>,
+    /// Overrides [`BootloaderConfig::log_level`].
+    pub log_level: Option<LevelFilter>,
+    reserved: [(u64, u64); MAX_RESERVED_RANGES],
+    reserved_count: usize,
+}
+
+/// An invalid line in a board config.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseError {
+    /// The one-based line number.
+    pub line: usize,
+    /// Describes the problem.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl BoardConfig {
+    /// Parses a board config.
+    ///
+    /// The file uses a subset of TOML: every line contains a `key = value` pair, and `#`
+    /// starts a comment. Integers can be given in decimal or, with a `0x` prefix, in
+    /// hexadecimal. The keys are:
+    ///
+    /// - `framebuffer-width` and `framebuffer-height`: the preferred framebuffer resolution.
+    /// - `log-level`: one of `"off"`, `"error"`, `"warn"`, `"info"`, `"debug"`, or `"trace"`.
+    /// - `reserved-memory`: an array of `[start, length]` pairs of physical memory that is
+    ///   neither used by the bootloader nor reported as usable to the kernel, e.g.
+    ///   `[[0x3f00_0000, 0x10_0000]]`. At most [`MAX_RESERVED_RANGES`] ranges are supported.
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut config = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or_default().trim();
+            if code.is_empty() {
+                continue;
+            }
+            config.parse_line(code).map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+        }
+        Ok(config)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), &'static str> {
+        let (key, value) = line.split_once('=').ok_or("expected `key = value`")?;
+        let value = value.trim();
+        match key.trim() {
+            "framebuffer-width" => self.framebuffer_width = Some(integer(value)?),
+            "framebuffer-height" => self.framebuffer_height = Some(integer(value)?),
+            "log-level" => self.log_level = Some(log_level(value)?),
+            "reserved-memory" => {
+                let mut ranges = array(value)?;
+                while !ranges.is_empty() {
+                    let (range, rest) = ranges.split_once(']').ok_or("expected `]`")?;
+                    let range = range.trim().strip_prefix('[').ok_or("expected `[`")?;
+                    let (start, len) = range.split_once(',').ok_or("expected `[start, length]`")?;
+                    let range = (integer(start.trim())?, integer(len.trim())?);
+                    if range.0.checked_add(range.1).is_none() {
+                        return Err("reserved memory range overflows");
+                    }
+                    let slot = self
+                        .reserved
+                        .get_mut(self.reserved_count)
+                        .ok_or("too many reserved memory ranges")?;
+                    *slot = range;
+                    self.reserved_count += 1;
+                    let rest = rest.trim();
+                    ranges = rest.strip_prefix(',').unwrap_or(rest).trim();
+                }
+            }
+            _ => return Err("unknown key"),
+        }
+        Ok(())
+    }
+
+    /// The reserved physical memory ranges as `(start, length)` pairs.
+    pub fn reserved_memory(&self) -> &[(u64, u64)] {
+        &self.reserved[..self.reserved_count]
+    }
+
+    /// Applies the overrides to the given kernel config.
+    pub fn apply(&self, config: &mut BootloaderConfig) {
+        if let Some(width) = self.framebuffer_width {
+            config.frame_buffer.framebuffer_width = Some(width);
+        }
+        if let Some(height) = self.framebuffer_height {
+            config.frame_buffer.framebuffer_height = Some(height);
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+    }
+}
+
+/// Writes the path of the board config of the given product name to `buf`, e.g.
+/// `boards\ThinkPad-X1.toml` for `ThinkPad X1`.
+///
+/// Runs of characters other than ASCII letters, digits, `-`, `_`, and `.` are replaced by a
+/// single `-`. Returns `None` if the name is empty or too long.
+pub fn file_name<'a>(product: &str, buf: &'a mut [u8; MAX_FILE_NAME_LEN]) -> Option<&'a str> {
+    let mut name = [0; MAX_FILE_NAME_LEN];
+    let mut len = 0;
+    for byte in product.trim().bytes() {
+        let byte = match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => byte,
+            _ if len == 0 || name[len - 1] == b'-' => continue,
+            _ => b'-',
+        };
+        *name.get_mut(len)? = byte;
+        len += 1;
+    }
+    let name = &name[..len];
+    let name = name.strip_suffix(b"-").unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+
+    let path_len = DIRECTORY.len() + name.len() + EXTENSION.len();
+    let path = buf.get_mut(..path_len)?;
+    let (directory, rest) = path.split_at_mut(DIRECTORY.len());
+    let (stem, extension) = rest.split_at_mut(name.len());
+    directory.copy_from_slice(DIRECTORY.as_bytes());
+    stem.copy_from_slice(name);
+    extension.copy_from_slice(EXTENSION.as_bytes());
+    core::str::from_utf8(path).ok()
+}
+
+fn integer(value: &str) -> Result<u64, &'static str> {
+    let (digits, radix) = match value.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (value, 10),
+    };
+    let mut result: u64 = 0;
+    let mut any = false;
+    for c in digits.chars().filter(|&c| c != '_') {
+        let digit = c.to_digit(radix).ok_or("invalid integer")?;
+        result = result
+            .checked_mul(u64::from(radix))
+            .and_then(|result| result.checked_add(u64::from(digit)))
+            .ok_or("integer too large")?;
+        any = true;
+    }
+    any.then_some(result).ok_or("invalid integer")
+}
+
+fn log_level(value: &str) -> Result<LevelFilter, &'static str> {
+    let name = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or("expected a string")?;
+    Ok(match name {
+        "off" => LevelFilter::Off,
+        "error" => LevelFilter::Error,
+        "warn" => LevelFilter::Warn,
+        "info" => LevelFilter::Info,
+        "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
+        _ => return Err("unknown log level"),
+    })
+}
+
+/// Returns the contents of an array value without the brackets.
+fn array(value: &str) -> Result<&str, &'static str> {
+    value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+        .map(str::trim)
+        .ok_or("expected an array")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn parses_all_keys() {
+        let config = BoardConfig::parse(
+            "# Lab board\n\
+             framebuffer-width = 1024\n\
+             framebuffer-height = 0x300 # hex\n\
+             \n\
+             log-level = \"warn\"\n\
+             reserved-memory = [[0x3f00_0000, 0x10_0000], [4096, 8192]]\n",
+        )
+        .unwrap();
+        assert_eq!(config.framebuffer_width, Some(1024));
+        assert_eq!(config.framebuffer_height, Some(768));
+        assert_eq!(config.log_level, Some(LevelFilter::Warn));
+        assert_eq!(
+            config.reserved_memory(),
+            &[(0x3f00_0000, 0x10_0000), (4096, 8192)]
+        );
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let config = BoardConfig::parse("framebuffer-width = 800\nlog-level = \"off\"").unwrap();
+        let mut kernel_config = BootloaderConfig::new_default();
+        kernel_config.frame_buffer.framebuffer_height = Some(600);
+        config.apply(&mut kernel_config);
+        assert_eq!(kernel_config.frame_buffer.framebuffer_width, Some(800));
+        assert_eq!(kernel_config.frame_buffer.framebuffer_height, Some(600));
+        assert_eq!(kernel_config.log_level, LevelFilter::Off);
+    }
+
+    #[test]
+    fn empty_config() {
+        let config = BoardConfig::parse("# nothing\n\n").unwrap();
+        assert_eq!(config.framebuffer_width, None);
+        assert!(config.reserved_memory().is_empty());
+        let config = BoardConfig::parse("reserved-memory = []").unwrap();
+        assert!(config.reserved_memory().is_empty());
+    }
+
+    fn error(text: &str) -> (usize, &'static str) {
+        let err = BoardConfig::parse(text).unwrap_err();
+        (err.line, err.message)
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(error("\nframebuffer-width"), (2, "expected `key = value`"));
+        assert_eq!(error("colour = 1"), (1, "unknown key"));
+        assert_eq!(error("framebuffer-width = 12px"), (1, "invalid integer"));
+        assert_eq!(error("framebuffer-width = 0x"), (1, "invalid integer"));
+        assert_eq!(error("framebuffer-width = -1"), (1, "invalid integer"));
+        assert_eq!(error("log-level = warn"), (1, "expected a string"));
+        assert_eq!(error("log-level = \"loud\""), (1, "unknown log level"));
+        assert_eq!(error("reserved-memory = 5"), (1, "expected an array"));
+        assert_eq!(error("reserved-memory = [[1, 2]"), (1, "expected `]`"));
+        assert_eq!(error("reserved-memory = [1, [2]]"), (1, "expected `[`"));
+        assert_eq!(
+            error("reserved-memory = [[1]]"),
+            (1, "expected `[start, length]`")
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_values() {
+        assert_eq!(
+            error("framebuffer-width = 18446744073709551616"),
+            (1, "integer too large")
+        );
+        assert_eq!(
+            error("framebuffer-width = 0x1_0000_0000_0000_0000"),
+            (1, "integer too large")
+        );
+        assert_eq!(
+            error("reserved-memory = [[0xffff_ffff_ffff_f000, 0x1000]]"),
+            (1, "reserved memory range overflows")
+        );
+        let config =
+            BoardConfig::parse("reserved-memory = [[0xffff_ffff_ffff_f000, 0xfff]]").unwrap();
+        assert_eq!(config.reserved_memory(), &[(0xffff_ffff_ffff_f000, 0xfff)]);
+    }
+
+    #[test]
+    fn rejects_too_many_ranges() {
+        let ranges = "[0, 1], ".repeat(MAX_RESERVED_RANGES);
+        let config = BoardConfig::parse(&format!("reserved-memory = [{ranges}]")).unwrap();
+        assert_eq!(config.reserved_memory().len(), MAX_RESERVED_RANGES);
+        assert_eq!(
+            error(&format!("reserved-memory = [{ranges}[2, 3]]")),
+            (1, "too many reserved memory ranges")
+        );
+    }
+
+    #[test]
+    fn file_names() {
+        let mut buf = [0; MAX_FILE_NAME_LEN];
+        assert_eq!(
+            file_name(" ThinkPad X1 (Gen 9) ", &mut buf),
+            Some("boards\\ThinkPad-X1-Gen-9.toml")
+        );
+        assert_eq!(file_name("  ()  ", &mut buf), None);
+        assert_eq!(file_name(&"a".repeat(MAX_FILE_NAME_LEN), &mut buf), None);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::boot_script::{BootEntry, ScriptError};
+
+/// The maximum number of menu entries, which are selected with the keys `1` to `9`.
+pub const MAX_ENTRIES: usize = 9;
+
+/// An entry of the boot menu.
+#[derive(Debug, Clone, Copy)]
+pub struct MenuEntry<'a> {
+    /// The text that is shown for the entry.
+    pub label: &'a str,
+    /// The kernel and ramdisk files of the entry.
+    pub files: BootEntry<'a>,
+    /// Whether the files are loaded from a USB mass-storage device instead of the boot
+    /// partition.
+    pub usb: bool,
+}
+
+/// The parsed `boot-menu` file.
+#[derive(Debug, Clone, Copy)]
+pub struct Menu<'a> {
+    entries: [Option<MenuEntry<'a>>; MAX_ENTRIES],
+    len: usize,
+    /// The number of seconds after which the default entry is booted.
+    pub timeout_secs: u32,
+    /// The index of the entry that is booted when the timeout expires.
+    pub default: usize,
+}
+
+impl<'a> Menu<'a> {
+    /// Returns the entries in the order of the file.
+    pub fn entries(&self) -> impl Iterator<Item = &MenuEntry<'a>> {
+        self.entries[..self.len].iter().flatten()
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the menu has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the entry at the given index.
+    pub fn get(&self, index: usize) -> Option<&MenuEntry<'a>> {
+        self.entries.get(index)?.as_ref()
+    }
+}
+
+/// Parses the `boot-menu` file that the disk image builder creates for multiple kernels.
+///
+/// Every line contains one statement, `#` starts a comment:
+///
+/// - `timeout <seconds>` sets the time after which the default entry is booted, `5` if not
+///   given. With `0`, the default entry is booted without showing the menu.
+/// - `default <index>` selects the zero-based index of the default entry, `0` if not given.
+/// - `entry <kernel> <ramdisk> <label>` adds an entry, where `-` as ramdisk loads no ramdisk.
+///   The label is the rest of the line and may contain spaces.
+/// - `usb-entry <kernel> <ramdisk> <label>` adds an entry whose files are loaded from a USB
+///   mass-storage device, e.g. a USB stick or the storage that a devboard exposes as a USB
+///   gadget.
+pub fn parse(text: &str) -> Result<Menu<'_>, ScriptError> {
+    let mut menu = Menu {
+        entries: [None; MAX_ENTRIES],
+        len: 0,
+        timeout_secs: 5,
+        default: 0,
+    };
+    for (index, line) in text.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or_default();
+        statement(code, &mut menu).map_err(|message| ScriptError {
+            line: index + 1,
+            message,
+        })?;
+    }
+    if menu.is_empty() {
+        return Err(ScriptError {
+            line: text.lines().count(),
+            message: "the menu has no entries",
+        });
+    }
+    if menu.default >= menu.len {
+        menu.default = 0;
+    }
+    Ok(menu)
+}
+
+fn statement<'a>(code: &'a str, menu: &mut Menu<'a>) -> Result<(), &'static str> {
+    let (keyword, rest) = token(code);
+    match keyword {
+        "" => Ok(()),
+        "timeout" => {
+            menu.timeout_secs = rest.trim().parse().map_err(|_| "invalid timeout")?;
+            Ok(())
+        }
+        "default" => {
+            menu.default = rest.trim().parse().map_err(|_| "invalid default entry")?;
+            Ok(())
+        }
+        "entry" | "usb-entry" => {
+            let (kernel, rest) = token(rest);
+            let (ramdisk, label) = token(rest);
+            let label = label.trim();
+            if label.is_empty() {
+                return Err("an entry needs a kernel, a ramdisk, and a label");
+            }
+            let slot = menu.entries.get_mut(menu.len).ok_or("too many entries")?;
+            *slot = Some(MenuEntry {
+                label,
+                files: BootEntry {
+                    kernel,
+                    ramdisk: (ramdisk != "-").then_some(ramdisk),
+                },
+                usb: keyword == "usb-entry",
+            });
+            menu.len += 1;
+            Ok(())
+        }
+        _ => Err("unknown statement"),
+    }
+}
+
+/// Splits off the first whitespace-separated token.
+fn token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    text.split_once(char::is_whitespace).unwrap_or((text, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_usb_entries() {
+        let menu = parse(
+            "timeout 3\nentry kernel-x86_64 ramdisk Disk kernel\n\
+            usb-entry kernel-x86_64 - Kernel from USB storage # dev boards\n",
+        )
+        .unwrap();
+        assert_eq!(menu.len(), 2);
+        let disk = menu.get(0).unwrap();
+        assert!(!disk.usb);
+        assert_eq!(disk.files.ramdisk, Some("ramdisk"));
+        let usb = menu.get(1).unwrap();
+        assert!(usb.usb);
+        assert_eq!(usb.label, "Kernel from USB storage");
+        assert_eq!(usb.files.kernel, "kernel-x86_64");
+        assert_eq!(usb.files.ramdisk, None);
+    }
+
+    #[test]
+    fn rejects_entries_without_label() {
+        let err = parse("usb-entry kernel-x86_64 -\n").unwrap_err();
+        assert_eq!(err.line, 1);
+    }
+}
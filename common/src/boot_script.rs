@@ -0,0 +1,408 @@
+use core::fmt;
+
+/// The maximum number of variables that a script can set.
+const MAX_VARIABLES: usize = 16;
+
+/// A key that the `key-pressed` condition checks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A function key from `F1` to `F12`.
+    Function(u8),
+    /// A key that produces the given character.
+    Char(char),
+}
+
+/// Answers the conditions of a boot script.
+pub trait Environment {
+    /// Returns whether the given key was pressed since the bootloader started.
+    fn key_pressed(&mut self, key: Key) -> bool;
+    /// Returns whether the previous boot attempt did not complete.
+    fn previous_boot_failed(&mut self) -> bool;
+}
+
+/// The files selected by a `boot` statement.
+#[derive(Debug, Clone, Copy)]
+pub struct BootEntry<'a> {
+    /// The file name of the kernel.
+    pub kernel: &'a str,
+    /// The file name of the ramdisk, if any.
+    pub ramdisk: Option<&'a str>,
+}
+
+/// An invalid statement in a boot script.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptError {
+    /// The one-based line number of the statement.
+    pub line: usize,
+    /// Describes the problem.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Runs the given boot script until the first `boot` statement.
+///
+/// Every line contains one statement, `#` starts a comment. Arguments that contain spaces or
+/// `#` can be put in double quotes:
+///
+/// - `set <name> <value>` sets a variable, which later arguments reference as `$<name>`.
+/// - `boot <kernel> [<ramdisk>]` selects the kernel and ramdisk files and ends the script.
+///   Without a ramdisk argument, no ramdisk is loaded.
+/// - `if <condition> <statement>` runs the statement only if the condition holds. The
+///   statement can be another `if`, which combines the conditions.
+///
+/// The conditions are `key-pressed <key>` (`F1` to `F12` or a character),
+/// `previous-boot-failed`, `equals <a> <b>`, and `not <condition>`.
+///
+/// Returns `None` if the script ends without a `boot` statement.
+pub fn run<'a>(
+    script: &'a str,
+    env: &mut impl Environment,
+) -> Result<Option<BootEntry<'a>>, ScriptError> {
+    let mut variables = Variables::default();
+    for (index, line) in script.lines().enumerate() {
+        let entry = Tokens::new(line)
+            .and_then(|mut tokens| statement(&mut tokens, &mut variables, env, true))
+            .map_err(|message| ScriptError {
+                line: index + 1,
+                message,
+            })?;
+        if entry.is_some() {
+            return Ok(entry);
+        }
+    }
+    Ok(None)
+}
+
+/// Parses the statement from `tokens` and runs it if `execute` is set.
+fn statement<'a>(
+    tokens: &mut Tokens<'a>,
+    variables: &mut Variables<'a>,
+    env: &mut impl Environment,
+    execute: bool,
+) -> Result<Option<BootEntry<'a>>, &'static str> {
+    let Some(keyword) = tokens.next() else {
+        return Ok(None);
+    };
+    match keyword {
+        "set" => {
+            let name = tokens.next().ok_or("`set` needs a name and a value")?;
+            let value = tokens.next().ok_or("`set` needs a name and a value")?;
+            let value = variables.expand(value)?;
+            end(tokens)?;
+            if execute {
+                variables.set(name, value)?;
+            }
+            Ok(None)
+        }
+        "boot" => {
+            let kernel = tokens.next().ok_or("`boot` needs a kernel file")?;
+            let kernel = variables.expand(kernel)?;
+            let ramdisk = tokens.next().map(|t| variables.expand(t)).transpose()?;
+            end(tokens)?;
+            Ok(execute.then_some(BootEntry { kernel, ramdisk }))
+        }
+        "if" => {
+            let holds = condition(tokens, variables, env, execute)?;
+            statement(tokens, variables, env, execute && holds)
+        }
+        _ => Err("unknown statement"),
+    }
+}
+
+/// Parses the condition from `tokens` and evaluates it if `execute` is set.
+fn condition<'a>(
+    tokens: &mut Tokens<'a>,
+    variables: &Variables<'a>,
+    env: &mut impl Environment,
+    execute: bool,
+) -> Result<bool, &'static str> {
+    match tokens
+        .next()
+        .ok_or("`if` needs a condition and a statement")?
+    {
+        "not" => Ok(!condition(tokens, variables, env, execute)?),
+        "key-pressed" => {
+            let key = parse_key(tokens.next().ok_or("`key-pressed` needs a key")?)?;
+            Ok(execute && env.key_pressed(key))
+        }
+        "previous-boot-failed" => Ok(execute && env.previous_boot_failed()),
+        "equals" => {
+            let a = tokens.next().ok_or("`equals` needs two values")?;
+            let b = tokens.next().ok_or("`equals` needs two values")?;
+            Ok(variables.expand(a)? == variables.expand(b)?)
+        }
+        _ => Err("unknown condition"),
+    }
+}
+
+fn parse_key(name: &str) -> Result<Key, &'static str> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Key::Char(c)),
+        _ => match name.strip_prefix('F').map(str::parse) {
+            Some(Ok(n @ 1..=12)) => Ok(Key::Function(n)),
+            _ => Err("unknown key"),
+        },
+    }
+}
+
+fn end(tokens: &mut Tokens) -> Result<(), &'static str> {
+    match tokens.next() {
+        Some(_) => Err("too many arguments"),
+        None => Ok(()),
+    }
+}
+
+/// The arguments of a line, without its comment.
+#[derive(Clone)]
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    /// Fails if the line contains a quote without a closing quote.
+    fn new(line: &'a str) -> Result<Self, &'static str> {
+        let tokens = Tokens { rest: line };
+        let mut check = tokens.clone();
+        while check.next_token()?.is_some() {}
+        Ok(tokens)
+    }
+
+    fn next_token(&mut self) -> Result<Option<&'a str>, &'static str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() || rest.starts_with('#') {
+            self.rest = "";
+            return Ok(None);
+        }
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or("missing closing quote")?;
+            self.rest = &quoted[end + 1..];
+            return Ok(Some(&quoted[..end]));
+        }
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '#' || c == '"')
+            .unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Ok(Some(&rest[..end]))
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the quotes were checked in `new`
+        self.next_token().ok().flatten()
+    }
+}
+
+#[derive(Default)]
+struct Variables<'a> {
+    entries: [(&'a str, &'a str); MAX_VARIABLES],
+    len: usize,
+}
+
+impl<'a> Variables<'a> {
+    fn set(&mut self, name: &'a str, value: &'a str) -> Result<(), &'static str> {
+        if let Some(entry) = self.entries[..self.len].iter_mut().find(|e| e.0 == name) {
+            entry.1 = value;
+            return Ok(());
+        }
+        let entry = self.entries.get_mut(self.len).ok_or("too many variables")?;
+        *entry = (name, value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Replaces a `$<name>` argument with the value of the variable.
+    fn expand(&self, arg: &'a str) -> Result<&'a str, &'static str> {
+        match arg.strip_prefix('$') {
+            Some(name) => self.entries[..self.len]
+                .iter()
+                .find(|e| e.0 == name)
+                .map(|e| e.1)
+                .ok_or("undefined variable"),
+            None => Ok(arg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{string::ToString, vec::Vec};
+
+    #[derive(Default)]
+    struct TestEnvironment {
+        pressed: Vec<Key>,
+        previous_boot_failed: bool,
+        queries: usize,
+    }
+
+    impl Environment for TestEnvironment {
+        fn key_pressed(&mut self, key: Key) -> bool {
+            self.queries += 1;
+            self.pressed.contains(&key)
+        }
+
+        fn previous_boot_failed(&mut self) -> bool {
+            self.queries += 1;
+            self.previous_boot_failed
+        }
+    }
+
+    fn boot<'a>(script: &'a str, env: &mut TestEnvironment) -> Option<(&'a str, Option<&'a str>)> {
+        run(script, env)
+            .unwrap()
+            .map(|entry| (entry.kernel, entry.ramdisk))
+    }
+
+    fn error(script: &str) -> (usize, &'static str) {
+        let err = run(script, &mut TestEnvironment::default()).unwrap_err();
+        (err.line, err.message)
+    }
+
+    #[test]
+    fn first_boot_statement_wins() {
+        let env = &mut TestEnvironment::default();
+        assert_eq!(
+            boot("boot kernel-a ramdisk\nboot kernel-b", env),
+            Some(("kernel-a", Some("ramdisk")))
+        );
+        assert_eq!(
+            boot("\n  boot   kernel-b  \n", env),
+            Some(("kernel-b", None))
+        );
+        assert_eq!(boot("set a b\n", env), None);
+        assert_eq!(boot("", env), None);
+    }
+
+    #[test]
+    fn comments() {
+        let env = &mut TestEnvironment::default();
+        let script = "# boot kernel-a\n  # indented\nboot kernel-b # boot kernel-c\n";
+        assert_eq!(boot(script, env), Some(("kernel-b", None)));
+        assert_eq!(boot("boot kernel#ramdisk", env), Some(("kernel", None)));
+    }
+
+    #[test]
+    fn quoting() {
+        let env = &mut TestEnvironment::default();
+        assert_eq!(
+            boot(r#"boot "my kernel" "ram#disk""#, env),
+            Some(("my kernel", Some("ram#disk")))
+        );
+        assert_eq!(
+            boot("set name \"new kernel\"\nboot $name", env),
+            Some(("new kernel", None))
+        );
+        assert_eq!(boot(r#"boot """#, env), Some(("", None)));
+        assert_eq!(
+            boot(r#"boot kernel"ramdisk""#, env),
+            Some(("kernel", Some("ramdisk")))
+        );
+        assert_eq!(
+            boot("# \"unterminated\nboot kernel", env),
+            Some(("kernel", None))
+        );
+        assert_eq!(
+            error("boot kernel \"ram disk"),
+            (1, "missing closing quote")
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let env = &mut TestEnvironment::default();
+        let script = "set kernel a\nset kernel b\nset ramdisk $kernel\nboot $kernel $ramdisk";
+        assert_eq!(boot(script, env), Some(("b", Some("b"))));
+        assert_eq!(error("boot $kernel"), (1, "undefined variable"));
+
+        let mut script = std::string::String::new();
+        for i in 0..=MAX_VARIABLES {
+            script += &std::format!("set v{i} x\n");
+        }
+        assert_eq!(error(&script), (MAX_VARIABLES + 1, "too many variables"));
+    }
+
+    #[test]
+    fn conditions() {
+        let env = &mut TestEnvironment {
+            pressed: [Key::Function(3), Key::Char('r')].into(),
+            previous_boot_failed: true,
+            queries: 0,
+        };
+        assert_eq!(
+            boot("if key-pressed F3 boot a\nboot b", env),
+            Some(("a", None))
+        );
+        assert_eq!(
+            boot("if key-pressed F4 boot a\nboot b", env),
+            Some(("b", None))
+        );
+        assert_eq!(boot("if key-pressed r boot a", env), Some(("a", None)));
+        assert_eq!(boot("if not key-pressed r boot a", env), None);
+        assert_eq!(
+            boot("if previous-boot-failed boot recovery", env),
+            Some(("recovery", None))
+        );
+        assert_eq!(
+            boot("set slot b\nif equals $slot b boot kernel-b", env),
+            Some(("kernel-b", None))
+        );
+        assert_eq!(
+            boot(
+                "if key-pressed r if not previous-boot-failed boot a\nboot b",
+                env
+            ),
+            Some(("b", None))
+        );
+    }
+
+    #[test]
+    fn skipped_statements_do_not_query_the_environment() {
+        let env = &mut TestEnvironment::default();
+        let script = "if key-pressed F1 if previous-boot-failed boot a\n\
+                      if not previous-boot-failed if key-pressed x set a b";
+        assert_eq!(boot(script, env), None);
+        // `previous-boot-failed` of the first line is skipped because `key-pressed F1` fails
+        assert_eq!(env.queries, 3);
+    }
+
+    #[test]
+    fn skipped_statements_are_still_checked() {
+        assert_eq!(
+            error("if key-pressed F1 boot a b c"),
+            (1, "too many arguments")
+        );
+        assert_eq!(error("if key-pressed F1 reboot"), (1, "unknown statement"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("set a b\nreboot now"), (2, "unknown statement"));
+        assert_eq!(error("boot"), (1, "`boot` needs a kernel file"));
+        assert_eq!(error("boot a b c"), (1, "too many arguments"));
+        assert_eq!(error("set a"), (1, "`set` needs a name and a value"));
+        assert_eq!(error("if"), (1, "`if` needs a condition and a statement"));
+        assert_eq!(error("if is-sunday boot a"), (1, "unknown condition"));
+        assert_eq!(error("if key-pressed F13 boot a"), (1, "unknown key"));
+        assert_eq!(error("if key-pressed Esc boot a"), (1, "unknown key"));
+        assert_eq!(error("if equals a"), (1, "`equals` needs two values"));
+        // the script stops at the first error, even if a later line would boot
+        assert_eq!(error("\n\nfoo\nboot a"), (3, "unknown statement"));
+    }
+
+    #[test]
+    fn error_display() {
+        let err = run("\nboot a b c", &mut TestEnvironment::default()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: too many arguments");
+    }
+}
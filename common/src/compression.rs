@@ -0,0 +1,232 @@
+//! Decompression of kernels that were compressed by the disk image builder.
+//!
+//! Header layout (all integers little-endian):
+//!
+//! | offset | size | content                                 |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 8    | magic `BLKCOMP1`                        |
+//! | 8      | 4    | algorithm (`1` = LZ4 block format)      |
+//! | 12     | 4    | reserved, zero                          |
+//! | 16     | 8    | length of the decompressed kernel       |
+//!
+//! The compressed data follows the header up to the end of the file.
+
+/// The magic bytes at the start of a compressed kernel.
+pub const MAGIC: [u8; 8] = *b"BLKCOMP1";
+/// The length of the header in bytes.
+pub const HEADER_LEN: usize = 24;
+/// The algorithm number of the LZ4 block format.
+pub const ALGORITHM_LZ4: u32 = 1;
+
+/// The minimum length of an LZ4 match.
+const MIN_MATCH: usize = 4;
+
+/// Returns the length of the decompressed kernel if the given file is a compressed kernel.
+pub fn decompressed_len(file: &[u8]) -> Option<usize> {
+    if file.len() < HEADER_LEN || file[..8] != MAGIC {
+        return None;
+    }
+    let mut len = [0; 8];
+    len.copy_from_slice(&file[16..24]);
+    usize::try_from(u64::from_le_bytes(len)).ok()
+}
+
+/// Decompresses the given compressed kernel into `out`, which must have the length returned
+/// by [`decompressed_len`].
+pub fn decompress(file: &[u8], out: &mut [u8]) -> Result<(), &'static str> {
+    if decompressed_len(file) != Some(out.len()) {
+        return Err("invalid compressed kernel header");
+    }
+    let mut algorithm = [0; 4];
+    algorithm.copy_from_slice(&file[8..12]);
+    match u32::from_le_bytes(algorithm) {
+        ALGORITHM_LZ4 => decompress_lz4(&file[HEADER_LEN..], out),
+        _ => Err("unsupported kernel compression algorithm"),
+    }
+}
+
+/// Decodes an LZ4 block that fills `out` exactly.
+fn decompress_lz4(input: &[u8], out: &mut [u8]) -> Result<(), &'static str> {
+    const TRUNCATED: &str = "truncated LZ4 data";
+
+    let mut input = input.iter().copied();
+    let mut pos = 0usize;
+    while let Some(token) = input.next() {
+        let literals = read_length(&mut input, usize::from(token >> 4)).ok_or(TRUNCATED)?;
+        let literals_end = pos
+            .checked_add(literals)
+            .filter(|&end| end <= out.len())
+            .ok_or("LZ4 literals exceed the kernel length")?;
+        for byte in &mut out[pos..literals_end] {
+            *byte = input.next().ok_or(TRUNCATED)?;
+        }
+        pos = literals_end;
+
+        // the last sequence only consists of literals
+        let Some(low) = input.next() else { break };
+        let offset = usize::from(u16::from_le_bytes([low, input.next().ok_or(TRUNCATED)?]));
+        if offset == 0 || offset > pos {
+            return Err("invalid LZ4 match offset");
+        }
+        let len = read_length(&mut input, usize::from(token & 0xf)).ok_or(TRUNCATED)?;
+        let match_end = len
+            .checked_add(MIN_MATCH)
+            .and_then(|len| pos.checked_add(len))
+            .filter(|&end| end <= out.len())
+            .ok_or("LZ4 match exceeds the kernel length")?;
+        // the match may overlap with its own output, so it's copied byte by byte
+        for i in pos..match_end {
+            out[i] = out[i - offset];
+        }
+        pos = match_end;
+    }
+
+    if pos != out.len() {
+        return Err("LZ4 data is shorter than the kernel length");
+    }
+    Ok(())
+}
+
+/// Reads the extension bytes of a literal or match length whose token nibble is `nibble`.
+///
+/// Returns `None` if the input ends early. Lengths that don't fit into a `usize` saturate,
+/// the callers reject them because they exceed the kernel length.
+fn read_length(input: &mut impl Iterator<Item = u8>, nibble: usize) -> Option<usize> {
+    let mut len = nibble;
+    if nibble == 0xf {
+        loop {
+            let byte = input.next()?;
+            len = len.saturating_add(usize::from(byte));
+            if byte != 0xff {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    fn compressed(algorithm: u32, len: usize, data: &[u8]) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&MAGIC);
+        file.extend_from_slice(&algorithm.to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&(len as u64).to_le_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    fn lz4(data: &[u8], len: usize) -> Result<Vec<u8>, &'static str> {
+        let mut out = vec![0; len];
+        decompress_lz4(data, &mut out).map(|()| out)
+    }
+
+    /// `abc` followed by a match that repeats it three times, then the literals `xy`.
+    const ABC: &[u8] = &[0x35, b'a', b'b', b'c', 3, 0, 0x20, b'x', b'y'];
+
+    #[test]
+    fn literals_and_overlapping_matches() {
+        assert_eq!(lz4(ABC, 14).unwrap(), b"abcabcabcabcxy");
+        assert_eq!(lz4(&[0x30, b'a', b'b', b'c'], 3).unwrap(), b"abc");
+        assert_eq!(lz4(&[], 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn extended_lengths() {
+        // 15 + 255 + 10 literals, then a match of 4 + 15 + 1 bytes
+        let mut data = vec![0xff, 255, 10];
+        data.extend((0..280).map(|i| i as u8));
+        data.extend_from_slice(&[1, 0, 1]);
+        let out = lz4(&data, 300).unwrap();
+        assert_eq!(out[..280], *(0..280).map(|i| i as u8).collect::<Vec<_>>());
+        assert!(out[280..].iter().all(|&b| b == out[279]));
+    }
+
+    #[test]
+    fn header() {
+        let file = compressed(ALGORITHM_LZ4, 14, ABC);
+        assert_eq!(decompressed_len(&file), Some(14));
+        let mut out = [0; 14];
+        decompress(&file, &mut out).unwrap();
+        assert_eq!(&out, b"abcabcabcabcxy");
+
+        assert_eq!(decompressed_len(&file[..HEADER_LEN - 1]), None);
+        assert_eq!(decompressed_len(b"\x7fELF"), None);
+        assert!(decompress(&file, &mut [0; 13]).is_err());
+        assert!(decompress(&file, &mut [0; 15]).is_err());
+        let unknown = compressed(2, 14, ABC);
+        assert!(decompress(&unknown, &mut out).is_err());
+    }
+
+    #[test]
+    fn output_size_mismatch() {
+        assert_eq!(lz4(ABC, 13), Err("LZ4 literals exceed the kernel length"));
+        assert_eq!(lz4(ABC, 11), Err("LZ4 match exceeds the kernel length"));
+        assert_eq!(
+            lz4(ABC, 15),
+            Err("LZ4 data is shorter than the kernel length")
+        );
+        assert_eq!(
+            lz4(&[0x30, b'a', b'b', b'c'], 2),
+            Err("LZ4 literals exceed the kernel length")
+        );
+    }
+
+    #[test]
+    fn truncated_input() {
+        for len in 1..ABC.len() {
+            if len == 6 {
+                // the input ends after a complete sequence
+                assert_eq!(
+                    lz4(&ABC[..len], 14),
+                    Err("LZ4 data is shorter than the kernel length")
+                );
+            } else {
+                assert!(lz4(&ABC[..len], 14).is_err(), "{len}");
+            }
+        }
+        assert_eq!(lz4(&[0xf0], 20), Err("truncated LZ4 data"));
+        assert_eq!(lz4(&[0xf0, 255], 300), Err("truncated LZ4 data"));
+    }
+
+    #[test]
+    fn invalid_match_offsets() {
+        assert_eq!(lz4(&[0x10, b'a', 0, 0], 5), Err("invalid LZ4 match offset"));
+        assert_eq!(lz4(&[0x10, b'a', 2, 0], 5), Err("invalid LZ4 match offset"));
+        assert_eq!(lz4(&[0x00, 1, 0], 4), Err("invalid LZ4 match offset"));
+    }
+
+    #[test]
+    fn long_lengths_beyond_the_kernel() {
+        let mut data = vec![0x1f, b'a', 1, 0];
+        data.extend([255; 1000]);
+        data.push(0);
+        assert_eq!(lz4(&data, 16), Err("LZ4 match exceeds the kernel length"));
+
+        let mut data = vec![0xf0];
+        data.extend([255; 1000]);
+        data.push(0);
+        assert_eq!(lz4(&data, 16), Err("LZ4 literals exceed the kernel length"));
+    }
+
+    #[test]
+    fn random_input_does_not_panic() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..10_000 {
+            let data: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+            let _ = lz4(&data, (next() % 256) as usize);
+        }
+    }
+}
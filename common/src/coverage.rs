@@ -0,0 +1,101 @@
+//! Execution counters of instrumented code locations, enabled by the `coverage` feature.
+//!
+//! Code is instrumented with [`hit`] calls, which compile to nothing without the feature.
+//! Before the bootloader jumps to the kernel, [`dump`] writes the counters to the debug
+//! console, where the `coverage-report` subcommand of the builder picks them up:
+//!
+//! ```text
+//! coverage-begin
+//! common/src/load_kernel.rs:210:9 3
+//! coverage-end
+//! ```
+
+/// The first line of the dump.
+pub const BEGIN_MARKER: &str = "coverage-begin";
+/// The last line of the dump.
+pub const END_MARKER: &str = "coverage-end";
+
+/// Counts an execution of the calling code location.
+#[inline(always)]
+#[track_caller]
+pub fn hit() {
+    #[cfg(feature = "coverage")]
+    counters::hit(core::panic::Location::caller());
+}
+
+/// Writes the counters of all locations that were executed at least once to the debug
+/// console.
+pub fn dump() {
+    #[cfg(feature = "coverage")]
+    counters::dump();
+}
+
+#[cfg(feature = "coverage")]
+mod counters {
+    use super::{BEGIN_MARKER, END_MARKER};
+    use crate::debugcon::DebugCon;
+    use core::{
+        fmt::Write,
+        panic::Location,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+    };
+
+    /// The maximum number of distinct locations, further locations are only counted in
+    /// [`DROPPED`].
+    const MAX_LOCATIONS: usize = 256;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_LOCATION: AtomicPtr<Location<'static>> = AtomicPtr::new(ptr::null_mut());
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU32 = AtomicU32::new(0);
+
+    /// An open-addressing hash table from locations to counters.
+    static LOCATIONS: [AtomicPtr<Location<'static>>; MAX_LOCATIONS] = [NO_LOCATION; MAX_LOCATIONS];
+    static COUNTS: [AtomicU32; MAX_LOCATIONS] = [ZERO; MAX_LOCATIONS];
+    static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+    pub fn hit(location: &'static Location<'static>) {
+        let hash = (location.line() as usize).wrapping_mul(31) ^ location.column() as usize;
+        for probe in 0..MAX_LOCATIONS {
+            let index = (hash + probe) % MAX_LOCATIONS;
+            let current = LOCATIONS[index].load(Ordering::Relaxed);
+            let occupied = if current.is_null() {
+                match LOCATIONS[index].compare_exchange(
+                    ptr::null_mut(),
+                    location as *const _ as *mut _,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => None,
+                    Err(other) => Some(other),
+                }
+            } else {
+                Some(current)
+            };
+            // the same call site might be represented by different `Location` statics
+            let matches = occupied.map_or(true, |other| unsafe { *other == *location });
+            if matches {
+                COUNTS[index].fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dump() {
+        let mut out = DebugCon::new();
+        let _ = writeln!(out, "{BEGIN_MARKER}");
+        for (location, count) in LOCATIONS.iter().zip(&COUNTS) {
+            let location = location.load(Ordering::Relaxed);
+            if let Some(location) = unsafe { location.as_ref() } {
+                let _ = writeln!(out, "{location} {}", count.load(Ordering::Relaxed));
+            }
+        }
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        if dropped > 0 {
+            let _ = writeln!(out, "dropped {dropped}");
+        }
+        let _ = writeln!(out, "{END_MARKER}");
+    }
+}
@@ -0,0 +1,36 @@
+use core::fmt;
+use x86_64::instructions::port::PortWriteOnly;
+
+/// The I/O port of the debug console of Bochs and QEMU.
+const DEBUGCON_PORT: u16 = 0xe9;
+
+/// The debug console of Bochs and QEMU (`-debugcon`).
+///
+/// Every byte written to the port is output by the emulator right away, which makes this
+/// the fastest way to capture the boot log. On real hardware, the port is usually unused.
+pub struct DebugCon {
+    port: PortWriteOnly<u8>,
+}
+
+impl DebugCon {
+    pub fn new() -> Self {
+        Self {
+            port: PortWriteOnly::new(DEBUGCON_PORT),
+        }
+    }
+}
+
+impl Default for DebugCon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            unsafe { self.port.write(byte) };
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,30 @@
+use crate::record;
+use conquer_once::spin::OnceCell;
 use rand::SeedableRng;
 use rand_hc::Hc128Rng;
 use raw_cpuid::CpuId;
 use x86_64::instructions::{port::Port, random::RdRand};
 
+/// Random bytes from the firmware, e.g. from the `EFI_RNG_PROTOCOL`.
+static FIRMWARE_ENTROPY: OnceCell<[u8; 32]> = OnceCell::uninit();
+
+/// Adds random bytes from the firmware to the seed of [`build_rng`].
+///
+/// The UEFI bootloader has to call this before exiting the boot services. Only the first
+/// call has an effect.
+pub fn set_firmware_entropy(entropy: [u8; 32]) {
+    FIRMWARE_ENTROPY.init_once(|| entropy);
+}
+
 /// Gather entropy from various sources to seed a RNG.
 pub fn build_rng() -> Hc128Rng {
-    const ENTROPY_SOURCES: [fn() -> [u8; 32]; 3] = [rd_rand_entropy, tsc_entropy, pit_entropy];
+    const ENTROPY_SOURCES: [fn() -> [u8; 32]; 5] = [
+        firmware_entropy,
+        rd_seed_entropy,
+        rd_rand_entropy,
+        tsc_entropy,
+        pit_entropy,
+    ];
 
     // Collect entropy from different sources and xor them all together.
     let mut seed = [0; 32];
@@ -17,10 +36,57 @@ pub fn build_rng() -> Hc128Rng {
         }
     }
 
+    record::rng_seed(seed);
     // Construct the RNG.
     Hc128Rng::from_seed(seed)
 }
 
+/// Returns the entropy that the firmware provided through [`set_firmware_entropy`].
+fn firmware_entropy() -> [u8; 32] {
+    FIRMWARE_ENTROPY.get().copied().unwrap_or_default()
+}
+
+/// Gather entropy by requesting random numbers with the `RDSEED` instruction if it's
+/// available.
+///
+/// Unlike `RDRAND`, `RDSEED` returns values directly from the hardware entropy source, which
+/// is what seeding an RNG calls for.
+fn rd_seed_entropy() -> [u8; 32] {
+    let mut entropy = [0; 32];
+
+    // Check if the CPU supports `RDSEED`.
+    let cpu_id = CpuId::new();
+    if let Some(feature_info) = cpu_id.get_extended_feature_info() {
+        if feature_info.has_rdseed() {
+            for i in 0..4 {
+                // SAFETY: We checked that the CPU supports `RDSEED`.
+                if let Some(value) = unsafe { get_seed_64() } {
+                    entropy[i * 8..(i + 1) * 8].copy_from_slice(&value.to_ne_bytes());
+                }
+            }
+        }
+    }
+
+    entropy
+}
+
+/// Try to fetch a 64 bit seed value with a retry count limit of 100.
+///
+/// `RDSEED` fails more often than `RDRAND` when the entropy source is drained, so it is
+/// retried more often.
+#[target_feature(enable = "rdseed")]
+unsafe fn get_seed_64() -> Option<u64> {
+    const RETRY_LIMIT: u32 = 100;
+    for _ in 0..RETRY_LIMIT {
+        let mut value = 0;
+        if unsafe { core::arch::x86_64::_rdseed64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
 /// Gather entropy by requesting random numbers with `RDRAND` instruction if it's available.
 ///
 /// This function provides excellent entropy (unless you don't trust the CPU vendors).
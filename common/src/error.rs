@@ -0,0 +1,301 @@
+use crate::{logger::LOGGER, messages, messages::Catalog, power, serial::SerialPort};
+use bootloader_api::{config::Language, BootloaderConfig};
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+static STAGE: AtomicU8 = AtomicU8::new(BootStage::Unknown as u8);
+static QR_CODE: AtomicBool = AtomicBool::new(false);
+static LANGUAGE: AtomicU8 = AtomicU8::new(Language::English as u8);
+static SUPPORT_INFO: OnceCell<&'static str> = OnceCell::uninit();
+
+/// The boot stage that reports an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootStage {
+    /// The stage was not set yet.
+    Unknown,
+    /// The fourth stage of the BIOS bootloader, which loads the kernel.
+    BiosStage4,
+    /// The UEFI bootloader.
+    Uefi,
+}
+
+impl BootStage {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => BootStage::BiosStage4,
+            2 => BootStage::Uefi,
+            _ => BootStage::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for BootStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootStage::Unknown => f.write_str("unknown"),
+            BootStage::BiosStage4 => f.write_str("bios-stage-4"),
+            BootStage::Uefi => f.write_str("uefi"),
+        }
+    }
+}
+
+/// An error that prevents the bootloader from starting the kernel.
+///
+/// Each error has a stable [error code](Self::code) that users can include in bug reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BootError {
+    /// The bootloader panicked, which indicates a bug in the bootloader.
+    Internal,
+    /// The kernel executable was not found on any boot medium.
+    KernelNotFound,
+    /// The kernel executable is not a valid ELF file.
+    InvalidKernel(&'static str),
+    /// The kernel executable has no `.bootloader-config` section.
+    MissingConfig,
+    /// The bootloader config of the kernel could not be parsed.
+    IncompatibleConfig(&'static str),
+    /// The segments of the kernel executable could not be loaded.
+    KernelLoadFailed(&'static str),
+    /// The bootloader ran out of memory while allocating the given object.
+    OutOfMemory(&'static str),
+    /// The bootloader failed to map the given object in the kernel address space.
+    MappingFailed(&'static str),
+    /// Two memory regions that are used by the kernel or bootloader overlap.
+    MemoryConflict,
+    /// The firmware did not report any physical memory regions.
+    MemoryMapUnavailable,
+    /// The UEFI boot services could not be exited.
+    ExitBootServicesFailed,
+    /// The framebuffer uses an unsupported pixel format.
+    UnsupportedFramebuffer(&'static str),
+    /// The given boot file could not be loaded over the network before the load deadline.
+    LoadDeadlineExceeded(&'static str),
+    /// The security version of the kernel is lower than the stored minimum version.
+    KernelRolledBack,
+    /// The kernel is not signed with a trusted key, for the given reason.
+    UntrustedKernel(&'static str),
+}
+
+impl BootError {
+    /// Returns the numeric error code.
+    ///
+    /// The hundreds digit describes the error category: `0` for bootloader bugs, `1` for
+    /// problems with the kernel executable, `2` for memory management, and `3` for firmware
+    /// problems.
+    pub fn code(&self) -> u16 {
+        match self {
+            BootError::Internal => 1,
+            BootError::KernelNotFound => 101,
+            BootError::InvalidKernel(_) => 102,
+            BootError::MissingConfig => 103,
+            BootError::IncompatibleConfig(_) => 104,
+            BootError::KernelLoadFailed(_) => 105,
+            BootError::OutOfMemory(_) => 201,
+            BootError::MappingFailed(_) => 202,
+            BootError::MemoryConflict => 203,
+            BootError::MemoryMapUnavailable => 301,
+            BootError::ExitBootServicesFailed => 302,
+            BootError::UnsupportedFramebuffer(_) => 303,
+            BootError::LoadDeadlineExceeded(_) => 304,
+            BootError::KernelRolledBack => 106,
+            BootError::UntrustedKernel(_) => 107,
+        }
+    }
+
+    /// The number of error variants, i.e. the length of the message arrays of a [`Catalog`].
+    pub(crate) const COUNT: usize = 15;
+
+    /// Returns the position of the error variant in the message arrays of a [`Catalog`].
+    pub(crate) fn index(&self) -> usize {
+        match self {
+            BootError::Internal => 0,
+            BootError::KernelNotFound => 1,
+            BootError::InvalidKernel(_) => 2,
+            BootError::MissingConfig => 3,
+            BootError::IncompatibleConfig(_) => 4,
+            BootError::KernelLoadFailed(_) => 5,
+            BootError::OutOfMemory(_) => 6,
+            BootError::MappingFailed(_) => 7,
+            BootError::MemoryConflict => 8,
+            BootError::MemoryMapUnavailable => 9,
+            BootError::ExitBootServicesFailed => 10,
+            BootError::UnsupportedFramebuffer(_) => 11,
+            BootError::LoadDeadlineExceeded(_) => 12,
+            BootError::KernelRolledBack => 13,
+            BootError::UntrustedKernel(_) => 14,
+        }
+    }
+
+    /// Returns the untranslated detail that is attached to some error variants.
+    pub fn detail(&self) -> Option<&'static str> {
+        match self {
+            BootError::InvalidKernel(detail)
+            | BootError::IncompatibleConfig(detail)
+            | BootError::KernelLoadFailed(detail)
+            | BootError::OutOfMemory(detail)
+            | BootError::MappingFailed(detail)
+            | BootError::UnsupportedFramebuffer(detail)
+            | BootError::LoadDeadlineExceeded(detail)
+            | BootError::UntrustedKernel(detail) => Some(detail),
+            _ => None,
+        }
+    }
+
+    /// Returns a hint on how to resolve the error.
+    pub fn hint(&self) -> &'static str {
+        messages::ENGLISH.hint(self)
+    }
+
+    /// Writes the description of the error in the language of the given catalog.
+    pub fn write_description(&self, f: &mut impl Write, catalog: &Catalog) -> fmt::Result {
+        let description = catalog.description(self);
+        match (self, self.detail()) {
+            // the detail is the object of the sentence
+            (BootError::OutOfMemory(_) | BootError::MappingFailed(_), Some(object)) => {
+                write!(f, "{description} {object}")
+            }
+            (_, Some(detail)) => write!(f, "{description}: {detail}"),
+            (_, None) => f.write_str(description),
+        }
+    }
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_description(f, &messages::ENGLISH)
+    }
+}
+
+/// The content of the error screen.
+pub struct ErrorReport<'a> {
+    /// The boot stage that reported the error.
+    pub stage: BootStage,
+    /// The error.
+    pub error: BootError,
+    /// Additional details, e.g. the panic message.
+    pub details: Option<&'a dyn fmt::Display>,
+    /// The messages in the configured language.
+    pub catalog: &'static Catalog,
+    /// Contact information for support, if provided by the boot image.
+    pub support: Option<&'static str>,
+}
+
+impl ErrorReport<'_> {
+    /// Writes the short summary that is encoded in the QR code of the error screen.
+    pub fn write_summary(&self, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            "bootloader error E{:04} ({}): {}",
+            self.error.code(),
+            self.stage,
+            self.error
+        )
+    }
+}
+
+impl fmt::Display for ErrorReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let catalog = self.catalog;
+        writeln!(f, "{}: E{:04}", catalog.error_code, self.error.code())?;
+        writeln!(f, "{}: {}", catalog.stage, self.stage)?;
+        write!(f, "{}: ", catalog.error)?;
+        self.error.write_description(f, catalog)?;
+        writeln!(f)?;
+        if let Some(details) = self.details {
+            writeln!(f, "{}: {details}", catalog.details)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", catalog.hint(&self.error))?;
+        if let Some(support) = self.support {
+            writeln!(f)?;
+            writeln!(f, "{}:", catalog.support)?;
+            writeln!(f, "{}", support.trim_end())?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", catalog.power)?;
+        Ok(())
+    }
+}
+
+/// Sets the boot stage that is reported on the error screen.
+pub fn set_stage(stage: BootStage) {
+    STAGE.store(stage as u8, Ordering::Relaxed);
+}
+
+/// Applies the error screen options of the kernel's config, i.e. the
+/// [`error_qr_code`](BootloaderConfig::error_qr_code) and
+/// [`language`](BootloaderConfig::language) fields.
+///
+/// Errors that occur before the config is parsed are reported in English.
+pub fn configure(config: &BootloaderConfig) {
+    QR_CODE.store(config.error_qr_code, Ordering::Relaxed);
+    LANGUAGE.store(config.language as u8, Ordering::Relaxed);
+}
+
+/// Sets the support contact information that is shown on the error screen.
+///
+/// Only the first call has an effect.
+pub fn set_support_info(info: &'static str) {
+    SUPPORT_INFO.init_once(|| info);
+}
+
+/// Shows the error screen for the given error and waits until the user reboots or powers off
+/// the system, see [`power::wait_for_action`].
+///
+/// The error screen is drawn to the framebuffer and mirrored to the serial port, depending
+/// on the logger configuration. Before the logger is initialized, the error is only
+/// written to the serial port.
+pub fn fail(error: BootError) -> ! {
+    fail_on_host(error, None);
+    report(error, None);
+    power::wait_for_action()
+}
+
+/// Shows the error screen for the given error with additional details and waits until the
+/// user reboots or powers off the system.
+pub fn fail_with_details(error: BootError, details: &dyn fmt::Display) -> ! {
+    fail_on_host(error, Some(details));
+    report(error, Some(details));
+    power::wait_for_action()
+}
+
+/// Panics when the code runs on the host, e.g. in a replay of a recorded boot, where the
+/// error screen and the power management ports aren't accessible.
+fn fail_on_host(error: BootError, details: Option<&dyn fmt::Display>) {
+    if cfg!(not(any(target_os = "none", target_os = "uefi"))) {
+        match details {
+            Some(details) => panic!("boot error: {error}: {details}"),
+            None => panic!("boot error: {error}"),
+        }
+    }
+}
+
+/// Shows the error screen for the given error with additional details.
+///
+/// Used by the panic handlers, which wait for the user afterwards.
+pub fn report(error: BootError, details: Option<&dyn fmt::Display>) {
+    let report = ErrorReport {
+        stage: BootStage::from_u8(STAGE.load(Ordering::Relaxed)),
+        error,
+        details,
+        catalog: Catalog::get(
+            Language::from_u8(LANGUAGE.load(Ordering::Relaxed)).unwrap_or(Language::English),
+        ),
+        support: SUPPORT_INFO.get().copied(),
+    };
+    match LOGGER.get() {
+        Some(logger) => {
+            // the error might have occurred while the logger was locked
+            unsafe { logger.force_unlock() };
+            logger.show_error_screen(&report, QR_CODE.load(Ordering::Relaxed));
+        }
+        None => {
+            let _ = write!(SerialPort::new(), "\nBOOT ERROR\n{report}");
+        }
+    }
+}
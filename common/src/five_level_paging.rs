@@ -0,0 +1,177 @@
+//! Switching the kernel to 5-level paging, see [`BootloaderConfig::five_level_paging`].
+//!
+//! The kernel page tables are built with 4 levels like usual. Right before the jump to the
+//! kernel, a level 5 table is put on top of them: its first entry points to the lower half of
+//! the level 4 table and its last entry to a copy of the higher half, so all mappings keep their
+//! addresses. `CR4.LA57` can only be changed while paging is disabled, so the context switch
+//! briefly leaves long mode through a 32-bit code segment. The level 5 table, the context
+//! switch code, and the data of the switch must thus lie below 4GiB.
+//!
+//! [`BootloaderConfig::five_level_paging`]: bootloader_api::BootloaderConfig::five_level_paging
+
+use crate::{
+    error::{self, BootError},
+    legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+    regions::RegionRegistry,
+};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use x86_64::{
+    structures::{
+        gdt::SegmentSelector,
+        paging::{
+            FrameAllocator, Mapper, OffsetPageTable, PageSize, PageTable, PageTableFlags,
+            PhysFrame, Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The `LA57` bit of `CPUID.(EAX=7, ECX=0):ECX`.
+const CPUID_7_LA57: u32 = 1 << 16;
+/// The end of the memory that 32-bit code can address.
+const FOUR_GIB: u64 = 0x1_0000_0000;
+/// The level 5 entry of a [`Mapping::Dynamic`] physical memory mapping.
+///
+/// [`Mapping::Dynamic`]: bootloader_api::config::Mapping::Dynamic
+const PHYSICAL_MEMORY_ENTRY: usize = 510;
+/// The start of the 256TiB that the level 5 entry [`PHYSICAL_MEMORY_ENTRY`] covers.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xfffe_0000_0000_0000;
+/// The size of the physical memory that fits into the entry [`PHYSICAL_MEMORY_ENTRY`].
+pub const PHYSICAL_MEMORY_LIMIT: u64 = 1 << 48;
+
+/// The frames and the GDT selector that the switch to 5-level paging needs.
+#[derive(Debug, Clone, Copy)]
+pub struct FiveLevelPaging {
+    level_5_frame: PhysFrame,
+    /// The level 4 table for the higher half.
+    upper_level_4_frame: PhysFrame,
+    /// The identity-mapped frame that holds the parameters and the stack of the switch.
+    switch_frame: PhysFrame,
+    /// The level 4 table of a physical memory mapping in its own level 5 entry.
+    physical_memory_frame: Option<PhysFrame>,
+    /// The selector of the 32-bit code segment, set once the GDT was created.
+    compat_code_selector: Option<SegmentSelector>,
+}
+
+/// Whether the CPU supports 5-level paging.
+pub fn supported() -> bool {
+    unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ecx & CPUID_7_LA57 != 0
+}
+
+impl FiveLevelPaging {
+    /// Allocates the frames of the switch and identity-maps the switch frame in the kernel
+    /// page table.
+    ///
+    /// Returns `None` if 5-level paging is not possible, e.g. because the CPU doesn't support
+    /// it or the context switch code at `context_switch` is above 4GiB.
+    pub fn prepare<I, D>(
+        context_switch: PhysAddr,
+        kernel_page_table: &mut OffsetPageTable,
+        frame_allocator: &mut LegacyFrameAllocator<I, D>,
+        regions: &mut RegionRegistry,
+    ) -> Option<Self>
+    where
+        I: ExactSizeIterator<Item = D> + Clone,
+        D: LegacyMemoryRegion,
+    {
+        if !supported() {
+            log::warn!("The CPU doesn't support 5-level paging, using 4-level paging");
+            return None;
+        }
+        if context_switch.as_u64() >= FOUR_GIB - 2 * Size4KiB::SIZE {
+            log::warn!("The bootloader was loaded above 4GiB, using 4-level paging");
+            return None;
+        }
+        let start = frame_allocator
+            .allocate_contiguous_below(3, PhysAddr::new(FOUR_GIB))
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the level 5 page table")));
+        let paging = Self {
+            level_5_frame: start,
+            upper_level_4_frame: start + 1,
+            switch_frame: start + 2,
+            physical_memory_frame: None,
+            compat_code_selector: None,
+        };
+        for frame in PhysFrame::range(start, start + 3) {
+            unsafe { table(frame) }.zero();
+        }
+
+        regions.claim_virtual(
+            VirtAddr::new(paging.switch_frame.start_address().as_u64()),
+            Size4KiB::SIZE,
+            "identity-mapped 5-level paging switch",
+        );
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { kernel_page_table.identity_map(paging.switch_frame, flags, frame_allocator) }
+        {
+            Ok(tlb) => tlb.flush(),
+            Err(err) => {
+                crate::mapping_failed("the 5-level paging switch", paging.switch_frame, err)
+            }
+        }
+        Some(paging)
+    }
+
+    /// Sets the selector of the 32-bit code segment of the kernel GDT.
+    pub fn set_compat_code_selector(&mut self, selector: Option<SegmentSelector>) {
+        self.compat_code_selector = selector;
+    }
+
+    /// Creates the level 4 table of a physical memory mapping at [`PHYSICAL_MEMORY_OFFSET`].
+    ///
+    /// The returned page table maps the physical address `addr` at the virtual address
+    /// `VirtAddr::new_truncate(addr)`, which ends up at `PHYSICAL_MEMORY_OFFSET + addr` once
+    /// the table is put into the level 5 table.
+    pub fn physical_memory_table(
+        &mut self,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> OffsetPageTable<'static> {
+        let frame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the physical memory mapping")));
+        self.physical_memory_frame = Some(frame);
+        let table = unsafe { table(frame) };
+        table.zero();
+        // the bootloader identity-maps the physical memory
+        unsafe { OffsetPageTable::new(table, VirtAddr::new(0)) }
+    }
+
+    /// Creates the level 5 table on top of the given level 4 table and returns its frame.
+    ///
+    /// Moves the higher half of the level 4 table to a separate table, so that it isn't also
+    /// mapped at the lower half addresses above 128TiB. Must be called after all mappings were
+    /// created.
+    pub fn build_level_5_table(&self, level_4_frame: PhysFrame) -> PhysFrame {
+        let level_4 = unsafe { table(level_4_frame) };
+        let upper_level_4 = unsafe { table(self.upper_level_4_frame) };
+        for index in 256..512 {
+            upper_level_4[index] = level_4[index].clone();
+            level_4[index].set_unused();
+        }
+
+        let level_5 = unsafe { table(self.level_5_frame) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        level_5[0].set_frame(level_4_frame, flags);
+        level_5[511].set_frame(self.upper_level_4_frame, flags);
+        if let Some(frame) = self.physical_memory_frame {
+            level_5[PHYSICAL_MEMORY_ENTRY].set_frame(frame, flags);
+        }
+        self.level_5_frame
+    }
+
+    /// The start address of the identity-mapped switch frame.
+    pub(crate) fn switch_frame_addr(&self) -> u64 {
+        self.switch_frame.start_address().as_u64()
+    }
+
+    pub(crate) fn compat_code_selector(&self) -> u16 {
+        self.compat_code_selector
+            .expect("GDT without 32-bit code segment")
+            .0
+    }
+}
+
+/// Returns the page table in the given identity-mapped frame.
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *(frame.start_address().as_u64() as *mut PageTable) }
+}
@@ -1,9 +1,13 @@
+use crate::error::ErrorReport;
 use bootloader_api::info::{FrameBufferInfo, PixelFormat};
-use core::{fmt, ptr};
-use font_constants::BACKUP_CHAR;
+use core::{
+    fmt::{self, Write},
+    ptr,
+};
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
 
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 2;
@@ -13,6 +17,12 @@ const LETTER_SPACING: usize = 0;
 /// Padding from the border. Prevent that font is too close to border.
 const BORDER_PADDING: usize = 1;
 
+/// Padding around the content of the error screen.
+const ERROR_SCREEN_PADDING: usize = 16;
+
+/// The largest QR code version that is used on the error screen.
+const QR_CODE_MAX_VERSION: Version = Version::new(10);
+
 /// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
 mod font_constants {
     use super::*;
@@ -29,18 +39,46 @@ mod font_constants {
     pub const BACKUP_CHAR: char = '�';
 
     pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+
+    /// Height of each char raster in the accessible display mode.
+    pub const LARGE_CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size24;
+
+    /// The width of each single symbol in the accessible display mode.
+    pub const LARGE_CHAR_RASTER_WIDTH: usize =
+        get_raster_width(LARGE_FONT_WEIGHT, LARGE_CHAR_RASTER_HEIGHT);
+
+    /// The bold weight improves the legibility in the accessible display mode.
+    pub const LARGE_FONT_WEIGHT: FontWeight = FontWeight::Bold;
+}
+
+/// The font size and weight that is used to render text.
+#[derive(Debug, Clone, Copy)]
+struct Font {
+    height: RasterHeight,
+    width: usize,
+    weight: FontWeight,
 }
 
-/// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
-fn get_char_raster(c: char) -> RasterizedChar {
-    fn get(c: char) -> Option<RasterizedChar> {
-        get_raster(
-            c,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
+impl Font {
+    const REGULAR: Font = Font {
+        height: font_constants::CHAR_RASTER_HEIGHT,
+        width: font_constants::CHAR_RASTER_WIDTH,
+        weight: font_constants::FONT_WEIGHT,
+    };
+
+    const LARGE: Font = Font {
+        height: font_constants::LARGE_CHAR_RASTER_HEIGHT,
+        width: font_constants::LARGE_CHAR_RASTER_WIDTH,
+        weight: font_constants::LARGE_FONT_WEIGHT,
+    };
+
+    /// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
+    fn char_raster(&self, c: char) -> RasterizedChar {
+        let get = |c| get_raster(c, self.weight, self.height);
+        get(c).unwrap_or_else(|| {
+            get(font_constants::BACKUP_CHAR).expect("Should get raster of backup char.")
+        })
     }
-    get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
 }
 
 /// Allows logging text to a pixel-based framebuffer.
@@ -49,33 +87,59 @@ pub struct FrameBufferWriter {
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    /// The x position at which new lines start.
+    left_margin: usize,
+    font: Font,
+    /// Render text in pure white instead of the default yellowish color.
+    high_contrast: bool,
+    /// The RGB color of the text, overrides the default color.
+    color: Option<[u8; 3]>,
 }
 
 impl FrameBufferWriter {
     /// Creates a new logger that uses the given framebuffer.
-    pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+    ///
+    /// If `accessible` is set, text is rendered in high contrast with a larger, bold font.
+    pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo, accessible: bool) -> Self {
         let mut logger = Self {
             framebuffer,
             info,
             x_pos: 0,
             y_pos: 0,
+            left_margin: BORDER_PADDING,
+            font: if accessible {
+                Font::LARGE
+            } else {
+                Font::REGULAR
+            },
+            high_contrast: accessible,
+            color: None,
         };
         logger.clear();
         logger
     }
 
+    /// Sets the RGB color of the following text, or restores the default color for `None`.
+    pub fn set_color(&mut self, color: Option<[u8; 3]>) {
+        self.color = color;
+    }
+
+    fn line_height(&self) -> usize {
+        self.font.height.val() + LINE_SPACING
+    }
+
     fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        self.y_pos += self.line_height();
         self.carriage_return()
     }
 
     fn carriage_return(&mut self) {
-        self.x_pos = BORDER_PADDING;
+        self.x_pos = self.left_margin;
     }
 
     /// Erases all text on the screen. Resets `self.x_pos` and `self.y_pos`.
     pub fn clear(&mut self) {
-        self.x_pos = BORDER_PADDING;
+        self.x_pos = self.left_margin;
         self.y_pos = BORDER_PADDING;
         self.framebuffer.fill(0);
     }
@@ -95,16 +159,15 @@ impl FrameBufferWriter {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
             c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
+                let new_xpos = self.x_pos + self.font.width;
                 if new_xpos >= self.width() {
                     self.newline();
                 }
-                let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
+                let new_ypos = self.y_pos + self.font.height.val() + BORDER_PADDING;
                 if new_ypos >= self.height() {
                     self.clear();
                 }
-                self.write_rendered_char(get_char_raster(c));
+                self.write_rendered_char(self.font.char_raster(c));
             }
         }
     }
@@ -120,19 +183,116 @@ impl FrameBufferWriter {
         self.x_pos += rendered_char.width() + LETTER_SPACING;
     }
 
-    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.stride + x;
+    /// Replaces the screen content with an error screen for the given report.
+    ///
+    /// The report is framed by red bars and optionally followed by a QR code that encodes
+    /// the error summary.
+    pub fn show_error_screen(&mut self, report: &ErrorReport, qr_code: bool) {
+        self.framebuffer.fill(0);
+        let line_height = self.line_height();
+        let bar_width = self.width().saturating_sub(2 * ERROR_SCREEN_PADDING);
+
+        self.fill_red(ERROR_SCREEN_PADDING, ERROR_SCREEN_PADDING, bar_width, 4);
+        self.x_pos = ERROR_SCREEN_PADDING;
+        self.y_pos = ERROR_SCREEN_PADDING + 12;
+        let _ = self.write_str(report.catalog.title);
+        self.y_pos += line_height + 12;
+        self.fill_red(ERROR_SCREEN_PADDING, self.y_pos - 8, bar_width, 4);
+
+        self.left_margin = ERROR_SCREEN_PADDING;
+        self.x_pos = ERROR_SCREEN_PADDING;
+        let _ = write!(self, "{report}");
+
+        if qr_code {
+            let mut summary = ScreenLine::new();
+            let _ = report.write_summary(&mut summary);
+            self.y_pos += line_height;
+            self.draw_qr_code(summary.as_str());
+        }
+    }
+
+    /// Draws a QR code encoding the given text below the current position.
+    fn draw_qr_code(&mut self, text: &str) {
+        let mut temp_buffer = [0; QR_CODE_MAX_VERSION.buffer_len()];
+        let mut out_buffer = [0; QR_CODE_MAX_VERSION.buffer_len()];
+        let Ok(qr) = QrCode::encode_text(
+            text,
+            &mut temp_buffer,
+            &mut out_buffer,
+            QrCodeEcc::Low,
+            Version::MIN,
+            QR_CODE_MAX_VERSION,
+            None,
+            true,
+        ) else {
+            return;
+        };
+
+        // include the quiet zone of four modules on each side
+        let modules = qr.size() as usize + 8;
+        let available = self
+            .height()
+            .saturating_sub(self.y_pos + ERROR_SCREEN_PADDING);
+        let scale = (available / modules).min(4);
+        if scale == 0 {
+            return;
+        }
+        let (x_start, y_start) = (ERROR_SCREEN_PADDING, self.y_pos);
+        for y in 0..modules {
+            for x in 0..modules {
+                let dark = qr.get_module(x as i32 - 4, y as i32 - 4);
+                let intensity = if dark { 0 } else { 0xff };
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        self.write_pixel(
+                            x_start + x * scale + dx,
+                            y_start + y * scale + dy,
+                            intensity,
+                        );
+                    }
+                }
+            }
+        }
+        self.y_pos += modules * scale;
+    }
+
+    /// Fills the given rectangle with red.
+    fn fill_red(&mut self, x: usize, y: usize, width: usize, height: usize) {
         let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
-            other => {
+            PixelFormat::Rgb => [0xcc, 0, 0, 0],
+            PixelFormat::Bgr => [0, 0, 0xcc, 0],
+            _ => [0xf, 0, 0, 0],
+        };
+        for y in y..(y + height).min(self.height()) {
+            for x in x..(x + width).min(self.width()) {
+                self.write_color(x, y, color);
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let scale = |channel: u8| (u16::from(channel) * u16::from(intensity) / 255) as u8;
+        let color = match (self.info.pixel_format, self.color) {
+            (PixelFormat::Rgb, Some([r, g, b])) => [scale(r), scale(g), scale(b), 0],
+            (PixelFormat::Bgr, Some([r, g, b])) => [scale(b), scale(g), scale(r), 0],
+            (PixelFormat::Rgb | PixelFormat::Bgr, _) if self.high_contrast => {
+                [intensity, intensity, intensity, 0]
+            }
+            (PixelFormat::Rgb, _) => [intensity, intensity, intensity / 2, 0],
+            (PixelFormat::Bgr, _) => [intensity / 2, intensity, intensity, 0],
+            (PixelFormat::U8, _) => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
+            (other, _) => {
                 // set a supported (but invalid) pixel format before panicking to avoid a double
                 // panic; it might not be readable though
                 self.info.pixel_format = PixelFormat::Rgb;
                 panic!("pixel format {:?} not supported in logger", other)
             }
         };
+        self.write_color(x, y, color);
+    }
+
+    fn write_color(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        let pixel_offset = y * self.info.stride + x;
         let bytes_per_pixel = self.info.bytes_per_pixel;
         let byte_offset = pixel_offset * bytes_per_pixel;
         self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
@@ -141,6 +301,42 @@ impl FrameBufferWriter {
     }
 }
 
+/// A fixed-capacity string buffer, used to encode the error summary without a heap.
+struct ScreenLine {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl ScreenLine {
+    fn new() -> Self {
+        Self {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // `write_str` only appends complete chars
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for ScreenLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > self.buf.len() {
+                // truncate overlong lines
+                break;
+            }
+            self.buf[self.len..][..encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
+
 unsafe impl Send for FrameBufferWriter {}
 unsafe impl Sync for FrameBufferWriter {}
 
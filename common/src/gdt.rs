@@ -1,13 +1,17 @@
 use x86_64::{
     instructions::segmentation::{self, Segment},
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector},
         paging::PhysFrame,
     },
     VirtAddr,
 };
 
-pub fn create_and_load(frame: PhysFrame) {
+/// Creates the GDT for the kernel in the given identity-mapped frame and loads it.
+///
+/// If `compat_code_segment` is set, the GDT also gets a 32-bit code segment, whose selector is
+/// returned.
+pub fn create_and_load(frame: PhysFrame, compat_code_segment: bool) -> Option<SegmentSelector> {
     let phys_addr = frame.start_address();
     log::info!("Creating GDT at {:?}", phys_addr);
     let virt_addr = VirtAddr::new(phys_addr.as_u64()); // utilize identity mapping
@@ -17,6 +21,11 @@ pub fn create_and_load(frame: PhysFrame) {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
     let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let compat_code_selector = compat_code_segment.then(|| {
+        gdt.add_entry(Descriptor::UserSegment(
+            DescriptorFlags::KERNEL_CODE32.bits(),
+        ))
+    });
     let gdt = unsafe {
         ptr.write(gdt);
         &*ptr
@@ -29,4 +38,5 @@ pub fn create_and_load(frame: PhysFrame) {
         segmentation::ES::set_reg(data_selector);
         segmentation::SS::set_reg(data_selector);
     }
+    compat_code_selector
 }
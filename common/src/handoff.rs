@@ -0,0 +1,356 @@
+//! The allocation that the boot info and the data it points to are placed in.
+//!
+//! The boot info, the memory map, and the strings and slices that the boot info refers to are
+//! placed in a single allocation that is mapped at the same address in the bootloader and the
+//! kernel address space. [`HandoffLayout`] computes where each part goes, [`Handoff`] splits
+//! the mapped allocation into disjoint references to the parts.
+//!
+//! ## Safety invariants
+//!
+//! - All references into the allocation are derived from the single base pointer that is
+//!   passed to [`Handoff::new`], never from integer addresses of the individual parts.
+//! - Each part is handed out exactly once, by [`Handoff::split`], which consumes the
+//!   `Handoff`. The parts don't overlap, so the returned references never alias.
+//! - Every part is aligned for its element type, because [`HandoffLayout`] is built with
+//!   [`Layout::extend`] and the base address is checked against the combined alignment.
+//! - Parts that the kernel sees as initialized data are written completely before they are
+//!   turned into shared references: bytes are zeroed on split, typed slices go through
+//!   [`init_slice`].
+//! - Once a part is referenced from the boot info, the bootloader only holds a shared
+//!   reference to it, so it isn't modified before the kernel takes over.
+
+use bootloader_api::{
+    info::{ArchiveFile, MemoryRegion, Module},
+    BootInfo,
+};
+use core::{
+    alloc::Layout,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+    slice, str,
+};
+
+/// The lengths of the variable-sized parts of the boot info allocation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandoffSizes {
+    /// The maximum number of memory regions in the memory map.
+    pub memory_regions: usize,
+    /// The length of the device path of the boot device in bytes.
+    pub device_path: usize,
+    /// The length of the copy of the in-memory boot log in bytes.
+    pub boot_log: usize,
+    /// The length of the kernel command line in bytes.
+    pub command_line: usize,
+    /// The number of files of the ramdisk archive.
+    pub archive_files: usize,
+    /// The combined length of the archive file names in bytes.
+    pub archive_names: usize,
+    /// The number of modules that were extracted from the ramdisk archive.
+    pub modules: usize,
+}
+
+/// The offsets of the parts of the boot info allocation.
+///
+/// The boot info is always at offset 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandoffLayout {
+    sizes: HandoffSizes,
+    layout: Layout,
+    memory_regions: usize,
+    device_path: usize,
+    boot_log: usize,
+    command_line: usize,
+    archive_files: usize,
+    archive_names: usize,
+    modules: usize,
+}
+
+impl HandoffLayout {
+    /// Computes the layout for the given sizes.
+    ///
+    /// Returns `None` if the allocation would be larger than `isize::MAX`.
+    pub fn new(sizes: HandoffSizes) -> Option<Self> {
+        let layout = Layout::new::<BootInfo>();
+        let (layout, memory_regions) = extend::<MemoryRegion>(layout, sizes.memory_regions)?;
+        let (layout, device_path) = extend::<u8>(layout, sizes.device_path)?;
+        let (layout, boot_log) = extend::<u8>(layout, sizes.boot_log)?;
+        let (layout, command_line) = extend::<u8>(layout, sizes.command_line)?;
+        let (layout, archive_files) = extend::<ArchiveFile>(layout, sizes.archive_files)?;
+        let (layout, archive_names) = extend::<u8>(layout, sizes.archive_names)?;
+        let (layout, modules) = extend::<Module>(layout, sizes.modules)?;
+        Some(Self {
+            sizes,
+            layout,
+            memory_regions,
+            device_path,
+            boot_log,
+            command_line,
+            archive_files,
+            archive_names,
+            modules,
+        })
+    }
+
+    /// The size of the whole allocation in bytes.
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The required alignment of the start of the allocation.
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// The sizes that the layout was computed for.
+    pub fn sizes(&self) -> HandoffSizes {
+        self.sizes
+    }
+}
+
+fn extend<T>(layout: Layout, len: usize) -> Option<(Layout, usize)> {
+    layout.extend(Layout::array::<T>(len).ok()?).ok()
+}
+
+/// Exclusive access to a mapped boot info allocation, see the [module docs](self).
+#[derive(Debug)]
+pub struct Handoff {
+    base: NonNull<u8>,
+    layout: HandoffLayout,
+}
+
+impl Handoff {
+    /// Takes ownership of the allocation at `base`.
+    ///
+    /// Panics if `base` is not aligned to [`HandoffLayout::align`].
+    ///
+    /// ## Safety
+    ///
+    /// `base` must point to `layout.size()` writable bytes that stay mapped at the same address
+    /// for the rest of the boot and in the kernel address space, and that nothing else accesses
+    /// until the kernel takes over.
+    pub unsafe fn new(base: NonNull<u8>, layout: HandoffLayout) -> Self {
+        assert_eq!(
+            base.as_ptr().align_offset(layout.align()),
+            0,
+            "boot info addr is not properly aligned"
+        );
+        Self { base, layout }
+    }
+
+    /// Splits the allocation into its parts.
+    pub fn split(self) -> HandoffParts {
+        let Self { base, layout } = self;
+        let sizes = layout.sizes;
+        // SAFETY: the offsets and lengths come from `layout`, so all parts are in bounds,
+        // aligned, and disjoint, and `new` requires exclusive access to the whole allocation
+        unsafe {
+            HandoffParts {
+                boot_info: &mut *part::<MaybeUninit<BootInfo>>(base, 0),
+                memory_regions: part_slice(base, layout.memory_regions, sizes.memory_regions),
+                device_path: zeroed_bytes(base, layout.device_path, sizes.device_path),
+                boot_log: zeroed_bytes(base, layout.boot_log, sizes.boot_log),
+                command_line: zeroed_bytes(base, layout.command_line, sizes.command_line),
+                archive_files: part_slice(base, layout.archive_files, sizes.archive_files),
+                archive_names: zeroed_bytes(base, layout.archive_names, sizes.archive_names),
+                modules: part_slice(base, layout.modules, sizes.modules),
+            }
+        }
+    }
+}
+
+/// The disjoint parts of a boot info allocation.
+#[derive(Debug)]
+pub struct HandoffParts {
+    /// The boot info itself, which is written last.
+    pub boot_info: &'static mut MaybeUninit<BootInfo>,
+    /// The backing storage of the memory map.
+    pub memory_regions: &'static mut [MaybeUninit<MemoryRegion>],
+    /// The copy of the device path of the boot device.
+    pub device_path: &'static mut [u8],
+    /// The copy of the in-memory boot log.
+    pub boot_log: &'static mut [u8],
+    /// The copy of the kernel command line.
+    pub command_line: &'static mut [u8],
+    /// The entries of the ramdisk archive.
+    pub archive_files: &'static mut [MaybeUninit<ArchiveFile>],
+    /// The names of the ramdisk archive entries.
+    pub archive_names: &'static mut [u8],
+    /// The modules that were extracted from the ramdisk archive.
+    pub modules: &'static mut [MaybeUninit<Module>],
+}
+
+unsafe fn part<T>(base: NonNull<u8>, offset: usize) -> *mut T {
+    unsafe { base.as_ptr().add(offset).cast() }
+}
+
+unsafe fn part_slice<T>(
+    base: NonNull<u8>,
+    offset: usize,
+    len: usize,
+) -> &'static mut [MaybeUninit<T>] {
+    unsafe { slice::from_raw_parts_mut(part(base, offset), len) }
+}
+
+unsafe fn zeroed_bytes(base: NonNull<u8>, offset: usize, len: usize) -> &'static mut [u8] {
+    unsafe {
+        let start = part::<u8>(base, offset);
+        ptr::write_bytes(start, 0, len);
+        slice::from_raw_parts_mut(start, len)
+    }
+}
+
+/// Copies `src` into `dst` and returns the copy.
+///
+/// Panics if the lengths differ. Consumes `dst`, so the copy can't be modified anymore.
+pub fn copy_str(dst: &'static mut [u8], src: &str) -> &'static str {
+    dst.copy_from_slice(src.as_bytes());
+    let dst: &'static [u8] = dst;
+    // SAFETY: `dst` is an unmodifiable copy of the valid UTF-8 in `src`
+    unsafe { str::from_utf8_unchecked(dst) }
+}
+
+/// Writes the values of `src` to `dst` and returns the initialized slice.
+///
+/// Panics if `src` doesn't yield exactly `dst.len()` values.
+pub fn init_slice<T>(
+    dst: &'static mut [MaybeUninit<T>],
+    src: impl IntoIterator<Item = T>,
+) -> &'static mut [T] {
+    let mut src = src.into_iter();
+    for slot in dst.iter_mut() {
+        slot.write(src.next().expect("too few values for the boot info slice"));
+    }
+    assert!(
+        src.next().is_none(),
+        "too many values for the boot info slice"
+    );
+    // SAFETY: every element was written above, and `MaybeUninit<T>` has the layout of `T`
+    unsafe { slice::from_raw_parts_mut(dst.as_mut_ptr().cast(), dst.len()) }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{alloc, vec::Vec};
+
+    fn sizes() -> HandoffSizes {
+        HandoffSizes {
+            memory_regions: 7,
+            device_path: 3,
+            boot_log: 5,
+            command_line: 1,
+            archive_files: 2,
+            archive_names: 9,
+            modules: 2,
+        }
+    }
+
+    fn parts(layout: &HandoffLayout) -> [(usize, usize, usize); 7] {
+        let sizes = layout.sizes;
+        [
+            (
+                layout.memory_regions,
+                sizes.memory_regions * core::mem::size_of::<MemoryRegion>(),
+                core::mem::align_of::<MemoryRegion>(),
+            ),
+            (layout.device_path, sizes.device_path, 1),
+            (layout.boot_log, sizes.boot_log, 1),
+            (layout.command_line, sizes.command_line, 1),
+            (
+                layout.archive_files,
+                sizes.archive_files * core::mem::size_of::<ArchiveFile>(),
+                core::mem::align_of::<ArchiveFile>(),
+            ),
+            (layout.archive_names, sizes.archive_names, 1),
+            (
+                layout.modules,
+                sizes.modules * core::mem::size_of::<Module>(),
+                core::mem::align_of::<Module>(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn layout_parts_are_disjoint_and_aligned() {
+        let layout = HandoffLayout::new(sizes()).unwrap();
+        let mut ranges = Vec::new();
+        ranges.push((0, core::mem::size_of::<BootInfo>()));
+        for (offset, len, align) in parts(&layout) {
+            assert_eq!(offset % align, 0);
+            ranges.push((offset, offset + len));
+        }
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 <= pair[1].0, "{pair:?} overlap");
+        }
+        assert!(ranges.last().unwrap().1 <= layout.size());
+        assert_eq!(layout.align(), core::mem::align_of::<BootInfo>());
+    }
+
+    #[test]
+    fn layout_overflow() {
+        let sizes = HandoffSizes {
+            memory_regions: usize::MAX / 2,
+            ..sizes()
+        };
+        assert_eq!(HandoffLayout::new(sizes), None);
+    }
+
+    #[test]
+    fn split_and_fill() {
+        let layout = HandoffLayout::new(sizes()).unwrap();
+        let alloc_layout = Layout::from_size_align(layout.size(), layout.align()).unwrap();
+        // leaked, like the mapped boot info
+        let base = NonNull::new(unsafe { alloc::alloc(alloc_layout) }).unwrap();
+        let parts = unsafe { Handoff::new(base, layout) }.split();
+
+        assert_eq!(parts.device_path, [0; 3]);
+        let command_line = copy_str(parts.command_line, "q");
+        let names = copy_str(parts.archive_names, "boot/init");
+        let modules: &[Module] = init_slice(
+            parts.modules,
+            [1, 2].map(|addr| Module {
+                name: names.into(),
+                addr,
+                len: 4,
+            }),
+        );
+        let boot_info = parts.boot_info.write(BootInfo::new(
+            init_slice(
+                parts.memory_regions,
+                (0..7).map(|start| MemoryRegion {
+                    start,
+                    end: start + 1,
+                    kind: bootloader_api::info::MemoryRegionKind::Usable,
+                }),
+            )
+            .into(),
+        ));
+
+        assert_eq!(ptr::addr_of!(*boot_info).cast::<u8>(), base.as_ptr());
+        assert_eq!(boot_info.memory_regions.len(), 7);
+        assert_eq!(boot_info.memory_regions[6].start, 6);
+        assert_eq!(command_line, "q");
+        assert_eq!(&*modules[1].name, "boot/init");
+        assert_eq!(modules[1].addr, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "too few values")]
+    fn init_slice_too_few() {
+        let dst = std::boxed::Box::leak(std::boxed::Box::new([MaybeUninit::<u64>::uninit(); 2]));
+        init_slice(dst, [1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not properly aligned")]
+    fn misaligned_base() {
+        let layout = HandoffLayout::new(sizes()).unwrap();
+        let buffer =
+            std::boxed::Box::leak(std::vec![0u64; layout.size() / 8 + 2].into_boxed_slice());
+        let base = NonNull::new(buffer.as_mut_ptr().cast::<u8>().wrapping_add(1)).unwrap();
+        unsafe { Handoff::new(base, layout) };
+    }
+}
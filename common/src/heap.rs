@@ -0,0 +1,90 @@
+use crate::error::{self, BootError};
+use bootloader_api::info::BootloaderHeap;
+use core::slice;
+use x86_64::align_up;
+
+/// A bump allocator for the temporary allocations of the bootloader.
+///
+/// Memory is handed out in order and is only reclaimed when the most recent allocation is
+/// freed, which is sufficient for the few allocations that the bootloader performs. The heap
+/// keeps track of its peak usage, which is reported to the kernel in the boot info.
+pub struct Heap {
+    start: u64,
+    size: u64,
+    next: u64,
+    peak: u64,
+    /// The number of allocations that were not freed yet.
+    allocations: usize,
+}
+
+impl Heap {
+    /// Creates a heap that allocates from the given memory.
+    pub fn new(memory: &'static mut [u8]) -> Self {
+        Self {
+            start: memory.as_mut_ptr() as u64,
+            size: memory.len() as u64,
+            next: 0,
+            peak: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Allocates `size` bytes with the given alignment.
+    ///
+    /// The `purpose` describes the allocation on the error screen. Halts with an
+    /// [out of memory](BootError::OutOfMemory) error if the heap is exhausted.
+    pub fn allocate(
+        &mut self,
+        size: usize,
+        align: usize,
+        purpose: &'static str,
+    ) -> &'static mut [u8] {
+        match self.try_allocate(size, align) {
+            Some(allocation) => allocation,
+            None => error::fail_with_details(
+                BootError::OutOfMemory(purpose),
+                &format_args!(
+                    "bootloader heap exhausted while allocating {size} bytes (alignment {align}): \
+                    {} of {} bytes in use by {} allocations, peak usage {} bytes; \
+                    increase the `bootloader_heap_size` config option",
+                    self.next, self.size, self.allocations, self.peak
+                ),
+            ),
+        }
+    }
+
+    /// Allocates `size` bytes with the given alignment, or returns `None` if the heap is
+    /// exhausted.
+    pub fn try_allocate(&mut self, size: usize, align: usize) -> Option<&'static mut [u8]> {
+        let offset = align_up(self.start + self.next, align as u64) - self.start;
+        let end = offset.checked_add(size as u64)?;
+        if end > self.size {
+            return None;
+        }
+
+        self.next = end;
+        self.peak = self.peak.max(end);
+        self.allocations += 1;
+        Some(unsafe { slice::from_raw_parts_mut((self.start + offset) as *mut u8, size) })
+    }
+
+    /// Frees the given allocation.
+    ///
+    /// The memory is only reclaimed if it is the most recent allocation.
+    pub fn deallocate(&mut self, allocation: &'static mut [u8]) {
+        let offset = allocation.as_ptr() as u64 - self.start;
+        if offset + allocation.len() as u64 == self.next {
+            self.next = offset;
+        }
+        self.allocations -= 1;
+    }
+
+    /// Returns the location, size, and peak usage of the heap.
+    pub fn usage(&self) -> BootloaderHeap {
+        BootloaderHeap {
+            size: self.size,
+            peak_usage: self.peak,
+            start: self.start,
+        }
+    }
+}
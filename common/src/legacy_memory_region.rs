@@ -1,7 +1,7 @@
-use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
-use core::mem::MaybeUninit;
+use bootloader_api::info::{FrameExtents, MemoryRegion, MemoryRegionKind};
+use core::{cmp, mem::MaybeUninit};
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB, Size4KiB},
     PhysAddr,
 };
 
@@ -22,12 +22,59 @@ pub trait LegacyMemoryRegion: Copy + core::fmt::Debug {
     fn usable_after_bootloader_exit(&self) -> bool;
 }
 
+/// The maximum number of allocated frame ranges whose purpose is tracked, see
+/// [`LegacyFrameAllocator::set_purpose`].
+pub const MAX_PURPOSE_RANGES: usize = 16;
+
+/// A frame allocator that allocates the frames of page tables separately, so that they are
+/// not reported with the [purpose](LegacyFrameAllocator::set_purpose) of the memory that is
+/// mapped through them.
+pub trait PageTableAllocator: FrameAllocator<Size4KiB> {
+    /// Allocates a frame for a page table.
+    fn allocate_page_table(&mut self) -> Option<PhysFrame>;
+
+    /// Returns an allocator for the page tables that `Mapper` calls create.
+    fn page_tables(&mut self) -> PageTables<'_, Self> {
+        PageTables(self)
+    }
+}
+
+/// Allocates page tables through [`PageTableAllocator::allocate_page_table`].
+pub struct PageTables<'a, A: ?Sized>(&'a mut A);
+
+unsafe impl<A> FrameAllocator<Size4KiB> for PageTables<'_, A>
+where
+    A: PageTableAllocator + ?Sized,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.0.allocate_page_table()
+    }
+}
+
 /// A physical frame allocator based on a BIOS or UEFI provided memory map.
 pub struct LegacyFrameAllocator<I, D> {
     original: I,
     memory_map: I,
     current_descriptor: Option<D>,
+    first_frame: PhysFrame,
     next_frame: PhysFrame,
+    /// Frames below this frame are only allocated once the memory above is exhausted.
+    min_frame: PhysFrame,
+    /// The frames allocated at or above `min_frame`, set when falling back to lower memory.
+    high_allocations: Option<(PhysFrame, PhysFrame)>,
+    /// A contiguous range of frames that was allocated outside of the regular allocation
+    /// order, which is skipped by later allocations.
+    reserved: Option<(PhysFrame, PhysFrame)>,
+    /// The number of allocated 4KiB frames, used for out-of-memory diagnostics.
+    allocated_frames: u64,
+    /// The number of allocated 2MiB frames, used for out-of-memory diagnostics.
+    allocated_huge_frames: u64,
+    /// The memory region kind of the frames that are allocated next, see
+    /// [`set_purpose`](Self::set_purpose).
+    purpose: Option<MemoryRegionKind>,
+    /// The allocated frame ranges with a purpose, as regions of the final memory map.
+    purpose_ranges: [MemoryRegion; MAX_PURPOSE_RANGES],
+    purpose_range_count: usize,
 }
 
 impl<I, D> LegacyFrameAllocator<I, D>
@@ -53,30 +100,269 @@ where
             original: memory_map.clone(),
             memory_map,
             current_descriptor: None,
+            first_frame: frame,
             next_frame: frame,
+            min_frame: frame,
+            high_allocations: None,
+            reserved: None,
+            allocated_frames: 0,
+            allocated_huge_frames: 0,
+            purpose: None,
+            purpose_ranges: [MemoryRegion::empty(); MAX_PURPOSE_RANGES],
+            purpose_range_count: 0,
+        }
+    }
+
+    /// Reports the frames that are allocated from now on as the given kind in the memory map,
+    /// or as [`MemoryRegionKind::Bootloader`] for `None`.
+    ///
+    /// Only [`MAX_PURPOSE_RANGES`] contiguous ranges are tracked, further frames are reported
+    /// as bootloader memory. Frames from [`PageTableAllocator::allocate_page_table`] never
+    /// get a purpose, so page tables must be mapped through [`PageTableAllocator::page_tables`]
+    /// while a purpose is set.
+    pub fn set_purpose(&mut self, purpose: Option<MemoryRegionKind>) {
+        self.purpose = purpose;
+    }
+
+    /// Records the purpose of the given newly allocated frames.
+    fn record_purpose(&mut self, start: PhysAddr, len: u64) {
+        let kind = match self.purpose {
+            Some(kind) => kind,
+            None => return,
+        };
+        let (start, end) = (start.as_u64(), start.as_u64() + len);
+        if let Some(last) = self.purpose_ranges[..self.purpose_range_count].last_mut() {
+            if last.kind == kind && last.end == start {
+                last.end = end;
+                return;
+            }
         }
+        if let Some(range) = self.purpose_ranges.get_mut(self.purpose_range_count) {
+            *range = MemoryRegion { start, end, kind };
+            self.purpose_range_count += 1;
+        }
+    }
+
+    /// Makes the allocator prefer the frames at or above the given address.
+    ///
+    /// Lower frames are only allocated once the memory above `addr` is exhausted. This keeps
+    /// memory that is reachable by legacy DMA engines free for the kernel. Must be called
+    /// before the first allocation.
+    pub fn prefer_frames_above(&mut self, addr: PhysAddr) {
+        assert!(
+            self.next_frame == self.first_frame,
+            "frames were already allocated"
+        );
+        let frame = PhysFrame::containing_address(addr.align_up(Size4KiB::SIZE));
+        self.min_frame = cmp::max(self.first_frame, frame);
+        self.next_frame = self.min_frame;
+    }
+
+    /// Allocates `count` physically contiguous frames that end at or below `limit`.
+    ///
+    /// The frames are taken from the highest free memory below `limit`, independent of the
+    /// regular allocation order, so that memory that was skipped because of
+    /// [`prefer_frames_above`](Self::prefer_frames_above) can be used. Only a single such
+    /// allocation is supported. Returns `None` if there is no large enough free range below
+    /// `limit`.
+    pub fn allocate_contiguous_below(&mut self, count: u64, limit: PhysAddr) -> Option<PhysFrame> {
+        assert!(
+            self.reserved.is_none(),
+            "only one contiguous allocation is supported"
+        );
+        let len = count * Size4KiB::SIZE;
+        let used_ranges = self.used_ranges();
+        let mut best: Option<PhysAddr> = None;
+        for descriptor in self.original.clone() {
+            if descriptor.kind() != MemoryRegionKind::Usable {
+                continue;
+            }
+            let start = cmp::max(descriptor.start(), self.first_frame.start_address());
+            let mut gap_end = cmp::min(descriptor.start() + descriptor.len(), limit);
+            // check the gaps between the used ranges, starting at the top
+            for (used_start, used_end) in used_ranges
+                .into_iter()
+                .rev()
+                .chain([(PhysAddr::zero(), start)])
+            {
+                let gap_start = cmp::max(used_end, start).align_up(Size4KiB::SIZE);
+                if gap_start < gap_end && gap_end - gap_start >= len {
+                    let candidate = (gap_end - len).align_down(Size4KiB::SIZE);
+                    if candidate >= gap_start && best.map_or(true, |best| candidate > best) {
+                        best = Some(candidate);
+                    }
+                }
+                gap_end = cmp::min(gap_end, used_start);
+            }
+        }
+
+        let start = PhysFrame::containing_address(best?);
+        self.reserved = Some((start, start + count));
+        self.allocated_frames += count;
+        self.record_purpose(start.start_address(), len);
+        Some(start)
+    }
+
+    /// Returns the first address at or after `start` that is aligned to `align` and from
+    /// which `len` bytes don't overlap the reserved range.
+    fn skip_reserved(&self, start: PhysAddr, len: u64, align: u64) -> PhysAddr {
+        match self.reserved {
+            Some((reserved_start, reserved_end))
+                if start < reserved_end.start_address()
+                    && reserved_start.start_address() < start + len =>
+            {
+                reserved_end.start_address().align_up(align)
+            }
+            _ => start,
+        }
+    }
+
+    /// Restarts the allocation at `first_frame` once the memory above `min_frame` is
+    /// exhausted.
+    ///
+    /// Returns `false` if there is no lower memory to fall back to.
+    fn fall_back_to_low_memory(&mut self) -> bool {
+        if self.high_allocations.is_some() || self.min_frame == self.first_frame {
+            return false;
+        }
+        log::warn!(
+            "No memory left above {:#x}, falling back to lower memory",
+            self.min_frame.start_address()
+        );
+        self.high_allocations = Some((self.min_frame, self.next_frame));
+        self.memory_map = self.original.clone();
+        self.current_descriptor = None;
+        self.next_frame = self.first_frame;
+        true
+    }
+
+    /// Limits the given end address of a descriptor to `min_frame` after falling back to
+    /// lower memory.
+    fn allocation_end(&self, end_addr: PhysAddr) -> PhysAddr {
+        match self.high_allocations {
+            Some(_) => cmp::min(end_addr, self.min_frame.start_address()),
+            None => end_addr,
+        }
+    }
+
+    /// Returns the physical address ranges that are used by the bootloader as `(start, end)`
+    /// pairs, sorted by address.
+    ///
+    /// Usable memory below `first_frame` is considered used, since it contains data that was
+    /// placed there before the allocator was created. The ranges might overlap and empty
+    /// ranges are possible.
+    fn used_ranges(&self) -> [(PhysAddr, PhysAddr); 3] {
+        let next_free = self.next_frame.start_address();
+        let reserved = self
+            .reserved
+            .map(|(start, end)| (start.start_address(), end.start_address()))
+            .unwrap_or((PhysAddr::zero(), PhysAddr::zero()));
+        let mut ranges = match self.high_allocations {
+            Some((start, end)) => [
+                (PhysAddr::zero(), next_free),
+                (start.start_address(), end.start_address()),
+                reserved,
+            ],
+            None => [
+                (PhysAddr::zero(), self.first_frame.start_address()),
+                (self.min_frame.start_address(), next_free),
+                reserved,
+            ],
+        };
+        ranges.sort_unstable_by_key(|&(start, _)| start);
+        ranges
     }
 
     fn allocate_frame_from_descriptor(&mut self, descriptor: D) -> Option<PhysFrame> {
         let start_addr = descriptor.start();
         let start_frame = PhysFrame::containing_address(start_addr);
-        let end_addr = start_addr + descriptor.len();
+        let end_addr = self.allocation_end(start_addr + descriptor.len());
+        if end_addr <= start_addr {
+            return None;
+        }
         let end_frame = PhysFrame::containing_address(end_addr - 1u64);
 
         // increase self.next_frame to start_frame if smaller
         if self.next_frame < start_frame {
             self.next_frame = start_frame;
         }
+        self.next_frame = PhysFrame::containing_address(self.skip_reserved(
+            self.next_frame.start_address(),
+            Size4KiB::SIZE,
+            Size4KiB::SIZE,
+        ));
 
         if self.next_frame <= end_frame {
             let ret = self.next_frame;
             self.next_frame += 1;
+            self.allocated_frames += 1;
+            self.record_purpose(ret.start_address(), Size4KiB::SIZE);
             Some(ret)
         } else {
             None
         }
     }
 
+    fn allocate_huge_frame_from_descriptor(
+        &mut self,
+        descriptor: D,
+    ) -> Option<PhysFrame<Size2MiB>> {
+        let start_addr =
+            cmp::max(descriptor.start(), self.next_frame.start_address()).align_up(Size2MiB::SIZE);
+        let start_addr = self.skip_reserved(start_addr, Size2MiB::SIZE, Size2MiB::SIZE);
+        let end_addr = self.allocation_end(descriptor.start() + descriptor.len());
+
+        if start_addr + Size2MiB::SIZE <= end_addr {
+            // the skipped frames before `start_addr` are reported as bootloader memory
+            self.next_frame = PhysFrame::containing_address(start_addr + Size2MiB::SIZE);
+            self.allocated_huge_frames += 1;
+            self.record_purpose(start_addr, Size2MiB::SIZE);
+            Some(PhysFrame::containing_address(start_addr))
+        } else {
+            None
+        }
+    }
+
+    /// Logs the memory map and the allocation statistics after a failed allocation.
+    ///
+    /// The caller is expected to report what was being allocated, typically by panicking.
+    fn report_out_of_memory(&self, frame_size: u64) {
+        log::error!(
+            "Out of physical memory while allocating a {}KiB frame \
+            ({} 4KiB frames and {} 2MiB frames allocated so far)",
+            frame_size / 1024,
+            self.allocated_frames,
+            self.allocated_huge_frames,
+        );
+        log::error!(
+            "Allocations started at {:#x}, next free frame is {:#x}",
+            self.min_frame.start_address(),
+            self.next_frame.start_address(),
+        );
+        log::error!("Memory map:");
+        for descriptor in self.original.clone() {
+            let start = descriptor.start();
+            let end = start + descriptor.len();
+            let usage = if descriptor.kind() != MemoryRegionKind::Usable {
+                ""
+            } else if self
+                .allocated_ranges()
+                .any(|(used_start, used_end)| start < used_end && used_start < end)
+            {
+                " (used by bootloader)"
+            } else {
+                " (unused)"
+            };
+            log::error!(
+                "  {:#012x}..{:#012x} {:?}{}",
+                start,
+                end,
+                descriptor.kind(),
+                usage
+            );
+        }
+    }
+
     /// Returns the number of memory regions in the underlying memory map.
     ///
     /// The function always returns the same value, i.e. the length doesn't
@@ -90,6 +376,16 @@ where
         self.len() == 0
     }
 
+    /// Returns the regions of the underlying memory map.
+    pub fn memory_map(&self) -> I {
+        self.original.clone()
+    }
+
+    /// Returns the first frame that the allocator may allocate.
+    pub fn first_frame(&self) -> PhysFrame {
+        self.first_frame
+    }
+
     /// Returns the largest detected physical memory address.
     ///
     /// Useful for creating a mapping for all physical memory.
@@ -101,114 +397,201 @@ where
             .unwrap()
     }
 
+    /// Returns the physical address ranges that frames were allocated from so far.
+    ///
+    /// The ranges are returned as `(start, end)` pairs with an exclusive end address.
+    pub fn allocated_ranges(&self) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + '_ {
+        let first_frame = self.first_frame.start_address();
+        let used_ranges = self.used_ranges();
+        self.original
+            .clone()
+            .filter(|descriptor| descriptor.kind() == MemoryRegionKind::Usable)
+            .flat_map(move |descriptor| {
+                used_ranges.into_iter().map(move |(used_start, used_end)| {
+                    let start = cmp::max(descriptor.start(), cmp::max(used_start, first_frame));
+                    let end = cmp::min(descriptor.start() + descriptor.len(), used_end);
+                    (start, end)
+                })
+            })
+            .filter(|(start, end)| start < end)
+    }
+
     /// Converts this type to a boot info memory map.
     ///
     /// The memory map is placed in the given `regions` slice. The length of the given slice
     /// must be at least the value returned by [`len`] pluse 1.
     ///
+    /// The parts of the used memory that are contained in the `reclaimable` extents are
+    /// reported as [`MemoryRegionKind::BootloaderReclaimable`]. Afterwards, the frames that
+    /// were allocated with a [purpose](Self::set_purpose) and the given `overlays`, e.g. the
+    /// kernel executable, are reported with their kind where they overlap usable or bootloader
+    /// memory. The length of `regions` must account for two more regions per overlay and
+    /// per [`MAX_PURPOSE_RANGES`], and for one more region per firmware region that lies
+    /// inside a usable region.
+    ///
+    /// The returned memory map is normalized, see [`normalize`].
+    ///
     /// The return slice is a subslice of `regions`, shortened to the actual number of regions.
-    pub fn construct_memory_map(
+    pub fn construct_memory_map<'a>(
         self,
-        regions: &mut [MaybeUninit<MemoryRegion>],
-        kernel_slice_start: u64,
-        kernel_slice_len: u64,
-    ) -> &mut [MemoryRegion] {
+        regions: &'a mut [MaybeUninit<MemoryRegion>],
+        reclaimable: &FrameExtents,
+        overlays: &[MemoryRegion],
+    ) -> &'a mut [MemoryRegion] {
         let mut next_index = 0;
+        let used_ranges = self.used_ranges();
 
         for descriptor in self.original {
-            let mut start = descriptor.start();
+            let start = descriptor.start();
             let end = start + descriptor.len();
-            let next_free = self.next_frame.start_address();
-            let kind = match descriptor.kind() {
+            match descriptor.kind() {
                 MemoryRegionKind::Usable => {
-                    if end <= next_free {
-                        MemoryRegionKind::Bootloader
-                    } else if descriptor.start() >= next_free {
-                        MemoryRegionKind::Usable
-                    } else {
-                        // part of the region is used -> add it separately
+                    // split off the parts that are used by the bootloader
+                    let mut usable_start = start;
+                    for (used_start, used_end) in used_ranges {
+                        let used_start = cmp::max(used_start, usable_start);
+                        let used_end = cmp::min(used_end, end);
+                        if used_start >= used_end {
+                            continue;
+                        }
+                        let usable_region = MemoryRegion {
+                            start: usable_start.as_u64(),
+                            end: used_start.as_u64(),
+                            kind: MemoryRegionKind::Usable,
+                        };
+                        Self::add_region(usable_region, regions, &mut next_index);
                         let used_region = MemoryRegion {
-                            start: descriptor.start().as_u64(),
-                            end: next_free.as_u64(),
+                            start: used_start.as_u64(),
+                            end: used_end.as_u64(),
                             kind: MemoryRegionKind::Bootloader,
                         };
-                        Self::add_region(used_region, regions, &mut next_index);
-
-                        // add unused part normally
-                        start = next_free;
-                        MemoryRegionKind::Usable
+                        Self::add_used_region(used_region, reclaimable, regions, &mut next_index);
+                        usable_start = used_end;
                     }
+                    let usable_region = MemoryRegion {
+                        start: usable_start.as_u64(),
+                        end: end.as_u64(),
+                        kind: MemoryRegionKind::Usable,
+                    };
+                    Self::add_region(usable_region, regions, &mut next_index);
                 }
                 _ if descriptor.usable_after_bootloader_exit() => {
                     // Region was not usable before, but it will be as soon as
                     // the bootloader passes control to the kernel. We don't
-                    // need to check against `next_free` because the
+                    // need to check against the used ranges because the
                     // LegacyFrameAllocator only allocates memory from usable
                     // descriptors.
-                    MemoryRegionKind::Usable
+                    let region = MemoryRegion {
+                        start: start.as_u64(),
+                        end: end.as_u64(),
+                        kind: MemoryRegionKind::Usable,
+                    };
+                    Self::add_region(region, regions, &mut next_index);
                 }
-                other => other,
-            };
+                other => {
+                    let region = MemoryRegion {
+                        start: start.as_u64(),
+                        end: end.as_u64(),
+                        kind: other,
+                    };
+                    Self::add_region(region, regions, &mut next_index);
+                }
+            }
+        }
 
-            let region = MemoryRegion {
-                start: start.as_u64(),
-                end: end.as_u64(),
-                kind,
-            };
+        let purpose_ranges = &self.purpose_ranges[..self.purpose_range_count];
+        for overlay in purpose_ranges.iter().chain(overlays) {
+            Self::add_overlay(*overlay, regions, &mut next_index);
+        }
 
-            // check if region overlaps with kernel
-            let kernel_slice_end = kernel_slice_start + kernel_slice_len;
-            if region.kind == MemoryRegionKind::Usable
-                && kernel_slice_start < region.end
-                && kernel_slice_end >= region.start
+        // the remaining entries are spare room for `normalize`
+        for region in &mut regions[next_index..] {
+            region.write(MemoryRegion::empty());
+        }
+        let initialized: &mut [MemoryRegion] = unsafe {
+            // inlined variant of: `MaybeUninit::slice_assume_init_mut(regions)`
+            // TODO: undo inlining when `slice_assume_init_mut` becomes stable
+            &mut *(regions as *mut [_] as *mut [_])
+        };
+        let len = normalize(initialized, next_index);
+        &mut initialized[..len]
+    }
+
+    /// Reports the parts of the usable and bootloader regions that overlap with the given
+    /// overlay as the kind of the overlay.
+    fn add_overlay(
+        overlay: MemoryRegion,
+        regions: &mut [MaybeUninit<MemoryRegion>],
+        next_index: &mut usize,
+    ) {
+        for index in 0..*next_index {
+            // all regions below `next_index` are initialized
+            let region = unsafe { regions[index].assume_init_mut() };
+            let start = cmp::max(region.start, overlay.start);
+            let end = cmp::min(region.end, overlay.end);
+            if start >= end
+                || !matches!(
+                    region.kind,
+                    MemoryRegionKind::Usable | MemoryRegionKind::Bootloader
+                )
             {
-                // region overlaps with kernel -> we might need to split it
-
-                // ensure that the kernel allocation does not span multiple regions
-                assert!(
-                    kernel_slice_start >= region.start,
-                    "region overlaps with kernel, but kernel begins before region \
-                    (kernel_slice_start: {kernel_slice_start:#x}, region_start: {:#x})",
-                    region.start
-                );
-                assert!(
-                    kernel_slice_end <= region.end,
-                    "region overlaps with kernel, but region ends before kernel \
-                    (kernel_slice_end: {kernel_slice_end:#x}, region_end: {:#x})",
-                    region.end,
-                );
-
-                // split the region into three parts
-                let before_kernel = MemoryRegion {
-                    end: kernel_slice_start,
-                    ..region
-                };
-                let kernel = MemoryRegion {
-                    start: kernel_slice_start,
-                    end: kernel_slice_end,
-                    kind: MemoryRegionKind::Bootloader,
-                };
-                let after_kernel = MemoryRegion {
-                    start: kernel_slice_end,
-                    ..region
-                };
-
-                // add the three regions (empty regions are ignored in `add_region`)
-                Self::add_region(before_kernel, regions, &mut next_index);
-                Self::add_region(kernel, regions, &mut next_index);
-                Self::add_region(after_kernel, regions, &mut next_index);
-            } else {
-                // add the region normally
-                Self::add_region(region, regions, &mut next_index);
+                continue;
             }
+            let original = *region;
+            *region = MemoryRegion {
+                start,
+                end,
+                kind: overlay.kind,
+            };
+            Self::add_region(
+                MemoryRegion {
+                    end: start,
+                    ..original
+                },
+                regions,
+                next_index,
+            );
+            Self::add_region(
+                MemoryRegion {
+                    start: end,
+                    ..original
+                },
+                regions,
+                next_index,
+            );
         }
+    }
 
-        let initialized = &mut regions[..next_index];
-        unsafe {
-            // inlined variant of: `MaybeUninit::slice_assume_init_mut(initialized)`
-            // TODO: undo inlining when `slice_assume_init_mut` becomes stable
-            &mut *(initialized as *mut [_] as *mut [_])
+    /// Adds a region that is used by the bootloader, splitting off the parts that are
+    /// contained in the `reclaimable` extents.
+    fn add_used_region(
+        region: MemoryRegion,
+        reclaimable: &FrameExtents,
+        regions: &mut [MaybeUninit<MemoryRegion>],
+        next_index: &mut usize,
+    ) {
+        let mut start = region.start;
+        for extent in reclaimable.iter() {
+            let extent_start = cmp::max(extent.start, start);
+            let extent_end = cmp::min(extent.end(), region.end);
+            if extent_start >= extent_end {
+                continue;
+            }
+            let used = MemoryRegion {
+                start,
+                end: extent_start,
+                ..region
+            };
+            let reclaimable = MemoryRegion {
+                start: extent_start,
+                end: extent_end,
+                kind: MemoryRegionKind::BootloaderReclaimable,
+            };
+            Self::add_region(used, regions, next_index);
+            Self::add_region(reclaimable, regions, next_index);
+            start = extent_end;
         }
+        Self::add_region(MemoryRegion { start, ..region }, regions, next_index);
     }
 
     fn add_region(
@@ -231,6 +614,127 @@ where
     }
 }
 
+/// Normalizes the first `len` regions of the given memory map in place and returns their
+/// new length.
+///
+/// Afterwards, the regions are sorted by start address, don't overlap, start and end at page
+/// boundaries, are not empty, and adjacent regions are of different kinds. To keep the kernel
+/// away from memory it must not touch, usable regions are shrunk to whole pages and all other
+/// regions are grown to whole pages. Where regions overlap, the non-usable region wins, or the
+/// region with the lower start address if neither is usable. A usable region that contains a
+/// non-usable region is split around it, which takes one of the spare entries after `len`.
+/// Without spare entries, the usable memory after the non-usable region is dropped.
+fn normalize(regions: &mut [MemoryRegion], len: usize) -> usize {
+    let page_size = Size4KiB::SIZE;
+    for region in regions[..len].iter_mut() {
+        if region.kind == MemoryRegionKind::Usable {
+            region.start = region.start.saturating_add(page_size - 1) & !(page_size - 1);
+            region.end &= !(page_size - 1);
+        } else {
+            region.start &= !(page_size - 1);
+            region.end = region.end.saturating_add(page_size - 1) & !(page_size - 1);
+        }
+    }
+    // resolve the overlaps between the non-usable regions and between the usable regions
+    // separately, the non-usable regions first
+    regions[..len].sort_unstable_by_key(|region| {
+        (
+            region.kind == MemoryRegionKind::Usable,
+            region.start,
+            region.end,
+        )
+    });
+    let reserved_len = regions[..len]
+        .iter()
+        .take_while(|region| region.kind != MemoryRegionKind::Usable)
+        .count();
+    let usable_start = reserved_len;
+    let usable_len = merge_sorted(&mut regions[usable_start..len]);
+    let reserved_len = merge_sorted(&mut regions[..usable_start]);
+    regions.copy_within(usable_start..usable_start + usable_len, reserved_len);
+    let len = reserved_len + usable_len;
+
+    // cut the non-usable regions out of the usable ones, writing the remaining parts of the
+    // usable regions backwards from the end of the slice
+    let mut write = regions.len();
+    for index in (reserved_len..len).rev() {
+        let usable = regions[index];
+        let mut end = usable.end;
+        for reserved_index in (0..reserved_len).rev() {
+            let reserved = regions[reserved_index];
+            if reserved.end <= usable.start || reserved.start >= end {
+                continue;
+            }
+            if reserved.end < end {
+                if write - 1 <= index {
+                    log::warn!(
+                        "No space to split the memory map, dropping usable memory at {:#x}",
+                        reserved.end
+                    );
+                } else {
+                    write -= 1;
+                    regions[write] = MemoryRegion {
+                        start: reserved.end,
+                        end,
+                        kind: MemoryRegionKind::Usable,
+                    };
+                }
+            }
+            end = reserved.start;
+        }
+        if usable.start < end {
+            // the entry of `usable` itself is free at this point
+            write -= 1;
+            regions[write] = MemoryRegion { end, ..usable };
+        }
+    }
+    let split_len = regions.len() - write;
+    regions.copy_within(write.., reserved_len);
+    let len = reserved_len + split_len;
+
+    // the usable and non-usable regions don't overlap anymore
+    regions[..len].sort_unstable_by_key(|region| region.start);
+    len
+}
+
+/// Merges the overlapping and adjacent regions of the same kind of the given sorted regions
+/// and returns the new length.
+///
+/// Where regions of different kinds overlap, the region with the lower start address wins.
+fn merge_sorted(regions: &mut [MemoryRegion]) -> usize {
+    let mut len = 0;
+    for index in 0..regions.len() {
+        let mut region = regions[index];
+        if len > 0 && region.start <= regions[len - 1].end {
+            let previous = &mut regions[len - 1];
+            if previous.kind == region.kind {
+                previous.end = cmp::max(previous.end, region.end);
+                continue;
+            }
+            region.start = cmp::max(region.start, previous.end);
+        }
+        if region.start >= region.end {
+            continue;
+        }
+        regions[len] = region;
+        len += 1;
+    }
+    len
+}
+
+impl<I, D> PageTableAllocator for LegacyFrameAllocator<I, D>
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    I::Item: LegacyMemoryRegion,
+{
+    fn allocate_page_table(&mut self) -> Option<PhysFrame> {
+        let purpose = self.purpose.take();
+        let frame = self.allocate_frame();
+        self.purpose = purpose;
+        frame
+    }
+}
+
 unsafe impl<I, D> FrameAllocator<Size4KiB> for LegacyFrameAllocator<I, D>
 where
     I: ExactSizeIterator<Item = D> + Clone,
@@ -257,6 +761,230 @@ where
             }
         }
 
+        if self.fall_back_to_low_memory() {
+            return self.allocate_frame();
+        }
+        self.report_out_of_memory(Size4KiB::SIZE);
         None
     }
 }
+
+/// Allocates 2MiB-aligned frames, used for kernel segments with a huge page alignment.
+///
+/// Since the allocator only moves forward, usable memory that is skipped to satisfy the
+/// alignment is reported as bootloader memory in the final memory map.
+unsafe impl<I, D> FrameAllocator<Size2MiB> for LegacyFrameAllocator<I, D>
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    I::Item: LegacyMemoryRegion,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        if let Some(current_descriptor) = self.current_descriptor {
+            match self.allocate_huge_frame_from_descriptor(current_descriptor) {
+                Some(frame) => return Some(frame),
+                None => {
+                    self.current_descriptor = None;
+                }
+            }
+        }
+
+        // find next suitable descriptor
+        while let Some(descriptor) = self.memory_map.next() {
+            if descriptor.kind() != MemoryRegionKind::Usable {
+                continue;
+            }
+            if let Some(frame) = self.allocate_huge_frame_from_descriptor(descriptor) {
+                self.current_descriptor = Some(descriptor);
+                return Some(frame);
+            }
+        }
+
+        if self.fall_back_to_low_memory() {
+            return self.allocate_frame();
+        }
+        self.report_out_of_memory(Size2MiB::SIZE);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USABLE: MemoryRegionKind = MemoryRegionKind::Usable;
+    const RESERVED: MemoryRegionKind = MemoryRegionKind::UnknownBios(2);
+    const ACPI: MemoryRegionKind = MemoryRegionKind::UnknownBios(3);
+
+    fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion { start, end, kind }
+    }
+
+    /// Normalizes the given regions with `spare` additional entries.
+    fn normalized<const N: usize>(input: [MemoryRegion; N], spare: usize) -> [MemoryRegion; 8] {
+        let mut regions = [MemoryRegion::empty(); 8];
+        regions[..N].copy_from_slice(&input);
+        let len = normalize(&mut regions[..N + spare], N);
+        for region in &mut regions[len..] {
+            *region = MemoryRegion::empty();
+        }
+        regions
+    }
+
+    fn expected(output: &[MemoryRegion]) -> [MemoryRegion; 8] {
+        let mut regions = [MemoryRegion::empty(); 8];
+        regions[..output.len()].copy_from_slice(output);
+        regions
+    }
+
+    #[test]
+    fn sorts_by_start() {
+        let input = [
+            region(0x3000, 0x4000, RESERVED),
+            region(0x1000, 0x2000, USABLE),
+            region(0x5000, 0x6000, ACPI),
+        ];
+        assert_eq!(
+            normalized(input, 0),
+            expected(&[
+                region(0x1000, 0x2000, USABLE),
+                region(0x3000, 0x4000, RESERVED),
+                region(0x5000, 0x6000, ACPI),
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_neighbours_of_the_same_kind() {
+        let input = [
+            region(0x1000, 0x2000, USABLE),
+            region(0x2000, 0x4000, USABLE),
+            region(0x3000, 0x5000, USABLE),
+            region(0x5000, 0x6000, RESERVED),
+            region(0x6000, 0x7000, RESERVED),
+            region(0x7000, 0x8000, ACPI),
+        ];
+        assert_eq!(
+            normalized(input, 0),
+            expected(&[
+                region(0x1000, 0x5000, USABLE),
+                region(0x5000, 0x7000, RESERVED),
+                region(0x7000, 0x8000, ACPI),
+            ])
+        );
+    }
+
+    #[test]
+    fn non_usable_regions_win_overlaps() {
+        let input = [
+            region(0x1000, 0x4000, USABLE),
+            region(0x3000, 0x6000, RESERVED),
+            region(0x5000, 0x8000, ACPI),
+        ];
+        assert_eq!(
+            normalized(input, 0),
+            expected(&[
+                region(0x1000, 0x3000, USABLE),
+                region(0x3000, 0x6000, RESERVED),
+                region(0x6000, 0x8000, ACPI),
+            ])
+        );
+    }
+
+    #[test]
+    fn splits_usable_regions_around_reserved_ones() {
+        let input = [
+            region(0x1000, 0x10_0000, USABLE),
+            region(0x9000, 0xa000, RESERVED),
+            region(0xc000, 0xd000, ACPI),
+        ];
+        assert_eq!(
+            normalized(input, 2),
+            expected(&[
+                region(0x1000, 0x9000, USABLE),
+                region(0x9000, 0xa000, RESERVED),
+                region(0xa000, 0xc000, USABLE),
+                region(0xc000, 0xd000, ACPI),
+                region(0xd000, 0x10_0000, USABLE),
+            ])
+        );
+    }
+
+    #[test]
+    fn drops_usable_memory_without_spare_entries() {
+        let input = [
+            region(0x1000, 0x10_0000, USABLE),
+            region(0x9000, 0xa000, RESERVED),
+        ];
+        assert_eq!(
+            normalized(input, 0),
+            expected(&[
+                region(0x1000, 0x9000, USABLE),
+                region(0x9000, 0xa000, RESERVED),
+            ])
+        );
+    }
+
+    #[test]
+    fn rounds_usable_regions_inward_and_others_outward() {
+        let input = [
+            region(0x1800, 0x4800, USABLE),
+            region(0x6800, 0x7800, RESERVED),
+            region(0x8100, 0x8200, USABLE),
+        ];
+        assert_eq!(
+            normalized(input, 0),
+            expected(&[
+                region(0x2000, 0x4000, USABLE),
+                region(0x6000, 0x8000, RESERVED),
+            ])
+        );
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct FirmwareRegion(MemoryRegion);
+
+    impl LegacyMemoryRegion for FirmwareRegion {
+        fn start(&self) -> PhysAddr {
+            PhysAddr::new(self.0.start)
+        }
+
+        fn len(&self) -> u64 {
+            self.0.end - self.0.start
+        }
+
+        fn kind(&self) -> MemoryRegionKind {
+            self.0.kind
+        }
+
+        fn usable_after_bootloader_exit(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn reports_reclaimable_frames() {
+        let memory_map = [FirmwareRegion(region(0x1000, 0x10_0000, USABLE))];
+        let mut allocator = LegacyFrameAllocator::new(memory_map.into_iter());
+        for _ in 0..4 {
+            FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator).unwrap();
+        }
+
+        // the reclaimable frames are merged into one extent, the unallocated frame at
+        // 0x9000 stays usable
+        let mut reclaimable = FrameExtents::new();
+        for addr in [0x3000, 0x2000, 0x9000] {
+            assert!(reclaimable.insert_frame(addr));
+        }
+        let mut regions = [MaybeUninit::uninit(); 8];
+        let memory_map = allocator.construct_memory_map(&mut regions, &reclaimable, &[]);
+        assert_eq!(
+            memory_map,
+            [
+                region(0x1000, 0x2000, MemoryRegionKind::Bootloader),
+                region(0x2000, 0x4000, MemoryRegionKind::BootloaderReclaimable),
+                region(0x4000, 0x5000, MemoryRegionKind::Bootloader),
+                region(0x5000, 0x10_0000, USABLE),
+            ]
+        );
+    }
+}
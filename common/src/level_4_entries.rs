@@ -4,11 +4,12 @@ use core::{alloc::Layout, iter::Step};
 use rand::{
     distributions::{Distribution, Uniform},
     seq::IteratorRandom,
+    SeedableRng,
 };
 use rand_hc::Hc128Rng;
 use usize_conversions::IntoUsize;
 use x86_64::{
-    structures::paging::{Page, PageTableIndex, Size4KiB},
+    structures::paging::{Page, PageSize, PageTableIndex, Size4KiB},
     PhysAddr, VirtAddr,
 };
 use xmas_elf::program::ProgramHeader;
@@ -22,21 +23,28 @@ pub struct UsedLevel4Entries {
     /// A random number generator that should be used to generate random addresses or
     /// `None` if aslr is disabled.
     rng: Option<Hc128Rng>,
+    /// Whether all dynamic addresses are randomized, or only the kernel base.
+    randomize_all: bool,
 }
 
 impl UsedLevel4Entries {
     /// Initializes a new instance.
     ///
-    /// Marks the statically configured virtual address ranges from the config as used.
+    /// Marks the statically configured virtual address ranges from the config as used. If ASLR
+    /// is enabled, the random number generator is seeded with `seed`, or with entropy from
+    /// [`entropy::build_rng`] if `None`.
     pub fn new(
         max_phys_addr: PhysAddr,
         regions_len: usize,
         framebuffer: Option<&RawFrameBufferInfo>,
         config: &BootloaderConfig,
+        seed: Option<[u8; 32]>,
     ) -> Self {
         let mut used = UsedLevel4Entries {
             entry_state: [false; 512],
-            rng: config.mappings.aslr.then(entropy::build_rng),
+            rng: (config.mappings.aslr || config.kernel_aslr)
+                .then(|| seed.map_or_else(entropy::build_rng, Hc128Rng::from_seed)),
+            randomize_all: config.mappings.aslr,
         };
 
         used.entry_state[0] = true; // TODO: Can we do this dynamically?
@@ -57,7 +65,12 @@ impl UsedLevel4Entries {
         }
 
         if let config::Mapping::FixedAddress(kernel_stack_address) = config.mappings.kernel_stack {
-            used.mark_range_as_used(kernel_stack_address, config.kernel_stack_size);
+            // include the guard page below the stack
+            let guard_page = kernel_stack_address.saturating_sub(Size4KiB::SIZE);
+            used.mark_range_as_used(
+                guard_page,
+                config.kernel_stack_size + (kernel_stack_address - guard_page),
+            );
         }
 
         if let config::Mapping::FixedAddress(boot_info_address) = config.mappings.boot_info {
@@ -75,6 +88,11 @@ impl UsedLevel4Entries {
             }
         }
 
+        if let Some(config::Mapping::FixedAddress(iommu_registers_address)) = config.iommu_registers
+        {
+            used.mark_range_as_used(iommu_registers_address, Size4KiB::SIZE);
+        }
+
         // Mark everything before the dynamic range as unusable.
         if let Some(dynamic_range_start) = config.mappings.dynamic_range_start {
             let dynamic_range_start = VirtAddr::new(dynamic_range_start);
@@ -103,7 +121,7 @@ impl UsedLevel4Entries {
     /// Marks all p4 entries in the range `[address..address+size)` as used.
     ///
     /// `size` can be a `u64` or `usize`.
-    fn mark_range_as_used<S>(&mut self, address: u64, size: S)
+    pub fn mark_range_as_used<S>(&mut self, address: u64, size: S)
     where
         VirtAddr: core::ops::Add<S, Output = VirtAddr>,
     {
@@ -142,6 +160,10 @@ impl UsedLevel4Entries {
     /// Since this method marks each returned index as used, it can be used multiple times
     /// to determine multiple unused virtual memory regions.
     pub fn get_free_entries(&mut self, num: u64) -> PageTableIndex {
+        self.free_entries(num, self.randomize_all)
+    }
+
+    fn free_entries(&mut self, num: u64, randomize: bool) -> PageTableIndex {
         // Create an iterator over all available p4 indices with `num` contiguous free entries.
         let mut free_entries = self
             .entry_state
@@ -151,14 +173,16 @@ impl UsedLevel4Entries {
             .map(|(idx, _)| idx);
 
         // Choose the free entry index.
-        let idx_opt = if let Some(rng) = self.rng.as_mut() {
+        let idx_opt = if let Some(rng) = self.rng.as_mut().filter(|_| randomize) {
             // Randomly choose an index.
             free_entries.choose(rng)
         } else {
             // Choose the first index.
             free_entries.next()
         };
-        let Some(idx) = idx_opt else { panic!("no usable level 4 entries found ({num} entries requested)"); };
+        let Some(idx) = idx_opt else {
+            panic!("no usable level 4 entries found ({num} entries requested)");
+        };
 
         // Mark the entries as used.
         for i in 0..num.into_usize() {
@@ -173,18 +197,28 @@ impl UsedLevel4Entries {
     /// This function calls [`get_free_entries`] internally, so all of its docs applies here
     /// too.
     pub fn get_free_address(&mut self, size: u64, alignment: u64) -> VirtAddr {
+        self.free_address(size, alignment, self.randomize_all)
+    }
+
+    /// Like [`Self::get_free_address`], but also returns a random address if only
+    /// `CONFIG.kernel_aslr` is enabled.
+    pub fn get_free_kernel_address(&mut self, size: u64, alignment: u64) -> VirtAddr {
+        self.free_address(size, alignment, true)
+    }
+
+    fn free_address(&mut self, size: u64, alignment: u64, randomize: bool) -> VirtAddr {
         assert!(alignment.is_power_of_two());
 
         const LEVEL_4_SIZE: u64 = 4096 * 512 * 512 * 512;
 
         let level_4_entries = (size + (LEVEL_4_SIZE - 1)) / LEVEL_4_SIZE;
         let base = Page::from_page_table_indices_1gib(
-            self.get_free_entries(level_4_entries),
+            self.free_entries(level_4_entries, randomize),
             PageTableIndex::new(0),
         )
         .start_address();
 
-        let offset = if let Some(rng) = self.rng.as_mut() {
+        let offset = if let Some(rng) = self.rng.as_mut().filter(|_| randomize) {
             // Choose a random offset.
             let max_offset = LEVEL_4_SIZE - (size % LEVEL_4_SIZE);
             let uniform_range = Uniform::from(0..max_offset / alignment);
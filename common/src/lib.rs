@@ -2,29 +2,73 @@
 #![feature(step_trait)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use crate::legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion};
+use crate::legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion, PageTableAllocator};
 use bootloader_api::{
-    config::{LevelFilter, LoggerStatus, Mapping},
-    info::{FrameBuffer, FrameBufferInfo, MemoryRegion, TlsTemplate},
+    config::{LevelFilter, Mapping},
+    info::{
+        ArchiveFile, ArchiveFormat, BootCounter, BootDevice, BootMetadata, BootSlot,
+        BootloaderHeap, Caching, ExtraMapping, FfiStr, FrameBuffer, FrameBufferInfo, FrameExtents,
+        Iommus, KernelStack, MemoryRegion, MemoryRegionKind, MmioRegisters, Module, PartitionKeys,
+        PlatformRegisters, RamdiskArchive, TlsBlock, TlsTemplate, UefiCallback, UefiRuntime,
+    },
     BootInfo, BootloaderConfig,
 };
-use core::{alloc::Layout, arch::asm, mem::MaybeUninit, slice};
+use core::{
+    arch::asm,
+    fmt, iter,
+    ptr::{self, NonNull},
+    slice, str,
+};
+use error::BootError;
+use five_level_paging::FiveLevelPaging;
+use handoff::{copy_str, init_slice, Handoff, HandoffLayout, HandoffSizes};
 use level_4_entries::UsedLevel4Entries;
+use raw_cpuid::CpuId;
+use regions::RegionRegistry;
+use sha2::{Digest, Sha256};
 use usize_conversions::FromUsize;
 use x86_64::{
+    align_down, align_up,
+    instructions::segmentation::{Segment, CS},
+    registers::model_specific::FsBase,
     structures::paging::{
-        page_table::PageTableLevel, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize,
-        PageTableFlags, PageTableIndex, PhysFrame, Size2MiB, Size4KiB,
+        mapper::MapToError, page_table::PageTableLevel, FrameAllocator, Mapper, OffsetPageTable,
+        Page, PageSize, PageTableFlags, PageTableIndex, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+        Translate,
     },
     PhysAddr, VirtAddr,
 };
 use xmas_elf::ElfFile;
 
+/// Parses the ACPI tables that describe the IOMMUs.
+pub mod acpi;
+/// Indexes the files of cpio and tar archives.
+pub mod archive;
+/// Parses the machine-specific config overlays of the boot partition.
+pub mod board_config;
+/// Parses the boot menu that lists the kernels of a disk image.
+pub mod boot_menu;
+/// Interprets the boot script that selects the kernel at boot time.
+pub mod boot_script;
+/// Decompresses kernels that were compressed by the disk image builder.
+pub mod compression;
+/// Counts the executions of instrumented code and dumps the counters to the debug console.
+pub mod coverage;
+/// Provides a type that logs output as text to the Bochs/QEMU debug console.
+pub mod debugcon;
 /// Provides a function to gather entropy and build a RNG.
-mod entropy;
+pub mod entropy;
+/// Provides the error type of the boot stages and the error screen.
+pub mod error;
+/// Switches the kernel to 5-level paging.
+pub mod five_level_paging;
 /// Provides a type that logs output as text to pixel-based framebuffers.
 pub mod framebuffer;
 mod gdt;
+/// Splits the boot info allocation into the boot info and the data it points to.
+pub mod handoff;
+/// Provides a bump allocator for temporary allocations of the bootloader.
+pub mod heap;
 /// Provides a frame allocator based on a BIOS or UEFI memory map.
 pub mod legacy_memory_region;
 /// Provides a type to keep track of used entries in a level 4 page table.
@@ -33,8 +77,50 @@ pub mod level_4_entries;
 pub mod load_kernel;
 /// Provides a logger that logs output as text in various formats.
 pub mod logger;
+/// Provides a ring buffer that keeps the log output for the kernel.
+pub mod memory_log;
+/// Provides the translated messages of the error screen.
+pub mod messages;
+/// Applies and reports the speculation control settings of the CPU.
+pub mod mitigations;
+/// Reports the firmware configuration of virtualization and SMM related MSRs.
+pub mod msr_state;
+/// Unlocks the encrypted partitions of the boot disk.
+#[cfg(feature = "partition-keys")]
+pub mod partition_key;
+/// Selects the loader for the executable format of the kernel.
+pub mod payload;
+/// Reboots and powers off the system from the error screen and the boot menu.
+pub mod power;
+/// Collects the bootloader frames that the kernel can reclaim.
+pub mod reclaim;
+/// Records the firmware inputs of a boot for replaying it on the host.
+pub mod record;
+/// Provides a registry that detects overlapping memory regions.
+pub mod regions;
 /// Provides a type that logs output as text to a Serial Being port.
 pub mod serial;
+/// Receives the kernel from the host in the serial kernel load developer mode.
+pub mod serial_load;
+/// Checks kernel signatures against the trusted keys of the bootloader.
+pub mod signature;
+/// Reads the product names of the machine from the SMBIOS tables.
+pub mod smbios;
+/// Measures the peak stack usage of the boot stages.
+pub mod stack;
+/// Logs a summary of the kernel environment before the jump to the kernel.
+pub mod summary;
+/// Reports the timer features of the CPU.
+pub mod timer_caps;
+/// Provides a clock based on the time stamp counter for the log timestamps.
+pub mod tsc;
+/// Switches the UEFI runtime services to the virtual addresses of the kernel.
+pub mod uefi_runtime;
+mod virtio;
+/// Provides a type that logs output as text to a virtio console.
+pub mod virtio_console;
+/// Provides a type that sends log output as UDP broadcasts through a virtio network device.
+pub mod virtio_net;
 
 const PAGE_SIZE: u64 = 4096;
 
@@ -42,24 +128,16 @@ const PAGE_SIZE: u64 = 4096;
 pub fn init_logger(
     framebuffer: &'static mut [u8],
     info: FrameBufferInfo,
-    log_level: LevelFilter,
-    frame_buffer_logger_status: LoggerStatus,
-    serial_logger_status: LoggerStatus,
+    config: &BootloaderConfig,
 ) {
-    let logger = logger::LOGGER.get_or_init(move || {
-        logger::LockedLogger::new(
-            framebuffer,
-            info,
-            frame_buffer_logger_status,
-            serial_logger_status,
-        )
-    });
+    let logger =
+        logger::LOGGER.get_or_init(move || logger::LockedLogger::new(framebuffer, info, config));
     log::set_logger(logger).expect("logger already set");
-    log::set_max_level(convert_level(log_level));
+    log::set_max_level(convert_level(config.log_level));
     log::info!("Framebuffer info: {:?}", info);
 }
 
-fn convert_level(level: LevelFilter) -> log::LevelFilter {
+pub(crate) fn convert_level(level: LevelFilter) -> log::LevelFilter {
     match level {
         LevelFilter::Off => log::LevelFilter::Off,
         LevelFilter::Error => log::LevelFilter::Error,
@@ -77,8 +155,42 @@ pub struct SystemInfo {
     pub framebuffer: Option<RawFrameBufferInfo>,
     /// Address of the _Root System Description Pointer_ structure of the ACPI standard.
     pub rsdp_addr: Option<PhysAddr>,
+    /// Address of the SMBIOS 3 or, if there is none, the SMBIOS 2 entry point structure.
+    pub smbios_addr: Option<PhysAddr>,
     pub ramdisk_addr: Option<u64>,
     pub ramdisk_len: u64,
+    /// The disk and partition that the kernel was loaded from, if known.
+    ///
+    /// The device path string is copied into the boot info, so it only needs to stay valid
+    /// until the boot info is created.
+    pub boot_device: Option<BootDevice>,
+    /// The verified metadata block of the boot image, see [`verify_boot_metadata`].
+    pub boot_metadata: Option<BootMetadata>,
+    /// The usage of the bootloader heap, see [`heap::Heap::usage`].
+    pub bootloader_heap: Option<BootloaderHeap>,
+    /// The persistent boot failure counter, if enabled.
+    pub boot_counter: Option<BootCounter>,
+    /// The A/B slot that the UEFI bootloader selected.
+    pub boot_slot: Option<BootSlot>,
+    /// The keys of the encrypted partitions that the BIOS bootloader unlocked.
+    pub partition_keys: Option<PartitionKeys>,
+    /// The UEFI system table and the memory regions of the runtime services.
+    pub uefi_runtime: Option<UefiRuntime>,
+    /// The callback of the UEFI bootloader, if the kernel is started before the boot services
+    /// are exited.
+    pub uefi_callback: Option<UefiCallbackInfo>,
+}
+
+/// The callback that the UEFI bootloader passes to the kernel, see
+/// [`BootInfo::uefi_callback`].
+#[derive(Debug, Copy, Clone)]
+pub struct UefiCallbackInfo {
+    pub callback: UefiCallback,
+    /// The physical memory ranges of the callback that are accessed while the kernel page
+    /// tables are active, i.e. its code and the data it shares with the bootloader. They are
+    /// identity-mapped for the kernel, the data as writable.
+    pub code: (PhysAddr, u64),
+    pub data: (PhysAddr, u64),
 }
 
 /// The physical address of the framebuffer and information about the framebuffer.
@@ -91,37 +203,167 @@ pub struct RawFrameBufferInfo {
 }
 
 pub struct Kernel<'a> {
-    pub elf: ElfFile<'a>,
     pub config: BootloaderConfig,
+    /// The serialized config as it is stored in the kernel executable.
+    pub raw_config: &'a [u8],
     pub start_address: *const u8,
     pub len: usize,
 }
 
 impl<'a> Kernel<'a> {
+    /// Reads the config of the given kernel executable.
+    ///
+    /// ELF kernels store their config in the `.bootloader-config` section. For the other
+    /// formats, the executable is searched for the serialized config.
     pub fn parse(kernel_slice: &'a [u8]) -> Self {
-        let kernel_elf = ElfFile::new(kernel_slice).unwrap();
-        let config = {
-            let section = kernel_elf
+        let (raw_config, position_independent) = if kernel_slice.starts_with(b"\x7fELF") {
+            let kernel_elf = ElfFile::new(kernel_slice)
+                .unwrap_or_else(|err| error::fail(BootError::InvalidKernel(err)));
+            let raw_config = kernel_elf
                 .find_section_by_name(".bootloader-config")
-                .expect("bootloader config section not found; kernel must be compiled against bootloader_api");
-            let raw = section.raw_data(&kernel_elf);
-            BootloaderConfig::deserialize(raw)
-                .expect("kernel was compiled with incompatible bootloader_api version")
+                .unwrap_or_else(|| error::fail(BootError::MissingConfig))
+                .raw_data(&kernel_elf);
+            let kernel_type = kernel_elf.header.pt2.type_().as_type();
+            (
+                raw_config,
+                kernel_type == xmas_elf::header::Type::SharedObject,
+            )
+        } else {
+            let raw_config = BootloaderConfig::find_serialized(kernel_slice)
+                .unwrap_or_else(|| error::fail(BootError::MissingConfig));
+            (raw_config, false)
         };
+        let config = BootloaderConfig::deserialize(raw_config)
+            .unwrap_or_else(|err| error::fail(BootError::IncompatibleConfig(err)));
+        if config.kernel_aslr && !position_independent {
+            error::fail(BootError::InvalidKernel(
+                "kernel_aslr requires a position-independent kernel",
+            ));
+        }
         Kernel {
-            elf: kernel_elf,
             config,
+            raw_config,
             start_address: kernel_slice.as_ptr(),
             len: kernel_slice.len(),
         }
     }
+
+    /// Returns the kernel executable.
+    pub fn bytes(&self) -> &'a [u8] {
+        unsafe { slice::from_raw_parts(self.start_address, self.len) }
+    }
+
+    /// Returns the entry point address in the ELF header, or `None` for the other formats.
+    pub fn linked_entry_point(&self) -> Option<u64> {
+        let kernel_elf = ElfFile::new(self.bytes()).ok()?;
+        Some(kernel_elf.header.pt2.entry_point())
+    }
+
+    /// Returns the number of bytes that the loaded kernel occupies in memory.
+    ///
+    /// For ELF kernels, this is the page-aligned size of the loadable segments, which includes
+    /// their zero-initialized parts. The other formats are estimated by their file size.
+    pub fn memory_size(&self) -> u64 {
+        let Ok(kernel_elf) = ElfFile::new(self.bytes()) else {
+            return self.len as u64;
+        };
+        kernel_elf
+            .program_iter()
+            .filter(|header| matches!(header.get_type(), Ok(xmas_elf::program::Type::Load)))
+            .map(|header| {
+                let start = align_down(header.virtual_addr(), Size4KiB::SIZE);
+                let end = align_up(header.virtual_addr() + header.mem_size(), Size4KiB::SIZE);
+                end - start
+            })
+            .sum()
+    }
+}
+
+/// The maximum length of a boot metadata file name, including the terminating null byte.
+pub const MAX_BOOT_METADATA_FILE_NAME_LEN: usize = 64;
+
+/// Writes the null-terminated name of the boot metadata file of the given kernel file to
+/// `buf`.
+///
+/// The metadata of the default `kernel-x86_64` kernel is stored in `boot-metadata`, the
+/// metadata of other kernels, e.g. of boot menu entries, A/B slots, and the recovery kernel,
+/// in the kernel file name with a `.metadata` extension. Keep in sync with `src/metadata.rs`
+/// of the `bootloader` crate. Returns `None` if the name is too long.
+pub fn boot_metadata_file_name<'a>(
+    kernel_file: &str,
+    buf: &'a mut [u8; MAX_BOOT_METADATA_FILE_NAME_LEN],
+) -> Option<&'a str> {
+    const DEFAULT_KERNEL: &str = "kernel-x86_64";
+    const DEFAULT_METADATA: &str = "boot-metadata\0";
+    const EXTENSION: &str = ".metadata\0";
+
+    let kernel_file = kernel_file.trim_end_matches('\0');
+    if kernel_file == DEFAULT_KERNEL {
+        return Some(DEFAULT_METADATA);
+    }
+    let name = buf.get_mut(..kernel_file.len() + EXTENSION.len())?;
+    let (stem, extension) = name.split_at_mut(kernel_file.len());
+    stem.copy_from_slice(kernel_file.as_bytes());
+    extension.copy_from_slice(EXTENSION.as_bytes());
+    core::str::from_utf8(name).ok()
+}
+
+/// Parses the metadata block of the boot image and checks that it matches the given kernel.
+///
+/// Returns `None` and logs an error if the block is corrupted or belongs to a different
+/// kernel, since the kernel then boots without its command line and extra mappings. The log
+/// levels of a verified block replace the ones of the kernel config from then on.
+pub fn verify_boot_metadata(raw: &[u8], kernel: &Kernel) -> Option<BootMetadata> {
+    let metadata = match BootMetadata::deserialize(raw) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            log::error!(
+                "Ignoring invalid boot metadata block, the kernel gets no command line \
+                and extra mappings: {}",
+                err
+            );
+            return None;
+        }
+    };
+
+    if <[u8; 32]>::from(Sha256::digest(kernel.bytes())) != metadata.kernel_hash {
+        log::error!(
+            "Ignoring boot metadata block of a different kernel, the kernel gets no \
+            command line and extra mappings"
+        );
+        return None;
+    }
+    if <[u8; 32]>::from(Sha256::digest(kernel.raw_config)) != metadata.config_hash {
+        log::error!(
+            "Ignoring boot metadata block: config hash does not match, the kernel gets no \
+            command line and extra mappings"
+        );
+        return None;
+    }
+
+    let version = metadata.bootloader_version;
+    log::info!(
+        "Boot image was created by bootloader {}.{}.{}{}",
+        version.version_major(),
+        version.version_minor(),
+        version.version_patch(),
+        if version.pre_release() { "-pre" } else { "" }
+    );
+    if !metadata.command_line.is_empty() {
+        log::info!("Kernel command line: {}", &*metadata.command_line);
+    }
+    if let Some(levels) = metadata.log_levels.into_option() {
+        logger::set_levels(&levels);
+        log::debug!("Using the log levels of the boot metadata block");
+    }
+    Some(metadata)
 }
 
 /// Loads the kernel ELF executable into memory and switches to it.
 ///
 /// This function is a convenience function that first calls [`set_up_mappings`], then
-/// [`create_boot_info`], and finally [`switch_to_kernel`]. The given arguments are passed
-/// directly to these functions, so see their docs for more info.
+/// [`create_boot_info`], [`mitigations::apply`], and finally [`switch_to_kernel`]. The given
+/// arguments are passed directly to these functions, so see their docs for more info.
 pub fn load_and_switch_to_kernel<I, D>(
     kernel: Kernel,
     mut frame_allocator: LegacyFrameAllocator<I, D>,
@@ -133,6 +375,22 @@ where
     D: LegacyMemoryRegion,
 {
     let config = kernel.config;
+    if config.acpi_s3_resume {
+        if let Some(rsdp_addr) = system_info.rsdp_addr {
+            power::resume_from_sleep(rsdp_addr);
+        }
+    }
+    logger::event(logger::Event::Loaded {
+        name: "kernel",
+        bytes: kernel.len as u64,
+    });
+    if system_info.ramdisk_addr.is_some() {
+        logger::event(logger::Event::Loaded {
+            name: "ramdisk",
+            bytes: system_info.ramdisk_len,
+        });
+    }
+    record::start(&kernel, &frame_allocator, &system_info);
     let mut mappings = set_up_mappings(
         kernel,
         &mut frame_allocator,
@@ -148,6 +406,13 @@ where
         &mut mappings,
         system_info,
     );
+    boot_info.cpu_state = Some(mitigations::apply(&config)).into();
+    if config.set_uefi_virtual_address_map {
+        if let Some(runtime) = boot_info.uefi_runtime.as_mut() {
+            uefi_runtime::set_virtual_address_map(runtime);
+        }
+    }
+    summary::show(config.pre_kernel_summary, boot_info, &mappings);
     switch_to_kernel(page_tables, mappings, boot_info);
 }
 
@@ -184,7 +449,16 @@ where
         frame_allocator.len(),
         framebuffer,
         config,
+        None,
     );
+    let extra_mappings = system_info
+        .boot_metadata
+        .map(|metadata| metadata.extra_mappings)
+        .unwrap_or_default();
+    for mapping in extra_mappings.iter().filter(|mapping| mapping.len > 0) {
+        check_extra_mapping(mapping);
+        used_entries.mark_range_as_used(mapping.virt_start, mapping.len);
+    }
 
     // Enable support for the no-execute bit in page tables.
     enable_nxe_bit();
@@ -195,42 +469,113 @@ where
     let kernel_slice_start = kernel.start_address as u64;
     let kernel_slice_len = u64::try_from(kernel.len).unwrap();
 
-    let (entry_point, tls_template) = load_kernel::load_kernel(
+    let mut regions = RegionRegistry::new();
+    regions.claim_physical(
+        PhysAddr::new(kernel_slice_start),
+        kernel_slice_len,
+        "kernel executable",
+    );
+    if let Some(ramdisk_addr) = system_info.ramdisk_addr {
+        regions.claim_physical(
+            PhysAddr::new(ramdisk_addr),
+            system_info.ramdisk_len,
+            "ramdisk",
+        );
+        let ramdisk_end = ramdisk_addr + system_info.ramdisk_len;
+        match config.dma_address_limit {
+            Some(limit) if ramdisk_end > limit => error::fail_with_details(
+                BootError::OutOfMemory("the ramdisk"),
+                &format_args!(
+                    "the ramdisk at {ramdisk_addr:#x}..{ramdisk_end:#x} ends above the \
+                    DMA address limit {limit:#x}"
+                ),
+            ),
+            _ => {}
+        }
+    }
+    if let Some(framebuffer) = framebuffer {
+        regions.claim_physical(
+            framebuffer.addr,
+            u64::from_usize(framebuffer.info.byte_len),
+            "framebuffer",
+        );
+    }
+
+    let linked_entry_point = kernel.linked_entry_point();
+    frame_allocator.set_purpose(Some(MemoryRegionKind::KernelImage));
+    let (entry_point, tls_template) = payload::load(
         kernel,
         kernel_page_table,
         frame_allocator,
         &mut used_entries,
+        &mut regions,
     )
-    .expect("no entry point");
+    .unwrap_or_else(|err| error::fail(BootError::KernelLoadFailed(err)));
+    frame_allocator.set_purpose(None);
     log::info!("Entry point at: {:#x}", entry_point.as_u64());
-    // create a stack
-    let stack_start_addr = mapping_addr(
-        config.mappings.kernel_stack,
-        config.kernel_stack_size,
-        16,
-        &mut used_entries,
-    );
+    let kernel_load_offset =
+        linked_entry_point.map(|linked| entry_point.as_u64().wrapping_sub(linked));
+    // create a stack with an unmapped guard page below it
+    let stack_start_addr = match config.mappings.kernel_stack {
+        Mapping::FixedAddress(addr) => {
+            coverage::hit();
+            VirtAddr::new(addr)
+        }
+        Mapping::Dynamic => {
+            coverage::hit();
+            used_entries.get_free_address(Size4KiB::SIZE + config.kernel_stack_size, Size4KiB::SIZE)
+                + Size4KiB::SIZE
+        }
+    };
     let stack_start: Page = Page::containing_address(stack_start_addr);
-    let stack_end = {
-        let end_addr = stack_start_addr + config.kernel_stack_size;
-        Page::containing_address(end_addr - 1u64)
+    let guard_page = stack_start
+        .start_address()
+        .as_u64()
+        .checked_sub(Size4KiB::SIZE)
+        .unwrap_or_else(|| error::fail(BootError::MappingFailed("the kernel stack guard page")));
+    let guard_page = VirtAddr::new(guard_page);
+    regions.claim_virtual(guard_page, Size4KiB::SIZE, "kernel stack guard page");
+    regions.claim_virtual(stack_start_addr, config.kernel_stack_size, "kernel stack");
+    let stack_end_addr = stack_start_addr + config.kernel_stack_size;
+    let stack_end = Page::containing_address(stack_end_addr - 1u64);
+    let kernel_stack = KernelStack {
+        start: stack_start_addr.as_u64(),
+        end: stack_end_addr.align_down(16u64).as_u64(),
+        guard_page: guard_page.as_u64(),
     };
+    log::info!("Kernel stack at {kernel_stack:x?}");
     for page in Page::range_inclusive(stack_start, stack_end) {
         let frame = frame_allocator
             .allocate_frame()
-            .expect("frame allocation failed when mapping a kernel stack");
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the kernel stack")));
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
             Ok(tlb) => tlb.flush(),
-            Err(err) => panic!("failed to map page {:?}: {:?}", page, err),
+            Err(err) => mapping_failed("the kernel stack", page, err),
         }
     }
 
+    let tls_block = tls_template.map(|template| {
+        coverage::hit();
+        set_up_tls_block(
+            &template,
+            kernel_page_table,
+            frame_allocator,
+            &mut used_entries,
+            &mut regions,
+        )
+    });
+
     // identity-map context switch function, so that we don't get an immediate pagefault
     // after switching the active page table
     let context_switch_function = PhysAddr::new(context_switch as *const () as u64);
     let context_switch_function_start_frame: PhysFrame =
         PhysFrame::containing_address(context_switch_function);
+    regions.claim_virtual(
+        VirtAddr::new(context_switch_function_start_frame.start_address().as_u64()),
+        2 * Size4KiB::SIZE,
+        "identity-mapped context switch function",
+    );
     for frame in PhysFrame::range_inclusive(
         context_switch_function_start_frame,
         context_switch_function_start_frame + 1,
@@ -239,25 +584,56 @@ where
             kernel_page_table.identity_map(frame, PageTableFlags::PRESENT, frame_allocator)
         } {
             Ok(tlb) => tlb.flush(),
-            Err(err) => panic!("failed to identity map frame {:?}: {:?}", frame, err),
+            Err(err) => mapping_failed("the context switch function", frame, err),
         }
     }
 
+    if let Some(callback) = &system_info.uefi_callback {
+        identity_map_uefi_callback(callback, kernel_page_table, frame_allocator, &mut regions);
+    }
+
+    let mut five_level_paging = if config.five_level_paging {
+        if config.mappings.page_table_recursive.is_some() {
+            log::warn!(
+                "5-level paging doesn't support the recursive mapping, using 4-level paging"
+            );
+            None
+        } else {
+            FiveLevelPaging::prepare(
+                context_switch_function,
+                kernel_page_table,
+                frame_allocator,
+                &mut regions,
+            )
+        }
+    } else {
+        None
+    };
+
     // create, load, and identity-map GDT (required for working `iretq`)
     let gdt_frame = frame_allocator
         .allocate_frame()
-        .expect("failed to allocate GDT frame");
-    gdt::create_and_load(gdt_frame);
+        .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the GDT")));
+    regions.claim_virtual(
+        VirtAddr::new(gdt_frame.start_address().as_u64()),
+        Size4KiB::SIZE,
+        "identity-mapped GDT",
+    );
+    let compat_code_selector = gdt::create_and_load(gdt_frame, five_level_paging.is_some());
+    if let Some(paging) = five_level_paging.as_mut() {
+        paging.set_compat_code_selector(compat_code_selector);
+    }
     match unsafe {
         kernel_page_table.identity_map(gdt_frame, PageTableFlags::PRESENT, frame_allocator)
     } {
         Ok(tlb) => tlb.flush(),
-        Err(err) => panic!("failed to identity map frame {:?}: {:?}", gdt_frame, err),
+        Err(err) => mapping_failed("the GDT", gdt_frame, err),
     }
 
     // map framebuffer
     let framebuffer_virt_addr = if let Some(framebuffer) = framebuffer {
         log::info!("Map framebuffer");
+        coverage::hit();
 
         let framebuffer_start_frame: PhysFrame = PhysFrame::containing_address(framebuffer.addr);
         let framebuffer_end_frame =
@@ -269,6 +645,11 @@ where
             &mut used_entries,
         ))
         .expect("the framebuffer address must be page aligned");
+        regions.claim_virtual(
+            start_page.start_address(),
+            u64::from_usize(framebuffer.info.byte_len),
+            "framebuffer",
+        );
         for (i, frame) in
             PhysFrame::range_inclusive(framebuffer_start_frame, framebuffer_end_frame).enumerate()
         {
@@ -276,10 +657,7 @@ where
             let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
             match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
                 Ok(tlb) => tlb.flush(),
-                Err(err) => panic!(
-                    "failed to map page {:?} to frame {:?}: {:?}",
-                    page, frame, err
-                ),
+                Err(err) => mapping_failed("the framebuffer", page, err),
             }
         }
         let framebuffer_virt_addr = start_page.start_address();
@@ -289,6 +667,7 @@ where
     };
     let ramdisk_slice_len = system_info.ramdisk_len;
     let ramdisk_slice_start = if let Some(ramdisk_address) = system_info.ramdisk_addr {
+        coverage::hit();
         let ramdisk_address_start = mapping_addr(
             config.mappings.ramdisk_memory,
             system_info.ramdisk_len,
@@ -302,6 +681,7 @@ where
         let ramdisk_physical_end_page = ramdisk_physical_start_page + ramdisk_page_count;
         let start_page = Page::from_start_address(ramdisk_address_start)
             .expect("the ramdisk start address must be page aligned");
+        regions.claim_virtual(ramdisk_address_start, system_info.ramdisk_len, "ramdisk");
 
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         for (i, frame) in
@@ -311,10 +691,7 @@ where
             let page = start_page + i as u64;
             match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
                 Ok(tlb) => tlb.ignore(),
-                Err(err) => panic!(
-                    "Failed to map page {:?} to frame {:?}: {:?}",
-                    page, frame, err
-                ),
+                Err(err) => mapping_failed("the ramdisk", page, err),
             };
         }
         Some(ramdisk_address_start)
@@ -322,46 +699,119 @@ where
         None
     };
 
-    let physical_memory_offset = if let Some(mapping) = config.mappings.physical_memory {
-        log::info!("Map physical memory");
+    let modules_start = match (ramdisk_archive(system_info), config.ramdisk_modules) {
+        (Some((ramdisk, format)), Some(mapping)) => {
+            log::info!("Extract ramdisk archive");
+            coverage::hit();
 
-        let start_frame = PhysFrame::containing_address(PhysAddr::new(0));
-        let max_phys = frame_allocator.max_phys_addr();
-        let end_frame: PhysFrame<Size2MiB> = PhysFrame::containing_address(max_phys - 1u64);
+            let size = archive::files(ramdisk, format)
+                .map(|file| module_span(u64::from_usize(file.len)))
+                .sum();
+            let start_addr = mapping_addr(mapping, size, Size4KiB::SIZE, &mut used_entries);
+            regions.claim_virtual(start_addr, size, "ramdisk modules");
+            frame_allocator.set_purpose(Some(MemoryRegionKind::Ramdisk));
 
-        let size = max_phys.as_u64();
-        let alignment = Size2MiB::SIZE;
-        let offset = mapping_addr(mapping, size, alignment, &mut used_entries);
+            let mut next_addr = start_addr;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            for file in archive::files(ramdisk, format) {
+                let contents = &ramdisk[file.offset..][..file.len];
+                let start_page = Page::from_start_address(next_addr)
+                    .expect("the module address must be page aligned");
+                for (i, chunk) in contents.chunks(Size4KiB::SIZE as usize).enumerate() {
+                    let frame: PhysFrame = frame_allocator.allocate_frame().unwrap_or_else(|| {
+                        error::fail(BootError::OutOfMemory("the ramdisk modules"))
+                    });
+                    // zero the frame and copy the file data, utilizing identity-mapping
+                    let frame_ptr = frame.start_address().as_u64() as *mut u8;
+                    unsafe {
+                        core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize);
+                        core::ptr::copy_nonoverlapping(chunk.as_ptr(), frame_ptr, chunk.len());
+                    }
+                    let page = start_page + u64::from_usize(i);
+                    let page_tables = &mut frame_allocator.page_tables();
+                    match unsafe { kernel_page_table.map_to(page, frame, flags, page_tables) } {
+                        Ok(tlb) => tlb.ignore(),
+                        Err(err) => mapping_failed("the ramdisk modules", page, err),
+                    }
+                }
+                next_addr += module_span(u64::from_usize(file.len));
+            }
+            frame_allocator.set_purpose(None);
+            Some(start_addr)
+        }
+        _ => None,
+    };
 
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            let page = Page::containing_address(offset + frame.start_address().as_u64());
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
-                Ok(tlb) => tlb.ignore(),
-                Err(err) => panic!(
-                    "failed to map page {:?} to frame {:?}: {:?}",
-                    page, frame, err
-                ),
+    let physical_memory = if let Some(mapping) = config.mappings.physical_memory {
+        log::info!("Map physical memory");
+        coverage::hit();
+
+        // 1GiB pages need a 1GiB-aligned offset, which fixed addresses might not have
+        let gigabyte_pages = supports_1gib_pages()
+            && match mapping {
+                Mapping::Dynamic => true,
+                Mapping::FixedAddress(addr) => addr % Size1GiB::SIZE == 0,
             };
-        }
+        let page_size = if gigabyte_pages {
+            Size1GiB::SIZE
+        } else {
+            Size2MiB::SIZE
+        };
+        let max_phys = frame_allocator.max_phys_addr();
+        let offset = match (five_level_paging.as_mut(), mapping) {
+            (Some(paging), Mapping::Dynamic) => {
+                // the mapping gets its own level 5 entry, so it isn't limited by the 128TiB of
+                // the higher half of the level 4 table
+                let limit = PhysAddr::new(five_level_paging::PHYSICAL_MEMORY_LIMIT);
+                if max_phys > limit {
+                    log::warn!("Only mapping the physical memory below {limit:?}");
+                }
+                let mut table = paging.physical_memory_table(frame_allocator);
+                map_physical_memory(
+                    gigabyte_pages,
+                    max_phys.min(limit),
+                    &mut table,
+                    frame_allocator,
+                    |addr| VirtAddr::new_truncate(addr.as_u64()),
+                );
+                five_level_paging::PHYSICAL_MEMORY_OFFSET
+            }
+            _ => {
+                let size = max_phys.align_up(page_size).as_u64();
+                let offset = mapping_addr(mapping, size, page_size, &mut used_entries);
+                regions.claim_virtual(offset, size, "physical memory mapping");
+                map_physical_memory(
+                    gigabyte_pages,
+                    max_phys,
+                    kernel_page_table,
+                    frame_allocator,
+                    |addr| offset + addr.as_u64(),
+                );
+                offset.as_u64()
+            }
+        };
+        log::info!("Mapped physical memory with {page_size:#x} byte pages");
 
-        Some(offset)
+        Some((offset, page_size))
     } else {
         None
     };
 
     let recursive_index = if let Some(mapping) = config.mappings.page_table_recursive {
         log::info!("Map page table recursively");
+        coverage::hit();
         let index = match mapping {
             Mapping::Dynamic => used_entries.get_free_entries(1),
             Mapping::FixedAddress(offset) => {
                 let offset = VirtAddr::new(offset);
                 let table_level = PageTableLevel::Four;
                 if !offset.is_aligned(table_level.entry_address_space_alignment()) {
-                    panic!(
-                        "Offset for recursive mapping must be properly aligned (must be \
-                        a multiple of {:#x})",
-                        table_level.entry_address_space_alignment()
+                    error::fail_with_details(
+                        BootError::MappingFailed("the recursive page table"),
+                        &format_args!(
+                            "offset must be a multiple of {:#x}",
+                            table_level.entry_address_space_alignment()
+                        ),
                     );
                 }
 
@@ -369,11 +819,17 @@ where
             }
         };
 
+        regions.claim_virtual(
+            Page::from_page_table_indices_1gib(index, PageTableIndex::new(0)).start_address(),
+            PageTableLevel::Four.entry_address_space_alignment(),
+            "recursive page table mapping",
+        );
+
         let entry = &mut kernel_page_table.level_4_table()[index];
         if !entry.is_unused() {
-            panic!(
-                "Could not set up recursive mapping: index {} already in use",
-                u16::from(index)
+            error::fail_with_details(
+                BootError::MappingFailed("the recursive page table"),
+                &format_args!("index {} already in use", u16::from(index)),
             );
         }
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
@@ -384,19 +840,381 @@ where
         None
     };
 
+    let mut iommus = system_info
+        .rsdp_addr
+        .and_then(|rsdp_addr| unsafe { acpi::find_iommus(rsdp_addr) });
+    if let (Some(iommus), Some(mapping)) = (iommus.as_mut(), config.iommu_registers) {
+        log::info!("Map IOMMU registers");
+        coverage::hit();
+
+        let size = iommus.iter().map(|unit| unit.register_len).sum();
+        let start_addr = mapping_addr(mapping, size, Size4KiB::SIZE, &mut used_entries);
+        regions.claim_virtual(start_addr, size, "IOMMU registers");
+
+        let mut next_addr = start_addr;
+        for unit in iommus.iter_mut() {
+            let start_frame =
+                PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(unit.register_base));
+            let end_frame = PhysFrame::containing_address(PhysAddr::new(
+                unit.register_base + unit.register_len - 1,
+            ));
+            let start_page = Page::from_start_address(next_addr)
+                .expect("the IOMMU register address must be page aligned");
+            let flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE;
+            for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+                let page = start_page + u64::from_usize(i);
+                match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(tlb) => tlb.ignore(),
+                    Err(err) => mapping_failed("the IOMMU registers", page, err),
+                }
+            }
+            unit.register_addr = Some(next_addr.as_u64()).into();
+            next_addr += unit.register_len;
+        }
+    }
+    if let Some(iommus) = &iommus {
+        for unit in iommus.iter() {
+            log::info!("Found IOMMU: {unit:x?}");
+        }
+    }
+
+    let mut platform_registers = system_info
+        .rsdp_addr
+        .and_then(|rsdp_addr| unsafe { acpi::find_platform_registers(rsdp_addr) });
+    if let (Some(registers), Some(mapping)) =
+        (platform_registers.as_mut(), config.platform_registers)
+    {
+        log::info!("Map platform registers");
+        coverage::hit();
+
+        let size = mmio_register_sets(registers)
+            .map(|set| page_span(set.base, set.len))
+            .sum();
+        let start_addr = mapping_addr(mapping, size, Size4KiB::SIZE, &mut used_entries);
+        regions.claim_virtual(start_addr, size, "platform registers");
+
+        let mut next_addr = start_addr;
+        for set in mmio_register_sets(registers) {
+            let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(set.base));
+            let end_frame = PhysFrame::containing_address(PhysAddr::new(set.base + set.len - 1));
+            let start_page = Page::from_start_address(next_addr)
+                .expect("the platform register address must be page aligned");
+            let flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE;
+            for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+                let page = start_page + u64::from_usize(i);
+                match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(tlb) => tlb.ignore(),
+                    Err(err) => mapping_failed("the platform registers", page, err),
+                }
+            }
+            set.addr = Some(next_addr.as_u64() + set.base % Size4KiB::SIZE).into();
+            next_addr += page_span(set.base, set.len);
+        }
+    }
+    if let Some(registers) = &platform_registers {
+        log::info!("Found platform registers: {registers:x?}");
+    }
+
+    let mut uefi_runtime = system_info.uefi_runtime;
+    if let (Some(runtime), Some(mapping)) = (uefi_runtime.as_mut(), config.uefi_runtime_services) {
+        log::info!("Map UEFI runtime services");
+        coverage::hit();
+
+        let size = runtime
+            .regions
+            .iter()
+            .map(|region| region.phys_start + region.page_count * Size4KiB::SIZE)
+            .max()
+            .unwrap_or(0);
+        let offset = mapping_addr(mapping, size, Size4KiB::SIZE, &mut used_entries);
+        regions.claim_virtual(offset, size, "UEFI runtime services");
+
+        for region in runtime.regions.iter() {
+            let mut flags = PageTableFlags::PRESENT;
+            if region.writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !region.executable {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            if matches!(
+                region.memory_type,
+                EFI_MEMORY_MAPPED_IO | EFI_MEMORY_MAPPED_IO_PORT_SPACE
+            ) {
+                flags |= PageTableFlags::NO_CACHE;
+            }
+            let start_frame =
+                PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(region.phys_start));
+            for i in 0..region.page_count {
+                let frame = start_frame + i;
+                let page = Page::containing_address(offset + frame.start_address().as_u64());
+                match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(tlb) => tlb.ignore(),
+                    Err(err) => mapping_failed("the UEFI runtime services", page, err),
+                }
+            }
+        }
+        runtime.virtual_offset = Some(offset.as_u64()).into();
+    }
+
+    for mapping in extra_mappings.iter().filter(|mapping| mapping.len > 0) {
+        log::info!("Map extra mapping {mapping:x?}");
+        map_extra_mapping(mapping, kernel_page_table, frame_allocator, &mut regions);
+    }
+
     Mappings {
         framebuffer: framebuffer_virt_addr,
         entry_point,
-        stack_end,
+        kernel_load_offset,
+        kernel_stack,
         used_entries,
-        physical_memory_offset,
+        regions,
+        physical_memory_offset: physical_memory.map(|(offset, _)| offset),
+        five_level_paging,
+        physical_memory_page_size: physical_memory.map(|(_, page_size)| page_size),
         recursive_index,
         tls_template,
+        tls_block,
 
         kernel_slice_start,
         kernel_slice_len,
         ramdisk_slice_start,
         ramdisk_slice_len,
+        modules_start,
+        iommus,
+        platform_registers,
+        uefi_runtime,
+        gdt_frame,
+    }
+}
+
+/// The EFI memory types of memory-mapped I/O regions, which are mapped uncacheable.
+const EFI_MEMORY_MAPPED_IO: u32 = 11;
+const EFI_MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+
+/// Identity-maps the code and the data of the UEFI callback, so that it can switch to the
+/// page tables of the firmware.
+fn identity_map_uefi_callback<I, D>(
+    callback: &UefiCallbackInfo,
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    regions: &mut RegionRegistry,
+) where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for ((start, len), flags) in [
+        (callback.code, PageTableFlags::PRESENT),
+        (callback.data, writable),
+    ] {
+        let start_frame: PhysFrame = PhysFrame::containing_address(start);
+        let end_frame = PhysFrame::containing_address(start + len - 1u64);
+        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+            let page: Page =
+                Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+            // the code may share a page with the context switch function
+            if kernel_page_table.translate_page(page).is_ok() {
+                continue;
+            }
+            regions.claim_virtual(page.start_address(), Size4KiB::SIZE, "UEFI callback");
+            match unsafe { kernel_page_table.identity_map(frame, flags, frame_allocator) } {
+                Ok(tlb) => tlb.flush(),
+                Err(err) => mapping_failed("the UEFI callback", frame, err),
+            }
+        }
+    }
+}
+
+/// Returns the register sets of the local APIC, the I/O APICs, and the HPET.
+fn mmio_register_sets(
+    registers: &mut PlatformRegisters,
+) -> impl Iterator<Item = &mut MmioRegisters> {
+    let PlatformRegisters {
+        local_apic,
+        io_apics,
+        hpet,
+    } = registers;
+    iter::once(local_apic)
+        .chain(io_apics.iter_mut().map(|io_apic| &mut io_apic.registers))
+        .chain(hpet.as_mut())
+}
+
+/// Returns the ramdisk and its archive format, if the ramdisk is a cpio or tar archive.
+fn ramdisk_archive(system_info: &SystemInfo) -> Option<(&'static [u8], ArchiveFormat)> {
+    // the ramdisk is identity-mapped in the bootloader address space
+    let ramdisk = unsafe {
+        slice::from_raw_parts(
+            system_info.ramdisk_addr? as *const u8,
+            usize::try_from(system_info.ramdisk_len).unwrap(),
+        )
+    };
+    Some((ramdisk, archive::detect(ramdisk)?))
+}
+
+/// Returns the size of the virtual memory that a module of the given length occupies,
+/// including its guard page.
+fn module_span(len: u64) -> u64 {
+    x86_64::align_up(len, Size4KiB::SIZE) + Size4KiB::SIZE
+}
+
+/// Returns the size of the pages that contain the given physical range.
+fn page_span(base: u64, len: u64) -> u64 {
+    x86_64::align_up(base + len, Size4KiB::SIZE) - x86_64::align_down(base, Size4KiB::SIZE)
+}
+
+/// Fails if the given extra mapping is not page aligned or not within the canonical virtual
+/// and the physical address space.
+fn check_extra_mapping(mapping: &ExtraMapping) {
+    let virt_end = mapping.virt_start.checked_add(mapping.len - 1);
+    let phys_end = mapping.phys_start.checked_add(mapping.len - 1);
+    let problem =
+        if mapping.virt_start % Size4KiB::SIZE != 0 || mapping.phys_start % Size4KiB::SIZE != 0 {
+            "is not page aligned"
+        } else if virt_end.map_or(true, |end| {
+            VirtAddr::try_new(mapping.virt_start).is_err() || VirtAddr::try_new(end).is_err()
+        }) {
+            "is not in the canonical address space"
+        } else if phys_end.map_or(true, |end| PhysAddr::try_new(end).is_err()) {
+            "exceeds the physical address space"
+        } else {
+            return;
+        };
+    error::fail_with_details(
+        BootError::MappingFailed("an extra mapping"),
+        &format_args!("{mapping:x?} {problem}"),
+    );
+}
+
+/// Maps a physical range that was requested in the `extra-mappings` of the boot image.
+fn map_extra_mapping<I, D>(
+    mapping: &ExtraMapping,
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    regions: &mut RegionRegistry,
+) where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(mapping.virt_start));
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(mapping.phys_start));
+    regions.claim_virtual(start_page.start_address(), mapping.len, "extra mapping");
+
+    let mut flags = PageTableFlags::PRESENT;
+    if mapping.writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !mapping.executable {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    match mapping.caching {
+        Caching::WriteBack => {}
+        Caching::WriteThrough => flags |= PageTableFlags::WRITE_THROUGH,
+        Caching::Uncacheable => flags |= PageTableFlags::NO_CACHE,
+    }
+    let end_frame = PhysFrame::containing_address(start_frame.start_address() + (mapping.len - 1));
+    for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+        let page = start_page + u64::from_usize(i);
+        match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.ignore(),
+            Err(err) => mapping_failed("an extra mapping", page, err),
+        }
+    }
+}
+
+/// Allocates the TLS block of the boot CPU and initializes it from the TLS template of the
+/// kernel, see [`TlsBlock`].
+fn set_up_tls_block<I, D>(
+    template: &TlsTemplate,
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    used_entries: &mut UsedLevel4Entries,
+    regions: &mut RegionRegistry,
+) -> TlsBlock
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    // the TCB starts with a pointer, so the thread pointer needs at least its alignment
+    let align = template.align.max(8);
+    if !align.is_power_of_two() || template.file_size > template.mem_size {
+        error::fail(BootError::KernelLoadFailed("invalid TLS segment"));
+    }
+    let tls_size = x86_64::align_up(template.mem_size, align);
+    let len = tls_size + 8;
+    let start = used_entries.get_free_address(len, align.max(Size4KiB::SIZE));
+    regions.claim_virtual(start, len, "kernel TLS block");
+
+    let start_page: Page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + (len - 1));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the kernel TLS block")));
+        // utilize that frames are identity-mapped
+        unsafe { ptr::write_bytes(frame.start_address().as_u64() as *mut u8, 0, 4096) };
+        match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.ignore(),
+            Err(err) => mapping_failed("the kernel TLS block", page, err),
+        }
+    }
+
+    // the TLS segment ends at the thread pointer, the `.tbss` part stays zeroed
+    let thread_pointer = start + tls_size;
+    copy_in_kernel_address_space(
+        kernel_page_table,
+        VirtAddr::new(template.start_addr),
+        thread_pointer - template.mem_size,
+        template.file_size,
+    );
+    let tcb = kernel_page_table
+        .translate_addr(thread_pointer)
+        .expect("TLS block is not mapped");
+    unsafe { ptr::write_unaligned(tcb.as_u64() as *mut u64, thread_pointer.as_u64()) };
+
+    let tls_block = TlsBlock {
+        start: start.as_u64(),
+        len,
+        thread_pointer: thread_pointer.as_u64(),
+    };
+    log::info!("TLS block at {tls_block:x?}");
+    tls_block
+}
+
+/// Copies `len` bytes within the kernel address space, utilizing that the bootloader
+/// identity-maps the physical memory.
+fn copy_in_kernel_address_space(
+    kernel_page_table: &OffsetPageTable<'static>,
+    src: VirtAddr,
+    dst: VirtAddr,
+    len: u64,
+) {
+    let mut offset = 0;
+    while offset < len {
+        let (src, dst) = (src + offset, dst + offset);
+        let chunk = (len - offset)
+            .min(Size4KiB::SIZE - u64::from(src.page_offset()))
+            .min(Size4KiB::SIZE - u64::from(dst.page_offset()));
+        let translate = |addr| {
+            kernel_page_table
+                .translate_addr(addr)
+                .expect("address is not mapped in the kernel address space")
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                translate(src).as_u64() as *const u8,
+                translate(dst).as_u64() as *mut u8,
+                chunk as usize,
+            )
+        };
+        offset += chunk;
     }
 }
 
@@ -404,19 +1222,34 @@ where
 pub struct Mappings {
     /// The entry point address of the kernel.
     pub entry_point: VirtAddr,
-    /// The stack end page of the kernel.
-    pub stack_end: Page,
+    /// The offset between the load and the link address of ELF kernels, which is non-zero for
+    /// position-independent kernels, e.g. with [`BootloaderConfig::kernel_aslr`].
+    pub kernel_load_offset: Option<u64>,
+    /// The stack of the kernel.
+    pub kernel_stack: KernelStack,
     /// Keeps track of used entries in the level 4 page table, useful for finding a free
     /// virtual memory when needed.
     pub used_entries: UsedLevel4Entries,
+    /// Keeps track of the memory regions claimed so far, used to detect overlapping
+    /// allocations.
+    pub regions: RegionRegistry,
     /// The start address of the framebuffer, if any.
     pub framebuffer: Option<VirtAddr>,
     /// The start address of the physical memory mapping, if enabled.
-    pub physical_memory_offset: Option<VirtAddr>,
+    ///
+    /// Not a `VirtAddr` because the address is only canonical with 5-level paging if the
+    /// mapping has its own level 5 entry.
+    pub physical_memory_offset: Option<u64>,
+    /// The size of the pages of the physical memory mapping, if enabled.
+    pub physical_memory_page_size: Option<u64>,
+    /// The frames for the switch to 5-level paging, if enabled and supported.
+    pub five_level_paging: Option<FiveLevelPaging>,
     /// The level 4 page table index of the recursive mapping, if enabled.
     pub recursive_index: Option<PageTableIndex>,
     /// The thread local storage template of the kernel executable, if it contains one.
     pub tls_template: Option<TlsTemplate>,
+    /// The TLS block of the boot CPU, if the kernel has a TLS segment.
+    pub tls_block: Option<TlsBlock>,
 
     /// Start address of the kernel slice allocation in memory.
     pub kernel_slice_start: u64,
@@ -424,6 +1257,16 @@ pub struct Mappings {
     pub kernel_slice_len: u64,
     pub ramdisk_slice_start: Option<VirtAddr>,
     pub ramdisk_slice_len: u64,
+    /// The start of the ramdisk archive files that were extracted into separate regions.
+    pub modules_start: Option<VirtAddr>,
+    /// The DMA remapping units described by the ACPI tables, if any.
+    pub iommus: Option<Iommus>,
+    /// The registers of the local APIC, the I/O APICs, and the HPET.
+    pub platform_registers: Option<PlatformRegisters>,
+    /// The UEFI runtime services, with the virtual offset of their regions if they are mapped.
+    pub uefi_runtime: Option<UefiRuntime>,
+    /// The identity-mapped frame that contains the GDT.
+    pub gdt_frame: PhysFrame,
 }
 
 /// Allocates and initializes the boot info struct and the memory map.
@@ -431,7 +1274,8 @@ pub struct Mappings {
 /// The boot info and memory map are mapped to both the kernel and bootloader
 /// address space at the same address. This makes it possible to return a Rust
 /// reference that is valid in both address spaces. The necessary physical frames
-/// are taken from the given `frame_allocator`.
+/// are taken from the given `frame_allocator`. See [`handoff`] for how the allocation is split
+/// into the boot info and the data it points to.
 pub fn create_boot_info<I, D>(
     config: &BootloaderConfig,
     mut frame_allocator: LegacyFrameAllocator<I, D>,
@@ -446,68 +1290,264 @@ where
     log::info!("Allocate bootinfo");
 
     // allocate and map space for the boot info
-    let (boot_info, memory_regions) = {
-        let boot_info_layout = Layout::new::<BootInfo>();
-        let regions = frame_allocator.len() + 4; // up to 4 regions might be split into used/unused
-        let memory_regions_layout = Layout::array::<MemoryRegion>(regions).unwrap();
-        let (combined, memory_regions_offset) =
-            boot_info_layout.extend(memory_regions_layout).unwrap();
+    let (boot_info, memory_regions, boot_device, boot_log, command_line, ramdisk_archive, modules) = {
+        let device_path = system_info
+            .boot_device
+            .and_then(|device| device.device_path.into_option());
+        let boot_log_len = logger::LOGGER
+            .get()
+            .and_then(|logger| logger.memory_log_len());
+        let command_line = system_info
+            .boot_metadata
+            .as_ref()
+            .map(|metadata| &*metadata.command_line)
+            .filter(|line| !line.is_empty());
+        let archive = ramdisk_archive(&system_info);
+        let (archive_file_count, archive_names_len) =
+            archive.map_or((0, 0), |(ramdisk, format)| {
+                archive::files(ramdisk, format).fold((0, 0), |(count, len), file| {
+                    (count + 1, len + file.path_len())
+                })
+            });
+        let layout = HandoffLayout::new(HandoffSizes {
+            // up to 6 regions might be split into used/unused, each reclaimable extent might
+            // split a used region into three, and so might the purpose ranges of the frame
+            // allocator and the kernel, ramdisk, and heap overlays; normalizing the map splits
+            // a usable region around every firmware region inside it
+            memory_regions: 2 * frame_allocator.len()
+                + 6
+                + 2 * FrameExtents::MAX_EXTENTS
+                + 2 * (legacy_memory_region::MAX_PURPOSE_RANGES + 3),
+            device_path: device_path.map_or(0, |path| path.len()),
+            boot_log: boot_log_len.unwrap_or(0),
+            command_line: command_line.map_or(0, str::len),
+            archive_files: archive_file_count,
+            archive_names: archive_names_len,
+            modules: if mappings.modules_start.is_some() {
+                archive_file_count
+            } else {
+                0
+            },
+        })
+        .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the boot info")));
 
         let boot_info_addr = mapping_addr(
             config.mappings.boot_info,
-            u64::from_usize(combined.size()),
-            u64::from_usize(combined.align()),
+            u64::from_usize(layout.size()),
+            u64::from_usize(layout.align()),
             &mut mappings.used_entries,
         );
-        assert!(
-            boot_info_addr.is_aligned(u64::from_usize(combined.align())),
-            "boot info addr is not properly aligned"
-        );
+        let boot_info_end = boot_info_addr + layout.size();
+        mappings
+            .regions
+            .claim_virtual(boot_info_addr, u64::from_usize(layout.size()), "boot info");
 
-        let memory_map_regions_addr = boot_info_addr + memory_regions_offset;
-        let memory_map_regions_end = boot_info_addr + combined.size();
+        let start_page: Page = Page::containing_address(boot_info_addr);
+        let end_page = Page::containing_address(boot_info_end - 1u64);
 
-        let start_page = Page::containing_address(boot_info_addr);
-        let end_page = Page::containing_address(memory_map_regions_end - 1u64);
-        for page in Page::range_inclusive(start_page, end_page) {
+        frame_allocator.set_purpose(Some(MemoryRegionKind::BootInfo));
+        // the boot info must be physically contiguous if it has to be reachable by DMA
+        let dma_frames = config.dma_address_limit.map(|limit| {
+            let count = Page::range_inclusive(start_page, end_page).count() as u64;
+            frame_allocator
+                .allocate_contiguous_below(count, PhysAddr::new(limit))
+                .unwrap_or_else(|| {
+                    error::fail_with_details(
+                        BootError::OutOfMemory("the boot info"),
+                        &format_args!(
+                            "no {count} contiguous free frames below the DMA address limit \
+                            {limit:#x}"
+                        ),
+                    )
+                })
+        });
+        for (i, page) in Page::range_inclusive(start_page, end_page).enumerate() {
             let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            let frame = frame_allocator
-                .allocate_frame()
-                .expect("frame allocation for boot info failed");
+            let frame = match dma_frames {
+                Some(start_frame) => start_frame + u64::from_usize(i),
+                None => frame_allocator
+                    .allocate_frame()
+                    .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the boot info"))),
+            };
             match unsafe {
                 page_tables
                     .kernel
-                    .map_to(page, frame, flags, &mut frame_allocator)
+                    .map_to(page, frame, flags, &mut frame_allocator.page_tables())
             } {
                 Ok(tlb) => tlb.flush(),
-                Err(err) => panic!("failed to map page {:?}: {:?}", page, err),
+                Err(err) => mapping_failed("the boot info", page, err),
             }
             // we need to be able to access it too
             match unsafe {
-                page_tables
-                    .bootloader
-                    .map_to(page, frame, flags, &mut frame_allocator)
+                page_tables.bootloader.map_to(
+                    page,
+                    frame,
+                    flags,
+                    &mut frame_allocator.page_tables(),
+                )
             } {
                 Ok(tlb) => tlb.flush(),
-                Err(err) => panic!("failed to map page {:?}: {:?}", page, err),
+                Err(err) => mapping_failed("the boot info", page, err),
             }
         }
+        frame_allocator.set_purpose(None);
 
-        let boot_info: &'static mut MaybeUninit<BootInfo> =
-            unsafe { &mut *boot_info_addr.as_mut_ptr() };
-        let memory_regions: &'static mut [MaybeUninit<MemoryRegion>] =
-            unsafe { slice::from_raw_parts_mut(memory_map_regions_addr.as_mut_ptr(), regions) };
-        (boot_info, memory_regions)
+        // SAFETY: the pages were just mapped in both address spaces, and the virtual range
+        // was claimed above, so nothing else uses it
+        let parts = unsafe {
+            Handoff::new(
+                NonNull::new(boot_info_addr.as_mut_ptr()).expect("boot info at address 0"),
+                layout,
+            )
+        }
+        .split();
+        let boot_device = system_info.boot_device.map(|device| {
+            let device_path =
+                device_path.map(|path| FfiStr::from(copy_str(parts.device_path, &path)));
+            BootDevice {
+                device_path: device_path.into(),
+                ..device
+            }
+        });
+        let boot_log = boot_log_len.and_then(|_| {
+            let log = logger::LOGGER.get()?.copy_memory_log(parts.boot_log)?;
+            Some(FfiStr::from(log))
+        });
+        let command_line =
+            command_line.map(|line| FfiStr::from(copy_str(parts.command_line, line)));
+        let ramdisk_archive = archive.map(|(ramdisk, format)| {
+            let mut names = parts.archive_names;
+            let files: &'static [ArchiveFile] = init_slice(
+                parts.archive_files,
+                archive::files(ramdisk, format).map(|file| {
+                    let (name, rest) = core::mem::take(&mut names).split_at_mut(file.path_len());
+                    file.write_path(name);
+                    names = rest;
+                    let name: &'static [u8] = name;
+                    ArchiveFile {
+                        name: str::from_utf8(name)
+                            .expect("archive file names are UTF-8")
+                            .into(),
+                        offset: u64::from_usize(file.offset),
+                        len: u64::from_usize(file.len),
+                    }
+                }),
+            );
+            log::info!(
+                "Ramdisk is a {:?} archive with {} files",
+                format,
+                archive_file_count
+            );
+            RamdiskArchive {
+                format,
+                files: files.into(),
+            }
+        });
+        let modules =
+            mappings
+                .modules_start
+                .zip(ramdisk_archive.as_ref())
+                .map(|(start_addr, archive)| {
+                    let mut next_addr = start_addr;
+                    let modules: &'static [Module] = init_slice(
+                        parts.modules,
+                        archive.files.iter().map(|file| {
+                            let module = Module {
+                                name: file.name,
+                                addr: next_addr.as_u64(),
+                                len: file.len,
+                            };
+                            next_addr += module_span(file.len);
+                            module
+                        }),
+                    );
+                    modules.into()
+                });
+        (
+            parts.boot_info,
+            parts.memory_regions,
+            boot_device,
+            boot_log,
+            command_line,
+            ramdisk_archive,
+            modules,
+        )
     };
 
+    // all frame allocations are done at this point
+    for (start, end) in frame_allocator.allocated_ranges() {
+        mappings
+            .regions
+            .check_physical(start, end - start, "bootloader frame allocations");
+    }
+
+    let reclaimable_frames = reclaim::reclaimable_frames(page_tables, mappings, |frame| {
+        let addr = frame.start_address();
+        frame_allocator
+            .allocated_ranges()
+            .any(|(start, end)| start <= addr && addr < end)
+    });
+
     log::info!("Create Memory Map");
 
     // build memory map
-    let memory_regions = frame_allocator.construct_memory_map(
-        memory_regions,
-        mappings.kernel_slice_start,
-        mappings.kernel_slice_len,
-    );
+    let overlay = |start: u64, len: u64, kind| MemoryRegion {
+        start,
+        end: start + len,
+        kind,
+    };
+    let overlays = [
+        overlay(
+            mappings.kernel_slice_start,
+            mappings.kernel_slice_len,
+            MemoryRegionKind::KernelImage,
+        ),
+        system_info
+            .ramdisk_addr
+            .map_or(MemoryRegion::empty(), |addr| {
+                overlay(addr, system_info.ramdisk_len, MemoryRegionKind::Ramdisk)
+            }),
+        system_info
+            .bootloader_heap
+            .map_or(MemoryRegion::empty(), |heap| {
+                overlay(heap.start, heap.size, MemoryRegionKind::BootloaderHeap)
+            }),
+    ];
+    let memory_regions =
+        frame_allocator.construct_memory_map(memory_regions, &reclaimable_frames, &overlays);
+    for region in memory_regions.iter() {
+        log::trace!("{region:x?}");
+    }
+
+    if let Some(heap) = system_info.bootloader_heap {
+        log::info!(
+            "Bootloader heap: peak usage {} of {} bytes",
+            heap.peak_usage,
+            heap.size
+        );
+    }
+
+    if let Some(counter) = system_info.boot_counter {
+        log::info!(
+            "Boot counter: {} of {} failed boots{}",
+            counter.failed_boots,
+            counter.limit,
+            if counter.recovery {
+                ", starting recovery kernel"
+            } else {
+                ""
+            }
+        );
+    }
+
+    if let Some(slot) = system_info.boot_slot {
+        log::info!(
+            "Boot slot: {:?} requested, {:?} booted, {} attempts remaining",
+            slot.requested,
+            slot.booted,
+            slot.attempts_remaining
+        );
+    }
 
     log::info!("Create bootinfo");
 
@@ -529,15 +1569,40 @@ where
                 )
             })
             .into();
-        info.physical_memory_offset = mappings.physical_memory_offset.map(VirtAddr::as_u64).into();
+        info.physical_memory_offset = mappings.physical_memory_offset.into();
+        info.physical_memory_page_size = mappings.physical_memory_page_size.into();
         info.recursive_index = mappings.recursive_index.map(Into::into).into();
         info.rsdp_addr = system_info.rsdp_addr.map(|addr| addr.as_u64()).into();
+        info.smbios_addr = system_info.smbios_addr.map(|addr| addr.as_u64()).into();
         info.tls_template = mappings.tls_template.into();
+        info.tls_block = mappings.tls_block.into();
         info.ramdisk_addr = mappings
             .ramdisk_slice_start
             .map(|addr| addr.as_u64())
             .into();
         info.ramdisk_len = mappings.ramdisk_slice_len;
+        info.boot_device = boot_device.into();
+        info.boot_metadata = system_info.boot_metadata.into();
+        info.bootloader_heap = system_info.bootloader_heap.into();
+        info.boot_counter = system_info.boot_counter.into();
+        info.boot_slot = system_info.boot_slot.into();
+        info.partition_keys = system_info.partition_keys.into();
+        info.uefi_callback = system_info.uefi_callback.map(|info| info.callback).into();
+        info.command_line = command_line.into();
+        info.ramdisk_archive = ramdisk_archive.into();
+        info.modules = modules.into();
+        info.msr_state = Some(msr_state::detect()).into();
+        info.timer_caps = Some(timer_caps::detect()).into();
+        info.kernel_stack = Some(mappings.kernel_stack).into();
+        info.acpi_fast_info = system_info
+            .rsdp_addr
+            .and_then(|rsdp_addr| unsafe { acpi::find_fast_info(rsdp_addr) })
+            .into();
+        info.iommus = mappings.iommus.into();
+        info.platform_registers = mappings.platform_registers.into();
+        info.uefi_runtime = mappings.uefi_runtime.into();
+        info.boot_log = boot_log.into();
+        info.reclaimable_frames = Some(reclaimable_frames).into();
         info
     });
 
@@ -554,17 +1619,37 @@ pub fn switch_to_kernel(
         kernel_level_4_frame,
         ..
     } = page_tables;
+    let page_table = match &mappings.five_level_paging {
+        Some(paging) => {
+            log::info!("Switching to 5-level paging");
+            paging.build_level_5_table(kernel_level_4_frame)
+        }
+        None => kernel_level_4_frame,
+    };
     let addresses = Addresses {
-        page_table: kernel_level_4_frame,
-        stack_top: mappings.stack_end.start_address(),
+        page_table,
+        five_level_paging: mappings.five_level_paging,
+        stack_top: VirtAddr::new(mappings.kernel_stack.end),
+        thread_pointer: mappings
+            .tls_block
+            .map(|block| VirtAddr::new(block.thread_pointer)),
         entry_point: mappings.entry_point,
-        boot_info,
+        // the kernel takes over the unique reference, so only its address is passed on
+        boot_info: ptr::addr_of_mut!(*boot_info),
     };
 
+    stack::report();
     log::info!(
         "Jumping to kernel entry point at {:?}",
         addresses.entry_point
     );
+    if let Some(logger) = logger::LOGGER.get() {
+        logger.log_suppressed_messages();
+        logger.event(logger::Event::Jump {
+            entry_point: addresses.entry_point.as_u64(),
+        });
+    }
+    coverage::dump();
 
     unsafe {
         context_switch(addresses);
@@ -587,13 +1672,74 @@ pub struct PageTables {
 
 /// Performs the actual context switch.
 unsafe fn context_switch(addresses: Addresses) -> ! {
+    // the bootloader doesn't use `FS`, so it can be set before the switch
+    if let Some(thread_pointer) = addresses.thread_pointer {
+        FsBase::write(thread_pointer);
+    }
+    if let Some(paging) = addresses.five_level_paging {
+        // the upper halves of the registers are lost in 32-bit mode, so the 64-bit values
+        // are passed through the identity-mapped switch frame
+        let switch_frame = paging.switch_frame_addr();
+        let params = switch_frame as *mut u64;
+        unsafe {
+            params.write(addresses.entry_point.as_u64());
+            params.add(1).write(addresses.stack_top.as_u64());
+            params.add(2).write(addresses.boot_info as u64);
+            params.add(3).write(CS::get_reg().0.into());
+            asm!(
+                "cli",
+                // `CR0.PG` can't be cleared while `CR4.PCIDE` is set
+                "mov rax, cr4",
+                "btr rax, 17",
+                "mov cr4, rax",
+                // switch to compatibility mode
+                "lea rdi, [rip + 3f]",
+                "push rcx",
+                "lea rax, [rip + 2f]",
+                "push rax",
+                "retfq",
+                ".code32",
+                "2:",
+                // disable paging, which leaves long mode
+                "mov eax, cr0",
+                "btr eax, 31",
+                "mov cr0, eax",
+                // enable 5-level paging and load the level 5 table
+                "mov eax, cr4",
+                "bts eax, 12",
+                "mov cr4, eax",
+                "mov cr3, esi",
+                // enable paging again, which enters long mode in compatibility mode
+                "mov eax, cr0",
+                "bts eax, 31",
+                "mov cr0, eax",
+                // switch back to 64-bit mode
+                "lea esp, [edx + 4096]",
+                "push dword ptr [edx + 24]",
+                "push edi",
+                "retf",
+                ".code64",
+                "3:",
+                "mov edx, edx",
+                "mov rdi, [rdx + 16]",
+                "mov rax, [rdx]",
+                "mov rsp, [rdx + 8]",
+                "push 0",
+                "jmp rax",
+                in("rcx") u64::from(paging.compat_code_selector()),
+                in("rsi") addresses.page_table.start_address().as_u64(),
+                in("rdx") switch_frame,
+                options(noreturn),
+            );
+        }
+    }
     unsafe {
         asm!(
             "mov cr3, {}; mov rsp, {}; push 0; jmp {}",
             in(reg) addresses.page_table.start_address().as_u64(),
             in(reg) addresses.stack_top.as_u64(),
             in(reg) addresses.entry_point.as_u64(),
-            in("rdi") addresses.boot_info as *const _ as usize,
+            in("rdi") addresses.boot_info,
         );
     }
     unreachable!();
@@ -602,9 +1748,11 @@ unsafe fn context_switch(addresses: Addresses) -> ! {
 /// Memory addresses required for the context switch.
 struct Addresses {
     page_table: PhysFrame,
+    five_level_paging: Option<FiveLevelPaging>,
     stack_top: VirtAddr,
+    thread_pointer: Option<VirtAddr>,
     entry_point: VirtAddr,
-    boot_info: &'static mut BootInfo,
+    boot_info: *mut BootInfo,
 }
 
 fn mapping_addr(
@@ -619,6 +1767,64 @@ fn mapping_addr(
     }
 }
 
+/// Maps the physical address space up to `max_phys` with 1GiB or 2MiB pages at the virtual
+/// addresses returned by `virt_addr`.
+fn map_physical_memory(
+    gigabyte_pages: bool,
+    max_phys: PhysAddr,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt_addr: impl Fn(PhysAddr) -> VirtAddr,
+) {
+    if gigabyte_pages {
+        map_physical_memory_with::<Size1GiB>(max_phys, page_table, frame_allocator, virt_addr);
+    } else {
+        map_physical_memory_with::<Size2MiB>(max_phys, page_table, frame_allocator, virt_addr);
+    }
+}
+
+fn map_physical_memory_with<S>(
+    max_phys: PhysAddr,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt_addr: impl Fn(PhysAddr) -> VirtAddr,
+) where
+    S: PageSize + fmt::Debug,
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let start_frame = PhysFrame::<S>::containing_address(PhysAddr::new(0));
+    let end_frame = PhysFrame::<S>::containing_address(max_phys - 1u64);
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::<S>::containing_address(virt_addr(frame.start_address()));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.ignore(),
+            Err(err) => mapping_failed("the physical memory", page, err),
+        };
+    }
+}
+
+/// Whether the CPU supports 1GiB pages.
+fn supports_1gib_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |features| features.has_1gib_pages())
+}
+
+/// Reports a failed mapping of the given object in the kernel address space.
+fn mapping_failed<S>(object: &'static str, target: impl fmt::Debug, err: MapToError<S>) -> !
+where
+    S: PageSize + fmt::Debug,
+{
+    match err {
+        MapToError::FrameAllocationFailed => error::fail(BootError::OutOfMemory(object)),
+        err => error::fail_with_details(
+            BootError::MappingFailed(object),
+            &format_args!("{target:?}: {err:?}"),
+        ),
+    }
+}
+
 fn enable_nxe_bit() {
     use x86_64::registers::control::{Efer, EferFlags};
     unsafe { Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE) }
@@ -628,3 +1834,29 @@ fn enable_write_protect_bit() {
     use x86_64::registers::control::{Cr0, Cr0Flags};
     unsafe { Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT) };
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn boot_metadata_file_names() {
+        let mut buf = [0; MAX_BOOT_METADATA_FILE_NAME_LEN];
+        assert_eq!(
+            boot_metadata_file_name("kernel-x86_64", &mut buf),
+            Some("boot-metadata\0")
+        );
+        assert_eq!(
+            boot_metadata_file_name("kernel-x86_64-a\0", &mut buf),
+            Some("kernel-x86_64-a.metadata\0")
+        );
+        assert_eq!(
+            boot_metadata_file_name("kernel-recovery-x86_64", &mut buf),
+            Some("kernel-recovery-x86_64.metadata\0")
+        );
+        let long = "k".repeat(MAX_BOOT_METADATA_FILE_NAME_LEN);
+        assert_eq!(boot_metadata_file_name(&long, &mut buf), None);
+    }
+}
@@ -1,20 +1,29 @@
-use crate::{level_4_entries::UsedLevel4Entries, PAGE_SIZE};
+use crate::{
+    coverage,
+    error::{self, BootError},
+    legacy_memory_region::PageTableAllocator,
+    level_4_entries::UsedLevel4Entries,
+    regions::RegionRegistry,
+    PAGE_SIZE,
+};
 use bootloader_api::info::TlsTemplate;
 use core::{cmp, iter::Step, mem::size_of, ops::Add};
-use log::debug;
+use log::{debug, trace};
 
 use x86_64::{
     align_up,
     structures::paging::{
         mapper::{MappedFrame, MapperAllSizes, TranslateResult},
-        FrameAllocator, Page, PageSize, PageTableFlags as Flags, PhysFrame, Size4KiB, Translate,
+        FrameAllocator, Page, PageSize, PageTableFlags as Flags, PhysFrame, Size2MiB, Size4KiB,
+        Translate,
     },
     PhysAddr, VirtAddr,
 };
 use xmas_elf::{
-    dynamic, header,
-    program::{self, ProgramHeader, SegmentData, Type},
+    header,
+    program::{self, ProgramHeader, Type},
     sections::Rela,
+    symbol_table::{Binding, DynEntry64, Entry},
     ElfFile,
 };
 
@@ -38,59 +47,56 @@ struct Inner<'a, M, F> {
 impl<'a, M, F> Loader<'a, M, F>
 where
     M: MapperAllSizes + Translate,
-    F: FrameAllocator<Size4KiB>,
+    F: FrameAllocator<Size2MiB> + PageTableAllocator,
 {
     fn new(
         kernel: Kernel<'a>,
         page_table: &'a mut M,
         frame_allocator: &'a mut F,
         used_entries: &mut UsedLevel4Entries,
+        regions: &mut RegionRegistry,
     ) -> Result<Self, &'static str> {
-        log::info!("Elf file loaded at {:#p}", kernel.elf.input);
-        let kernel_offset = PhysAddr::new(&kernel.elf.input[0] as *const u8 as u64);
+        let elf_file = ElfFile::new(kernel.bytes())?;
+        log::info!("Elf file loaded at {:#p}", elf_file.input);
+        let kernel_offset = PhysAddr::new(&elf_file.input[0] as *const u8 as u64);
         if !kernel_offset.is_aligned(PAGE_SIZE) {
             return Err("Loaded kernel ELF file is not sufficiently aligned");
         }
 
-        let elf_file = kernel.elf;
         for program_header in elf_file.program_iter() {
+            trace!("Program header: {:x?}", program_header);
             program::sanity_check(program_header, &elf_file)?;
+            if let Ok(Type::Load) = program_header.get_type() {
+                let flags = program_header.flags();
+                if flags.is_write() && flags.is_execute() {
+                    if kernel.config.strict_segment_permissions {
+                        return Err("kernel has a writable and executable segment");
+                    }
+                    log::warn!(
+                        "Kernel segment at {:#x} is writable and executable",
+                        program_header.virtual_addr()
+                    );
+                }
+            }
         }
 
-        let virtual_address_offset = match elf_file.header.pt2.type_().as_type() {
-            header::Type::None => unimplemented!(),
-            header::Type::Relocatable => unimplemented!(),
-            header::Type::Executable => VirtualAddressOffset::zero(),
-            header::Type::SharedObject => {
-                // Find the highest virtual memory address and the biggest alignment.
-                let load_program_headers = elf_file
-                    .program_iter()
-                    .filter(|h| matches!(h.get_type(), Ok(Type::Load)));
-                let max_addr = load_program_headers
-                    .clone()
-                    .map(|h| h.virtual_addr() + h.mem_size())
-                    .max()
-                    .unwrap_or(0);
-                let min_addr = load_program_headers
-                    .clone()
-                    .map(|h| h.virtual_addr())
-                    .min()
-                    .unwrap_or(0);
-                let size = max_addr - min_addr;
-                let align = load_program_headers.map(|h| h.align()).max().unwrap_or(1);
-
-                let offset = used_entries.get_free_address(size, align).as_u64();
-                VirtualAddressOffset::new(i128::from(offset) - i128::from(min_addr))
-            }
-            header::Type::Core => unimplemented!(),
-            header::Type::ProcessorSpecific(_) => unimplemented!(),
-        };
+        let virtual_address_offset = virtual_address_offset(&elf_file, used_entries);
         log::info!(
             "virtual_address_offset: {:#x}",
             virtual_address_offset.virtual_address_offset()
         );
 
         used_entries.mark_segments(elf_file.program_iter(), virtual_address_offset);
+        for segment in elf_file
+            .program_iter()
+            .filter(|h| matches!(h.get_type(), Ok(Type::Load)))
+        {
+            regions.claim_virtual(
+                VirtAddr::new(virtual_address_offset + segment.virtual_addr()),
+                segment.mem_size(),
+                "kernel load segment",
+            );
+        }
 
         header::sanity_check(&elf_file)?;
         let loader = Loader {
@@ -160,10 +166,10 @@ where
 impl<'a, M, F> Inner<'a, M, F>
 where
     M: MapperAllSizes + Translate,
-    F: FrameAllocator<Size4KiB>,
+    F: FrameAllocator<Size2MiB> + PageTableAllocator,
 {
     fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
-        log::info!("Handling Segment: {:x?}", segment);
+        debug!("Handling Segment: {:x?}", segment);
 
         let phys_start_addr = self.kernel_offset + segment.offset();
         let start_frame: PhysFrame = PhysFrame::containing_address(phys_start_addr);
@@ -181,13 +187,29 @@ where
             segment_flags |= Flags::WRITABLE;
         }
 
+        // Segments that request huge page alignment must be backed by physical memory with the
+        // same alignment, which the loaded ELF file generally doesn't provide.
+        if segment.align() >= Size2MiB::SIZE
+            && phys_start_addr.as_u64() % Size2MiB::SIZE
+                != virt_start_addr.as_u64() % Size2MiB::SIZE
+        {
+            coverage::hit();
+            return self.handle_huge_aligned_load_segment(&segment, segment_flags);
+        }
+        coverage::hit();
+
         // map all frames of the segment at the desired virtual address
         for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
             let offset = frame - start_frame;
             let page = start_page + offset;
             let flusher = unsafe {
                 self.page_table
-                    .map_to(page, frame, segment_flags, self.frame_allocator)
+                    .map_to(
+                        page,
+                        frame,
+                        segment_flags,
+                        &mut self.frame_allocator.page_tables(),
+                    )
                     .map_err(|_err| "map_to failed")?
             };
             // we operate on an inactive page table, so there's no need to flush anything
@@ -197,18 +219,93 @@ where
         // Handle .bss section (mem_size > file_size)
         if segment.mem_size() > segment.file_size() {
             // .bss section (or similar), which needs to be mapped and zeroed
+            coverage::hit();
             self.handle_bss_section(&segment, segment_flags)?;
         }
 
         Ok(())
     }
 
+    /// Copies a segment to newly allocated 2MiB-aligned frames and maps it.
+    ///
+    /// Each 2MiB region of the segment's virtual memory is backed by a single 2MiB-aligned
+    /// physical frame, so that the kernel can later remap the segment using huge pages. The
+    /// `.bss` part of the segment is zeroed as part of the copy.
+    fn handle_huge_aligned_load_segment(
+        &mut self,
+        segment: &ProgramHeader,
+        segment_flags: Flags,
+    ) -> Result<(), &'static str> {
+        log::info!(
+            "Copying segment to 2MiB-aligned frames (p_align: {:#x})",
+            segment.align()
+        );
+        if segment.align() > Size2MiB::SIZE {
+            log::warn!("Only 2MiB of the requested segment alignment can be honored");
+        }
+
+        let file_start = self.kernel_offset + segment.offset();
+        let virt_start_addr = VirtAddr::new(self.virtual_address_offset + segment.virtual_addr());
+        let virt_file_end = virt_start_addr + segment.file_size();
+        let start_page: Page = Page::containing_address(virt_start_addr);
+        let end_page: Page = Page::containing_address(virt_start_addr + segment.mem_size() - 1u64);
+
+        let mut current_huge_frame: Option<(Page<Size2MiB>, PhysFrame<Size2MiB>)> = None;
+        for page in Page::range_inclusive(start_page, end_page) {
+            let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+            let huge_frame = match current_huge_frame {
+                Some((current_page, frame)) if current_page == huge_page => frame,
+                _ => {
+                    let frame = FrameAllocator::<Size2MiB>::allocate_frame(self.frame_allocator)
+                        .ok_or("Failed to allocate 2MiB frame for aligned segment")?;
+                    current_huge_frame = Some((huge_page, frame));
+                    frame
+                }
+            };
+            let frame = PhysFrame::<Size4KiB>::containing_address(
+                huge_frame.start_address() + (page.start_address() - huge_page.start_address()),
+            );
+
+            // zero the frame and copy the file data, utilizing identity-mapping
+            let frame_ptr = frame.start_address().as_u64() as *mut u8;
+            unsafe { core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+            let copy_start = cmp::max(page.start_address(), virt_start_addr);
+            let copy_end = cmp::min(page.start_address() + Size4KiB::SIZE, virt_file_end);
+            if copy_start < copy_end {
+                let src = (file_start + (copy_start - virt_start_addr)).as_u64() as *const u8;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        src,
+                        frame_ptr.add((copy_start - page.start_address()) as usize),
+                        (copy_end - copy_start) as usize,
+                    );
+                }
+            }
+
+            // the frame no longer aliases the ELF file, so mark it as copied for `make_mut`
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(
+                        page,
+                        frame,
+                        segment_flags | COPIED,
+                        &mut self.frame_allocator.page_tables(),
+                    )
+                    .map_err(|_err| "map_to failed")?
+            };
+            // we operate on an inactive page table, so there's no need to flush anything
+            flusher.ignore();
+        }
+
+        Ok(())
+    }
+
     fn handle_bss_section(
         &mut self,
         segment: &ProgramHeader,
         segment_flags: Flags,
     ) -> Result<(), &'static str> {
-        log::info!("Mapping bss section");
+        debug!("Mapping bss section");
 
         let virt_start_addr = VirtAddr::new(self.virtual_address_offset + segment.virtual_addr());
         let mem_size = segment.mem_size();
@@ -273,7 +370,9 @@ where
         let end_page = Page::containing_address(zero_end);
         for page in Page::range_inclusive(start_page, end_page) {
             // allocate a new unused frame
-            let frame = self.frame_allocator.allocate_frame().unwrap();
+            let frame: PhysFrame = self.frame_allocator.allocate_frame().unwrap_or_else(|| {
+                error::fail(BootError::OutOfMemory(".bss memory of the kernel"))
+            });
 
             // zero frame, utilizing identity-mapping
             let frame_ptr = frame.start_address().as_u64() as *mut PageArray;
@@ -282,7 +381,12 @@ where
             // map frame
             let flusher = unsafe {
                 self.page_table
-                    .map_to(page, frame, segment_flags, self.frame_allocator)
+                    .map_to(
+                        page,
+                        frame,
+                        segment_flags,
+                        &mut self.frame_allocator.page_tables(),
+                    )
                     .map_err(|_err| "Failed to map new frame for bss memory")?
             };
             // we operate on an inactive page table, so we don't need to flush our changes
@@ -462,7 +566,10 @@ where
         }
 
         // Allocate a new frame and copy the memory, utilizing that both frames are identity mapped.
-        let new_frame = self.frame_allocator.allocate_frame().unwrap();
+        let new_frame: PhysFrame = self
+            .frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("a copy of a kernel page")));
         let frame_ptr = frame.start_address().as_u64() as *const u8;
         let new_frame_ptr = new_frame.start_address().as_u64() as *mut u8;
         unsafe {
@@ -474,7 +581,12 @@ where
         let new_flags = flags | COPIED;
         unsafe {
             self.page_table
-                .map_to(page, new_frame, new_flags, self.frame_allocator)
+                .map_to(
+                    page,
+                    new_frame,
+                    new_flags,
+                    &mut self.frame_allocator.page_tables(),
+                )
                 .unwrap()
                 .ignore();
         }
@@ -526,6 +638,7 @@ where
             start_addr: self.virtual_address_offset + segment.virtual_addr(),
             mem_size: segment.mem_size(),
             file_size: segment.file_size(),
+            align: segment.align(),
         })
     }
 
@@ -534,72 +647,55 @@ where
         segment: ProgramHeader,
         elf_file: &ElfFile,
     ) -> Result<(), &'static str> {
-        let data = segment.get_data(elf_file)?;
-        let data = if let SegmentData::Dynamic64(data) = data {
-            data
-        } else {
-            panic!("expected Dynamic64 segment")
-        };
-
-        // Find the `Rela`, `RelaSize` and `RelaEnt` entries.
-        let mut rela = None;
-        let mut rela_size = None;
-        let mut rela_ent = None;
-        for rel in data {
-            let tag = rel.get_tag()?;
-            match tag {
-                dynamic::Tag::Rela => {
-                    let ptr = rel.get_ptr()?;
-                    let prev = rela.replace(ptr);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one Rela entry");
-                    }
-                }
-                dynamic::Tag::RelaSize => {
-                    let val = rel.get_val()?;
-                    let prev = rela_size.replace(val);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one RelaSize entry");
-                    }
-                }
-                dynamic::Tag::RelaEnt => {
-                    let val = rel.get_val()?;
-                    let prev = rela_ent.replace(val);
-                    if prev.is_some() {
-                        return Err("Dynamic section contains more than one RelaEnt entry");
-                    }
-                }
-                _ => {}
+        let dynamic = DynamicInfo::parse(segment, elf_file)?;
+
+        if let Some(offset) = dynamic.rela {
+            let total_size = dynamic.rela_size.ok_or("RelaSize entry is missing")?;
+            let entry_size = dynamic.rela_ent.ok_or("RelaEnt entry is missing")?;
+            // Make sure that the reported size matches our `Rela<u64>`.
+            if entry_size != size_of::<Rela<u64>>() as u64 {
+                return Err("unsupported RelaEnt value");
             }
+            self.apply_relocations(offset, total_size, &dynamic, elf_file)?;
+        } else if dynamic.rela_size.is_some() || dynamic.rela_ent.is_some() {
+            return Err("Rela entry is missing but RelaSize or RelaEnt have been provided");
         }
-        let offset = if let Some(rela) = rela {
-            rela
-        } else {
-            // The section doesn't contain any relocations.
 
-            if rela_size.is_some() || rela_ent.is_some() {
-                return Err("Rela entry is missing but RelaSize or RelaEnt have been provided");
+        // The PLT relocations, e.g. `R_X86_64_JUMP_SLOT`. Kernels can't be loaded lazily, so
+        // they are applied right away.
+        if let Some(offset) = dynamic.jmp_rel {
+            if dynamic.plt_rel != Some(DT_RELA) {
+                return Err("only Rela PLT relocations are supported");
             }
+            let total_size = dynamic.plt_rel_size.ok_or("PltRelSize entry is missing")?;
+            self.apply_relocations(offset, total_size, &dynamic, elf_file)?;
+        }
 
-            return Ok(());
-        };
-        let total_size = rela_size.ok_or("RelaSize entry is missing")?;
-        let entry_size = rela_ent.ok_or("RelaEnt entry is missing")?;
+        if let Some(offset) = dynamic.relr {
+            let total_size = dynamic.relr_size.ok_or("RelrSize entry is missing")?;
+            if dynamic.relr_ent.map_or(false, |size| size != 8) {
+                return Err("unsupported RelrEnt value");
+            }
+            self.apply_relr_relocations(offset, total_size, elf_file)?;
+        }
 
-        // Make sure that the reported size matches our `Rela<u64>`.
-        assert_eq!(
-            entry_size,
-            size_of::<Rela<u64>>() as u64,
-            "unsupported entry size: {entry_size}"
-        );
+        Ok(())
+    }
 
-        // Apply the relocations.
-        let num_entries = total_size / entry_size;
+    /// Applies the `Rela` relocations of the table at the given address.
+    fn apply_relocations(
+        &mut self,
+        relocation_table: u64,
+        total_size: u64,
+        dynamic: &DynamicInfo,
+        elf_file: &ElfFile,
+    ) -> Result<(), &'static str> {
+        let num_entries = total_size / size_of::<Rela<u64>>() as u64;
+        trace!("Applying {num_entries} relocations of the table at {relocation_table:#x}");
         for idx in 0..num_entries {
-            let rela = self.read_relocation(offset, idx);
-            self.apply_relocation(rela, elf_file)?;
+            let rela = self.read_relocation(relocation_table, idx);
+            self.apply_relocation(rela, dynamic, elf_file)?;
         }
-
         Ok(())
     }
 
@@ -625,37 +721,101 @@ where
     fn apply_relocation(
         &mut self,
         rela: Rela<u64>,
+        dynamic: &DynamicInfo,
         elf_file: &ElfFile,
     ) -> Result<(), &'static str> {
-        let symbol_idx = rela.get_symbol_table_index();
-        assert_eq!(
-            symbol_idx, 0,
-            "relocations using the symbol table are not supported"
-        );
+        let value = relocation_value(&rela, self.virtual_address_offset, |symbol_idx| {
+            self.symbol_value(symbol_idx, dynamic, elf_file)
+        })?;
+        let Some(value) = value else {
+            return Ok(());
+        };
 
-        match rela.get_type() {
-            // R_AMD64_RELATIVE
-            8 => {
-                // Make sure that the relocation happens in memory mapped
-                // by a Load segment.
-                check_is_in_load(elf_file, rela.get_offset())?;
+        // Make sure that the relocation happens in memory mapped
+        // by a Load segment.
+        check_is_in_load(elf_file, rela.get_offset())?;
 
-                // Calculate the destionation of the relocation.
-                let addr = self.virtual_address_offset + rela.get_offset();
-                let addr = VirtAddr::new(addr);
+        // Calculate the destionation of the relocation.
+        let addr = self.virtual_address_offset + rela.get_offset();
+        let addr = VirtAddr::new(addr);
 
-                // Calculate the relocated value.
-                let value = self.virtual_address_offset + rela.get_addend();
+        // Write the relocated value to memory.
+        unsafe {
+            // SAFETY: We just verified that the address is in a Load segment.
+            self.copy_to(addr, &value.to_ne_bytes());
+        }
 
-                // Write the relocated value to memory.
-                unsafe {
-                    // SAFETY: We just verified that the address is in a Load segment.
-                    self.copy_to(addr, &value.to_ne_bytes());
-                }
+        Ok(())
+    }
+
+    /// Reads the given dynamic symbol and returns its relocated address.
+    fn symbol_value(
+        &self,
+        symbol_idx: u32,
+        dynamic: &DynamicInfo,
+        elf_file: &ElfFile,
+    ) -> Result<u64, &'static str> {
+        let symbol_table = dynamic.sym_tab.ok_or("SymTab entry is missing")?;
+        let entry_size = dynamic.sym_ent.unwrap_or(size_of::<DynEntry64>() as u64);
+        if entry_size != size_of::<DynEntry64>() as u64 {
+            return Err("unsupported SymEnt value");
+        }
+        let offset = symbol_table + entry_size * u64::from(symbol_idx);
+        check_is_in_load(elf_file, offset)?;
+
+        // Read the symbol from the kernel address space.
+        let mut buf = [0; 24];
+        self.copy_from(
+            VirtAddr::new(self.virtual_address_offset + offset),
+            &mut buf,
+        );
+        let symbol = unsafe {
+            // SAFETY: Any bitpattern is valid for `DynEntry64` and buf is
+            // valid for reads.
+            core::ptr::read_unaligned(&buf as *const u8 as *const DynEntry64)
+        };
+        resolve_symbol(&symbol, self.virtual_address_offset)
+    }
+
+    /// Applies the packed relative relocations of a `DT_RELR` table, see [`RelrDecoder`].
+    ///
+    /// Unlike `R_X86_64_RELATIVE`, the addend is stored in the relocated word.
+    fn apply_relr_relocations(
+        &mut self,
+        relocation_table: u64,
+        total_size: u64,
+        elf_file: &ElfFile,
+    ) -> Result<(), &'static str> {
+        trace!(
+            "Applying {} packed relocation entries of the table at {relocation_table:#x}",
+            total_size / 8
+        );
+        let mut decoder = RelrDecoder::default();
+        for idx in 0..total_size / 8 {
+            let entry_addr = self.virtual_address_offset + (relocation_table + idx * 8);
+            let mut buf = [0; 8];
+            self.copy_from(VirtAddr::new(entry_addr), &mut buf);
+            for offset in decoder.decode(u64::from_ne_bytes(buf)) {
+                self.apply_relr_relocation(offset, elf_file)?;
             }
-            ty => unimplemented!("relocation type {:x} not supported", ty),
         }
+        Ok(())
+    }
 
+    fn apply_relr_relocation(
+        &mut self,
+        offset: u64,
+        elf_file: &ElfFile,
+    ) -> Result<(), &'static str> {
+        check_is_in_load(elf_file, offset)?;
+        let addr = VirtAddr::new(self.virtual_address_offset + offset);
+        let mut buf = [0; 8];
+        self.copy_from(addr, &mut buf);
+        let value = self.virtual_address_offset + u64::from_ne_bytes(buf);
+        unsafe {
+            // SAFETY: We just verified that the address is in a Load segment.
+            self.copy_to(addr, &value.to_ne_bytes());
+        }
         Ok(())
     }
 
@@ -665,6 +825,7 @@ where
     /// need to be writable while applying relocations, but should never be
     /// written to after relocations have been applied.
     fn handle_relro_segment(&mut self, program_header: ProgramHeader) {
+        coverage::hit();
         let start = self.virtual_address_offset + program_header.virtual_addr();
         let end = start + program_header.mem_size();
         let start = VirtAddr::new(start);
@@ -698,6 +859,169 @@ where
     }
 }
 
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_SYMTAB: u64 = 6;
+/// Also the `DT_PLTREL` value of `Rela` PLT relocations.
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_SYMENT: u64 = 11;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+const DT_RELRENT: u64 = 37;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+/// Computes the value that the given relocation writes, or `None` for `R_X86_64_NONE`.
+///
+/// The `symbol_value` closure returns the relocated address of the dynamic symbol with the
+/// given index.
+fn relocation_value(
+    rela: &Rela<u64>,
+    virtual_address_offset: VirtualAddressOffset,
+    symbol_value: impl FnOnce(u32) -> Result<u64, &'static str>,
+) -> Result<Option<u64>, &'static str> {
+    let symbol_idx = rela.get_symbol_table_index();
+    let value = match rela.get_type() {
+        R_X86_64_NONE => {
+            coverage::hit();
+            return Ok(None);
+        }
+        R_X86_64_RELATIVE => {
+            coverage::hit();
+            if symbol_idx != 0 {
+                return Err("R_X86_64_RELATIVE relocation references a symbol");
+            }
+            virtual_address_offset + rela.get_addend()
+        }
+        R_X86_64_64 => {
+            coverage::hit();
+            symbol_value(symbol_idx)?.wrapping_add(rela.get_addend())
+        }
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => {
+            coverage::hit();
+            symbol_value(symbol_idx)?
+        }
+        _ => return Err("unsupported relocation type"),
+    };
+    Ok(Some(value))
+}
+
+/// Returns the relocated address of the given dynamic symbol.
+///
+/// Undefined weak symbols resolve to `0`, other undefined symbols are an error because
+/// there is nothing to link the kernel against.
+fn resolve_symbol(
+    symbol: &DynEntry64,
+    virtual_address_offset: VirtualAddressOffset,
+) -> Result<u64, &'static str> {
+    match symbol.shndx() {
+        SHN_UNDEF if symbol.get_binding()? == Binding::Weak => Ok(0),
+        SHN_UNDEF => Err("relocation references an undefined symbol"),
+        SHN_ABS => Ok(symbol.value()),
+        _ => Ok(virtual_address_offset + symbol.value()),
+    }
+}
+
+/// Decodes the entries of a `DT_RELR` table into the offsets of the words to relocate.
+///
+/// An even entry is the address of the next word to relocate. An odd entry is a bitmap
+/// whose bits 1 to 63 select which of the 63 words after the last relocated address are
+/// relocated too.
+#[derive(Default)]
+struct RelrDecoder {
+    next: u64,
+}
+
+impl RelrDecoder {
+    /// Returns the offsets that the given entry relocates.
+    fn decode(&mut self, entry: u64) -> impl Iterator<Item = u64> {
+        let (base, bitmap) = if entry & 1 == 0 {
+            coverage::hit();
+            self.next = entry.wrapping_add(8);
+            (entry, 1)
+        } else {
+            coverage::hit();
+            let base = self.next;
+            self.next = base.wrapping_add(63 * 8);
+            (base, entry >> 1)
+        };
+        (0..63)
+            .filter(move |bit| bitmap & (1 << bit) != 0)
+            .map(move |bit| base.wrapping_add(bit * 8))
+    }
+}
+
+/// The entries of the dynamic section that describe the relocations.
+#[derive(Default)]
+struct DynamicInfo {
+    rela: Option<u64>,
+    rela_size: Option<u64>,
+    rela_ent: Option<u64>,
+    jmp_rel: Option<u64>,
+    plt_rel: Option<u64>,
+    plt_rel_size: Option<u64>,
+    relr: Option<u64>,
+    relr_size: Option<u64>,
+    relr_ent: Option<u64>,
+    sym_tab: Option<u64>,
+    sym_ent: Option<u64>,
+}
+
+impl DynamicInfo {
+    /// Collects the relocation entries of the given `PT_DYNAMIC` segment.
+    ///
+    /// The entries are read as raw tag and value pairs because xmas-elf rejects tags that
+    /// are newer than it, e.g. `DT_RELR`.
+    fn parse(segment: ProgramHeader, elf_file: &ElfFile) -> Result<Self, &'static str> {
+        let data = elf_file
+            .input
+            .get(segment.offset() as usize..)
+            .and_then(|data| data.get(..segment.file_size() as usize))
+            .ok_or("dynamic segment is outside of the kernel file")?;
+        Self::parse_entries(data)
+    }
+
+    /// Collects the relocation entries of the raw contents of a dynamic section.
+    fn parse_entries(data: &[u8]) -> Result<Self, &'static str> {
+        let mut info = DynamicInfo::default();
+        for entry in data.chunks_exact(16) {
+            let tag = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let value = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            let (slot, name) = match tag {
+                DT_NULL => break,
+                DT_RELA => (&mut info.rela, "Rela"),
+                DT_RELASZ => (&mut info.rela_size, "RelaSize"),
+                DT_RELAENT => (&mut info.rela_ent, "RelaEnt"),
+                DT_JMPREL => (&mut info.jmp_rel, "JmpRel"),
+                DT_PLTREL => (&mut info.plt_rel, "PltRel"),
+                DT_PLTRELSZ => (&mut info.plt_rel_size, "PltRelSize"),
+                DT_RELR => (&mut info.relr, "Relr"),
+                DT_RELRSZ => (&mut info.relr_size, "RelrSize"),
+                DT_RELRENT => (&mut info.relr_ent, "RelrEnt"),
+                DT_SYMTAB => (&mut info.sym_tab, "SymTab"),
+                DT_SYMENT => (&mut info.sym_ent, "SymEnt"),
+                _ => continue,
+            };
+            if slot.replace(value).is_some() {
+                log::error!("Dynamic section contains more than one {name} entry");
+                return Err("Dynamic section contains duplicate entries");
+            }
+        }
+        Ok(info)
+    }
+}
+
 /// Check that the virtual offset belongs to a load segment.
 fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static str> {
     for program_header in elf_file.program_iter() {
@@ -720,15 +1044,58 @@ fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static
 pub fn load_kernel(
     kernel: Kernel<'_>,
     page_table: &mut (impl MapperAllSizes + Translate),
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
     used_entries: &mut UsedLevel4Entries,
+    regions: &mut RegionRegistry,
 ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
-    let mut loader = Loader::new(kernel, page_table, frame_allocator, used_entries)?;
+    let mut loader = Loader::new(kernel, page_table, frame_allocator, used_entries, regions)?;
     let tls_template = loader.load_segments()?;
 
     Ok((loader.entry_point(), tls_template))
 }
 
+/// Returns the offset at which the given kernel is loaded.
+///
+/// Executables are loaded at their link address. Position-independent kernels are placed at
+/// a free, possibly random address of the level 4 page table.
+pub fn virtual_address_offset(
+    elf_file: &ElfFile,
+    used_entries: &mut UsedLevel4Entries,
+) -> VirtualAddressOffset {
+    match elf_file.header.pt2.type_().as_type() {
+        header::Type::None => unimplemented!(),
+        header::Type::Relocatable => unimplemented!(),
+        header::Type::Executable => {
+            coverage::hit();
+            VirtualAddressOffset::zero()
+        }
+        header::Type::SharedObject => {
+            coverage::hit();
+            // Find the highest virtual memory address and the biggest alignment.
+            let load_program_headers = elf_file
+                .program_iter()
+                .filter(|h| matches!(h.get_type(), Ok(Type::Load)));
+            let max_addr = load_program_headers
+                .clone()
+                .map(|h| h.virtual_addr() + h.mem_size())
+                .max()
+                .unwrap_or(0);
+            let min_addr = load_program_headers
+                .clone()
+                .map(|h| h.virtual_addr())
+                .min()
+                .unwrap_or(0);
+            let size = max_addr - min_addr;
+            let align = load_program_headers.map(|h| h.align()).max().unwrap_or(1);
+
+            let offset = used_entries.get_free_kernel_address(size, align).as_u64();
+            VirtualAddressOffset::new(i128::from(offset) - i128::from(min_addr))
+        }
+        header::Type::Core => unimplemented!(),
+        header::Type::ProcessorSpecific(_) => unimplemented!(),
+    }
+}
+
 /// A helper type used to offset virtual addresses for position independent
 /// executables.
 #[derive(Clone, Copy)]
@@ -764,3 +1131,179 @@ impl Add<u64> for VirtualAddressOffset {
         .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    const OFFSET: u64 = 0xffff_8000_0000_0000;
+
+    fn offset() -> VirtualAddressOffset {
+        VirtualAddressOffset::new(i128::from(OFFSET))
+    }
+
+    fn rela(offset: u64, kind: u32, symbol: u32, addend: i64) -> Rela<u64> {
+        let mut bytes = [0; 24];
+        bytes[..8].copy_from_slice(&offset.to_le_bytes());
+        bytes[8..16].copy_from_slice(&(u64::from(symbol) << 32 | u64::from(kind)).to_le_bytes());
+        bytes[16..].copy_from_slice(&addend.to_le_bytes());
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Rela<u64>) }
+    }
+
+    fn symbol(binding: u8, shndx: u16, value: u64) -> DynEntry64 {
+        let mut bytes = [0; 24];
+        bytes[4] = binding << 4;
+        bytes[6..8].copy_from_slice(&shndx.to_le_bytes());
+        bytes[8..16].copy_from_slice(&value.to_le_bytes());
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const DynEntry64) }
+    }
+
+    fn dynamic(entries: &[(u64, u64)]) -> Vec<u8> {
+        entries
+            .iter()
+            .flat_map(|(tag, value)| [tag.to_le_bytes(), value.to_le_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn dynamic_entries() {
+        let data = dynamic(&[
+            (DT_RELA, 0x2000),
+            (DT_RELASZ, 48),
+            (DT_RELAENT, 24),
+            // DT_FLAGS_1 is ignored
+            (0x6fff_fffb, 0x0800_0000),
+            (DT_JMPREL, 0x3000),
+            (DT_PLTREL, DT_RELA),
+            (DT_PLTRELSZ, 24),
+            (DT_RELR, 0x4000),
+            (DT_RELRSZ, 16),
+            (DT_RELRENT, 8),
+            (DT_SYMTAB, 0x1000),
+            (DT_SYMENT, 24),
+            (DT_NULL, 0),
+            // everything after the terminator is ignored
+            (DT_RELA, 0x5000),
+        ]);
+        let info = DynamicInfo::parse_entries(&data).unwrap();
+        assert_eq!(info.rela, Some(0x2000));
+        assert_eq!(info.rela_size, Some(48));
+        assert_eq!(info.rela_ent, Some(24));
+        assert_eq!(info.jmp_rel, Some(0x3000));
+        assert_eq!(info.plt_rel, Some(DT_RELA));
+        assert_eq!(info.plt_rel_size, Some(24));
+        assert_eq!(info.relr, Some(0x4000));
+        assert_eq!(info.relr_size, Some(16));
+        assert_eq!(info.relr_ent, Some(8));
+        assert_eq!(info.sym_tab, Some(0x1000));
+        assert_eq!(info.sym_ent, Some(24));
+    }
+
+    #[test]
+    fn malformed_dynamic_entries() {
+        let data = dynamic(&[(DT_RELR, 0x4000), (DT_RELR, 0x5000)]);
+        assert!(DynamicInfo::parse_entries(&data).is_err());
+
+        // a truncated entry at the end is ignored
+        let mut data = dynamic(&[(DT_SYMTAB, 0x1000)]);
+        data.extend_from_slice(&DT_RELA.to_le_bytes());
+        let info = DynamicInfo::parse_entries(&data).unwrap();
+        assert_eq!(info.sym_tab, Some(0x1000));
+        assert_eq!(info.rela, None);
+    }
+
+    fn value(rela: Rela<u64>) -> Result<Option<u64>, &'static str> {
+        relocation_value(&rela, offset(), |symbol| match symbol {
+            1 => Ok(OFFSET + 0x5000),
+            _ => Err("relocation references an undefined symbol"),
+        })
+    }
+
+    #[test]
+    fn relocation_values() {
+        assert_eq!(value(rela(0x10, R_X86_64_NONE, 0, 0)), Ok(None));
+        assert_eq!(
+            value(rela(0x10, R_X86_64_RELATIVE, 0, 0x1234)),
+            Ok(Some(OFFSET + 0x1234))
+        );
+        assert_eq!(
+            value(rela(0x10, R_X86_64_64, 1, 8)),
+            Ok(Some(OFFSET + 0x5008))
+        );
+        assert_eq!(
+            value(rela(0x10, R_X86_64_64, 1, -8)),
+            Ok(Some(OFFSET + 0x4ff8))
+        );
+        // the addend of GOT and PLT relocations is ignored
+        assert_eq!(
+            value(rela(0x10, R_X86_64_GLOB_DAT, 1, 8)),
+            Ok(Some(OFFSET + 0x5000))
+        );
+        assert_eq!(
+            value(rela(0x10, R_X86_64_JUMP_SLOT, 1, 0)),
+            Ok(Some(OFFSET + 0x5000))
+        );
+    }
+
+    #[test]
+    fn invalid_relocations() {
+        assert_eq!(
+            value(rela(0x10, R_X86_64_RELATIVE, 1, 0)),
+            Err("R_X86_64_RELATIVE relocation references a symbol")
+        );
+        assert_eq!(
+            value(rela(0x10, R_X86_64_64, 2, 0)),
+            Err("relocation references an undefined symbol")
+        );
+        // R_X86_64_PC32
+        assert_eq!(
+            value(rela(0x10, 2, 1, 0)),
+            Err("unsupported relocation type")
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        const GLOBAL: u8 = 1;
+        const WEAK: u8 = 2;
+        assert_eq!(
+            resolve_symbol(&symbol(GLOBAL, 3, 0x5000), offset()),
+            Ok(OFFSET + 0x5000)
+        );
+        assert_eq!(
+            resolve_symbol(&symbol(GLOBAL, SHN_ABS, 0x5000), offset()),
+            Ok(0x5000)
+        );
+        assert_eq!(resolve_symbol(&symbol(WEAK, SHN_UNDEF, 0), offset()), Ok(0));
+        assert_eq!(
+            resolve_symbol(&symbol(GLOBAL, SHN_UNDEF, 0), offset()),
+            Err("relocation references an undefined symbol")
+        );
+    }
+
+    fn relr(entries: &[u64]) -> Vec<u64> {
+        let mut decoder = RelrDecoder::default();
+        entries
+            .iter()
+            .flat_map(|&entry| decoder.decode(entry).collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn relr_entries() {
+        assert_eq!(relr(&[0x1000, 0x2000]), [0x1000, 0x2000]);
+        // bits 1 and 3 select the first and third word after the address
+        assert_eq!(relr(&[0x1000, 0b1011]), [0x1000, 0x1008, 0x1018]);
+        // a second bitmap continues 63 words after the first one
+        assert_eq!(
+            relr(&[0x1000, 1 | 1 << 63, 0b11, 0x3000]),
+            [0x1000, 0x1008 + 62 * 8, 0x1008 + 63 * 8, 0x3000]
+        );
+        assert_eq!(relr(&[0x1000, 1]), [0x1000]);
+        assert_eq!(relr(&[]), vec![]);
+    }
+}
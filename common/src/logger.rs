@@ -1,39 +1,398 @@
-use crate::{framebuffer::FrameBufferWriter, serial::SerialPort};
-use bootloader_api::{config::LoggerStatus, info::FrameBufferInfo};
+use crate::{
+    convert_level,
+    debugcon::DebugCon,
+    error::ErrorReport,
+    framebuffer::FrameBufferWriter,
+    memory_log::MemoryLog,
+    record::{Input, Key},
+    serial::{self, SerialPort},
+    tsc::Clock,
+    virtio_console::VirtioConsole,
+    virtio_net::VirtioNet,
+};
+use bootloader_api::{
+    config::{LevelFilter, LogFormat, LogLevels, LoggerStatus},
+    info::FrameBufferInfo,
+    BootloaderConfig,
+};
 use conquer_once::spin::OnceCell;
-use core::fmt::Write;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
 use spinning_top::Spinlock;
 
 /// The global logger instance used for the `log` crate.
 pub static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
 
-/// A logger instance protected by a spinlock.
+/// The maximum number of sinks, i.e. one per [`Output`] variant.
+const MAX_SINKS: usize = 6;
+
+/// A logger instance that writes to a registry of sinks, each protected by a spinlock.
 pub struct LockedLogger {
-    framebuffer: Option<Spinlock<FrameBufferWriter>>,
-    serial: Option<Spinlock<SerialPort>>,
+    sinks: [Option<Sink>; MAX_SINKS],
+    /// Color the message levels, see [`BootloaderConfig::log_colors`].
+    colors: bool,
+    /// The clock for the message timestamps, see [`BootloaderConfig::log_timestamps`].
+    clock: Option<Clock>,
+}
+
+/// A log output together with the maximum level of the messages it receives.
+struct Sink {
+    /// The [`LevelFilter`] as `u8`, which can be replaced through [`set_levels`].
+    level: AtomicU8,
+    /// The maximum number of messages below `Warn` that are written, see
+    /// [`BootloaderConfig::log_message_limit`].
+    limit: Option<u32>,
+    /// The number of messages below `Warn` that were passed to this sink.
+    limited: AtomicU32,
+    format: LogFormat,
+    output: Spinlock<Output>,
+}
+
+impl Sink {
+    fn level(&self) -> log::LevelFilter {
+        let level = LevelFilter::from_u8(self.level.load(Ordering::Relaxed));
+        convert_level(level.unwrap_or(LevelFilter::Trace))
+    }
+
+    /// Returns whether a message of the given level should be written, counting it against
+    /// the message limit.
+    fn admit(&self, level: log::Level) -> bool {
+        match self.limit {
+            Some(limit) if level > log::Level::Warn => {
+                self.limited.fetch_add(1, Ordering::Relaxed) < limit
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the number of messages that were dropped because of the message limit.
+    fn suppressed(&self) -> u32 {
+        let limit = self.limit.unwrap_or(u32::MAX);
+        self.limited.load(Ordering::Relaxed).saturating_sub(limit)
+    }
+}
+
+/// A boot progress event, which is written to the outputs that use
+/// [`LogFormat::JsonLines`].
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// The bootloader stage with the given name started logging.
+    Stage(&'a str),
+    /// The kernel or ramdisk with the given size was loaded.
+    Loaded { name: &'a str, bytes: u64 },
+    /// The bootloader jumps to the kernel entry point at the given address.
+    Jump { entry_point: u64 },
+    /// The bootloader consumed the given firmware input, see [`record`](crate::record).
+    Input(Input<'a>),
+    /// The peak usage of the stack in bytes, see [`stack`](crate::stack).
+    Stack { used: u64, size: u64 },
+}
+
+/// Replaces the levels of the outputs with the given levels, if the logger was initialized.
+///
+/// Outputs that are disabled in the config stay disabled, and the global
+/// [`BootloaderConfig::log_level`] still applies.
+pub fn set_levels(levels: &LogLevels) {
+    if let Some(logger) = LOGGER.get() {
+        logger.set_levels(levels);
+    }
+}
+
+/// Writes the given event to the outputs that use [`LogFormat::JsonLines`], if the logger
+/// was initialized.
+pub fn event(event: Event) {
+    if let Some(logger) = LOGGER.get() {
+        logger.event(event);
+    }
+}
+
+/// An output that receives the log messages and the error report.
+pub enum Output {
+    /// The pixel-based framebuffer.
+    FrameBuffer(FrameBufferWriter),
+    /// The 16550 UART at the port of [`BootloaderConfig::serial_port`].
+    Serial(SerialPort),
+    /// The first port of a virtio console on the PCI bus.
+    VirtioConsole(VirtioConsole),
+    /// The Bochs/QEMU debug console at port `0xE9`.
+    DebugCon(DebugCon),
+    /// A memory buffer that is passed to the kernel.
+    Memory(MemoryLog),
+    /// UDP broadcasts through a virtio network device on the PCI bus.
+    Network(VirtioNet),
+}
+
+impl Output {
+    /// Shows the error screen on the framebuffer or writes the report as text to the other
+    /// outputs.
+    fn show_error(&mut self, report: &ErrorReport, qr_code: bool, format: LogFormat) {
+        match (self, format) {
+            (Output::FrameBuffer(framebuffer), _) => framebuffer.show_error_screen(report, qr_code),
+            (output, LogFormat::Text) => {
+                let _ = write!(output, "\nBOOT ERROR\n{report}");
+            }
+            (output, LogFormat::JsonLines) => {
+                let _ = output
+                    .write_json_start("error", None)
+                    .and_then(|()| write_json_field(output, "report", format_args!("{report}")))
+                    .and_then(|()| writeln!(output, "}}"));
+            }
+        }
+    }
+
+    /// Writes the opening brace and the `type` and `ms` fields of a JSON object.
+    fn write_json_start(&mut self, ty: &str, ms: Option<u64>) -> fmt::Result {
+        write!(self, "{{\"type\":\"{ty}\"")?;
+        if let Some(ms) = ms {
+            write!(self, ",\"ms\":{ms}")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a log message as a JSON object on a single line.
+    fn write_json_record(&mut self, record: &log::Record, ms: Option<u64>) -> fmt::Result {
+        self.write_json_start("log", ms)?;
+        write!(self, ",\"level\":\"{}\"", record.level())?;
+        write_json_field(self, "target", format_args!("{}", record.target()))?;
+        write_json_field(self, "msg", *record.args())?;
+        writeln!(self, "}}")
+    }
+
+    /// Writes an event as a JSON object on a single line.
+    fn write_json_event(&mut self, event: Event, ms: Option<u64>) -> fmt::Result {
+        self.write_json_start("event", ms)?;
+        match event {
+            Event::Stage(name) => {
+                write!(self, ",\"event\":\"stage\"")?;
+                write_json_field(self, "name", format_args!("{name}"))?;
+            }
+            Event::Loaded { name, bytes } => {
+                write!(self, ",\"event\":\"loaded\"")?;
+                write_json_field(self, "name", format_args!("{name}"))?;
+                write!(self, ",\"bytes\":{bytes}")?;
+            }
+            Event::Jump { entry_point } => {
+                write!(self, ",\"event\":\"jump\",\"entry_point\":{entry_point}")?;
+            }
+            Event::Input(input) => {
+                write!(self, ",\"event\":\"input\"")?;
+                self.write_json_input(input)?;
+            }
+            Event::Stack { used, size } => {
+                write!(self, ",\"event\":\"stack\",\"used\":{used},\"size\":{size}")?;
+            }
+        }
+        writeln!(self, "}}")
+    }
+
+    /// Writes the `input` field and the values of a recorded input.
+    fn write_json_input(&mut self, input: Input) -> fmt::Result {
+        match input {
+            Input::MemoryRegion {
+                start,
+                len,
+                kind,
+                usable_after_exit,
+            } => {
+                write!(
+                    self,
+                    ",\"input\":\"memory-region\",\"start\":{start},\"len\":{len}"
+                )?;
+                write_json_field(self, "kind", format_args!("{kind:?}"))?;
+                write!(self, ",\"usable_after_exit\":{usable_after_exit}")
+            }
+            Input::FirstFrame(addr) => write!(self, ",\"input\":\"first-frame\",\"addr\":{addr}"),
+            Input::FrameBuffer { addr, byte_len } => write!(
+                self,
+                ",\"input\":\"framebuffer\",\"addr\":{addr},\"byte_len\":{byte_len}"
+            ),
+            Input::File {
+                name,
+                bytes,
+                sha256,
+            } => {
+                write!(self, ",\"input\":\"file\"")?;
+                write_json_field(self, "name", format_args!("{name}"))?;
+                write!(self, ",\"bytes\":{bytes},\"sha256\":\"")?;
+                for byte in sha256 {
+                    write!(self, "{byte:02x}")?;
+                }
+                write!(self, "\"")
+            }
+            Input::ExtraMapping { virt_start, len } => write!(
+                self,
+                ",\"input\":\"extra-mapping\",\"virt_start\":{virt_start},\"len\":{len}"
+            ),
+            Input::Key(Key::Char(c)) => {
+                write!(self, ",\"input\":\"key\"")?;
+                write_json_field(self, "key", format_args!("{c}"))
+            }
+            Input::Key(Key::ScanCode(code)) => {
+                write!(self, ",\"input\":\"key\",\"scan_code\":{code}")
+            }
+            Input::RngSeed(seed) => {
+                write!(self, ",\"input\":\"rng-seed\",\"seed\":\"")?;
+                for byte in seed {
+                    write!(self, "{byte:02x}")?;
+                }
+                write!(self, "\"")
+            }
+        }
+    }
+
+    /// Writes the timestamp and level that precede a log message.
+    fn write_prefix(&mut self, level: log::Level, colors: bool, ms: Option<u64>) -> fmt::Result {
+        if let Some(ms) = ms {
+            write!(self, "[{:6}.{:03}] ", ms / 1000, ms % 1000)?;
+        }
+        match self {
+            _ if !colors => write!(self, "{level:5}"),
+            Output::Memory(memory) => write!(memory, "{level:5}"),
+            Output::FrameBuffer(framebuffer) => {
+                framebuffer.set_color(Some(level_rgb(level)));
+                let result = write!(framebuffer, "{level:5}");
+                framebuffer.set_color(None);
+                result
+            }
+            output => write!(output, "\x1b[{}m{level:5}\x1b[0m", level_ansi(level)),
+        }
+    }
+}
+
+/// Writes a string field with the given name and formatted value to a JSON object.
+fn write_json_field(output: &mut Output, name: &str, value: fmt::Arguments) -> fmt::Result {
+    write!(output, ",\"{name}\":\"")?;
+    JsonEscape(output).write_fmt(value)?;
+    write!(output, "\"")
+}
+
+/// Escapes the quotes, backslashes, and control characters of a JSON string.
+struct JsonEscape<'a>(&'a mut Output);
+
+impl fmt::Write for JsonEscape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if c.is_control() => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The RGB color of the given level on the framebuffer.
+fn level_rgb(level: log::Level) -> [u8; 3] {
+    match level {
+        log::Level::Error => [0xff, 0x40, 0x40],
+        log::Level::Warn => [0xff, 0xd0, 0x00],
+        log::Level::Info => [0x40, 0xe0, 0x40],
+        log::Level::Debug => [0x40, 0xd0, 0xff],
+        log::Level::Trace => [0xa0, 0xa0, 0xa0],
+    }
+}
+
+/// The ANSI foreground color code of the given level.
+fn level_ansi(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 31,
+        log::Level::Warn => 33,
+        log::Level::Info => 32,
+        log::Level::Debug => 36,
+        log::Level::Trace => 90,
+    }
+}
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Output::FrameBuffer(framebuffer) => framebuffer.write_str(s),
+            Output::Serial(serial) => serial.write_str(s),
+            Output::VirtioConsole(virtio_console) => virtio_console.write_str(s),
+            Output::DebugCon(debugcon) => debugcon.write_str(s),
+            Output::Memory(memory) => memory.write_str(s),
+            Output::Network(network) => network.write_str(s),
+        }
+    }
 }
 
 impl LockedLogger {
-    /// Create a new instance that logs to the given framebuffer.
+    /// Create a new instance that logs to the given framebuffer and to the other outputs
+    /// that are enabled in the config.
     pub fn new(
         framebuffer: &'static mut [u8],
         info: FrameBufferInfo,
-        frame_buffer_logger_status: LoggerStatus,
-        serial_logger_status: LoggerStatus,
+        config: &BootloaderConfig,
     ) -> Self {
-        let framebuffer = match frame_buffer_logger_status {
-            LoggerStatus::Enable => Some(Spinlock::new(FrameBufferWriter::new(framebuffer, info))),
-            LoggerStatus::Disable => None,
-        };
-
-        let serial = match serial_logger_status {
-            LoggerStatus::Enable => Some(Spinlock::new(SerialPort::new())),
-            LoggerStatus::Disable => None,
-        };
+        serial::configure(config);
+        let levels = config.log_levels;
+        let limit = config.log_message_limit;
+        let sinks = [
+            sink(
+                config.frame_buffer_logger_status,
+                levels.frame_buffer,
+                limit,
+                LogFormat::Text,
+                move || {
+                    Some(Output::FrameBuffer(FrameBufferWriter::new(
+                        framebuffer,
+                        info,
+                        config.accessible_display,
+                    )))
+                },
+            ),
+            sink(
+                config.serial_logger_status,
+                levels.serial,
+                limit,
+                config.serial_log_format,
+                || Some(Output::Serial(SerialPort::new())),
+            ),
+            sink(
+                config.virtio_console_logger_status,
+                levels.virtio_console,
+                None,
+                LogFormat::Text,
+                || VirtioConsole::probe().map(Output::VirtioConsole),
+            ),
+            sink(
+                config.debugcon_logger_status,
+                levels.debugcon,
+                None,
+                LogFormat::Text,
+                || Some(Output::DebugCon(DebugCon::new())),
+            ),
+            sink(
+                config.memory_logger_status,
+                levels.memory,
+                None,
+                LogFormat::Text,
+                || MemoryLog::new().map(Output::Memory),
+            ),
+            sink(
+                config.network_logger_status,
+                levels.network,
+                None,
+                LogFormat::Text,
+                || VirtioNet::probe().map(Output::Network),
+            ),
+        ];
 
         LockedLogger {
-            framebuffer,
-            serial,
+            sinks,
+            colors: config.log_colors,
+            clock: if config.log_timestamps {
+                Clock::start()
+            } else {
+                None
+            },
         }
     }
 
@@ -42,28 +401,140 @@ impl LockedLogger {
     /// ## Safety
     /// This method is not memory safe and should be only used when absolutely necessary.
     pub unsafe fn force_unlock(&self) {
-        if let Some(framebuffer) = &self.framebuffer {
-            unsafe { framebuffer.force_unlock() };
+        for sink in self.sinks() {
+            unsafe { sink.output.force_unlock() };
+        }
+    }
+
+    /// Replaces the levels of the outputs, see [`set_levels`].
+    pub fn set_levels(&self, levels: &LogLevels) {
+        for sink in self.sinks() {
+            let level = match &*sink.output.lock() {
+                Output::FrameBuffer(_) => levels.frame_buffer,
+                Output::Serial(_) => levels.serial,
+                Output::VirtioConsole(_) => levels.virtio_console,
+                Output::DebugCon(_) => levels.debugcon,
+                Output::Memory(_) => levels.memory,
+                Output::Network(_) => levels.network,
+            };
+            sink.level.store(level as u8, Ordering::Relaxed);
+        }
+    }
+
+    fn sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.sinks.iter().flatten()
+    }
+
+    fn elapsed_ms(&self) -> Option<u64> {
+        self.clock.as_ref().map(Clock::elapsed_ms)
+    }
+
+    /// Returns the number of bytes in the memory log, if it is enabled.
+    pub fn memory_log_len(&self) -> Option<usize> {
+        self.sinks().find_map(|sink| match &*sink.output.lock() {
+            Output::Memory(memory) => Some(memory.len()),
+            _ => None,
+        })
+    }
+
+    /// Prints the number of messages that were dropped because of the message limit to each
+    /// output that dropped any.
+    pub fn log_suppressed_messages(&self) {
+        for sink in self.sinks() {
+            let suppressed = sink.suppressed();
+            if suppressed > 0 {
+                let mut output = sink.output.lock();
+                let ms = self.elapsed_ms();
+                let _ = match sink.format {
+                    LogFormat::Text => output
+                        .write_prefix(log::Level::Info, self.colors, ms)
+                        .and_then(|()| {
+                            writeln!(
+                                output,
+                                ": Suppressed {suppressed} log messages on this output"
+                            )
+                        }),
+                    LogFormat::JsonLines => output.write_json_start("event", ms).and_then(|()| {
+                        writeln!(output, ",\"event\":\"suppressed\",\"count\":{suppressed}}}")
+                    }),
+                };
+            }
         }
-        if let Some(serial) = &self.serial {
-            unsafe { serial.force_unlock() };
+    }
+
+    /// Writes the given event to the outputs that use [`LogFormat::JsonLines`].
+    pub fn event(&self, event: Event) {
+        let ms = self.elapsed_ms();
+        for sink in self.sinks() {
+            if sink.format == LogFormat::JsonLines {
+                let _ = sink.output.lock().write_json_event(event, ms);
+            }
+        }
+    }
+
+    /// Copies the most recent content of the memory log to `dst`, if it is enabled.
+    pub fn copy_memory_log<'a>(&self, dst: &'a mut [u8]) -> Option<&'a str> {
+        for sink in self.sinks() {
+            if let Output::Memory(memory) = &*sink.output.lock() {
+                return Some(memory.copy_to(dst));
+            }
+        }
+        None
+    }
+}
+
+/// Creates a sink if it is enabled.
+///
+/// Sinks with the level `Off` are created as well, since [`set_levels`] might enable them.
+fn sink(
+    status: LoggerStatus,
+    level: LevelFilter,
+    limit: Option<u16>,
+    format: LogFormat,
+    output: impl FnOnce() -> Option<Output>,
+) -> Option<Sink> {
+    match status {
+        LoggerStatus::Disable => None,
+        LoggerStatus::Enable => output().map(|output| Sink {
+            level: AtomicU8::new(level as u8),
+            limit: limit.map(u32::from),
+            limited: AtomicU32::new(0),
+            format,
+            output: Spinlock::new(output),
+        }),
+    }
+}
+
+impl LockedLogger {
+    /// Replaces the framebuffer content with an error screen for the given report and writes
+    /// the report to the other outputs.
+    pub fn show_error_screen(&self, report: &ErrorReport, qr_code: bool) {
+        for sink in self.sinks() {
+            sink.output.lock().show_error(report, qr_code, sink.format);
         }
     }
 }
 
 impl log::Log for LockedLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.sinks().any(|sink| metadata.level() <= sink.level())
     }
 
     fn log(&self, record: &log::Record) {
-        if let Some(framebuffer) = &self.framebuffer {
-            let mut framebuffer = framebuffer.lock();
-            writeln!(framebuffer, "{:5}: {}", record.level(), record.args()).unwrap();
-        }
-        if let Some(serial) = &self.serial {
-            let mut serial = serial.lock();
-            writeln!(serial, "{:5}: {}", record.level(), record.args()).unwrap();
+        let ms = self.elapsed_ms();
+        for sink in self.sinks() {
+            if record.level() <= sink.level() && sink.admit(record.level()) {
+                let mut output = sink.output.lock();
+                match sink.format {
+                    LogFormat::Text => {
+                        output
+                            .write_prefix(record.level(), self.colors, ms)
+                            .unwrap();
+                        writeln!(output, ": {}", record.args()).unwrap();
+                    }
+                    LogFormat::JsonLines => output.write_json_record(record, ms).unwrap(),
+                }
+            }
         }
     }
 
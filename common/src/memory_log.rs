@@ -0,0 +1,71 @@
+use core::{
+    cmp, fmt,
+    ptr::addr_of_mut,
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The number of most recent log bytes that are kept.
+const MEMORY_LOG_SIZE: usize = 16 * 1024;
+
+static mut BUFFER: [u8; MEMORY_LOG_SIZE] = [0; MEMORY_LOG_SIZE];
+/// Ensures that [`BUFFER`] is only handed out once.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A ring buffer that keeps the most recent log output, so that it can be passed to the
+/// kernel.
+pub struct MemoryLog {
+    buffer: &'static mut [u8; MEMORY_LOG_SIZE],
+    /// The total number of bytes written so far.
+    written: usize,
+}
+
+impl MemoryLog {
+    /// Creates the memory log, or returns `None` if it was created before.
+    pub fn new() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Self {
+            buffer: unsafe { &mut *addr_of_mut!(BUFFER) },
+            written: 0,
+        })
+    }
+
+    /// Returns the number of bytes currently stored in the log.
+    pub fn len(&self) -> usize {
+        cmp::min(self.written, MEMORY_LOG_SIZE)
+    }
+
+    /// Returns `true` if nothing was logged yet.
+    pub fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+
+    /// Copies the most recent log output that fits into `dst`.
+    ///
+    /// Leading bytes of a character that was cut off by the ring buffer are skipped, so the
+    /// returned string might be shorter than `dst`.
+    pub fn copy_to<'a>(&self, dst: &'a mut [u8]) -> &'a str {
+        let len = cmp::min(self.len(), dst.len());
+        let start = self.written - len;
+        for (i, byte) in dst[..len].iter_mut().enumerate() {
+            *byte = self.buffer[(start + i) % MEMORY_LOG_SIZE];
+        }
+        let skip = dst[..len]
+            .iter()
+            .take_while(|&&byte| byte & 0b1100_0000 == 0b1000_0000)
+            .count();
+        str::from_utf8(&dst[skip..len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for MemoryLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.written % MEMORY_LOG_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use crate::error::BootError;
+use bootloader_api::config::Language;
+
+/// The translated messages of the error screen in one language.
+pub struct Catalog {
+    /// The heading of the error screen.
+    pub title: &'static str,
+    /// The label of the error code.
+    pub error_code: &'static str,
+    /// The label of the boot stage.
+    pub stage: &'static str,
+    /// The label of the error description.
+    pub error: &'static str,
+    /// The label of the additional details.
+    pub details: &'static str,
+    /// The heading of the support contact information.
+    pub support: &'static str,
+    /// Tells the user how to reboot or power off the system.
+    pub power: &'static str,
+    /// The error descriptions, indexed by [`BootError::index`].
+    descriptions: [&'static str; BootError::COUNT],
+    /// The hints, indexed by [`BootError::index`].
+    hints: [&'static str; BootError::COUNT],
+}
+
+impl Catalog {
+    /// Returns the catalog for the given language.
+    pub fn get(language: Language) -> &'static Catalog {
+        match language {
+            Language::English => &ENGLISH,
+            Language::German => &GERMAN,
+            Language::French => &FRENCH,
+            Language::Spanish => &SPANISH,
+        }
+    }
+
+    /// Returns the translated description of the given error.
+    pub fn description(&self, error: &BootError) -> &'static str {
+        self.descriptions[error.index()]
+    }
+
+    /// Returns the translated hint on how to resolve the given error.
+    pub fn hint(&self, error: &BootError) -> &'static str {
+        self.hints[error.index()]
+    }
+}
+
+/// The English catalog, which is also used for the serial output of the QR code summary.
+pub static ENGLISH: Catalog = Catalog {
+    title: "The bootloader failed to start the kernel",
+    error_code: "Error code",
+    stage: "Stage",
+    error: "Error",
+    details: "Details",
+    support: "Support",
+    power: "Press R to reboot or P to power off.",
+    descriptions: [
+        "internal bootloader error",
+        "kernel executable not found",
+        "invalid kernel executable",
+        "kernel has no bootloader config section",
+        "incompatible bootloader config",
+        "failed to load kernel",
+        "out of memory while allocating",
+        "failed to map",
+        "overlapping memory regions",
+        "no physical memory regions found",
+        "failed to exit boot services",
+        "unsupported framebuffer",
+        "network load deadline exceeded",
+        "kernel is older than the minimum security version",
+        "untrusted kernel",
+    ],
+    hints: [
+        "This is a bug in the bootloader, please report it.",
+        "Check that the boot image was created with a kernel executable.",
+        "Check that the kernel is a statically linked x86_64 ELF executable.",
+        "Use the `entry_point` macro of `bootloader_api` to define the kernel entry point.",
+        "Compile the kernel against the `bootloader_api` version of this bootloader.",
+        "Check that the kernel is a statically linked x86_64 ELF executable.",
+        "Provide more memory or reduce the size of the kernel, ramdisk, and heap.",
+        "Check the fixed addresses in the `mappings` config of the kernel.",
+        "Check the fixed addresses in the `mappings` config of the kernel.",
+        "The firmware might be incompatible, try updating it.",
+        "The firmware might be incompatible, try updating it.",
+        "Try a different display mode or disable the framebuffer logger.",
+        "Check the network and the TFTP server, or place a copy of the file on a local disk.",
+        "Install a kernel with the current or a newer security version.",
+        "Sign the kernel with a trusted key, or enable developer mode for developer keys.",
+    ],
+};
+
+static GERMAN: Catalog = Catalog {
+    title: "Der Bootloader konnte den Kernel nicht starten",
+    error_code: "Fehlercode",
+    stage: "Phase",
+    error: "Fehler",
+    details: "Details",
+    support: "Support",
+    power: "Drücken Sie R zum Neustarten oder P zum Ausschalten.",
+    descriptions: [
+        "interner Fehler des Bootloaders",
+        "Kernel-Programmdatei nicht gefunden",
+        "ungültige Kernel-Programmdatei",
+        "Kernel enthält keine Bootloader-Konfiguration",
+        "inkompatible Bootloader-Konfiguration",
+        "Kernel konnte nicht geladen werden",
+        "nicht genügend Speicher für",
+        "Zuordnung fehlgeschlagen für",
+        "überlappende Speicherbereiche",
+        "keine physischen Speicherbereiche gefunden",
+        "Boot-Services konnten nicht beendet werden",
+        "nicht unterstützter Framebuffer",
+        "Zeitlimit für das Laden über das Netzwerk überschritten",
+        "Kernel ist älter als die minimale Sicherheitsversion",
+        "nicht vertrauenswürdiger Kernel",
+    ],
+    hints: [
+        "Dies ist ein Fehler im Bootloader, bitte melden Sie ihn.",
+        "Prüfen Sie, ob das Boot-Image mit einem Kernel erstellt wurde.",
+        "Prüfen Sie, ob der Kernel eine statisch gelinkte x86_64-ELF-Datei ist.",
+        "Definieren Sie den Einsprungpunkt mit dem `entry_point`-Makro von `bootloader_api`.",
+        "Kompilieren Sie den Kernel mit der `bootloader_api`-Version dieses Bootloaders.",
+        "Prüfen Sie, ob der Kernel eine statisch gelinkte x86_64-ELF-Datei ist.",
+        "Stellen Sie mehr Speicher bereit oder verkleinern Sie Kernel, Ramdisk und Heap.",
+        "Prüfen Sie die festen Adressen in der `mappings`-Konfiguration des Kernels.",
+        "Prüfen Sie die festen Adressen in der `mappings`-Konfiguration des Kernels.",
+        "Die Firmware ist möglicherweise inkompatibel, versuchen Sie ein Update.",
+        "Die Firmware ist möglicherweise inkompatibel, versuchen Sie ein Update.",
+        "Wählen Sie einen anderen Anzeigemodus oder deaktivieren Sie die Bildschirmausgabe.",
+        "Prüfen Sie Netzwerk und TFTP-Server oder legen Sie die Datei lokal ab.",
+        "Installieren Sie einen Kernel mit der aktuellen oder einer neueren Sicherheitsversion.",
+        "Signieren Sie den Kernel mit einem vertrauenswürdigen Schlüssel.",
+    ],
+};
+
+static FRENCH: Catalog = Catalog {
+    title: "Le chargeur d'amorçage n'a pas pu démarrer le noyau",
+    error_code: "Code d'erreur",
+    stage: "Étape",
+    error: "Erreur",
+    details: "Détails",
+    support: "Assistance",
+    power: "Appuyez sur R pour redémarrer ou sur P pour éteindre.",
+    descriptions: [
+        "erreur interne du chargeur d'amorçage",
+        "exécutable du noyau introuvable",
+        "exécutable du noyau invalide",
+        "le noyau n'a pas de section de configuration",
+        "configuration du chargeur incompatible",
+        "échec du chargement du noyau",
+        "mémoire insuffisante pour",
+        "échec du mappage de",
+        "régions de mémoire qui se chevauchent",
+        "aucune région de mémoire physique trouvée",
+        "impossible de quitter les services de démarrage",
+        "framebuffer non pris en charge",
+        "délai de chargement réseau dépassé",
+        "le noyau est plus ancien que la version de sécurité minimale",
+        "noyau non approuvé",
+    ],
+    hints: [
+        "Il s'agit d'un bogue du chargeur d'amorçage, veuillez le signaler.",
+        "Vérifiez que l'image de démarrage contient un exécutable du noyau.",
+        "Vérifiez que le noyau est un exécutable ELF x86_64 lié statiquement.",
+        "Définissez le point d'entrée avec la macro `entry_point` de `bootloader_api`.",
+        "Compilez le noyau avec la version de `bootloader_api` de ce chargeur.",
+        "Vérifiez que le noyau est un exécutable ELF x86_64 lié statiquement.",
+        "Ajoutez de la mémoire ou réduisez la taille du noyau, du ramdisk et du tas.",
+        "Vérifiez les adresses fixes de la configuration `mappings` du noyau.",
+        "Vérifiez les adresses fixes de la configuration `mappings` du noyau.",
+        "Le micrologiciel est peut-être incompatible, essayez de le mettre à jour.",
+        "Le micrologiciel est peut-être incompatible, essayez de le mettre à jour.",
+        "Essayez un autre mode d'affichage ou désactivez la sortie à l'écran.",
+        "Vérifiez le réseau et le serveur TFTP, ou copiez le fichier sur un disque local.",
+        "Installez un noyau avec la version de sécurité actuelle ou une version plus récente.",
+        "Signez le noyau avec une clé approuvée.",
+    ],
+};
+
+static SPANISH: Catalog = Catalog {
+    title: "El cargador de arranque no pudo iniciar el núcleo",
+    error_code: "Código de error",
+    stage: "Etapa",
+    error: "Error",
+    details: "Detalles",
+    support: "Soporte",
+    power: "Pulse R para reiniciar o P para apagar.",
+    descriptions: [
+        "error interno del cargador de arranque",
+        "no se encontró el ejecutable del núcleo",
+        "ejecutable del núcleo no válido",
+        "el núcleo no tiene sección de configuración",
+        "configuración del cargador incompatible",
+        "no se pudo cargar el núcleo",
+        "memoria insuficiente para",
+        "no se pudo mapear",
+        "regiones de memoria superpuestas",
+        "no se encontraron regiones de memoria física",
+        "no se pudieron finalizar los servicios de arranque",
+        "framebuffer no compatible",
+        "se superó el plazo de carga por red",
+        "el núcleo es anterior a la versión de seguridad mínima",
+        "núcleo no confiable",
+    ],
+    hints: [
+        "Es un error del cargador de arranque, por favor notifíquelo.",
+        "Compruebe que la imagen de arranque contiene un ejecutable del núcleo.",
+        "Compruebe que el núcleo es un ejecutable ELF x86_64 enlazado estáticamente.",
+        "Defina el punto de entrada con la macro `entry_point` de `bootloader_api`.",
+        "Compile el núcleo con la versión de `bootloader_api` de este cargador.",
+        "Compruebe que el núcleo es un ejecutable ELF x86_64 enlazado estáticamente.",
+        "Añada memoria o reduzca el tamaño del núcleo, del ramdisk y del heap.",
+        "Compruebe las direcciones fijas de la configuración `mappings` del núcleo.",
+        "Compruebe las direcciones fijas de la configuración `mappings` del núcleo.",
+        "El firmware podría ser incompatible, intente actualizarlo.",
+        "El firmware podría ser incompatible, intente actualizarlo.",
+        "Pruebe otro modo de pantalla o desactive la salida en pantalla.",
+        "Compruebe la red y el servidor TFTP, o copie el archivo en un disco local.",
+        "Instale un núcleo con la versión de seguridad actual o una más reciente.",
+        "Firme el núcleo con una clave de confianza.",
+    ],
+};
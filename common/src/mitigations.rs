@@ -0,0 +1,81 @@
+use bootloader_api::{info::CpuState, BootloaderConfig};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+
+/// The `IBPB` command of the `IA32_PRED_CMD` MSR.
+const PRED_CMD_IBPB: u64 = 1;
+
+// CPUID.(EAX=7,ECX=0):EDX
+const CPUID_7_IBRS_IBPB: u32 = 1 << 26;
+const CPUID_7_STIBP: u32 = 1 << 27;
+const CPUID_7_ARCH_CAPABILITIES: u32 = 1 << 29;
+const CPUID_7_SSBD: u32 = 1 << 31;
+
+// CPUID.(EAX=0x8000_0008):EBX
+const CPUID_8000_0008_IBPB: u32 = 1 << 12;
+const CPUID_8000_0008_IBRS: u32 = 1 << 14;
+const CPUID_8000_0008_STIBP: u32 = 1 << 15;
+const CPUID_8000_0008_SSBD: u32 = 1 << 24;
+
+/// Applies the `spec_ctrl` and `indirect_branch_barrier` config options and returns the
+/// resulting speculation control state.
+///
+/// Should be called right before entering the kernel, so that the indirect branch
+/// prediction barrier also covers the branches of the bootloader.
+pub fn apply(config: &BootloaderConfig) -> CpuState {
+    let cpuid_7_edx = if unsafe { __cpuid(0) }.eax >= 7 {
+        unsafe { __cpuid_count(7, 0) }.edx
+    } else {
+        0
+    };
+    let cpuid_8000_0008_ebx = if unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0008 {
+        unsafe { __cpuid(0x8000_0008) }.ebx
+    } else {
+        0
+    };
+
+    let spec_ctrl_supported = cpuid_7_edx & (CPUID_7_IBRS_IBPB | CPUID_7_STIBP | CPUID_7_SSBD) != 0
+        || cpuid_8000_0008_ebx
+            & (CPUID_8000_0008_IBRS | CPUID_8000_0008_STIBP | CPUID_8000_0008_SSBD)
+            != 0;
+    let pred_cmd_supported =
+        cpuid_7_edx & CPUID_7_IBRS_IBPB != 0 || cpuid_8000_0008_ebx & CPUID_8000_0008_IBPB != 0;
+
+    let mut spec_ctrl = Msr::new(IA32_SPEC_CTRL);
+    if let Some(value) = config.spec_ctrl {
+        if spec_ctrl_supported {
+            log::info!("Setting IA32_SPEC_CTRL to {value:#x}");
+            unsafe { spec_ctrl.write(value) };
+        } else {
+            log::warn!("Ignoring `spec_ctrl` config: the CPU doesn't support IA32_SPEC_CTRL");
+        }
+    }
+
+    let arch_capabilities = (cpuid_7_edx & CPUID_7_ARCH_CAPABILITIES != 0)
+        .then(|| unsafe { Msr::new(IA32_ARCH_CAPABILITIES).read() });
+    let spec_ctrl = spec_ctrl_supported.then(|| unsafe { spec_ctrl.read() });
+
+    let indirect_branch_barrier = config.indirect_branch_barrier && pred_cmd_supported;
+    if config.indirect_branch_barrier && !pred_cmd_supported {
+        log::warn!("Ignoring `indirect_branch_barrier` config: the CPU doesn't support IBPB");
+    }
+
+    let state = CpuState {
+        cpuid_7_edx,
+        cpuid_8000_0008_ebx,
+        arch_capabilities: arch_capabilities.into(),
+        spec_ctrl: spec_ctrl.into(),
+        indirect_branch_barrier,
+    };
+    log::info!("CPU mitigation state: {state:x?}");
+
+    if indirect_branch_barrier {
+        unsafe { Msr::new(IA32_PRED_CMD).write(PRED_CMD_IBPB) };
+    }
+
+    state
+}
@@ -0,0 +1,56 @@
+use bootloader_api::info::MsrState;
+use core::arch::x86_64::__cpuid;
+use x86_64::registers::model_specific::Msr;
+
+const IA32_FEATURE_CONTROL: u32 = 0x3a;
+const IA32_MTRRCAP: u32 = 0xfe;
+const VM_CR: u32 = 0xc001_0114;
+
+// CPUID.1:ECX
+const CPUID_1_VMX: u32 = 1 << 5;
+const CPUID_1_SMX: u32 = 1 << 6;
+// CPUID.1:EDX
+const CPUID_1_MTRR: u32 = 1 << 12;
+// CPUID.8000_0001:ECX
+const CPUID_8000_0001_SVM: u32 = 1 << 2;
+
+/// Reads the virtualization and SMM related MSRs and logs problems that prevent the kernel
+/// from using hardware virtualization.
+pub fn detect() -> MsrState {
+    let cpuid_1 = unsafe { __cpuid(1) };
+    let svm_supported = unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0001
+        && unsafe { __cpuid(0x8000_0001) }.ecx & CPUID_8000_0001_SVM != 0;
+    let vmx_supported = cpuid_1.ecx & CPUID_1_VMX != 0;
+
+    // the MSRs only exist if the corresponding features are supported
+    let feature_control = (cpuid_1.ecx & (CPUID_1_VMX | CPUID_1_SMX) != 0)
+        .then(|| unsafe { Msr::new(IA32_FEATURE_CONTROL).read() });
+    let vm_cr = svm_supported.then(|| unsafe { Msr::new(VM_CR).read() });
+    let mtrr_cap =
+        (cpuid_1.edx & CPUID_1_MTRR != 0).then(|| unsafe { Msr::new(IA32_MTRRCAP).read() });
+
+    let state = MsrState {
+        vmx_supported,
+        svm_supported,
+        feature_control: feature_control.into(),
+        vm_cr: vm_cr.into(),
+        mtrr_cap: mtrr_cap.into(),
+    };
+    log::info!("MSR state: {state:x?}");
+    log::info!(
+        "IA32_FEATURE_CONTROL locked: {}, SMRR supported: {}",
+        state.feature_control_locked(),
+        state.smrr_supported()
+    );
+    if vmx_supported && !state.vmx_usable() {
+        log::warn!(
+            "VMX is disabled and locked in IA32_FEATURE_CONTROL, so VMXON will fail; \
+            enable virtualization in the firmware setup"
+        );
+    }
+    if svm_supported && !state.svm_usable() {
+        log::warn!("SVM is disabled in VM_CR; enable virtualization in the firmware setup");
+    }
+
+    state
+}
@@ -0,0 +1,324 @@
+//! The disk image builder copies the header of every encrypted partition into the
+//! `partition-keys` file of the boot partition, so that the BIOS stages don't have to read
+//! other partitions. Each record of the file consists of the partition number as
+//! little-endian `u32`, four reserved bytes, and the first [`HEADER_LEN`] bytes of the
+//! header. See `src/bios/encryption.rs` of the `bootloader` crate for the header layout.
+
+use crate::power::{self, Input};
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes256,
+};
+use bootloader_api::info::{PartitionKey, PartitionKeys};
+use hmac::Hmac;
+use sha2::{Digest, Sha256};
+use xts_mode::{get_tweak_default, Xts128};
+
+/// The length of the header part that is copied into the `partition-keys` file.
+pub const HEADER_LEN: usize = 160;
+/// The length of a record of the `partition-keys` file.
+pub const RECORD_LEN: usize = 8 + HEADER_LEN;
+/// The maximum length of a passphrase.
+pub const MAX_PASSPHRASE_LEN: usize = 128;
+
+const MAGIC: &[u8; 8] = b"BLCRYPT1";
+const HEADER_VERSION: u16 = 1;
+const CIPHER_AES_256_XTS: u16 = 1;
+/// Limits the time that a crafted header can make the bootloader spend in the key derivation.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// The header of an encrypted partition.
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptedPartition<'a> {
+    partition_number: u32,
+    header: &'a [u8; HEADER_LEN],
+}
+
+impl<'a> EncryptedPartition<'a> {
+    /// Returns the one-based index of the partition in the MBR partition table.
+    pub fn partition_number(&self) -> u32 {
+        self.partition_number
+    }
+
+    /// Derives the master key of the partition from the given passphrase.
+    ///
+    /// Returns `None` if the passphrase is wrong.
+    pub fn unlock(&self, passphrase: &[u8]) -> Option<PartitionKey> {
+        let header = self.header;
+        let iterations = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let salt = &header[16..48];
+
+        let mut derived_key = [0; 64];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, salt, iterations, &mut derived_key);
+        let mut master_key = [0; 64];
+        master_key.copy_from_slice(&header[64..128]);
+        xts_cipher(&derived_key).decrypt_sector(&mut master_key, get_tweak_default(0));
+
+        let hash = Sha256::new()
+            .chain_update(master_key)
+            .chain_update(salt)
+            .finalize();
+        if hash[..] != header[128..160] {
+            return None;
+        }
+        Some(PartitionKey {
+            partition_number: self.partition_number,
+            payload_offset: u32::from_le_bytes(header[48..52].try_into().unwrap()),
+            payload_sectors: u64::from_le_bytes(header[52..60].try_into().unwrap()),
+            master_key,
+        })
+    }
+}
+
+/// Parses the `partition-keys` file.
+pub fn parse(file: &[u8]) -> Result<impl Iterator<Item = EncryptedPartition<'_>>, &'static str> {
+    if file.len() % RECORD_LEN != 0 {
+        return Err("truncated record");
+    }
+    let records = file.chunks_exact(RECORD_LEN);
+    for record in records.clone() {
+        let header = &record[8..];
+        if &header[0..8] != MAGIC {
+            return Err("invalid header magic");
+        }
+        if u16::from_le_bytes([header[8], header[9]]) != HEADER_VERSION {
+            return Err("unsupported header version");
+        }
+        if u16::from_le_bytes([header[10], header[11]]) != CIPHER_AES_256_XTS {
+            return Err("unsupported cipher");
+        }
+        let iterations = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if iterations == 0 || iterations > MAX_PBKDF2_ITERATIONS {
+            return Err("invalid number of PBKDF2 iterations");
+        }
+    }
+    Ok(records.map(|record| EncryptedPartition {
+        partition_number: u32::from_le_bytes(record[..4].try_into().unwrap()),
+        header: record[8..].try_into().unwrap(),
+    }))
+}
+
+/// The number of passphrase attempts per partition.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Asks for the passphrase of every partition in the given `partition-keys` file and returns
+/// the unlocked keys.
+///
+/// Partitions are skipped after [`MAX_ATTEMPTS`] wrong passphrases or an empty passphrase.
+pub fn unlock_partitions(file: &[u8]) -> PartitionKeys {
+    let mut keys = PartitionKeys::new();
+    let partitions = match parse(file) {
+        Ok(partitions) => partitions,
+        Err(reason) => {
+            log::error!("Ignoring invalid partition keys file: {reason}");
+            return keys;
+        }
+    };
+    for partition in partitions {
+        let number = partition.partition_number();
+        let mut buf = [0; MAX_PASSPHRASE_LEN];
+        let mut key = None;
+        for _ in 0..MAX_ATTEMPTS {
+            log::warn!("Enter the passphrase of encrypted partition {number}:");
+            let passphrase = read_passphrase(&mut buf);
+            if passphrase.is_empty() {
+                break;
+            }
+            key = partition.unlock(passphrase);
+            if key.is_some() {
+                break;
+            }
+            log::error!("Wrong passphrase for partition {number}");
+        }
+        match key {
+            Some(key) => {
+                log::info!("Unlocked encrypted partition {number}");
+                if !keys.push(key) {
+                    log::error!("Too many encrypted partitions, ignoring partition {number}");
+                }
+            }
+            None => log::error!("Partition {number} stays locked"),
+        }
+    }
+    keys
+}
+
+/// Reads a passphrase from the keyboard or the serial port into `buf`, until enter is
+/// pressed.
+///
+/// The input is not echoed. Backspace removes the last character, further characters are
+/// ignored once the buffer is full.
+pub fn read_passphrase(buf: &mut [u8; MAX_PASSPHRASE_LEN]) -> &[u8] {
+    let mut len = 0usize;
+    let mut shift = false;
+    // discard input that arrived before the prompt
+    while power::poll_input().is_some() {}
+    loop {
+        let byte = match power::poll_input() {
+            Some(Input::Serial(b'\r' | b'\n')) | Some(Input::Keyboard(SCAN_CODE_ENTER)) => break,
+            Some(Input::Serial(0x08 | 0x7f)) | Some(Input::Keyboard(SCAN_CODE_BACKSPACE)) => {
+                len = len.saturating_sub(1);
+                continue;
+            }
+            Some(Input::Serial(byte)) => byte,
+            Some(Input::Keyboard(SCAN_CODE_LEFT_SHIFT | SCAN_CODE_RIGHT_SHIFT)) => {
+                shift = true;
+                continue;
+            }
+            Some(Input::Keyboard(scan_code))
+                if scan_code == SCAN_CODE_LEFT_SHIFT | 0x80
+                    || scan_code == SCAN_CODE_RIGHT_SHIFT | 0x80 =>
+            {
+                shift = false;
+                continue;
+            }
+            Some(Input::Keyboard(scan_code)) => match keyboard_char(scan_code, shift) {
+                Some(byte) => byte,
+                None => continue,
+            },
+            None => {
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+        if let Some(slot) = buf.get_mut(len) {
+            *slot = byte;
+            len += 1;
+        }
+    }
+    &buf[..len]
+}
+
+const SCAN_CODE_ENTER: u8 = 0x1c;
+const SCAN_CODE_BACKSPACE: u8 = 0x0e;
+const SCAN_CODE_LEFT_SHIFT: u8 = 0x2a;
+const SCAN_CODE_RIGHT_SHIFT: u8 = 0x36;
+
+/// The characters of the set 1 scan codes `0x02` to `0x39` on a US keyboard layout, without
+/// and with shift. Zero marks keys without a character.
+const KEYMAP: [&[u8; 56]; 2] = [
+    b"1234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+    b"!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+];
+
+/// Returns the character of the given key press, or `None` for key releases and keys
+/// without a character.
+fn keyboard_char(scan_code: u8, shift: bool) -> Option<u8> {
+    let index = usize::from(scan_code).checked_sub(0x02)?;
+    KEYMAP[usize::from(shift)]
+        .get(index)
+        .copied()
+        .filter(|&byte| byte != 0)
+}
+
+fn xts_cipher(key: &[u8; 64]) -> Xts128<Aes256> {
+    let (data_key, tweak_key) = key.split_at(32);
+    Xts128::new(
+        Aes256::new(GenericArray::from_slice(data_key)),
+        Aes256::new(GenericArray::from_slice(tweak_key)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const MASTER_KEY: [u8; 64] = [0x5a; 64];
+
+    /// Creates a record like the disk image builder, with few iterations to keep the tests
+    /// fast.
+    fn record(partition_number: u32, passphrase: &[u8]) -> Vec<u8> {
+        let salt = [7; 32];
+        let iterations = 16u32;
+        let mut derived_key = [0; 64];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, &salt, iterations, &mut derived_key);
+        let mut encrypted_master_key = MASTER_KEY;
+        xts_cipher(&derived_key).encrypt_sector(&mut encrypted_master_key, get_tweak_default(0));
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&partition_number.to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(MAGIC);
+        record.extend_from_slice(&HEADER_VERSION.to_le_bytes());
+        record.extend_from_slice(&CIPHER_AES_256_XTS.to_le_bytes());
+        record.extend_from_slice(&iterations.to_le_bytes());
+        record.extend_from_slice(&salt);
+        record.extend_from_slice(&8u32.to_le_bytes());
+        record.extend_from_slice(&100u64.to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(&encrypted_master_key);
+        record.extend_from_slice(
+            &Sha256::new()
+                .chain_update(MASTER_KEY)
+                .chain_update(salt)
+                .finalize(),
+        );
+        assert_eq!(record.len(), RECORD_LEN);
+        record
+    }
+
+    #[test]
+    fn unlocks_with_passphrase() {
+        let mut file = record(3, b"secret");
+        file.extend(record(4, b"other"));
+        let partitions: Vec<_> = parse(&file).unwrap().collect();
+        assert_eq!(partitions.len(), 2);
+
+        let key = partitions[0].unlock(b"secret").unwrap();
+        assert_eq!(key.partition_number, 3);
+        assert_eq!(key.payload_offset, 8);
+        assert_eq!(key.payload_sectors, 100);
+        assert_eq!(key.master_key, MASTER_KEY);
+        assert_eq!(partitions[1].partition_number(), 4);
+        assert!(partitions[1].unlock(b"other").is_some());
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let file = record(3, b"secret");
+        let partition = parse(&file).unwrap().next().unwrap();
+        assert!(partition.unlock(b"Secret").is_none());
+        assert!(partition.unlock(b"").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        let file = record(3, b"secret");
+        assert!(parse(&[]).unwrap().next().is_none());
+        assert_eq!(parse(&file[1..]).err(), Some("truncated record"));
+
+        let mut invalid = file.clone();
+        invalid[8] ^= 1;
+        assert_eq!(parse(&invalid).err(), Some("invalid header magic"));
+        let mut invalid = file.clone();
+        invalid[16] = 2;
+        assert_eq!(parse(&invalid).err(), Some("unsupported header version"));
+        let mut invalid = file.clone();
+        invalid[18] = 2;
+        assert_eq!(parse(&invalid).err(), Some("unsupported cipher"));
+        let mut invalid = file;
+        invalid[20..24].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(
+            parse(&invalid).err(),
+            Some("invalid number of PBKDF2 iterations")
+        );
+    }
+
+    #[test]
+    fn maps_scan_codes() {
+        assert_eq!(keyboard_char(0x02, false), Some(b'1'));
+        assert_eq!(keyboard_char(0x02, true), Some(b'!'));
+        assert_eq!(keyboard_char(0x10, false), Some(b'q'));
+        assert_eq!(keyboard_char(0x2b, true), Some(b'|'));
+        assert_eq!(keyboard_char(0x35, false), Some(b'/'));
+        assert_eq!(keyboard_char(0x39, false), Some(b' '));
+        // tab, left control, key releases
+        assert_eq!(keyboard_char(0x0f, false), None);
+        assert_eq!(keyboard_char(0x1d, false), None);
+        assert_eq!(keyboard_char(0x90, false), None);
+        assert_eq!(keyboard_char(0x01, false), None);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{
+    coverage, legacy_memory_region::PageTableAllocator, level_4_entries::UsedLevel4Entries,
+    load_kernel, regions::RegionRegistry, Kernel,
+};
+use bootloader_api::{config::PayloadFormat, info::TlsTemplate};
+use x86_64::{
+    structures::paging::{
+        mapper::MapperAllSizes, FrameAllocator, Page, PageSize, PageTableFlags, PhysFrame,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Maps a kernel executable of one [`PayloadFormat`] into the kernel address space.
+///
+/// The loader only maps the kernel itself. The stack, boot info, and the other mappings
+/// are set up by [`set_up_mappings`](crate::set_up_mappings) independent of the format.
+pub trait PayloadLoader {
+    /// Maps the kernel into `page_table` and returns its entry point and the template for
+    /// its thread local storage, if any.
+    ///
+    /// The virtual address ranges that the kernel occupies must be marked in `used_entries`
+    /// and claimed in `regions`.
+    fn load(
+        &self,
+        kernel: Kernel<'_>,
+        page_table: &mut (impl MapperAllSizes + Translate),
+        frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
+        used_entries: &mut UsedLevel4Entries,
+        regions: &mut RegionRegistry,
+    ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str>;
+}
+
+/// Loads ELF executables, see [`load_kernel::load_kernel`].
+pub struct ElfLoader;
+
+impl PayloadLoader for ElfLoader {
+    fn load(
+        &self,
+        kernel: Kernel<'_>,
+        page_table: &mut (impl MapperAllSizes + Translate),
+        frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
+        used_entries: &mut UsedLevel4Entries,
+        regions: &mut RegionRegistry,
+    ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
+        load_kernel::load_kernel(kernel, page_table, frame_allocator, used_entries, regions)
+    }
+}
+
+/// Loads flat binaries by mapping the loaded image in place.
+///
+/// The load address and entry offset are taken from the `flat_binary_load_address` and
+/// `flat_binary_entry_offset` config options.
+pub struct FlatBinaryLoader;
+
+impl PayloadLoader for FlatBinaryLoader {
+    fn load(
+        &self,
+        kernel: Kernel<'_>,
+        page_table: &mut (impl MapperAllSizes + Translate),
+        frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
+        used_entries: &mut UsedLevel4Entries,
+        regions: &mut RegionRegistry,
+    ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
+        let load_address = Self::load_address(&kernel)?;
+        let phys_start = PhysAddr::new(kernel.start_address as u64);
+        let len = kernel.len as u64;
+        log::info!("Flat binary loaded at {phys_start:#x}, mapping it at {load_address:#x}");
+
+        used_entries.mark_range_as_used(load_address.as_u64(), len);
+        regions.claim_virtual(load_address, len, "kernel image");
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for offset in (0..len).step_by(Size4KiB::SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(load_address + offset);
+            let frame = PhysFrame::containing_address(phys_start + offset);
+            let flusher = unsafe {
+                page_table
+                    .map_to(page, frame, flags, &mut frame_allocator.page_tables())
+                    .map_err(|_err| "map_to failed")?
+            };
+            // we operate on an inactive page table, so there's no need to flush anything
+            flusher.ignore();
+        }
+
+        Ok((load_address + kernel.config.flat_binary_entry_offset, None))
+    }
+}
+
+impl FlatBinaryLoader {
+    /// Checks the config options and the alignment of the loaded image and returns the
+    /// address at which the image is mapped.
+    fn load_address(kernel: &Kernel<'_>) -> Result<VirtAddr, &'static str> {
+        let load_address = VirtAddr::try_new(kernel.config.flat_binary_load_address)
+            .map_err(|_| "flat binary load address is not canonical")?;
+        if !load_address.is_aligned(Size4KiB::SIZE) {
+            return Err("flat binary load address is not page-aligned");
+        }
+        if !PhysAddr::new(kernel.start_address as u64).is_aligned(Size4KiB::SIZE) {
+            return Err("Loaded flat binary is not sufficiently aligned");
+        }
+        if kernel.config.flat_binary_entry_offset >= kernel.len as u64 {
+            return Err("flat binary entry offset is outside of the image");
+        }
+        if kernel.config.strict_segment_permissions {
+            return Err("flat binaries can't be mapped with strict segment permissions");
+        }
+        Ok(load_address)
+    }
+}
+
+/// Loads 64-bit PE executables at their preferred image base.
+///
+/// The sections must be page-aligned in memory and are copied into newly allocated frames.
+/// Base relocations are not applied.
+pub struct PeLoader;
+
+impl PayloadLoader for PeLoader {
+    fn load(
+        &self,
+        kernel: Kernel<'_>,
+        page_table: &mut (impl MapperAllSizes + Translate),
+        frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
+        used_entries: &mut UsedLevel4Entries,
+        regions: &mut RegionRegistry,
+    ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
+        let image = PeImage::parse(kernel.bytes())?;
+        let image_base =
+            VirtAddr::try_new(image.image_base).map_err(|_| "PE image base is not canonical")?;
+        if !image_base.is_aligned(Size4KiB::SIZE) {
+            return Err("PE image base is not page-aligned");
+        }
+        if image.size_of_image == 0 {
+            return Err("PE image is empty");
+        }
+        log::info!("PE executable with image base {image_base:#x}");
+
+        used_entries.mark_range_as_used(image_base.as_u64(), image.size_of_image);
+        regions.claim_virtual(image_base, image.size_of_image, "kernel image");
+
+        for section in image.sections() {
+            let section = section?;
+            if section.mem_size == 0 {
+                continue;
+            }
+            let start = image_base + u64::from(section.virtual_address);
+            if !start.is_aligned(Size4KiB::SIZE) {
+                return Err("PE section is not page-aligned");
+            }
+            let mut flags = PageTableFlags::PRESENT;
+            if section.characteristics & IMAGE_SCN_MEM_WRITE != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if section.characteristics & IMAGE_SCN_MEM_EXECUTE == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            if !flags.contains(PageTableFlags::NO_EXECUTE)
+                && flags.contains(PageTableFlags::WRITABLE)
+            {
+                if kernel.config.strict_segment_permissions {
+                    return Err("PE section is writable and executable");
+                }
+                log::warn!("PE section at {start:#x} is writable and executable");
+            }
+
+            for offset in (0..u64::from(section.mem_size)).step_by(Size4KiB::SIZE as usize) {
+                let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+                    .ok_or("Failed to allocate frame for PE section")?;
+
+                // zero the frame and copy the file data, utilizing identity-mapping
+                let frame_ptr = frame.start_address().as_u64() as *mut u8;
+                unsafe { core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+                let data = section.data.get(offset as usize..).unwrap_or_default();
+                let copy_len = data.len().min(Size4KiB::SIZE as usize);
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), frame_ptr, copy_len) };
+
+                let page = Page::<Size4KiB>::containing_address(start + offset);
+                let flusher = unsafe {
+                    page_table
+                        .map_to(page, frame, flags, &mut frame_allocator.page_tables())
+                        .map_err(|_err| "map_to failed")?
+                };
+                // we operate on an inactive page table, so there's no need to flush anything
+                flusher.ignore();
+            }
+        }
+
+        Ok((image_base + u64::from(image.entry_point), None))
+    }
+}
+
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+const SECTION_HEADER_LEN: usize = 40;
+
+/// The headers of a 64-bit PE executable that are needed to load it.
+struct PeImage<'a> {
+    bytes: &'a [u8],
+    image_base: u64,
+    entry_point: u32,
+    size_of_image: u64,
+    section_table: &'a [u8],
+}
+
+struct PeSection<'a> {
+    virtual_address: u32,
+    mem_size: u32,
+    characteristics: u32,
+    /// The initialized data of the section, at most `mem_size` bytes.
+    data: &'a [u8],
+}
+
+impl<'a> PeImage<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, &'static str> {
+        if !bytes.starts_with(b"MZ") {
+            return Err("kernel is not a PE executable");
+        }
+        let pe_header = read_u32(bytes, 0x3c)? as usize;
+        if bytes.get(pe_header..pe_header + 4) != Some(b"PE\0\0") {
+            return Err("PE signature not found");
+        }
+        let coff_header = pe_header + 4;
+        if read_u16(bytes, coff_header)? != IMAGE_FILE_MACHINE_AMD64 {
+            return Err("PE executable is not built for x86_64");
+        }
+        let number_of_sections = usize::from(read_u16(bytes, coff_header + 2)?);
+        let size_of_optional_header = usize::from(read_u16(bytes, coff_header + 16)?);
+
+        let optional_header = coff_header + 20;
+        if read_u16(bytes, optional_header)? != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
+            return Err("PE executable is not a PE32+ image");
+        }
+        let section_table_start = optional_header + size_of_optional_header;
+        let section_table = bytes
+            .get(section_table_start..)
+            .and_then(|rest| rest.get(..number_of_sections * SECTION_HEADER_LEN))
+            .ok_or("PE section table is out of bounds")?;
+
+        Ok(Self {
+            bytes,
+            entry_point: read_u32(bytes, optional_header + 16)?,
+            image_base: read_u64(bytes, optional_header + 24)?,
+            size_of_image: u64::from(read_u32(bytes, optional_header + 56)?),
+            section_table,
+        })
+    }
+
+    fn sections(&self) -> impl Iterator<Item = Result<PeSection<'a>, &'static str>> + '_ {
+        self.section_table
+            .chunks_exact(SECTION_HEADER_LEN)
+            .map(|header| {
+                let virtual_size = read_u32(header, 8)?;
+                let virtual_address = read_u32(header, 12)?;
+                let raw_size = read_u32(header, 16)?;
+                let raw_offset = read_u32(header, 20)? as usize;
+                let characteristics = read_u32(header, 36)?;
+
+                // some linkers leave the virtual size of sections without padding at zero
+                let mem_size = if virtual_size == 0 {
+                    raw_size
+                } else {
+                    virtual_size
+                };
+                let data_len = raw_size.min(mem_size) as usize;
+                let data = self
+                    .bytes
+                    .get(raw_offset..)
+                    .and_then(|rest| rest.get(..data_len))
+                    .ok_or("PE section data is out of bounds")?;
+                Ok(PeSection {
+                    virtual_address,
+                    mem_size,
+                    characteristics,
+                    data,
+                })
+            })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, &'static str> {
+    read_array(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, &'static str> {
+    read_array(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, &'static str> {
+    read_array(bytes, offset).map(u64::from_le_bytes)
+}
+
+fn read_array<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], &'static str> {
+    bytes
+        .get(offset..)
+        .and_then(|rest| rest.get(..N))
+        .map(|slice| slice.try_into().unwrap())
+        .ok_or("PE header is out of bounds")
+}
+
+/// Maps the kernel with the loader for the `payload_format` of its config.
+pub fn load(
+    kernel: Kernel<'_>,
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size2MiB> + PageTableAllocator),
+    used_entries: &mut UsedLevel4Entries,
+    regions: &mut RegionRegistry,
+) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
+    match kernel.config.payload_format {
+        PayloadFormat::Elf => {
+            coverage::hit();
+            ElfLoader.load(kernel, page_table, frame_allocator, used_entries, regions)
+        }
+        PayloadFormat::FlatBinary => {
+            coverage::hit();
+            FlatBinaryLoader.load(kernel, page_table, frame_allocator, used_entries, regions)
+        }
+        PayloadFormat::Pe => {
+            coverage::hit();
+            PeLoader.load(kernel, page_table, frame_allocator, used_entries, regions)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use bootloader_api::BootloaderConfig;
+    use std::{vec, vec::Vec};
+
+    const PE_HEADER: usize = 0x40;
+    const OPTIONAL_HEADER: usize = PE_HEADER + 24;
+    const OPTIONAL_HEADER_LEN: usize = 240;
+    const SECTION_TABLE: usize = OPTIONAL_HEADER + OPTIONAL_HEADER_LEN;
+
+    /// A section as `(virtual_size, virtual_address, raw_size, raw_offset, characteristics)`.
+    type Section = (u32, u32, u32, u32, u32);
+
+    /// Builds a PE32+ executable with the given sections and 0x1000 bytes of section data
+    /// at file offset 0x400.
+    fn pe(sections: &[Section]) -> Vec<u8> {
+        let mut pe = vec![0; 0x1400];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&(PE_HEADER as u32).to_le_bytes());
+        pe[PE_HEADER..PE_HEADER + 4].copy_from_slice(b"PE\0\0");
+        let coff = PE_HEADER + 4;
+        pe[coff..coff + 2].copy_from_slice(&IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+        pe[coff + 2..coff + 4].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        pe[coff + 16..coff + 18].copy_from_slice(&(OPTIONAL_HEADER_LEN as u16).to_le_bytes());
+        let optional = OPTIONAL_HEADER;
+        pe[optional..optional + 2].copy_from_slice(&IMAGE_NT_OPTIONAL_HDR64_MAGIC.to_le_bytes());
+        pe[optional + 16..optional + 20].copy_from_slice(&0x1010u32.to_le_bytes());
+        pe[optional + 24..optional + 32].copy_from_slice(&0xffff_8000_0000_0000u64.to_le_bytes());
+        pe[optional + 56..optional + 60].copy_from_slice(&0x3000u32.to_le_bytes());
+        for (i, section) in sections.iter().enumerate() {
+            let header = SECTION_TABLE + i * SECTION_HEADER_LEN;
+            let (virtual_size, virtual_address, raw_size, raw_offset, characteristics) = *section;
+            for (offset, value) in [
+                (8, virtual_size),
+                (12, virtual_address),
+                (16, raw_size),
+                (20, raw_offset),
+                (36, characteristics),
+            ] {
+                pe[header + offset..header + offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        for (i, byte) in pe[0x400..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        pe
+    }
+
+    #[test]
+    fn pe_headers() {
+        let pe = pe(&[
+            (0x10, 0x1000, 0x200, 0x400, IMAGE_SCN_MEM_EXECUTE),
+            // the virtual size of sections without padding may be zero
+            (0, 0x2000, 0x200, 0x600, IMAGE_SCN_MEM_WRITE),
+            // uninitialized data
+            (0x1000, 0x3000, 0, 0, IMAGE_SCN_MEM_WRITE),
+        ]);
+        let image = PeImage::parse(&pe).unwrap();
+        assert_eq!(image.image_base, 0xffff_8000_0000_0000);
+        assert_eq!(image.entry_point, 0x1010);
+        assert_eq!(image.size_of_image, 0x3000);
+
+        let sections: Vec<_> = image.sections().map(Result::unwrap).collect();
+        assert_eq!(sections.len(), 3);
+        assert_eq!(sections[0].virtual_address, 0x1000);
+        assert_eq!(sections[0].mem_size, 0x10);
+        assert_eq!(sections[0].characteristics, IMAGE_SCN_MEM_EXECUTE);
+        // the raw data is cut off at the virtual size
+        assert_eq!(sections[0].data, &pe[0x400..0x410]);
+        assert_eq!(sections[1].mem_size, 0x200);
+        assert_eq!(sections[1].data, &pe[0x600..0x800]);
+        assert_eq!(sections[2].mem_size, 0x1000);
+        assert!(sections[2].data.is_empty());
+    }
+
+    fn parse_error(pe: &[u8]) -> &'static str {
+        match PeImage::parse(pe) {
+            Ok(_) => panic!("invalid PE executable was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pe_headers() {
+        let valid = pe(&[(0x10, 0x1000, 0x200, 0x400, 0)]);
+
+        assert_eq!(parse_error(b"\x7fELF"), "kernel is not a PE executable");
+        assert_eq!(parse_error(b"MZ"), "PE header is out of bounds");
+
+        let mut pe = valid.clone();
+        pe[0x3c..0x40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_error(&pe), "PE signature not found");
+
+        let mut pe = valid.clone();
+        pe[PE_HEADER + 2] = b'X';
+        assert_eq!(parse_error(&pe), "PE signature not found");
+
+        // i386
+        let mut pe = valid.clone();
+        pe[PE_HEADER + 4..PE_HEADER + 6].copy_from_slice(&0x14cu16.to_le_bytes());
+        assert_eq!(parse_error(&pe), "PE executable is not built for x86_64");
+
+        // PE32
+        let mut pe = valid.clone();
+        pe[OPTIONAL_HEADER..OPTIONAL_HEADER + 2].copy_from_slice(&0x10bu16.to_le_bytes());
+        assert_eq!(parse_error(&pe), "PE executable is not a PE32+ image");
+
+        let mut pe = valid.clone();
+        pe[PE_HEADER + 6..PE_HEADER + 8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(parse_error(&pe), "PE section table is out of bounds");
+
+        assert_eq!(
+            parse_error(&valid[..OPTIONAL_HEADER + 1]),
+            "PE header is out of bounds"
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_sections() {
+        let pe = pe(&[
+            (0x1000, 0x1000, 0x1000, 0x400, 0),
+            (0x1000, 0x2000, 0x10, u32::MAX, 0),
+        ]);
+        let image = PeImage::parse(&pe).unwrap();
+        let sections: Vec<_> = image.sections().map(|s| s.map(|s| s.mem_size)).collect();
+        assert_eq!(sections[0], Ok(0x1000));
+        assert_eq!(sections[1], Err("PE section data is out of bounds"));
+
+        // the section data is only checked when the sections are read
+        let image = PeImage::parse(&pe[..0x1400 - 1]).unwrap();
+        assert!(image.sections().all(|section| section.is_err()));
+    }
+
+    fn flat_binary(load_address: u64, entry_offset: u64, start: usize) -> Kernel<'static> {
+        let mut config = BootloaderConfig::new_default();
+        config.payload_format = PayloadFormat::FlatBinary;
+        config.flat_binary_load_address = load_address;
+        config.flat_binary_entry_offset = entry_offset;
+        Kernel {
+            config,
+            raw_config: &[],
+            start_address: start as *const u8,
+            len: 0x2000,
+        }
+    }
+
+    #[test]
+    fn flat_binary_load_address() {
+        let kernel = flat_binary(0xffff_8000_0000_0000, 0x1fff, 0x20_0000);
+        assert_eq!(
+            FlatBinaryLoader::load_address(&kernel),
+            Ok(VirtAddr::new(0xffff_8000_0000_0000))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_flat_binaries() {
+        let error = |kernel: &Kernel| FlatBinaryLoader::load_address(kernel).unwrap_err();
+        assert_eq!(
+            error(&flat_binary(0x1_0000_0000_0000, 0, 0x20_0000)),
+            "flat binary load address is not canonical"
+        );
+        assert_eq!(
+            error(&flat_binary(0x10_0800, 0, 0x20_0000)),
+            "flat binary load address is not page-aligned"
+        );
+        assert_eq!(
+            error(&flat_binary(0x10_0000, 0, 0x20_0010)),
+            "Loaded flat binary is not sufficiently aligned"
+        );
+        assert_eq!(
+            error(&flat_binary(0x10_0000, 0x2000, 0x20_0000)),
+            "flat binary entry offset is outside of the image"
+        );
+        let mut kernel = flat_binary(0x10_0000, 0, 0x20_0000);
+        kernel.config.strict_segment_permissions = true;
+        assert_eq!(
+            error(&kernel),
+            "flat binaries can't be mapped with strict segment permissions"
+        );
+    }
+}
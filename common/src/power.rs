@@ -0,0 +1,286 @@
+use crate::{acpi, logger, serial};
+use bootloader_api::info::GenericAddress;
+use conquer_once::spin::OnceCell;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+/// The ports of the PS/2 keyboard controller.
+const KEYBOARD_DATA_PORT: u16 = 0x60;
+const KEYBOARD_STATUS_PORT: u16 = 0x64;
+/// The keyboard controller command that pulses the CPU reset line.
+const KEYBOARD_RESET_COMMAND: u8 = 0xfe;
+/// The set 1 scan codes of the `R` and `P` keys.
+const SCAN_CODE_R: u8 = 0x13;
+const SCAN_CODE_P: u8 = 0x19;
+/// The `SLP_TYP` field and the `SLP_EN` bit of the PM1 control registers.
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+/// The `WAK_STS` bit of the PM1 status registers.
+const WAK_STS: u16 = 1 << 15;
+
+/// Resets or powers off the system through the firmware, registered by the UEFI bootloader.
+static FIRMWARE_RESET: OnceCell<fn(Action) -> !> = OnceCell::uninit();
+/// Whether the firmware function must no longer be used, see [`disable_firmware_reset`].
+static FIRMWARE_RESET_DISABLED: AtomicBool = AtomicBool::new(false);
+/// The physical address of the ACPI RSDP, or `0` if unknown.
+static RSDP_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// What happens to the system when the user gives up on booting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Restart the system.
+    Reboot,
+    /// Turn the system off.
+    PowerOff,
+}
+
+/// Makes [`reboot`] and [`power_off`] use the given firmware function, e.g. the
+/// `ResetSystem` runtime service of UEFI.
+///
+/// Only the first call has an effect.
+pub fn set_firmware_reset(reset: fn(Action) -> !) {
+    FIRMWARE_RESET.init_once(|| reset);
+}
+
+/// Makes [`reboot`] and [`power_off`] skip the firmware function, e.g. because the UEFI
+/// runtime services were switched to virtual addresses that the bootloader doesn't map.
+pub fn disable_firmware_reset() {
+    FIRMWARE_RESET_DISABLED.store(true, Ordering::Relaxed);
+}
+
+fn firmware_reset() -> Option<&'static fn(Action) -> !> {
+    FIRMWARE_RESET
+        .get()
+        .filter(|_| !FIRMWARE_RESET_DISABLED.load(Ordering::Relaxed))
+}
+
+/// Sets the address of the ACPI RSDP, through which [`reboot`] and [`power_off`] find the
+/// power management registers.
+pub fn set_rsdp_addr(rsdp_addr: Option<PhysAddr>) {
+    RSDP_ADDR.store(rsdp_addr.map_or(0, PhysAddr::as_u64), Ordering::Relaxed);
+}
+
+/// Performs the given action.
+pub fn perform(action: Action) -> ! {
+    match action {
+        Action::Reboot => reboot(),
+        Action::PowerOff => power_off(),
+    }
+}
+
+/// Restarts the system.
+///
+/// Tries the firmware, the ACPI reset register, and the keyboard controller, and triggers a
+/// triple fault if all of them fail.
+pub fn reboot() -> ! {
+    if let Some(reset) = firmware_reset() {
+        reset(Action::Reboot);
+    }
+    if let Some(reset) = acpi_fast_info().and_then(|info| info.reset.into_option()) {
+        unsafe { write_register(&reset.register, u16::from(reset.value)) };
+    }
+    unsafe {
+        let mut status = Port::<u8>::new(KEYBOARD_STATUS_PORT);
+        // wait until the input buffer of the controller is empty
+        for _ in 0..0x10000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(KEYBOARD_RESET_COMMAND);
+    }
+    // an empty IDT turns the breakpoint exception into a triple fault
+    let idt = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::new(0),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&idt);
+        asm!("int3");
+    }
+    halt()
+}
+
+/// Turns the system off.
+///
+/// Tries the firmware and the ACPI `\_S5` sleep state. If both fail, the CPU is halted.
+pub fn power_off() -> ! {
+    if let Some(reset) = firmware_reset() {
+        reset(Action::PowerOff);
+    }
+    if let Some(info) = acpi_fast_info() {
+        if let Some([sleep_type_a, sleep_type_b]) = info.sleep_type_s5.into_option() {
+            unsafe {
+                sleep(&info.pm1a_control_block, sleep_type_a);
+                if let Some(pm1b) = info.pm1b_control_block.into_option() {
+                    sleep(&pm1b, sleep_type_b);
+                }
+            }
+        }
+    }
+    log::error!("Failed to power off, halting the CPU instead");
+    halt()
+}
+
+/// Jumps to the ACPI waking vector if the system wakes up from a sleep state, see
+/// [`BootloaderConfig::acpi_s3_resume`].
+///
+/// Returns if the system doesn't wake up, i.e. if the `WAK_STS` bit isn't set or the OS didn't
+/// set a waking vector, and if the waking vector isn't a 64-bit one.
+///
+/// [`BootloaderConfig::acpi_s3_resume`]: bootloader_api::BootloaderConfig::acpi_s3_resume
+pub fn resume_from_sleep(rsdp_addr: PhysAddr) {
+    // the bootloader identity-maps the ACPI tables
+    let info = match unsafe { acpi::find_wake_info(rsdp_addr) } {
+        Some(info) => info,
+        None => return,
+    };
+    let waking = [Some(info.pm1a_status), info.pm1b_status]
+        .iter()
+        .flatten()
+        .any(|status| unsafe { read_register(status) } & WAK_STS != 0);
+    let vector = match info.waking_vector {
+        Some(vector) if waking => vector,
+        _ => return,
+    };
+
+    match vector {
+        acpi::WakingVector::LongMode(addr) => {
+            log::info!("Waking up from a sleep state, jumping to the waking vector at {addr:#x}");
+            if let Some(logger) = logger::LOGGER.get() {
+                logger.log_suppressed_messages();
+            }
+            // the waking vector expects identity-mapped page tables, which the bootloader
+            // uses already
+            unsafe { asm!("cli", "jmp {}", in(reg) addr, options(noreturn)) }
+        }
+        acpi::WakingVector::RealMode(_) | acpi::WakingVector::ProtectedMode(_) => {
+            log::warn!(
+                "Waking up from a sleep state, but the waking vector {vector:x?} isn't a \
+                64-bit one, loading the kernel instead"
+            );
+        }
+    }
+}
+
+/// Waits until the user presses `R` or `P` on the keyboard or the serial port, and reboots
+/// or powers off the system accordingly.
+pub fn wait_for_action() -> ! {
+    loop {
+        match poll_input() {
+            Some(Input::Serial(byte)) => match byte.to_ascii_lowercase() {
+                b'r' => perform(Action::Reboot),
+                b'p' => perform(Action::PowerOff),
+                _ => {}
+            },
+            Some(Input::Keyboard(SCAN_CODE_R)) => perform(Action::Reboot),
+            Some(Input::Keyboard(SCAN_CODE_P)) => perform(Action::PowerOff),
+            _ => {}
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits until the user presses any key on the keyboard or the serial port.
+///
+/// Input that arrived before the call is discarded.
+pub fn wait_for_key() {
+    while poll_input().is_some() {}
+    loop {
+        match poll_input() {
+            // the scan codes of key releases and extended keys have the highest bit set
+            Some(Input::Serial(_)) => return,
+            Some(Input::Keyboard(scan_code)) if scan_code & 0x80 == 0 => return,
+            _ => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// A byte received from the serial port or a scan code from the keyboard.
+pub(crate) enum Input {
+    Serial(u8),
+    Keyboard(u8),
+}
+
+pub(crate) fn poll_input() -> Option<Input> {
+    // reads of missing devices return `0xff`
+    let serial_port = serial::base_port();
+    let status = unsafe { Port::<u8>::new(serial_port + 5).read() };
+    if status != 0xff && status & 1 != 0 {
+        return Some(Input::Serial(unsafe {
+            Port::<u8>::new(serial_port).read()
+        }));
+    }
+    let status = unsafe { Port::<u8>::new(KEYBOARD_STATUS_PORT).read() };
+    if status != 0xff && status & 1 != 0 {
+        return Some(Input::Keyboard(unsafe {
+            Port::<u8>::new(KEYBOARD_DATA_PORT).read()
+        }));
+    }
+    None
+}
+
+fn acpi_fast_info() -> Option<bootloader_api::info::AcpiFastInfo> {
+    let rsdp_addr = match RSDP_ADDR.load(Ordering::Relaxed) {
+        0 => return None,
+        addr => PhysAddr::new(addr),
+    };
+    // the bootloader identity-maps the ACPI tables
+    unsafe { acpi::find_fast_info(rsdp_addr) }
+}
+
+/// Enters the sleep state with the given type through the given PM1 control register.
+unsafe fn sleep(control: &GenericAddress, sleep_type: u8) {
+    let value = unsafe { read_register(control) } & !SLP_TYP_MASK;
+    let value = value | (u16::from(sleep_type) << SLP_TYP_SHIFT) & SLP_TYP_MASK | SLP_EN;
+    unsafe { write_register(control, value) };
+}
+
+/// Reads an I/O port or memory-mapped register of up to 16 bits.
+unsafe fn read_register(register: &GenericAddress) -> u16 {
+    match (register.address_space, register.bit_width) {
+        (GenericAddress::SYSTEM_IO, 8) => {
+            u16::from(unsafe { Port::<u8>::new(register.address as u16).read() })
+        }
+        (GenericAddress::SYSTEM_IO, _) => unsafe {
+            Port::<u16>::new(register.address as u16).read()
+        },
+        (GenericAddress::SYSTEM_MEMORY, 8) => {
+            u16::from(unsafe { ptr::read_volatile(register.address as *const u8) })
+        }
+        (GenericAddress::SYSTEM_MEMORY, _) => unsafe {
+            ptr::read_volatile(register.address as *const u16)
+        },
+        _ => 0,
+    }
+}
+
+/// Writes an I/O port or memory-mapped register of up to 16 bits.
+unsafe fn write_register(register: &GenericAddress, value: u16) {
+    match (register.address_space, register.bit_width) {
+        (GenericAddress::SYSTEM_IO, 8) => unsafe {
+            Port::<u8>::new(register.address as u16).write(value as u8)
+        },
+        (GenericAddress::SYSTEM_IO, _) => unsafe {
+            Port::<u16>::new(register.address as u16).write(value)
+        },
+        (GenericAddress::SYSTEM_MEMORY, 8) => unsafe {
+            ptr::write_volatile(register.address as *mut u8, value as u8)
+        },
+        (GenericAddress::SYSTEM_MEMORY, _) => unsafe {
+            ptr::write_volatile(register.address as *mut u16, value)
+        },
+        _ => {}
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt") };
+    }
+}
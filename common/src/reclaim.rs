@@ -0,0 +1,173 @@
+use crate::{Mappings, PageTables};
+use bootloader_api::info::FrameExtents;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{page_table::PageTableLevel, PageTable, PageTableIndex, PhysFrame},
+};
+
+/// Collects the frames that only contain page tables and the GDT created by the bootloader.
+///
+/// Both the kernel and the bootloader page table hierarchies are walked, but only frames for
+/// which `allocated` returns `true` are reported. This excludes the page tables of the
+/// firmware and of the earlier boot stages, which are covered by the memory map already.
+/// Like the rest of the bootloader, the walk relies on the identity mapping of the physical
+/// memory.
+pub fn reclaimable_frames(
+    page_tables: &mut PageTables,
+    mappings: &Mappings,
+    allocated: impl Fn(PhysFrame) -> bool,
+) -> FrameExtents {
+    let roots = [
+        (page_tables.kernel_level_4_frame, mappings.recursive_index),
+        (Cr3::read().0, None),
+    ];
+    let (extents, dropped) = collect_frames(mappings.gdt_frame, roots, allocated);
+
+    if dropped > 0 {
+        log::warn!(
+            "{dropped} reclaimable frames don't fit into the frame extent list, \
+            reporting them as bootloader memory"
+        );
+    }
+    log::info!(
+        "Reclaimable bootloader frames: {} in {} extents",
+        extents.frame_count(),
+        extents.len()
+    );
+    extents
+}
+
+/// Collects the GDT frame and the frames of the given level 4 page tables, skipping the
+/// given entry of each.
+///
+/// Returns the extents and the number of frames that didn't fit into them.
+fn collect_frames(
+    gdt_frame: PhysFrame,
+    roots: [(PhysFrame, Option<PageTableIndex>); 2],
+    allocated: impl Fn(PhysFrame) -> bool,
+) -> (FrameExtents, u64) {
+    let mut extents = FrameExtents::new();
+    let mut dropped = 0u64;
+    let mut add = |frame: PhysFrame| {
+        if allocated(frame) && !extents.insert_frame(frame.start_address().as_u64()) {
+            dropped += 1;
+        }
+    };
+
+    add(gdt_frame);
+    for (level_4_frame, skip) in roots {
+        walk(level_4_frame, PageTableLevel::Four, skip, &mut add);
+    }
+    (extents, dropped)
+}
+
+/// Calls `f` for the given page table frame and all lower level tables it references.
+///
+/// Huge pages and the entry of the recursive mapping (`skip`) are not followed.
+fn walk(
+    table_frame: PhysFrame,
+    level: PageTableLevel,
+    skip: Option<PageTableIndex>,
+    f: &mut impl FnMut(PhysFrame),
+) {
+    f(table_frame);
+    let next_level = match level.next_lower_level() {
+        Some(next_level) => next_level,
+        None => return,
+    };
+
+    let table: &PageTable = unsafe { &*(table_frame.start_address().as_u64() as *const _) };
+    for (index, entry) in table.iter().enumerate() {
+        if skip.map(usize::from) == Some(index) {
+            continue;
+        }
+        // `frame` fails for unused entries and huge pages
+        if let Ok(frame) = entry.frame() {
+            walk(frame, next_level, None, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{boxed::Box, vec::Vec};
+    use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+    /// Page tables in host memory, which stand in for the identity-mapped physical memory.
+    struct Tables(Vec<Box<PageTable>>);
+
+    impl Tables {
+        fn new(count: usize) -> Self {
+            Self((0..count).map(|_| Box::new(PageTable::new())).collect())
+        }
+
+        fn frame(&self, table: usize) -> PhysFrame {
+            let addr = &*self.0[table] as *const PageTable as u64;
+            PhysFrame::from_start_address(PhysAddr::new(addr)).unwrap()
+        }
+
+        fn link(&mut self, table: usize, index: usize, frame: PhysFrame, flags: PageTableFlags) {
+            self.0[table][index].set_addr(frame.start_address(), flags | PageTableFlags::PRESENT);
+        }
+    }
+
+    /// Returns the sorted start addresses of the given frames.
+    fn addresses(frames: &[PhysFrame]) -> Vec<u64> {
+        let mut addresses: Vec<_> = frames.iter().map(|f| f.start_address().as_u64()).collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    /// Returns the start addresses of all frames of the given extents.
+    fn frames(extents: &FrameExtents) -> Vec<u64> {
+        extents
+            .iter()
+            .flat_map(|extent| {
+                (0..extent.frame_count).map(move |i| extent.start + i * FrameExtents::FRAME_SIZE)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn walks_page_tables() {
+        let mut tables = Tables::new(6);
+        let [l4, other_l4, l3, l2, l1, gdt] = [0, 1, 2, 3, 4, 5].map(|i| tables.frame(i));
+        let none = PageTableFlags::empty();
+        tables.link(0, 0, l3, none);
+        // the recursive entry isn't followed
+        tables.link(0, 510, l4, none);
+        tables.link(2, 3, l2, none);
+        tables.link(3, 0, l1, none);
+        // huge pages and the pages mapped by the level 1 table are not page tables
+        let huge_page = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+        tables.link(3, 1, huge_page, PageTableFlags::HUGE_PAGE);
+        let page = PhysFrame::containing_address(PhysAddr::new(0x1000));
+        tables.link(4, 0, page, none);
+        // the second hierarchy shares the level 3 table
+        tables.link(1, 1, l3, none);
+
+        let roots = [(l4, Some(PageTableIndex::new(510))), (other_l4, None)];
+        let (extents, dropped) = collect_frames(gdt, roots, |_| true);
+        assert_eq!(dropped, 0);
+        assert_eq!(
+            frames(&extents),
+            addresses(&[l4, other_l4, l3, l2, l1, gdt])
+        );
+    }
+
+    #[test]
+    fn skips_frames_that_were_not_allocated() {
+        let mut tables = Tables::new(4);
+        let [l4, firmware_l4, l3, gdt] = [0, 1, 2, 3].map(|i| tables.frame(i));
+        tables.link(0, 0, l3, PageTableFlags::empty());
+        tables.link(1, 0, l3, PageTableFlags::empty());
+
+        let roots = [(l4, None), (firmware_l4, None)];
+        let (extents, dropped) = collect_frames(gdt, roots, |frame| frame != firmware_l4);
+        assert_eq!(dropped, 0);
+        assert_eq!(frames(&extents), addresses(&[l4, l3, gdt]));
+    }
+}
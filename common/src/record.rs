@@ -0,0 +1,182 @@
+//! Recording of the firmware inputs, see [`BootloaderConfig::record_inputs`].
+//!
+//! The inputs are written as `input` events to the outputs that use
+//! [`LogFormat::JsonLines`](bootloader_api::config::LogFormat::JsonLines), e.g.
+//! `{"type":"event","event":"input","input":"key","key":"r"}`. The replay harness in
+//! `tests/replay` parses them and re-runs the firmware-agnostic parts of the bootloader.
+//!
+//! [`BootloaderConfig::record_inputs`]: bootloader_api::BootloaderConfig::record_inputs
+
+use crate::{
+    legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+    logger::{self, Event},
+    Kernel, SystemInfo,
+};
+use bootloader_api::info::MemoryRegionKind;
+use core::{
+    slice,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+use sha2::{Digest, Sha256};
+use usize_conversions::usize_from;
+
+/// The maximum number of keys that are buffered until the kernel config is known.
+const MAX_KEYS: usize = 64;
+/// Distinguishes scan codes from characters in [`KEYS`].
+const SCAN_CODE_FLAG: u32 = 1 << 31;
+
+/// Whether the kernel config enabled the recording.
+static RECORDING: AtomicBool = AtomicBool::new(false);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_KEY: AtomicU32 = AtomicU32::new(0);
+/// The keys that were pressed so far, encoded by [`Key::encode`].
+static KEYS: [AtomicU32; MAX_KEYS] = [NO_KEY; MAX_KEYS];
+static KEY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An input that the bootloader consumed.
+#[derive(Debug, Clone, Copy)]
+pub enum Input<'a> {
+    /// A region of the memory map of the firmware.
+    MemoryRegion {
+        start: u64,
+        len: u64,
+        kind: MemoryRegionKind,
+        usable_after_exit: bool,
+    },
+    /// The address of the first frame that the frame allocator may allocate.
+    FirstFrame(u64),
+    /// The location of the framebuffer.
+    FrameBuffer { addr: u64, byte_len: u64 },
+    /// A file that was loaded from the boot medium, identified by its hash.
+    File {
+        name: &'a str,
+        bytes: u64,
+        sha256: [u8; 32],
+    },
+    /// An extra mapping from the boot metadata block.
+    ExtraMapping { virt_start: u64, len: u64 },
+    /// A key that was pressed, e.g. in the boot menu.
+    Key(Key),
+    /// The seed of the random number generator, gathered from the entropy sources.
+    RngSeed([u8; 32]),
+}
+
+/// A pressed key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A printable character.
+    Char(char),
+    /// A special key, identified by its UEFI scan code.
+    ScanCode(u16),
+}
+
+impl Key {
+    fn encode(self) -> u32 {
+        match self {
+            Key::Char(c) => c as u32,
+            Key::ScanCode(code) => SCAN_CODE_FLAG | u32::from(code),
+        }
+    }
+
+    fn decode(value: u32) -> Self {
+        if value & SCAN_CODE_FLAG != 0 {
+            Key::ScanCode(value as u16)
+        } else {
+            Key::Char(char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER))
+        }
+    }
+}
+
+/// Remembers a key that the bootloader consumed.
+///
+/// Keys are usually read before the kernel config is known, so they are buffered and only
+/// written by [`start`]. Keys after the first 64 are dropped.
+pub fn key(key: Key) {
+    let index = KEY_COUNT.fetch_add(1, Ordering::Relaxed);
+    if let Some(slot) = KEYS.get(index) {
+        slot.store(key.encode(), Ordering::Relaxed);
+    }
+}
+
+/// Writes the inputs that the bootloader consumed so far, if the kernel config enables the
+/// recording.
+///
+/// Must be called after the logger was initialized and before [`UsedLevel4Entries::new`],
+/// which gathers the RNG seed.
+///
+/// [`UsedLevel4Entries::new`]: crate::level_4_entries::UsedLevel4Entries::new
+pub fn start<I, D>(
+    kernel: &Kernel,
+    frame_allocator: &LegacyFrameAllocator<I, D>,
+    system_info: &SystemInfo,
+) where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    if !kernel.config.record_inputs {
+        return;
+    }
+    RECORDING.store(true, Ordering::Relaxed);
+
+    for region in frame_allocator.memory_map() {
+        record(Input::MemoryRegion {
+            start: region.start().as_u64(),
+            len: region.len(),
+            kind: region.kind(),
+            usable_after_exit: region.usable_after_bootloader_exit(),
+        });
+    }
+    record(Input::FirstFrame(
+        frame_allocator.first_frame().start_address().as_u64(),
+    ));
+    if let Some(framebuffer) = &system_info.framebuffer {
+        record(Input::FrameBuffer {
+            addr: framebuffer.addr.as_u64(),
+            byte_len: framebuffer.info.byte_len as u64,
+        });
+    }
+
+    record(file("kernel", kernel.bytes()));
+    if let Some(ramdisk_addr) = system_info.ramdisk_addr {
+        // the ramdisk is identity-mapped
+        let ramdisk = unsafe {
+            slice::from_raw_parts(
+                ramdisk_addr as *const u8,
+                usize_from(system_info.ramdisk_len),
+            )
+        };
+        record(file("ramdisk", ramdisk));
+    }
+    if let Some(metadata) = &system_info.boot_metadata {
+        for mapping in metadata.extra_mappings.iter().filter(|m| m.len > 0) {
+            record(Input::ExtraMapping {
+                virt_start: mapping.virt_start,
+                len: mapping.len,
+            });
+        }
+    }
+
+    let count = KEY_COUNT.load(Ordering::Relaxed).min(MAX_KEYS);
+    for key in &KEYS[..count] {
+        record(Input::Key(Key::decode(key.load(Ordering::Relaxed))));
+    }
+}
+
+/// Writes the seed of the random number generator, if the recording was started.
+pub fn rng_seed(seed: [u8; 32]) {
+    if RECORDING.load(Ordering::Relaxed) {
+        record(Input::RngSeed(seed));
+    }
+}
+
+fn file<'a>(name: &'a str, content: &[u8]) -> Input<'a> {
+    Input::File {
+        name,
+        bytes: content.len() as u64,
+        sha256: Sha256::digest(content).into(),
+    }
+}
+
+fn record(input: Input) {
+    logger::event(Event::Input(input));
+}
@@ -0,0 +1,219 @@
+use crate::error::{self, BootError};
+use core::fmt;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Maximum number of regions that can be claimed in a [`RegionRegistry`].
+const MAX_CLAIMS: usize = 64;
+
+/// The address space that a claimed region belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressSpace {
+    /// Physical memory.
+    Physical,
+    /// Virtual memory in the kernel address space.
+    Virtual,
+}
+
+impl fmt::Display for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressSpace::Physical => f.write_str("physical"),
+            AddressSpace::Virtual => f.write_str("virtual"),
+        }
+    }
+}
+
+/// A memory region that was claimed by some part of the loader.
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    space: AddressSpace,
+    start: u64,
+    /// Inclusive end address, so that regions can reach the end of the address space.
+    last: u64,
+    owner: &'static str,
+}
+
+impl Claim {
+    fn overlaps(&self, other: &Claim) -> bool {
+        self.space == other.space && self.start <= other.last && other.start <= self.last
+    }
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{:#x}..={:#x}]", self.owner, self.start, self.last)
+    }
+}
+
+/// Keeps track of the physical and virtual memory regions used by the kernel, the ramdisk,
+/// and the mappings created by the bootloader.
+///
+/// Every region is registered with a description of its owner. Registering a region that
+/// overlaps an existing region of the same address space results in a
+/// [`BootError::MemoryConflict`] that describes both claimants, instead of silently
+/// corrupting memory.
+pub struct RegionRegistry {
+    claims: [Option<Claim>; MAX_CLAIMS],
+    len: usize,
+}
+
+impl RegionRegistry {
+    /// Creates an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            claims: [None; MAX_CLAIMS],
+            len: 0,
+        }
+    }
+
+    /// Claims the physical memory range `[start..start+len)` for the given owner.
+    ///
+    /// Fails with a [`BootError::MemoryConflict`] if the range overlaps a previously claimed
+    /// physical range.
+    pub fn claim_physical(&mut self, start: PhysAddr, len: u64, owner: &'static str) {
+        self.claim(AddressSpace::Physical, start.as_u64(), len, owner);
+    }
+
+    /// Claims the virtual memory range `[start..start+len)` of the kernel address space for
+    /// the given owner.
+    ///
+    /// Fails with a [`BootError::MemoryConflict`] if the range overlaps a previously claimed
+    /// virtual range.
+    pub fn claim_virtual(&mut self, start: VirtAddr, len: u64, owner: &'static str) {
+        self.claim(AddressSpace::Virtual, start.as_u64(), len, owner);
+    }
+
+    /// Checks that the physical memory range `[start..start+len)` doesn't overlap any claimed
+    /// physical range, without claiming it.
+    ///
+    /// Useful for checking large numbers of ranges, e.g. the ranges used by the frame
+    /// allocator.
+    pub fn check_physical(&self, start: PhysAddr, len: u64, owner: &'static str) {
+        if let Some(claim) = Self::new_claim(AddressSpace::Physical, start.as_u64(), len, owner) {
+            self.check(&claim);
+        }
+    }
+
+    fn claim(&mut self, space: AddressSpace, start: u64, len: u64, owner: &'static str) {
+        let Some(claim) = Self::new_claim(space, start, len, owner) else {
+            return;
+        };
+        self.check(&claim);
+
+        let Some(slot) = self.claims.get_mut(self.len) else {
+            panic!("too many claimed memory regions, cannot claim {space} region {claim}");
+        };
+        *slot = Some(claim);
+        self.len += 1;
+    }
+
+    fn new_claim(space: AddressSpace, start: u64, len: u64, owner: &'static str) -> Option<Claim> {
+        if len == 0 {
+            return None;
+        }
+        let last = start
+            .checked_add(len - 1)
+            .unwrap_or_else(|| panic!("{space} region of {owner} at {start:#x} overflows"));
+        Some(Claim {
+            space,
+            start,
+            last,
+            owner,
+        })
+    }
+
+    fn check(&self, claim: &Claim) {
+        if let Some(existing) = self.claims[..self.len]
+            .iter()
+            .flatten()
+            .find(|existing| existing.overlaps(claim))
+        {
+            error::fail_with_details(
+                BootError::MemoryConflict,
+                &format_args!("{} memory: {claim} overlaps {existing}", claim.space),
+            );
+        }
+    }
+}
+
+impl Default for RegionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disjoint_regions() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_physical(PhysAddr::new(0x1000), 0x1000, "kernel");
+        // directly adjacent regions don't overlap
+        regions.claim_physical(PhysAddr::new(0x2000), 0x1000, "ramdisk");
+        regions.claim_physical(PhysAddr::new(0), 0x1000, "boot info");
+        // the same addresses in the other address space
+        regions.claim_virtual(VirtAddr::new(0x1000), 0x2000, "kernel load segment");
+        // empty regions are ignored
+        regions.claim_physical(PhysAddr::new(0x1800), 0, "empty");
+        regions.check_physical(PhysAddr::new(0x3000), 0x1000, "frame allocator");
+        assert_eq!(regions.len, 4);
+    }
+
+    #[test]
+    fn region_at_end_of_address_space() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_virtual(VirtAddr::new(0xffff_ffff_ffff_f000), 0x1000, "boot info");
+        regions.claim_virtual(VirtAddr::new(0xffff_ffff_ffff_e000), 0x1000, "stack");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "physical memory: ramdisk [0x2000..=0x2fff] overlaps kernel [0x1000..=0x2fff]"
+    )]
+    fn overlapping_physical_regions() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_physical(PhysAddr::new(0x1000), 0x2000, "kernel");
+        regions.claim_physical(PhysAddr::new(0x2000), 0x1000, "ramdisk");
+    }
+
+    #[test]
+    #[should_panic(expected = "virtual memory: stack [0x0..=0xffff] overlaps kernel")]
+    fn enclosing_virtual_region() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_virtual(VirtAddr::new(0x1000), 0x1000, "kernel");
+        regions.claim_virtual(VirtAddr::new(0), 0x1_0000, "stack");
+    }
+
+    #[test]
+    #[should_panic(expected = "frame allocator [0x2fff..=0x2fff] overlaps ramdisk")]
+    fn checked_region_overlaps() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_physical(PhysAddr::new(0x2000), 0x1000, "ramdisk");
+        regions.check_physical(PhysAddr::new(0x2fff), 1, "frame allocator");
+    }
+
+    #[test]
+    fn checked_region_is_not_claimed() {
+        let mut regions = RegionRegistry::new();
+        regions.check_physical(PhysAddr::new(0x2000), 0x1000, "frame allocator");
+        regions.claim_physical(PhysAddr::new(0x2000), 0x1000, "ramdisk");
+    }
+
+    #[test]
+    #[should_panic(expected = "virtual region of kernel at 0xfffffffffffff000 overflows")]
+    fn overflowing_region() {
+        let mut regions = RegionRegistry::new();
+        regions.claim_virtual(VirtAddr::new(0xffff_ffff_ffff_f000), 0x2000, "kernel");
+    }
+
+    #[test]
+    #[should_panic(expected = "too many claimed memory regions")]
+    fn too_many_regions() {
+        let mut regions = RegionRegistry::new();
+        for i in 0..=MAX_CLAIMS as u64 {
+            regions.claim_physical(PhysAddr::new(i * 0x1000), 0x1000, "frame");
+        }
+    }
+}
@@ -1,4 +1,34 @@
-use core::fmt;
+use bootloader_api::BootloaderConfig;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+};
+use x86_64::instructions::port::Port;
+
+/// The frequency of the UART clock divided by 16, i.e. the baud rate for divisor `1`.
+const MAX_BAUD_RATE: u32 = 115_200;
+/// The `DLAB` bit of the line control register, which maps the divisor latch to the data and
+/// interrupt enable registers.
+const LINE_CONTROL_DLAB: u8 = 0x80;
+/// Eight data bits, no parity, one stop bit.
+const LINE_CONTROL_8N1: u8 = 0x03;
+
+/// The base port and baud rate of the serial port, see [`configure`].
+static BASE_PORT: AtomicU16 = AtomicU16::new(BootloaderConfig::new_default().serial_port);
+static BAUD_RATE: AtomicU32 = AtomicU32::new(BootloaderConfig::new_default().serial_baud_rate);
+
+/// Uses the serial port and baud rate of the given kernel config from now on.
+///
+/// Until this is called, the defaults of [`BootloaderConfig::new_default`] are used.
+pub fn configure(config: &BootloaderConfig) {
+    BASE_PORT.store(config.serial_port, Ordering::Relaxed);
+    BAUD_RATE.store(config.serial_baud_rate, Ordering::Relaxed);
+}
+
+/// The base port of the serial port that the bootloader logs to.
+pub fn base_port() -> u16 {
+    BASE_PORT.load(Ordering::Relaxed)
+}
 
 pub struct SerialPort {
     port: uart_16550::SerialPort,
@@ -6,8 +36,20 @@ pub struct SerialPort {
 
 impl SerialPort {
     pub fn new() -> Self {
-        let mut port = unsafe { uart_16550::SerialPort::new(0x3F8) };
+        let base = base_port();
+        let mut port = unsafe { uart_16550::SerialPort::new(base) };
         port.init();
+
+        // `init` always selects 38400 baud
+        let divisor = MAX_BAUD_RATE / BAUD_RATE.load(Ordering::Relaxed).clamp(1, MAX_BAUD_RATE);
+        let [low, high, ..] = divisor.to_le_bytes();
+        unsafe {
+            let mut line_control = Port::<u8>::new(base + 3);
+            line_control.write(LINE_CONTROL_DLAB);
+            Port::<u8>::new(base).write(low);
+            Port::<u8>::new(base + 1).write(high);
+            line_control.write(LINE_CONTROL_8N1);
+        }
         Self { port }
     }
 }
@@ -0,0 +1,105 @@
+const FRAME_START: u8 = 0x7e;
+const HEADER: u8 = b'H';
+const DATA: u8 = b'D';
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+/// The maximum payload length of a frame.
+const MAX_PAYLOAD: usize = 1024;
+
+/// A byte-oriented connection to the host, e.g. a serial port.
+pub trait SerialLink {
+    /// Reads a byte, or returns `None` if no byte arrives within about a second.
+    fn read(&mut self) -> Option<u8>;
+    /// Writes a byte.
+    fn write(&mut self, byte: u8);
+}
+
+/// Waits for the host to send a kernel and receives it into the buffer that `allocate`
+/// returns for the announced kernel length.
+///
+/// The host sends frames of the form `0x7E, kind, seq, len (u16 LE), payload, crc32 (u32 LE)`,
+/// where the CRC-32 covers all fields from `kind` to the end of the payload. Each frame is
+/// answered with `ACK, seq` or with `NAK`, on which the host resends the frame. The transfer
+/// starts with a `HEADER` frame with sequence number 0 that contains the kernel length as
+/// `u32 LE`, which the host repeats until the bootloader acknowledges it. It is followed by
+/// `DATA` frames with consecutive (wrapping) sequence numbers.
+pub fn receive_kernel<'a>(
+    link: &mut impl SerialLink,
+    allocate: impl FnOnce(usize) -> &'a mut [u8],
+) -> &'a mut [u8] {
+    let mut buffer = [0; MAX_PAYLOAD + 8];
+    let len = loop {
+        // the header is only acknowledged once we are ready for the data
+        if read_frame(link, &mut buffer) == Some((HEADER, 0, 4)) {
+            let len = u32::from_le_bytes(buffer[4..8].try_into().unwrap()) as usize;
+            if len > 0 {
+                break len;
+            }
+        }
+        link.write(NAK);
+    };
+    let kernel = &mut allocate(len)[..len];
+
+    let mut offset = 0;
+    let mut seq = 0u8;
+    acknowledge(link, seq);
+    while offset < len {
+        match read_frame(link, &mut buffer) {
+            Some((DATA, frame_seq, frame_len))
+                if frame_seq == seq.wrapping_add(1) && frame_len <= len - offset =>
+            {
+                kernel[offset..][..frame_len].copy_from_slice(&buffer[4..][..frame_len]);
+                offset += frame_len;
+                seq = frame_seq;
+                acknowledge(link, seq);
+            }
+            // our acknowledgement got lost, so the host sent the frame again
+            Some((_, frame_seq, _)) if frame_seq == seq => acknowledge(link, seq),
+            _ => link.write(NAK),
+        }
+    }
+    kernel
+}
+
+/// Reads the next frame into `buffer`.
+///
+/// Returns the kind, sequence number, and payload length of the frame, whose payload starts
+/// at offset 4 of `buffer`. Returns `None` if the frame is too large, has an invalid
+/// checksum, or is not completed in time.
+fn read_frame(
+    link: &mut impl SerialLink,
+    buffer: &mut [u8; MAX_PAYLOAD + 8],
+) -> Option<(u8, u8, usize)> {
+    while link.read()? != FRAME_START {}
+    read_into(link, &mut buffer[..4])?;
+    let len = usize::from(u16::from_le_bytes([buffer[2], buffer[3]]));
+    let frame = buffer.get_mut(..len + 8)?;
+    read_into(link, &mut frame[4..])?;
+    let (data, crc) = frame.split_at(len + 4);
+    (crc32(data).to_le_bytes() == crc).then_some((buffer[0], buffer[1], len))
+}
+
+fn read_into(link: &mut impl SerialLink, buffer: &mut [u8]) -> Option<()> {
+    for byte in buffer {
+        *byte = link.read()?;
+    }
+    Some(())
+}
+
+fn acknowledge(link: &mut impl SerialLink, seq: u8) {
+    link.write(ACK);
+    link.write(seq);
+}
+
+/// CRC-32 (IEEE), computed bitwise.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
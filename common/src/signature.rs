@@ -0,0 +1,150 @@
+use ed25519_compact::{PublicKey, Signature};
+
+/// Marks the end of a signed kernel, keep in sync with `src/signing.rs` of the `bootloader`
+/// crate.
+const SIGNATURE_MAGIC: &[u8; 8] = b"BLKSIG01";
+const SIGNATURE_LEN: usize = 64;
+
+/// The length of a trusted key entry, which is a role byte followed by a 32-byte public key.
+pub const KEY_ENTRY_LEN: usize = 33;
+
+/// The trust level of a key, keep in sync with `KeyRole` of the `bootloader` crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    /// Kernels signed with the key are always booted.
+    Production,
+    /// Kernels signed with the key are only booted in developer mode.
+    Developer,
+}
+
+/// Returns the given kernel without its signature, or the kernel unchanged if it's not
+/// signed.
+///
+/// Compressed kernels are signed after compression, so the signature must be removed before
+/// they are decompressed.
+pub fn content(kernel: &[u8]) -> &[u8] {
+    match kernel
+        .strip_suffix(SIGNATURE_MAGIC)
+        .filter(|signed| signed.len() >= SIGNATURE_LEN)
+    {
+        Some(signed) => &signed[..signed.len() - SIGNATURE_LEN],
+        None => kernel,
+    }
+}
+
+/// Checks the signature that is appended to the kernel against the given trusted key
+/// entries.
+///
+/// Returns the role of the key that signed the kernel, or the reason why the kernel is
+/// rejected.
+pub fn verify(kernel: &[u8], trusted_keys: &[u8]) -> Result<KeyRole, &'static str> {
+    if trusted_keys.is_empty() || trusted_keys.len() % KEY_ENTRY_LEN != 0 {
+        return Err("invalid trusted keys");
+    }
+
+    let content = content(kernel);
+    if content.len() == kernel.len() {
+        return Err("kernel is not signed");
+    }
+    let signature = &kernel[content.len()..][..SIGNATURE_LEN];
+    let signature = Signature::from_slice(signature).map_err(|_| "invalid signature")?;
+
+    let role = trusted_keys
+        .chunks_exact(KEY_ENTRY_LEN)
+        .find(|entry| {
+            PublicKey::from_slice(&entry[1..])
+                .map_or(false, |key| key.verify(content, &signature).is_ok())
+        })
+        .map(|entry| entry[0])
+        .ok_or("signature does not match any trusted key")?;
+    match role {
+        0 => Ok(KeyRole::Production),
+        1 => Ok(KeyRole::Developer),
+        _ => Err("unknown role of trusted key"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use ed25519_compact::{KeyPair, Seed};
+    use std::vec::Vec;
+
+    const KERNEL: &[u8] = b"\x7fELF kernel contents";
+
+    fn key_pair(seed: u8) -> KeyPair {
+        KeyPair::from_seed(Seed::new([seed; 32]))
+    }
+
+    fn sign(kernel: &[u8], key_pair: &KeyPair) -> Vec<u8> {
+        let mut signed = kernel.to_vec();
+        signed.extend_from_slice(key_pair.sk.sign(kernel, None).as_ref());
+        signed.extend_from_slice(SIGNATURE_MAGIC);
+        signed
+    }
+
+    fn trusted_keys(keys: &[(u8, &KeyPair)]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (role, key_pair) in keys {
+            entries.push(*role);
+            entries.extend_from_slice(key_pair.pk.as_ref());
+        }
+        entries
+    }
+
+    #[test]
+    fn accepts_trusted_signatures() {
+        let (production, developer) = (key_pair(1), key_pair(2));
+        let keys = trusted_keys(&[(0, &production), (1, &developer)]);
+
+        assert_eq!(
+            verify(&sign(KERNEL, &production), &keys),
+            Ok(KeyRole::Production)
+        );
+        assert_eq!(
+            verify(&sign(KERNEL, &developer), &keys),
+            Ok(KeyRole::Developer)
+        );
+    }
+
+    #[test]
+    fn rejects_untrusted_kernels() {
+        let trusted = key_pair(1);
+        let keys = trusted_keys(&[(0, &trusted)]);
+
+        assert_eq!(verify(KERNEL, &keys), Err("kernel is not signed"));
+        assert_eq!(
+            verify(&sign(KERNEL, &key_pair(3)), &keys),
+            Err("signature does not match any trusted key")
+        );
+
+        let mut tampered = sign(KERNEL, &trusted);
+        tampered[0] ^= 1;
+        assert_eq!(
+            verify(&tampered, &keys),
+            Err("signature does not match any trusted key")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        let key_pair = key_pair(1);
+        let signed = sign(KERNEL, &key_pair);
+
+        assert_eq!(verify(&signed, &[]), Err("invalid trusted keys"));
+        let keys = trusted_keys(&[(0, &key_pair)]);
+        assert_eq!(verify(&signed, &keys[1..]), Err("invalid trusted keys"));
+        let keys = trusted_keys(&[(7, &key_pair)]);
+        assert_eq!(verify(&signed, &keys), Err("unknown role of trusted key"));
+    }
+
+    #[test]
+    fn strips_signature() {
+        assert_eq!(content(&sign(KERNEL, &key_pair(1))), KERNEL);
+        assert_eq!(content(KERNEL), KERNEL);
+        // too short to hold a signature
+        assert_eq!(content(SIGNATURE_MAGIC), SIGNATURE_MAGIC);
+    }
+}
@@ -0,0 +1,97 @@
+use core::{ptr, slice, str};
+use x86_64::PhysAddr;
+
+/// The structure types of the system and the baseboard information.
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+/// The structure type that ends the structure table.
+const TYPE_END_OF_TABLE: u8 = 127;
+/// The offset of the product name string index in the system and baseboard structures.
+const PRODUCT_NAME_OFFSET: usize = 5;
+/// The size of the header of every structure.
+const STRUCTURE_HEADER_LEN: usize = 4;
+
+/// The product names that identify the machine, read from the SMBIOS structure table.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProductNames {
+    /// The product name of the system information structure, e.g. `ThinkPad X1 Carbon`.
+    pub system: Option<&'static str>,
+    /// The product name of the baseboard information structure.
+    pub board: Option<&'static str>,
+}
+
+/// Reads the product names from the SMBIOS 3 or SMBIOS 2 entry point at the given address.
+///
+/// Names that are empty or not valid UTF-8 are ignored.
+///
+/// ## Safety
+///
+/// The entry point and the structure table must be identity-mapped in the current address
+/// space.
+pub unsafe fn find_product_names(entry_point: PhysAddr) -> ProductNames {
+    let mut names = ProductNames::default();
+    let table = match unsafe { structure_table(entry_point.as_u64()) } {
+        Some(table) => table,
+        None => return names,
+    };
+
+    let mut rest = table;
+    while rest.len() >= STRUCTURE_HEADER_LEN {
+        let (kind, len) = (rest[0], usize::from(rest[1]));
+        if kind == TYPE_END_OF_TABLE || len < STRUCTURE_HEADER_LEN || len > rest.len() {
+            break;
+        }
+        let (formatted, strings) = rest.split_at(len);
+        // the string set ends with two null bytes, even if it is empty
+        let strings_len = match strings.windows(2).position(|pair| pair == [0, 0]) {
+            Some(position) => position + 2,
+            None => break,
+        };
+        let strings = &strings[..strings_len];
+        let product_name = || {
+            let index = *formatted.get(PRODUCT_NAME_OFFSET)?;
+            string(strings, index)
+        };
+        match kind {
+            TYPE_SYSTEM if names.system.is_none() => names.system = product_name(),
+            TYPE_BASEBOARD if names.board.is_none() => names.board = product_name(),
+            _ => {}
+        }
+        rest = &rest[len + strings_len..];
+    }
+    names
+}
+
+/// Returns the structure table that the entry point at the given address points to.
+unsafe fn structure_table(entry_point: u64) -> Option<&'static [u8]> {
+    let anchor: [u8; 5] = unsafe { read(entry_point) };
+    let (addr, len) = if anchor == *b"_SM3_" {
+        // the 64-bit entry point only gives the maximum size of the table
+        let len: u32 = unsafe { read(entry_point + 0x0c) };
+        let addr: u64 = unsafe { read(entry_point + 0x10) };
+        (addr, len)
+    } else if anchor[..4] == *b"_SM_" {
+        let len: u16 = unsafe { read(entry_point + 0x16) };
+        let addr: u32 = unsafe { read(entry_point + 0x18) };
+        (u64::from(addr), u32::from(len))
+    } else {
+        return None;
+    };
+    if addr == 0 {
+        return None;
+    }
+    let len = usize::try_from(len).ok()?;
+    Some(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+}
+
+/// Returns the string with the given one-based index of a string set.
+fn string(strings: &'static [u8], index: u8) -> Option<&'static str> {
+    let index = usize::from(index).checked_sub(1)?;
+    let string = strings.split(|&byte| byte == 0).nth(index)?;
+    let string = str::from_utf8(string).ok()?.trim();
+    (!string.is_empty()).then_some(string)
+}
+
+unsafe fn read<T: Copy>(addr: u64) -> T {
+    unsafe { ptr::read_unaligned(addr as *const T) }
+}
@@ -0,0 +1,73 @@
+//! Measurement of the peak stack usage of the bootloader.
+//!
+//! The boot stages fill the unused part of their stack with [`POISON`] when they start. Right
+//! before the jump to the kernel, [`report`] searches for the lowest overwritten word and logs
+//! the peak usage as text and as a `stack` event, e.g.
+//! `{"type":"event","event":"stack","used":9216,"size":30464}`.
+
+use crate::logger::{self, Event};
+use conquer_once::spin::OnceCell;
+use core::{arch::asm, ptr};
+
+/// The value that unused stack words are filled with.
+///
+/// The BIOS second stage uses the same value, see `bootloader_x86_64_bios_common::STACK_POISON`.
+pub const POISON: u32 = 0x5354_4b21;
+
+/// The number of bytes below the stack pointer that [`poison_below`] leaves untouched, which
+/// covers its own stack frame.
+const SAFETY_MARGIN: u64 = 0x200;
+
+/// The start and end address of the watched stack.
+static REGION: OnceCell<(u64, u64)> = OnceCell::uninit();
+
+/// Fills the `size` bytes below the current stack pointer with [`POISON`] and watches them.
+///
+/// ## Safety
+///
+/// The stack must extend at least `size` bytes below the current stack pointer.
+#[inline(never)]
+pub unsafe fn poison_below(size: u64) {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let end = (rsp - SAFETY_MARGIN) & !3;
+    let start = rsp - size;
+    let mut addr = start;
+    while addr < end {
+        unsafe { ptr::write_volatile(addr as *mut u32, POISON) };
+        addr += 4;
+    }
+    unsafe { watch(start, rsp) };
+}
+
+/// Watches the given stack, whose unused part was already filled with [`POISON`].
+///
+/// Only the first call has an effect.
+///
+/// ## Safety
+///
+/// The given range must be readable until [`report`] is called.
+pub unsafe fn watch(start: u64, end: u64) {
+    REGION.init_once(|| (start, end));
+}
+
+/// Logs the peak usage of the watched stack, if any.
+pub fn report() {
+    let Some(&(start, end)) = REGION.get() else {
+        return;
+    };
+    let size = end - start;
+    let mut lowest_used = start;
+    while lowest_used < end && unsafe { ptr::read_volatile(lowest_used as *const u32) } == POISON {
+        lowest_used += 4;
+    }
+    let used = end - lowest_used;
+    if lowest_used == start {
+        log::warn!("The bootloader stack overflowed its {size} bytes");
+    } else if used > size / 4 * 3 {
+        log::warn!("Peak stack usage: {used} of {size} bytes");
+    } else {
+        log::info!("Peak stack usage: {used} of {size} bytes");
+    }
+    logger::event(Event::Stack { used, size });
+}
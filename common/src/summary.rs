@@ -0,0 +1,66 @@
+use crate::{power, Mappings};
+use bootloader_api::{config::PreKernelSummary, info::MemoryRegionKind, BootInfo};
+
+/// Logs the summary of the kernel environment if enabled, and waits for a key press in
+/// [`PreKernelSummary::Pause`] mode.
+pub fn show(mode: PreKernelSummary, boot_info: &BootInfo, mappings: &Mappings) {
+    if mode == PreKernelSummary::Disabled {
+        return;
+    }
+
+    log::info!("Kernel environment:");
+    log::info!("  entry point:      {:#x}", mappings.entry_point.as_u64());
+    match mappings.kernel_load_offset {
+        Some(offset) => log::info!("  load offset:      {:#x}", offset),
+        None => log::info!("  load offset:      unknown"),
+    }
+    let stack = mappings.kernel_stack;
+    log::info!(
+        "  stack:            {:#x}..{:#x} ({} KiB)",
+        stack.start,
+        stack.end,
+        (stack.end - stack.start) / 1024
+    );
+    match boot_info.physical_memory_offset.into_option() {
+        Some(offset) => log::info!("  physical memory:  mapped at {:#x}", offset),
+        None => log::info!("  physical memory:  not mapped"),
+    }
+
+    let (mut usable, mut reclaimable, mut total) = (0, 0, 0);
+    for region in boot_info.memory_regions.iter() {
+        let len = region.end - region.start;
+        match region.kind {
+            MemoryRegionKind::Usable => usable += len,
+            MemoryRegionKind::BootloaderReclaimable => reclaimable += len,
+            _ => {}
+        }
+        total += len;
+    }
+    log::info!(
+        "  memory:           {} MiB usable, {} MiB reclaimable, {} MiB in {} regions",
+        usable >> 20,
+        reclaimable >> 20,
+        total >> 20,
+        boot_info.memory_regions.len()
+    );
+
+    match boot_info.framebuffer.as_ref() {
+        Some(framebuffer) => {
+            let info = framebuffer.info();
+            log::info!(
+                "  framebuffer:      {}x{}, stride {}, {:?} with {} bytes per pixel",
+                info.width,
+                info.height,
+                info.stride,
+                info.pixel_format,
+                info.bytes_per_pixel
+            );
+        }
+        None => log::info!("  framebuffer:      none"),
+    }
+
+    if mode == PreKernelSummary::Pause {
+        log::info!("Press any key to start the kernel");
+        power::wait_for_key();
+    }
+}
@@ -0,0 +1,24 @@
+use bootloader_api::info::TimerCaps;
+use core::arch::x86_64::__cpuid;
+
+// CPUID.1:ECX
+const CPUID_1_TSC_DEADLINE: u32 = 1 << 24;
+// CPUID.6:EAX
+const CPUID_6_ARAT: u32 = 1 << 2;
+// CPUID.8000_0007:EDX
+const CPUID_8000_0007_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Reads the timer features of the CPU.
+pub fn detect() -> TimerCaps {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+    let caps = TimerCaps {
+        invariant_tsc: max_extended_leaf >= 0x8000_0007
+            && unsafe { __cpuid(0x8000_0007) }.edx & CPUID_8000_0007_INVARIANT_TSC != 0,
+        tsc_deadline: unsafe { __cpuid(1) }.ecx & CPUID_1_TSC_DEADLINE != 0,
+        arat: max_leaf >= 6 && unsafe { __cpuid(6) }.eax & CPUID_6_ARAT != 0,
+    };
+    log::info!("Timer capabilities: {caps:?}");
+    caps
+}
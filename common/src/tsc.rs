@@ -0,0 +1,93 @@
+use core::arch::x86_64::_rdtsc;
+use raw_cpuid::CpuId;
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT input clock in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// The duration of the PIT calibration in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+/// The number of status polls after which the calibration gives up, e.g. if there is no PIT.
+const CALIBRATION_POLL_LIMIT: u32 = 1_000_000;
+
+/// Measures the time since its creation through the time stamp counter.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    start: u64,
+    ticks_per_ms: u64,
+}
+
+impl Clock {
+    /// Starts a new clock, or returns `None` if the CPU has no time stamp counter or its
+    /// frequency can't be determined.
+    pub fn start() -> Option<Self> {
+        let cpu_id = CpuId::new();
+        if !cpu_id.get_feature_info()?.has_tsc() {
+            return None;
+        }
+        let ticks_per_ms = frequency(&cpu_id)? / 1000;
+        (ticks_per_ms > 0).then(|| Self {
+            start: rdtsc(),
+            ticks_per_ms,
+        })
+    }
+
+    /// Returns the number of milliseconds since the clock was started.
+    pub fn elapsed_ms(&self) -> u64 {
+        rdtsc().wrapping_sub(self.start) / self.ticks_per_ms
+    }
+}
+
+/// Returns the TSC frequency in Hz, as reported by the CPU or the hypervisor, or measured
+/// against the PIT.
+fn frequency(cpu_id: &CpuId) -> Option<u64> {
+    if let Some(frequency) = cpu_id.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return Some(frequency);
+    }
+    if let Some(khz) = cpu_id
+        .get_hypervisor_info()
+        .and_then(|info| info.tsc_frequency())
+    {
+        return Some(u64::from(khz) * 1000);
+    }
+    match cpu_id.get_processor_frequency_info() {
+        // the base frequency matches the TSC frequency on CPUs with an invariant TSC
+        Some(info) if info.processor_base_frequency() != 0 => {
+            Some(u64::from(info.processor_base_frequency()) * 1_000_000)
+        }
+        _ => pit_calibration(),
+    }
+}
+
+/// Measures the TSC frequency by counting the ticks while PIT channel 2 counts down.
+fn pit_calibration() -> Option<u64> {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    // SAFETY: channel 2 only drives the PC speaker, which stays disconnected
+    let (start, end) = unsafe {
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // channel 2, low and high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        let mut polls = 0;
+        while gate.read() & 0x20 == 0 {
+            polls += 1;
+            if polls == CALIBRATION_POLL_LIMIT {
+                return None;
+            }
+        }
+        (start, rdtsc())
+    };
+    Some((end - start) * (1000 / CALIBRATION_MS))
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: callers check that the CPU supports `RDTSC`
+    unsafe { _rdtsc() }
+}
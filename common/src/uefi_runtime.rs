@@ -0,0 +1,43 @@
+//! Switching the UEFI runtime services to virtual addressing, see
+//! [`BootloaderConfig::set_uefi_virtual_address_map`].
+//!
+//! [`BootloaderConfig::set_uefi_virtual_address_map`]: bootloader_api::BootloaderConfig::set_uefi_virtual_address_map
+
+use crate::power;
+use bootloader_api::info::UefiRuntime;
+use conquer_once::spin::OnceCell;
+
+/// Calls `SetVirtualAddressMap` with the given virtual offset of the runtime regions and
+/// returns the virtual address of the system table, registered by the UEFI bootloader.
+static SET_VIRTUAL_ADDRESS_MAP: OnceCell<fn(u64) -> Option<u64>> = OnceCell::uninit();
+
+/// Makes [`set_virtual_address_map`] use the given firmware function.
+///
+/// The function must return the new virtual address of the system table, or `None` if the
+/// firmware rejected the virtual address map. Only the first call has an effect.
+pub fn set_firmware_function(function: fn(u64) -> Option<u64>) {
+    SET_VIRTUAL_ADDRESS_MAP.init_once(|| function);
+}
+
+/// Switches the runtime services to the virtual addresses at which they were mapped for the
+/// kernel and sets [`UefiRuntime::virtual_system_table`] on success.
+///
+/// The bootloader's page tables don't map these addresses, so the runtime services must not
+/// be called anymore afterwards. This function should thus be called right before switching
+/// to the kernel. Does nothing if the runtime services weren't mapped.
+pub fn set_virtual_address_map(runtime: &mut UefiRuntime) {
+    let Some(offset) = runtime.virtual_offset.into_option() else {
+        log::warn!("Not calling SetVirtualAddressMap because `uefi_runtime_services` is not set");
+        return;
+    };
+    let Some(function) = SET_VIRTUAL_ADDRESS_MAP.get() else {
+        return;
+    };
+    log::info!("Switch UEFI runtime services to virtual addressing");
+    // the firmware might already have converted some of its pointers if the call fails
+    power::disable_firmware_reset();
+    match function(offset) {
+        Some(system_table) => runtime.virtual_system_table = Some(system_table).into(),
+        None => log::warn!("The firmware rejected the virtual address map"),
+    }
+}
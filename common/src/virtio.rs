@@ -0,0 +1,227 @@
+use core::{
+    hint,
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::instructions::port::Port;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+/// The offset of the command register in the PCI configuration space.
+const PCI_COMMAND: u8 = 0x04;
+/// The offset of the first base address register in the PCI configuration space.
+const PCI_BAR_0: u8 = 0x10;
+/// Enables the I/O space and bus mastering of a PCI device.
+const PCI_COMMAND_IO_BUS_MASTER: u32 = 0b101;
+
+const VIRTIO_VENDOR_ID: u32 = 0x1af4;
+
+// Registers of the legacy virtio PCI interface, relative to the I/O base address.
+const REG_HOST_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// The start of the device-specific configuration, as long as MSI-X is disabled.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+/// The largest queue size that fits into a [`Ring`].
+const MAX_QUEUE_SIZE: u16 = 256;
+/// The alignment of the used ring, as required by the legacy interface.
+const RING_ALIGN: usize = 4096;
+/// The number of polls after which a transfer is considered lost.
+const TIMEOUT_POLLS: u32 = 10_000_000;
+
+/// The memory of a virtqueue, which is shared with the device.
+#[repr(C, align(4096))]
+pub struct Ring([u8; 3 * RING_ALIGN]);
+
+impl Ring {
+    pub const fn new() -> Self {
+        Self([0; 3 * RING_ALIGN])
+    }
+}
+
+/// A device on the PCI bus that is driven through the legacy virtio interface and a single
+/// transmit queue.
+///
+/// Each transfer waits until the device has consumed the data, so the driver works without
+/// interrupts. The bootloader must run on identity-mapped page tables, since the queue and
+/// buffer addresses are passed to the device as is.
+pub struct LegacyDevice {
+    io_base: u16,
+    queue: u16,
+    queue_size: u16,
+    features: u32,
+    ring: &'static mut Ring,
+    /// The index of the next available ring entry.
+    next_avail: u16,
+    /// Set if the device stopped processing the queue.
+    broken: bool,
+}
+
+impl LegacyDevice {
+    /// Probes the PCI bus for a virtio device with the given transitional device ID and sets
+    /// up the given queue in `ring`.
+    ///
+    /// Only the requested `features` that the device offers are enabled. Returns `None` if no
+    /// device with the legacy interface is found.
+    pub fn probe(
+        device_id: u32,
+        queue: u16,
+        features: u32,
+        ring: &'static mut Ring,
+    ) -> Option<Self> {
+        let address = find_device(device_id)?;
+
+        let bar = unsafe { pci_read(address, PCI_BAR_0) };
+        if bar & 1 == 0 {
+            // the legacy interface is always located in I/O space
+            return None;
+        }
+        let io_base = u16::try_from(bar & !0b11).ok()?;
+        let command = unsafe { pci_read(address, PCI_COMMAND) } & 0xffff;
+        unsafe { pci_write(address, PCI_COMMAND, command | PCI_COMMAND_IO_BUS_MASTER) };
+
+        let status = |value| unsafe { Port::new(io_base + REG_DEVICE_STATUS).write(value) };
+        status(0u8);
+        status(STATUS_ACKNOWLEDGE);
+        status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = unsafe {
+            let offered: u32 = Port::new(io_base + REG_HOST_FEATURES).read();
+            Port::new(io_base + REG_GUEST_FEATURES).write(offered & features);
+            Port::new(io_base + REG_QUEUE_SELECT).write(queue);
+            offered & features
+        };
+        let queue_size: u16 = unsafe { Port::new(io_base + REG_QUEUE_SIZE).read() };
+        let pfn = u32::try_from(ring.0.as_ptr() as u64 / RING_ALIGN as u64).ok();
+        let pfn = match pfn {
+            Some(pfn) if queue_size != 0 && queue_size <= MAX_QUEUE_SIZE => pfn,
+            _ => {
+                status(STATUS_FAILED);
+                return None;
+            }
+        };
+        ring.0.fill(0);
+        unsafe { Port::new(io_base + REG_QUEUE_ADDRESS).write(pfn) };
+        status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+
+        Some(Self {
+            io_base,
+            queue,
+            queue_size,
+            features,
+            ring,
+            next_avail: 0,
+            broken: false,
+        })
+    }
+
+    /// Returns the enabled features.
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// Reads a byte of the device-specific configuration.
+    pub fn read_config(&self, offset: u16) -> u8 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_CONFIG + offset).read() }
+    }
+
+    /// Passes the given buffer to the device and waits until the device consumed it.
+    ///
+    /// Does nothing if an earlier transfer timed out.
+    pub fn transmit(&mut self, buffer: &[u8]) {
+        if self.broken {
+            return;
+        }
+        let queue_size = usize::from(self.queue_size);
+        let avail_offset = 16 * queue_size;
+        let used_offset = align_up(avail_offset + 6 + 2 * queue_size, RING_ALIGN);
+
+        // all transfers use the first descriptor, since we wait for their completion
+        self.write(0, buffer.as_ptr() as u64);
+        self.write(8, buffer.len() as u32);
+        self.write(12, 0u16); // flags
+        self.write(14, 0u16); // next
+        let slot = usize::from(self.next_avail % self.queue_size);
+        self.write(avail_offset + 4 + 2 * slot, 0u16);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        self.write(avail_offset + 2, self.next_avail);
+        fence(Ordering::SeqCst);
+        unsafe { Port::new(self.io_base + REG_QUEUE_NOTIFY).write(self.queue) };
+
+        // wait until the device is done with the buffer before it is reused
+        let mut polls = 0;
+        while self.read::<u16>(used_offset + 2) != self.next_avail {
+            polls += 1;
+            if polls == TIMEOUT_POLLS {
+                self.broken = true;
+                break;
+            }
+            hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ring.0.as_ptr().add(offset).cast::<T>().read_volatile() }
+    }
+
+    fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe {
+            self.ring
+                .0
+                .as_mut_ptr()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value)
+        }
+    }
+}
+
+/// Returns the PCI configuration address of the first virtio device with the given ID.
+fn find_device(device_id: u32) -> Option<u32> {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = (1 << 31) | (bus << 16) | (device << 11) | (function << 8);
+                let id = unsafe { pci_read(address, 0) };
+                if id & 0xffff == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                if id == device_id << 16 | VIRTIO_VENDOR_ID {
+                    return Some(address);
+                }
+            }
+        }
+    }
+    None
+}
+
+unsafe fn pci_read(address: u32, offset: u8) -> u32 {
+    unsafe {
+        Port::new(PCI_CONFIG_ADDRESS).write(address | u32::from(offset));
+        Port::new(PCI_CONFIG_DATA).read()
+    }
+}
+
+unsafe fn pci_write(address: u32, offset: u8, value: u32) {
+    unsafe {
+        Port::new(PCI_CONFIG_ADDRESS).write(address | u32::from(offset));
+        Port::new(PCI_CONFIG_DATA).write(value);
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,60 @@
+use crate::virtio::{LegacyDevice, Ring};
+use core::{
+    fmt,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The device ID of a transitional virtio console, which supports the legacy interface.
+const VIRTIO_CONSOLE_DEVICE_ID: u32 = 0x1003;
+/// The transmit queue of the first console port.
+const TRANSMIT_QUEUE: u16 = 1;
+const BUFFER_SIZE: usize = 256;
+
+/// The memory of the transmit queue, which is shared with the device.
+static mut RING: Ring = Ring::new();
+/// The data buffer that is passed to the device.
+static mut BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+/// Ensures that [`RING`] and [`BUFFER`] are only handed out once.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A minimal driver for the legacy interface of a virtio console on the PCI bus.
+///
+/// Only the transmit queue of the first port is used, see [`LegacyDevice`].
+pub struct VirtioConsole {
+    device: LegacyDevice,
+    buffer: &'static mut [u8; BUFFER_SIZE],
+}
+
+impl VirtioConsole {
+    /// Probes the PCI bus for a virtio console and sets up its transmit queue.
+    ///
+    /// Returns `None` if no device with the legacy interface is found or if this function
+    /// was called before.
+    pub fn probe() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let ring = unsafe { &mut *addr_of_mut!(RING) };
+        // we don't need any of the optional features, in particular not multiport
+        let device = LegacyDevice::probe(VIRTIO_CONSOLE_DEVICE_ID, TRANSMIT_QUEUE, 0, ring)?;
+        Some(Self {
+            device,
+            buffer: unsafe { &mut *addr_of_mut!(BUFFER) },
+        })
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(BUFFER_SIZE) {
+            self.buffer[..chunk.len()].copy_from_slice(chunk);
+            self.device.transmit(&self.buffer[..chunk.len()]);
+        }
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
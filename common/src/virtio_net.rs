@@ -0,0 +1,168 @@
+use crate::virtio::{LegacyDevice, Ring};
+use core::{
+    fmt,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The device ID of a transitional virtio network device, which supports the legacy
+/// interface.
+const VIRTIO_NET_DEVICE_ID: u32 = 0x1000;
+/// The first transmit queue of the device.
+const TRANSMIT_QUEUE: u16 = 1;
+/// The feature bit that signals a valid MAC address in the device configuration.
+const FEATURE_MAC: u32 = 1 << 5;
+/// The address that is used if the device has no MAC address, a locally administered one.
+const DEFAULT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+/// The UDP port to which the log messages are sent, the default port of Linux' netconsole.
+pub const PORT: u16 = 6666;
+
+/// The length of the `virtio_net_hdr` that precedes each frame in the legacy interface.
+const VIRTIO_HEADER_LEN: usize = 10;
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const HEADER_LEN: usize =
+    VIRTIO_HEADER_LEN + ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN;
+/// The maximum length of a datagram payload, well below the Ethernet MTU.
+const MAX_PAYLOAD_LEN: usize = 1024;
+
+/// The memory of the transmit queue, which is shared with the device.
+static mut RING: Ring = Ring::new();
+/// The frame that is passed to the device.
+static mut FRAME: [u8; HEADER_LEN + MAX_PAYLOAD_LEN] = [0; HEADER_LEN + MAX_PAYLOAD_LEN];
+/// Ensures that [`RING`] and [`FRAME`] are only handed out once.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sends log output as UDP broadcasts through a virtio network device on the PCI bus.
+///
+/// Each line becomes one datagram from `0.0.0.0` to `255.255.255.255` on [`PORT`], so no
+/// address configuration is needed. Longer lines are split. Since the receive queue is not
+/// set up, nothing is ever received.
+pub struct VirtioNet {
+    device: LegacyDevice,
+    mac: [u8; 6],
+    frame: &'static mut [u8; HEADER_LEN + MAX_PAYLOAD_LEN],
+    /// The number of payload bytes in `frame` that were not sent yet.
+    pending: usize,
+}
+
+impl VirtioNet {
+    /// Probes the PCI bus for a virtio network device and sets up its transmit queue.
+    ///
+    /// Returns `None` if no device with the legacy interface is found or if this function
+    /// was called before.
+    pub fn probe() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let ring = unsafe { &mut *addr_of_mut!(RING) };
+        let device = LegacyDevice::probe(VIRTIO_NET_DEVICE_ID, TRANSMIT_QUEUE, FEATURE_MAC, ring)?;
+        let mac = match device.features() & FEATURE_MAC {
+            0 => DEFAULT_MAC,
+            _ => core::array::from_fn(|i| device.read_config(i as u16)),
+        };
+        Some(Self {
+            device,
+            mac,
+            frame: unsafe { &mut *addr_of_mut!(FRAME) },
+            pending: 0,
+        })
+    }
+
+    fn send(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let len = HEADER_LEN + self.pending;
+        write_headers(&mut self.frame[..HEADER_LEN], self.mac, self.pending);
+        self.device.transmit(&self.frame[..len]);
+        self.pending = 0;
+    }
+}
+
+impl fmt::Write for VirtioNet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.frame[HEADER_LEN + self.pending] = byte;
+            self.pending += 1;
+            if byte == b'\n' || self.pending == MAX_PAYLOAD_LEN {
+                self.send();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the headers of a UDP broadcast with the given payload length from the given MAC
+/// address.
+fn write_headers(headers: &mut [u8], mac: [u8; 6], payload_len: usize) {
+    let (virtio, headers) = headers.split_at_mut(VIRTIO_HEADER_LEN);
+    let (ethernet, headers) = headers.split_at_mut(ETHERNET_HEADER_LEN);
+    let (ipv4, udp) = headers.split_at_mut(IPV4_HEADER_LEN);
+
+    // no checksum offloading or segmentation
+    virtio.fill(0);
+
+    ethernet[0..6].fill(0xff);
+    ethernet[6..12].copy_from_slice(&mac);
+    ethernet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let udp_len = (UDP_HEADER_LEN + payload_len) as u16;
+    ipv4[0] = 0x45; // version 4, 5 words of header
+    ipv4[1] = 0;
+    ipv4[2..4].copy_from_slice(&(IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+    ipv4[4..6].fill(0); // identification
+    ipv4[6..8].copy_from_slice(&0x4000u16.to_be_bytes()); // don't fragment
+    ipv4[8] = 64; // time to live
+    ipv4[9] = 17; // UDP
+    ipv4[10..12].fill(0);
+    ipv4[12..16].fill(0);
+    ipv4[16..20].fill(0xff);
+    let checksum = ipv4_checksum(ipv4);
+    ipv4[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    udp[0..2].copy_from_slice(&PORT.to_be_bytes());
+    udp[2..4].copy_from_slice(&PORT.to_be_bytes());
+    udp[4..6].copy_from_slice(&udp_len.to_be_bytes());
+    // the checksum is optional for UDP over IPv4
+    udp[6..8].fill(0);
+}
+
+/// Computes the one's complement checksum of an IPv4 header.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast_headers() {
+        let mut headers = [0xaa; HEADER_LEN];
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        write_headers(&mut headers, mac, 12);
+
+        assert_eq!(headers[..VIRTIO_HEADER_LEN], [0; VIRTIO_HEADER_LEN]);
+        let ethernet = &headers[VIRTIO_HEADER_LEN..][..ETHERNET_HEADER_LEN];
+        assert_eq!(ethernet[..6], [0xff; 6]);
+        assert_eq!(ethernet[6..12], mac);
+        assert_eq!(ethernet[12..], [0x08, 0x00]);
+        let ipv4 = &headers[VIRTIO_HEADER_LEN + ETHERNET_HEADER_LEN..][..IPV4_HEADER_LEN];
+        assert_eq!(u16::from_be_bytes([ipv4[2], ipv4[3]]), 20 + 8 + 12);
+        assert_eq!(ipv4[16..], [0xff; 4]);
+        // a header with a valid checksum sums up to zero
+        assert_eq!(ipv4_checksum(ipv4), 0);
+        let udp = &headers[HEADER_LEN - UDP_HEADER_LEN..];
+        assert_eq!(udp, [0x1a, 0x0a, 0x1a, 0x0a, 0, 20, 0, 0]);
+    }
+}
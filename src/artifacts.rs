@@ -0,0 +1,138 @@
+#[cfg(feature = "bios")]
+use crate::BiosBoot;
+#[cfg(all(feature = "bios", feature = "uefi"))]
+use crate::HybridBoot;
+#[cfg(feature = "uefi")]
+use crate::UefiBoot;
+use std::path::{Path, PathBuf};
+
+/// Creates disk images with explicitly given bootloader executables.
+///
+/// By default, the images contain the bootloader executables that were built together with
+/// this crate. Tools that build or sign the bootloader themselves can replace individual
+/// executables through the setters of this type. The returned [`BiosBoot`], [`UefiBoot`],
+/// and [`HybridBoot`] values then create the images with these executables and support all
+/// of their usual options.
+#[derive(Debug, Clone, Default)]
+pub struct DiskImageBuilder {
+    #[cfg(feature = "bios")]
+    bios_boot_sector: Option<PathBuf>,
+    #[cfg(feature = "bios")]
+    bios_stage_2: Option<PathBuf>,
+    #[cfg(feature = "bios")]
+    bios_stage_3: Option<PathBuf>,
+    #[cfg(feature = "bios")]
+    bios_stage_4: Option<PathBuf>,
+    #[cfg(feature = "uefi")]
+    uefi_bootloader: Option<PathBuf>,
+}
+
+impl DiskImageBuilder {
+    /// Start with the bootloader executables that were built together with this crate.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the given flat binary as the BIOS boot sector.
+    #[cfg(feature = "bios")]
+    pub fn set_bios_boot_sector(&mut self, path: &Path) -> &mut Self {
+        self.bios_boot_sector = Some(path.to_owned());
+        self
+    }
+
+    /// Use the given flat binary as the second stage of the BIOS bootloader.
+    ///
+    /// The network boot program of [`BiosBoot::create_pxe_tftp_folder`] requires the built-in
+    /// second stage, since its entry point is only known at build time.
+    #[cfg(feature = "bios")]
+    pub fn set_bios_stage_2(&mut self, path: &Path) -> &mut Self {
+        self.bios_stage_2 = Some(path.to_owned());
+        self
+    }
+
+    /// Use the given flat binary as the third stage of the BIOS bootloader.
+    #[cfg(feature = "bios")]
+    pub fn set_bios_stage_3(&mut self, path: &Path) -> &mut Self {
+        self.bios_stage_3 = Some(path.to_owned());
+        self
+    }
+
+    /// Use the given flat binary as the fourth stage of the BIOS bootloader.
+    #[cfg(feature = "bios")]
+    pub fn set_bios_stage_4(&mut self, path: &Path) -> &mut Self {
+        self.bios_stage_4 = Some(path.to_owned());
+        self
+    }
+
+    /// Use the given executable as the UEFI bootloader.
+    #[cfg(feature = "uefi")]
+    pub fn set_uefi_bootloader(&mut self, path: &Path) -> &mut Self {
+        self.uefi_bootloader = Some(path.to_owned());
+        self
+    }
+
+    /// Start creating a BIOS disk image for the given kernel with these executables.
+    #[cfg(feature = "bios")]
+    pub fn bios_boot(&self, kernel_path: &Path) -> BiosBoot {
+        let mut bios = BiosBoot::new(kernel_path);
+        bios.set_artifacts(self.clone());
+        bios
+    }
+
+    /// Start creating a UEFI disk image for the given kernel with these executables.
+    #[cfg(feature = "uefi")]
+    pub fn uefi_boot(&self, kernel_path: &Path) -> UefiBoot {
+        let mut uefi = UefiBoot::new(kernel_path);
+        uefi.set_artifacts(self.clone());
+        uefi
+    }
+
+    /// Start creating a hybrid image for the given kernel with these executables.
+    #[cfg(all(feature = "bios", feature = "uefi"))]
+    pub fn hybrid_boot(&self, kernel_path: &Path) -> HybridBoot {
+        let mut hybrid = HybridBoot::new(kernel_path);
+        hybrid.set_artifacts(self.clone());
+        hybrid
+    }
+
+    #[cfg(feature = "bios")]
+    pub(crate) fn bios_boot_sector_path(&self) -> &Path {
+        self.bios_boot_sector
+            .as_deref()
+            .unwrap_or(Path::new(env!("BIOS_BOOT_SECTOR_PATH")))
+    }
+
+    #[cfg(feature = "bios")]
+    pub(crate) fn bios_stage_2_path(&self) -> &Path {
+        self.bios_stage_2
+            .as_deref()
+            .unwrap_or(Path::new(env!("BIOS_STAGE_2_PATH")))
+    }
+
+    /// Returns whether the second stage was replaced through [`Self::set_bios_stage_2`].
+    #[cfg(feature = "bios")]
+    pub(crate) fn custom_bios_stage_2(&self) -> bool {
+        self.bios_stage_2.is_some()
+    }
+
+    #[cfg(feature = "bios")]
+    pub(crate) fn bios_stage_3_path(&self) -> &Path {
+        self.bios_stage_3
+            .as_deref()
+            .unwrap_or(Path::new(env!("BIOS_STAGE_3_PATH")))
+    }
+
+    #[cfg(feature = "bios")]
+    pub(crate) fn bios_stage_4_path(&self) -> &Path {
+        self.bios_stage_4
+            .as_deref()
+            .unwrap_or(Path::new(env!("BIOS_STAGE_4_PATH")))
+    }
+
+    #[cfg(feature = "uefi")]
+    pub(crate) fn uefi_bootloader_path(&self) -> &Path {
+        self.uefi_bootloader
+            .as_deref()
+            .unwrap_or(Path::new(env!("UEFI_BOOTLOADER_PATH")))
+    }
+}
@@ -0,0 +1,236 @@
+//! Implementation of the `coverage-report` subcommand.
+
+use anyhow::{bail, Context};
+use clap::Args;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The lines around a dump, see `bootloader_x86_64_common::coverage`.
+const BEGIN_MARKER: &str = "coverage-begin";
+const END_MARKER: &str = "coverage-end";
+/// The call that counts an execution, searched for in the `--source` directories.
+const HIT_CALL: &str = "coverage::hit()";
+
+#[derive(Debug, Args)]
+pub struct CoverageReportArgs {
+    /// Debug console logs of bootloaders that were built with the `coverage` feature, e.g.
+    /// written by QEMU's `-debugcon file:debugcon.log`.
+    #[arg(required = true)]
+    logs: Vec<PathBuf>,
+    /// Lists the instrumented locations below this directory that were never executed.
+    ///
+    /// Typically the root of the bootloader repository.
+    #[arg(long)]
+    source: Vec<PathBuf>,
+    /// Print the report as JSON instead of human-readable text.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct CoverageReport {
+    /// The number of dumps in the logs, usually one per boot.
+    dumps: usize,
+    /// The executions of locations that didn't fit into the counter table of a stage.
+    dropped: u64,
+    executed: Vec<LocationCount>,
+    /// Only filled if source directories were given.
+    never_executed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct LocationCount {
+    /// The location as `<file>:<line>:<column>`.
+    location: String,
+    count: u64,
+}
+
+pub fn run(args: &CoverageReportArgs) -> anyhow::Result<()> {
+    let mut report = CoverageReport::default();
+    let mut counts: BTreeMap<Location, u64> = BTreeMap::new();
+    for log in &args.logs {
+        // the log also contains other output of the debug console, which isn't necessarily
+        // valid UTF-8
+        let content =
+            fs::read(log).with_context(|| format!("failed to read `{}`", log.display()))?;
+        let dumps = parse_dumps(&String::from_utf8_lossy(&content), &mut counts, &mut report)
+            .with_context(|| format!("invalid coverage dump in `{}`", log.display()))?;
+        if dumps == 0 {
+            bail!(
+                "`{}` contains no coverage dump, was the bootloader built with the `coverage` \
+                 feature?",
+                log.display()
+            );
+        }
+        report.dumps += dumps;
+    }
+
+    for source in &args.source {
+        for point in instrumented_locations(source)? {
+            if !counts.keys().any(|location| location.matches(&point)) {
+                report.never_executed.push(point.to_string());
+            }
+        }
+    }
+    report.executed = counts
+        .into_iter()
+        .map(|(location, count)| LocationCount {
+            location: location.to_string(),
+            count,
+        })
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!(
+        "Executed locations ({} dumps from {} logs):",
+        report.dumps,
+        args.logs.len()
+    );
+    for entry in &report.executed {
+        println!("  {:<50} {}", entry.location, entry.count);
+    }
+    if report.dropped > 0 {
+        println!(
+            "  {} executions of further locations didn't fit into the counter table",
+            report.dropped
+        );
+    }
+    if !args.source.is_empty() {
+        println!("Never executed locations:");
+        for location in &report.never_executed {
+            println!("  {location}");
+        }
+        let total = report.executed.len() + report.never_executed.len();
+        println!(
+            "{} of {total} instrumented locations executed",
+            report.executed.len()
+        );
+    }
+    Ok(())
+}
+
+/// Adds the counters of all dumps in the log to `counts` and returns the number of dumps.
+fn parse_dumps(
+    log: &str,
+    counts: &mut BTreeMap<Location, u64>,
+    report: &mut CoverageReport,
+) -> anyhow::Result<usize> {
+    let mut dumps = 0;
+    let mut in_dump = false;
+    for line in log.lines().map(str::trim) {
+        if line == BEGIN_MARKER {
+            in_dump = true;
+        } else if line == END_MARKER && in_dump {
+            in_dump = false;
+            dumps += 1;
+        } else if in_dump {
+            let (location, count) = line
+                .rsplit_once(' ')
+                .with_context(|| format!("invalid line `{line}`"))?;
+            let count: u64 = count
+                .parse()
+                .with_context(|| format!("invalid count in line `{line}`"))?;
+            if location == "dropped" {
+                report.dropped += count;
+            } else {
+                let location = location
+                    .parse()
+                    .with_context(|| format!("invalid location in line `{line}`"))?;
+                *counts.entry(location).or_default() += count;
+            }
+        }
+    }
+    if in_dump {
+        bail!("the last dump is incomplete, the bootloader might not have reached the kernel");
+    }
+    Ok(dumps)
+}
+
+/// Finds the `coverage::hit()` calls in the Rust files below the given directory.
+///
+/// The file paths are relative to the directory.
+fn instrumented_locations(dir: &Path) -> anyhow::Result<Vec<Location>> {
+    let mut locations = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)
+            .with_context(|| format!("failed to read directory `{}`", current.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                if path.file_name().map_or(false, |name| name != "target") {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            if path.extension().map_or(true, |ext| ext != "rs") {
+                continue;
+            }
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()))?;
+            let file = path.strip_prefix(dir).unwrap_or(&path);
+            for (index, line) in content.lines().enumerate() {
+                // skips mentions in comments and strings, e.g. `HIT_CALL` itself
+                let code = line.split("//").next().unwrap_or_default();
+                let column = code
+                    .find(HIT_CALL)
+                    .filter(|&column| !code[..column].ends_with('"'));
+                if let Some(column) = column {
+                    locations.push(Location {
+                        file: file.to_string_lossy().replace('\\', "/"),
+                        line: index as u32 + 1,
+                        column: code[..column].chars().count() as u32 + 1,
+                    });
+                }
+            }
+        }
+    }
+    locations.sort();
+    Ok(locations)
+}
+
+/// A source location as reported by `core::panic::Location`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    file: String,
+    line: u32,
+    column: u32,
+}
+
+impl Location {
+    /// Whether this executed location is the given instrumented location.
+    ///
+    /// Stages that were installed from crates.io report absolute paths, so only the path
+    /// components of the instrumented location have to match.
+    fn matches(&self, instrumented: &Location) -> bool {
+        self.line == instrumented.line
+            && self.column == instrumented.column
+            && Path::new(&self.file).ends_with(&instrumented.file)
+    }
+}
+
+impl std::str::FromStr for Location {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the file name might contain colons, e.g. a Windows drive letter
+        let mut parts = s.rsplitn(3, ':');
+        let column = parts.next().unwrap_or_default().parse()?;
+        let line = parts.next().unwrap_or_default().parse()?;
+        let file = parts.next().context("missing file name")?.to_owned();
+        Ok(Location { file, line, column })
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
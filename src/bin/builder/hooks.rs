@@ -0,0 +1,115 @@
+//! Implementation of the `--post-process` flag and the `post-process` metadata key.
+
+use crate::ImageManifest;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Environment variable that tells post-processing commands the kind of the artifact.
+const ARTIFACT_KIND_VAR: &str = "BOOTLOADER_ARTIFACT_KIND";
+
+/// The kinds of artifacts that the builder creates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    BiosImage,
+    UefiImage,
+    HybridImage,
+    EfiStub,
+    /// The directory of the netboot bundle.
+    NetbootBundle,
+    BootEntryScript,
+}
+
+impl ArtifactKind {
+    fn name(self) -> &'static str {
+        match self {
+            ArtifactKind::BiosImage => "bios-image",
+            ArtifactKind::UefiImage => "uefi-image",
+            ArtifactKind::HybridImage => "hybrid-image",
+            ArtifactKind::EfiStub => "efi-stub",
+            ArtifactKind::NetbootBundle => "netboot-bundle",
+            ArtifactKind::BootEntryScript => "boot-entry-script",
+        }
+    }
+}
+
+/// An entry of the `post-process` array, e.g.
+/// `{ command = "scripts/upload.sh", args = ["--rack", "3"], artifacts = ["uefi-image"] }`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PostProcessCommand {
+    /// Relative paths with more than one component are resolved against the directory of
+    /// the `Cargo.toml`, single names are looked up in `PATH`.
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    /// The kinds of artifacts that the command runs on, all kinds if empty.
+    #[serde(default)]
+    artifacts: Vec<ArtifactKind>,
+}
+
+impl PostProcessCommand {
+    /// Runs the given program on all artifacts, for `--post-process`.
+    pub fn program(program: &Path) -> Self {
+        PostProcessCommand {
+            command: program.to_owned(),
+            args: Vec::new(),
+            artifacts: Vec::new(),
+        }
+    }
+
+    /// Resolves the command relative to the directory of the given `Cargo.toml`.
+    pub fn relative_to_manifest(mut self, manifest_path: &Path) -> Self {
+        if self.command.is_relative() && self.command.components().count() > 1 {
+            let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+            self.command = manifest_dir.join(&self.command);
+        }
+        self
+    }
+}
+
+/// Runs the commands on the artifacts of the manifest, in the order of the commands.
+///
+/// Every command is started once per artifact, with the given arguments followed by the path
+/// of the artifact and the path of the JSON image manifest. The `BOOTLOADER_ARTIFACT_KIND`
+/// environment variable contains the kind of the artifact, e.g. `uefi-image`. Fails if a
+/// command exits with an error.
+pub(crate) fn run(
+    commands: &[PostProcessCommand],
+    manifest: &ImageManifest,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    for command in commands {
+        for (kind, artifact) in manifest.artifacts() {
+            if !command.artifacts.is_empty() && !command.artifacts.contains(&kind) {
+                continue;
+            }
+            let mut cmd = Command::new(&command.command);
+            cmd.args(&command.args)
+                .arg(artifact)
+                .arg(&manifest.path)
+                .env(ARTIFACT_KIND_VAR, kind.name());
+            if !quiet {
+                println!("Running {cmd:?}");
+            }
+            let status = cmd.status().with_context(|| {
+                format!(
+                    "failed to run post-processing command `{}`",
+                    command.command.display()
+                )
+            })?;
+            if !status.success() {
+                bail!(
+                    "post-processing command `{}` failed for `{}` ({status})",
+                    command.command.display(),
+                    artifact.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,672 @@
+//! Implementation of the `inspect-image` subcommand.
+
+use anyhow::{anyhow, Context};
+use bootloader_api::info::{BootInfoAbi, BootMetadata};
+use clap::Args;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// MBR partition type of the protective MBR of GPT disks.
+const GPT_PROTECTIVE_PARTITION_TYPE: u8 = 0xee;
+/// MBR partition types that can contain a FAT file system.
+const FAT_PARTITION_TYPES: [u8; 5] = [0x0b, 0x0c, 0x1b, 0x1c, 0xef];
+
+const KERNEL_FILE_NAME: &str = "kernel-x86_64";
+const BOOT_METADATA_FILE_NAME: &str = "boot-metadata";
+const UEFI_BOOTLOADER_FILE_NAME: &str = "efi/boot/bootx64.efi";
+/// The bootloader in a vendor directory `efi/<vendor>/`, see `EspLayout`.
+const UEFI_VENDOR_BOOTLOADER_FILE_NAME: &str = "bootloader-x64.efi";
+const BOOT_LOG_FILE_NAME: &str = "boot-log";
+const BOOT_INFO_ABI_SECTION: &[u8; 8] = b".bootabi";
+/// Marks the start of a kernel compressed with `--compress-kernel`, followed by the algorithm,
+/// four reserved bytes, and the uncompressed length.
+const COMPRESSED_KERNEL_MAGIC: &[u8; 8] = b"BLKCOMP1";
+const COMPRESSED_KERNEL_HEADER_LEN: usize = 24;
+/// Marks the end of a signed kernel, which is preceded by a 64-byte signature.
+const SIGNATURE_MAGIC: &[u8; 8] = b"BLKSIG01";
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// Path to the disk image.
+    image: PathBuf,
+    /// Print the report as JSON instead of human-readable text.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageReport {
+    image: PathBuf,
+    size: u64,
+    /// Whether the image also contains an ISO 9660 file system.
+    iso9660: bool,
+    partition_table: PartitionTable,
+    file_systems: Vec<FileSystemReport>,
+    /// The `BootInfo` ABI if the image is a UEFI bootloader executable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bootloader_abi: Option<AbiReport>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum PartitionTable {
+    None,
+    Mbr {
+        disk_signature: String,
+        partitions: Vec<MbrPartitionReport>,
+    },
+    Gpt {
+        disk_guid: String,
+        partitions: Vec<GptPartitionReport>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct MbrPartitionReport {
+    number: usize,
+    partition_type: u8,
+    bootable: bool,
+    start_lba: u32,
+    sectors: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct GptPartitionReport {
+    number: u32,
+    name: String,
+    type_guid: String,
+    partition_guid: String,
+    first_lba: u64,
+    last_lba: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct FileSystemReport {
+    partition: u32,
+    volume_label: String,
+    files: Vec<FileReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boot_metadata: Option<BootMetadataReport>,
+    /// The `BootInfo` ABI of the UEFI bootloader on this file system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bootloader_abi: Option<AbiReport>,
+    /// The records of the boot log, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    boot_log: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AbiReport {
+    api_version: String,
+    boot_info_size: u32,
+    boot_info_align: u32,
+    layout_hash: String,
+    /// Whether the ABI matches the `bootloader_api` version of this tool.
+    matches_builder: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct FileReport {
+    path: String,
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct BootMetadataReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bootloader_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel_hash: Option<String>,
+    /// Whether the kernel hash matches the kernel file on the same file system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel_hash_matches: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_line: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn run(args: &InspectArgs) -> anyhow::Result<()> {
+    let report = inspect_image(&args.image)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", format_report(&report));
+    }
+    Ok(())
+}
+
+/// Returns the `inspect-image` report of the given image as JSON, without the image path.
+pub fn report_json(path: &Path) -> anyhow::Result<serde_json::Value> {
+    let mut report = serde_json::to_value(inspect_image(path)?)?;
+    if let Some(report) = report.as_object_mut() {
+        report.remove("image");
+    }
+    Ok(report)
+}
+
+/// A FAT partition of an image, with the contents of its files.
+pub struct FatPartition {
+    pub number: u32,
+    /// The byte offset of the partition in the image.
+    pub offset: u64,
+    pub len: u64,
+    /// The paths and contents of the files, sorted by path.
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+/// Reads all files of the FAT partitions of the given image.
+pub fn fat_partitions(path: &Path) -> anyhow::Result<Vec<FatPartition>> {
+    let mut image =
+        File::open(path).with_context(|| format!("failed to open `{}`", path.display()))?;
+    let (_, fat_candidates) = read_partition_table(&mut image, path)?;
+    let mut partitions = Vec::new();
+    for (number, offset, len) in fat_candidates {
+        let fs = open_fat_partition(&mut image, offset, len)?;
+        let mut files = Vec::new();
+        for file in file_list(&fs)? {
+            let mut content = Vec::new();
+            fs.root_dir()
+                .open_file(&file.path)
+                .and_then(|mut f| f.read_to_end(&mut content))
+                .with_context(|| format!("failed to read `{}`", file.path))?;
+            files.push((file.path, content));
+        }
+        partitions.push(FatPartition {
+            number,
+            offset,
+            len,
+            files,
+        });
+    }
+    Ok(partitions)
+}
+
+fn inspect_image(path: &Path) -> anyhow::Result<ImageReport> {
+    let mut image =
+        File::open(path).with_context(|| format!("failed to open `{}`", path.display()))?;
+    let size = image
+        .metadata()
+        .with_context(|| format!("failed to read metadata of `{}`", path.display()))?
+        .len();
+
+    let mut iso_magic = [0; 5];
+    image.seek(SeekFrom::Start(16 * 2048 + 1))?;
+    let iso9660 = image.read_exact(&mut iso_magic).is_ok() && &iso_magic == b"CD001";
+
+    let (partition_table, fat_candidates) = read_partition_table(&mut image, path)?;
+
+    let bootloader_abi = if matches!(partition_table, PartitionTable::None) {
+        let mut content = Vec::new();
+        image.rewind()?;
+        image.read_to_end(&mut content)?;
+        bootloader_abi(&content)
+    } else {
+        None
+    };
+
+    let file_systems = fat_candidates
+        .into_iter()
+        .map(|(partition, offset, len)| inspect_fat_partition(&mut image, partition, offset, len))
+        .collect();
+
+    Ok(ImageReport {
+        image: path.to_owned(),
+        size,
+        iso9660,
+        partition_table,
+        file_systems,
+        bootloader_abi,
+    })
+}
+
+/// The partition number, byte offset, and byte length of the partitions that might contain a
+/// FAT file system.
+type FatCandidates = Vec<(u32, u64, u64)>;
+
+/// Reads the MBR or GPT partition table of the image.
+fn read_partition_table(
+    image: &mut File,
+    path: &Path,
+) -> anyhow::Result<(PartitionTable, FatCandidates)> {
+    let mut fat_candidates = Vec::new();
+    image.rewind()?;
+    let partition_table = match mbrman::MBR::read_from(image, SECTOR_SIZE as u32) {
+        Err(_) => PartitionTable::None,
+        Ok(mbr)
+            if mbr
+                .iter()
+                .any(|(_, entry)| entry.sys == GPT_PROTECTIVE_PARTITION_TYPE) =>
+        {
+            let disk = gpt::GptConfig::new()
+                .writable(false)
+                .open(path)
+                .context("failed to read GPT partition table")?;
+            let partitions = disk
+                .partitions()
+                .iter()
+                .map(|(&number, partition)| {
+                    if partition.part_type_guid == gpt::partition_types::EFI {
+                        fat_candidates.push((
+                            number,
+                            partition.first_lba * SECTOR_SIZE,
+                            (partition.last_lba + 1 - partition.first_lba) * SECTOR_SIZE,
+                        ));
+                    }
+                    GptPartitionReport {
+                        number,
+                        name: partition.name.clone(),
+                        type_guid: partition.part_type_guid.guid.to_lowercase(),
+                        partition_guid: partition.part_guid.to_string(),
+                        first_lba: partition.first_lba,
+                        last_lba: partition.last_lba,
+                    }
+                })
+                .collect();
+            PartitionTable::Gpt {
+                disk_guid: disk.guid().to_string(),
+                partitions,
+            }
+        }
+        Ok(mbr) => {
+            let partitions = mbr
+                .iter()
+                .filter(|(_, entry)| entry.is_used())
+                .map(|(number, entry)| {
+                    if FAT_PARTITION_TYPES.contains(&entry.sys) {
+                        let number = u32::try_from(number).unwrap();
+                        let range = (
+                            u64::from(entry.starting_lba) * SECTOR_SIZE,
+                            u64::from(entry.sectors) * SECTOR_SIZE,
+                        );
+                        // hybrid images reference the same FAT partition twice
+                        if !fat_candidates.iter().any(|&(_, s, l)| (s, l) == range) {
+                            fat_candidates.push((number, range.0, range.1));
+                        }
+                    }
+                    MbrPartitionReport {
+                        number,
+                        partition_type: entry.sys,
+                        bootable: entry.is_active(),
+                        start_lba: entry.starting_lba,
+                        sectors: entry.sectors,
+                    }
+                })
+                .collect();
+            PartitionTable::Mbr {
+                disk_signature: format!("{:#010x}", u32::from_le_bytes(mbr.header.disk_signature)),
+                partitions,
+            }
+        }
+    };
+    Ok((partition_table, fat_candidates))
+}
+
+fn inspect_fat_partition(
+    image: &mut File,
+    partition: u32,
+    offset: u64,
+    len: u64,
+) -> FileSystemReport {
+    let mut report = FileSystemReport {
+        partition,
+        volume_label: String::new(),
+        files: Vec::new(),
+        boot_metadata: None,
+        bootloader_abi: None,
+        boot_log: Vec::new(),
+        error: None,
+    };
+    if let Err(err) = read_fat_partition(image, offset, len, &mut report) {
+        report.error = Some(format!("{err:#}"));
+    }
+    report
+}
+
+fn read_fat_partition(
+    image: &mut File,
+    offset: u64,
+    len: u64,
+    report: &mut FileSystemReport,
+) -> anyhow::Result<()> {
+    let fs = open_fat_partition(image, offset, len)?;
+    report.volume_label = fs.volume_label().trim_end_matches([' ', '\0']).to_owned();
+    report.files = file_list(&fs)?;
+
+    let read_file = |name: &str| -> anyhow::Result<Option<Vec<u8>>> {
+        let mut file = match fs.root_dir().open_file(name) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)
+            .with_context(|| format!("failed to read `{name}`"))?;
+        Ok(Some(content))
+    };
+    let kernel = read_file(KERNEL_FILE_NAME)?
+        .map(decompress_kernel)
+        .transpose()?;
+    if let Some(log) = read_file(BOOT_LOG_FILE_NAME)? {
+        report.boot_log = boot_log_records(&log);
+    }
+    let bootloader_path = report
+        .files
+        .iter()
+        .map(|file| file.path.as_str())
+        .find(|&path| path == UEFI_BOOTLOADER_FILE_NAME)
+        .or_else(|| {
+            report
+                .files
+                .iter()
+                .map(|file| file.path.as_str())
+                .find(|path| {
+                    path.strip_prefix("efi/")
+                        .and_then(|path| path.split_once('/'))
+                        .map_or(false, |(_, name)| name == UEFI_VENDOR_BOOTLOADER_FILE_NAME)
+                })
+        })
+        .map(str::to_owned);
+    if let Some(path) = bootloader_path {
+        report.bootloader_abi = read_file(&path)?.as_deref().and_then(bootloader_abi);
+    }
+    report.boot_metadata =
+        read_file(BOOT_METADATA_FILE_NAME)?.map(|raw| match BootMetadata::deserialize(&raw) {
+            Ok(metadata) => {
+                let version = metadata.bootloader_version;
+                BootMetadataReport {
+                    bootloader_version: Some(format!(
+                        "{}.{}.{}{}",
+                        version.version_major(),
+                        version.version_minor(),
+                        version.version_patch(),
+                        if version.pre_release() { "-pre" } else { "" }
+                    )),
+                    config_hash: Some(hex(&metadata.config_hash)),
+                    kernel_hash: Some(hex(&metadata.kernel_hash)),
+                    kernel_hash_matches: kernel.as_ref().map(|kernel| {
+                        <[u8; 32]>::from(Sha256::digest(kernel)) == metadata.kernel_hash
+                    }),
+                    command_line: Some(metadata.command_line.to_string())
+                        .filter(|command_line| !command_line.is_empty()),
+                    error: None,
+                }
+            }
+            Err(err) => BootMetadataReport {
+                bootloader_version: None,
+                config_hash: None,
+                kernel_hash: None,
+                kernel_hash_matches: None,
+                command_line: None,
+                error: Some(err.to_owned()),
+            },
+        });
+
+    Ok(())
+}
+
+fn open_fat_partition(
+    image: &mut File,
+    offset: u64,
+    len: u64,
+) -> anyhow::Result<fatfs::FileSystem<Cursor<Vec<u8>>>> {
+    let mut data = Vec::new();
+    image.seek(SeekFrom::Start(offset))?;
+    image
+        .take(len)
+        .read_to_end(&mut data)
+        .context("failed to read partition")?;
+    fatfs::FileSystem::new(Cursor::new(data), fatfs::FsOptions::new())
+        .context("failed to parse FAT file system")
+}
+
+/// Lists the files of the file system, sorted by path.
+fn file_list(fs: &fatfs::FileSystem<Cursor<Vec<u8>>>) -> anyhow::Result<Vec<FileReport>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(String::new(), fs.root_dir())];
+    while let Some((prefix, dir)) = dirs.pop() {
+        for entry in dir.iter() {
+            let entry = entry.context("failed to read directory entry")?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = format!("{prefix}{name}");
+            if entry.is_dir() {
+                dirs.push((format!("{path}/"), entry.to_dir()));
+            } else {
+                files.push(FileReport {
+                    path,
+                    size: entry.len(),
+                });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn format_report(report: &ImageReport) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Image:       {}", report.image.display());
+    let _ = writeln!(out, "Size:        {} bytes", report.size);
+    if report.iso9660 {
+        let _ = writeln!(out, "ISO 9660:    yes");
+    }
+
+    match &report.partition_table {
+        PartitionTable::None => {
+            let _ = writeln!(out, "Partitions:  no partition table found");
+            if let Some(abi) = &report.bootloader_abi {
+                let _ = writeln!(out, "UEFI bootloader executable");
+                format_abi(&mut out, abi);
+            }
+        }
+        PartitionTable::Mbr {
+            disk_signature,
+            partitions,
+        } => {
+            let _ = writeln!(out, "Partitions:  MBR, disk signature {disk_signature}");
+            for p in partitions {
+                let _ = writeln!(
+                    out,
+                    "  {}: type {:#04x}{}, start {}, {} sectors",
+                    p.number,
+                    p.partition_type,
+                    if p.bootable { " (bootable)" } else { "" },
+                    p.start_lba,
+                    p.sectors
+                );
+            }
+        }
+        PartitionTable::Gpt {
+            disk_guid,
+            partitions,
+        } => {
+            let _ = writeln!(out, "Partitions:  GPT, disk GUID {disk_guid}");
+            for p in partitions {
+                let _ = writeln!(
+                    out,
+                    "  {}: \"{}\" type {}, GUID {}, LBA {}..={}",
+                    p.number, p.name, p.type_guid, p.partition_guid, p.first_lba, p.last_lba
+                );
+            }
+        }
+    }
+
+    for fs in &report.file_systems {
+        let _ = writeln!(out);
+        let _ = writeln!(
+            out,
+            "FAT file system on partition {} (label \"{}\")",
+            fs.partition, fs.volume_label
+        );
+        if let Some(error) = &fs.error {
+            let _ = writeln!(out, "  error: {error}");
+        }
+        for file in &fs.files {
+            let _ = writeln!(out, "  {:>10}  {}", file.size, file.path);
+        }
+        if let Some(abi) = &fs.bootloader_abi {
+            format_abi(&mut out, abi);
+        }
+        match &fs.boot_metadata {
+            None => {
+                let _ = writeln!(out, "  no boot metadata");
+            }
+            Some(BootMetadataReport {
+                error: Some(error), ..
+            }) => {
+                let _ = writeln!(out, "  invalid boot metadata: {error}");
+            }
+            Some(metadata) => {
+                let field = |value: &Option<String>| value.clone().unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "  bootloader version: {}",
+                    field(&metadata.bootloader_version)
+                );
+                let _ = writeln!(
+                    out,
+                    "  config hash:        {}",
+                    field(&metadata.config_hash)
+                );
+                let _ = writeln!(
+                    out,
+                    "  kernel hash:        {}",
+                    field(&metadata.kernel_hash)
+                );
+                let matches = match metadata.kernel_hash_matches {
+                    Some(true) => "yes",
+                    Some(false) => "NO",
+                    None => "kernel not found",
+                };
+                let _ = writeln!(out, "  kernel hash matches: {matches}");
+                if let Some(command_line) = &metadata.command_line {
+                    let _ = writeln!(out, "  command line:       {command_line}");
+                }
+            }
+        }
+        if !fs.boot_log.is_empty() {
+            let _ = writeln!(out, "  boot log:");
+            for record in &fs.boot_log {
+                let _ = writeln!(out, "    {record}");
+            }
+        }
+    }
+    out
+}
+
+/// Returns the used records of a boot log, sorted by their sequence number.
+///
+/// Every record is a line that starts with a decimal sequence number, unused records are
+/// blank.
+fn boot_log_records(log: &[u8]) -> Vec<String> {
+    let mut records: Vec<(u32, String)> = String::from_utf8_lossy(log)
+        .lines()
+        .filter_map(|line| {
+            let sequence = line.split(' ').next()?.parse().ok()?;
+            Some((sequence, line.trim_end().to_owned()))
+        })
+        .collect();
+    records.sort_by_key(|(sequence, _)| *sequence);
+    records.into_iter().map(|(_, record)| record).collect()
+}
+
+/// Reads the `BootInfo` ABI description from a UEFI bootloader executable.
+fn bootloader_abi(executable: &[u8]) -> Option<AbiReport> {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes(
+            executable.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let read_u32 = |offset: usize| -> Option<usize> {
+        let value = u32::from_le_bytes(executable.get(offset..offset + 4)?.try_into().ok()?);
+        usize::try_from(value).ok()
+    };
+
+    if executable.get(..2) != Some(b"MZ") {
+        return None;
+    }
+    let pe_offset = read_u32(0x3c)?;
+    if executable.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff_header = pe_offset + 4;
+    let number_of_sections = usize::from(read_u16(coff_header + 2)?);
+    let section_table = coff_header + 20 + usize::from(read_u16(coff_header + 16)?);
+    let section = (0..number_of_sections)
+        .map(|index| section_table + index * 40)
+        .find(|&entry| executable.get(entry..entry + 8) == Some(&BOOT_INFO_ABI_SECTION[..]))?;
+    let raw_data = read_u32(section + 20)?;
+    let raw = executable.get(raw_data..raw_data + BootInfoAbi::SERIALIZED_LEN)?;
+
+    let abi = BootInfoAbi::deserialize(raw).ok()?;
+    let version = abi.api_version;
+    Some(AbiReport {
+        api_version: format!(
+            "{}.{}.{}{}",
+            version.version_major(),
+            version.version_minor(),
+            version.version_patch(),
+            if version.pre_release() { "-pre" } else { "" }
+        ),
+        boot_info_size: abi.boot_info_size,
+        boot_info_align: abi.boot_info_align,
+        layout_hash: format!("{:016x}", abi.layout_hash),
+        matches_builder: abi == BootInfoAbi::current(),
+    })
+}
+
+fn format_abi(out: &mut String, abi: &AbiReport) {
+    let _ = writeln!(
+        out,
+        "  BootInfo ABI:       api {}, size {}, align {}, layout hash {}{}",
+        abi.api_version,
+        abi.boot_info_size,
+        abi.boot_info_align,
+        abi.layout_hash,
+        if abi.matches_builder {
+            ""
+        } else {
+            " (differs from builder)"
+        }
+    );
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decompresses a kernel that was compressed with `--compress-kernel`, since the hash in the
+/// boot metadata refers to the uncompressed kernel.
+///
+/// Compressed kernels are signed after compression, so their signature is removed first.
+fn decompress_kernel(kernel: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let content = match kernel
+        .strip_suffix(SIGNATURE_MAGIC)
+        .filter(|signed| signed.len() >= SIGNATURE_LEN)
+    {
+        Some(signed) => &signed[..signed.len() - SIGNATURE_LEN],
+        None => &kernel,
+    };
+    if content.len() < COMPRESSED_KERNEL_HEADER_LEN || !content.starts_with(COMPRESSED_KERNEL_MAGIC)
+    {
+        return Ok(kernel);
+    }
+    let len = u64::from_le_bytes(content[16..24].try_into().unwrap());
+    lz4_flex::block::decompress(&content[COMPRESSED_KERNEL_HEADER_LEN..], len as usize)
+        .map_err(|err| anyhow!("failed to decompress kernel: {err}"))
+}
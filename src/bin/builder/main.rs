@@ -0,0 +1,939 @@
+//! Command line tool for creating bootable disk images for a kernel.
+//!
+//! Image-level settings are read from the `[package.metadata.bootloader]` table of the
+//! kernel's `Cargo.toml` (passed via `--kernel-manifest`). Command line arguments override
+//! the values from the manifest.
+//!
+//! The `inspect-image` subcommand prints the layout and contents of an existing image. The
+//! `diff-images` and `apply-patch` subcommands create and apply binary patches between two
+//! images. The `push-serial` subcommand sends a kernel to an image that was created with
+//! `--bios-serial-load` or `--uefi-serial-load`. The `size-report` subcommand prints the sizes
+//! of the boot stages that the builder puts into the images. The `coverage-report` subcommand
+//! merges the coverage dumps of boot stages that were built with the `coverage` feature. The
+//! `generate-key` and `sign-kernel` subcommands create signing keys and signed kernels for
+//! `--production-key` and `--developer-key`. With `--sign-key`, the builder signs the kernel
+//! itself. The trusted keys are embedded into the BIOS, UEFI, and hybrid images.
+//!
+//! Given several `--kernel-binary` arguments, the UEFI image boots into a menu that lists all
+//! kernels. The BIOS and hybrid images, the stub executable, and the netboot bundle only
+//! contain the first kernel.
+//!
+//! With `--run` or `--run-uefi`, the builder boots the created BIOS or UEFI image in QEMU.
+//! Arguments after `--` are passed on to QEMU. The `[package.metadata.bootloader.test]` table
+//! turns the run into a boot test with a timeout and expected serial output. With
+//! `--verify-reproducible`, the builder creates all artifacts a second time and fails if they
+//! differ.
+//!
+//! With `--run-remote`, the builder boots the image on a lab machine that is described by the
+//! `[package.metadata.bootloader.remote]` table and streams its serial console.
+//!
+//! Post-processing commands from `--post-process` and the `post-process` metadata key run
+//! on every artifact before QEMU is started, e.g. to sign or upload the images.
+
+use anyhow::{anyhow, Context};
+use bootloader::{
+    BiosBoot, Caching, EspLayout, ExtraMapping, HybridBoot, KeyRole, LevelFilter, LogLevels,
+    MbrPartition, UefiBoot, Uuid,
+};
+use bootloader_api::BootloaderConfig;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+};
+
+mod coverage;
+mod hooks;
+mod inspect;
+mod patch;
+mod remote;
+mod reproducible;
+mod run;
+mod serial;
+mod sign;
+mod size;
+
+/// Creates bootable BIOS and UEFI disk images for a kernel executable.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct BuilderArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Prints the partition layout, file systems, and boot metadata of a disk image.
+    InspectImage(inspect::InspectArgs),
+    /// Creates a binary patch that transforms one image into another.
+    DiffImages(patch::DiffArgs),
+    /// Applies a patch created by `diff-images` to an image.
+    ApplyPatch(patch::ApplyArgs),
+    /// Sends a kernel over a serial port to an image in serial kernel load mode.
+    PushSerial(serial::PushArgs),
+    /// Prints the sizes of the boot stages, grouped by cargo feature.
+    SizeReport(size::SizeReportArgs),
+    /// Merges the coverage dumps of bootloaders built with the `coverage` feature.
+    CoverageReport(coverage::CoverageReportArgs),
+    /// Creates a key pair for signing kernels.
+    GenerateKey(sign::GenerateKeyArgs),
+    /// Appends a signature to a kernel.
+    SignKernel(sign::SignArgs),
+}
+
+/// Arguments for creating disk images, used when no subcommand is given.
+#[derive(Debug, Clone, Args)]
+struct BuildArgs {
+    /// Path to the kernel ELF executable.
+    ///
+    /// Further kernels are added to the boot menu of the UEFI image.
+    #[arg(long, required = true)]
+    kernel_binary: Vec<PathBuf>,
+    /// Path to the `Cargo.toml` of the kernel, for reading `[package.metadata.bootloader]`.
+    ///
+    /// Given several times, the manifests belong to the kernels in the same order. Only the
+    /// `ramdisk` key is read for the kernels of the boot menu.
+    #[arg(long)]
+    kernel_manifest: Vec<PathBuf>,
+    /// File that the bootloader loads into memory as the kernel's ramdisk.
+    #[arg(long)]
+    ramdisk: Option<PathBuf>,
+    /// Command line that is passed to the kernel, overrides the `command-line` metadata key.
+    #[arg(long)]
+    command_line: Option<String>,
+    /// Directory in which the disk images and the JSON manifest are placed.
+    #[arg(long, required = true)]
+    out_dir: Option<PathBuf>,
+    /// GUID of the GPT disk of the UEFI image.
+    #[arg(long)]
+    disk_guid: Option<Uuid>,
+    /// Unique partition GUID of the EFI system partition of the UEFI image.
+    #[arg(long)]
+    esp_partition_guid: Option<Uuid>,
+    /// MBR disk signature of the BIOS and hybrid images, e.g. `0x1234abcd`.
+    #[arg(long, value_parser = parse_disk_signature)]
+    disk_signature: Option<u32>,
+    /// Derive the disk and partition GUIDs and the disk signature from the kernel name instead
+    /// of generating random ones.
+    #[arg(long)]
+    derive_guids: bool,
+    /// Image of an additional data partition for the BIOS image.
+    #[arg(long)]
+    data_partition: Option<PathBuf>,
+    /// Encrypt the data partition with the passphrase stored in the given file.
+    #[arg(long, requires = "data_partition")]
+    data_passphrase_file: Option<PathBuf>,
+    /// Make the BIOS image receive the kernel over the serial port, see `push-serial`.
+    #[arg(long)]
+    bios_serial_load: bool,
+    /// Make the UEFI image receive the kernel over a serial device, see `push-serial`.
+    #[arg(long)]
+    uefi_serial_load: bool,
+    /// Add an entry to the boot menu of the UEFI image that loads the kernel from a USB
+    /// mass-storage device.
+    #[arg(long)]
+    uefi_usb_load: bool,
+    /// Make the UEFI image load the kernel from the 9P server at the given address, e.g.
+    /// `10.0.2.2:564`.
+    #[arg(long)]
+    uefi_9p_server: Option<SocketAddrV4>,
+    /// The export of the 9P server to attach to, see `--uefi-9p-server`.
+    #[arg(long, requires = "uefi_9p_server", default_value = "")]
+    uefi_9p_export: String,
+    /// Recovery kernel that the UEFI bootloader starts after too many failed boots.
+    #[arg(long)]
+    recovery_kernel: Option<PathBuf>,
+    /// Text file with support contact information for the error screen of the UEFI image.
+    #[arg(long)]
+    support_info: Option<PathBuf>,
+    /// Public key whose signed kernels the BIOS and UEFI images always boot, see
+    /// `generate-key`.
+    ///
+    /// Once a key is given, the images only boot signed kernels.
+    #[arg(long)]
+    production_key: Vec<PathBuf>,
+    /// Public key whose signed kernels the UEFI image only boots in developer mode.
+    #[arg(long)]
+    developer_key: Vec<PathBuf>,
+    /// Sign the kernel with the given secret key from `generate-key` and trust the public key
+    /// from the `.pub` file next to it, like `--production-key`.
+    ///
+    /// The signed kernel is written to `<out-dir>/<kernel-name>.signed` and placed on the
+    /// images instead of the kernel.
+    #[arg(long)]
+    sign_key: Option<PathBuf>,
+    /// Place an additional file on the boot partition of the images and in the PXE folders,
+    /// given as `HOST_PATH:IMAGE_PATH`, e.g. `fw/wifi.bin:firmware/wifi.bin`.
+    #[arg(long, value_name = "HOST_PATH:IMAGE_PATH", value_parser = parse_extra_file)]
+    add_file: Vec<(PathBuf, String)>,
+    /// Compress the kernel with LZ4 on the images and in the PXE folders.
+    #[arg(long)]
+    compress_kernel: bool,
+    /// Seconds after which the boot menu of the UEFI image boots the default kernel,
+    /// overrides the `menu-timeout` metadata key.
+    #[arg(long)]
+    menu_timeout: Option<u32>,
+    /// Zero-based index of the kernel that the boot menu boots after the timeout, overrides
+    /// the `menu-default` metadata key.
+    #[arg(long)]
+    menu_default: Option<usize>,
+    /// Boot script that selects the kernel of the UEFI image at boot time.
+    #[arg(long)]
+    boot_script: Option<PathBuf>,
+    /// Keep a log of the last boots on the EFI system partition of the UEFI image.
+    #[arg(long)]
+    boot_log: bool,
+    /// Where to place the UEFI bootloader on the EFI system partition, overrides the
+    /// `efi-path` metadata key. `vendor` and `both` require `--efi-vendor`.
+    #[arg(long, value_enum)]
+    efi_path: Option<EfiPath>,
+    /// Name of the vendor directory `EFI/<vendor>/` for `--efi-path`, overrides the
+    /// `efi-vendor` metadata key.
+    #[arg(long)]
+    efi_vendor: Option<String>,
+    /// Additionally create a hybrid image that boots from both optical media and USB drives.
+    #[arg(long)]
+    hybrid_iso: bool,
+    /// Additionally create a single UEFI executable with the kernel embedded into it.
+    #[arg(long)]
+    efi_stub: bool,
+    /// Additionally create a netboot bundle for BIOS and UEFI clients, with DHCP
+    /// configuration snippets that point to the TFTP server at the given address.
+    #[arg(long, value_name = "SERVER_ADDRESS")]
+    netboot_bundle: Option<Ipv4Addr>,
+    /// Additionally create `efibootmgr` and `bcdedit` scripts that add a firmware boot entry
+    /// for the UEFI image after it was written to a disk.
+    #[arg(long)]
+    boot_entry_scripts: bool,
+    /// Program that runs on every artifact, with the path of the artifact and of the JSON
+    /// manifest as arguments.
+    ///
+    /// Runs after the commands of the `post-process` metadata key. The
+    /// `BOOTLOADER_ARTIFACT_KIND` environment variable contains the kind of the artifact,
+    /// e.g. `uefi-image`.
+    #[arg(long, value_name = "PROGRAM")]
+    post_process: Vec<PathBuf>,
+    /// Boot the BIOS image in QEMU after creating it.
+    #[arg(long, conflicts_with = "run_uefi")]
+    run: bool,
+    /// Boot the UEFI image in QEMU after creating it.
+    ///
+    /// The OVMF firmware is read from the path in the `OVMF_PATH` environment variable or
+    /// from the install locations of common Linux distributions.
+    #[arg(long)]
+    run_uefi: bool,
+    /// Boot the image on the lab machine of the `remote` metadata table after creating it.
+    ///
+    /// The builder uploads the image, power-cycles the machine, and prints its serial console
+    /// until the run ends.
+    #[arg(long, conflicts_with_all = ["run", "run_uefi"])]
+    run_remote: bool,
+    /// Additional QEMU arguments for `--run` and `--run-uefi`, given after `--`.
+    #[arg(last = true, value_name = "QEMU_ARGS")]
+    qemu_args: Vec<String>,
+    /// Suppress all output except errors.
+    #[arg(long)]
+    quiet: bool,
+    /// Create all artifacts a second time in a temporary directory and fail if they differ
+    /// from the first ones.
+    ///
+    /// The second build reuses the GPT GUIDs of the first one, which are random unless given.
+    /// Both builds use the file timestamp of `SOURCE_DATE_EPOCH`, or the current time if it
+    /// isn't set.
+    #[arg(long)]
+    verify_reproducible: bool,
+}
+
+/// The placement of the UEFI bootloader, see `EspLayout`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum EfiPath {
+    /// `EFI/BOOT/BOOTX64.EFI`
+    #[default]
+    Removable,
+    /// `EFI/<vendor>/bootloader-x64.efi`
+    Vendor,
+    /// Both paths.
+    Both,
+}
+
+/// MBR partition type of unencrypted data partitions.
+const LINUX_PARTITION_TYPE: u8 = 0x83;
+/// MBR partition type of encrypted data partitions.
+///
+/// The header is not LUKS, so the LUKS type `0xe8` would mislead other tools. `0x7f` is
+/// reserved for individual use.
+const ENCRYPTED_PARTITION_TYPE: u8 = 0x7f;
+
+/// The `[package.metadata.bootloader]` table of the kernel's `Cargo.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct BootloaderMetadata {
+    disk_guid: Option<String>,
+    esp_partition_guid: Option<String>,
+    disk_signature: Option<u32>,
+    /// Relative paths are resolved against the directory of the `Cargo.toml`.
+    ramdisk: Option<PathBuf>,
+    command_line: Option<String>,
+    #[serde(default)]
+    derive_guids: bool,
+    #[serde(default)]
+    hybrid_iso: bool,
+    #[serde(default)]
+    efi_stub: bool,
+    #[serde(default)]
+    boot_entry_scripts: bool,
+    #[serde(default)]
+    boot_log: bool,
+    #[serde(default)]
+    compress_kernel: bool,
+    efi_path: Option<EfiPath>,
+    efi_vendor: Option<String>,
+    menu_timeout: Option<u32>,
+    menu_default: Option<usize>,
+    #[serde(default)]
+    extra_mappings: Vec<ExtraMappingMetadata>,
+    log_levels: Option<LogLevelsMetadata>,
+    #[serde(default)]
+    post_process: Vec<hooks::PostProcessCommand>,
+    test: Option<run::TestMetadata>,
+    remote: Option<remote::RemoteMetadata>,
+}
+
+/// An entry of the `extra-mappings` array, e.g.
+/// `{ phys = 0xfee00000, virt = "0xffff_8000_fee0_0000", len = 4096, caching = "uncacheable" }`.
+///
+/// Addresses above `i64::MAX` can't be TOML integers, so they can also be given as hex strings.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ExtraMappingMetadata {
+    phys: Address,
+    virt: Address,
+    len: u64,
+    #[serde(default)]
+    writable: bool,
+    #[serde(default)]
+    executable: bool,
+    #[serde(default)]
+    caching: CachingMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Address {
+    Integer(u64),
+    Hex(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CachingMetadata {
+    #[default]
+    WriteBack,
+    WriteThrough,
+    Uncacheable,
+}
+
+/// The `log-levels` table, e.g. `{ serial = "debug", framebuffer = "warn" }`.
+///
+/// Outputs that are not listed keep the level from the kernel's `BootloaderConfig`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct LogLevelsMetadata {
+    framebuffer: Option<LevelMetadata>,
+    serial: Option<LevelMetadata>,
+    virtio_console: Option<LevelMetadata>,
+    debugcon: Option<LevelMetadata>,
+    memory: Option<LevelMetadata>,
+    network: Option<LevelMetadata>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LevelMetadata {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevelsMetadata {
+    /// Returns the levels of the config of the given kernel, with the listed levels replaced.
+    fn to_levels(&self, kernel: &Path) -> anyhow::Result<LogLevels> {
+        let kernel_bytes = fs::read(kernel)
+            .with_context(|| format!("failed to read kernel `{}`", kernel.display()))?;
+        let mut levels = BootloaderConfig::find_serialized(&kernel_bytes)
+            .and_then(|raw| BootloaderConfig::deserialize(raw).ok())
+            .context("bootloader config not found; kernel must be compiled against bootloader_api")?
+            .log_levels;
+        let outputs = [
+            (self.framebuffer, &mut levels.frame_buffer),
+            (self.serial, &mut levels.serial),
+            (self.virtio_console, &mut levels.virtio_console),
+            (self.debugcon, &mut levels.debugcon),
+            (self.memory, &mut levels.memory),
+            (self.network, &mut levels.network),
+        ];
+        for (metadata, level) in outputs {
+            if let Some(metadata) = metadata {
+                *level = metadata.to_level();
+            }
+        }
+        Ok(levels)
+    }
+}
+
+impl LevelMetadata {
+    fn to_level(self) -> LevelFilter {
+        match self {
+            LevelMetadata::Off => LevelFilter::Off,
+            LevelMetadata::Error => LevelFilter::Error,
+            LevelMetadata::Warn => LevelFilter::Warn,
+            LevelMetadata::Info => LevelFilter::Info,
+            LevelMetadata::Debug => LevelFilter::Debug,
+            LevelMetadata::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+impl ExtraMappingMetadata {
+    fn to_mapping(&self) -> anyhow::Result<ExtraMapping> {
+        Ok(ExtraMapping {
+            phys_start: self.phys.value()?,
+            virt_start: self.virt.value()?,
+            len: self.len,
+            writable: self.writable,
+            executable: self.executable,
+            caching: match self.caching {
+                CachingMetadata::WriteBack => Caching::WriteBack,
+                CachingMetadata::WriteThrough => Caching::WriteThrough,
+                CachingMetadata::Uncacheable => Caching::Uncacheable,
+            },
+        })
+    }
+}
+
+impl Address {
+    fn value(&self) -> anyhow::Result<u64> {
+        match self {
+            Address::Integer(value) => Ok(*value),
+            Address::Hex(value) => {
+                let digits = value.strip_prefix("0x").unwrap_or(value).replace('_', "");
+                u64::from_str_radix(&digits, 16)
+                    .with_context(|| format!("invalid hex address `{value}` in `extra-mappings`"))
+            }
+        }
+    }
+}
+
+/// Describes the artifacts created by the builder.
+///
+/// Written as JSON to `<out-dir>/<kernel-name>.json`.
+#[derive(Debug, Serialize)]
+struct ImageManifest {
+    kernel: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ramdisk: Option<PathBuf>,
+    bios_image: PathBuf,
+    uefi_image: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hybrid_image: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    efi_stub: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    netboot_bundle: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    boot_entry_scripts: Vec<PathBuf>,
+    disk_guid: String,
+    esp_partition_guid: String,
+    disk_signature: String,
+    /// The path of the manifest itself.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl ImageManifest {
+    /// Returns the created artifacts with their kinds, in a fixed order.
+    fn artifacts(&self) -> Vec<(hooks::ArtifactKind, &Path)> {
+        use hooks::ArtifactKind;
+
+        let mut artifacts = vec![
+            (ArtifactKind::BiosImage, self.bios_image.as_path()),
+            (ArtifactKind::UefiImage, self.uefi_image.as_path()),
+        ];
+        if let Some(path) = &self.hybrid_image {
+            artifacts.push((ArtifactKind::HybridImage, path));
+        }
+        if let Some(path) = &self.efi_stub {
+            artifacts.push((ArtifactKind::EfiStub, path));
+        }
+        if let Some(path) = &self.netboot_bundle {
+            artifacts.push((ArtifactKind::NetbootBundle, path));
+        }
+        for path in &self.boot_entry_scripts {
+            artifacts.push((ArtifactKind::BootEntryScript, path));
+        }
+        artifacts
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = BuilderArgs::parse();
+    match args.command {
+        Some(Command::InspectImage(args)) => inspect::run(&args),
+        Some(Command::DiffImages(args)) => patch::diff(&args),
+        Some(Command::ApplyPatch(args)) => patch::apply(&args),
+        Some(Command::PushSerial(args)) => serial::push(&args),
+        Some(Command::SizeReport(args)) => size::run(&args),
+        Some(Command::CoverageReport(args)) => coverage::run(&args),
+        Some(Command::GenerateKey(args)) => sign::generate_key(&args),
+        Some(Command::SignKernel(args)) => sign::sign_kernel(&args),
+        None => build(args.build),
+    }
+}
+
+fn build(args: BuildArgs) -> anyhow::Result<()> {
+    let run = args.run;
+    let run_uefi = args.run_uefi;
+    let qemu_args = args.qemu_args.clone();
+    let quiet = args.quiet;
+    let run_remote = args.run_remote;
+    let mut post_process = Vec::new();
+    let mut test = None;
+    let mut remote = None;
+    if let Some(path) = args.kernel_manifest.first() {
+        let metadata = read_bootloader_metadata(path)?;
+        for command in metadata.post_process {
+            post_process.push(command.relative_to_manifest(path));
+        }
+        test = metadata.test;
+        remote = metadata
+            .remote
+            .map(|remote| remote.relative_to_manifest(path));
+    }
+    if run_remote && remote.is_none() {
+        return Err(anyhow!(
+            "`--run-remote` requires a `[package.metadata.bootloader.remote]` table in the \
+             kernel manifest"
+        ));
+    }
+    for program in &args.post_process {
+        post_process.push(hooks::PostProcessCommand::program(program));
+    }
+
+    let manifest = if args.verify_reproducible {
+        reproducible::verify(args)?
+    } else {
+        create_images(args)?
+    };
+    // post-processing runs only once, after the reproducibility check
+    hooks::run(&post_process, &manifest, quiet)?;
+
+    if run {
+        let firmware = run::Firmware::Bios;
+        run::run(
+            &manifest.bios_image,
+            firmware,
+            &qemu_args,
+            test.as_ref(),
+            quiet,
+        )?;
+    } else if run_uefi {
+        let firmware = run::Firmware::Uefi;
+        run::run(
+            &manifest.uefi_image,
+            firmware,
+            &qemu_args,
+            test.as_ref(),
+            quiet,
+        )?;
+    } else if let (true, Some(remote)) = (run_remote, &remote) {
+        remote::run(remote, &manifest.bios_image, &manifest.uefi_image, quiet)?;
+    }
+
+    Ok(())
+}
+
+/// Creates the images and the other artifacts, and writes the image manifest.
+fn create_images(args: BuildArgs) -> anyhow::Result<ImageManifest> {
+    // both arguments are required when no subcommand is given
+    let mut kernel_binaries = args.kernel_binary;
+    let mut kernel_binary = kernel_binaries.remove(0);
+    let out_dir = args.out_dir.expect("missing out dir argument");
+    if args.kernel_manifest.len() > kernel_binaries.len() + 1 {
+        return Err(anyhow!("more kernel manifests than kernel binaries given"));
+    }
+
+    let kernel_manifest = args.kernel_manifest.first();
+    let metadata = match kernel_manifest {
+        Some(path) => read_bootloader_metadata(path)?,
+        None => BootloaderMetadata::default(),
+    };
+
+    let kernel_name = kernel_binary
+        .file_stem()
+        .ok_or_else(|| anyhow!("kernel binary path has no file name"))?
+        .to_string_lossy()
+        .into_owned();
+    fs::create_dir_all(&out_dir)
+        .with_context(|| format!("failed to create output directory `{}`", out_dir.display()))?;
+
+    // read from the kernel before it is signed and possibly compressed
+    let log_levels = match &metadata.log_levels {
+        Some(log_levels) => Some(log_levels.to_levels(&kernel_binary)?),
+        None => None,
+    };
+
+    let compress_kernel = args.compress_kernel || metadata.compress_kernel;
+    let mut production_keys = args.production_key;
+    if let Some(secret_key) = &args.sign_key {
+        for kernel in std::iter::once(&mut kernel_binary).chain(&mut kernel_binaries) {
+            let name = kernel.file_stem().unwrap_or_default().to_string_lossy();
+            let signed_kernel = out_dir.join(format!("{name}.signed"));
+            bootloader::sign_kernel(kernel, secret_key, &signed_kernel, compress_kernel)?;
+            *kernel = signed_kernel;
+        }
+        let mut public_key = secret_key.clone().into_os_string();
+        public_key.push(".pub");
+        production_keys.push(public_key.into());
+    }
+
+    let ramdisk = match args.ramdisk {
+        Some(path) => Some(path),
+        None => manifest_ramdisk(&metadata, kernel_manifest),
+    };
+    check_ramdisk(ramdisk.as_deref())?;
+
+    // the remaining kernels of the boot menu, with their ramdisk
+    let mut menu_entries = Vec::new();
+    for (index, kernel) in kernel_binaries.iter().enumerate() {
+        let ramdisk = match args.kernel_manifest.get(index + 1) {
+            Some(path) => manifest_ramdisk(&read_bootloader_metadata(path)?, Some(path)),
+            None => None,
+        };
+        check_ramdisk(ramdisk.as_deref())?;
+        let label = kernel
+            .file_stem()
+            .ok_or_else(|| anyhow!("kernel binary path has no file name"))?
+            .to_string_lossy()
+            .into_owned();
+        menu_entries.push((label, kernel, ramdisk));
+    }
+
+    let command_line = args
+        .command_line
+        .or_else(|| metadata.command_line.clone())
+        .unwrap_or_default();
+
+    let extra_mappings = metadata
+        .extra_mappings
+        .iter()
+        .map(ExtraMappingMetadata::to_mapping)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut bios = BiosBoot::new(&kernel_binary);
+    for mapping in &extra_mappings {
+        bios.add_extra_mapping(*mapping);
+    }
+    if let Some(path) = &ramdisk {
+        bios.set_ramdisk(path);
+    }
+    bios.set_command_line(&command_line);
+    if let Some(levels) = log_levels {
+        bios.set_log_levels(levels);
+    }
+    bios.set_serial_kernel_load(args.bios_serial_load);
+    bios.set_compress_kernel(compress_kernel);
+    for (host_path, image_path) in &args.add_file {
+        bios.add_file(image_path, host_path);
+    }
+    for path in &production_keys {
+        bios.add_trusted_key(path);
+    }
+    if let Some(path) = &args.data_partition {
+        let partition = match &args.data_passphrase_file {
+            Some(passphrase_file) => {
+                let passphrase = fs::read(passphrase_file)
+                    .with_context(|| format!("failed to read `{}`", passphrase_file.display()))?;
+                // ignore the trailing newline that most editors add
+                let passphrase = passphrase.strip_suffix(b"\n").unwrap_or(&passphrase);
+                MbrPartition::new(path, ENCRYPTED_PARTITION_TYPE).set_passphrase(passphrase)
+            }
+            None => MbrPartition::new(path, LINUX_PARTITION_TYPE),
+        };
+        bios.add_partition(partition);
+    }
+    if args.derive_guids || metadata.derive_guids {
+        bios.derive_disk_signature_from_kernel_name();
+    }
+    let disk_signature = match args
+        .disk_signature
+        .or(metadata.disk_signature)
+        .or_else(|| bios.disk_signature())
+    {
+        Some(0) => return Err(anyhow!("the disk signature must not be zero")),
+        Some(signature) => signature,
+        // zero means that the disk has no signature
+        None => rand::random::<u32>().max(1),
+    };
+    bios.set_disk_signature(disk_signature);
+    let bios_image = out_dir.join(format!("boot-bios-{kernel_name}.img"));
+    bios.create_disk_image(&bios_image)
+        .context("failed to create BIOS disk image")?;
+
+    let mut uefi = UefiBoot::new(&kernel_binary);
+    if let Some(path) = &ramdisk {
+        uefi.set_ramdisk(path);
+    }
+    uefi.set_command_line(&command_line);
+    if let Some(levels) = log_levels {
+        uefi.set_log_levels(levels);
+    }
+    uefi.set_serial_kernel_load(args.uefi_serial_load);
+    if args.uefi_usb_load {
+        uefi.add_usb_menu_entry("Kernel from USB storage");
+    }
+    uefi.set_boot_log(args.boot_log || metadata.boot_log);
+    uefi.set_compress_kernel(compress_kernel);
+    for (host_path, image_path) in &args.add_file {
+        uefi.add_file(image_path, host_path);
+    }
+    let efi_vendor = args.efi_vendor.or_else(|| metadata.efi_vendor.clone());
+    let esp_layout = match (args.efi_path.or(metadata.efi_path), efi_vendor) {
+        (None | Some(EfiPath::Removable), _) => EspLayout::RemovableMedia,
+        (Some(EfiPath::Vendor), Some(vendor)) => EspLayout::Vendor(vendor),
+        (Some(EfiPath::Both), Some(vendor)) => EspLayout::RemovableMediaAndVendor(vendor),
+        (Some(_), None) => {
+            return Err(anyhow!(
+                "the `vendor` and `both` EFI paths require an EFI vendor name"
+            ))
+        }
+    };
+    uefi.set_esp_layout(esp_layout);
+    if let Some(address) = args.uefi_9p_server {
+        uefi.set_p9_server(address, &args.uefi_9p_export);
+    }
+    for mapping in &extra_mappings {
+        uefi.add_extra_mapping(*mapping);
+    }
+    if args.derive_guids || metadata.derive_guids {
+        uefi.derive_guids_from_kernel_name();
+    }
+    let disk_guid = args
+        .disk_guid
+        .or(parse_guid(metadata.disk_guid.as_deref(), "disk-guid")?)
+        .or_else(|| uefi.disk_guid())
+        .unwrap_or_else(Uuid::new_v4);
+    let esp_partition_guid = args
+        .esp_partition_guid
+        .or(parse_guid(
+            metadata.esp_partition_guid.as_deref(),
+            "esp-partition-guid",
+        )?)
+        .or_else(|| uefi.esp_partition_guid())
+        .unwrap_or_else(Uuid::new_v4);
+    uefi.set_disk_guid(disk_guid)
+        .set_esp_partition_guid(esp_partition_guid);
+    if let Some(path) = &args.recovery_kernel {
+        uefi.set_recovery_kernel(path);
+    }
+    if let Some(path) = &args.support_info {
+        uefi.set_support_info(path);
+    }
+    if let Some(path) = &args.boot_script {
+        uefi.set_boot_script(path);
+    }
+    for (label, kernel, ramdisk) in &menu_entries {
+        uefi.add_menu_entry(label, kernel, ramdisk.as_deref());
+    }
+    if let Some(seconds) = args.menu_timeout.or(metadata.menu_timeout) {
+        uefi.set_menu_timeout(seconds);
+    }
+    if let Some(index) = args.menu_default.or(metadata.menu_default) {
+        uefi.set_menu_default(index);
+    }
+    for path in &production_keys {
+        uefi.add_trusted_key(path, KeyRole::Production);
+    }
+    for path in &args.developer_key {
+        uefi.add_trusted_key(path, KeyRole::Developer);
+    }
+
+    let uefi_image = out_dir.join(format!("boot-uefi-{kernel_name}.img"));
+    uefi.create_disk_image(&uefi_image)
+        .context("failed to create UEFI disk image")?;
+
+    let hybrid_image = if args.hybrid_iso || metadata.hybrid_iso {
+        let path = out_dir.join(format!("boot-hybrid-{kernel_name}.iso"));
+        let mut hybrid = HybridBoot::new(&kernel_binary);
+        if let Some(path) = &ramdisk {
+            hybrid.set_ramdisk(path);
+        }
+        hybrid.set_command_line(&command_line);
+        if let Some(levels) = log_levels {
+            hybrid.set_log_levels(levels);
+        }
+        hybrid.set_compress_kernel(compress_kernel);
+        hybrid.set_disk_signature(disk_signature);
+        for path in &production_keys {
+            hybrid.add_trusted_key(path, KeyRole::Production);
+        }
+        for path in &args.developer_key {
+            hybrid.add_trusted_key(path, KeyRole::Developer);
+        }
+        for (host_path, image_path) in &args.add_file {
+            hybrid.add_file(image_path, host_path);
+        }
+        for mapping in &extra_mappings {
+            hybrid.add_extra_mapping(*mapping);
+        }
+        hybrid
+            .create_hybrid_image(&path)
+            .context("failed to create hybrid ISO image")?;
+        Some(path)
+    } else {
+        None
+    };
+
+    let efi_stub = if args.efi_stub || metadata.efi_stub {
+        let path = out_dir.join(format!("boot-uefi-{kernel_name}.efi"));
+        uefi.create_stub_efi(&path)
+            .context("failed to create UEFI stub executable")?;
+        Some(path)
+    } else {
+        None
+    };
+
+    let netboot_bundle = if let Some(server_address) = args.netboot_bundle {
+        let path = out_dir.join(format!("netboot-{kernel_name}"));
+        uefi.create_netboot_bundle(&path, server_address, Some(&bios_image))
+            .context("failed to create netboot bundle")?;
+        Some(path)
+    } else {
+        None
+    };
+
+    let boot_entry_scripts = if args.boot_entry_scripts || metadata.boot_entry_scripts {
+        let efibootmgr = out_dir.join(format!("boot-entry-{kernel_name}.sh"));
+        uefi.create_efibootmgr_script(&kernel_name, &efibootmgr)?;
+        let bcdedit = out_dir.join(format!("boot-entry-{kernel_name}.ps1"));
+        uefi.create_bcdedit_script(&kernel_name, &bcdedit)?;
+        vec![efibootmgr, bcdedit]
+    } else {
+        Vec::new()
+    };
+
+    let manifest_path = out_dir.join(format!("{kernel_name}.json"));
+    let manifest = ImageManifest {
+        kernel: kernel_binary,
+        ramdisk,
+        bios_image,
+        uefi_image,
+        hybrid_image,
+        efi_stub,
+        netboot_bundle,
+        boot_entry_scripts,
+        disk_guid: disk_guid.to_string(),
+        esp_partition_guid: esp_partition_guid.to_string(),
+        disk_signature: format!("{disk_signature:#010x}"),
+        path: manifest_path.clone(),
+    };
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("failed to write `{}`", manifest_path.display()))?;
+
+    if !args.quiet {
+        println!("Created BIOS image at `{}`", manifest.bios_image.display());
+        println!("Created UEFI image at `{}`", manifest.uefi_image.display());
+        if let Some(path) = &manifest.hybrid_image {
+            println!("Created hybrid ISO image at `{}`", path.display());
+        }
+        if let Some(path) = &manifest.efi_stub {
+            println!("Created UEFI stub executable at `{}`", path.display());
+        }
+        if let Some(path) = &manifest.netboot_bundle {
+            println!("Created netboot bundle at `{}`", path.display());
+        }
+        for path in &manifest.boot_entry_scripts {
+            println!("Created boot entry script at `{}`", path.display());
+        }
+        println!("  GPT disk GUID:           {}", manifest.disk_guid);
+        println!("  EFI partition GUID:      {}", manifest.esp_partition_guid);
+        println!("  MBR disk signature:      {}", manifest.disk_signature);
+        println!("Wrote image manifest to `{}`", manifest_path.display());
+    }
+
+    Ok(manifest)
+}
+
+/// Reads the `[package.metadata.bootloader]` table from the given `Cargo.toml`.
+fn read_bootloader_metadata(manifest_path: &Path) -> anyhow::Result<BootloaderMetadata> {
+    let content = fs::read_to_string(manifest_path)
+        .with_context(|| format!("failed to read `{}`", manifest_path.display()))?;
+    let manifest: toml::Value = content
+        .parse()
+        .with_context(|| format!("failed to parse `{}`", manifest_path.display()))?;
+
+    let metadata = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("bootloader"));
+    match metadata {
+        Some(metadata) => metadata
+            .clone()
+            .try_into()
+            .context("invalid `[package.metadata.bootloader]` table"),
+        None => Ok(BootloaderMetadata::default()),
+    }
+}
+
+/// Returns the `ramdisk` of the metadata, relative to the directory of the `Cargo.toml`.
+fn manifest_ramdisk(
+    metadata: &BootloaderMetadata,
+    manifest_path: Option<&PathBuf>,
+) -> Option<PathBuf> {
+    let path = metadata.ramdisk.as_ref()?;
+    let manifest_dir = manifest_path?.parent().unwrap_or_else(|| Path::new(""));
+    Some(manifest_dir.join(path))
+}
+
+fn check_ramdisk(ramdisk: Option<&Path>) -> anyhow::Result<()> {
+    match ramdisk {
+        Some(path) if !path.is_file() => {
+            Err(anyhow!("ramdisk `{}` does not exist", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn parse_guid(value: Option<&str>, key: &str) -> anyhow::Result<Option<Uuid>> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("invalid GUID `{value}` for `{key}`"))
+        })
+        .transpose()
+}
+
+fn parse_disk_signature(value: &str) -> anyhow::Result<u32> {
+    let digits = value.strip_prefix("0x").unwrap_or(value).replace('_', "");
+    u32::from_str_radix(&digits, 16)
+        .with_context(|| format!("invalid disk signature `{value}`, expected 8 hex digits"))
+}
+
+fn parse_extra_file(value: &str) -> anyhow::Result<(PathBuf, String)> {
+    let (host_path, image_path) = value.rsplit_once(':').with_context(|| {
+        format!("invalid extra file `{value}`, expected `HOST_PATH:IMAGE_PATH`")
+    })?;
+    Ok((PathBuf::from(host_path), image_path.to_owned()))
+}
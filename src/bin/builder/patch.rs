@@ -0,0 +1,262 @@
+//! Implementation of the `diff-images` and `apply-patch` subcommands.
+//!
+//! A patch stores the 512-byte blocks that differ between two images. Since the builder
+//! places files at the same offsets when only their content changes, kernel-only updates
+//! typically result in small patches.
+//!
+//! Patch format (all integers little-endian):
+//!
+//! - magic `BLPATCH1`
+//! - old image length (`u64`), new image length (`u64`)
+//! - SHA-256 hash of the old image, SHA-256 hash of the new image
+//! - a sequence of records until the end of the file, each consisting of a byte offset
+//!   (`u64`), a length (`u32`), and the new data at that offset
+
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::{fs, path::PathBuf};
+
+const MAGIC: &[u8; 8] = b"BLPATCH1";
+const BLOCK_SIZE: usize = 512;
+const HEADER_LEN: usize = MAGIC.len() + 8 + 8 + 32 + 32;
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Path to the image that is installed on the target.
+    #[arg(long)]
+    old: PathBuf,
+    /// Path to the updated image.
+    #[arg(long)]
+    new: PathBuf,
+    /// Path of the patch file to create.
+    #[arg(long)]
+    out: PathBuf,
+    /// Suppress all output except errors.
+    #[arg(long)]
+    quiet: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ApplyArgs {
+    /// Path to the image that the patch was created against.
+    #[arg(long)]
+    image: PathBuf,
+    /// Path to the patch file.
+    #[arg(long)]
+    patch: PathBuf,
+    /// Path of the patched image. Defaults to updating `--image` in place.
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
+pub fn diff(args: &DiffArgs) -> anyhow::Result<()> {
+    let old =
+        fs::read(&args.old).with_context(|| format!("failed to read `{}`", args.old.display()))?;
+    let new =
+        fs::read(&args.new).with_context(|| format!("failed to read `{}`", args.new.display()))?;
+
+    let patch = create_patch(&old, &new);
+    fs::write(&args.out, &patch)
+        .with_context(|| format!("failed to write `{}`", args.out.display()))?;
+
+    if !args.quiet {
+        println!(
+            "Created patch at `{}` ({} bytes, new image is {} bytes)",
+            args.out.display(),
+            patch.len(),
+            new.len()
+        );
+    }
+    Ok(())
+}
+
+pub fn apply(args: &ApplyArgs) -> anyhow::Result<()> {
+    let image = fs::read(&args.image)
+        .with_context(|| format!("failed to read `{}`", args.image.display()))?;
+    let patch = fs::read(&args.patch)
+        .with_context(|| format!("failed to read `{}`", args.patch.display()))?;
+
+    let patched = apply_patch(&image, &patch)
+        .with_context(|| format!("failed to apply `{}`", args.patch.display()))?;
+    let out = args.out.as_ref().unwrap_or(&args.image);
+    fs::write(out, patched).with_context(|| format!("failed to write `{}`", out.display()))
+}
+
+fn create_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::with_capacity(HEADER_LEN);
+    patch.extend_from_slice(MAGIC);
+    patch.extend_from_slice(&(old.len() as u64).to_le_bytes());
+    patch.extend_from_slice(&(new.len() as u64).to_le_bytes());
+    patch.extend_from_slice(&Sha256::digest(old));
+    patch.extend_from_slice(&Sha256::digest(new));
+
+    // bytes beyond the end of the old image are compared against zeros because the
+    // patched image is zero-extended before the records are applied
+    let block_changed = |start: usize| {
+        let end = usize::min(start + BLOCK_SIZE, new.len());
+        let old_block = old.get(start..usize::min(end, old.len())).unwrap_or(&[]);
+        let new_block = &new[start..end];
+        new_block[..old_block.len()] != *old_block
+            || new_block[old_block.len()..].iter().any(|&b| b != 0)
+    };
+
+    let mut start = 0;
+    while start < new.len() {
+        if !block_changed(start) {
+            start += BLOCK_SIZE;
+            continue;
+        }
+        let mut end = start + BLOCK_SIZE;
+        while end < new.len() && block_changed(end) && end - start < u32::MAX as usize {
+            end += BLOCK_SIZE;
+        }
+        let end = usize::min(end, new.len());
+        patch.extend_from_slice(&(start as u64).to_le_bytes());
+        patch.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        patch.extend_from_slice(&new[start..end]);
+        start = end;
+    }
+
+    patch
+}
+
+fn apply_patch(image: &[u8], patch: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if patch.len() < HEADER_LEN || &patch[..MAGIC.len()] != MAGIC {
+        bail!("not a bootloader image patch");
+    }
+    let read_u64 = |offset: usize| u64::from_le_bytes(patch[offset..][..8].try_into().unwrap());
+    let old_len = read_u64(8);
+    let new_len = read_u64(16);
+    let old_hash = &patch[24..56];
+    let new_hash = &patch[56..88];
+
+    if image.len() as u64 != old_len || Sha256::digest(image)[..] != *old_hash {
+        bail!("the patch was created for a different image");
+    }
+
+    let mut patched = image.to_vec();
+    patched.resize(
+        usize::try_from(new_len).context("new image is too large")?,
+        0,
+    );
+    let mut records = &patch[HEADER_LEN..];
+    while !records.is_empty() {
+        if records.len() < 12 {
+            bail!("truncated patch record header");
+        }
+        let offset = u64::from_le_bytes(records[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(records[8..12].try_into().unwrap()) as usize;
+        let data = records
+            .get(12..12 + len)
+            .ok_or_else(|| anyhow!("truncated patch record data"))?;
+        let target = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| patched.get_mut(offset..offset.checked_add(len)?))
+            .ok_or_else(|| anyhow!("patch record at offset {offset:#x} is out of bounds"))?;
+        target.copy_from_slice(data);
+        records = &records[12 + len..];
+    }
+
+    if Sha256::digest(&patched)[..] != *new_hash {
+        bail!("patched image does not match the expected hash");
+    }
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let old = image(20 * BLOCK_SIZE + 100, 1);
+
+        let mut kernel_update = old.clone();
+        kernel_update[3 * BLOCK_SIZE + 7] ^= 0xff;
+        kernel_update[10 * BLOCK_SIZE..12 * BLOCK_SIZE].fill(0xaa);
+        let mut grown = kernel_update.clone();
+        grown.extend(image(3 * BLOCK_SIZE + 5, 2));
+        grown.extend([0; BLOCK_SIZE]);
+        let shrunk = old[..5 * BLOCK_SIZE + 1].to_vec();
+
+        for new in [old.clone(), kernel_update, grown, shrunk, Vec::new()] {
+            let patch = create_patch(&old, &new);
+            assert_eq!(apply_patch(&old, &patch).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn patch_contains_only_changed_blocks() {
+        let old = image(20 * BLOCK_SIZE, 1);
+        assert_eq!(create_patch(&old, &old).len(), HEADER_LEN);
+
+        let mut new = old.clone();
+        new[5 * BLOCK_SIZE] ^= 1;
+        new[6 * BLOCK_SIZE + 1] ^= 1;
+        new[15 * BLOCK_SIZE - 1] ^= 1;
+        // one record for the two adjacent blocks and one for the last changed block
+        assert_eq!(
+            create_patch(&old, &new).len(),
+            HEADER_LEN + 2 * 12 + 3 * BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn wrong_base_image_is_rejected() {
+        let old = image(8 * BLOCK_SIZE, 1);
+        let mut new = old.clone();
+        new[BLOCK_SIZE] ^= 1;
+        let patch = create_patch(&old, &new);
+
+        let mut other = old.clone();
+        other[7 * BLOCK_SIZE] ^= 1;
+        let err = apply_patch(&other, &patch).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the patch was created for a different image"
+        );
+
+        // applying the patch twice must not succeed either
+        let err = apply_patch(&new, &patch).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the patch was created for a different image"
+        );
+
+        let err = apply_patch(&old[..old.len() - 1], &patch).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the patch was created for a different image"
+        );
+    }
+
+    #[test]
+    fn malformed_patches_are_rejected() {
+        let old = image(4 * BLOCK_SIZE, 1);
+        let new = image(4 * BLOCK_SIZE, 2);
+        let patch = create_patch(&old, &new);
+
+        assert!(apply_patch(&old, &patch[..HEADER_LEN - 1]).is_err());
+        assert!(apply_patch(&old, &patch[..HEADER_LEN + 5]).is_err());
+        assert!(apply_patch(&old, &patch[..patch.len() - 1]).is_err());
+
+        let mut corrupted = patch.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = apply_patch(&old, &corrupted).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "patched image does not match the expected hash"
+        );
+
+        let mut out_of_bounds = patch;
+        out_of_bounds[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(apply_patch(&old, &out_of_bounds).is_err());
+    }
+}
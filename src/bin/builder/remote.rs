@@ -0,0 +1,205 @@
+//! Implementation of the `--run-remote` flag and the `remote` metadata table.
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Environment variable that tells the remote commands the path of the image.
+const IMAGE_VAR: &str = "BOOTLOADER_IMAGE";
+
+/// The `[package.metadata.bootloader.remote]` table, which describes a lab machine, e.g.
+///
+/// ```toml
+/// [package.metadata.bootloader.remote]
+/// upload = { command = "scripts/upload-to-pikvm.sh" }
+/// power-cycle = { command = "ipmitool", args = ["-H", "rack3-bmc", "-E", "chassis", "power", "cycle"] }
+/// serial = { command = "ipmitool", args = ["-H", "rack3-bmc", "-E", "sol", "activate"] }
+/// timeout = 120
+/// success = "tests passed"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RemoteMetadata {
+    /// The image that is booted, `uefi` if not given.
+    #[serde(default)]
+    firmware: RemoteFirmware,
+    /// Makes the image available to the machine, e.g. by writing it to the virtual USB drive
+    /// of a PiKVM or by copying it to a PXE server. Runs with the path of the image as last
+    /// argument.
+    upload: RemoteCommand,
+    /// Resets or powers on the machine, e.g. through IPMI.
+    power_cycle: RemoteCommand,
+    /// Prints the serial console of the machine to stdout until it is killed, e.g. an IPMI
+    /// serial-over-LAN session or a connection to a console server.
+    serial: RemoteCommand,
+    /// Seconds after the power cycle after which the run fails.
+    timeout: Option<u64>,
+    /// Serial output that ends the run successfully.
+    success: Option<String>,
+    /// Serial output that fails the run.
+    failure: Option<String>,
+}
+
+/// A command of the `remote` table, e.g. `{ command = "ipmitool", args = ["sol", "activate"] }`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RemoteCommand {
+    /// Relative paths with more than one component are resolved against the directory of
+    /// the `Cargo.toml`, single names are looked up in `PATH`.
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RemoteFirmware {
+    Bios,
+    #[default]
+    Uefi,
+}
+
+impl RemoteMetadata {
+    /// Resolves the commands relative to the directory of the given `Cargo.toml`.
+    pub fn relative_to_manifest(self, manifest_path: &Path) -> Self {
+        Self {
+            upload: self.upload.relative_to_manifest(manifest_path),
+            power_cycle: self.power_cycle.relative_to_manifest(manifest_path),
+            serial: self.serial.relative_to_manifest(manifest_path),
+            ..self
+        }
+    }
+}
+
+impl RemoteCommand {
+    fn relative_to_manifest(mut self, manifest_path: &Path) -> Self {
+        if self.command.is_relative() && self.command.components().count() > 1 {
+            let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+            self.command = manifest_dir.join(&self.command);
+        }
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.command);
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+/// Boots an image on the lab machine and streams its serial console to stdout.
+///
+/// Uploads the BIOS or UEFI image, connects to the serial console, and power-cycles the
+/// machine. The run ends when the serial command exits or the `success` output appears. It
+/// fails on the `failure` output or after the timeout. All commands get the path of the
+/// image in the `BOOTLOADER_IMAGE` environment variable.
+pub fn run(
+    remote: &RemoteMetadata,
+    bios_image: &Path,
+    uefi_image: &Path,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let image = match remote.firmware {
+        RemoteFirmware::Bios => bios_image,
+        RemoteFirmware::Uefi => uefi_image,
+    };
+    let mut upload = remote.upload.command();
+    upload.arg(image);
+    run_command(upload, "upload", image, quiet)?;
+
+    // connect first, so that the early output of the boot isn't lost
+    let mut serial = remote.serial.command();
+    serial.env(IMAGE_VAR, image).stdout(Stdio::piped());
+    if !quiet {
+        println!("Running {serial:?}");
+    }
+    let mut serial = serial
+        .spawn()
+        .context("failed to run the serial console command")?;
+    let stdout = serial.stdout.take().expect("serial stdout is piped");
+    let (lines, received) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = run_command(remote.power_cycle.command(), "power cycle", image, quiet)
+        .and_then(|()| watch_serial(remote, &received));
+    // the serial session would otherwise stay open after the builder exits
+    let _ = serial.kill();
+    let _ = serial.wait();
+    result
+}
+
+/// Prints the serial lines until the run ends, see [`run`].
+fn watch_serial(
+    remote: &RemoteMetadata,
+    lines: &mpsc::Receiver<io::Result<String>>,
+) -> anyhow::Result<()> {
+    let deadline = remote
+        .timeout
+        .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+    let mut stdout = io::stdout().lock();
+    loop {
+        let line = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match lines.recv_timeout(remaining) {
+                    Ok(line) => line,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        bail!("remote run timed out after {}s", remote.timeout.unwrap())
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(mpsc::RecvError) => break,
+            },
+        };
+        let line = line.context("failed to read the serial console")?;
+        writeln!(stdout, "{line}")?;
+        if remote
+            .failure
+            .as_deref()
+            .map_or(false, |f| line.contains(f))
+        {
+            bail!("remote run failed: serial output contains `{line}`");
+        }
+        if remote
+            .success
+            .as_deref()
+            .map_or(false, |s| line.contains(s))
+        {
+            return Ok(());
+        }
+    }
+    if remote.success.is_some() {
+        bail!("serial console closed before the expected output appeared");
+    }
+    Ok(())
+}
+
+fn run_command(mut cmd: Command, step: &str, image: &Path, quiet: bool) -> anyhow::Result<()> {
+    cmd.env(IMAGE_VAR, image);
+    if !quiet {
+        println!("Running {cmd:?}");
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run the {step} command"))?;
+    if !status.success() {
+        bail!("{step} command failed ({status})");
+    }
+    Ok(())
+}
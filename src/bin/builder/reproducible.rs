@@ -0,0 +1,185 @@
+//! Implementation of the `--verify-reproducible` mode.
+
+use crate::{create_images, inspect, BuildArgs, ImageManifest};
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Fixes the timestamps of the files on the FAT partitions.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Creates the artifacts twice and fails with the first difference of every artifact that
+/// differs.
+///
+/// The second build runs in a fresh temporary directory, so it can't pick up files of the
+/// first one. Unless `SOURCE_DATE_EPOCH` is set, it is set to the current time for both
+/// builds. Returns the manifest of the first build.
+pub(crate) fn verify(args: BuildArgs) -> anyhow::Result<ImageManifest> {
+    if env::var_os(SOURCE_DATE_EPOCH).is_none() {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        env::set_var(SOURCE_DATE_EPOCH, now.as_secs().to_string());
+    }
+    let quiet = args.quiet;
+    let mut second_args = args.clone();
+    let first = create_images(args)?;
+
+    let temp_dir = tempfile::tempdir().context("failed to create temp directory")?;
+    // the GUIDs and the disk signature are random unless given, so they are inputs of the build
+    second_args.disk_guid = Some(first.disk_guid.parse()?);
+    second_args.esp_partition_guid = Some(first.esp_partition_guid.parse()?);
+    second_args.disk_signature = Some(crate::parse_disk_signature(&first.disk_signature)?);
+    second_args.out_dir = Some(temp_dir.path().to_owned());
+    second_args.quiet = true;
+    let second = create_images(second_args)?;
+
+    let mut differences = Vec::new();
+    for (first_path, second_path) in artifacts(&first)?.into_iter().zip(artifacts(&second)?) {
+        if let Some(difference) = first_difference(&first_path, &second_path)? {
+            differences.push(format!("`{}`: {difference}", first_path.display()));
+        }
+    }
+    if !differences.is_empty() {
+        bail!(
+            "the build is not reproducible, the second build differs in:\n  {}",
+            differences.join("\n  ")
+        );
+    }
+    if !quiet {
+        println!("Verified that a second build creates identical artifacts");
+    }
+    Ok(first)
+}
+
+/// Returns the files that the build created, in a fixed order.
+///
+/// The files of the netboot bundle are listed in the order of their relative paths.
+fn artifacts(manifest: &ImageManifest) -> anyhow::Result<Vec<PathBuf>> {
+    let mut artifacts = Vec::new();
+    for (_, artifact) in manifest.artifacts() {
+        if !artifact.is_dir() {
+            artifacts.push(artifact.to_owned());
+            continue;
+        }
+        let mut files = Vec::new();
+        let mut dirs = vec![artifact.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)
+                .with_context(|| format!("failed to read directory `{}`", dir.display()))?
+            {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort_by(|a, b| {
+            a.strip_prefix(artifact)
+                .ok()
+                .cmp(&b.strip_prefix(artifact).ok())
+        });
+        artifacts.extend(files);
+    }
+    Ok(artifacts)
+}
+
+/// Describes the first difference between the two files, or returns `None` if they are
+/// identical.
+///
+/// Images are compared structure by structure: first the partition tables, file lists, and
+/// boot metadata of `inspect-image`, then the contents of the files on the FAT partitions,
+/// and finally the raw bytes.
+fn first_difference(first: &Path, second: &Path) -> anyhow::Result<Option<String>> {
+    let read = |path: &Path| {
+        fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))
+    };
+    let (first_bytes, second_bytes) = (read(first)?, read(second)?);
+    if first_bytes == second_bytes {
+        return Ok(None);
+    }
+
+    if let (Ok(first_report), Ok(second_report)) =
+        (inspect::report_json(first), inspect::report_json(second))
+    {
+        if let Some(difference) = json_difference("", &first_report, &second_report) {
+            return Ok(Some(difference));
+        }
+    }
+
+    let partitions = inspect::fat_partitions(first).unwrap_or_default();
+    let second_partitions = inspect::fat_partitions(second).unwrap_or_default();
+    for (partition, second_partition) in partitions.iter().zip(&second_partitions) {
+        for ((path, content), (_, second_content)) in
+            partition.files.iter().zip(&second_partition.files)
+        {
+            if let Some(offset) = mismatch(content, second_content) {
+                return Ok(Some(format!(
+                    "file `{path}` of partition {} at byte {offset:#x}",
+                    partition.number
+                )));
+            }
+        }
+    }
+
+    let offset = mismatch(&first_bytes, &second_bytes).unwrap_or_default();
+    let partition = partitions
+        .iter()
+        .find(|p| (p.offset..p.offset + p.len).contains(&offset));
+    Ok(Some(match partition {
+        Some(partition) => format!(
+            "file system metadata of partition {} (e.g. timestamps) at byte {offset:#x}",
+            partition.number
+        ),
+        None => format!("byte {offset:#x}"),
+    }))
+}
+
+/// Returns the path and the two values of the first differing field.
+fn json_difference(path: &str, first: &Value, second: &Value) -> Option<String> {
+    if first == second {
+        return None;
+    }
+    match (first, second) {
+        (Value::Object(first), Value::Object(second)) => first
+            .keys()
+            .chain(second.keys().filter(|key| !first.contains_key(*key)))
+            .find_map(|key| {
+                json_difference(
+                    &format!("{path}.{key}"),
+                    first.get(key).unwrap_or(&Value::Null),
+                    second.get(key).unwrap_or(&Value::Null),
+                )
+            }),
+        (Value::Array(first), Value::Array(second)) => {
+            (0..first.len().max(second.len())).find_map(|index| {
+                json_difference(
+                    &format!("{path}[{index}]"),
+                    first.get(index).unwrap_or(&Value::Null),
+                    second.get(index).unwrap_or(&Value::Null),
+                )
+            })
+        }
+        _ => Some(format!(
+            "`{}` is {first} instead of {second}",
+            path.trim_start_matches('.')
+        )),
+    }
+}
+
+/// Returns the offset of the first differing byte, or `None` if the slices are equal.
+fn mismatch(first: &[u8], second: &[u8]) -> Option<u64> {
+    if first == second {
+        return None;
+    }
+    let offset = first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .unwrap_or(first.len().min(second.len()));
+    Some(offset as u64)
+}
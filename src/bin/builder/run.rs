@@ -0,0 +1,224 @@
+//! Implementation of the `--run` and `--run-uefi` flags and the `test` metadata table.
+
+use anyhow::{anyhow, bail, Context};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    env,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const QEMU: &str = "qemu-system-x86_64";
+
+/// Environment variable that overrides the location of the OVMF firmware image.
+const OVMF_PATH_VAR: &str = "OVMF_PATH";
+
+/// Locations of the combined OVMF code and variables image in the packages of common Linux
+/// distributions.
+const OVMF_PATHS: &[&str] = &[
+    "/usr/share/ovmf/OVMF.fd",
+    "/usr/share/OVMF/OVMF.fd",
+    "/usr/share/edk2/x64/OVMF.4m.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF.fd",
+    "/usr/share/qemu/ovmf-x86_64.bin",
+];
+
+/// How often the builder checks whether a test finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The `[package.metadata.bootloader.test]` table, e.g.
+/// `{ timeout = 30, expect = ["kernel started"], expect-regex = ["took \\d+ms"] }`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TestMetadata {
+    /// Seconds after which QEMU is stopped and the test fails.
+    timeout: Option<u64>,
+    /// Substrings that the serial output must contain, in this order.
+    #[serde(default)]
+    expect: Vec<String>,
+    /// Regular expressions that the serial output must match, in any order.
+    #[serde(default)]
+    expect_regex: Vec<String>,
+}
+
+/// The firmware that QEMU boots the image with.
+#[derive(Debug, Clone, Copy)]
+pub enum Firmware {
+    Bios,
+    Uefi,
+}
+
+/// Boots the given disk image in QEMU, with the serial port connected to stdout.
+///
+/// The `extra_args` are appended to the QEMU command line. Exits the process with the exit
+/// code of QEMU if it fails, so that kernels can report test results through the
+/// `isa-debug-exit` device. With a `test` table, the serial output is checked against its
+/// expectations, see [`run_test`].
+pub fn run(
+    image: &Path,
+    firmware: Firmware,
+    extra_args: &[String],
+    test: Option<&TestMetadata>,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let mut cmd = Command::new(QEMU);
+    cmd.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()));
+    cmd.arg("-serial").arg("stdio");
+    if let Firmware::Uefi = firmware {
+        cmd.arg("-bios").arg(find_ovmf()?);
+    }
+    cmd.args(extra_args);
+
+    if !quiet {
+        println!("Running {cmd:?}");
+    }
+    let status = match test {
+        Some(test) => run_test(cmd, test)?,
+        None => Some(
+            cmd.status()
+                .with_context(|| format!("failed to run `{QEMU}`, is QEMU installed?"))?,
+        ),
+    };
+    if let Some(status) = status.filter(|status| !status.success()) {
+        process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// Runs QEMU, forwarding the serial output to stdout, and fails with the expectations that
+/// the output didn't meet.
+///
+/// QEMU is stopped as soon as all expectations are met, so kernels don't need to exit on
+/// their own; the test then succeeds. Returns the exit status of QEMU if it exited before.
+fn run_test(mut cmd: Command, test: &TestMetadata) -> anyhow::Result<Option<ExitStatus>> {
+    let regexes = test
+        .expect_regex
+        .iter()
+        .map(|regex| {
+            Regex::new(regex).with_context(|| format!("invalid regex `{regex}` in `expect-regex`"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let has_expectations = !test.expect.is_empty() || !regexes.is_empty();
+    let deadline = test
+        .timeout
+        .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run `{QEMU}`, is QEMU installed?"))?;
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn({
+        let output = output.clone();
+        move || -> io::Result<()> {
+            let mut buf = [0; 4096];
+            loop {
+                let len = stdout.read(&mut buf)?;
+                if len == 0 {
+                    return Ok(());
+                }
+                io::stdout().write_all(&buf[..len])?;
+                output.lock().unwrap().extend_from_slice(&buf[..len]);
+            }
+        }
+    });
+
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        let output = String::from_utf8_lossy(&output.lock().unwrap()).into_owned();
+        if has_expectations && unmet_expectations(&output, test, &regexes).is_empty() {
+            break None;
+        }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    match status {
+        Some(_) => reader
+            .join()
+            .unwrap()
+            .context("failed to read the serial output")?,
+        // the output after this point doesn't matter, so the reader isn't joined
+        None => {
+            child.kill()?;
+            child.wait()?;
+        }
+    }
+
+    let output = String::from_utf8_lossy(&output.lock().unwrap()).into_owned();
+    let unmet = unmet_expectations(&output, test, &regexes);
+    let reason = match test.timeout.filter(|_| timed_out) {
+        Some(timeout) => format!("QEMU timed out after {timeout}s"),
+        None => "QEMU exited".to_owned(),
+    };
+    if !unmet.is_empty() {
+        bail!(
+            "{reason} before the serial output met all expectations:\n  {}",
+            unmet.join("\n  ")
+        );
+    }
+    if timed_out {
+        bail!("{reason}");
+    }
+    // a kernel that met all expectations before it failed still failed
+    Ok(status)
+}
+
+/// Describes the expectations of the test that the serial output doesn't meet.
+fn unmet_expectations(output: &str, test: &TestMetadata, regexes: &[Regex]) -> Vec<String> {
+    let mut unmet = Vec::new();
+    let mut rest = output;
+    for expected in &test.expect {
+        match rest.find(expected.as_str()) {
+            Some(pos) => rest = &rest[pos + expected.len()..],
+            None if output.contains(expected.as_str()) => {
+                unmet.push(format!("`{expected}` (found, but out of order)"))
+            }
+            None => unmet.push(format!("`{expected}`")),
+        }
+    }
+    for regex in regexes {
+        if !regex.is_match(output) {
+            unmet.push(format!("regex `{regex}`"));
+        }
+    }
+    unmet
+}
+
+/// Returns the path of the OVMF firmware image, from `OVMF_PATH` or the first existing
+/// distribution path.
+fn find_ovmf() -> anyhow::Result<PathBuf> {
+    if let Some(path) = env::var_os(OVMF_PATH_VAR) {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            return Err(anyhow!(
+                "OVMF image `{}` from `{OVMF_PATH_VAR}` does not exist",
+                path.display()
+            ));
+        }
+        return Ok(path);
+    }
+    OVMF_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "no OVMF firmware found at {}; install OVMF or set `{OVMF_PATH_VAR}` to the path \
+                 of a combined OVMF image",
+                OVMF_PATHS.join(", ")
+            )
+        })
+}
@@ -0,0 +1,40 @@
+//! Implementation of the `push-serial` subcommand.
+
+use anyhow::Context;
+use clap::Args;
+use std::{fs, path::PathBuf};
+
+#[derive(Debug, Args)]
+pub struct PushArgs {
+    /// Serial port connected to the target (COM1 for BIOS images).
+    ///
+    /// The port must be configured for 115200 baud in raw mode with a read timeout, e.g.
+    /// with `stty -F <port> 115200 raw min 0 time 10` on Linux.
+    #[arg(long)]
+    port: PathBuf,
+    /// Path to the kernel ELF executable.
+    #[arg(long)]
+    kernel_binary: PathBuf,
+    /// Suppress all output except errors.
+    #[arg(long)]
+    quiet: bool,
+}
+
+pub fn push(args: &PushArgs) -> anyhow::Result<()> {
+    let kernel = fs::read(&args.kernel_binary)
+        .with_context(|| format!("failed to read `{}`", args.kernel_binary.display()))?;
+    let mut port = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args.port)
+        .with_context(|| format!("failed to open `{}`", args.port.display()))?;
+
+    if !args.quiet {
+        println!("Waiting for the bootloader on `{}`...", args.port.display());
+    }
+    bootloader::push_kernel_over_serial(&mut port, &kernel)?;
+    if !args.quiet {
+        println!("Sent {} kernel bytes", kernel.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Implementation of the `generate-key` and `sign-kernel` subcommands.
+
+use clap::Args;
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub struct GenerateKeyArgs {
+    /// Path of the secret key, the public key is written next to it with a `.pub` extension.
+    #[arg(long)]
+    out: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct SignArgs {
+    /// Path to the kernel ELF executable.
+    #[arg(long)]
+    kernel_binary: PathBuf,
+    /// Secret key created by `generate-key`.
+    #[arg(long)]
+    key: PathBuf,
+    /// Path of the signed kernel.
+    #[arg(long)]
+    out: PathBuf,
+    /// Compress the kernel before signing it, see `--compress-kernel` of the image commands.
+    #[arg(long)]
+    compress: bool,
+}
+
+pub fn generate_key(args: &GenerateKeyArgs) -> anyhow::Result<()> {
+    let mut public_key = args.out.clone().into_os_string();
+    public_key.push(".pub");
+    let public_key = PathBuf::from(public_key);
+    bootloader::generate_signing_key(&args.out, &public_key)?;
+    println!(
+        "Wrote secret key to `{}` and public key to `{}`",
+        args.out.display(),
+        public_key.display()
+    );
+    Ok(())
+}
+
+pub fn sign_kernel(args: &SignArgs) -> anyhow::Result<()> {
+    bootloader::sign_kernel(&args.kernel_binary, &args.key, &args.out, args.compress)
+}
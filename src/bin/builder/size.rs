@@ -0,0 +1,95 @@
+//! Implementation of the `size-report` subcommand.
+
+use anyhow::Context;
+use clap::Args;
+use serde::Serialize;
+use std::{fs, path::Path};
+
+#[derive(Debug, Args)]
+pub struct SizeReportArgs {
+    /// Print the report as JSON instead of human-readable text.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SizeReport {
+    /// The cargo profile that the boot stages were built with.
+    profile: &'static str,
+    features: Vec<FeatureSizes>,
+}
+
+/// The sizes of the boot stages that a cargo feature of the `bootloader` crate adds.
+#[derive(Debug, Serialize)]
+struct FeatureSizes {
+    feature: &'static str,
+    stages: Vec<StageSize>,
+    total: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct StageSize {
+    stage: &'static str,
+    /// The number of bytes that the stage occupies in the disk images.
+    size: u64,
+}
+
+pub fn run(args: &SizeReportArgs) -> anyhow::Result<()> {
+    let bios_stages = vec![
+        stage_size("boot sector", env!("BIOS_BOOT_SECTOR_PATH"))?,
+        // the stage 2 file is padded up to the end of its memory area
+        StageSize {
+            stage: "stage 2",
+            size: env!("BIOS_STAGE_2_LEN").parse()?,
+        },
+        stage_size("stage 3", env!("BIOS_STAGE_3_PATH"))?,
+        stage_size("stage 4", env!("BIOS_STAGE_4_PATH"))?,
+    ];
+    let uefi_stages = vec![stage_size("bootloader", env!("UEFI_BOOTLOADER_PATH"))?];
+    let report = SizeReport {
+        profile: if cfg!(feature = "min-size") {
+            "min-size"
+        } else {
+            "default"
+        },
+        features: vec![
+            FeatureSizes::new("bios", bios_stages),
+            FeatureSizes::new("uefi", uefi_stages),
+        ],
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!("Boot stages built with the {} profile", report.profile);
+    for feature in &report.features {
+        println!("{}:", feature.feature);
+        for stage in &feature.stages {
+            println!("  {:<12} {}", stage.stage, format_size(stage.size));
+        }
+        println!("  {:<12} {}", "total", format_size(feature.total));
+    }
+    Ok(())
+}
+
+impl FeatureSizes {
+    fn new(feature: &'static str, stages: Vec<StageSize>) -> Self {
+        Self {
+            feature,
+            total: stages.iter().map(|stage| stage.size).sum(),
+            stages,
+        }
+    }
+}
+
+fn stage_size(stage: &'static str, path: &str) -> anyhow::Result<StageSize> {
+    let size = fs::metadata(Path::new(path))
+        .with_context(|| format!("failed to read size of `{path}`"))?
+        .len();
+    Ok(StageSize { stage, size })
+}
+
+fn format_size(size: u64) -> String {
+    format!("{size:>9} bytes ({:.1} KiB)", size as f64 / 1024.0)
+}
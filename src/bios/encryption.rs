@@ -0,0 +1,261 @@
+//! Encryption of extra partitions with AES-256-XTS.
+//!
+//! The encrypted partition starts with a header that is loosely modeled after LUKS: a random
+//! master key encrypts the payload, and the master key itself is stored encrypted with a key
+//! that is derived from the passphrase through PBKDF2-HMAC-SHA256.
+//!
+//! Header layout (all integers little-endian, remaining bytes zero):
+//!
+//! | offset | size | content                                                   |
+//! |--------|------|-----------------------------------------------------------|
+//! | 0      | 8    | magic `BLCRYPT1`                                          |
+//! | 8      | 2    | header version (`1`)                                      |
+//! | 10     | 2    | cipher (`1` = AES-256-XTS, 512-byte sectors, plain64 IVs) |
+//! | 12     | 4    | PBKDF2 iterations                                         |
+//! | 16     | 32   | PBKDF2 salt                                               |
+//! | 48     | 4    | payload offset in sectors                                 |
+//! | 52     | 8    | payload length in sectors                                 |
+//! | 64     | 64   | master key, encrypted with the derived key as sector 0    |
+//! | 128    | 32   | SHA-256 hash of the master key followed by the salt       |
+
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes256,
+};
+use anyhow::Context;
+use hmac::Hmac;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+use xts_mode::{get_tweak_default, Xts128};
+
+const MAGIC: &[u8; 8] = b"BLCRYPT1";
+const HEADER_VERSION: u16 = 1;
+const CIPHER_AES_256_XTS: u16 = 1;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+const SECTOR_SIZE: usize = 512;
+/// The header occupies the first 4KiB of the partition.
+const HEADER_SECTORS: u32 = 8;
+/// Length of the used part of the header, which is copied into the partition keys file.
+pub const HEADER_LEN: usize = 160;
+
+/// Passphrase-based encryption settings of an [`MbrPartition`](super::MbrPartition).
+#[derive(Clone)]
+pub struct PartitionEncryption {
+    passphrase: Vec<u8>,
+}
+
+impl PartitionEncryption {
+    pub fn new(passphrase: &[u8]) -> Self {
+        Self {
+            passphrase: passphrase.to_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for PartitionEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartitionEncryption")
+            .field("passphrase", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Encrypts the given partition image and writes the encrypted partition to `out_path`.
+///
+/// Returns the used part of the written header.
+pub fn encrypt_partition_image(
+    image_path: &Path,
+    encryption: &PartitionEncryption,
+    out_path: &Path,
+) -> anyhow::Result<[u8; HEADER_LEN]> {
+    let mut image = File::open(image_path)
+        .with_context(|| format!("failed to open partition image `{}`", image_path.display()))?;
+    let image_size = image
+        .metadata()
+        .context("failed to read partition image metadata")?
+        .len();
+    let payload_sectors = (image_size + SECTOR_SIZE as u64 - 1) / SECTOR_SIZE as u64;
+
+    let mut salt = [0; 32];
+    let mut master_key = [0; 64];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut master_key);
+
+    let mut derived_key = [0; 64];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(
+        &encryption.passphrase,
+        &salt,
+        PBKDF2_ITERATIONS,
+        &mut derived_key,
+    );
+    let mut encrypted_master_key = master_key;
+    xts_cipher(&derived_key).encrypt_sector(&mut encrypted_master_key, get_tweak_default(0));
+
+    let mut header = [0; HEADER_SECTORS as usize * SECTOR_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    header[8..10].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+    header[10..12].copy_from_slice(&CIPHER_AES_256_XTS.to_le_bytes());
+    header[12..16].copy_from_slice(&PBKDF2_ITERATIONS.to_le_bytes());
+    header[16..48].copy_from_slice(&salt);
+    header[48..52].copy_from_slice(&HEADER_SECTORS.to_le_bytes());
+    header[52..60].copy_from_slice(&payload_sectors.to_le_bytes());
+    header[64..128].copy_from_slice(&encrypted_master_key);
+    header[128..160].copy_from_slice(
+        &Sha256::new()
+            .chain_update(master_key)
+            .chain_update(salt)
+            .finalize(),
+    );
+
+    let mut out = File::create(out_path).with_context(|| {
+        format!(
+            "failed to create encrypted partition at `{}`",
+            out_path.display()
+        )
+    })?;
+    out.write_all(&header)
+        .context("failed to write encryption header")?;
+
+    let cipher = xts_cipher(&master_key);
+    let mut buffer = vec![0; 256 * SECTOR_SIZE];
+    let mut sector = 0;
+    loop {
+        let len = read_full(&mut image, &mut buffer).context("failed to read partition image")?;
+        if len == 0 {
+            break;
+        }
+        // pad the last sector with zeros
+        let padded_len = (len + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        buffer[len..padded_len].fill(0);
+        cipher.encrypt_area(
+            &mut buffer[..padded_len],
+            SECTOR_SIZE,
+            sector,
+            get_tweak_default,
+        );
+        out.write_all(&buffer[..padded_len])
+            .context("failed to write encrypted partition")?;
+        sector += (padded_len / SECTOR_SIZE) as u128;
+    }
+
+    Ok(header[..HEADER_LEN].try_into().unwrap())
+}
+
+/// Creates the partition keys file that tells the bootloader which partitions to unlock.
+///
+/// Each record consists of the MBR partition number as `u32`, four reserved bytes, and the
+/// used part of the partition's header.
+pub fn create_partition_keys_file(
+    headers: &[(u32, [u8; HEADER_LEN])],
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let mut file = Vec::new();
+    for (partition_number, header) in headers {
+        file.extend_from_slice(&partition_number.to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(header);
+    }
+    std::fs::write(out_path, file).context("failed to write partition keys file")
+}
+
+fn xts_cipher(key: &[u8; 64]) -> Xts128<Aes256> {
+    let (data_key, tweak_key) = key.split_at(32);
+    Xts128::new(
+        Aes256::new(GenericArray::from_slice(data_key)),
+        Aes256::new(GenericArray::from_slice(tweak_key)),
+    )
+}
+
+/// Reads until the buffer is full or the end of the file is reached.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// Decrypts an encrypted partition the way the kernel would with the unlocked master key.
+    fn decrypt_partition(partition: &[u8], passphrase: &[u8]) -> Option<Vec<u8>> {
+        let header = &partition[..HEADER_LEN];
+        let iterations = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let salt = &header[16..48];
+        let payload_offset = u32::from_le_bytes(header[48..52].try_into().unwrap()) as usize;
+        let payload_sectors = u64::from_le_bytes(header[52..60].try_into().unwrap()) as usize;
+
+        let mut derived_key = [0; 64];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, salt, iterations, &mut derived_key);
+        let mut master_key: [u8; 64] = header[64..128].try_into().unwrap();
+        xts_cipher(&derived_key).decrypt_sector(&mut master_key, get_tweak_default(0));
+        let hash = Sha256::new()
+            .chain_update(master_key)
+            .chain_update(salt)
+            .finalize();
+        if hash.as_slice() != &header[128..160] {
+            return None;
+        }
+
+        let start = payload_offset * SECTOR_SIZE;
+        let mut payload = partition[start..start + payload_sectors * SECTOR_SIZE].to_vec();
+        xts_cipher(&master_key).decrypt_area(&mut payload, SECTOR_SIZE, 0, get_tweak_default);
+        Some(payload)
+    }
+
+    #[test]
+    fn round_trip() {
+        let content: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        let image = NamedTempFile::new().unwrap();
+        std::fs::write(image.path(), &content).unwrap();
+        let encrypted = NamedTempFile::new().unwrap();
+        let header = encrypt_partition_image(
+            image.path(),
+            &PartitionEncryption::new(b"secret"),
+            encrypted.path(),
+        )
+        .unwrap();
+
+        let partition = std::fs::read(encrypted.path()).unwrap();
+        assert_eq!(&partition[..HEADER_LEN], &header);
+        assert_eq!(&header[..8], MAGIC);
+        assert_eq!(
+            partition.len(),
+            HEADER_SECTORS as usize * SECTOR_SIZE + 6 * SECTOR_SIZE
+        );
+        assert!(!partition.windows(64).any(|window| window == &content[..64]));
+
+        let decrypted = decrypt_partition(&partition, b"secret").unwrap();
+        assert_eq!(&decrypted[..content.len()], &content);
+        assert!(decrypted[content.len()..].iter().all(|&b| b == 0));
+        assert!(decrypt_partition(&partition, b"wrong").is_none());
+    }
+
+    #[test]
+    fn partition_keys_file() {
+        let out = NamedTempFile::new().unwrap();
+        create_partition_keys_file(&[(3, [1; HEADER_LEN]), (4, [2; HEADER_LEN])], out.path())
+            .unwrap();
+        let file = std::fs::read(out.path()).unwrap();
+        assert_eq!(file.len(), 2 * (8 + HEADER_LEN));
+        assert_eq!(&file[..8], &[3, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&file[8..8 + HEADER_LEN], &[1; HEADER_LEN]);
+        assert_eq!(
+            &file[8 + HEADER_LEN..16 + HEADER_LEN],
+            &[4, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -1,5 +1,7 @@
+use super::MbrPartition;
 use anyhow::Context;
-use mbrman::BOOT_ACTIVE;
+use mbrman::{BOOT_ACTIVE, BOOT_INACTIVE};
+use sha2::{Digest, Sha256};
 use std::{
     fs::{self, File},
     io::{self, Seek, SeekFrom},
@@ -7,12 +9,42 @@ use std::{
 };
 const SECTOR_SIZE: u32 = 512;
 
+/// FAT32 with LBA addressing.
+const FAT32_LBA: u8 = 0x0c;
+/// Hidden variant of [`FAT32_LBA`], which operating systems don't mount automatically.
+const HIDDEN_FAT32_LBA: u8 = 0x1c;
+
+/// The number of partition entries that are not used by the bootloader itself.
+const MAX_EXTRA_PARTITIONS: usize = 2;
+
+/// Returns a random non-zero disk signature.
+pub fn random_disk_signature() -> u32 {
+    rand::random::<u32>().max(1)
+}
+
+/// Returns a non-zero disk signature that is derived from the given kernel name.
+pub fn derived_disk_signature(kernel_name: &str) -> u32 {
+    let hash = Sha256::digest(format!("{kernel_name}/disk-signature").as_bytes());
+    let signature = u32::from_le_bytes(hash[..4].try_into().unwrap());
+    signature.max(1)
+}
+
 pub fn create_mbr_disk(
     bootsector_path: &Path,
     second_stage_path: &Path,
     boot_partition_path: &Path,
+    extra_partitions: &[MbrPartition],
+    hidden_boot_partition: bool,
+    disk_signature: u32,
     out_mbr_path: &Path,
 ) -> anyhow::Result<()> {
+    if extra_partitions.len() > MAX_EXTRA_PARTITIONS {
+        anyhow::bail!(
+            "at most {MAX_EXTRA_PARTITIONS} extra partitions are supported, got {}",
+            extra_partitions.len()
+        );
+    }
+
     let mut boot_sector = File::open(bootsector_path).context("failed to open boot sector")?;
     let mut mbr =
         mbrman::MBR::read_from(&mut boot_sector, SECTOR_SIZE).context("failed to read MBR")?;
@@ -22,6 +54,8 @@ pub fn create_mbr_disk(
             anyhow::bail!("partition {index} should be unused");
         }
     }
+    // the boot sector reserves the signature bytes at offset 440, which identify the disk
+    mbr.header.disk_signature = disk_signature.to_le_bytes();
 
     let mut second_stage =
         File::open(second_stage_path).context("failed to open second stage binary")?;
@@ -51,19 +85,56 @@ pub fn create_mbr_disk(
         .metadata()
         .context("failed to read file metadata of FAT boot partition")?
         .len();
+    let boot_partition_sectors = ((boot_partition_size - 1) / u64::from(SECTOR_SIZE) + 1)
+        .try_into()
+        .context("size of FAT partition is larger than u32::MAX")?;
     mbr[2] = mbrman::MBRPartitionEntry {
         boot: BOOT_ACTIVE,
         starting_lba: boot_partition_start_sector,
-        sectors: ((boot_partition_size - 1) / u64::from(SECTOR_SIZE) + 1)
-            .try_into()
-            .context("size of FAT partition is larger than u32::MAX")?,
-        //TODO: is this the correct type?
-        sys: 0x0c, // FAT32 with LBA
+        sectors: boot_partition_sectors,
+        sys: if hidden_boot_partition {
+            HIDDEN_FAT32_LBA
+        } else {
+            FAT32_LBA
+        },
 
         first_chs: mbrman::CHS::empty(),
         last_chs: mbrman::CHS::empty(),
     };
 
+    let mut next_start_sector = boot_partition_start_sector
+        .checked_add(boot_partition_sectors)
+        .context("disk image is larger than u32::MAX sectors")?;
+    for (index, partition) in extra_partitions.iter().enumerate() {
+        let size = fs::metadata(&partition.image)
+            .with_context(|| {
+                format!(
+                    "failed to read file metadata of partition image `{}`",
+                    partition.image.display()
+                )
+            })?
+            .len();
+        let sectors: u32 = ((size.max(1) - 1) / u64::from(SECTOR_SIZE) + 1)
+            .try_into()
+            .context("size of partition image is larger than u32::MAX")?;
+        mbr[index + 3] = mbrman::MBRPartitionEntry {
+            boot: if partition.bootable {
+                BOOT_ACTIVE
+            } else {
+                BOOT_INACTIVE
+            },
+            starting_lba: next_start_sector,
+            sectors,
+            sys: partition.partition_type,
+
+            first_chs: mbrman::CHS::empty(),
+            last_chs: mbrman::CHS::empty(),
+        };
+        next_start_sector = next_start_sector
+            .checked_add(sectors)
+            .context("disk image is larger than u32::MAX sectors")?;
+    }
+
     let mut disk = fs::OpenOptions::new()
         .create(true)
         .truncate(true)
@@ -97,5 +168,123 @@ pub fn create_mbr_disk(
     io::copy(&mut boot_partition, &mut disk)
         .context("failed to copy FAT image to MBR disk image")?;
 
+    // extra partitions
+    for (index, partition) in extra_partitions.iter().enumerate() {
+        let entry = &mbr[index + 3];
+        disk.seek(SeekFrom::Start(
+            u64::from(entry.starting_lba) * u64::from(SECTOR_SIZE),
+        ))
+        .context("seek failed")?;
+        let mut image = File::open(&partition.image).with_context(|| {
+            format!(
+                "failed to open partition image `{}`",
+                partition.image.display()
+            )
+        })?;
+        io::copy(&mut image, &mut disk)
+            .context("failed to copy partition image to MBR disk image")?;
+    }
+    let disk_size = u64::from(next_start_sector) * u64::from(SECTOR_SIZE);
+    if disk
+        .metadata()
+        .context("failed to read disk image metadata")?
+        .len()
+        < disk_size
+    {
+        disk.set_len(disk_size)
+            .context("failed to set MBR disk image length")?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn partition_table(
+        hidden_boot_partition: bool,
+        extra_partitions: &[MbrPartition],
+    ) -> Vec<(u8, u8, u32, u32)> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let mut boot_sector = vec![0; 510];
+        boot_sector.extend([0x55, 0xaa]);
+        fs::write(path("boot-sector"), boot_sector).unwrap();
+        fs::write(path("stage-2"), [2; 1000]).unwrap();
+        fs::write(path("fat"), [3; 4096]).unwrap();
+
+        create_mbr_disk(
+            &path("boot-sector"),
+            &path("stage-2"),
+            &path("fat"),
+            extra_partitions,
+            hidden_boot_partition,
+            0x1234abcd,
+            &path("disk"),
+        )
+        .unwrap();
+
+        let mut mbr = [0; 512];
+        File::open(path("disk"))
+            .unwrap()
+            .read_exact(&mut mbr)
+            .unwrap();
+        assert_eq!(mbr[440..444], 0x1234abcd_u32.to_le_bytes());
+        mbr[446..510]
+            .chunks(16)
+            .map(|entry| {
+                let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+                let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+                (entry[0], entry[4], start, sectors)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boot_partition_type() {
+        let table = partition_table(false, &[]);
+        assert_eq!(
+            table,
+            [
+                (BOOT_ACTIVE, 0x20, 1, 2),
+                (BOOT_ACTIVE, FAT32_LBA, 3, 8),
+                (0, 0, 0, 0),
+                (0, 0, 0, 0)
+            ]
+        );
+        // hiding the boot partition only changes its type
+        let hidden = partition_table(true, &[]);
+        assert_eq!(hidden[1], (BOOT_ACTIVE, HIDDEN_FAT32_LBA, 3, 8));
+        assert_eq!(hidden[0], table[0]);
+    }
+
+    #[test]
+    fn extra_partitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        fs::write(&data, [4; 513]).unwrap();
+        let partitions = [
+            MbrPartition::new(&data, 0x83),
+            MbrPartition::new(&data, 0x07).set_bootable(true),
+        ];
+        let table = partition_table(false, &partitions);
+        assert_eq!(table[2], (BOOT_INACTIVE, 0x83, 11, 2));
+        assert_eq!(table[3], (BOOT_ACTIVE, 0x07, 13, 2));
+
+        let dir = tempfile::tempdir().unwrap();
+        let too_many = vec![MbrPartition::new(&data, 0x83); 3];
+        let err = create_mbr_disk(
+            &data,
+            &data,
+            &data,
+            &too_many,
+            false,
+            1,
+            &dir.path().join("disk"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("at most 2 extra partitions"));
+    }
+}
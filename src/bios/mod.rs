@@ -1,20 +1,86 @@
-use crate::fat;
+use crate::{
+    compression::KernelFile,
+    extra_files::{self, ExtraFile},
+    fat, metadata, signing, DiskImageBuilder,
+};
 use anyhow::Context;
+use bootloader_api::{config::LogLevels, info::ExtraMapping};
 use std::{
     collections::BTreeMap,
+    fs,
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
 
+mod encryption;
 mod mbr;
+mod pxe;
+
+pub(crate) use mbr::random_disk_signature;
 
-const BIOS_STAGE_3: &str = "boot-stage-3";
-const BIOS_STAGE_4: &str = "boot-stage-4";
+pub(crate) const BIOS_STAGE_3: &str = "boot-stage-3";
+pub(crate) const BIOS_STAGE_4: &str = "boot-stage-4";
 
 /// Create disk images for booting on legacy BIOS systems.
 pub struct BiosBoot {
     kernel: PathBuf,
     ramdisk: Option<PathBuf>,
+    extra_partitions: Vec<MbrPartition>,
+    hidden_boot_partition: bool,
+    disk_signature: Option<u32>,
+    serial_kernel_load: bool,
+    extra_mappings: Vec<ExtraMapping>,
+    log_levels: Option<LogLevels>,
+    command_line: String,
+    trusted_keys: Vec<PathBuf>,
+    compress_kernel: bool,
+    extra_files: Vec<ExtraFile>,
+    artifacts: DiskImageBuilder,
+}
+
+/// An additional primary partition for BIOS disk images.
+///
+/// The bootloader occupies the first two entries of the MBR partition table, so up to two
+/// additional partitions can be added. They are placed behind the bootloader's partitions.
+#[derive(Debug, Clone)]
+pub struct MbrPartition {
+    image: PathBuf,
+    partition_type: u8,
+    bootable: bool,
+    encryption: Option<encryption::PartitionEncryption>,
+}
+
+impl MbrPartition {
+    /// Creates a partition of the given MBR partition type (e.g. `0x83` for Linux).
+    ///
+    /// The partition is sized to fit the given image file, whose content is copied into it.
+    pub fn new(image_path: &Path, partition_type: u8) -> Self {
+        Self {
+            image: image_path.to_owned(),
+            partition_type,
+            bootable: false,
+            encryption: None,
+        }
+    }
+
+    /// Set the active (bootable) flag of the partition entry.
+    pub fn set_bootable(mut self, bootable: bool) -> Self {
+        self.bootable = bootable;
+        self
+    }
+
+    /// Encrypt the partition content with AES-256-XTS using a key protected by the given
+    /// passphrase.
+    ///
+    /// The partition starts with a 4KiB header that stores the encrypted master key and the
+    /// key derivation parameters, followed by the encrypted image. The bootloader asks for
+    /// the passphrase at boot and passes the unlocked master key to the kernel through
+    /// [`BootInfo::partition_keys`](bootloader_api::BootInfo::partition_keys). Avoid the LUKS
+    /// partition type `0xe8` because the header format is not LUKS; the builder uses `0x7f`.
+    pub fn set_passphrase(mut self, passphrase: &[u8]) -> Self {
+        self.encryption = Some(encryption::PartitionEncryption::new(passphrase));
+        self
+    }
 }
 
 impl BiosBoot {
@@ -23,6 +89,17 @@ impl BiosBoot {
         Self {
             kernel: kernel_path.to_owned(),
             ramdisk: None,
+            extra_partitions: Vec::new(),
+            hidden_boot_partition: false,
+            disk_signature: None,
+            serial_kernel_load: false,
+            extra_mappings: Vec::new(),
+            log_levels: None,
+            command_line: String::new(),
+            trusted_keys: Vec::new(),
+            compress_kernel: false,
+            extra_files: Vec::new(),
+            artifacts: DiskImageBuilder::new(),
         }
     }
 
@@ -32,19 +109,156 @@ impl BiosBoot {
         self
     }
 
+    /// Append an additional primary partition to the disk image.
+    ///
+    /// At most two additional partitions are supported.
+    pub fn add_partition(&mut self, partition: MbrPartition) -> &mut Self {
+        self.extra_partitions.push(partition);
+        self
+    }
+
+    /// Mark the bootloader's FAT partition as hidden FAT32 partition (type `0x1c`).
+    ///
+    /// Operating systems don't mount hidden partitions automatically, which protects the
+    /// kernel and bootloader files from accidental modification when the disk is attached
+    /// to another machine. The partition table layout stays the same.
+    pub fn set_hidden_boot_partition(&mut self, enable: bool) -> &mut Self {
+        self.hidden_boot_partition = enable;
+        self
+    }
+
+    /// Set the disk signature of the MBR, which the kernel sees as
+    /// `PartitionSignature::Mbr` in `BootInfo::boot_disk` to identify the boot disk.
+    ///
+    /// If not set, a random non-zero signature is generated for every created disk image.
+    /// The signature must not be zero, since zero means that the disk has no signature.
+    pub fn set_disk_signature(&mut self, signature: u32) -> &mut Self {
+        self.disk_signature = Some(signature);
+        self
+    }
+
+    /// Derive the disk signature from the file name of the kernel, so that it stays the same
+    /// across builds of a kernel with the same name.
+    pub fn derive_disk_signature_from_kernel_name(&mut self) -> &mut Self {
+        let kernel_name = self
+            .kernel
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.disk_signature = Some(mbr::derived_disk_signature(&kernel_name));
+        self
+    }
+
+    /// Returns the configured disk signature, if any.
+    pub fn disk_signature(&self) -> Option<u32> {
+        self.disk_signature
+    }
+
+    /// Set the command line that is passed to the kernel.
+    ///
+    /// The command line is stored in the boot metadata block and reported in
+    /// `BootInfo::command_line`. At most `CommandLine::MAX_LEN` bytes are supported.
+    pub fn set_command_line(&mut self, command_line: &str) -> &mut Self {
+        self.command_line = command_line.to_owned();
+        self
+    }
+
+    /// Place the file at `host_path` on the boot partition under `image_path`, e.g. a
+    /// firmware blob or a configuration file that the kernel reads after boot.
+    ///
+    /// The image path is relative to the root of the partition and uses `/` as separator, e.g.
+    /// `firmware/wifi.bin`. Missing directories are created. The path must not conflict with
+    /// the files of the bootloader. The file is also placed into the PXE folder.
+    pub fn add_file(&mut self, image_path: &str, host_path: &Path) -> &mut Self {
+        self.extra_files.push(ExtraFile {
+            image_path: image_path.to_owned(),
+            host_path: host_path.to_owned(),
+        });
+        self
+    }
+
+    /// Set the log levels of the bootloader outputs for this kernel.
+    ///
+    /// The levels are stored in the boot metadata block and replace the `log_levels` of the
+    /// kernel's `BootloaderConfig` once the block is verified.
+    pub fn set_log_levels(&mut self, levels: LogLevels) -> &mut Self {
+        self.log_levels = Some(levels);
+        self
+    }
+
+    /// Map the given physical range at a fixed virtual address before entering the kernel.
+    ///
+    /// This is useful for device registers that the kernel accesses early, e.g. the local
+    /// APIC or the HPET. The mappings are stored in the boot metadata block and reported in
+    /// the `extra_mappings` field of `BootInfo::boot_metadata`. At most
+    /// `ExtraMappings::MAX_MAPPINGS` mappings are supported.
+    pub fn add_extra_mapping(&mut self, mapping: ExtraMapping) -> &mut Self {
+        self.extra_mappings.push(mapping);
+        self
+    }
+
+    /// Receive the kernel over the serial port instead of using the kernel from the disk image.
+    ///
+    /// This is a developer mode that avoids rebuilding and reflashing the disk image after
+    /// every kernel change. On boot, the fourth stage waits for a kernel on COM1, which can
+    /// be sent with [`push_kernel_over_serial`](crate::push_kernel_over_serial). The kernel is still placed on the disk image
+    /// because the second stage expects it, but it is not used.
+    pub fn set_serial_kernel_load(&mut self, enable: bool) -> &mut Self {
+        self.serial_kernel_load = enable;
+        self
+    }
+
+    /// Only boot kernels that are signed with the given public key, see
+    /// [`sign_kernel`](crate::sign_kernel).
+    ///
+    /// The key must have been created by [`generate_signing_key`](crate::generate_signing_key).
+    /// It is embedded into the fourth stage, which refuses kernels that aren't signed with
+    /// one of its keys. Once a key is added, unsigned kernels no longer boot. At most 8 keys
+    /// are supported. The BIOS bootloader has no developer mode, so all keys are production
+    /// keys.
+    pub fn add_trusted_key(&mut self, public_key_path: &Path) -> &mut Self {
+        self.trusted_keys.push(public_key_path.to_owned());
+        self
+    }
+
+    /// Compress the kernel with LZ4 to reduce the size of the image and the load time.
+    ///
+    /// This applies to the disk image and the PXE folder. The fourth stage decompresses the
+    /// kernel before parsing it, which needs a second contiguous block of usable memory
+    /// below 4GiB with the size of the uncompressed kernel.
+    pub fn set_compress_kernel(&mut self, enable: bool) -> &mut Self {
+        self.compress_kernel = enable;
+        self
+    }
+
+    /// Use the bootloader executables of the given builder, see
+    /// [`DiskImageBuilder::bios_boot`].
+    pub(crate) fn set_artifacts(&mut self, artifacts: DiskImageBuilder) -> &mut Self {
+        self.artifacts = artifacts;
+        self
+    }
+
     /// Create a bootable BIOS disk image at the given path.
     pub fn create_disk_image(&self, out_path: &Path) -> anyhow::Result<()> {
-        let bootsector_path = Path::new(env!("BIOS_BOOT_SECTOR_PATH"));
-        let stage_2_path = Path::new(env!("BIOS_STAGE_2_PATH"));
+        let bootsector_path = self.artifacts.bios_boot_sector_path();
+        let stage_2_path = self.artifacts.bios_stage_2_path();
+
+        let (extra_partitions, encrypted_images, partition_keys) = self
+            .encrypt_extra_partitions()
+            .context("failed to encrypt partition")?;
 
         let fat_partition = self
-            .create_fat_partition()
+            .create_fat_partition(partition_keys.as_ref().map(|file| file.path()))
             .context("failed to create FAT partition")?;
 
         mbr::create_mbr_disk(
             bootsector_path,
             stage_2_path,
             fat_partition.path(),
+            &extra_partitions,
+            self.hidden_boot_partition,
+            self.disk_signature
+                .unwrap_or_else(mbr::random_disk_signature),
             out_path,
         )
         .context("failed to create BIOS MBR disk image")?;
@@ -52,22 +266,139 @@ impl BiosBoot {
         fat_partition
             .close()
             .context("failed to delete FAT partition after disk image creation")?;
+        for image in encrypted_images {
+            image
+                .close()
+                .context("failed to delete encrypted partition after disk image creation")?;
+        }
 
         Ok(())
     }
 
+    /// Prepare a folder for booting BIOS clients over PXE.
+    ///
+    /// This places a network boot program under the path "bootloader.0", next to the
+    /// bootloader stages, the kernel, and the other boot files. The DHCP server should set the
+    /// filename option to that path. The second stage loads the other files over TFTP from the
+    /// same folder, so the TFTP server needs to support the `tsize` option. Extra partitions
+    /// are not part of the folder.
+    pub fn create_pxe_tftp_folder(&self, out_path: &Path) -> anyhow::Result<()> {
+        let stage_4 = self.stage_4_with_trusted_keys()?;
+        let kernel = KernelFile::new(&self.kernel, self.compress_kernel)?;
+        pxe::create_bios_tftp_folder(
+            &self.artifacts,
+            self.stage_4_path(&stage_4),
+            kernel.path(),
+            self.ramdisk.as_deref(),
+            self.serial_kernel_load,
+            out_path,
+        )
+        .context("failed to create BIOS PXE tftp folder")?;
+        metadata::create_boot_metadata_file(
+            &self.kernel,
+            &self.extra_mappings,
+            &self.command_line,
+            self.log_levels,
+            &out_path.join(crate::BOOT_METADATA_FILE_NAME),
+        )
+        .context("failed to create boot metadata")?;
+        metadata::create_video_mode_file(&self.kernel, &out_path.join(crate::VIDEO_MODE_FILE_NAME))
+            .context("failed to create video mode file")?;
+        let reserved = [
+            pxe::NETWORK_BOOT_PROGRAM_FILE_NAME,
+            BIOS_STAGE_3,
+            BIOS_STAGE_4,
+            crate::KERNEL_FILE_NAME,
+            crate::RAMDISK_FILE_NAME,
+            crate::SERIAL_LOAD_FILE_NAME,
+            crate::BOOT_METADATA_FILE_NAME,
+            crate::VIDEO_MODE_FILE_NAME,
+        ];
+        extra_files::copy_to_folder(&self.extra_files, &reserved, out_path)?;
+
+        Ok(())
+    }
+
+    /// Replaces the images of partitions that should be encrypted with encrypted copies.
+    ///
+    /// Also creates the partition keys file for the bootloader if any partition is encrypted.
+    /// The returned temporary files must be kept alive until the disk image is created.
+    #[allow(clippy::type_complexity)]
+    fn encrypt_extra_partitions(
+        &self,
+    ) -> anyhow::Result<(Vec<MbrPartition>, Vec<NamedTempFile>, Option<NamedTempFile>)> {
+        let mut partitions = Vec::new();
+        let mut encrypted_images = Vec::new();
+        let mut headers = Vec::new();
+        for (index, partition) in self.extra_partitions.iter().enumerate() {
+            let mut partition = partition.clone();
+            if let Some(encryption) = partition.encryption.take() {
+                let out_file = NamedTempFile::new().context("failed to create temp file")?;
+                let header = encryption::encrypt_partition_image(
+                    &partition.image,
+                    &encryption,
+                    out_file.path(),
+                )?;
+                // the bootloader's partitions occupy the first two MBR entries
+                headers.push((index as u32 + 3, header));
+                partition.image = out_file.path().to_owned();
+                encrypted_images.push(out_file);
+            }
+            partitions.push(partition);
+        }
+        let partition_keys = if headers.is_empty() {
+            None
+        } else {
+            let out_file = NamedTempFile::new().context("failed to create temp file")?;
+            encryption::create_partition_keys_file(&headers, out_file.path())?;
+            Some(out_file)
+        };
+        Ok((partitions, encrypted_images, partition_keys))
+    }
+
     /// Creates an BIOS-bootable FAT partition with the kernel.
-    fn create_fat_partition(&self) -> anyhow::Result<NamedTempFile> {
-        let stage_3_path = Path::new(env!("BIOS_STAGE_3_PATH"));
-        let stage_4_path = Path::new(env!("BIOS_STAGE_4_PATH"));
+    fn create_fat_partition(&self, partition_keys: Option<&Path>) -> anyhow::Result<NamedTempFile> {
+        let stage_3_path = self.artifacts.bios_stage_3_path();
+        let stage_4 = self.stage_4_with_trusted_keys()?;
+        let stage_4_path = self.stage_4_path(&stage_4);
+
+        metadata::check_dma_address_limit(
+            &self.kernel,
+            self.ramdisk.as_deref(),
+            metadata::RamdiskPlacement::Bios,
+        )?;
+        let boot_metadata = NamedTempFile::new().context("failed to create temp file")?;
+        metadata::create_boot_metadata_file(
+            &self.kernel,
+            &self.extra_mappings,
+            &self.command_line,
+            self.log_levels,
+            boot_metadata.path(),
+        )
+        .context("failed to create boot metadata")?;
+        let video_mode = NamedTempFile::new().context("failed to create temp file")?;
+        metadata::create_video_mode_file(&self.kernel, video_mode.path())
+            .context("failed to create video mode file")?;
+
+        let kernel = KernelFile::new(&self.kernel, self.compress_kernel)?;
 
         let mut files = BTreeMap::new();
-        files.insert(crate::KERNEL_FILE_NAME, self.kernel.as_path());
+        files.insert(crate::KERNEL_FILE_NAME, kernel.path());
         files.insert(BIOS_STAGE_3, stage_3_path);
         files.insert(BIOS_STAGE_4, stage_4_path);
         if let Some(ramdisk_path) = &self.ramdisk {
             files.insert(crate::RAMDISK_FILE_NAME, ramdisk_path);
         }
+        files.insert(crate::BOOT_METADATA_FILE_NAME, boot_metadata.path());
+        files.insert(crate::VIDEO_MODE_FILE_NAME, video_mode.path());
+        if let Some(partition_keys) = partition_keys {
+            files.insert(crate::PARTITION_KEYS_FILE_NAME, partition_keys);
+        }
+        let serial_load_marker = NamedTempFile::new().context("failed to create temp file")?;
+        if self.serial_kernel_load {
+            files.insert(crate::SERIAL_LOAD_FILE_NAME, serial_load_marker.path());
+        }
+        extra_files::insert(&mut files, &self.extra_files)?;
 
         let out_file = NamedTempFile::new().context("failed to create temp file")?;
         fat::create_fat_filesystem(files, out_file.path())
@@ -75,4 +406,45 @@ impl BiosBoot {
 
         Ok(out_file)
     }
+
+    /// Creates a copy of the fourth stage with the trusted keys embedded, if any.
+    fn stage_4_with_trusted_keys(&self) -> anyhow::Result<Option<NamedTempFile>> {
+        stage_4_with_trusted_keys(&self.artifacts, &self.trusted_keys)
+    }
+
+    /// Returns the path of the fourth stage created by [`Self::stage_4_with_trusted_keys`].
+    fn stage_4_path<'a>(&'a self, stage_4: &'a Option<NamedTempFile>) -> &'a Path {
+        stage_4
+            .as_ref()
+            .map_or(self.artifacts.bios_stage_4_path(), |file| file.path())
+    }
+}
+
+/// Creates a copy of the fourth stage with the trusted keys embedded, if any.
+pub(crate) fn stage_4_with_trusted_keys(
+    artifacts: &DiskImageBuilder,
+    trusted_keys: &[PathBuf],
+) -> anyhow::Result<Option<NamedTempFile>> {
+    if trusted_keys.is_empty() {
+        return Ok(None);
+    }
+    let keys = trusted_keys
+        .iter()
+        .map(|path| {
+            signing::read_public_key(path)
+                .with_context(|| format!("failed to read trusted key `{}`", path.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let stage_4_path = artifacts.bios_stage_4_path();
+    let stage_4 = fs::read(stage_4_path).with_context(|| {
+        format!(
+            "failed to read fourth stage at `{}`",
+            stage_4_path.display()
+        )
+    })?;
+    let stage_4 = signing::embed_bios_trusted_keys(&stage_4, &keys)
+        .context("failed to embed trusted keys into fourth stage")?;
+    let out_file = NamedTempFile::new().context("failed to create temp file")?;
+    fs::write(out_file.path(), stage_4).context("failed to write fourth stage")?;
+    Ok(Some(out_file))
 }
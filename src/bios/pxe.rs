@@ -0,0 +1,88 @@
+//! Network boot of BIOS clients through PXE.
+//!
+//! The network boot program consists of a first sector that jumps to the PXE entry point of
+//! the second stage, followed by the second stage itself. The PXE firmware loads it to
+//! `0x7c00`, so the second stage ends up at `0x7e00`, where it is linked. The second stage
+//! then loads the other stages and the kernel over TFTP.
+
+use super::{BIOS_STAGE_3, BIOS_STAGE_4};
+use crate::DiskImageBuilder;
+use anyhow::{bail, Context};
+use std::{fs, path::Path};
+
+/// The file that the DHCP server announces as boot file to BIOS clients.
+pub const NETWORK_BOOT_PROGRAM_FILE_NAME: &str = "bootloader.0";
+
+const SECTOR_SIZE: usize = 512;
+
+pub fn create_bios_tftp_folder(
+    artifacts: &DiskImageBuilder,
+    stage_4_path: &Path,
+    kernel_binary: &Path,
+    ramdisk_path: Option<&Path>,
+    serial_kernel_load: bool,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    crate::metadata::check_dma_address_limit(
+        kernel_binary,
+        ramdisk_path,
+        crate::metadata::RamdiskPlacement::Bios,
+    )?;
+    fs::create_dir_all(out_path)
+        .with_context(|| format!("failed to create out dir at {}", out_path.display()))?;
+
+    let to = out_path.join(NETWORK_BOOT_PROGRAM_FILE_NAME);
+    fs::write(&to, network_boot_program(artifacts)?)
+        .with_context(|| format!("failed to write network boot program to {}", to.display()))?;
+
+    let mut files = vec![
+        (BIOS_STAGE_3, artifacts.bios_stage_3_path()),
+        (BIOS_STAGE_4, stage_4_path),
+        (crate::KERNEL_FILE_NAME, kernel_binary),
+    ];
+    if let Some(ramdisk_path) = ramdisk_path {
+        files.push((crate::RAMDISK_FILE_NAME, ramdisk_path));
+    }
+    for (name, from) in files {
+        let to = out_path.join(name);
+        fs::copy(from, &to).with_context(|| {
+            format!(
+                "failed to copy {name} from {} to {}",
+                from.display(),
+                to.display()
+            )
+        })?;
+    }
+    if serial_kernel_load {
+        let to = out_path.join(crate::SERIAL_LOAD_FILE_NAME);
+        fs::write(&to, []).with_context(|| format!("failed to create {}", to.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Builds the network boot program from the second stage.
+fn network_boot_program(artifacts: &DiskImageBuilder) -> anyhow::Result<Vec<u8>> {
+    if artifacts.custom_bios_stage_2() {
+        // the length and the PXE entry point are read from the ELF file at build time
+        bail!("the network boot program requires the built-in second stage");
+    }
+    let stage_2_path = artifacts.bios_stage_2_path();
+    let stage_2_len: usize = env!("BIOS_STAGE_2_LEN").parse()?;
+    let pxe_entry: u32 = env!("BIOS_STAGE_2_PXE_ENTRY").parse()?;
+
+    let stage_2 = fs::read(stage_2_path).context("failed to read second stage binary")?;
+    // the flat binary is padded up to the end marker, which isn't needed when loading it
+    // over the network
+    let stage_2 = stage_2
+        .get(..stage_2_len)
+        .context("second stage binary is shorter than expected")?;
+
+    // `jmp far segment:offset` to the PXE entry point
+    let [offset_low, offset_high] = ((pxe_entry & 0xf) as u16).to_le_bytes();
+    let [segment_low, segment_high] = u16::try_from(pxe_entry >> 4)?.to_le_bytes();
+    let mut program = vec![0; SECTOR_SIZE];
+    program[..5].copy_from_slice(&[0xea, offset_low, offset_high, segment_low, segment_high]);
+    program.extend_from_slice(stage_2);
+    Ok(program)
+}
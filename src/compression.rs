@@ -0,0 +1,110 @@
+//! Compression of kernels for disk images and PXE folders.
+//!
+//! See `BiosBoot::set_compress_kernel` and `UefiBoot::set_compress_kernel`.
+
+use crate::signing;
+use anyhow::{anyhow, bail, Context};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+/// Marks the start of a compressed kernel, keep in sync with `common/src/compression.rs`.
+const MAGIC: &[u8; 8] = b"BLKCOMP1";
+/// The algorithm number of the LZ4 block format.
+const ALGORITHM_LZ4: u32 = 1;
+/// The length of the header that precedes the compressed data.
+const HEADER_LEN: usize = 24;
+
+/// A kernel file that is placed on an image, either as given or compressed.
+pub(crate) enum KernelFile {
+    Uncompressed(PathBuf),
+    Compressed(NamedTempFile),
+}
+
+impl KernelFile {
+    /// Creates a compressed copy of the given kernel if `compress` is set.
+    ///
+    /// Kernels that are already compressed, e.g. by [`sign_kernel`](crate::sign_kernel), are
+    /// used as given. Signed kernels that are not compressed yet are rejected, because
+    /// compressing them would invalidate the signature.
+    pub fn new(kernel_path: &Path, compress: bool) -> anyhow::Result<Self> {
+        if !compress {
+            return Ok(Self::Uncompressed(kernel_path.to_owned()));
+        }
+        let kernel = fs::read(kernel_path)
+            .with_context(|| format!("failed to read kernel at `{}`", kernel_path.display()))?;
+        if is_compressed(signing::content(&kernel)) {
+            return Ok(Self::Uncompressed(kernel_path.to_owned()));
+        }
+        if signing::is_signed(&kernel) {
+            bail!(
+                "signed kernel `{}` can't be compressed without invalidating its signature; \
+                compress it while signing instead",
+                kernel_path.display()
+            );
+        }
+        compress_kernel(kernel_path, &kernel).map(Self::Compressed)
+    }
+
+    /// Returns the path of the file that should be placed on the image.
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Uncompressed(path) => path,
+            Self::Compressed(file) => file.path(),
+        }
+    }
+}
+
+/// Reads the given kernel and decompresses it if it was compressed, e.g. by
+/// [`sign_kernel`](crate::sign_kernel).
+///
+/// The boot stages parse and hash the decompressed kernel, so the builder has to do the same.
+pub(crate) fn read_kernel(kernel_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let kernel = fs::read(kernel_path)
+        .with_context(|| format!("failed to read kernel at `{}`", kernel_path.display()))?;
+    let content = signing::content(&kernel);
+    if !is_compressed(content) {
+        return Ok(kernel);
+    }
+    let len = u64::from_le_bytes(content[16..HEADER_LEN].try_into().unwrap());
+    lz4_flex::block::decompress(&content[HEADER_LEN..], len as usize).map_err(|err| {
+        anyhow!(
+            "failed to decompress kernel at `{}`: {err}",
+            kernel_path.display()
+        )
+    })
+}
+
+/// Returns the LZ4-compressed kernel with the header that the boot stages expect.
+pub(crate) fn compress(kernel: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::with_capacity(kernel.len() / 2);
+    compressed.extend_from_slice(MAGIC);
+    compressed.extend_from_slice(&ALGORITHM_LZ4.to_le_bytes());
+    compressed.extend_from_slice(&[0; 4]);
+    compressed.extend_from_slice(&(kernel.len() as u64).to_le_bytes());
+    compressed.extend_from_slice(&lz4_flex::block::compress(kernel));
+    compressed
+}
+
+fn is_compressed(kernel: &[u8]) -> bool {
+    kernel.len() >= HEADER_LEN && kernel.starts_with(MAGIC)
+}
+
+/// Writes an LZ4-compressed copy of the given kernel to a temporary file.
+fn compress_kernel(kernel_path: &Path, kernel: &[u8]) -> anyhow::Result<NamedTempFile> {
+    let compressed = compress(kernel);
+
+    // keep the file stem of the kernel, from which the FAT volume label is derived
+    let stem = kernel_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let out_file = tempfile::Builder::new()
+        .prefix(&format!("{stem}."))
+        .tempfile()
+        .context("failed to create temp file")?;
+    fs::write(out_file.path(), compressed).context("failed to write compressed kernel")?;
+    Ok(out_file)
+}
@@ -0,0 +1,92 @@
+//! Additional files on the boot partition and in the PXE folders.
+//!
+//! See `BiosBoot::add_file` and `UefiBoot::add_file`.
+
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A file that is copied from `host_path` to `image_path` on the boot partition.
+#[derive(Debug, Clone)]
+pub(crate) struct ExtraFile {
+    pub image_path: String,
+    pub host_path: PathBuf,
+}
+
+/// Adds the extra files to the files of a FAT partition.
+///
+/// Fails if an image path is invalid or conflicts with a file of the bootloader. FAT file
+/// names are case-insensitive, so paths that only differ in case conflict as well.
+pub(crate) fn insert<'a>(
+    files: &mut BTreeMap<&'a str, &'a Path>,
+    extra_files: &'a [ExtraFile],
+) -> anyhow::Result<()> {
+    for file in extra_files {
+        check_image_path(&file.image_path)?;
+        if files
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case(&file.image_path))
+        {
+            bail!(
+                "extra file `{}` conflicts with another file on the boot partition",
+                file.image_path
+            );
+        }
+        files.insert(&file.image_path, &file.host_path);
+    }
+    Ok(())
+}
+
+/// Copies the extra files into a PXE folder.
+///
+/// Fails if an image path is invalid or equals one of the `reserved` file names of the
+/// bootloader.
+pub(crate) fn copy_to_folder(
+    extra_files: &[ExtraFile],
+    reserved: &[&str],
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    for file in extra_files {
+        check_image_path(&file.image_path)?;
+        if reserved
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&file.image_path))
+        {
+            bail!(
+                "extra file `{}` conflicts with another file in the folder",
+                file.image_path
+            );
+        }
+        let to = out_path.join(&file.image_path);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create dir at {}", parent.display()))?;
+        }
+        fs::copy(&file.host_path, &to).with_context(|| {
+            format!(
+                "failed to copy extra file from {} to {}",
+                file.host_path.display(),
+                to.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks that the image path is a relative path with `/` separators and without `.` or `..`
+/// components.
+fn check_image_path(image_path: &str) -> anyhow::Result<()> {
+    let valid = !image_path.contains('\\')
+        && image_path
+            .split('/')
+            .all(|part| !matches!(part, "" | "." | ".."));
+    if !valid {
+        bail!(
+            "invalid image path `{image_path}` for extra file, expected e.g. `firmware/blob.bin`"
+        );
+    }
+    Ok(())
+}
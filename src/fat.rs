@@ -1,8 +1,12 @@
 use anyhow::Context;
-use std::{collections::BTreeMap, fs, io, path::Path};
+use std::{collections::BTreeMap, env, fs, io, path::Path};
 
 use crate::KERNEL_FILE_NAME;
 
+/// The environment variable that fixes the timestamps of the files, in seconds since the Unix
+/// epoch, see <https://reproducible-builds.org/specs/source-date-epoch/>.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
 pub fn create_fat_filesystem(
     files: BTreeMap<&str, &Path>,
     out_fat_path: &Path,
@@ -46,7 +50,15 @@ pub fn create_fat_filesystem(
     // format the file system and open it
     let format_options = fatfs::FormatVolumeOptions::new().volume_label(label);
     fatfs::format_volume(&fat_file, format_options).context("Failed to format FAT file")?;
-    let filesystem = fatfs::FileSystem::new(&fat_file, fatfs::FsOptions::new())
+    let mut fs_options = fatfs::FsOptions::new();
+    if let Ok(epoch) = env::var(SOURCE_DATE_EPOCH) {
+        let epoch = epoch
+            .parse()
+            .with_context(|| format!("invalid {SOURCE_DATE_EPOCH} `{epoch}`"))?;
+        // the options need a `'static` provider, which is small enough to leak
+        fs_options = fs_options.time_provider(Box::leak(Box::new(FixedTime(date_time(epoch)))));
+    }
+    let filesystem = fatfs::FileSystem::new(&fat_file, fs_options)
         .context("Failed to open FAT file system of UEFI FAT file")?;
 
     // copy files to file system
@@ -80,3 +92,56 @@ pub fn create_fat_filesystem(
 
     Ok(())
 }
+
+/// Sets all timestamps of the file system to the same time.
+#[derive(Debug)]
+struct FixedTime(fatfs::DateTime);
+
+impl fatfs::TimeProvider for FixedTime {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.0.date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        self.0
+    }
+}
+
+/// Converts seconds since the Unix epoch to a FAT timestamp in UTC.
+///
+/// FAT can't represent times before 1980, which are clamped to the start of 1980.
+fn date_time(epoch: u64) -> fatfs::DateTime {
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = epoch / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    if year < 1980 {
+        return date_time(315_532_800);
+    }
+
+    let seconds = epoch % 86400;
+    fatfs::DateTime {
+        date: fatfs::Date {
+            year: year.min(2107) as u16,
+            month: month as u16,
+            day: day as u16,
+        },
+        time: fatfs::Time {
+            hour: (seconds / 3600) as u16,
+            min: (seconds / 60 % 60) as u16,
+            sec: (seconds % 60) as u16,
+            millis: 0,
+        },
+    }
+}
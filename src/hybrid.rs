@@ -0,0 +1,335 @@
+use crate::{
+    bios,
+    compression::KernelFile,
+    extra_files::{self, ExtraFile},
+    fat, iso, metadata, uefi, DiskImageBuilder, KeyRole,
+};
+use anyhow::Context;
+use bootloader_api::{config::LogLevels, info::ExtraMapping};
+use mbrman::BOOT_ACTIVE;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+const SECTOR_SIZE: u32 = 512;
+
+const STAGE_2_FILE_NAME: &str = "STAGE2.BIN";
+const BOOT_IMAGE_FILE_NAME: &str = "BOOT.IMG";
+
+/// See `BOOTLOADER_SECOND_STAGE_PARTITION_TYPE` in the `boot_sector` crate.
+const SECOND_STAGE_PARTITION_TYPE: u8 = 0x20;
+/// The second stage accepts this type for the FAT partition behind its own partition.
+const EFI_SYSTEM_PARTITION_TYPE: u8 = 0xef;
+
+/// Create a single image that boots on both BIOS and UEFI systems, from optical media as
+/// well as from USB drives.
+///
+/// The image is a valid ISO 9660 file system with El Torito boot entries for BIOS and UEFI
+/// systems. Its system area contains an MBR whose partitions point into the files of the
+/// ISO file system, so the image also boots when it is written directly to a USB drive
+/// (this is known as the _isohybrid_ technique).
+///
+/// BIOS systems boot optical media through El Torito hard disk emulation: the firmware
+/// presents the image as a hard disk with 512-byte sectors and runs the MBR in its system
+/// area, so the boot sector and the second stage use the same partition table and sector
+/// numbers as on a USB drive. No-emulation El Torito boot with 2048-byte sectors is not
+/// supported for BIOS systems.
+pub struct HybridBoot {
+    kernel: PathBuf,
+    ramdisk: Option<PathBuf>,
+    extra_mappings: Vec<ExtraMapping>,
+    log_levels: Option<LogLevels>,
+    command_line: String,
+    compress_kernel: bool,
+    disk_signature: Option<u32>,
+    trusted_keys: Vec<(PathBuf, KeyRole)>,
+    extra_files: Vec<ExtraFile>,
+    artifacts: DiskImageBuilder,
+}
+
+impl HybridBoot {
+    /// Start creating a hybrid image for the given bootloader ELF executable.
+    pub fn new(kernel_path: &Path) -> Self {
+        Self {
+            kernel: kernel_path.to_owned(),
+            ramdisk: None,
+            extra_mappings: Vec::new(),
+            log_levels: None,
+            command_line: String::new(),
+            compress_kernel: false,
+            disk_signature: None,
+            trusted_keys: Vec::new(),
+            extra_files: Vec::new(),
+            artifacts: DiskImageBuilder::new(),
+        }
+    }
+
+    /// Add a ramdisk file to the image
+    pub fn set_ramdisk(&mut self, ramdisk_path: &Path) -> &mut Self {
+        self.ramdisk = Some(ramdisk_path.to_owned());
+        self
+    }
+
+    /// Set the command line that is passed to the kernel.
+    ///
+    /// The command line is stored in the boot metadata block and reported in
+    /// `BootInfo::command_line`. At most `CommandLine::MAX_LEN` bytes are supported.
+    pub fn set_command_line(&mut self, command_line: &str) -> &mut Self {
+        self.command_line = command_line.to_owned();
+        self
+    }
+
+    /// Place the file at `host_path` on the FAT partition under `image_path`, see
+    /// [`BiosBoot::add_file`](crate::BiosBoot::add_file).
+    pub fn add_file(&mut self, image_path: &str, host_path: &Path) -> &mut Self {
+        self.extra_files.push(ExtraFile {
+            image_path: image_path.to_owned(),
+            host_path: host_path.to_owned(),
+        });
+        self
+    }
+
+    /// Set the log levels of the bootloader outputs for this kernel.
+    ///
+    /// The levels are stored in the boot metadata block and replace the `log_levels` of the
+    /// kernel's `BootloaderConfig` once the block is verified.
+    pub fn set_log_levels(&mut self, levels: LogLevels) -> &mut Self {
+        self.log_levels = Some(levels);
+        self
+    }
+
+    /// Map the given physical range at a fixed virtual address before entering the kernel.
+    ///
+    /// This is useful for device registers that the kernel accesses early, e.g. the local
+    /// APIC or the HPET. The mappings are stored in the boot metadata block and reported in
+    /// the `extra_mappings` field of `BootInfo::boot_metadata`. At most
+    /// `ExtraMappings::MAX_MAPPINGS` mappings are supported.
+    pub fn add_extra_mapping(&mut self, mapping: ExtraMapping) -> &mut Self {
+        self.extra_mappings.push(mapping);
+        self
+    }
+
+    /// Compress the kernel with LZ4 to reduce the size of the image and the load time, see
+    /// [`BiosBoot::set_compress_kernel`](crate::BiosBoot::set_compress_kernel).
+    pub fn set_compress_kernel(&mut self, enable: bool) -> &mut Self {
+        self.compress_kernel = enable;
+        self
+    }
+
+    /// Set the disk signature of the MBR, see
+    /// [`BiosBoot::set_disk_signature`](crate::BiosBoot::set_disk_signature).
+    ///
+    /// If not set, a random non-zero signature is generated for every created image.
+    pub fn set_disk_signature(&mut self, signature: u32) -> &mut Self {
+        self.disk_signature = Some(signature);
+        self
+    }
+
+    /// Only boot kernels that are signed with one of the trusted keys, see
+    /// [`UefiBoot::add_trusted_key`](crate::UefiBoot::add_trusted_key).
+    ///
+    /// The keys are embedded into both the UEFI bootloader and the fourth BIOS stage. The BIOS
+    /// bootloader has no developer mode, so it only trusts the [`KeyRole::Production`] keys,
+    /// like [`BiosBoot::add_trusted_key`](crate::BiosBoot::add_trusted_key).
+    pub fn add_trusted_key(&mut self, public_key_path: &Path, role: KeyRole) -> &mut Self {
+        self.trusted_keys.push((public_key_path.to_owned(), role));
+        self
+    }
+
+    /// Use the bootloader executables of the given builder, see
+    /// [`DiskImageBuilder::hybrid_boot`].
+    pub(crate) fn set_artifacts(&mut self, artifacts: DiskImageBuilder) -> &mut Self {
+        self.artifacts = artifacts;
+        self
+    }
+
+    /// Create a bootable hybrid ISO image at the given path.
+    pub fn create_hybrid_image(&self, out_path: &Path) -> anyhow::Result<()> {
+        let bootsector_path = self.artifacts.bios_boot_sector_path();
+        let stage_2_path = self.artifacts.bios_stage_2_path();
+
+        let fat_partition = self
+            .create_fat_partition()
+            .context("failed to create FAT partition")?;
+
+        let files = [
+            (STAGE_2_FILE_NAME, stage_2_path),
+            (BOOT_IMAGE_FILE_NAME, fat_partition.path()),
+        ];
+        let boot = iso::ElTorito {
+            bios_hard_disk_emulation: Some(SECOND_STAGE_PARTITION_TYPE),
+            efi_image: Some(BOOT_IMAGE_FILE_NAME),
+        };
+        iso::create_iso_image(
+            &files,
+            &boot,
+            |find_extent| {
+                let stage_2 = find_extent(STAGE_2_FILE_NAME).context("second stage missing")?;
+                let boot_image =
+                    find_extent(BOOT_IMAGE_FILE_NAME).context("boot partition missing")?;
+                let disk_signature = self
+                    .disk_signature
+                    .unwrap_or_else(bios::random_disk_signature);
+                create_mbr(bootsector_path, stage_2, boot_image, disk_signature)
+            },
+            out_path,
+        )
+        .context("failed to create hybrid ISO image")?;
+
+        fat_partition
+            .close()
+            .context("failed to delete FAT partition after image creation")?;
+
+        Ok(())
+    }
+
+    /// Creates a FAT partition with the kernel and the files of both the BIOS and the UEFI
+    /// bootloader.
+    fn create_fat_partition(&self) -> anyhow::Result<NamedTempFile> {
+        let bootloader = uefi::bootloader_with_trusted_keys(&self.artifacts, &self.trusted_keys)?;
+        let bootloader_path = bootloader
+            .as_ref()
+            .map_or(self.artifacts.uefi_bootloader_path(), |file| file.path());
+        let stage_3_path = self.artifacts.bios_stage_3_path();
+        let production_keys: Vec<_> = self
+            .trusted_keys
+            .iter()
+            .filter(|(_, role)| *role == KeyRole::Production)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let stage_4 = bios::stage_4_with_trusted_keys(&self.artifacts, &production_keys)?;
+        let stage_4_path = stage_4
+            .as_ref()
+            .map_or(self.artifacts.bios_stage_4_path(), |file| file.path());
+
+        metadata::check_dma_address_limit(
+            &self.kernel,
+            self.ramdisk.as_deref(),
+            metadata::RamdiskPlacement::Bios,
+        )?;
+        let boot_metadata = NamedTempFile::new().context("failed to create temp file")?;
+        metadata::create_boot_metadata_file(
+            &self.kernel,
+            &self.extra_mappings,
+            &self.command_line,
+            self.log_levels,
+            boot_metadata.path(),
+        )
+        .context("failed to create boot metadata")?;
+        let video_mode = NamedTempFile::new().context("failed to create temp file")?;
+        metadata::create_video_mode_file(&self.kernel, video_mode.path())
+            .context("failed to create video mode file")?;
+
+        let kernel = KernelFile::new(&self.kernel, self.compress_kernel)?;
+
+        let mut files = BTreeMap::new();
+        files.insert("efi/boot/bootx64.efi", bootloader_path);
+        files.insert(bios::BIOS_STAGE_3, stage_3_path);
+        files.insert(bios::BIOS_STAGE_4, stage_4_path);
+        files.insert(crate::KERNEL_FILE_NAME, kernel.path());
+        if let Some(ramdisk_path) = &self.ramdisk {
+            files.insert(crate::RAMDISK_FILE_NAME, ramdisk_path);
+        }
+        files.insert(crate::BOOT_METADATA_FILE_NAME, boot_metadata.path());
+        files.insert(crate::VIDEO_MODE_FILE_NAME, video_mode.path());
+        extra_files::insert(&mut files, &self.extra_files)?;
+
+        let out_file = NamedTempFile::new().context("failed to create temp file")?;
+        fat::create_fat_filesystem(files, out_file.path())
+            .context("failed to create hybrid FAT filesystem")?;
+
+        Ok(out_file)
+    }
+}
+
+/// Creates the MBR for booting from USB drives and through El Torito hard disk emulation.
+///
+/// The first partition contains the second stage and the second one is the FAT image, which
+/// is marked as EFI system partition for UEFI systems. The BIOS second stage looks for it
+/// behind its own partition.
+fn create_mbr(
+    bootsector_path: &Path,
+    stage_2: iso::Extent,
+    boot_image: iso::Extent,
+    disk_signature: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let mut boot_sector = File::open(bootsector_path).context("failed to open boot sector")?;
+    let mut mbr =
+        mbrman::MBR::read_from(&mut boot_sector, SECTOR_SIZE).context("failed to read MBR")?;
+    mbr.header.disk_signature = disk_signature.to_le_bytes();
+
+    let entry = |partition_type, extent: iso::Extent| -> anyhow::Result<_> {
+        Ok(mbrman::MBRPartitionEntry {
+            boot: BOOT_ACTIVE,
+            starting_lba: extent
+                .disk_sector()
+                .try_into()
+                .context("partition start is larger than u32::MAX")?,
+            sectors: extent
+                .disk_sectors()
+                .try_into()
+                .context("partition size is larger than u32::MAX")?,
+            sys: partition_type,
+
+            first_chs: mbrman::CHS::empty(),
+            last_chs: mbrman::CHS::empty(),
+        })
+    };
+    mbr[1] = entry(SECOND_STAGE_PARTITION_TYPE, stage_2)?;
+    mbr[2] = entry(EFI_SYSTEM_PARTITION_TYPE, boot_image)?;
+
+    let mut out = Cursor::new(Vec::new());
+    mbr.write_into(&mut out)
+        .context("failed to write MBR header")?;
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mbr_partitions() {
+        let mut boot_sector = NamedTempFile::new().unwrap();
+        let mut code = vec![0x90; 440];
+        code.resize(510, 0);
+        code.extend([0x55, 0xaa]);
+        std::io::Write::write_all(&mut boot_sector, &code).unwrap();
+
+        let stage_2 = iso::Extent {
+            sector: 30,
+            len: 40_000,
+        };
+        let boot_image = iso::Extent {
+            sector: 50,
+            len: 8 * 1024 * 1024,
+        };
+        let mbr = create_mbr(boot_sector.path(), stage_2, boot_image, 0x1234abcd).unwrap();
+        assert_eq!(mbr.len(), 512);
+        assert_eq!(mbr[..440], code[..440]);
+        assert_eq!(mbr[440..444], 0x1234abcd_u32.to_le_bytes());
+        assert_eq!(mbr[510..], [0x55, 0xaa]);
+
+        let partition = |index: usize| {
+            let entry = &mbr[446 + index * 16..][..16];
+            let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+            let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+            (entry[0], entry[4], start, sectors)
+        };
+        // the second stage finds the FAT partition right behind its own partition
+        assert_eq!(
+            partition(0),
+            (BOOT_ACTIVE, SECOND_STAGE_PARTITION_TYPE, 120, 79)
+        );
+        assert_eq!(
+            partition(1),
+            (BOOT_ACTIVE, EFI_SYSTEM_PARTITION_TYPE, 200, 16384)
+        );
+        // no other partitions, in particular none that overlaps the boot image
+        assert_eq!(mbr[446 + 32..510], [0; 32]);
+    }
+}
@@ -0,0 +1,503 @@
+//! Minimal ISO 9660 writer with El Torito boot support.
+//!
+//! Only a flat root directory with ISO level 1 file names is supported, which is all that
+//! is needed for boot media.
+
+use anyhow::Context;
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Size of an ISO 9660 logical sector.
+pub const ISO_SECTOR_SIZE: u64 = 2048;
+/// Size of the sectors used by the BIOS and in MBR partition tables.
+const DISK_SECTOR_SIZE: u64 = 512;
+
+/// The system area occupies the first 16 sectors and is free for other uses (e.g. an MBR).
+const SYSTEM_AREA_SECTORS: u32 = 16;
+const PRIMARY_VOLUME_DESCRIPTOR: u32 = SYSTEM_AREA_SECTORS;
+const BOOT_RECORD: u32 = PRIMARY_VOLUME_DESCRIPTOR + 1;
+const TERMINATOR: u32 = BOOT_RECORD + 1;
+const L_PATH_TABLE: u32 = TERMINATOR + 1;
+const M_PATH_TABLE: u32 = L_PATH_TABLE + 1;
+const ROOT_DIRECTORY: u32 = M_PATH_TABLE + 1;
+const BOOT_CATALOG: u32 = ROOT_DIRECTORY + 1;
+const FIRST_FILE: u32 = BOOT_CATALOG + 1;
+
+const BOOT_CATALOG_NAME: &str = "BOOT.CAT";
+
+/// The location of a file in the ISO image.
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    /// Index of the first 2048-byte sector.
+    pub sector: u32,
+    /// Size of the file in bytes.
+    pub len: u64,
+}
+
+impl Extent {
+    /// Returns the index of the first 512-byte sector, as used in MBR partition tables.
+    pub fn disk_sector(&self) -> u64 {
+        u64::from(self.sector) * (ISO_SECTOR_SIZE / DISK_SECTOR_SIZE)
+    }
+
+    /// Returns the number of 512-byte sectors needed for the file.
+    pub fn disk_sectors(&self) -> u64 {
+        (self.len + DISK_SECTOR_SIZE - 1) / DISK_SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u32 {
+        ((self.len + ISO_SECTOR_SIZE - 1) / ISO_SECTOR_SIZE)
+            .try_into()
+            .unwrap()
+    }
+}
+
+/// El Torito boot entries of an ISO image.
+#[derive(Debug, Default)]
+pub struct ElTorito<'a> {
+    /// Boot BIOS systems through hard disk emulation of the image itself, starting at the
+    /// MBR in the system area.
+    ///
+    /// The emulated disk uses 512-byte sectors, so code that reads the disk through its MBR
+    /// partitions works unchanged. This is the only supported BIOS boot mode; the initial
+    /// entry is marked as not bootable without it. The value is the partition type of the
+    /// first partition of that MBR.
+    pub bios_hard_disk_emulation: Option<u8>,
+    /// Name of the file that contains the FAT image used for booting on UEFI systems.
+    pub efi_image: Option<&'a str>,
+}
+
+/// Creates an ISO 9660 image containing the given files in its root directory.
+///
+/// The file names must be valid ISO level 1 names, i.e. uppercase 8.3 names. The
+/// `system_area` callback is invoked with the final file locations and returns the content
+/// of the first 32 KiB of the image. This allows placing an MBR with partitions that point
+/// to files of the image there.
+pub fn create_iso_image(
+    files: &[(&str, &Path)],
+    boot: &ElTorito,
+    system_area: impl FnOnce(&dyn Fn(&str) -> Option<Extent>) -> anyhow::Result<Vec<u8>>,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    // lay out the files one after another, sorted by name as required for directories
+    let mut files = files.to_vec();
+    files.sort_by_key(|(name, _)| *name);
+    let mut extents = Vec::new();
+    let mut next_sector = FIRST_FILE;
+    for (name, path) in &files {
+        check_file_name(name)?;
+        let len = fs::metadata(path)
+            .with_context(|| format!("failed to read metadata of file `{}`", path.display()))?
+            .len();
+        let extent = Extent {
+            sector: next_sector,
+            len,
+        };
+        next_sector = next_sector
+            .checked_add(extent.sectors())
+            .context("ISO image is too large")?;
+        extents.push((*name, extent));
+    }
+    let volume_sectors = next_sector;
+
+    let find_extent = |name: &str| {
+        extents
+            .iter()
+            .find(|(file_name, _)| *file_name == name)
+            .map(|(_, extent)| *extent)
+    };
+    if let Some(efi_image) = boot.efi_image {
+        find_extent(efi_image)
+            .with_context(|| format!("EFI boot image `{efi_image}` is not part of the ISO"))?;
+    }
+
+    let mut system_area = system_area(&find_extent).context("failed to create ISO system area")?;
+    if system_area.len() > (u64::from(SYSTEM_AREA_SECTORS) * ISO_SECTOR_SIZE) as usize {
+        anyhow::bail!("ISO system area is larger than 32 KiB");
+    }
+    system_area.resize(
+        (u64::from(SYSTEM_AREA_SECTORS) * ISO_SECTOR_SIZE) as usize,
+        0,
+    );
+
+    let mut disk = fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(out_path)
+        .with_context(|| format!("failed to create ISO image at `{}`", out_path.display()))?;
+    disk.set_len(u64::from(volume_sectors) * ISO_SECTOR_SIZE)
+        .context("failed to set ISO image length")?;
+    disk.write_all(&system_area)
+        .context("failed to write ISO system area")?;
+
+    // root directory
+    let boot_catalog = Extent {
+        sector: BOOT_CATALOG,
+        len: ISO_SECTOR_SIZE,
+    };
+    let root = Extent {
+        sector: ROOT_DIRECTORY,
+        len: ISO_SECTOR_SIZE,
+    };
+    let mut root_directory = Vec::new();
+    root_directory.extend(directory_record(&[0], root, true));
+    root_directory.extend(directory_record(&[1], root, true));
+    let mut entries: Vec<_> = extents.clone();
+    entries.push((BOOT_CATALOG_NAME, boot_catalog));
+    entries.sort_by_key(|(name, _)| *name);
+    for (name, extent) in entries {
+        root_directory.extend(directory_record(
+            format!("{name};1").as_bytes(),
+            extent,
+            false,
+        ));
+    }
+    if root_directory.len() > ISO_SECTOR_SIZE as usize {
+        anyhow::bail!("too many files for the ISO root directory");
+    }
+
+    write_sector(
+        &mut disk,
+        PRIMARY_VOLUME_DESCRIPTOR,
+        &primary_volume_descriptor(volume_sectors, root),
+    )?;
+    write_sector(&mut disk, BOOT_RECORD, &boot_record())?;
+    write_sector(&mut disk, TERMINATOR, &volume_descriptor_header(255))?;
+    write_sector(&mut disk, L_PATH_TABLE, &path_table(false))?;
+    write_sector(&mut disk, M_PATH_TABLE, &path_table(true))?;
+    write_sector(&mut disk, ROOT_DIRECTORY, &root_directory)?;
+    write_sector(
+        &mut disk,
+        BOOT_CATALOG,
+        &boot_catalog_sector(boot, &find_extent),
+    )?;
+
+    for ((name, path), (_, extent)) in files.iter().zip(&extents) {
+        disk.seek(SeekFrom::Start(u64::from(extent.sector) * ISO_SECTOR_SIZE))
+            .context("seek failed")?;
+        io::copy(
+            &mut File::open(path)
+                .with_context(|| format!("failed to open `{}` for copying", path.display()))?,
+            &mut disk,
+        )
+        .with_context(|| format!("failed to copy `{name}` to ISO image"))?;
+    }
+
+    Ok(())
+}
+
+fn check_file_name(name: &str) -> anyhow::Result<()> {
+    let (stem, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid_chars = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+    };
+    if stem.is_empty()
+        || stem.len() > 8
+        || extension.len() > 3
+        || !valid_chars(stem)
+        || !valid_chars(extension)
+    {
+        anyhow::bail!("`{name}` is not a valid ISO 9660 level 1 file name");
+    }
+    Ok(())
+}
+
+fn write_sector(disk: &mut File, sector: u32, data: &[u8]) -> anyhow::Result<()> {
+    assert!(data.len() <= ISO_SECTOR_SIZE as usize);
+    disk.seek(SeekFrom::Start(u64::from(sector) * ISO_SECTOR_SIZE))
+        .context("seek failed")?;
+    disk.write_all(data)
+        .with_context(|| format!("failed to write ISO sector {sector}"))
+}
+
+fn both_endian_u16(value: u16) -> [u8; 4] {
+    let [a, b] = value.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both_endian_u32(value: u32) -> [u8; 8] {
+    let le = value.to_le_bytes();
+    let be = value.to_be_bytes();
+    [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+/// Pads the given string with spaces, as required for identifier fields.
+fn padded(value: &str, len: usize) -> Vec<u8> {
+    let mut field = value.as_bytes().to_vec();
+    field.resize(len, b' ');
+    field
+}
+
+fn volume_descriptor_header(descriptor_type: u8) -> Vec<u8> {
+    let mut sector = vec![descriptor_type];
+    sector.extend_from_slice(b"CD001");
+    sector.push(1);
+    sector
+}
+
+fn directory_record(name: &[u8], extent: Extent, directory: bool) -> Vec<u8> {
+    let mut record = vec![0, 0];
+    record.extend(both_endian_u32(extent.sector));
+    record.extend(both_endian_u32(extent.len.try_into().unwrap()));
+    record.extend([0; 7]); // recording date: not specified
+    record.push(if directory { 2 } else { 0 });
+    record.extend([0, 0]); // no interleaving
+    record.extend(both_endian_u16(1)); // volume sequence number
+    record.push(name.len() as u8);
+    record.extend_from_slice(name);
+    if record.len() % 2 != 0 {
+        record.push(0);
+    }
+    record[0] = record.len() as u8;
+    record
+}
+
+fn primary_volume_descriptor(volume_sectors: u32, root: Extent) -> Vec<u8> {
+    let mut sector = volume_descriptor_header(1);
+    sector.push(0);
+    sector.extend(padded("", 32)); // system identifier
+    sector.extend(padded("BOOT", 32)); // volume identifier
+    sector.extend([0; 8]);
+    sector.extend(both_endian_u32(volume_sectors));
+    sector.extend([0; 32]);
+    sector.extend(both_endian_u16(1)); // volume set size
+    sector.extend(both_endian_u16(1)); // volume sequence number
+    sector.extend(both_endian_u16(ISO_SECTOR_SIZE as u16));
+    sector.extend(both_endian_u32(path_table(false).len() as u32));
+    sector.extend(L_PATH_TABLE.to_le_bytes());
+    sector.extend([0; 4]);
+    sector.extend(M_PATH_TABLE.to_be_bytes());
+    sector.extend([0; 4]);
+    sector.extend(directory_record(&[0], root, true));
+    for len in [128, 128, 128, 128, 37, 37, 37] {
+        // volume set, publisher, data preparer, application, and file identifiers
+        sector.extend(padded("", len));
+    }
+    for _ in 0..4 {
+        // creation, modification, expiration, and effective dates: not specified
+        sector.extend([b'0'; 16]);
+        sector.push(0);
+    }
+    sector.push(1); // file structure version
+    sector
+}
+
+fn boot_record() -> Vec<u8> {
+    let mut sector = volume_descriptor_header(0);
+    let mut boot_system_id = b"EL TORITO SPECIFICATION".to_vec();
+    boot_system_id.resize(32, 0);
+    sector.extend(boot_system_id);
+    sector.extend([0; 32]);
+    sector.extend(BOOT_CATALOG.to_le_bytes());
+    sector
+}
+
+/// Creates a path table that only contains the root directory.
+fn path_table(big_endian: bool) -> Vec<u8> {
+    let mut table = vec![1, 0];
+    if big_endian {
+        table.extend(ROOT_DIRECTORY.to_be_bytes());
+        table.extend(1u16.to_be_bytes());
+    } else {
+        table.extend(ROOT_DIRECTORY.to_le_bytes());
+        table.extend(1u16.to_le_bytes());
+    }
+    table.extend([0, 0]);
+    table
+}
+
+fn boot_catalog_sector(boot: &ElTorito, find_extent: &dyn Fn(&str) -> Option<Extent>) -> Vec<u8> {
+    const PLATFORM_X86: u8 = 0;
+    const PLATFORM_EFI: u8 = 0xef;
+    const BOOTABLE: u8 = 0x88;
+    const NOT_BOOTABLE: u8 = 0;
+    const NO_EMULATION: u8 = 0;
+    const HARD_DISK_EMULATION: u8 = 4;
+
+    let mut catalog = Vec::new();
+
+    // validation entry
+    let mut validation = vec![1, PLATFORM_X86, 0, 0];
+    validation.extend([0; 24]);
+    validation.extend([0, 0, 0x55, 0xaa]);
+    let sum = validation.chunks(2).fold(0u16, |sum, word| {
+        sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+    });
+    validation[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+    catalog.extend(validation);
+
+    // initial entry, used for BIOS systems
+    let mut initial = Vec::new();
+    match boot.bios_hard_disk_emulation {
+        Some(system_type) => {
+            initial.extend([BOOTABLE, HARD_DISK_EMULATION, 0, 0, system_type, 0]);
+            initial.extend(1u16.to_le_bytes()); // the emulated disk is defined by the MBR
+            initial.extend(0u32.to_le_bytes());
+        }
+        None => initial.extend([NOT_BOOTABLE, NO_EMULATION]),
+    }
+    initial.resize(32, 0);
+    catalog.extend(initial);
+
+    // section for UEFI systems
+    if let Some(extent) = boot.efi_image.and_then(find_extent) {
+        let mut header = vec![0x91, PLATFORM_EFI];
+        header.extend(1u16.to_le_bytes());
+        header.resize(32, 0);
+        catalog.extend(header);
+
+        let mut entry = vec![BOOTABLE, NO_EMULATION, 0, 0, 0, 0];
+        // sector count in 512-byte units; 0 tells the firmware to use the file system size
+        let sectors = u16::try_from(extent.disk_sectors()).unwrap_or(0);
+        entry.extend(sectors.to_le_bytes());
+        entry.extend(extent.sector.to_le_bytes());
+        entry.resize(32, 0);
+        catalog.extend(entry);
+    }
+
+    catalog
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(catalog: &[u8], index: usize) -> &[u8] {
+        &catalog[index * 32..][..32]
+    }
+
+    #[test]
+    fn boot_catalog() {
+        let efi = Extent {
+            sector: 40,
+            len: 1440 * 1024,
+        };
+        let boot = ElTorito {
+            bios_hard_disk_emulation: Some(0x20),
+            efi_image: Some("BOOT.IMG"),
+        };
+        let catalog = boot_catalog_sector(&boot, &|name| (name == "BOOT.IMG").then_some(efi));
+        assert_eq!(catalog.len(), 4 * 32);
+
+        let validation = entry(&catalog, 0);
+        assert_eq!(validation[..2], [1, 0]);
+        assert_eq!(validation[30..], [0x55, 0xaa]);
+        let sum = validation.chunks(2).fold(0u16, |sum, word| {
+            sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+        });
+        assert_eq!(sum, 0);
+
+        // hard disk emulation of the whole image, loading only its MBR
+        let initial = entry(&catalog, 1);
+        assert_eq!(initial[..6], [0x88, 4, 0, 0, 0x20, 0]);
+        assert_eq!(initial[6..8], 1u16.to_le_bytes());
+        assert_eq!(initial[8..12], 0u32.to_le_bytes());
+
+        // final section header for the EFI platform with a single no-emulation entry
+        assert_eq!(entry(&catalog, 2)[..4], [0x91, 0xef, 1, 0]);
+        let efi_entry = entry(&catalog, 3);
+        assert_eq!(efi_entry[..2], [0x88, 0]);
+        assert_eq!(efi_entry[6..8], 2880u16.to_le_bytes());
+        assert_eq!(efi_entry[8..12], 40u32.to_le_bytes());
+    }
+
+    #[test]
+    fn boot_catalog_without_bios_entry() {
+        let efi = Extent {
+            sector: 40,
+            len: 64 * 1024 * 1024,
+        };
+        let boot = ElTorito {
+            bios_hard_disk_emulation: None,
+            efi_image: Some("BOOT.IMG"),
+        };
+        let catalog = boot_catalog_sector(&boot, &|_| Some(efi));
+        assert_eq!(entry(&catalog, 1)[..2], [0, 0]);
+        // too large for the sector count, so the firmware uses the file system size
+        assert_eq!(entry(&catalog, 3)[6..8], [0, 0]);
+
+        let catalog = boot_catalog_sector(&ElTorito::default(), &|_| None);
+        assert_eq!(catalog.len(), 2 * 32);
+    }
+
+    #[test]
+    fn extents_use_both_sector_sizes() {
+        let extent = Extent {
+            sector: 30,
+            len: 2049,
+        };
+        assert_eq!(extent.sectors(), 2);
+        assert_eq!(extent.disk_sector(), 120);
+        assert_eq!(extent.disk_sectors(), 5);
+    }
+
+    #[test]
+    fn iso_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let stage_2 = dir.path().join("stage-2");
+        let boot_image = dir.path().join("boot.img");
+        fs::write(&stage_2, [2; 3000]).unwrap();
+        fs::write(&boot_image, [3; 5000]).unwrap();
+        let out = dir.path().join("boot.iso");
+
+        let files = [
+            ("STAGE2.BIN", stage_2.as_path()),
+            ("BOOT.IMG", boot_image.as_path()),
+        ];
+        let boot = ElTorito {
+            bios_hard_disk_emulation: Some(0x20),
+            efi_image: Some("BOOT.IMG"),
+        };
+        let mut extents = Vec::new();
+        create_iso_image(
+            &files,
+            &boot,
+            |find_extent| {
+                extents.push(find_extent("BOOT.IMG").unwrap());
+                extents.push(find_extent("STAGE2.BIN").unwrap());
+                Ok(vec![0xaa; 512])
+            },
+            &out,
+        )
+        .unwrap();
+        let image = fs::read(&out).unwrap();
+        let sector = |index: u32| &image[(u64::from(index) * ISO_SECTOR_SIZE) as usize..];
+
+        assert_eq!(image[..512], [0xaa; 512]);
+        assert_eq!(image[512..SYSTEM_AREA_SECTORS as usize * 2048], [0; 32256]);
+        assert_eq!(sector(PRIMARY_VOLUME_DESCRIPTOR)[..6], *b"\x01CD001");
+        assert_eq!(sector(BOOT_RECORD)[7..30], *b"EL TORITO SPECIFICATION");
+        assert_eq!(sector(BOOT_RECORD)[71..75], BOOT_CATALOG.to_le_bytes());
+        assert_eq!(sector(TERMINATOR)[..6], *b"\xffCD001");
+        assert_eq!(sector(BOOT_CATALOG)[..2], [1, 0]);
+
+        // the files are sorted by name and the EFI entry points to the boot image
+        let [boot_img, stage_2] = extents[..] else {
+            panic!()
+        };
+        assert_eq!(boot_img.sector, FIRST_FILE);
+        assert_eq!(stage_2.sector, FIRST_FILE + 3);
+        assert_eq!(
+            sector(BOOT_CATALOG)[96 + 8..][..4],
+            FIRST_FILE.to_le_bytes()
+        );
+        assert_eq!(sector(boot_img.sector)[..5000], [3; 5000]);
+        assert_eq!(sector(stage_2.sector)[..3000], [2; 3000]);
+        assert_eq!(image.len(), (FIRST_FILE + 5) as usize * 2048);
+    }
+
+    #[test]
+    fn invalid_file_names() {
+        for name in ["boot.img", "TOOLONGNAME.BIN", "BOOT.IMGX", ".BIN", "A-B"] {
+            assert!(check_file_name(name).is_err(), "{name}");
+        }
+        check_file_name("STAGE_2.BIN").unwrap();
+        check_file_name("KERNEL").unwrap();
+    }
+}
@@ -4,17 +4,76 @@ An experimental x86_64 bootloader that works on both BIOS and UEFI systems.
 
 #![warn(missing_docs)]
 
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod artifacts;
 #[cfg(feature = "bios")]
 mod bios;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod compression;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod extra_files;
 mod fat;
+#[cfg(all(feature = "bios", feature = "uefi"))]
+mod hybrid;
+#[cfg(all(feature = "bios", feature = "uefi"))]
+mod iso;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod metadata;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod serial_load;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+mod signing;
 #[cfg(feature = "uefi")]
 mod uefi;
 
+#[cfg(any(feature = "bios", feature = "uefi"))]
+pub use artifacts::DiskImageBuilder;
 #[cfg(feature = "bios")]
-pub use bios::BiosBoot;
+pub use bios::{BiosBoot, MbrPartition};
+#[cfg(any(feature = "bios", feature = "uefi"))]
+pub use bootloader_api::{
+    config::{LevelFilter, LogLevels},
+    info::{Caching, ExtraMapping},
+};
+#[cfg(any(feature = "bios", feature = "uefi"))]
+pub use metadata::create_boot_metadata_file;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+pub use serial_load::push_kernel_over_serial;
 
+#[cfg(all(feature = "bios", feature = "uefi"))]
+pub use hybrid::HybridBoot;
+#[cfg(any(feature = "bios", feature = "uefi"))]
+pub use signing::{generate_signing_key, sign_kernel, KeyRole};
 #[cfg(feature = "uefi")]
-pub use uefi::UefiBoot;
+pub use uefi::{EspLayout, UefiBoot};
+#[cfg(feature = "uefi")]
+pub use uuid::Uuid;
 
 const KERNEL_FILE_NAME: &str = "kernel-x86_64";
 const RAMDISK_FILE_NAME: &str = "ramdisk";
+#[cfg(feature = "uefi")]
+const RECOVERY_KERNEL_FILE_NAME: &str = "kernel-recovery-x86_64";
+#[cfg(feature = "uefi")]
+const SUPPORT_INFO_FILE_NAME: &str = "support-info";
+#[cfg(feature = "uefi")]
+const BOOT_SCRIPT_FILE_NAME: &str = "boot-script";
+/// Lists the kernels of the boot menu of the UEFI bootloader.
+#[cfg(feature = "uefi")]
+const BOOT_MENU_FILE_NAME: &str = "boot-menu";
+#[cfg(any(feature = "bios", feature = "uefi"))]
+const BOOT_METADATA_FILE_NAME: &str = "boot-metadata";
+/// Preferred video mode of the BIOS bootloader, derived from the kernel config.
+#[cfg(feature = "bios")]
+const VIDEO_MODE_FILE_NAME: &str = "video-mode";
+/// Headers of the encrypted partitions that the BIOS bootloader unlocks.
+#[cfg(feature = "bios")]
+const PARTITION_KEYS_FILE_NAME: &str = "partition-keys";
+/// Marker file that makes the bootloader receive the kernel over a serial port.
+#[cfg(any(feature = "bios", feature = "uefi"))]
+const SERIAL_LOAD_FILE_NAME: &str = "serial-load";
+/// Log of boot decisions that the UEFI bootloader writes on every boot.
+#[cfg(feature = "uefi")]
+const BOOT_LOG_FILE_NAME: &str = "boot-log";
+/// File with the address of a 9P server that the UEFI bootloader loads the kernel from.
+#[cfg(feature = "uefi")]
+const P9_SERVER_FILE_NAME: &str = "9p-server";
@@ -0,0 +1,177 @@
+use crate::compression;
+use anyhow::{anyhow, bail, Context};
+use bootloader_api::{
+    config::LogLevels,
+    info::{BootMetadata, CommandLine, ExtraMapping, ExtraMappings},
+    BootloaderConfig,
+};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+
+/// The lowest physical address at which the BIOS stages load the kernel. The ramdisk is
+/// placed directly after the kernel.
+const BIOS_KERNEL_MIN_ADDR: u64 = 16 * 1024 * 1024;
+/// The first MiB of physical memory is not used for the ramdisk on UEFI systems.
+const UEFI_RAMDISK_MIN_ADDR: u64 = 1024 * 1024;
+
+/// The boot stages that load the ramdisk, which determines where it is placed.
+#[derive(Debug, Clone, Copy)]
+pub enum RamdiskPlacement {
+    /// The ramdisk is placed directly after the kernel, at 16MiB or above.
+    Bios,
+    /// The ramdisk can be placed anywhere above the first MiB.
+    Uefi,
+}
+
+/// Creates the metadata block for the given kernel and writes it to `out_path`.
+///
+/// The block records the bootloader version and SHA-256 hashes of the kernel executable and
+/// its serialized config. The boot stages compare the hashes against the loaded
+/// kernel before passing the block to the kernel. The block also carries the extra virtual
+/// mappings that the boot stages set up for the kernel, the kernel command line, and the
+/// log levels that replace the ones of the kernel config.
+///
+/// The disk image builders create the blocks of the kernels that they place on the image.
+/// Each block only applies to the kernel it was created for, so update agents that install
+/// kernels into A/B slots also write the block of `kernel-x86_64-a` to
+/// `kernel-x86_64-a.metadata`, and likewise for slot B.
+pub fn create_boot_metadata_file(
+    kernel_path: &Path,
+    extra_mappings: &[ExtraMapping],
+    command_line: &str,
+    log_levels: Option<LogLevels>,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let mut metadata = kernel_metadata(kernel_path)?;
+    for mapping in extra_mappings {
+        if mapping.phys_start % 4096 != 0 || mapping.virt_start % 4096 != 0 {
+            bail!("extra mapping {mapping:x?} is not page aligned");
+        }
+        if !metadata.extra_mappings.push(*mapping) {
+            bail!(
+                "too many extra mappings, at most {} are supported",
+                ExtraMappings::MAX_MAPPINGS
+            );
+        }
+    }
+    if !metadata.command_line.set(command_line) {
+        bail!(
+            "command line is too long, at most {} bytes are supported",
+            CommandLine::MAX_LEN
+        );
+    }
+    metadata.log_levels = log_levels.into();
+    fs::write(out_path, metadata.serialize())
+        .with_context(|| format!("failed to write boot metadata to `{}`", out_path.display()))
+}
+
+/// Returns the name of the boot metadata file of the given kernel file.
+///
+/// Each kernel is bound to its own block through the kernel hash. The block of the default
+/// kernel is `boot-metadata`, the blocks of other kernels, e.g. boot menu entries, A/B slots,
+/// and the recovery kernel, have the name of the kernel file with a `.metadata` extension.
+/// Keep in sync with `common/src/lib.rs`.
+pub fn file_name(kernel_file: &str) -> String {
+    if kernel_file == crate::KERNEL_FILE_NAME {
+        crate::BOOT_METADATA_FILE_NAME.to_owned()
+    } else {
+        format!("{kernel_file}.metadata")
+    }
+}
+
+/// Writes the framebuffer settings of the kernel config for the BIOS stages to `out_path`.
+///
+/// The second stage selects the VESA mode before it could parse the kernel, so the builder
+/// extracts the settings up front. The file contains the minimum width and height, the
+/// preferred width and height, and the preferred bits per pixel, as little-endian `u16`
+/// values followed by a byte. Unset values are `0`.
+#[cfg(feature = "bios")]
+pub fn create_video_mode_file(kernel_path: &Path, out_path: &Path) -> anyhow::Result<()> {
+    let kernel = compression::read_kernel(kernel_path)?;
+    let config = BootloaderConfig::deserialize(raw_config(&kernel)?)
+        .map_err(|err| anyhow!("failed to parse bootloader config of kernel: {err}"))?;
+
+    let frame_buffer = config.frame_buffer;
+    let mut video_mode = Vec::with_capacity(9);
+    for (name, value) in [
+        (
+            "minimum_framebuffer_width",
+            frame_buffer.minimum_framebuffer_width,
+        ),
+        (
+            "minimum_framebuffer_height",
+            frame_buffer.minimum_framebuffer_height,
+        ),
+        ("framebuffer_width", frame_buffer.framebuffer_width),
+        ("framebuffer_height", frame_buffer.framebuffer_height),
+    ] {
+        let value = u16::try_from(value.unwrap_or(0))
+            .map_err(|_| anyhow!("{name} of the kernel config is too large for VESA modes"))?;
+        video_mode.extend_from_slice(&value.to_le_bytes());
+    }
+    video_mode.push(frame_buffer.framebuffer_bpp.unwrap_or(0));
+    fs::write(out_path, video_mode)
+        .with_context(|| format!("failed to write video mode to `{}`", out_path.display()))
+}
+
+/// Fails if the ramdisk can't be placed below the `dma_address_limit` of the kernel config.
+///
+/// The check assumes that enough free memory is available below the limit, so the boot
+/// stages can still fail on machines with little or fragmented memory.
+pub fn check_dma_address_limit(
+    kernel_path: &Path,
+    ramdisk_path: Option<&Path>,
+    placement: RamdiskPlacement,
+) -> anyhow::Result<()> {
+    let kernel = compression::read_kernel(kernel_path)?;
+    let config = BootloaderConfig::deserialize(raw_config(&kernel)?)
+        .map_err(|err| anyhow!("failed to parse bootloader config of kernel: {err}"))?;
+
+    let (Some(limit), Some(ramdisk_path)) = (config.dma_address_limit, ramdisk_path) else {
+        return Ok(());
+    };
+    let ramdisk_len = fs::metadata(ramdisk_path)
+        .with_context(|| format!("failed to read ramdisk at `{}`", ramdisk_path.display()))?
+        .len();
+    let ramdisk_start = match placement {
+        RamdiskPlacement::Bios => BIOS_KERNEL_MIN_ADDR + (kernel.len() as u64 + 4095) / 4096 * 4096,
+        RamdiskPlacement::Uefi => UEFI_RAMDISK_MIN_ADDR,
+    };
+    let ramdisk_end = ramdisk_start + ramdisk_len;
+    if ramdisk_end > limit {
+        bail!(
+            "ramdisk `{}` ({ramdisk_len} bytes) does not fit below the DMA address limit \
+            {limit:#x} of the kernel config (lowest possible end address: {ramdisk_end:#x})",
+            ramdisk_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn kernel_metadata(kernel_path: &Path) -> anyhow::Result<BootMetadata> {
+    let kernel = compression::read_kernel(kernel_path)?;
+    Ok(BootMetadata::new(
+        Sha256::digest(raw_config(&kernel)?).into(),
+        Sha256::digest(&kernel).into(),
+    ))
+}
+
+/// Returns the serialized bootloader config of the given kernel executable.
+///
+/// ELF kernels store the config in their `.bootloader-config` section, kernels in other
+/// formats are searched for it, like the boot stages do.
+fn raw_config(kernel: &[u8]) -> anyhow::Result<&[u8]> {
+    if !kernel.starts_with(b"\x7fELF") {
+        return BootloaderConfig::find_serialized(kernel).context(
+            "bootloader config not found; kernel must be compiled against bootloader_api",
+        );
+    }
+    let elf = xmas_elf::ElfFile::new(kernel)
+        .map_err(|err| anyhow!("failed to parse kernel ELF file: {err}"))?;
+    Ok(elf
+        .find_section_by_name(".bootloader-config")
+        .context(
+            "bootloader config section not found; kernel must be compiled against bootloader_api",
+        )?
+        .raw_data(&elf))
+}
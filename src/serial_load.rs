@@ -0,0 +1,113 @@
+//! Host side of the serial kernel load developer mode.
+//!
+//! See `BiosBoot::set_serial_kernel_load` and `UefiBoot::set_serial_kernel_load`.
+
+use anyhow::{bail, Context};
+use std::io::{self, Read, Write};
+
+const FRAME_START: u8 = 0x7e;
+const HEADER: u8 = b'H';
+const DATA: u8 = b'D';
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+/// The maximum payload length of a frame, must match the bootloader.
+const MAX_PAYLOAD: usize = 1024;
+/// The number of times a frame is sent before giving up.
+const MAX_ATTEMPTS: usize = 16;
+
+/// Sends a kernel to a bootloader that waits in serial kernel load mode.
+///
+/// `port` is the serial port connected to the target (COM1 for BIOS images, the first serial
+/// device of the firmware for UEFI images), configured for 115200 baud, 8N1, and raw mode.
+/// The kernel header is sent repeatedly until the bootloader is ready, so this function can
+/// be called before the target is started.
+///
+/// Frames that are not acknowledged are sent again. For this to work, reads from `port`
+/// should time out: a read that returns no data or fails with [`io::ErrorKind::TimedOut`]
+/// or [`io::ErrorKind::WouldBlock`] is treated as a missing acknowledgement. On Linux, a
+/// tty configured with `stty -F <port> 115200 raw min 0 time 10` behaves like this.
+pub fn push_kernel_over_serial<P: Read + Write>(port: &mut P, kernel: &[u8]) -> anyhow::Result<()> {
+    let len = u32::try_from(kernel.len()).context("kernel must be smaller than 4GiB")?;
+
+    let header = len.to_le_bytes();
+    while !send_frame(port, HEADER, 0, &header)? {}
+
+    for (i, chunk) in kernel.chunks(MAX_PAYLOAD).enumerate() {
+        let seq = (i + 1) as u8;
+        let mut attempts = 1;
+        while !send_frame(port, DATA, seq, chunk)? {
+            attempts += 1;
+            if attempts > MAX_ATTEMPTS {
+                bail!(
+                    "bootloader did not accept the kernel data at {:#x}",
+                    i * MAX_PAYLOAD
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends a frame and returns whether the bootloader acknowledged it.
+fn send_frame<P: Read + Write>(
+    port: &mut P,
+    kind: u8,
+    seq: u8,
+    payload: &[u8],
+) -> anyhow::Result<bool> {
+    let len = u16::try_from(payload.len()).unwrap();
+    let mut frame = vec![FRAME_START, kind, seq];
+    frame.extend(len.to_le_bytes());
+    frame.extend(payload);
+    let crc = crc32(&frame[1..]);
+    frame.extend(crc.to_le_bytes());
+
+    port.write_all(&frame)
+        .context("failed to write to serial port")?;
+    port.flush().context("failed to flush serial port")?;
+    loop {
+        match read_byte(port)? {
+            Some(ACK) => {
+                // acknowledgements of earlier frames are ignored
+                if read_byte(port)? == Some(seq) {
+                    return Ok(true);
+                }
+            }
+            Some(NAK) | None => return Ok(false),
+            // noise on the line
+            Some(_) => {}
+        }
+    }
+}
+
+/// Reads a single byte, returning `None` if the read timed out.
+fn read_byte(port: &mut impl Read) -> anyhow::Result<Option<u8>> {
+    let mut byte = [0];
+    match port.read(&mut byte) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(byte[0])),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => read_byte(port),
+        Err(err) => Err(err).context("failed to read from serial port"),
+    }
+}
+
+/// CRC-32 (IEEE), as computed by the bootloader.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
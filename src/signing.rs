@@ -0,0 +1,285 @@
+//! Signing of kernels for images with trusted keys.
+//!
+//! See `UefiBoot::add_trusted_key` and `BiosBoot::add_trusted_key`.
+
+use crate::compression;
+use anyhow::{anyhow, bail, Context};
+use ed25519_compact::{KeyPair, PublicKey, Seed};
+use std::{fs, path::Path};
+
+/// Marks the end of a signed kernel, which is followed by nothing else.
+///
+/// Keep in sync with `common/src/signature.rs`.
+const SIGNATURE_MAGIC: &[u8; 8] = b"BLKSIG01";
+const SIGNATURE_LEN: usize = 64;
+
+/// Marks the trusted keys structure of the fourth BIOS stage, keep in sync with
+/// `bios/stage-4/src/signature.rs`.
+#[cfg(feature = "bios")]
+const BIOS_TRUSTED_KEYS_MAGIC: &[u8; 8] = b"BLTRUSTK";
+/// The capacity of the trusted keys structure of the fourth BIOS stage.
+#[cfg(feature = "bios")]
+const BIOS_MAX_TRUSTED_KEYS: usize = 8;
+
+/// The trust level of a public key that is embedded into the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    /// Kernels signed with this key are always booted.
+    Production,
+    /// Kernels signed with this key are only booted if developer mode is enabled on the
+    /// device. The BIOS bootloader has no developer mode and doesn't accept these keys.
+    ///
+    /// Developer mode is enabled through the `BootloaderDevMode` EFI variable, which must be
+    /// set to a non-zero byte without runtime access, e.g. from the UEFI shell or by the
+    /// firmware when a jumper is set.
+    Developer,
+}
+
+impl KeyRole {
+    /// Returns the role byte of the trusted keys section, keep in sync with
+    /// `common/src/signature.rs`.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            KeyRole::Production => 0,
+            KeyRole::Developer => 1,
+        }
+    }
+}
+
+/// Generates a new Ed25519 key pair for signing kernels.
+///
+/// The secret key is written to `secret_key_path` and the public key to `public_key_path`,
+/// both as hex text. The secret key must be kept private; the public key is passed to
+/// `UefiBoot::add_trusted_key` or `BiosBoot::add_trusted_key`.
+pub fn generate_signing_key(secret_key_path: &Path, public_key_path: &Path) -> anyhow::Result<()> {
+    let key_pair = KeyPair::from_seed(Seed::new(rand::random()));
+    fs::write(secret_key_path, hex(key_pair.sk.seed().as_ref())).with_context(|| {
+        format!(
+            "failed to write secret key to `{}`",
+            secret_key_path.display()
+        )
+    })?;
+    fs::write(public_key_path, hex(key_pair.pk.as_ref())).with_context(|| {
+        format!(
+            "failed to write public key to `{}`",
+            public_key_path.display()
+        )
+    })
+}
+
+/// Signs the given kernel executable and writes the signed kernel to `out_path`.
+///
+/// The signature is appended to the kernel, so the signed kernel can be loaded from any boot
+/// source, e.g. an A/B slot, a TFTP server, or the serial port. Bootloaders without trusted
+/// keys ignore the signature.
+///
+/// The bootloaders check the signature before they decompress or parse the kernel, so it
+/// covers the kernel as stored. If `compress` is set, the kernel is compressed before it's
+/// signed, like `set_compress_kernel` of the image builders does for unsigned kernels. The
+/// image builders use compressed signed kernels as given.
+pub fn sign_kernel(
+    kernel_path: &Path,
+    secret_key_path: &Path,
+    out_path: &Path,
+    compress: bool,
+) -> anyhow::Result<()> {
+    let secret_key = KeyPair::from_seed(Seed::new(read_hex_key(secret_key_path)?)).sk;
+
+    let mut kernel = fs::read(kernel_path)
+        .with_context(|| format!("failed to read kernel at `{}`", kernel_path.display()))?;
+    if is_signed(&kernel) {
+        bail!("kernel `{}` is already signed", kernel_path.display());
+    }
+    if compress {
+        kernel = compression::compress(&kernel);
+    }
+    let signature = secret_key.sign(&kernel, None);
+    kernel.extend_from_slice(signature.as_ref());
+    kernel.extend_from_slice(SIGNATURE_MAGIC);
+    fs::write(out_path, kernel)
+        .with_context(|| format!("failed to write signed kernel to `{}`", out_path.display()))
+}
+
+/// Returns whether the given kernel ends with a signature.
+pub(crate) fn is_signed(kernel: &[u8]) -> bool {
+    content(kernel).len() != kernel.len()
+}
+
+/// Returns the given kernel without its signature, keep in sync with
+/// `common/src/signature.rs`.
+pub(crate) fn content(kernel: &[u8]) -> &[u8] {
+    match kernel
+        .strip_suffix(SIGNATURE_MAGIC)
+        .filter(|signed| signed.len() >= SIGNATURE_LEN)
+    {
+        Some(signed) => &signed[..signed.len() - SIGNATURE_LEN],
+        None => kernel,
+    }
+}
+
+/// Returns a copy of the fourth BIOS stage with the given public keys embedded as trusted
+/// production keys.
+#[cfg(feature = "bios")]
+pub(crate) fn embed_bios_trusted_keys(
+    stage_4: &[u8],
+    keys: &[[u8; 32]],
+) -> anyhow::Result<Vec<u8>> {
+    if keys.len() > BIOS_MAX_TRUSTED_KEYS {
+        bail!("the BIOS bootloader supports at most {BIOS_MAX_TRUSTED_KEYS} trusted keys");
+    }
+    let magic = stage_4
+        .windows(BIOS_TRUSTED_KEYS_MAGIC.len())
+        .position(|window| window == BIOS_TRUSTED_KEYS_MAGIC)
+        .context("fourth BIOS stage has no trusted keys structure")?;
+
+    let mut stage_4 = stage_4.to_vec();
+    let len_offset = magic + BIOS_TRUSTED_KEYS_MAGIC.len();
+    stage_4[len_offset..][..4].copy_from_slice(&(keys.len() as u32).to_le_bytes());
+    let mut offset = len_offset + 4;
+    for key in keys {
+        stage_4[offset] = KeyRole::Production.to_u8();
+        stage_4[offset + 1..][..key.len()].copy_from_slice(key);
+        offset += 1 + key.len();
+    }
+    Ok(stage_4)
+}
+
+/// Reads a public key that was written by [`generate_signing_key`].
+pub(crate) fn read_public_key(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let key = read_hex_key(path)?;
+    PublicKey::from_slice(&key).map_err(|err| anyhow!("invalid public key: {err}"))?;
+    Ok(key)
+}
+
+fn read_hex_key(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read key at `{}`", path.display()))?;
+    let text = text.trim();
+    if text.len() != 64 {
+        bail!("key at `{}` is not 32 hex-encoded bytes", path.display());
+    }
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .with_context(|| format!("key at `{}` is not valid hex", path.display()))?;
+    }
+    Ok(key)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut text: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_compact::Signature;
+    use tempfile::TempDir;
+
+    const KERNEL: &[u8] = b"\x7fELF kernel contents";
+
+    /// Generates a key pair and writes the kernel into a new temporary directory.
+    fn setup() -> (TempDir, PublicKey) {
+        let dir = tempfile::tempdir().unwrap();
+        generate_signing_key(&dir.path().join("key"), &dir.path().join("key.pub")).unwrap();
+        fs::write(dir.path().join("kernel"), KERNEL).unwrap();
+        let public_key = read_public_key(&dir.path().join("key.pub")).unwrap();
+        (dir, PublicKey::new(public_key))
+    }
+
+    /// Checks the signature of the given signed kernel and returns the signed content.
+    fn verify(signed: &[u8], public_key: &PublicKey) -> Vec<u8> {
+        assert!(is_signed(signed));
+        let content = content(signed);
+        let signature = &signed[content.len()..][..SIGNATURE_LEN];
+        public_key
+            .verify(content, &Signature::from_slice(signature).unwrap())
+            .unwrap();
+        content.to_vec()
+    }
+
+    #[test]
+    fn signs_kernel() {
+        let (dir, public_key) = setup();
+        let (kernel, key, signed) = (
+            dir.path().join("kernel"),
+            dir.path().join("key"),
+            dir.path().join("kernel.signed"),
+        );
+        sign_kernel(&kernel, &key, &signed, false).unwrap();
+
+        let signed = fs::read(&signed).unwrap();
+        assert_eq!(verify(&signed, &public_key), KERNEL);
+        assert!(!is_signed(KERNEL));
+        assert_eq!(content(KERNEL), KERNEL);
+    }
+
+    #[test]
+    fn signs_compressed_kernel() {
+        let (dir, public_key) = setup();
+        let (kernel, key, signed) = (
+            dir.path().join("kernel"),
+            dir.path().join("key"),
+            dir.path().join("kernel.signed"),
+        );
+        sign_kernel(&kernel, &key, &signed, true).unwrap();
+
+        let content = verify(&fs::read(&signed).unwrap(), &public_key);
+        assert_eq!(content, compression::compress(KERNEL));
+        assert_eq!(compression::read_kernel(&signed).unwrap(), KERNEL);
+    }
+
+    #[test]
+    fn rejects_signed_kernels() {
+        let (dir, _) = setup();
+        let (kernel, key, signed) = (
+            dir.path().join("kernel"),
+            dir.path().join("key"),
+            dir.path().join("kernel.signed"),
+        );
+        sign_kernel(&kernel, &key, &signed, false).unwrap();
+
+        let out = dir.path().join("kernel.signed2");
+        assert!(sign_kernel(&signed, &key, &out, false).is_err());
+        // compressing would invalidate the signature
+        assert!(compression::KernelFile::new(&signed, true).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        for key in ["", "00", &"zz".repeat(32), &"00".repeat(33)] {
+            fs::write(&path, key).unwrap();
+            assert!(read_hex_key(&path).is_err(), "{key:?}");
+        }
+        fs::write(&path, hex(&[0xab; 32])).unwrap();
+        assert_eq!(read_hex_key(&path).unwrap(), [0xab; 32]);
+    }
+
+    #[cfg(feature = "bios")]
+    #[test]
+    fn embeds_bios_trusted_keys() {
+        let mut stage_4 = vec![0xff; 16];
+        stage_4.extend_from_slice(BIOS_TRUSTED_KEYS_MAGIC);
+        stage_4.extend_from_slice(&[0; 4 + BIOS_MAX_TRUSTED_KEYS * 33]);
+        stage_4.extend_from_slice(&[0xff; 16]);
+
+        let embedded = embed_bios_trusted_keys(&stage_4, &[[1; 32], [2; 32]]).unwrap();
+        assert_eq!(embedded.len(), stage_4.len());
+        let keys = &embedded[16 + BIOS_TRUSTED_KEYS_MAGIC.len()..];
+        assert_eq!(keys[..4], 2u32.to_le_bytes());
+        assert_eq!(keys[4], KeyRole::Production.to_u8());
+        assert_eq!(keys[5..][..32], [1; 32]);
+        assert_eq!(keys[37], KeyRole::Production.to_u8());
+        assert_eq!(keys[38..][..32], [2; 32]);
+        assert_eq!(keys[70..][..33], [0; 33]);
+
+        assert!(embed_bios_trusted_keys(&stage_4, &[[1; 32]; BIOS_MAX_TRUSTED_KEYS + 1]).is_err());
+        assert!(embed_bios_trusted_keys(&[0; 64], &[[1; 32]]).is_err());
+    }
+}
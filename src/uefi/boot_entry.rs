@@ -0,0 +1,127 @@
+use std::{fmt::Write, fs, path::Path};
+
+use anyhow::Context;
+use uuid::Uuid;
+
+/// Writes a shell script that creates a firmware boot entry with `efibootmgr` on Linux.
+///
+/// The script finds the disk and the partition number through the unique partition GUID of
+/// the EFI system partition, so it works no matter which device the image was written to.
+pub fn create_efibootmgr_script(
+    esp_partition_guid: Uuid,
+    loader: &str,
+    label: &str,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let mut script = String::new();
+    let s = &mut script;
+    writeln!(s, "#!/bin/sh").unwrap();
+    writeln!(
+        s,
+        "# Creates a UEFI boot entry for the disk image after it was written to a disk."
+    )
+    .unwrap();
+    writeln!(s, "# Run as root.").unwrap();
+    writeln!(s, "set -e").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "esp=/dev/disk/by-partuuid/{esp_partition_guid}").unwrap();
+    writeln!(s, "if [ ! -e \"$esp\" ]; then").unwrap();
+    writeln!(
+        s,
+        "    echo \"EFI system partition {esp_partition_guid} not found\" >&2"
+    )
+    .unwrap();
+    writeln!(s, "    exit 1").unwrap();
+    writeln!(s, "fi").unwrap();
+    writeln!(s, "esp=$(readlink -f \"$esp\")").unwrap();
+    writeln!(s, "disk=/dev/$(lsblk -no PKNAME \"$esp\")").unwrap();
+    writeln!(
+        s,
+        "part=$(cat \"/sys/class/block/$(basename \"$esp\")/partition\")"
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "efibootmgr --create --disk \"$disk\" --part \"$part\" --label {} --loader {}",
+        sh_quote(label),
+        sh_quote(loader)
+    )
+    .unwrap();
+
+    fs::write(out_path, script)
+        .with_context(|| format!("failed to write {}", out_path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(out_path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {} executable", out_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes a PowerShell script that creates a firmware boot entry with `bcdedit` on Windows.
+///
+/// Windows names the volumes of GPT partitions after their unique partition GUID, so the
+/// script mounts the EFI system partition through it.
+pub fn create_bcdedit_script(
+    esp_partition_guid: Uuid,
+    loader: &str,
+    label: &str,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let mut script = String::new();
+    let s = &mut script;
+    writeln!(
+        s,
+        "# Creates a UEFI boot entry for the disk image after it was written to a disk."
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "# Run in an elevated PowerShell. Assigns the drive letter S: to the EFI system \
+         partition while it runs."
+    )
+    .unwrap();
+    writeln!(s, "$ErrorActionPreference = 'Stop'").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "mountvol S: '\\\\?\\Volume{{{esp_partition_guid}}}\\'").unwrap();
+    writeln!(s, "try {{").unwrap();
+    writeln!(
+        s,
+        "    $output = bcdedit /copy '{{bootmgr}}' /d {}",
+        ps_quote(label)
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "    $id = [regex]::Match($output, '\\{{[0-9a-fA-F-]+\\}}').Value"
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "    if (-not $id) {{ throw \"bcdedit failed: $output\" }}"
+    )
+    .unwrap();
+    writeln!(s, "    bcdedit /set $id device partition=S:").unwrap();
+    writeln!(s, "    bcdedit /set $id path {}", ps_quote(loader)).unwrap();
+    writeln!(
+        s,
+        "    bcdedit /set '{{fwbootmgr}}' displayorder $id /addfirst"
+    )
+    .unwrap();
+    writeln!(s, "}} finally {{").unwrap();
+    writeln!(s, "    mountvol S: /D").unwrap();
+    writeln!(s, "}}").unwrap();
+
+    fs::write(out_path, script).with_context(|| format!("failed to write {}", out_path.display()))
+}
+
+/// Quotes the given text for POSIX shells.
+fn sh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Quotes the given text for PowerShell.
+fn ps_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use tempfile::NamedTempFile;
+
+use crate::{compression::KernelFile, metadata};
+
+/// The maximum number of menu entries, keep in sync with `common/src/boot_menu.rs`.
+pub const MAX_ENTRIES: usize = 9;
+
+/// An additional kernel of the boot menu.
+#[derive(Debug, Clone)]
+pub struct MenuEntry {
+    pub label: String,
+    pub kernel: PathBuf,
+    pub ramdisk: Option<PathBuf>,
+}
+
+/// The `boot-menu` file and the files of the additional entries, with their names on the
+/// image.
+pub struct MenuFiles {
+    menu: NamedTempFile,
+    kernels: Vec<(String, KernelFile)>,
+    boot_metadata: Vec<(String, NamedTempFile)>,
+    ramdisks: Vec<(String, PathBuf)>,
+}
+
+impl MenuFiles {
+    /// Adds the files to the files of a FAT partition.
+    pub fn insert<'a>(&'a self, files: &mut BTreeMap<&'a str, &'a Path>) {
+        files.insert(crate::BOOT_MENU_FILE_NAME, self.menu.path());
+        for (name, kernel) in &self.kernels {
+            files.insert(name, kernel.path());
+        }
+        for (name, boot_metadata) in &self.boot_metadata {
+            files.insert(name, boot_metadata.path());
+        }
+        for (name, ramdisk) in &self.ramdisks {
+            files.insert(name, ramdisk);
+        }
+    }
+}
+
+/// Creates the `boot-menu` file, see `bootloader_x86_64_common::boot_menu::parse`.
+///
+/// The first entry boots the regular kernel. The kernel and ramdisk of the additional entry
+/// with the one-based index `i` are named `kernel-x86_64-<i>` and `ramdisk-<i>`. The boot
+/// metadata block of each additional kernel is created by `boot_metadata` and named after the
+/// kernel, see `metadata::file_name`. The entry with the `usb_label` comes last and loads the
+/// regular file names from a USB mass-storage device.
+#[allow(clippy::too_many_arguments)]
+pub fn create_menu_files(
+    label: &str,
+    has_ramdisk: bool,
+    entries: &[MenuEntry],
+    usb_label: Option<&str>,
+    timeout_secs: u32,
+    default: usize,
+    compress_kernel: bool,
+    boot_metadata: impl Fn(&Path) -> anyhow::Result<NamedTempFile>,
+) -> anyhow::Result<MenuFiles> {
+    let len = entries.len() + 1 + usize::from(usb_label.is_some());
+    if len > MAX_ENTRIES {
+        bail!("the boot menu supports at most {MAX_ENTRIES} entries");
+    }
+    if default >= len {
+        bail!("default boot menu entry {default} does not exist");
+    }
+
+    let mut menu = String::new();
+    writeln!(menu, "timeout {timeout_secs}").unwrap();
+    writeln!(menu, "default {default}").unwrap();
+    let ramdisk = if has_ramdisk {
+        crate::RAMDISK_FILE_NAME
+    } else {
+        "-"
+    };
+    write_entry(&mut menu, "entry", crate::KERNEL_FILE_NAME, ramdisk, label)?;
+
+    let mut kernels = Vec::new();
+    let mut metadata_files = Vec::new();
+    let mut ramdisks = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let kernel_name = format!("{}-{}", crate::KERNEL_FILE_NAME, index + 1);
+        let ramdisk_name = match &entry.ramdisk {
+            Some(path) => {
+                let name = format!("{}-{}", crate::RAMDISK_FILE_NAME, index + 1);
+                ramdisks.push((name.clone(), path.clone()));
+                name
+            }
+            None => "-".to_owned(),
+        };
+        write_entry(
+            &mut menu,
+            "entry",
+            &kernel_name,
+            &ramdisk_name,
+            &entry.label,
+        )?;
+        metadata_files.push((
+            metadata::file_name(&kernel_name),
+            boot_metadata(&entry.kernel)?,
+        ));
+        kernels.push((
+            kernel_name,
+            KernelFile::new(&entry.kernel, compress_kernel)?,
+        ));
+    }
+
+    if let Some(usb_label) = usb_label {
+        write_entry(
+            &mut menu,
+            "usb-entry",
+            crate::KERNEL_FILE_NAME,
+            ramdisk,
+            usb_label,
+        )?;
+    }
+
+    let mut file = NamedTempFile::new().context("failed to create temp file")?;
+    file.write_all(menu.as_bytes())
+        .context("failed to write boot menu")?;
+    Ok(MenuFiles {
+        menu: file,
+        kernels,
+        boot_metadata: metadata_files,
+        ramdisks,
+    })
+}
+
+fn write_entry(
+    menu: &mut String,
+    keyword: &str,
+    kernel: &str,
+    ramdisk: &str,
+    label: &str,
+) -> anyhow::Result<()> {
+    if label.trim().is_empty() || label.contains(['\n', '\r', '#']) {
+        bail!("invalid boot menu label `{label}`");
+    }
+    writeln!(menu, "{keyword} {kernel} {ramdisk} {}", label.trim()).unwrap();
+    Ok(())
+}
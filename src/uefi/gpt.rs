@@ -4,8 +4,17 @@ use std::{
     io::{self, Seek},
     path::Path,
 };
+use uuid::Uuid;
 
-pub fn create_gpt_disk(fat_image: &Path, out_gpt_path: &Path) -> anyhow::Result<()> {
+/// Creates a GPT disk with a single EFI system partition containing the given FAT image.
+///
+/// Random GUIDs are generated for the disk and the partition if none are given.
+pub fn create_gpt_disk(
+    fat_image: &Path,
+    out_gpt_path: &Path,
+    disk_guid: Option<Uuid>,
+    esp_partition_guid: Option<Uuid>,
+) -> anyhow::Result<()> {
     // create new file
     let mut disk = fs::OpenOptions::new()
         .create(true)
@@ -37,7 +46,7 @@ pub fn create_gpt_disk(fat_image: &Path, out_gpt_path: &Path) -> anyhow::Result<
         .writable(true)
         .initialized(false)
         .logical_block_size(block_size)
-        .create_from_device(Box::new(&mut disk), None)
+        .create_from_device(Box::new(&mut disk), disk_guid)
         .context("failed to create GPT structure in file")?;
     gpt.update_partitions(Default::default())
         .context("failed to update GPT partitions")?;
@@ -46,6 +55,14 @@ pub fn create_gpt_disk(fat_image: &Path, out_gpt_path: &Path) -> anyhow::Result<
     let partition_id = gpt
         .add_partition("boot", partition_size, gpt::partition_types::EFI, 0, None)
         .context("failed to add boot EFI partition")?;
+    if let Some(guid) = esp_partition_guid {
+        let mut partitions = gpt.partitions().clone();
+        if let Some(partition) = partitions.get_mut(&partition_id) {
+            partition.part_guid = guid;
+        }
+        gpt.update_partitions(partitions)
+            .context("failed to set GUID of boot EFI partition")?;
+    }
     let partition = gpt
         .partitions()
         .get(&partition_id)
@@ -1,18 +1,108 @@
-use crate::fat;
-use anyhow::Context;
+use crate::{
+    compression::KernelFile,
+    extra_files::{self, ExtraFile},
+    fat, metadata, signing, DiskImageBuilder, KeyRole,
+};
+use anyhow::{bail, Context};
+use bootloader_api::{config::LogLevels, info::ExtraMapping};
 use std::{
     collections::BTreeMap,
+    io::Write,
+    net::{Ipv4Addr, SocketAddrV4},
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
+use uuid::Uuid;
 
+mod boot_entry;
+mod boot_menu;
 mod gpt;
 mod pxe;
+mod stub;
+
+/// Namespace for the name-based GUIDs created by [`UefiBoot::derive_guids_from_kernel_name`].
+const GUID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x8e, 0x4d, 0x6c, 0x1b, 0x35, 0x0f, 0x4a, 0x7e, 0x9d, 0x2c, 0x51, 0xb3, 0x0a, 0x6e, 0xf2, 0x94,
+]);
+
+/// The boot log consists of this many fixed-size records, keep in sync with
+/// `uefi/src/boot_log.rs`.
+const BOOT_LOG_RECORDS: usize = 64;
+const BOOT_LOG_RECORD_LEN: usize = 64;
+
+/// The path at which firmware boots removable media without a boot entry.
+const REMOVABLE_MEDIA_BOOTLOADER_PATH: &str = "efi/boot/bootx64.efi";
+/// The file name of the bootloader in the vendor directory `efi/<vendor>/`.
+const VENDOR_BOOTLOADER_FILE_NAME: &str = "bootloader-x64.efi";
+/// Lists the boot entries for the executables of the vendor directory, see [`boot_csv`].
+const BOOT_CSV_FILE_NAME: &str = "bootx64.csv";
+
+/// Where the bootloader executable is placed on the EFI system partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspLayout {
+    /// Only at the removable media path `EFI/BOOT/BOOTX64.EFI`, which firmware boots without
+    /// a boot entry. This is the default.
+    RemovableMedia,
+    /// Only at `EFI/<vendor>/bootloader-x64.efi`, for installations that create a firmware
+    /// boot entry for the bootloader.
+    Vendor(String),
+    /// At both the removable media path and the vendor path.
+    ///
+    /// Some firmware only enumerates the vendor directories if boot entries exist, so the
+    /// removable media path keeps the disk bootable until an entry is created.
+    RemovableMediaAndVendor(String),
+}
+
+impl EspLayout {
+    fn removable_media(&self) -> bool {
+        matches!(
+            self,
+            EspLayout::RemovableMedia | EspLayout::RemovableMediaAndVendor(_)
+        )
+    }
+
+    /// Returns the path of the bootloader for firmware boot entries, preferring the vendor
+    /// path.
+    fn boot_entry_loader(&self) -> String {
+        match self.vendor() {
+            Some(vendor) => format!("\\EFI\\{vendor}\\{VENDOR_BOOTLOADER_FILE_NAME}"),
+            None => "\\EFI\\BOOT\\BOOTX64.EFI".to_owned(),
+        }
+    }
+
+    fn vendor(&self) -> Option<&str> {
+        match self {
+            EspLayout::RemovableMedia => None,
+            EspLayout::Vendor(vendor) | EspLayout::RemovableMediaAndVendor(vendor) => Some(vendor),
+        }
+    }
+}
 
 /// Create disk images for booting on UEFI systems.
 pub struct UefiBoot {
     kernel: PathBuf,
     ramdisk: Option<PathBuf>,
+    recovery_kernel: Option<PathBuf>,
+    support_info: Option<PathBuf>,
+    boot_script: Option<PathBuf>,
+    disk_guid: Option<Uuid>,
+    esp_partition_guid: Option<Uuid>,
+    serial_kernel_load: bool,
+    boot_log: bool,
+    p9_server: Option<(SocketAddrV4, String)>,
+    extra_mappings: Vec<ExtraMapping>,
+    log_levels: Option<LogLevels>,
+    trusted_keys: Vec<(PathBuf, KeyRole)>,
+    command_line: String,
+    esp_layout: EspLayout,
+    compress_kernel: bool,
+    extra_files: Vec<ExtraFile>,
+    menu_entries: Vec<boot_menu::MenuEntry>,
+    menu_label: Option<String>,
+    usb_menu_entry: Option<String>,
+    menu_timeout_secs: u32,
+    menu_default: usize,
+    artifacts: DiskImageBuilder,
 }
 
 impl UefiBoot {
@@ -21,6 +111,27 @@ impl UefiBoot {
         Self {
             kernel: kernel_path.to_owned(),
             ramdisk: None,
+            recovery_kernel: None,
+            support_info: None,
+            boot_script: None,
+            disk_guid: None,
+            esp_partition_guid: None,
+            serial_kernel_load: false,
+            boot_log: false,
+            p9_server: None,
+            extra_mappings: Vec::new(),
+            log_levels: None,
+            trusted_keys: Vec::new(),
+            command_line: String::new(),
+            esp_layout: EspLayout::RemovableMedia,
+            compress_kernel: false,
+            extra_files: Vec::new(),
+            menu_entries: Vec::new(),
+            menu_label: None,
+            usb_menu_entry: None,
+            menu_timeout_secs: 5,
+            menu_default: 0,
+            artifacts: DiskImageBuilder::new(),
         }
     }
 
@@ -30,14 +141,291 @@ impl UefiBoot {
         self
     }
 
+    /// Add a recovery kernel to the disk image.
+    ///
+    /// The bootloader starts the recovery kernel instead of the regular kernel after the
+    /// number of failed boots given by the `boot_failure_limit` config option of the kernel.
+    pub fn set_recovery_kernel(&mut self, recovery_kernel_path: &Path) -> &mut Self {
+        self.recovery_kernel = Some(recovery_kernel_path.to_owned());
+        self
+    }
+
+    /// Add a text file with support contact information to the disk image.
+    ///
+    /// The text is shown on the error screen when the bootloader fails to start the kernel.
+    pub fn set_support_info(&mut self, support_info_path: &Path) -> &mut Self {
+        self.support_info = Some(support_info_path.to_owned());
+        self
+    }
+
+    /// Add a boot script that selects the kernel and ramdisk at boot time.
+    ///
+    /// The script is a text file with one statement per line, for example:
+    ///
+    /// ```text
+    /// set kernel kernel-x86_64
+    /// if key-pressed F2 set kernel kernel-debug
+    /// if previous-boot-failed boot kernel-recovery-x86_64
+    /// boot $kernel ramdisk
+    /// ```
+    ///
+    /// The referenced files must be added to the disk image separately. The
+    /// `previous-boot-failed` condition relies on the boot counter that is enabled by the
+    /// `boot_failure_limit` config option of the kernel. If the script is invalid or ends
+    /// without a `boot` statement, the bootloader falls back to the default kernel. See
+    /// `bootloader_x86_64_common::boot_script::run` for the full syntax.
+    pub fn set_boot_script(&mut self, boot_script_path: &Path) -> &mut Self {
+        self.boot_script = Some(boot_script_path.to_owned());
+        self
+    }
+
+    /// Add another kernel to the disk image and let the user choose between the kernels in a
+    /// boot menu.
+    ///
+    /// The first entry of the menu boots the regular kernel, further entries are added in the
+    /// order of the calls. At most 9 kernels are supported. The bootloader shows the menu on
+    /// the firmware console, which most firmware mirrors to the serial port, and boots the
+    /// default entry after the timeout, see [`Self::set_menu_timeout`]. Each kernel gets its
+    /// own boot metadata block with the command line and the extra mappings of the image. The
+    /// additional kernels are only placed on the disk image, not into the PXE folder or the
+    /// stub executable.
+    pub fn add_menu_entry(
+        &mut self,
+        label: &str,
+        kernel_path: &Path,
+        ramdisk_path: Option<&Path>,
+    ) -> &mut Self {
+        self.menu_entries.push(boot_menu::MenuEntry {
+            label: label.to_owned(),
+            kernel: kernel_path.to_owned(),
+            ramdisk: ramdisk_path.map(Path::to_owned),
+        });
+        self
+    }
+
+    /// Set the label of the regular kernel in the boot menu.
+    ///
+    /// Defaults to the file name of the kernel.
+    pub fn set_menu_label(&mut self, label: &str) -> &mut Self {
+        self.menu_label = Some(label.to_owned());
+        self
+    }
+
+    /// Set the number of seconds after which the boot menu boots the default entry.
+    ///
+    /// Defaults to 5 seconds. With `0`, the default entry is booted without showing the menu.
+    pub fn set_menu_timeout(&mut self, seconds: u32) -> &mut Self {
+        self.menu_timeout_secs = seconds;
+        self
+    }
+
+    /// Set the zero-based index of the boot menu entry that is booted after the timeout.
+    ///
+    /// Index `0`, the regular kernel, is the default.
+    pub fn set_menu_default(&mut self, index: usize) -> &mut Self {
+        self.menu_default = index;
+        self
+    }
+
+    /// Add an entry with the given label to the boot menu that loads the kernel from a USB
+    /// mass-storage device.
+    ///
+    /// This is an alternative to [`Self::set_serial_kernel_load`] for boards that are tedious
+    /// to reflash. When the entry is chosen, the bootloader loads `kernel-x86_64` and, if the
+    /// image has a ramdisk, `ramdisk` from the root directory of the first USB file system
+    /// other than the boot device, e.g. a USB stick or the storage that a devboard exposes as
+    /// a USB mass-storage gadget. The boot metadata block and the other files are loaded from
+    /// there as well. The entry is added after the kernels of [`Self::add_menu_entry`].
+    pub fn add_usb_menu_entry(&mut self, label: &str) -> &mut Self {
+        self.usb_menu_entry = Some(label.to_owned());
+        self
+    }
+
+    /// Receive the kernel over a serial device instead of loading it from the disk image.
+    ///
+    /// This is a developer mode for boards that are tedious to reflash. On boot, the
+    /// bootloader waits for a kernel on the first serial I/O device of the firmware, which
+    /// includes USB serial devices on many boards. The kernel can be sent with
+    /// [`push_kernel_over_serial`](crate::push_kernel_over_serial). All other files are
+    /// still loaded from the disk image.
+    pub fn set_serial_kernel_load(&mut self, enable: bool) -> &mut Self {
+        self.serial_kernel_load = enable;
+        self
+    }
+
+    /// Compress the kernel and the recovery kernel with LZ4 to reduce the size of the image
+    /// and the load time.
+    ///
+    /// This applies to the disk image and the PXE folder. The bootloader decompresses the
+    /// kernel into newly allocated memory before parsing it. Kernels that are received over
+    /// serial, loaded from a 9P server, or embedded by [`Self::create_stub_efi`] are not
+    /// compressed, but compressed kernels from these sources are decompressed as well.
+    pub fn set_compress_kernel(&mut self, enable: bool) -> &mut Self {
+        self.compress_kernel = enable;
+        self
+    }
+
+    /// Keep a log of the last boots on the EFI system partition.
+    ///
+    /// On every boot, the bootloader overwrites the oldest of 64 records in the `boot-log`
+    /// file with the boot mode, the loaded kernel file, and the code of the boot error, if
+    /// any. The records are lines of text, so the log can be read on another machine after a
+    /// failure in the field. The OS reports the outcome of the boot by replacing the `?` at
+    /// byte 62 of the newest record with `+` or `-`.
+    pub fn set_boot_log(&mut self, enable: bool) -> &mut Self {
+        self.boot_log = enable;
+        self
+    }
+
+    /// Load the kernel and the other files from a 9P server instead of the disk image.
+    ///
+    /// This is a developer mode for diskless setups. On boot, the bootloader connects to the
+    /// server over TCP, attaches to the given export (or the default export of the server if
+    /// empty), and loads the files from its root directory with the 9P2000.L protocol. The
+    /// firmware needs a TCP stack and configures the network through DHCP. Under QEMU's user
+    /// networking, a server on the host is reachable at `10.0.2.2`. If the server can't be
+    /// reached, the files are loaded from the disk image.
+    pub fn set_p9_server(&mut self, address: SocketAddrV4, export: &str) -> &mut Self {
+        self.p9_server = Some((address, export.to_owned()));
+        self
+    }
+
+    /// Set the command line that is passed to the kernel.
+    ///
+    /// The command line is stored in the boot metadata block and reported in
+    /// `BootInfo::command_line`. At most `CommandLine::MAX_LEN` bytes are supported.
+    pub fn set_command_line(&mut self, command_line: &str) -> &mut Self {
+        self.command_line = command_line.to_owned();
+        self
+    }
+
+    /// Place the file at `host_path` on the boot partition under `image_path`, e.g. a
+    /// firmware blob or a configuration file that the kernel reads after boot.
+    ///
+    /// The image path is relative to the root of the partition and uses `/` as separator, e.g.
+    /// `firmware/wifi.bin`. Missing directories are created. The path must not conflict with
+    /// the files of the bootloader. The file is also placed into the PXE folder.
+    pub fn add_file(&mut self, image_path: &str, host_path: &Path) -> &mut Self {
+        self.extra_files.push(ExtraFile {
+            image_path: image_path.to_owned(),
+            host_path: host_path.to_owned(),
+        });
+        self
+    }
+
+    /// Set the log levels of the bootloader outputs for this kernel.
+    ///
+    /// The levels are stored in the boot metadata block and replace the `log_levels` of the
+    /// kernel's `BootloaderConfig` once the block is verified.
+    pub fn set_log_levels(&mut self, levels: LogLevels) -> &mut Self {
+        self.log_levels = Some(levels);
+        self
+    }
+
+    /// Map the given physical range at a fixed virtual address before entering the kernel.
+    ///
+    /// This is useful for device registers that the kernel accesses early, e.g. the local
+    /// APIC or the HPET. The mappings are stored in the boot metadata block and reported in
+    /// the `extra_mappings` field of `BootInfo::boot_metadata`. At most
+    /// `ExtraMappings::MAX_MAPPINGS` mappings are supported.
+    pub fn add_extra_mapping(&mut self, mapping: ExtraMapping) -> &mut Self {
+        self.extra_mappings.push(mapping);
+        self
+    }
+
+    /// Only boot kernels that are signed with one of the trusted keys.
+    ///
+    /// The public key must have been created with
+    /// [`generate_signing_key`](crate::generate_signing_key), and kernels are signed with
+    /// [`sign_kernel`](crate::sign_kernel). The keys are embedded into the bootloader
+    /// executable, so they are covered when the executable is signed for Secure Boot.
+    /// Kernels signed with a [`KeyRole::Developer`] key are only booted if developer mode is
+    /// enabled on the device. If no key is added, kernels are booted without checking their
+    /// signature.
+    pub fn add_trusted_key(&mut self, public_key_path: &Path, role: KeyRole) -> &mut Self {
+        self.trusted_keys.push((public_key_path.to_owned(), role));
+        self
+    }
+
+    /// Choose where the bootloader executable is placed on the EFI system partition.
+    ///
+    /// The vendor directory `EFI/<vendor>/` also receives a `BOOTX64.CSV` file in the format of
+    /// the `fallback.efi` program of shim, which creates the missing boot entries from it. The
+    /// vendor name may only contain ASCII letters, digits, `-`, and `_`. The kernel and the
+    /// other boot files stay in the root directory.
+    pub fn set_esp_layout(&mut self, layout: EspLayout) -> &mut Self {
+        self.esp_layout = layout;
+        self
+    }
+
+    /// Set the GUID of the GPT disk.
+    ///
+    /// If not set, a random GUID is generated for every created disk image.
+    pub fn set_disk_guid(&mut self, guid: Uuid) -> &mut Self {
+        self.disk_guid = Some(guid);
+        self
+    }
+
+    /// Set the unique partition GUID of the EFI system partition.
+    ///
+    /// If not set, a random GUID is generated for every created disk image.
+    pub fn set_esp_partition_guid(&mut self, guid: Uuid) -> &mut Self {
+        self.esp_partition_guid = Some(guid);
+        self
+    }
+
+    /// Derive the disk GUID and the EFI system partition GUID from the file name of the kernel.
+    ///
+    /// The GUIDs are name-based (version 5) UUIDs, so they stay the same across builds of a
+    /// kernel with the same name. Use this instead of random GUIDs if other tools need to
+    /// reference the disk or partition.
+    pub fn derive_guids_from_kernel_name(&mut self) -> &mut Self {
+        let kernel_name = self
+            .kernel
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.disk_guid = Some(Uuid::new_v5(
+            &GUID_NAMESPACE,
+            format!("{kernel_name}/disk").as_bytes(),
+        ));
+        self.esp_partition_guid = Some(Uuid::new_v5(
+            &GUID_NAMESPACE,
+            format!("{kernel_name}/esp").as_bytes(),
+        ));
+        self
+    }
+
+    /// Returns the configured GPT disk GUID, if any.
+    pub fn disk_guid(&self) -> Option<Uuid> {
+        self.disk_guid
+    }
+
+    /// Returns the configured EFI system partition GUID, if any.
+    pub fn esp_partition_guid(&self) -> Option<Uuid> {
+        self.esp_partition_guid
+    }
+
+    /// Use the bootloader executables of the given builder, see
+    /// [`DiskImageBuilder::uefi_boot`].
+    pub(crate) fn set_artifacts(&mut self, artifacts: DiskImageBuilder) -> &mut Self {
+        self.artifacts = artifacts;
+        self
+    }
+
     /// Create a bootable UEFI disk image at the given path.
     pub fn create_disk_image(&self, out_path: &Path) -> anyhow::Result<()> {
         let fat_partition = self
             .create_fat_partition()
             .context("failed to create FAT partition")?;
 
-        gpt::create_gpt_disk(fat_partition.path(), out_path)
-            .context("failed to create UEFI GPT disk image")?;
+        gpt::create_gpt_disk(
+            fat_partition.path(),
+            out_path,
+            self.disk_guid,
+            self.esp_partition_guid,
+        )
+        .context("failed to create UEFI GPT disk image")?;
 
         fat_partition
             .close()
@@ -46,35 +434,262 @@ impl UefiBoot {
         Ok(())
     }
 
+    /// Create a single UEFI executable that contains the bootloader, the kernel, and the
+    /// ramdisk.
+    ///
+    /// The files are embedded as PE sections, similar to the EFI stub of Linux. The resulting
+    /// executable can be signed as one unit and launched directly through a firmware boot
+    /// entry, without any further files on the EFI system partition.
+    pub fn create_stub_efi(&self, out_path: &Path) -> anyhow::Result<()> {
+        let bootloader_path = self.artifacts.uefi_bootloader_path();
+
+        metadata::check_dma_address_limit(
+            &self.kernel,
+            self.ramdisk.as_deref(),
+            metadata::RamdiskPlacement::Uefi,
+        )?;
+        let boot_metadata = self.boot_metadata_file(&self.kernel)?;
+        let recovery_boot_metadata = self
+            .recovery_kernel
+            .as_deref()
+            .map(|path| self.boot_metadata_file(path))
+            .transpose()?;
+
+        let mut sections = vec![(stub::KERNEL_SECTION, self.kernel.as_path())];
+        if let Some(ramdisk_path) = &self.ramdisk {
+            sections.push((stub::RAMDISK_SECTION, ramdisk_path));
+        }
+        if let Some(recovery_kernel_path) = &self.recovery_kernel {
+            sections.push((stub::RECOVERY_KERNEL_SECTION, recovery_kernel_path));
+        }
+        if let Some(recovery_boot_metadata) = &recovery_boot_metadata {
+            sections.push((
+                stub::RECOVERY_BOOT_METADATA_SECTION,
+                recovery_boot_metadata.path(),
+            ));
+        }
+        if let Some(support_info_path) = &self.support_info {
+            sections.push((stub::SUPPORT_INFO_SECTION, support_info_path));
+        }
+        sections.push((stub::BOOT_METADATA_SECTION, boot_metadata.path()));
+        let trusted_keys = trusted_keys_file(&self.trusted_keys)?;
+        if let Some(trusted_keys) = &trusted_keys {
+            sections.push((stub::TRUSTED_KEYS_SECTION, trusted_keys.path()));
+        }
+
+        stub::create_stub_efi(bootloader_path, &sections, out_path)
+            .context("failed to create UEFI stub executable")?;
+
+        boot_metadata
+            .close()
+            .context("failed to delete boot metadata after stub creation")?;
+
+        Ok(())
+    }
+
+    /// Create a Linux shell script that adds a firmware boot entry for a disk with the image.
+    ///
+    /// The script runs `efibootmgr` with the disk and partition number of the EFI system
+    /// partition, which it looks up through the partition GUID. The entry starts the
+    /// bootloader at the vendor path if the [`EspLayout`] has one. The partition GUID must
+    /// have been set before, e.g. through
+    /// [`derive_guids_from_kernel_name`](Self::derive_guids_from_kernel_name).
+    pub fn create_efibootmgr_script(&self, label: &str, out_path: &Path) -> anyhow::Result<()> {
+        boot_entry::create_efibootmgr_script(
+            self.fixed_esp_partition_guid()?,
+            &self.esp_layout.boot_entry_loader(),
+            label,
+            out_path,
+        )
+        .context("failed to create efibootmgr script")
+    }
+
+    /// Create a Windows PowerShell script that adds a firmware boot entry for a disk with the
+    /// image.
+    ///
+    /// The script mounts the EFI system partition through its partition GUID and runs
+    /// `bcdedit`. See [`create_efibootmgr_script`](Self::create_efibootmgr_script) for the
+    /// requirements.
+    pub fn create_bcdedit_script(&self, label: &str, out_path: &Path) -> anyhow::Result<()> {
+        boot_entry::create_bcdedit_script(
+            self.fixed_esp_partition_guid()?,
+            &self.esp_layout.boot_entry_loader(),
+            label,
+            out_path,
+        )
+        .context("failed to create bcdedit script")
+    }
+
     /// Prepare a folder for use with booting over UEFI_PXE.
     ///
     /// This places the bootloader executable under the path "bootloader". The
     /// DHCP server should set the filename option to that path, otherwise the
     /// bootloader won't be found.
     pub fn create_pxe_tftp_folder(&self, out_path: &Path) -> anyhow::Result<()> {
-        let bootloader_path = Path::new(env!("UEFI_BOOTLOADER_PATH"));
+        let bootloader = self.bootloader_with_trusted_keys()?;
+        let kernel = KernelFile::new(&self.kernel, self.compress_kernel)?;
+        let recovery_kernel = self.recovery_kernel_file()?;
 
         pxe::create_uefi_tftp_folder(
-            bootloader_path,
-            self.kernel.as_path(),
+            self.bootloader_path(&bootloader),
+            kernel.path(),
             self.ramdisk.as_deref(),
+            recovery_kernel.as_ref().map(KernelFile::path),
+            self.support_info.as_deref(),
             out_path,
         )
         .context("failed to create UEFI PXE tftp folder")?;
+        metadata::create_boot_metadata_file(
+            &self.kernel,
+            &self.extra_mappings,
+            &self.command_line,
+            self.log_levels,
+            &out_path.join(crate::BOOT_METADATA_FILE_NAME),
+        )
+        .context("failed to create boot metadata")?;
+        let recovery_metadata_name = metadata::file_name(crate::RECOVERY_KERNEL_FILE_NAME);
+        if let Some(recovery_kernel_path) = &self.recovery_kernel {
+            metadata::create_boot_metadata_file(
+                recovery_kernel_path,
+                &self.extra_mappings,
+                &self.command_line,
+                self.log_levels,
+                &out_path.join(&recovery_metadata_name),
+            )
+            .context("failed to create boot metadata of recovery kernel")?;
+        }
+        let reserved = [
+            pxe::BOOTLOADER_FILE_NAME,
+            crate::KERNEL_FILE_NAME,
+            crate::RAMDISK_FILE_NAME,
+            crate::RECOVERY_KERNEL_FILE_NAME,
+            crate::SUPPORT_INFO_FILE_NAME,
+            crate::BOOT_METADATA_FILE_NAME,
+            &recovery_metadata_name,
+        ];
+        extra_files::copy_to_folder(&self.extra_files, &reserved, out_path)?;
+
+        Ok(())
+    }
+
+    /// Prepare a folder for setting up a PXE boot server for BIOS and UEFI clients.
+    ///
+    /// The `tftp` subfolder contains the files of
+    /// [`create_pxe_tftp_folder`](Self::create_pxe_tftp_folder) and, if given, the BIOS
+    /// disk image created by [`BiosBoot`](crate::BiosBoot). The `dnsmasq.conf` and
+    /// `dhcpd.conf` files are configuration snippets for dnsmasq and the ISC DHCP server,
+    /// which select the boot file by the client architecture (DHCP option 93) and point the
+    /// clients to the TFTP server at `server_address`.
+    ///
+    /// The BIOS bootloader can't load files over the network, so BIOS clients are
+    /// chainloaded into iPXE, which boots the disk image through the `memdisk` loader of
+    /// SYSLINUX. Neither is part of the bundle: `undionly.kpxe` and `memdisk` need to be
+    /// copied into the `tftp/bios` folder separately.
+    pub fn create_netboot_bundle(
+        &self,
+        out_path: &Path,
+        server_address: Ipv4Addr,
+        bios_disk_image: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        pxe::create_netboot_bundle(
+            |tftp_root| self.create_pxe_tftp_folder(tftp_root),
+            bios_disk_image,
+            server_address,
+            out_path,
+        )
+        .context("failed to create netboot bundle")?;
 
         Ok(())
     }
 
     /// Creates an UEFI-bootable FAT partition with the kernel.
     fn create_fat_partition(&self) -> anyhow::Result<NamedTempFile> {
-        let bootloader_path = Path::new(env!("UEFI_BOOTLOADER_PATH"));
+        let bootloader = self.bootloader_with_trusted_keys()?;
+        let bootloader_path = self.bootloader_path(&bootloader);
+
+        metadata::check_dma_address_limit(
+            &self.kernel,
+            self.ramdisk.as_deref(),
+            metadata::RamdiskPlacement::Uefi,
+        )?;
+        let boot_metadata = self.boot_metadata_file(&self.kernel)?;
+        let recovery_boot_metadata = self
+            .recovery_kernel
+            .as_deref()
+            .map(|path| self.boot_metadata_file(path))
+            .transpose()?;
+
+        let vendor_paths = match self.esp_layout.vendor() {
+            Some(vendor) => {
+                check_vendor_name(vendor)?;
+                Some((
+                    vendor,
+                    format!("efi/{vendor}/{VENDOR_BOOTLOADER_FILE_NAME}"),
+                    format!("efi/{vendor}/{BOOT_CSV_FILE_NAME}"),
+                ))
+            }
+            None => None,
+        };
+        let mut boot_csv_file = NamedTempFile::new().context("failed to create temp file")?;
+        let kernel = KernelFile::new(&self.kernel, self.compress_kernel)?;
+        let recovery_kernel = self.recovery_kernel_file()?;
 
         let mut files = BTreeMap::new();
-        files.insert("efi/boot/bootx64.efi", bootloader_path);
-        files.insert(crate::KERNEL_FILE_NAME, self.kernel.as_path());
+        if self.esp_layout.removable_media() {
+            files.insert(REMOVABLE_MEDIA_BOOTLOADER_PATH, bootloader_path);
+        }
+        if let Some((vendor, vendor_bootloader_path, boot_csv_path)) = &vendor_paths {
+            boot_csv_file
+                .write_all(&boot_csv(vendor))
+                .context("failed to write boot entry list")?;
+            files.insert(vendor_bootloader_path.as_str(), bootloader_path);
+            files.insert(boot_csv_path.as_str(), boot_csv_file.path());
+        }
+        files.insert(crate::KERNEL_FILE_NAME, kernel.path());
         if let Some(ramdisk_path) = &self.ramdisk {
             files.insert(crate::RAMDISK_FILE_NAME, ramdisk_path);
         }
+        let recovery_metadata_name = metadata::file_name(crate::RECOVERY_KERNEL_FILE_NAME);
+        if let (Some(recovery_kernel), Some(recovery_boot_metadata)) =
+            (&recovery_kernel, &recovery_boot_metadata)
+        {
+            files.insert(crate::RECOVERY_KERNEL_FILE_NAME, recovery_kernel.path());
+            files.insert(&recovery_metadata_name, recovery_boot_metadata.path());
+        }
+        if let Some(support_info_path) = &self.support_info {
+            files.insert(crate::SUPPORT_INFO_FILE_NAME, support_info_path);
+        }
+        if let Some(boot_script_path) = &self.boot_script {
+            files.insert(crate::BOOT_SCRIPT_FILE_NAME, boot_script_path);
+        }
+        files.insert(crate::BOOT_METADATA_FILE_NAME, boot_metadata.path());
+        let serial_load_marker = NamedTempFile::new().context("failed to create temp file")?;
+        if self.serial_kernel_load {
+            files.insert(crate::SERIAL_LOAD_FILE_NAME, serial_load_marker.path());
+        }
+        let mut boot_log = NamedTempFile::new().context("failed to create temp file")?;
+        if self.boot_log {
+            // the file is created with its final size, so that the bootloader only overwrites
+            // records and never changes the FAT
+            let mut empty_record = [b' '; BOOT_LOG_RECORD_LEN];
+            empty_record[BOOT_LOG_RECORD_LEN - 1] = b'\n';
+            for _ in 0..BOOT_LOG_RECORDS {
+                boot_log
+                    .write_all(&empty_record)
+                    .context("failed to write boot log")?;
+            }
+            files.insert(crate::BOOT_LOG_FILE_NAME, boot_log.path());
+        }
+        let boot_menu = self.boot_menu_files()?;
+        if let Some(boot_menu) = &boot_menu {
+            boot_menu.insert(&mut files);
+        }
+        let mut p9_server = NamedTempFile::new().context("failed to create temp file")?;
+        if let Some((address, export)) = &self.p9_server {
+            writeln!(p9_server, "{address} {export}").context("failed to write 9P server")?;
+            files.insert(crate::P9_SERVER_FILE_NAME, p9_server.path());
+        }
+        extra_files::insert(&mut files, &self.extra_files)?;
 
         let out_file = NamedTempFile::new().context("failed to create temp file")?;
         fat::create_fat_filesystem(files, out_file.path())
@@ -82,4 +697,136 @@ impl UefiBoot {
 
         Ok(out_file)
     }
+
+    /// Returns the EFI system partition GUID, which boot entry scripts can only reference if
+    /// it doesn't change with every image.
+    fn fixed_esp_partition_guid(&self) -> anyhow::Result<Uuid> {
+        self.esp_partition_guid
+            .context("boot entry scripts require a fixed EFI system partition GUID")
+    }
+
+    /// Creates a copy of the bootloader executable with the trusted keys embedded, if any.
+    fn bootloader_with_trusted_keys(&self) -> anyhow::Result<Option<NamedTempFile>> {
+        bootloader_with_trusted_keys(&self.artifacts, &self.trusted_keys)
+    }
+
+    /// Returns the path of the bootloader executable created by
+    /// [`Self::bootloader_with_trusted_keys`].
+    fn bootloader_path<'a>(&'a self, bootloader: &'a Option<NamedTempFile>) -> &'a Path {
+        bootloader
+            .as_ref()
+            .map_or(self.artifacts.uefi_bootloader_path(), |file| file.path())
+    }
+
+    /// Creates the files of the boot menu, if there are additional kernels or a USB entry.
+    fn boot_menu_files(&self) -> anyhow::Result<Option<boot_menu::MenuFiles>> {
+        if self.menu_entries.is_empty() && self.usb_menu_entry.is_none() {
+            return Ok(None);
+        }
+        let label = match &self.menu_label {
+            Some(label) => label.clone(),
+            None => self
+                .kernel
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        boot_menu::create_menu_files(
+            &label,
+            self.ramdisk.is_some(),
+            &self.menu_entries,
+            self.usb_menu_entry.as_deref(),
+            self.menu_timeout_secs,
+            self.menu_default,
+            self.compress_kernel,
+            |kernel_path| self.boot_metadata_file(kernel_path),
+        )
+        .map(Some)
+    }
+
+    /// Creates the boot metadata block of the given kernel, with the command line and the
+    /// extra mappings of the image.
+    fn boot_metadata_file(&self, kernel_path: &Path) -> anyhow::Result<NamedTempFile> {
+        let boot_metadata = NamedTempFile::new().context("failed to create temp file")?;
+        metadata::create_boot_metadata_file(
+            kernel_path,
+            &self.extra_mappings,
+            &self.command_line,
+            self.log_levels,
+            boot_metadata.path(),
+        )
+        .with_context(|| {
+            format!(
+                "failed to create boot metadata of `{}`",
+                kernel_path.display()
+            )
+        })?;
+        Ok(boot_metadata)
+    }
+
+    /// Returns the recovery kernel file, compressed like the kernel.
+    fn recovery_kernel_file(&self) -> anyhow::Result<Option<KernelFile>> {
+        self.recovery_kernel
+            .as_deref()
+            .map(|path| KernelFile::new(path, self.compress_kernel))
+            .transpose()
+    }
+}
+
+/// Checks that the vendor directory name is a plain directory name other than `BOOT`.
+fn check_vendor_name(vendor: &str) -> anyhow::Result<()> {
+    if vendor.is_empty()
+        || vendor.eq_ignore_ascii_case("boot")
+        || !vendor
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid vendor directory name `{vendor}`");
+    }
+    Ok(())
+}
+
+/// Returns the `BOOTX64.CSV` file of the vendor directory, which lists the boot entries for
+/// shim's `fallback.efi`.
+///
+/// Every line consists of the executable in the same directory, the label of the boot entry,
+/// the load options, and a description. The file is encoded in UCS-2 with a byte order mark.
+fn boot_csv(vendor: &str) -> Vec<u8> {
+    let line = format!("\u{feff}{VENDOR_BOOTLOADER_FILE_NAME},{vendor},,{vendor} bootloader\n");
+    line.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Writes the trusted keys section, which consists of a role byte and a 32-byte public
+/// key per key. Keep in sync with `uefi/src/signature.rs`.
+fn trusted_keys_file(trusted_keys: &[(PathBuf, KeyRole)]) -> anyhow::Result<Option<NamedTempFile>> {
+    if trusted_keys.is_empty() {
+        return Ok(None);
+    }
+    let mut file = NamedTempFile::new().context("failed to create temp file")?;
+    for (path, role) in trusted_keys {
+        let key = signing::read_public_key(path)
+            .with_context(|| format!("failed to read trusted key `{}`", path.display()))?;
+        file.write_all(&[role.to_u8()])
+            .and_then(|()| file.write_all(&key))
+            .context("failed to write trusted keys")?;
+    }
+    Ok(Some(file))
+}
+
+/// Creates a copy of the bootloader executable with the trusted keys embedded, if any.
+pub(crate) fn bootloader_with_trusted_keys(
+    artifacts: &DiskImageBuilder,
+    trusted_keys: &[(PathBuf, KeyRole)],
+) -> anyhow::Result<Option<NamedTempFile>> {
+    let Some(trusted_keys) = trusted_keys_file(trusted_keys)? else {
+        return Ok(None);
+    };
+    let bootloader = NamedTempFile::new().context("failed to create temp file")?;
+    stub::create_stub_efi(
+        artifacts.uefi_bootloader_path(),
+        &[(stub::TRUSTED_KEYS_SECTION, trusted_keys.path())],
+        bootloader.path(),
+    )
+    .context("failed to embed trusted keys into UEFI bootloader")?;
+    Ok(Some(bootloader))
 }
@@ -1,17 +1,182 @@
-use std::path::Path;
+use std::{fmt::Write, net::Ipv4Addr, path::Path};
 
 use anyhow::Context;
 
+/// The file that the DHCP server announces as boot file to UEFI clients.
+pub const BOOTLOADER_FILE_NAME: &str = "bootloader";
+
+/// The folder of a netboot bundle that becomes the root directory of the TFTP server.
+const TFTP_FOLDER_NAME: &str = "tftp";
+/// The TFTP root directory that the generated dnsmasq snippet serves.
+const DNSMASQ_TFTP_ROOT: &str = "/srv/tftp";
+
+/// The folder in the TFTP root with the files for BIOS clients.
+const BIOS_FOLDER_NAME: &str = "bios";
+const BIOS_IMAGE_FILE_NAME: &str = "boot.img";
+const BIOS_IPXE_SCRIPT_FILE_NAME: &str = "boot.ipxe";
+/// The iPXE build for BIOS clients, which is not part of the bundle.
+const BIOS_IPXE_FILE_NAME: &str = "undionly.kpxe";
+
+/// iPXE script that boots the BIOS disk image through the `memdisk` loader of SYSLINUX.
+///
+/// Relative paths are resolved against the location of the script.
+const BIOS_IPXE_SCRIPT: &str = "#!ipxe
+kernel memdisk raw
+initrd boot.img
+boot
+";
+
+/// Creates a folder with the files and DHCP configuration for booting BIOS and UEFI clients
+/// over the network.
+///
+/// The `tftp` subfolder contains the UEFI TFTP folder (see [`create_uefi_tftp_folder`]) and,
+/// if given, the BIOS disk image. The DHCP snippets select the boot file by the client system
+/// architecture (DHCP option 93). The BIOS stages can't load files over the network, so BIOS
+/// clients chainload iPXE, which boots the disk image through `memdisk`.
+pub fn create_netboot_bundle(
+    uefi_tftp_folder: impl FnOnce(&Path) -> anyhow::Result<()>,
+    bios_disk_image: Option<&Path>,
+    server_address: Ipv4Addr,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let tftp_root = out_path.join(TFTP_FOLDER_NAME);
+    uefi_tftp_folder(&tftp_root)?;
+
+    if let Some(bios_disk_image) = bios_disk_image {
+        let bios_folder = tftp_root.join(BIOS_FOLDER_NAME);
+        std::fs::create_dir_all(&bios_folder)
+            .with_context(|| format!("failed to create {}", bios_folder.display()))?;
+        let to = bios_folder.join(BIOS_IMAGE_FILE_NAME);
+        std::fs::copy(bios_disk_image, &to).with_context(|| {
+            format!(
+                "failed to copy BIOS disk image from {} to {}",
+                bios_disk_image.display(),
+                to.display()
+            )
+        })?;
+        let to = bios_folder.join(BIOS_IPXE_SCRIPT_FILE_NAME);
+        std::fs::write(&to, BIOS_IPXE_SCRIPT)
+            .with_context(|| format!("failed to write {}", to.display()))?;
+    }
+
+    let bios = bios_disk_image.is_some();
+    let to = out_path.join("dnsmasq.conf");
+    std::fs::write(&to, dnsmasq_snippet(server_address, bios))
+        .with_context(|| format!("failed to write {}", to.display()))?;
+    let to = out_path.join("dhcpd.conf");
+    std::fs::write(&to, isc_dhcpd_snippet(server_address, bios))
+        .with_context(|| format!("failed to write {}", to.display()))?;
+
+    Ok(())
+}
+
+/// Returns a dnsmasq configuration snippet that serves the `tftp` folder of the bundle.
+///
+/// The snippet doesn't contain a `dhcp-range`, so it can be added to an existing setup.
+fn dnsmasq_snippet(server_address: Ipv4Addr, bios: bool) -> String {
+    let mut snippet = String::new();
+    let s = &mut snippet;
+    writeln!(
+        s,
+        "# Copy the `{TFTP_FOLDER_NAME}` folder to {DNSMASQ_TFTP_ROOT}."
+    )
+    .unwrap();
+    writeln!(s, "enable-tftp").unwrap();
+    writeln!(s, "tftp-root={DNSMASQ_TFTP_ROOT}").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "# UEFI x86_64 clients").unwrap();
+    writeln!(s, "dhcp-match=set:efi-x86_64,option:client-arch,7").unwrap();
+    writeln!(s, "dhcp-match=set:efi-x86_64,option:client-arch,9").unwrap();
+    writeln!(s, "dhcp-boot=tag:efi-x86_64,bootloader,,{server_address}").unwrap();
+    if bios {
+        writeln!(s).unwrap();
+        writeln!(
+            s,
+            "# BIOS clients chainload iPXE, which then runs the boot script. Copy \
+             `{BIOS_IPXE_FILE_NAME}` from iPXE and `memdisk` from SYSLINUX into the \
+             `{BIOS_FOLDER_NAME}` folder."
+        )
+        .unwrap();
+        writeln!(s, "dhcp-match=set:bios,option:client-arch,0").unwrap();
+        writeln!(s, "dhcp-userclass=set:ipxe,iPXE").unwrap();
+        writeln!(
+            s,
+            "dhcp-boot=tag:bios,tag:!ipxe,{BIOS_FOLDER_NAME}/{BIOS_IPXE_FILE_NAME},,{server_address}"
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "dhcp-boot=tag:bios,tag:ipxe,{BIOS_FOLDER_NAME}/{BIOS_IPXE_SCRIPT_FILE_NAME},,{server_address}"
+        )
+        .unwrap();
+    }
+    snippet
+}
+
+/// Returns a configuration snippet for the ISC DHCP server, to be included in a `subnet`
+/// declaration.
+fn isc_dhcpd_snippet(server_address: Ipv4Addr, bios: bool) -> String {
+    let mut snippet = String::new();
+    let s = &mut snippet;
+    writeln!(
+        s,
+        "# Serve the `{TFTP_FOLDER_NAME}` folder with a TFTP server on {server_address}."
+    )
+    .unwrap();
+    writeln!(s, "option client-arch code 93 = unsigned integer 16;").unwrap();
+    writeln!(s, "next-server {server_address};").unwrap();
+    writeln!(s).unwrap();
+    writeln!(
+        s,
+        "if option client-arch = 00:07 or option client-arch = 00:09 {{"
+    )
+    .unwrap();
+    writeln!(s, "    filename \"bootloader\";").unwrap();
+    if bios {
+        writeln!(
+            s,
+            "}} elsif exists user-class and option user-class = \"iPXE\" {{"
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "    filename \"{BIOS_FOLDER_NAME}/{BIOS_IPXE_SCRIPT_FILE_NAME}\";"
+        )
+        .unwrap();
+        writeln!(s, "}} else {{").unwrap();
+        writeln!(
+            s,
+            "    # copy `{BIOS_IPXE_FILE_NAME}` from iPXE and `memdisk` from SYSLINUX into the \
+             `{BIOS_FOLDER_NAME}` folder"
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "    filename \"{BIOS_FOLDER_NAME}/{BIOS_IPXE_FILE_NAME}\";"
+        )
+        .unwrap();
+    }
+    writeln!(s, "}}").unwrap();
+    snippet
+}
+
 pub fn create_uefi_tftp_folder(
     bootloader_path: &Path,
     kernel_binary: &Path,
     ramdisk_path: Option<&Path>,
+    recovery_kernel: Option<&Path>,
+    support_info: Option<&Path>,
     out_path: &Path,
 ) -> anyhow::Result<()> {
+    crate::metadata::check_dma_address_limit(
+        kernel_binary,
+        ramdisk_path,
+        crate::metadata::RamdiskPlacement::Uefi,
+    )?;
     std::fs::create_dir_all(out_path)
         .with_context(|| format!("failed to create out dir at {}", out_path.display()))?;
 
-    let to = out_path.join("bootloader");
+    let to = out_path.join(BOOTLOADER_FILE_NAME);
     std::fs::copy(bootloader_path, &to).with_context(|| {
         format!(
             "failed to copy bootloader from {} to {}",
@@ -39,5 +204,26 @@ pub fn create_uefi_tftp_folder(
         })?;
     }
 
+    if let Some(recovery_kernel) = recovery_kernel {
+        let to = out_path.join(crate::RECOVERY_KERNEL_FILE_NAME);
+        std::fs::copy(recovery_kernel, &to).with_context(|| {
+            format!(
+                "failed to copy recovery kernel from {} to {}",
+                recovery_kernel.display(),
+                to.display()
+            )
+        })?;
+    }
+    if let Some(support_info) = support_info {
+        let to = out_path.join(crate::SUPPORT_INFO_FILE_NAME);
+        std::fs::copy(support_info, &to).with_context(|| {
+            format!(
+                "failed to copy support info from {} to {}",
+                support_info.display(),
+                to.display()
+            )
+        })?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,145 @@
+use anyhow::{bail, Context};
+use std::{fs, path::Path};
+
+/// Section names for the files that can be embedded into the bootloader executable.
+///
+/// Keep in sync with `uefi/src/stub.rs`.
+pub const KERNEL_SECTION: &[u8; 8] = b".kernel\0";
+pub const RAMDISK_SECTION: &[u8; 8] = b".ramdisk";
+pub const BOOT_METADATA_SECTION: &[u8; 8] = b".bootmd\0";
+pub const RECOVERY_KERNEL_SECTION: &[u8; 8] = b".recover";
+pub const RECOVERY_BOOT_METADATA_SECTION: &[u8; 8] = b".recmd\0\0";
+pub const SUPPORT_INFO_SECTION: &[u8; 8] = b".support";
+pub const TRUSTED_KEYS_SECTION: &[u8; 8] = b".keys\0\0\0";
+
+const SECTION_HEADER_SIZE: usize = 40;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+/// The kernel is mapped directly from its load address, so embedded files must start at
+/// page boundaries.
+const PAGE_SIZE: u32 = 4096;
+
+/// Creates a copy of the UEFI bootloader executable with the given files appended as PE
+/// sections.
+///
+/// The bootloader looks for these sections on startup and prefers them over files on the
+/// boot partition, so the resulting executable can be launched directly by the firmware.
+pub fn create_stub_efi(
+    bootloader_path: &Path,
+    sections: &[(&[u8; 8], &Path)],
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let mut image = fs::read(bootloader_path).with_context(|| {
+        format!(
+            "failed to read UEFI bootloader at `{}`",
+            bootloader_path.display()
+        )
+    })?;
+    for (name, path) in sections {
+        let data =
+            fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+        append_section(&mut image, name, &data).with_context(|| {
+            format!("failed to embed `{}` into UEFI bootloader", path.display())
+        })?;
+    }
+    fs::write(out_path, image).with_context(|| format!("failed to write `{}`", out_path.display()))
+}
+
+fn append_section(image: &mut Vec<u8>, name: &[u8; 8], data: &[u8]) -> anyhow::Result<()> {
+    let read_u16 = |image: &[u8], offset: usize| -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(
+            image
+                .get(offset..offset + 2)
+                .context("unexpected end of PE file")?
+                .try_into()?,
+        ))
+    };
+    let read_u32 = |image: &[u8], offset: usize| -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(
+            image
+                .get(offset..offset + 4)
+                .context("unexpected end of PE file")?
+                .try_into()?,
+        ))
+    };
+
+    let pe_offset = usize::try_from(read_u32(image, 0x3c)?)?;
+    if image.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        bail!("UEFI bootloader is not a PE executable");
+    }
+    let coff_header = pe_offset + 4;
+    let number_of_sections = usize::from(read_u16(image, coff_header + 2)?);
+    let optional_header_size = usize::from(read_u16(image, coff_header + 16)?);
+    let optional_header = coff_header + 20;
+    if read_u16(image, optional_header)? != PE32_PLUS_MAGIC {
+        bail!("UEFI bootloader is not a PE32+ executable");
+    }
+    let section_alignment = read_u32(image, optional_header + 32)?;
+    let file_alignment = read_u32(image, optional_header + 36)?;
+    let size_of_headers = usize::try_from(read_u32(image, optional_header + 60)?)?;
+    if section_alignment % PAGE_SIZE != 0 {
+        bail!("section alignment {section_alignment:#x} is not a multiple of the page size");
+    }
+
+    let section_table = optional_header + optional_header_size;
+    let mut image_end = 0;
+    let mut first_raw_data = size_of_headers;
+    for index in 0..number_of_sections {
+        let entry = section_table + index * SECTION_HEADER_SIZE;
+        if image.get(entry..entry + 8) == Some(&name[..]) {
+            bail!(
+                "bootloader already contains a `{}` section",
+                String::from_utf8_lossy(name).trim_end_matches('\0')
+            );
+        }
+        let virtual_size = read_u32(image, entry + 8)?;
+        let virtual_address = read_u32(image, entry + 12)?;
+        let raw_data_size = read_u32(image, entry + 16)?;
+        let raw_data = usize::try_from(read_u32(image, entry + 20)?)?;
+        image_end = image_end.max(virtual_address + virtual_size.max(raw_data_size));
+        if raw_data_size > 0 {
+            first_raw_data = first_raw_data.min(raw_data);
+        }
+    }
+    let new_entry = section_table + number_of_sections * SECTION_HEADER_SIZE;
+    if new_entry + SECTION_HEADER_SIZE > size_of_headers.min(first_raw_data) {
+        bail!("no space for an additional section header");
+    }
+
+    let data_len = u32::try_from(data.len()).context("embedded file is larger than 4GiB")?;
+    let virtual_address = align_up(image_end, section_alignment);
+    let raw_data_size = align_up(data_len, file_alignment);
+    let raw_data = align_up(u32::try_from(image.len())?, file_alignment);
+    let size_of_image = align_up(virtual_address + data_len, section_alignment);
+
+    let mut header = [0; SECTION_HEADER_SIZE];
+    header[0..8].copy_from_slice(name);
+    header[8..12].copy_from_slice(&data_len.to_le_bytes());
+    header[12..16].copy_from_slice(&virtual_address.to_le_bytes());
+    header[16..20].copy_from_slice(&raw_data_size.to_le_bytes());
+    header[20..24].copy_from_slice(&raw_data.to_le_bytes());
+    header[36..40].copy_from_slice(
+        &(IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE).to_le_bytes(),
+    );
+    image[new_entry..new_entry + SECTION_HEADER_SIZE].copy_from_slice(&header);
+
+    let number_of_sections = u16::try_from(number_of_sections + 1)?;
+    image[coff_header + 2..coff_header + 4].copy_from_slice(&number_of_sections.to_le_bytes());
+    image[optional_header + 56..optional_header + 60].copy_from_slice(&size_of_image.to_le_bytes());
+    // the checksum is not verified by UEFI firmware, so we simply clear it
+    image[optional_header + 64..optional_header + 68].fill(0);
+
+    image.resize(usize::try_from(raw_data)?, 0);
+    image.extend_from_slice(data);
+    image.resize(usize::try_from(raw_data + raw_data_size)?, 0);
+
+    Ok(())
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) / align * align
+}
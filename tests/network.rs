@@ -0,0 +1,33 @@
+use std::path::{Path, PathBuf};
+
+use bootloader_test_runner::run_test_kernel_with_ramdisk;
+
+static KERNEL_PATH: &str = env!("CARGO_BIN_FILE_TEST_KERNEL_NETWORK_large_ramdisk");
+
+/// Keep in sync with `LARGE_RAMDISK_LEN` of the `network` test kernel.
+const LARGE_RAMDISK_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes the ramdisk that the `large_ramdisk` test kernel checks.
+///
+/// The pattern must match `large_ramdisk_byte` of the `network` test kernel.
+fn create_large_ramdisk(name: &str) -> PathBuf {
+    let path = Path::new(KERNEL_PATH).with_extension(name);
+    let contents: Vec<u8> = (0..LARGE_RAMDISK_LEN).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Boots from disk and over UEFI PXE, where the iPXE ROM of QEMU's virtio-net device serves
+/// the ramdisk over TFTP in a long transfer.
+#[test]
+fn large_ramdisk_over_pxe() {
+    let ramdisk = create_large_ramdisk("pxe-ramdisk");
+    run_test_kernel_with_ramdisk(KERNEL_PATH, Some(&ramdisk));
+}
+
+#[cfg(feature = "uefi")]
+#[test]
+fn large_ramdisk_over_9p() {
+    let ramdisk = create_large_ramdisk("9p-ramdisk");
+    bootloader_test_runner::run_test_kernel_over_9p(KERNEL_PATH, Some(&ramdisk));
+}
@@ -0,0 +1,313 @@
+//! Replays boots that were recorded with `BootloaderConfig::record_inputs`.
+//!
+//! A recorded trace contains the firmware inputs that the bootloader consumed, as `input`
+//! events in its JSON lines log. [`replay`] feeds them into the firmware-agnostic code of the
+//! bootloader, so that failures that only occur with the memory map or the random kernel
+//! address of a specific machine become deterministic unit tests:
+//!
+//! ```ignore
+//! #[test]
+//! fn rack_3() {
+//!     let trace = Trace::parse(include_str!("rack-3.jsonl")).unwrap();
+//!     let replay = bootloader_replay::replay(&trace, include_bytes!("kernel")).unwrap();
+//!     assert_eq!(replay.kernel_offset, Some(0xffff_8000_0000_0000));
+//! }
+//! ```
+//!
+//! `tests/replay.rs` replays a recorded QEMU boot this way.
+//!
+//! The replay covers the parsing of the kernel config, the memory map handling of the frame
+//! allocator, and the placement of the kernel in the virtual address space. The code that
+//! accesses the hardware or physical memory, e.g. the page table setup and the copying of the
+//! kernel segments, can't run on the host. Boot errors of the replayed code panic.
+
+use bootloader_api::{
+    info::{FrameBufferInfo, MemoryRegionKind, PixelFormat},
+    BootloaderConfig,
+};
+use bootloader_x86_64_common::{
+    legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+    level_4_entries::UsedLevel4Entries,
+    load_kernel,
+    record::Key,
+    Kernel, RawFrameBufferInfo,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+use xmas_elf::ElfFile;
+
+/// The firmware inputs of a recorded boot.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub memory_map: Vec<Region>,
+    /// The first frame that the frame allocator may allocate.
+    pub first_frame: u64,
+    pub framebuffer: Option<FrameBuffer>,
+    pub files: Vec<File>,
+    pub extra_mappings: Vec<ExtraMapping>,
+    pub keys: Vec<Key>,
+    /// The seeds of the random number generators, in the order in which they were created.
+    pub rng_seeds: Vec<[u8; 32]>,
+}
+
+/// A region of the memory map of the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub len: u64,
+    pub kind: MemoryRegionKind,
+    pub usable_after_exit: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBuffer {
+    pub addr: u64,
+    pub byte_len: u64,
+}
+
+/// A file that the bootloader loaded, e.g. `kernel` or `ramdisk`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    pub name: String,
+    pub bytes: u64,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtraMapping {
+    pub virt_start: u64,
+    pub len: u64,
+}
+
+impl Trace {
+    /// Parses the `input` events of a JSON lines log.
+    ///
+    /// Other lines, e.g. log messages or output of the firmware, are skipped.
+    pub fn parse(log: &str) -> Result<Self, String> {
+        let mut trace = Trace::default();
+        let mut recorded = false;
+        for line in log.lines() {
+            let Ok(event) = serde_json::from_str::<Value>(line.trim()) else {
+                continue;
+            };
+            if event["type"] != "event" || event["event"] != "input" {
+                continue;
+            }
+            recorded = true;
+            trace
+                .add(&event)
+                .map_err(|err| format!("invalid input event `{}`: {err}", line.trim()))?;
+        }
+        if !recorded {
+            return Err("the log contains no input events, was `record_inputs` enabled?".into());
+        }
+        Ok(trace)
+    }
+
+    fn add(&mut self, event: &Value) -> Result<(), String> {
+        match event["input"].as_str().unwrap_or_default() {
+            "memory-region" => self.memory_map.push(Region {
+                start: u64_field(event, "start")?,
+                len: u64_field(event, "len")?,
+                kind: parse_kind(str_field(event, "kind")?)?,
+                usable_after_exit: event["usable_after_exit"]
+                    .as_bool()
+                    .ok_or("missing field `usable_after_exit`")?,
+            }),
+            "first-frame" => self.first_frame = u64_field(event, "addr")?,
+            "framebuffer" => {
+                self.framebuffer = Some(FrameBuffer {
+                    addr: u64_field(event, "addr")?,
+                    byte_len: u64_field(event, "byte_len")?,
+                })
+            }
+            "file" => self.files.push(File {
+                name: str_field(event, "name")?.to_owned(),
+                bytes: u64_field(event, "bytes")?,
+                sha256: hex_field(event, "sha256")?,
+            }),
+            "extra-mapping" => self.extra_mappings.push(ExtraMapping {
+                virt_start: u64_field(event, "virt_start")?,
+                len: u64_field(event, "len")?,
+            }),
+            "key" => self.keys.push(match event["key"].as_str() {
+                Some(key) => Key::Char(key.chars().next().ok_or("empty key")?),
+                None => Key::ScanCode(
+                    u16::try_from(u64_field(event, "scan_code")?)
+                        .map_err(|_| "invalid scan code")?,
+                ),
+            }),
+            "rng-seed" => self.rng_seeds.push(hex_field(event, "seed")?),
+            other => return Err(format!("unknown input `{other}`")),
+        }
+        Ok(())
+    }
+}
+
+/// The results of the replayed code.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    /// The config of the kernel.
+    pub config: BootloaderConfig,
+    /// The end of the highest region of the memory map.
+    pub max_phys_addr: u64,
+    /// The number of bytes of usable memory in the memory map.
+    pub usable_bytes: u64,
+    /// The offset of the kernel in the virtual address space, `None` for kernels that aren't
+    /// ELF files.
+    pub kernel_offset: Option<i128>,
+    /// The keys that the user pressed, e.g. in the boot menu.
+    pub keys: Vec<Key>,
+}
+
+/// Re-runs the firmware-agnostic parts of the recorded boot with the given kernel.
+///
+/// Fails if the kernel differs from the recorded one.
+pub fn replay(trace: &Trace, kernel: &[u8]) -> Result<Replay, String> {
+    let recorded = trace
+        .files
+        .iter()
+        .find(|file| file.name == "kernel")
+        .ok_or("the trace contains no kernel file")?;
+    if recorded.bytes != kernel.len() as u64
+        || recorded.sha256 != <[u8; 32]>::from(Sha256::digest(kernel))
+    {
+        return Err("the kernel differs from the recorded one".into());
+    }
+    // xmas-elf reads the headers in place, which requires an aligned buffer
+    let mut aligned = vec![0u64; (kernel.len() + 7) / 8];
+    let aligned = &mut as_bytes_mut(&mut aligned)[..kernel.len()];
+    aligned.copy_from_slice(kernel);
+    let kernel = Kernel::parse(aligned);
+    let config = kernel.config;
+
+    let mut frame_allocator = LegacyFrameAllocator::new_starting_at(
+        PhysFrame::containing_address(PhysAddr::new(trace.first_frame)),
+        trace.memory_map.iter().copied(),
+    );
+    frame_allocator.prefer_frames_above(PhysAddr::new(config.min_frame_address));
+    let framebuffer = trace.framebuffer.map(|framebuffer| RawFrameBufferInfo {
+        addr: PhysAddr::new(framebuffer.addr),
+        // the other fields don't influence the replayed code
+        info: FrameBufferInfo {
+            byte_len: framebuffer.byte_len as usize,
+            width: 0,
+            height: 0,
+            pixel_format: PixelFormat::U8,
+            bytes_per_pixel: 0,
+            stride: 0,
+        },
+    });
+    let aslr = config.mappings.aslr || config.kernel_aslr;
+    let seed = trace.rng_seeds.first().copied();
+    if aslr && seed.is_none() {
+        return Err("ASLR is enabled, but the trace contains no RNG seed".into());
+    }
+
+    let mut used_entries = UsedLevel4Entries::new(
+        frame_allocator.max_phys_addr(),
+        frame_allocator.len(),
+        framebuffer.as_ref(),
+        &config,
+        seed,
+    );
+    for mapping in &trace.extra_mappings {
+        used_entries.mark_range_as_used(mapping.virt_start, mapping.len);
+    }
+    let kernel_offset = match ElfFile::new(kernel.bytes()) {
+        Ok(elf_file) => Some(
+            load_kernel::virtual_address_offset(&elf_file, &mut used_entries)
+                .virtual_address_offset(),
+        ),
+        Err(_) => None,
+    };
+
+    Ok(Replay {
+        config,
+        max_phys_addr: frame_allocator.max_phys_addr().as_u64(),
+        usable_bytes: trace
+            .memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| region.len)
+            .sum(),
+        kernel_offset,
+        keys: trace.keys.clone(),
+    })
+}
+
+impl LegacyMemoryRegion for Region {
+    fn start(&self) -> PhysAddr {
+        PhysAddr::new(self.start)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn kind(&self) -> MemoryRegionKind {
+        self.kind
+    }
+
+    fn usable_after_bootloader_exit(&self) -> bool {
+        self.usable_after_exit
+    }
+}
+
+fn as_bytes_mut(words: &mut [u64]) -> &mut [u8] {
+    let len = words.len() * 8;
+    unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), len) }
+}
+
+fn u64_field(event: &Value, name: &str) -> Result<u64, String> {
+    event[name]
+        .as_u64()
+        .ok_or_else(|| format!("missing field `{name}`"))
+}
+
+fn str_field<'a>(event: &'a Value, name: &str) -> Result<&'a str, String> {
+    event[name]
+        .as_str()
+        .ok_or_else(|| format!("missing field `{name}`"))
+}
+
+fn hex_field(event: &Value, name: &str) -> Result<[u8; 32], String> {
+    let hex = str_field(event, name)?;
+    let mut bytes = [0; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("invalid field `{name}`"));
+    }
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| format!("invalid field `{name}`"))?;
+    }
+    Ok(bytes)
+}
+
+/// Parses the `Debug` representation of a memory region kind, e.g. `UnknownUefi(7)`.
+fn parse_kind(kind: &str) -> Result<MemoryRegionKind, String> {
+    let number = |prefix: &str| {
+        kind.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|number| number.parse().ok())
+    };
+    Ok(match kind {
+        "Usable" => MemoryRegionKind::Usable,
+        "Bootloader" => MemoryRegionKind::Bootloader,
+        "BootloaderReclaimable" => MemoryRegionKind::BootloaderReclaimable,
+        "KernelImage" => MemoryRegionKind::KernelImage,
+        "BootInfo" => MemoryRegionKind::BootInfo,
+        "Ramdisk" => MemoryRegionKind::Ramdisk,
+        "BootloaderHeap" => MemoryRegionKind::BootloaderHeap,
+        _ => {
+            if let Some(number) = number("UnknownUefi(") {
+                MemoryRegionKind::UnknownUefi(number)
+            } else if let Some(number) = number("UnknownBios(") {
+                MemoryRegionKind::UnknownBios(number)
+            } else {
+                return Err(format!("unknown memory region kind `{kind}`"));
+            }
+        }
+    })
+}
@@ -0,0 +1,89 @@
+use bootloader_api::info::MemoryRegionKind;
+use bootloader_replay::{replay, Region, Trace};
+use bootloader_x86_64_common::record::Key;
+
+/// A boot of the `fixtures/kernel` executable in QEMU with OVMF and 256 MiB of memory.
+///
+/// The kernel is a minimal position-independent executable with `record_inputs`,
+/// `kernel_aslr`, and `mappings.aslr` enabled.
+const LOG: &str = include_str!("fixtures/qemu-uefi.jsonl");
+const KERNEL: &[u8] = include_bytes!("fixtures/kernel");
+
+#[test]
+fn parse_recorded_log() {
+    let trace = Trace::parse(LOG).unwrap();
+    assert_eq!(trace.memory_map.len(), 12);
+    assert_eq!(
+        trace.memory_map[2],
+        Region {
+            start: 0x80_0000,
+            len: 0x8000,
+            kind: MemoryRegionKind::UnknownUefi(10),
+            usable_after_exit: false,
+        }
+    );
+    assert_eq!(trace.first_frame, 0x1000);
+    assert_eq!(trace.framebuffer.unwrap().addr, 0x8000_0000);
+    assert_eq!(trace.files.len(), 1);
+    assert_eq!(trace.files[0].bytes, KERNEL.len() as u64);
+    assert_eq!(trace.keys, [Key::ScanCode(2), Key::Char('\r')]);
+    assert_eq!(trace.rng_seeds.len(), 1);
+}
+
+#[test]
+fn replay_recorded_boot() {
+    let trace = Trace::parse(LOG).unwrap();
+    let replayed = replay(&trace, KERNEL).unwrap();
+
+    assert!(replayed.config.record_inputs);
+    assert!(replayed.config.kernel_aslr);
+    assert_eq!(replayed.max_phys_addr, 0x1_0000_0000);
+    assert_eq!(replayed.usable_bytes, 0xcfa_8000);
+    assert_eq!(replayed.keys, trace.keys);
+    // the recorded seed determines the kernel address
+    assert_eq!(replayed.kernel_offset, Some(0xffff_b08f_c13a_c000));
+    assert_eq!(
+        replay(&trace, KERNEL).unwrap().kernel_offset,
+        replayed.kernel_offset
+    );
+
+    let mut other_seed = trace;
+    other_seed.rng_seeds[0][0] ^= 1;
+    let other = replay(&other_seed, KERNEL).unwrap();
+    assert_ne!(other.kernel_offset, replayed.kernel_offset);
+}
+
+#[test]
+fn replay_requires_the_recorded_kernel() {
+    let trace = Trace::parse(LOG).unwrap();
+    let mut kernel = KERNEL.to_vec();
+    *kernel.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        replay(&trace, &kernel).unwrap_err(),
+        "the kernel differs from the recorded one"
+    );
+}
+
+#[test]
+fn replay_requires_the_rng_seed_for_aslr() {
+    let mut trace = Trace::parse(LOG).unwrap();
+    trace.rng_seeds.clear();
+    assert_eq!(
+        replay(&trace, KERNEL).unwrap_err(),
+        "ASLR is enabled, but the trace contains no RNG seed"
+    );
+}
+
+#[test]
+fn log_without_inputs() {
+    let log: String = LOG
+        .lines()
+        .filter(|line| !line.contains("\"input\""))
+        .collect();
+    assert!(Trace::parse(&log).is_err());
+
+    let invalid = LOG.replace("\"kind\":\"Usable\"", "\"kind\":\"Free\"");
+    assert!(Trace::parse(&invalid)
+        .unwrap_err()
+        .contains("unknown memory region kind `Free`"));
+}
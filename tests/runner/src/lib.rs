@@ -1,4 +1,15 @@
-use std::{io::Write, path::Path, process::Command};
+use std::{
+    io::{Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+pub use screenshot::{Screenshot, Tolerance};
+
+#[cfg(feature = "uefi")]
+mod p9_server;
+mod screenshot;
 
 const QEMU_ARGS: &[&str] = &[
     "-device",
@@ -10,11 +21,48 @@ const QEMU_ARGS: &[&str] = &[
     "--no-reboot",
 ];
 
+/// Golden images and the screenshots to compare against them, see
+/// [`run_test_kernel_with_screenshots`].
+#[derive(Clone, Copy)]
+struct Screenshots<'a> {
+    golden_dir: &'a Path,
+    screenshots: &'a [Screenshot<'a>],
+}
+
 pub fn run_test_kernel(kernel_binary_path: &str) {
     run_test_kernel_with_ramdisk(kernel_binary_path, None)
 }
 
 pub fn run_test_kernel_with_ramdisk(kernel_binary_path: &str, ramdisk_path: Option<&Path>) {
+    run_test_kernel_internal(kernel_binary_path, ramdisk_path, None)
+}
+
+/// Runs the test kernel like [`run_test_kernel`] and compares screenshots of the given boot
+/// milestones against golden images, e.g. to test the rendering of the boot menu.
+///
+/// The screenshots are taken through the QEMU monitor when the serial output contains the
+/// milestone text. The golden image of a screenshot is `<golden_dir>/<name>-<firmware>.ppm`,
+/// where `<firmware>` is `bios`, `uefi`, or `uefi-pxe`, because the firmware affects the
+/// screen content. The screenshots are stored next to the disk images. If the
+/// `BOOTLOADER_UPDATE_SCREENSHOTS` environment variable is set, the golden images are
+/// overwritten with the screenshots instead of compared.
+pub fn run_test_kernel_with_screenshots(
+    kernel_binary_path: &str,
+    golden_dir: &Path,
+    screenshots: &[Screenshot],
+) {
+    let screenshots = Screenshots {
+        golden_dir,
+        screenshots,
+    };
+    run_test_kernel_internal(kernel_binary_path, None, Some(screenshots))
+}
+
+fn run_test_kernel_internal(
+    kernel_binary_path: &str,
+    ramdisk_path: Option<&Path>,
+    screenshots: Option<Screenshots>,
+) {
     let kernel_path = Path::new(kernel_binary_path);
 
     #[cfg(feature = "uefi")]
@@ -33,8 +81,8 @@ pub fn run_test_kernel_with_ramdisk(kernel_binary_path: &str, ramdisk_path: Opti
         let tftp_path = kernel_path.with_extension(".tftp");
         uefi_builder.create_pxe_tftp_folder(&tftp_path).unwrap();
 
-        run_test_kernel_on_uefi(&gpt_path);
-        run_test_kernel_on_uefi_pxe(&tftp_path);
+        boot_uefi(&gpt_path, screenshots);
+        boot_uefi_pxe(&tftp_path, screenshots);
     }
 
     #[cfg(feature = "bios")]
@@ -48,12 +96,59 @@ pub fn run_test_kernel_with_ramdisk(kernel_binary_path: &str, ramdisk_path: Opti
         }
         bios_builder.create_disk_image(&mbr_path).unwrap();
 
-        run_test_kernel_on_bios(&mbr_path);
+        boot_bios(&mbr_path, screenshots);
+    }
+}
+
+/// Boots the test kernel on UEFI with the kernel and ramdisk loaded from a 9P server.
+///
+/// The server runs in the test process and exports the files of the UEFI PXE folder. The
+/// disk image only provides the bootloader and the server address, so the test panics if
+/// the bootloader didn't load the kernel over 9P.
+#[cfg(feature = "uefi")]
+pub fn run_test_kernel_over_9p(kernel_binary_path: &str, ramdisk_path: Option<&Path>) {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let kernel_path = Path::new(kernel_binary_path);
+    let export_path = kernel_path.with_extension("9p");
+    let server = p9_server::P9Server::start(&export_path);
+
+    let gpt_path = kernel_path.with_extension("9p.gpt");
+    let mut uefi_builder = bootloader::UefiBoot::new(kernel_path);
+    if let Some(rdp) = ramdisk_path {
+        uefi_builder.set_ramdisk(rdp);
     }
+    // QEMU's user networking makes the host reachable at 10.0.2.2
+    let host = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), server.port());
+    uefi_builder.set_p9_server(host, "");
+    uefi_builder.create_disk_image(&gpt_path).unwrap();
+    uefi_builder.create_pxe_tftp_folder(&export_path).unwrap();
+
+    let mut run_cmd = Command::new("qemu-system-x86_64");
+    run_cmd
+        .arg("-drive")
+        .arg(format!("format=raw,file={}", gpt_path.display()));
+    run_cmd.arg("-netdev").arg("user,id=net0");
+    run_cmd.arg("-device").arg("virtio-net-pci,netdev=net0");
+    run_cmd.args(QEMU_ARGS);
+    run_cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+
+    run_qemu(run_cmd, &gpt_path, "uefi-9p", None);
+
+    let opened = server.opened_files();
+    assert!(
+        opened.iter().any(|file| file == "kernel-x86_64"),
+        "kernel wasn't loaded over 9P, opened files: {opened:?}"
+    );
 }
 
 #[cfg(feature = "uefi")]
 pub fn run_test_kernel_on_uefi(out_gpt_path: &Path) {
+    boot_uefi(out_gpt_path, None)
+}
+
+#[cfg(feature = "uefi")]
+fn boot_uefi(out_gpt_path: &Path, screenshots: Option<Screenshots>) {
     let mut run_cmd = Command::new("qemu-system-x86_64");
     run_cmd
         .arg("-drive")
@@ -61,46 +156,32 @@ pub fn run_test_kernel_on_uefi(out_gpt_path: &Path) {
     run_cmd.args(QEMU_ARGS);
     run_cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
 
-    let child_output = run_cmd.output().unwrap();
-    strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stderr)
-        .unwrap();
-    strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stdout)
-        .unwrap();
-
-    match child_output.status.code() {
-        Some(33) => {}                     // success
-        Some(35) => panic!("Test failed"), // success
-        other => panic!("Test failed with unexpected exit code `{:?}`", other),
-    }
+    run_qemu(run_cmd, out_gpt_path, "uefi", screenshots);
 }
 
 #[cfg(feature = "bios")]
 pub fn run_test_kernel_on_bios(out_mbr_path: &Path) {
+    boot_bios(out_mbr_path, None)
+}
+
+#[cfg(feature = "bios")]
+fn boot_bios(out_mbr_path: &Path, screenshots: Option<Screenshots>) {
     let mut run_cmd = Command::new("qemu-system-x86_64");
     run_cmd
         .arg("-drive")
         .arg(format!("format=raw,file={}", out_mbr_path.display()));
     run_cmd.args(QEMU_ARGS);
 
-    let child_output = run_cmd.output().unwrap();
-    strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stderr)
-        .unwrap();
-    strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stdout)
-        .unwrap();
-
-    match child_output.status.code() {
-        Some(33) => {}                     // success
-        Some(35) => panic!("Test failed"), // success
-        other => panic!("Test failed with unexpected exit code `{:?}`", other),
-    }
+    run_qemu(run_cmd, out_mbr_path, "bios", screenshots);
 }
 
 #[cfg(feature = "uefi")]
 pub fn run_test_kernel_on_uefi_pxe(out_tftp_path: &Path) {
+    boot_uefi_pxe(out_tftp_path, None)
+}
+
+#[cfg(feature = "uefi")]
+fn boot_uefi_pxe(out_tftp_path: &Path, screenshots: Option<Screenshots>) {
     let mut run_cmd = Command::new("qemu-system-x86_64");
     run_cmd.arg("-netdev").arg(format!(
         "user,id=net0,net=192.168.17.0/24,tftp={},bootfile=bootloader,id=net0",
@@ -110,17 +191,102 @@ pub fn run_test_kernel_on_uefi_pxe(out_tftp_path: &Path) {
     run_cmd.args(QEMU_ARGS);
     run_cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
 
-    let child_output = run_cmd.output().unwrap();
+    run_qemu(run_cmd, out_tftp_path, "uefi-pxe", screenshots);
+}
+
+/// Runs QEMU until the test kernel exits, taking the screenshots along the way.
+///
+/// Panics if the kernel reports a failure, or if a milestone isn't reached or a screenshot
+/// doesn't match its golden image.
+fn run_qemu(
+    mut run_cmd: Command,
+    image_path: &Path,
+    firmware: &'static str,
+    screenshots: Option<Screenshots>,
+) {
+    let captures = screenshots.map(|screenshots| screenshot::Captures {
+        screenshots: screenshots.screenshots,
+        golden_dir: screenshots.golden_dir,
+        firmware,
+        image_path,
+        monitor_port: screenshot::free_port(),
+    });
+    if let Some(captures) = &captures {
+        run_cmd.arg("-monitor").arg(format!(
+            "tcp:127.0.0.1:{},server=on,wait=off",
+            captures.monitor_port
+        ));
+    }
+
+    let mut child = run_cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).unwrap();
+        buf
+    });
+
+    // read the serial output incrementally to take the screenshots at their milestones
+    let mut stdout = child.stdout.take().unwrap();
+    let mut output = Vec::new();
+    let mut searched = 0;
+    let mut next_screenshot = 0;
+    let mut failures = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = stdout.read(&mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[..len]);
+
+        let Some(captures) = &captures else {
+            continue;
+        };
+        while let Some(screenshot) = captures.screenshots.get(next_screenshot) {
+            let milestone = screenshot.milestone.as_bytes();
+            let Some(pos) = output[searched..]
+                .windows(milestone.len())
+                .position(|window| window == milestone)
+            else {
+                break;
+            };
+            searched += pos + milestone.len();
+            thread::sleep(screenshot.delay);
+            if let Err(failure) = captures.check(screenshot) {
+                failures.push(failure);
+            }
+            next_screenshot += 1;
+        }
+    }
+    let status = child.wait().unwrap();
+
     strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stderr)
+        .write_all(&stderr_reader.join().unwrap())
         .unwrap();
     strip_ansi_escapes::Writer::new(std::io::stderr())
-        .write_all(&child_output.stdout)
+        .write_all(&output)
         .unwrap();
 
-    match child_output.status.code() {
+    match status.code() {
         Some(33) => {} // success
         Some(35) => panic!("Test failed"),
         other => panic!("Test failed with unexpected exit code `{:?}`", other),
     }
+
+    if let Some(captures) = &captures {
+        for screenshot in &captures.screenshots[next_screenshot..] {
+            failures.push(format!(
+                "milestone `{}` of screenshot `{}` was not reached",
+                screenshot.milestone, screenshot.name
+            ));
+        }
+    }
+    if !failures.is_empty() {
+        panic!("Screenshot test failed:\n  {}", failures.join("\n  "));
+    }
 }
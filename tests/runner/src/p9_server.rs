@@ -0,0 +1,255 @@
+//! A minimal read-only 9P2000.L server for testing the 9P boot mode of the UEFI bootloader.
+//!
+//! Only the messages that the bootloader sends are implemented, all others are answered
+//! with `EOPNOTSUPP`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+const VERSION: &[u8] = b"9P2000.L";
+const MAX_MESSAGE_SIZE: u32 = 128 * 1024;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+const EIO: u32 = 5;
+const EOPNOTSUPP: u32 = 95;
+
+/// The `qid.type` of directories.
+const QTDIR: u8 = 0x80;
+/// All basic fields of `Rgetattr` are valid.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// A server that exports a directory on a local TCP port.
+pub struct P9Server {
+    port: u16,
+    opened: Arc<Mutex<Vec<String>>>,
+}
+
+impl P9Server {
+    /// Starts serving the given directory on a free port of the loopback interface.
+    ///
+    /// Each connection is handled on its own thread. The threads run until the test process
+    /// exits.
+    pub fn start(root: &Path) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let root = root.to_owned();
+        let opened_clone = opened.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let connection = Connection {
+                    stream,
+                    root: root.clone(),
+                    fids: HashMap::new(),
+                    opened: opened_clone.clone(),
+                };
+                thread::spawn(move || {
+                    let _ = connection.serve();
+                });
+            }
+        });
+        Self { port, opened }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the paths of the files that clients opened, relative to the export.
+    pub fn opened_files(&self) -> Vec<String> {
+        self.opened.lock().unwrap().clone()
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    root: PathBuf,
+    /// The paths of the fids, relative to the root.
+    fids: HashMap<u32, PathBuf>,
+    opened: Arc<Mutex<Vec<String>>>,
+}
+
+impl Connection {
+    fn serve(mut self) -> io::Result<()> {
+        loop {
+            let mut header = [0; 7];
+            self.stream.read_exact(&mut header)?;
+            let size = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let ty = header[4];
+            let tag = u16::from_le_bytes([header[5], header[6]]);
+            let mut body = vec![0; size.saturating_sub(header.len())];
+            self.stream.read_exact(&mut body)?;
+
+            let reply = match self.handle(ty, &mut Reader(&body)) {
+                Ok(reply) => (ty + 1, reply),
+                Err(errno) => (RLERROR, errno.to_le_bytes().to_vec()),
+            };
+            let mut message = Vec::new();
+            message.extend_from_slice(&(7 + reply.1.len() as u32).to_le_bytes());
+            message.push(reply.0);
+            message.extend_from_slice(&tag.to_le_bytes());
+            message.extend_from_slice(&reply.1);
+            self.stream.write_all(&message)?;
+        }
+    }
+
+    /// Handles a request and returns the body of the reply or an errno.
+    fn handle(&mut self, ty: u8, request: &mut Reader) -> Result<Vec<u8>, u32> {
+        let mut reply = Vec::new();
+        match ty {
+            TVERSION => {
+                let max_size = request.u32()?.min(MAX_MESSAGE_SIZE);
+                if request.bytes()? != VERSION {
+                    return Err(EOPNOTSUPP);
+                }
+                reply.extend_from_slice(&max_size.to_le_bytes());
+                put_bytes(&mut reply, VERSION);
+            }
+            TATTACH => {
+                let fid = request.u32()?;
+                self.fids.insert(fid, PathBuf::new());
+                reply.extend_from_slice(&self.qid(Path::new(""))?);
+            }
+            TWALK => {
+                let fid = request.u32()?;
+                let new_fid = request.u32()?;
+                let mut path = self.fids.get(&fid).ok_or(EBADF)?.clone();
+                let count = request.u16()?;
+                let mut qids = Vec::new();
+                for _ in 0..count {
+                    let name = String::from_utf8(request.bytes()?.to_vec()).map_err(|_| ENOENT)?;
+                    let component = Path::new(&name);
+                    if !matches!(component.components().next(), Some(Component::Normal(_))) {
+                        return Err(ENOENT);
+                    }
+                    path.push(component);
+                    match self.qid(&path) {
+                        Ok(qid) => qids.push(qid),
+                        Err(_) => break,
+                    }
+                }
+                if qids.is_empty() && count > 0 {
+                    return Err(ENOENT);
+                }
+                if qids.len() == usize::from(count) {
+                    self.fids.insert(new_fid, path);
+                }
+                reply.extend_from_slice(&(qids.len() as u16).to_le_bytes());
+                for qid in qids {
+                    reply.extend_from_slice(&qid);
+                }
+            }
+            TLOPEN => {
+                let path = self.fids.get(&request.u32()?).ok_or(EBADF)?.clone();
+                self.opened
+                    .lock()
+                    .unwrap()
+                    .push(path.to_string_lossy().into_owned());
+                reply.extend_from_slice(&self.qid(&path)?);
+                reply.extend_from_slice(&0u32.to_le_bytes()); // iounit
+            }
+            TGETATTR => {
+                let path = self.fids.get(&request.u32()?).ok_or(EBADF)?.clone();
+                let metadata = fs::metadata(self.root.join(&path)).map_err(|_| ENOENT)?;
+                let mode: u32 = if metadata.is_dir() {
+                    0o040555
+                } else {
+                    0o100444
+                };
+                reply.extend_from_slice(&GETATTR_BASIC.to_le_bytes());
+                reply.extend_from_slice(&self.qid(&path)?);
+                reply.extend_from_slice(&mode.to_le_bytes());
+                reply.extend_from_slice(&[0; 8]); // uid, gid
+                reply.extend_from_slice(&1u64.to_le_bytes()); // nlink
+                reply.extend_from_slice(&[0; 8]); // rdev
+                reply.extend_from_slice(&metadata.len().to_le_bytes());
+                // blksize, blocks, times, gen, and data_version
+                reply.extend_from_slice(&[0; 8 * 12]);
+            }
+            TREAD => {
+                let path = self.fids.get(&request.u32()?).ok_or(EBADF)?.clone();
+                let offset = request.u64()? as usize;
+                let count = request.u32()? as usize;
+                let contents = fs::read(self.root.join(path)).map_err(|_| EIO)?;
+                let start = offset.min(contents.len());
+                let end = (start + count).min(contents.len());
+                reply.extend_from_slice(&((end - start) as u32).to_le_bytes());
+                reply.extend_from_slice(&contents[start..end]);
+            }
+            TCLUNK => {
+                self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+            }
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+
+    /// Returns the 13-byte qid of the given path, using a hash of the path as its ID.
+    fn qid(&self, path: &Path) -> Result<[u8; 13], u32> {
+        let metadata = fs::metadata(self.root.join(path)).map_err(|_| ENOENT)?;
+        let mut qid = [0; 13];
+        qid[0] = if metadata.is_dir() { QTDIR } else { 0 };
+        let id = path
+            .to_string_lossy()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+            });
+        qid[5..].copy_from_slice(&id.to_le_bytes());
+        Ok(qid)
+    }
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+/// Reads the little-endian fields of a request body.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        if self.0.len() < len {
+            return Err(EIO);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], u32> {
+        let len = usize::from(self.u16()?);
+        self.take(len)
+    }
+}
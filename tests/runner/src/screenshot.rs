@@ -0,0 +1,228 @@
+//! Screenshots of boot milestones, compared against golden images.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Overwrites the golden images with the screenshots instead of comparing them if set.
+const UPDATE_VAR: &str = "BOOTLOADER_UPDATE_SCREENSHOTS";
+/// The prompt of the QEMU monitor.
+const MONITOR_PROMPT: &[u8] = b"(qemu) ";
+/// How long to wait for QEMU to open the monitor port.
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A screenshot that is taken when the serial output reaches a boot milestone.
+#[derive(Debug, Clone, Copy)]
+pub struct Screenshot<'a> {
+    /// The name of the golden image, see [`run_test_kernel_with_screenshots`].
+    ///
+    /// [`run_test_kernel_with_screenshots`]: crate::run_test_kernel_with_screenshots
+    pub name: &'a str,
+    /// The text in the serial output that marks the milestone, e.g. a log message.
+    ///
+    /// The text is matched against the raw output, so it must not span ANSI escape codes.
+    pub milestone: &'a str,
+    /// How long to wait after the milestone before the screenshot is taken, to give the
+    /// firmware or kernel time to finish drawing.
+    pub delay: Duration,
+    /// How much the screenshot may differ from the golden image.
+    pub tolerance: Tolerance,
+}
+
+/// How much a screenshot may differ from its golden image.
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    /// The maximum difference of a color channel for which two pixels are still equal.
+    pub channel: u8,
+    /// The maximum fraction of pixels that may differ, e.g. `0.001` for 0.1%.
+    pub pixels: f64,
+}
+
+impl Default for Tolerance {
+    /// Allows small color differences, e.g. from font anti-aliasing, but no differing pixels.
+    fn default() -> Self {
+        Tolerance {
+            channel: 8,
+            pixels: 0.0,
+        }
+    }
+}
+
+/// The screenshots of one QEMU run.
+pub(crate) struct Captures<'a> {
+    pub screenshots: &'a [Screenshot<'a>],
+    pub golden_dir: &'a Path,
+    /// Distinguishes the golden images of the boot methods, e.g. `bios`.
+    pub firmware: &'static str,
+    /// The disk image or TFTP folder, next to which the screenshots are stored.
+    pub image_path: &'a Path,
+    /// The port of the QEMU monitor.
+    pub monitor_port: u16,
+}
+
+impl Captures<'_> {
+    /// Takes the given screenshot and compares it against its golden image.
+    pub fn check(&self, screenshot: &Screenshot) -> Result<(), String> {
+        let file_name = format!("{}-{}.ppm", screenshot.name, self.firmware);
+        let actual_path = self
+            .image_path
+            .with_file_name(format!("screenshot-{file_name}"));
+        let golden_path = self.golden_dir.join(&file_name);
+        screendump(self.monitor_port, &actual_path)
+            .map_err(|err| format!("failed to take screenshot `{}`: {err}", screenshot.name))?;
+
+        if env::var_os(UPDATE_VAR).is_some() {
+            fs::create_dir_all(self.golden_dir).unwrap();
+            fs::copy(&actual_path, &golden_path).unwrap();
+            return Ok(());
+        }
+        if !golden_path.exists() {
+            return Err(format!(
+                "golden image `{}` does not exist, set `{UPDATE_VAR}=1` to create it from `{}`",
+                golden_path.display(),
+                actual_path.display()
+            ));
+        }
+        let actual = Image::read(&actual_path)?;
+        let golden = Image::read(&golden_path)?;
+        actual
+            .compare(&golden, screenshot.tolerance)
+            .map_err(|difference| {
+                format!(
+                    "screenshot `{}` differs from `{}` in {difference}, see `{}`",
+                    screenshot.name,
+                    golden_path.display(),
+                    actual_path.display()
+                )
+            })
+    }
+}
+
+/// Returns a TCP port that is currently unused.
+pub(crate) fn free_port() -> u16 {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Writes the current display content to the given path in the PPM format, through the
+/// QEMU monitor.
+fn screendump(port: u16, path: &Path) -> io::Result<()> {
+    let start = Instant::now();
+    let mut monitor = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < MONITOR_TIMEOUT => {
+                thread::sleep(Duration::from_millis(100))
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    read_until_prompt(&mut monitor)?;
+    // `screendump` only returns after the file was written
+    writeln!(monitor, "screendump {}", path.display())?;
+    read_until_prompt(&mut monitor)
+}
+
+fn read_until_prompt(monitor: &mut TcpStream) -> io::Result<()> {
+    let mut received = Vec::new();
+    let mut buf = [0; 256];
+    while !received
+        .windows(MONITOR_PROMPT.len())
+        .any(|window| window == MONITOR_PROMPT)
+    {
+        let len = monitor.read(&mut buf)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        received.extend_from_slice(&buf[..len]);
+    }
+    Ok(())
+}
+
+/// An RGB image.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Reads a binary PPM (`P6`) file with 8-bit channels, as written by `screendump`.
+    fn read(path: &Path) -> Result<Self, String> {
+        let invalid = || format!("`{}` is not a valid PPM image", path.display());
+        let data =
+            fs::read(path).map_err(|err| format!("failed to read `{}`: {err}", path.display()))?;
+
+        // the header consists of four whitespace-separated fields, comments start with `#`
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while fields.len() < 4 {
+            match data.get(pos) {
+                Some(b'#') => {
+                    while data.get(pos).map_or(false, |&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => pos += 1,
+                Some(_) => {
+                    let start = pos;
+                    while data.get(pos).map_or(false, |b| !b.is_ascii_whitespace()) {
+                        pos += 1;
+                    }
+                    fields.push(&data[start..pos]);
+                }
+                None => return Err(invalid()),
+            }
+        }
+        let number = |field: &[u8]| -> Result<usize, String> {
+            std::str::from_utf8(field)
+                .ok()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)
+        };
+        if fields[0] != b"P6" || number(fields[3])? != 255 {
+            return Err(invalid());
+        }
+        let (width, height) = (number(fields[1])?, number(fields[2])?);
+        // a single whitespace character separates the header from the pixels
+        let pixels = data.get(pos + 1..).ok_or_else(invalid)?;
+        if pixels.len() != width * height * 3 {
+            return Err(invalid());
+        }
+        Ok(Image {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+        })
+    }
+
+    /// Describes how this image differs from the golden image beyond the tolerance.
+    fn compare(&self, golden: &Image, tolerance: Tolerance) -> Result<(), String> {
+        if (self.width, self.height) != (golden.width, golden.height) {
+            return Err(format!(
+                "size ({}x{} instead of {}x{})",
+                self.width, self.height, golden.width, golden.height
+            ));
+        }
+        let differing = self
+            .pixels
+            .chunks_exact(3)
+            .zip(golden.pixels.chunks_exact(3))
+            .filter(|(a, b)| {
+                a.iter()
+                    .zip(*b)
+                    .any(|(a, b)| a.abs_diff(*b) > tolerance.channel)
+            })
+            .count();
+        let fraction = differing as f64 / (self.width * self.height).max(1) as f64;
+        if fraction > tolerance.pixels {
+            return Err(format!("{differing} pixels ({:.3}%)", fraction * 100.0));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader_api::{entry_point, BootInfo};
+use core::{fmt::Write, slice};
+use test_kernel_network::{exit_qemu, large_ramdisk_byte, serial, QemuExitCode, LARGE_RAMDISK_LEN};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let ramdisk_addr = boot_info.ramdisk_addr.into_option().unwrap();
+    assert_eq!(boot_info.ramdisk_len as usize, LARGE_RAMDISK_LEN);
+    let ramdisk = unsafe { slice::from_raw_parts(ramdisk_addr as *const u8, LARGE_RAMDISK_LEN) };
+    if let Some(offset) = (0..LARGE_RAMDISK_LEN).find(|&i| ramdisk[i] != large_ramdisk_byte(i)) {
+        panic!("ramdisk differs at offset {offset:#x}");
+    }
+    writeln!(serial(), "Ramdisk of {LARGE_RAMDISK_LEN} bytes is intact").unwrap();
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let _ = writeln!(serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,41 @@
+#![no_std]
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// The length of the ramdisk that the network tests pass to the kernel.
+///
+/// Large enough that the transfer takes many round trips. Keep in sync with
+/// `tests/network.rs`.
+pub const LARGE_RAMDISK_LEN: usize = 16 * 1024 * 1024;
+
+/// Returns the byte at the given offset of the large ramdisk.
+///
+/// The pattern repeats every 251 bytes, so lost, reordered, or duplicated blocks are
+/// detected. Keep in sync with `tests/network.rs`.
+pub fn large_ramdisk_byte(offset: usize) -> u8 {
+    (offset % 251) as u8
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    use x86_64::instructions::{nop, port::Port};
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+
+    loop {
+        nop();
+    }
+}
+
+pub fn serial() -> uart_16550::SerialPort {
+    let mut port = unsafe { uart_16550::SerialPort::new(0x3F8) };
+    port.init();
+    port
+}
@@ -0,0 +1,89 @@
+use crate::{load_file_from_disk, smbios_addr};
+use bootloader_x86_64_common::{
+    board_config::{self, BoardConfig},
+    smbios,
+};
+use core::fmt::Write;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    table::boot::{AllocateType, MemoryType},
+};
+
+/// Loads the board config of this machine from the `boards` directory of the boot partition
+/// and reserves its memory ranges.
+///
+/// The system product name of the SMBIOS tables is tried first, then the baseboard product
+/// name. Invalid configs are reported on the console and ignored.
+pub fn load(image: Handle, st: &mut SystemTable<Boot>) -> Option<BoardConfig> {
+    let smbios_addr = smbios_addr(st.config_table())?;
+    // the firmware identity-maps the SMBIOS tables
+    let names = unsafe { smbios::find_product_names(smbios_addr) };
+
+    let mut buf = [0; board_config::MAX_FILE_NAME_LEN];
+    let (path, file) = 'found: {
+        for product in [names.system, names.board].into_iter().flatten() {
+            let Some(path) = board_config::file_name(product, &mut buf) else {
+                continue;
+            };
+            if let Some(file) = load_file_from_disk(path, image, st) {
+                break 'found (path, file);
+            }
+        }
+        return None;
+    };
+    let Ok(text) = core::str::from_utf8(file) else {
+        writeln!(
+            st.stdout(),
+            "Ignoring board config `{path}` that is not UTF-8"
+        )
+        .unwrap();
+        return None;
+    };
+    let config = match BoardConfig::parse(text) {
+        Ok(config) => config,
+        Err(err) => {
+            writeln!(st.stdout(), "Ignoring invalid board config `{path}`: {err}").unwrap();
+            return None;
+        }
+    };
+    writeln!(st.stdout(), "Using board config `{path}`").unwrap();
+
+    for &(start, len) in config.reserved_memory() {
+        reserve(st, start, len);
+    }
+    Some(config)
+}
+
+/// Marks the given physical memory range as reserved in the UEFI memory map, so that neither
+/// the bootloader nor the kernel use it.
+fn reserve(st: &mut SystemTable<Boot>, start: u64, len: u64) {
+    let first_page = start / 4096;
+    let Some(end_page) = start
+        .checked_add(len)
+        .and_then(|end| end.checked_add(4095))
+        .map(|end| end / 4096)
+    else {
+        writeln!(
+            st.stdout(),
+            "Ignoring reserved memory at {start:#x} that extends past the address space"
+        )
+        .unwrap();
+        return;
+    };
+    if end_page <= first_page {
+        return;
+    }
+    let result = st.boot_services().allocate_pages(
+        AllocateType::Address(first_page * 4096),
+        MemoryType::RESERVED,
+        (end_page - first_page) as usize,
+    );
+    if result.is_err() {
+        writeln!(
+            st.stdout(),
+            "Failed to reserve memory at {start:#x}..{:#x}, it is already in use",
+            start + len
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+use uefi::{
+    guid,
+    prelude::{cstr16, Boot, SystemTable},
+    table::runtime::{VariableAttributes, VariableVendor},
+    CStr16,
+};
+
+/// The vendor GUID of the boot counter, boot slot, and minimum security version variables.
+///
+/// Keep in sync with the documentation of `bootloader_api::info::BootCounter` and
+/// `bootloader_api::info::BootSlot`.
+pub const VENDOR: VariableVendor = VariableVendor(guid!("d6f9a6b4-5a3e-4c7f-9e1b-2f8a0c3d4e51"));
+
+const NAME: &CStr16 = cstr16!("BootloaderFailedBoots");
+
+/// Reads the number of failed boots from the non-volatile boot counter variable.
+///
+/// Returns `0` if the variable does not exist yet, i.e. on the first boot.
+pub fn read(st: &SystemTable<Boot>) -> u8 {
+    let mut buf = [0; 1];
+    match st.runtime_services().get_variable(NAME, &VENDOR, &mut buf) {
+        Ok((&[count], _)) => count,
+        _ => 0,
+    }
+}
+
+/// Stores the number of failed boots in the non-volatile boot counter variable.
+///
+/// The variable stays accessible at runtime, so that the kernel can reset it after a
+/// successful boot.
+pub fn write(st: &SystemTable<Boot>, count: u8) -> uefi::Result {
+    st.runtime_services().set_variable(
+        NAME,
+        &VENDOR,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &[count],
+    )
+}
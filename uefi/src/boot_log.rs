@@ -0,0 +1,163 @@
+use crate::{locate_and_open_protocol, BootMode, RacyCell, SYSTEM_TABLE};
+use bootloader_x86_64_common::error::BootError;
+use core::{fmt::Write, ops::Range};
+use uefi::{
+    prelude::{cstr16, Boot, Handle, SystemTable},
+    proto::media::{
+        file::{File, FileAttribute, FileMode, RegularFile},
+        fs::SimpleFileSystem,
+    },
+    CStr16,
+};
+
+/// The log file on the boot partition, which is only written if it exists.
+///
+/// The builder creates it with a fixed size, so that the bootloader never allocates clusters on
+/// the FAT file system. Keep the format in sync with `src/uefi/mod.rs` of the `bootloader`
+/// crate.
+const FILE_NAME: &CStr16 = cstr16!("boot-log");
+
+/// Every boot overwrites the oldest record, which is a line of this many bytes.
+const RECORD_LEN: usize = 64;
+/// The decimal sequence number of the boot, the newest record has the highest number.
+const SEQUENCE: Range<usize> = 0..8;
+const MODE: Range<usize> = 9..18;
+/// The name of the loaded kernel file.
+const ENTRY: Range<usize> = 19..55;
+/// The code of the boot error, e.g. `E0100`.
+const ERROR: Range<usize> = 56..61;
+/// `?` until the OS replaces it with `+` after a successful boot or `-` after a failed one.
+const OUTCOME: usize = 62;
+
+static LOG: RacyCell<Option<BootLog>> = RacyCell::new(None);
+
+struct BootLog {
+    image: Handle,
+    /// The position of the record of this boot in the log file.
+    position: u64,
+    record: [u8; RECORD_LEN],
+}
+
+/// Appends a record for this boot to the boot log, if the boot partition contains one.
+///
+/// The record is completed by [`set_entry`] and [`set_error`].
+pub fn start(image: Handle, st: &mut SystemTable<Boot>) {
+    let Some(mut file) = open(image, st) else {
+        return;
+    };
+    let Some((capacity, last_sequence)) = scan(&mut file) else {
+        writeln!(
+            st.stdout(),
+            "Ignoring boot log that is smaller than a record"
+        )
+        .unwrap();
+        return;
+    };
+
+    let sequence = last_sequence.map_or(0, |s| (s + 1) % 100_000_000);
+    let mut record = [b' '; RECORD_LEN];
+    write_number(&mut record[SEQUENCE], sequence);
+    set_field(&mut record, MODE, "-");
+    set_field(&mut record, ENTRY, "-");
+    set_field(&mut record, ERROR, "-");
+    record[OUTCOME] = b'?';
+    record[RECORD_LEN - 1] = b'\n';
+    let log = BootLog {
+        image,
+        position: u64::from(sequence % capacity) * RECORD_LEN as u64,
+        record,
+    };
+    if write(&mut file, &log).is_err() {
+        writeln!(st.stdout(), "Failed to write the boot log").unwrap();
+        return;
+    }
+    unsafe { *LOG.get() = Some(log) };
+}
+
+/// Records the boot mode and the name of the kernel file that is booted.
+pub fn set_entry(st: &SystemTable<Boot>, boot_mode: BootMode, entry: &str) {
+    let mode = match boot_mode {
+        BootMode::Stub => "stub",
+        BootMode::Disk => "disk",
+        BootMode::Serial => "serial",
+        BootMode::NetworkFs => "networkfs",
+        BootMode::P9(_) => "9p",
+        BootMode::Tftp => "tftp",
+        BootMode::Usb(_) => "usb",
+    };
+    update(st, |record| {
+        set_field(record, MODE, mode);
+        set_field(record, ENTRY, entry.trim_end_matches('\0'));
+    });
+}
+
+/// Records the code of a boot error, as long as boot services are still active.
+pub fn set_error(error: BootError) {
+    if let Some(st) = unsafe { &*SYSTEM_TABLE.get() } {
+        update(st, |record| {
+            record[ERROR.start] = b'E';
+            write_number(&mut record[ERROR.start + 1..ERROR.end], error.code().into());
+        });
+    }
+}
+
+fn update(st: &SystemTable<Boot>, f: impl FnOnce(&mut [u8; RECORD_LEN])) {
+    let Some(log) = (unsafe { &mut *LOG.get() }) else {
+        return;
+    };
+    f(&mut log.record);
+    if let Some(mut file) = open(log.image, st) {
+        let _ = write(&mut file, log);
+    }
+}
+
+fn open(image: Handle, st: &SystemTable<Boot>) -> Option<RegularFile> {
+    let mut file_system = locate_and_open_protocol::<SimpleFileSystem>(image, st)?;
+    let mut root = file_system.open_volume().ok()?;
+    root.open(FILE_NAME, FileMode::ReadWrite, FileAttribute::empty())
+        .ok()?
+        .into_regular_file()
+}
+
+/// Returns the number of records that fit into the log and the highest sequence number.
+fn scan(file: &mut RegularFile) -> Option<(u32, Option<u32>)> {
+    let mut capacity = 0;
+    let mut last_sequence = None;
+    let mut record = [0; RECORD_LEN];
+    while file.read(&mut record).ok()? == RECORD_LEN {
+        capacity += 1;
+        last_sequence = last_sequence.max(parse_number(&record[SEQUENCE]));
+    }
+    (capacity > 0).then_some((capacity, last_sequence))
+}
+
+fn write(file: &mut RegularFile, log: &BootLog) -> uefi::Result {
+    file.set_position(log.position)?;
+    file.write(&log.record)
+        .map_err(|err| uefi::Error::from(err.status()))?;
+    file.flush()
+}
+
+/// Copies `text` into the given field, truncated or padded with spaces.
+fn set_field(record: &mut [u8; RECORD_LEN], field: Range<usize>, text: &str) {
+    let field = &mut record[field];
+    field.fill(b' ');
+    let len = text.len().min(field.len());
+    field[..len].copy_from_slice(&text.as_bytes()[..len]);
+}
+
+/// Writes the lowest decimal digits of `value` into `digits`, padded with zeros.
+fn write_number(digits: &mut [u8], mut value: u32) {
+    for digit in digits.iter_mut().rev() {
+        *digit = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0u32, |value, &digit| {
+        digit
+            .is_ascii_digit()
+            .then(|| value * 10 + u32::from(digit - b'0'))
+    })
+}
@@ -0,0 +1,169 @@
+use crate::{
+    boot_script, load_file_from_disk,
+    pointer::{PointerInput, Pointers},
+    watchdog,
+};
+use bootloader_x86_64_common::boot_menu::{self, Menu, MenuEntry};
+use bootloader_x86_64_common::power;
+use core::fmt::Write;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::console::text::{Key, ScanCode},
+};
+
+/// The interval in which the keyboard and the pointer devices are polled.
+const POLL_INTERVAL_US: usize = 100_000;
+const POLLS_PER_SECOND: u32 = 10;
+
+/// Shows the menu of the `boot-menu` file of the boot partition, if there is one.
+///
+/// Returns the selected entry. The menu is shown on the firmware console, which
+/// most firmware mirrors to the serial port, and accepts input from both and from a mouse or
+/// touchscreen. Invalid menus are reported on the console and ignored.
+pub fn run(image: Handle, st: &mut SystemTable<Boot>) -> Option<MenuEntry<'static>> {
+    let menu = load_file_from_disk("boot-menu\0", image, st)?;
+    let Ok(menu) = core::str::from_utf8(menu) else {
+        writeln!(st.stdout(), "Ignoring boot menu that is not UTF-8").unwrap();
+        return None;
+    };
+    let menu = match boot_menu::parse(menu) {
+        Ok(menu) => menu,
+        Err(err) => {
+            writeln!(st.stdout(), "Ignoring invalid boot menu: {err}").unwrap();
+            return None;
+        }
+    };
+    if menu.entries().any(|entry| {
+        boot_script::too_long(entry.files.kernel)
+            || entry.files.ramdisk.map_or(false, boot_script::too_long)
+    }) {
+        writeln!(st.stdout(), "Ignoring boot menu: file name too long").unwrap();
+        return None;
+    }
+
+    let selected = match menu.timeout_secs {
+        0 => menu.default,
+        _ => {
+            let selected = select(image, st, &menu);
+            writeln!(st.stdout()).unwrap();
+            selected
+        }
+    };
+    let entry = menu.get(selected)?;
+    writeln!(st.stdout(), "Booting `{}`", entry.label).unwrap();
+    Some(*entry)
+}
+
+/// Lets the user choose an entry and returns its index.
+///
+/// The default entry is selected when the timeout expires. Any key stops the countdown, the
+/// arrow keys move the selection, `Enter` confirms it, and `1` to `9` choose an entry
+/// directly. `R` and `P` reboot and power off the system. Moving the mouse also moves the
+/// selection and a click confirms it; touching an entry selects it and touching the selected
+/// entry again confirms it.
+fn select(image: Handle, st: &mut SystemTable<Boot>, menu: &Menu) -> usize {
+    // the pointer protocols only use the boot services, which stay valid while the menu runs
+    let pointer_st = unsafe { st.unsafe_clone() };
+    let mut pointers = Pointers::open(image, &pointer_st);
+
+    writeln!(st.stdout(), "Boot menu:").unwrap();
+    for (index, entry) in menu.entries().enumerate() {
+        writeln!(st.stdout(), "  {}  {}", index + 1, entry.label).unwrap();
+    }
+    writeln!(st.stdout(), "  r  Reboot").unwrap();
+    writeln!(st.stdout(), "  p  Power off").unwrap();
+    writeln!(
+        st.stdout(),
+        "Press 1-{} or the arrow keys and Enter to choose a kernel",
+        menu.len()
+    )
+    .unwrap();
+    // the status line is the cursor row, the entries are above the prompt and the actions
+    let rows = st
+        .stdout()
+        .current_mode()
+        .ok()
+        .flatten()
+        .map_or(25, |mode| mode.rows());
+    let first_entry_row = st.stdout().cursor_position().1.checked_sub(menu.len() + 3);
+
+    let mut selected = menu.default;
+    let mut remaining_polls = Some(menu.timeout_secs.saturating_mul(POLLS_PER_SECOND));
+    loop {
+        watchdog::restart(st);
+        match remaining_polls {
+            Some(0) => return selected,
+            Some(polls) if polls % POLLS_PER_SECOND == 0 => {
+                status(st, menu, selected, Some(polls / POLLS_PER_SECOND))
+            }
+            _ => {}
+        }
+        match st.stdin().read_key() {
+            Ok(Some(key)) => {
+                boot_script::record_key(key);
+                remaining_polls = None;
+                match key {
+                    Key::Printable(c) if char::from(c) == '\r' => return selected,
+                    Key::Printable(c) if char::from(c).eq_ignore_ascii_case(&'r') => {
+                        power::reboot()
+                    }
+                    Key::Printable(c) if char::from(c).eq_ignore_ascii_case(&'p') => {
+                        power::power_off()
+                    }
+                    Key::Printable(c) => {
+                        let index = char::from(c).to_digit(10).and_then(|d| d.checked_sub(1));
+                        if let Some(index) = index.filter(|&i| (i as usize) < menu.len()) {
+                            return index as usize;
+                        }
+                    }
+                    Key::Special(ScanCode::UP) => selected = selected.saturating_sub(1),
+                    Key::Special(ScanCode::DOWN) => selected = (selected + 1).min(menu.len() - 1),
+                    Key::Special(_) => {}
+                }
+                status(st, menu, selected, None);
+            }
+            _ => {
+                if let Some(input) = pointers.poll(rows) {
+                    remaining_polls = None;
+                    match input {
+                        PointerInput::Click => return selected,
+                        PointerInput::Move(entries) => {
+                            selected = selected.saturating_add_signed(entries).min(menu.len() - 1)
+                        }
+                        PointerInput::Touch(row) => {
+                            let index = first_entry_row.and_then(|first| row.checked_sub(first));
+                            match index.filter(|&i| i < menu.len()) {
+                                Some(index) if index == selected => return selected,
+                                Some(index) => selected = index,
+                                None => {}
+                            }
+                        }
+                    }
+                    status(st, menu, selected, None);
+                    continue;
+                }
+                st.boot_services().stall(POLL_INTERVAL_US);
+                remaining_polls = remaining_polls.map(|polls| polls - 1);
+            }
+        }
+    }
+}
+
+/// Overwrites the status line with the selected entry and the remaining seconds.
+fn status(st: &mut SystemTable<Boot>, menu: &Menu, selected: usize, seconds: Option<u32>) {
+    let label = menu.get(selected).map_or("", |entry| entry.label);
+    // the trailing spaces clear the rest of a longer previous line
+    match seconds {
+        Some(seconds) => write!(
+            st.stdout(),
+            "\r> {}  {label} (booting in {seconds}s)    ",
+            selected + 1
+        ),
+        None => write!(
+            st.stdout(),
+            "\r> {}  {label}                    ",
+            selected + 1
+        ),
+    }
+    .unwrap();
+}
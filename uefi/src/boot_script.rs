@@ -0,0 +1,109 @@
+use crate::{boot_counter, load_file_from_disk};
+use bootloader_x86_64_common::{
+    boot_script::{self, BootEntry, Environment, Key},
+    record,
+};
+use core::fmt::Write;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::console::text::{self, ScanCode},
+};
+
+/// The maximum number of distinct keys that are remembered.
+const MAX_KEYS: usize = 32;
+
+/// The keys that were pressed since the bootloader started.
+///
+/// The UEFI input buffer can only be read once, so all checks for pressed keys go through
+/// this type.
+#[derive(Default)]
+pub struct PressedKeys {
+    keys: [Option<Key>; MAX_KEYS],
+}
+
+impl PressedKeys {
+    /// Reads the keys that were pressed since the last call.
+    pub fn update(&mut self, st: &mut SystemTable<Boot>) {
+        while let Ok(Some(key)) = st.stdin().read_key() {
+            record_key(key);
+            let key = match key {
+                text::Key::Printable(c) => Key::Char(char::from(c)),
+                text::Key::Special(code)
+                    if (ScanCode::FUNCTION_1.0..=ScanCode::FUNCTION_12.0).contains(&code.0) =>
+                {
+                    Key::Function((code.0 - ScanCode::FUNCTION_1.0 + 1) as u8)
+                }
+                text::Key::Special(_) => continue,
+            };
+            if self.contains(key) {
+                continue;
+            }
+            if let Some(slot) = self.keys.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(key);
+            }
+        }
+    }
+
+    /// Returns whether the given key was pressed.
+    pub fn contains(&self, key: Key) -> bool {
+        self.keys.contains(&Some(key))
+    }
+}
+
+struct UefiEnvironment<'a> {
+    st: &'a SystemTable<Boot>,
+    keys: &'a PressedKeys,
+}
+
+impl Environment for UefiEnvironment<'_> {
+    fn key_pressed(&mut self, key: Key) -> bool {
+        self.keys.contains(key)
+    }
+
+    fn previous_boot_failed(&mut self) -> bool {
+        boot_counter::read(self.st) > 0
+    }
+}
+
+/// Runs the `boot-script` file of the boot partition, if there is one.
+///
+/// Returns the boot entry selected by the script. Invalid scripts are reported on the
+/// console and ignored.
+pub fn run(
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+    keys: &mut PressedKeys,
+) -> Option<BootEntry<'static>> {
+    let script = load_file_from_disk("boot-script\0", image, st)?;
+    let Ok(script) = core::str::from_utf8(script) else {
+        writeln!(st.stdout(), "Ignoring boot script that is not UTF-8").unwrap();
+        return None;
+    };
+
+    keys.update(st);
+    let mut env = UefiEnvironment { st, keys };
+    match boot_script::run(script, &mut env) {
+        Ok(Some(entry)) if too_long(entry.kernel) || entry.ramdisk.map_or(false, too_long) => {
+            writeln!(st.stdout(), "Ignoring boot script: file name too long").unwrap();
+            None
+        }
+        Ok(entry) => entry,
+        Err(err) => {
+            writeln!(st.stdout(), "Ignoring invalid boot script: {err}").unwrap();
+            None
+        }
+    }
+}
+
+/// Returns whether the file name exceeds the limit of `load_file_from_disk`.
+pub fn too_long(name: &str) -> bool {
+    name.len() >= 256
+}
+
+/// Remembers a key that was read from the UEFI console, see [`record::key`].
+pub fn record_key(key: text::Key) {
+    record::key(match key {
+        text::Key::Printable(c) => record::Key::Char(char::from(c)),
+        text::Key::Special(code) => record::Key::ScanCode(code.0),
+    });
+}
@@ -0,0 +1,87 @@
+use crate::boot_counter::VENDOR;
+use bootloader_api::info::{BootSlot, Slot};
+use uefi::{
+    prelude::{cstr16, Boot, SystemTable},
+    table::runtime::VariableAttributes,
+    CStr16,
+};
+
+const NAME: &CStr16 = cstr16!("BootloaderSlot");
+
+/// The number of remaining attempts that marks a slot as successfully booted.
+const SUCCESSFUL: u8 = 0xff;
+
+/// Selects the slot to boot from the `BootloaderSlot` variable of the update agent.
+///
+/// Returns `None` if the variable doesn't exist or is invalid.
+pub fn select(st: &SystemTable<Boot>) -> Option<BootSlot> {
+    let mut buf = [0; 2];
+    let (&[slot, attempts], _) = st
+        .runtime_services()
+        .get_variable(NAME, &VENDOR, &mut buf)
+        .ok()?
+    else {
+        return None;
+    };
+    let requested = match slot {
+        0 => Slot::A,
+        1 => Slot::B,
+        _ => {
+            log::warn!("Ignoring invalid boot slot {slot}");
+            return None;
+        }
+    };
+    let slot = match attempts {
+        0 => BootSlot {
+            requested,
+            booted: requested.other(),
+            attempts_remaining: 0,
+        },
+        SUCCESSFUL => BootSlot {
+            requested,
+            booted: requested,
+            attempts_remaining: SUCCESSFUL,
+        },
+        attempts => BootSlot {
+            requested,
+            booted: requested,
+            attempts_remaining: attempts - 1,
+        },
+    };
+    Some(slot)
+}
+
+/// Stores the decremented number of remaining attempts, once the kernel of the slot was found.
+pub fn commit(st: &SystemTable<Boot>, slot: &BootSlot) -> uefi::Result {
+    if slot.requested != slot.booted || slot.attempts_remaining == SUCCESSFUL {
+        return Ok(());
+    }
+    st.runtime_services().set_variable(
+        NAME,
+        &VENDOR,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &[slot.requested as u8, slot.attempts_remaining],
+    )
+}
+
+/// Returns whether the kernel of the requested slot is booted before it was marked as
+/// successfully booted.
+pub fn is_trial(slot: &BootSlot) -> bool {
+    slot.requested == slot.booted && slot.attempts_remaining != SUCCESSFUL
+}
+
+pub fn kernel_file(slot: Slot) -> &'static str {
+    match slot {
+        Slot::A => "kernel-x86_64-a\0",
+        Slot::B => "kernel-x86_64-b\0",
+    }
+}
+
+pub fn ramdisk_file(slot: Slot) -> &'static str {
+    match slot {
+        Slot::A => "ramdisk-a\0",
+        Slot::B => "ramdisk-b\0",
+    }
+}
@@ -0,0 +1,368 @@
+//! Starts the kernel before the boot services are exited, see
+//! [`BootloaderConfig::uefi_callback`](bootloader_api::BootloaderConfig::uefi_callback).
+//!
+//! The kernel calls the `uefi_callback_trampoline` routine below through
+//! [`UefiCallback`]. The trampoline is identity-mapped in the kernel page tables. It saves the
+//! kernel state in the [`SharedData`] page, switches back to the page tables, descriptor tables,
+//! and interrupt flag of the firmware that were saved before the jump to the kernel, and calls
+//! [`handle`] on a separate stack. Afterwards, it restores the kernel state.
+
+use crate::{
+    load_file_from_boot_method, memory_descriptor::CallbackMemoryRegion, memory_map_storage,
+    runtime, BootMode, RacyCell,
+};
+use bootloader_api::info::{LoadedFile, UefiCallback};
+use bootloader_x86_64_common::{
+    error::{self, BootError},
+    heap::Heap,
+    legacy_memory_region::LegacyFrameAllocator,
+    Kernel, SystemInfo, UefiCallbackInfo,
+};
+use core::{arch::asm, arch::global_asm, mem, ptr, slice, str};
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    table::{
+        boot::{AllocateType, MemoryType},
+        Runtime,
+    },
+};
+use x86_64::PhysAddr;
+
+/// The size of the stack that the firmware functions run on during a callback.
+const STACK_SIZE: u64 = 128 * 1024;
+
+/// The memory that the kernel pool contains in addition to the kernel, its stack, and the
+/// ramdisk, e.g. for page tables and the boot info.
+const POOL_RESERVE: u64 = 32 * 1024 * 1024;
+
+/// The page that the trampoline shares between the kernel and the bootloader.
+///
+/// The trampoline accesses the fields by their offsets, which are noted next to them.
+#[repr(C)]
+struct SharedData {
+    firmware_cr3: u64,                               // 0
+    firmware_gdtr: [u64; 2],                         // 8
+    firmware_idtr: [u64; 2],                         // 24
+    firmware_cs: u64,                                // 40
+    firmware_ss: u64,                                // 48
+    firmware_rflags: u64,                            // 56
+    stack_top: u64,                                  // 64
+    handler: u64,                                    // 72
+    kernel_rsp: u64,                                 // 80
+    kernel_cr3: u64,                                 // 88
+    kernel_gdtr: [u64; 2],                           // 96
+    kernel_idtr: [u64; 2],                           // 112
+    kernel_cs: u64,                                  // 128
+    kernel_ss: u64,                                  // 136
+    name_len: u64,                                   // 144
+    name: [u8; UefiCallback::MAX_FILE_NAME_LEN + 1], // 152
+}
+
+const _: () = assert!(mem::size_of::<SharedData>() <= 4096);
+
+/// The boot services state that the callback needs.
+struct State {
+    image: Handle,
+    /// `None` after the kernel exited the boot services.
+    st: Option<SystemTable<Boot>>,
+    boot_mode: BootMode,
+}
+
+static STATE: RacyCell<Option<State>> = RacyCell::new(None);
+
+global_asm!(
+    ".global uefi_callback_trampoline",
+    ".global uefi_callback_trampoline_end",
+    "uefi_callback_trampoline:",
+    // rdi = shared data, rsi = operation, rdx = file name, rcx = file name length
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "cli",
+    "mov r12, rdi",
+    "mov r13, rsi",
+    // copy the file name, the handler rejects names that don't fit
+    "mov [r12 + 144], rcx",
+    "cmp rcx, 256",
+    "jbe 2f",
+    "xor ecx, ecx",
+    "2:",
+    "mov rsi, rdx",
+    "lea rdi, [r12 + 152]",
+    "cld",
+    "rep movsb",
+    // save the kernel state
+    "mov [r12 + 80], rsp",
+    "mov rax, cr3",
+    "mov [r12 + 88], rax",
+    "sgdt [r12 + 96]",
+    "sidt [r12 + 112]",
+    "xor eax, eax",
+    "mov ax, cs",
+    "mov [r12 + 128], rax",
+    "mov ax, ss",
+    "mov [r12 + 136], rax",
+    // switch to the firmware state
+    "mov rax, [r12]",
+    "mov cr3, rax",
+    "lgdt [r12 + 8]",
+    "lidt [r12 + 24]",
+    "mov rsp, [r12 + 64]",
+    "mov rax, [r12 + 48]",
+    "mov ss, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "push qword ptr [r12 + 40]",
+    "lea rax, [rip + 3f]",
+    "push rax",
+    "retfq",
+    "3:",
+    "push qword ptr [r12 + 56]",
+    "popfq",
+    "mov rdi, r13",
+    "mov rsi, r12",
+    "call qword ptr [r12 + 72]",
+    "cli",
+    "mov r14, rax",
+    "mov r15, rdx",
+    // restore the kernel state
+    "lgdt [r12 + 96]",
+    "lidt [r12 + 112]",
+    "mov rax, [r12 + 88]",
+    "mov cr3, rax",
+    "mov rsp, [r12 + 80]",
+    "mov rax, [r12 + 136]",
+    "mov ss, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "push qword ptr [r12 + 128]",
+    "lea rax, [rip + 4f]",
+    "push rax",
+    "retfq",
+    "4:",
+    "mov rax, r14",
+    "mov rdx, r15",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "uefi_callback_trampoline_end:",
+);
+
+extern "sysv64" {
+    fn uefi_callback_trampoline(context: u64, op: u64, name: *const u8, len: usize) -> LoadedFile;
+}
+
+extern "C" {
+    static uefi_callback_trampoline_end: u8;
+}
+
+/// Jumps to the kernel without exiting the boot services.
+///
+/// Only the pool allocated here is reported as usable memory to the kernel. The page tables,
+/// the kernel, its stack, and the boot info are all placed in the pool, so that the firmware
+/// does not hand out their memory while the boot services are running.
+pub fn switch_to_kernel(
+    image: Handle,
+    st: SystemTable<Boot>,
+    boot_mode: BootMode,
+    mut heap: Heap,
+    mut kernel: Kernel<'static>,
+    mut system_info: SystemInfo,
+) -> ! {
+    if kernel.config.set_uefi_virtual_address_map {
+        log::warn!("Ignoring set_uefi_virtual_address_map, since uefi_callback is enabled");
+        kernel.config.set_uefi_virtual_address_map = false;
+    }
+
+    let data = allocate_pages(&st, 4096, "the UEFI callback data");
+    let stack = allocate_pages(&st, STACK_SIZE, "the UEFI callback stack");
+    let pool_size = kernel.memory_size()
+        + kernel.config.kernel_stack_size
+        + system_info.ramdisk_len
+        + POOL_RESERVE;
+    let pool = allocate_pages(&st, pool_size, "the kernel memory pool");
+
+    let storage = memory_map_storage(&st, &mut heap, 0);
+    let (_, memory_map) = st
+        .boot_services()
+        .memory_map(storage)
+        .unwrap_or_else(|_| error::fail(BootError::MemoryMapUnavailable));
+    let regions = heap.allocate(
+        (memory_map.len() + 2) * mem::size_of::<CallbackMemoryRegion>(),
+        mem::align_of::<CallbackMemoryRegion>(),
+        "the memory map",
+    );
+    let regions = unsafe {
+        slice::from_raw_parts_mut(
+            regions.as_mut_ptr().cast(),
+            regions.len() / mem::size_of::<CallbackMemoryRegion>(),
+        )
+    };
+    let regions = CallbackMemoryRegion::split(memory_map.clone(), (pool, pool_size), regions);
+    // the runtime services are also reachable through the system table of the boot services
+    let runtime_table = unsafe { SystemTable::<Runtime>::from_ptr(mem::transmute_copy(&st)) }
+        .unwrap_or_else(|| error::fail(BootError::Internal));
+    let uefi_runtime = runtime::collect(&runtime_table, memory_map);
+
+    let mut shared = SharedData {
+        firmware_cr3: 0,
+        firmware_gdtr: [0; 2],
+        firmware_idtr: [0; 2],
+        firmware_cs: 0,
+        firmware_ss: 0,
+        firmware_rflags: 0,
+        stack_top: stack.as_u64() + STACK_SIZE,
+        handler: handle as usize as u64,
+        kernel_rsp: 0,
+        kernel_cr3: 0,
+        kernel_gdtr: [0; 2],
+        kernel_idtr: [0; 2],
+        kernel_cs: 0,
+        kernel_ss: 0,
+        name_len: 0,
+        name: [0; UefiCallback::MAX_FILE_NAME_LEN + 1],
+    };
+    // The firmware interrupt handlers can't run once the bootloader loads its own GDT, so
+    // interrupts stay disabled until the trampoline restores the firmware state.
+    unsafe {
+        asm!(
+            "pushfq",
+            "pop {rflags}",
+            "cli",
+            "mov {cr3}, cr3",
+            "sgdt [{gdtr}]",
+            "sidt [{idtr}]",
+            "xor {cs:e}, {cs:e}",
+            "mov {cs:x}, cs",
+            "xor {ss:e}, {ss:e}",
+            "mov {ss:x}, ss",
+            rflags = out(reg) shared.firmware_rflags,
+            cr3 = out(reg) shared.firmware_cr3,
+            gdtr = in(reg) shared.firmware_gdtr.as_mut_ptr(),
+            idtr = in(reg) shared.firmware_idtr.as_mut_ptr(),
+            cs = out(reg) shared.firmware_cs,
+            ss = out(reg) shared.firmware_ss,
+        );
+        ptr::write(data.as_u64() as *mut SharedData, shared);
+    }
+
+    let mut frame_allocator = LegacyFrameAllocator::new(regions.iter().copied());
+    frame_allocator.prefer_frames_above(PhysAddr::new(kernel.config.min_frame_address));
+    let page_tables = crate::create_page_tables(&mut frame_allocator);
+
+    let trampoline = uefi_callback_trampoline as usize as u64;
+    let trampoline_end = unsafe { ptr::addr_of!(uefi_callback_trampoline_end) } as u64;
+    system_info.bootloader_heap = Some(heap.usage());
+    system_info.uefi_runtime = Some(uefi_runtime);
+    system_info.uefi_callback = Some(UefiCallbackInfo {
+        callback: unsafe { UefiCallback::new(data.as_u64(), uefi_callback_trampoline) },
+        code: (PhysAddr::new(trampoline), trampoline_end - trampoline),
+        data: (data, 4096),
+    });
+    unsafe {
+        *STATE.get() = Some(State {
+            image,
+            st: Some(st),
+            boot_mode,
+        });
+    }
+
+    log::info!("Jumping to the kernel without exiting the boot services");
+    bootloader_x86_64_common::load_and_switch_to_kernel(
+        kernel,
+        frame_allocator,
+        page_tables,
+        system_info,
+    );
+}
+
+fn allocate_pages(st: &SystemTable<Boot>, size: u64, name: &'static str) -> PhysAddr {
+    let pages = usize::try_from((size + 4095) / 4096)
+        .unwrap_or_else(|_| error::fail(BootError::OutOfMemory(name)));
+    let addr = st
+        .boot_services()
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .unwrap_or_else(|_| error::fail(BootError::OutOfMemory(name)));
+    PhysAddr::new(addr)
+}
+
+/// Runs a callback operation of the kernel with the firmware state restored.
+extern "sysv64" fn handle(op: u64, data: &'static mut SharedData) -> LoadedFile {
+    const FAILED: LoadedFile = LoadedFile { addr: 0, len: 0 };
+
+    let Some(state) = (unsafe { (*STATE.get()).as_mut() }) else {
+        return FAILED;
+    };
+    let Some(st) = state.st.as_mut() else {
+        log::warn!("UEFI callback called after the boot services were exited");
+        return FAILED;
+    };
+    match op {
+        UefiCallback::LOAD_FILE => {
+            let file = load_file(state.image, st, state.boot_mode, data);
+            // loading restarts the watchdog, which would reset the machine under the kernel
+            crate::watchdog::disable(st);
+            file.unwrap_or(FAILED)
+        }
+        UefiCallback::EXIT_BOOT_SERVICES => exit_boot_services(state).unwrap_or(FAILED),
+        _ => {
+            log::warn!("Unknown UEFI callback operation {op}");
+            FAILED
+        }
+    }
+}
+
+fn load_file(
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+    boot_mode: BootMode,
+    data: &'static mut SharedData,
+) -> Option<LoadedFile> {
+    let len = usize::try_from(data.name_len).ok()?;
+    if len == 0 || len > UefiCallback::MAX_FILE_NAME_LEN {
+        return None;
+    }
+    data.name[len] = 0;
+    let name: &'static str = str::from_utf8(&data.name[..=len]).ok()?;
+    if name[..len].contains('\0') {
+        return None;
+    }
+    log::info!("Loading {} for the kernel", &name[..len]);
+    let file = load_file_from_boot_method(image, st, name, boot_mode)?;
+    Some(LoadedFile {
+        addr: file.as_ptr() as u64,
+        len: file.len() as u64,
+    })
+}
+
+fn exit_boot_services(state: &mut State) -> Option<LoadedFile> {
+    let st = state.st.take()?;
+    // the allocation of the buffer can add descriptors to the memory map
+    let size = st.boot_services().memory_map_size();
+    let len = size.map_size + 8 * size.entry_size;
+    let Ok(buffer) = st.boot_services().allocate_pool(MemoryType::LOADER_DATA, len) else {
+        log::error!("Failed to allocate the memory map for exiting the boot services");
+        state.st = Some(st);
+        return None;
+    };
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer, len) };
+    match st.exit_boot_services(state.image, buffer) {
+        Ok(_) => {
+            log::info!("Exited the boot services for the kernel");
+            Some(LoadedFile { addr: 1, len: 0 })
+        }
+        Err(err) => {
+            log::error!("Failed to exit the boot services: {:?}", err.status());
+            None
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use bootloader_api::BootloaderConfig;
+use core::sync::atomic::{AtomicU16, Ordering};
+use uefi::{
+    prelude::{Boot, SystemTable},
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Event,
+};
+
+/// The deadline in seconds, see the `network_load_deadline` config option.
+static SECONDS: AtomicU16 = AtomicU16::new(BootloaderConfig::new_default().network_load_deadline);
+
+/// Applies the deadline of the kernel config to the following loads.
+pub fn configure(config: &BootloaderConfig) {
+    SECONDS.store(config.network_load_deadline, Ordering::Relaxed);
+}
+
+/// A timer that expires once the network load deadline has passed.
+pub struct Deadline<'a> {
+    st: &'a SystemTable<Boot>,
+    /// The timer event, or `None` if the deadline is disabled or the timer could not be set.
+    event: Option<Event>,
+}
+
+impl<'a> Deadline<'a> {
+    /// Starts the timer for a new load.
+    pub fn start(st: &'a SystemTable<Boot>) -> Self {
+        let seconds = SECONDS.load(Ordering::Relaxed);
+        let boot_services = st.boot_services();
+        let event = if seconds == 0 {
+            None
+        } else {
+            unsafe { boot_services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) }
+                .ok()
+        };
+        let event = event.and_then(|event| {
+            // the trigger time is given in units of 100ns
+            let trigger = TimerTrigger::Relative(u64::from(seconds) * 10_000_000);
+            match boot_services.set_timer(&event, trigger) {
+                Ok(()) => Some(event),
+                Err(_) => {
+                    let _ = boot_services.close_event(event);
+                    None
+                }
+            }
+        });
+        Self { st, event }
+    }
+
+    /// Returns whether the deadline has passed.
+    ///
+    /// A disabled deadline passes immediately, so failed loads are not retried.
+    pub fn passed(&self) -> bool {
+        match &self.event {
+            Some(event) => self
+                .st
+                .boot_services()
+                .check_event(unsafe { event.unsafe_clone() })
+                .unwrap_or(true),
+            None => true,
+        }
+    }
+}
+
+impl Drop for Deadline<'_> {
+    fn drop(&mut self) {
+        if let Some(event) = self.event.take() {
+            let _ = self.st.boot_services().close_event(event);
+        }
+    }
+}
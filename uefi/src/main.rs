@@ -1,27 +1,44 @@
 #![no_std]
 #![no_main]
 #![feature(abi_efiapi)]
+#![feature(negative_impls)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use crate::memory_descriptor::UefiMemoryDescriptor;
-use bootloader_api::{info::FrameBufferInfo, BootloaderConfig};
+use crate::{deadline::Deadline, memory_descriptor::UefiMemoryDescriptor};
+use bootloader_api::{
+    info::{BootCounter, BootDevice, BootInfoAbi, FrameBufferInfo, PartitionSignature},
+    BootloaderConfig,
+};
 use bootloader_x86_64_common::{
-    legacy_memory_region::LegacyFrameAllocator, Kernel, RawFrameBufferInfo, SystemInfo,
+    boot_metadata_file_name,
+    boot_script::Key,
+    compression,
+    error::{self, BootError, BootStage},
+    heap::Heap,
+    legacy_memory_region::LegacyFrameAllocator,
+    logger::{self, Event},
+    messages, power, stack, verify_boot_metadata, Kernel, RawFrameBufferInfo, SystemInfo,
+    MAX_BOOT_METADATA_FILE_NAME_LEN,
 };
 use core::{
     cell::UnsafeCell,
     fmt::Write,
+    mem,
     ops::{Deref, DerefMut},
     ptr, slice,
 };
 use uefi::{
     prelude::{entry, Boot, Handle, Status, SystemTable},
     proto::{
-        console::gop::{GraphicsOutput, PixelFormat},
-        device_path::DevicePath,
+        console::gop::{GraphicsOutput, Mode, PixelFormat},
+        device_path::{
+            media,
+            text::{AllowShortcuts, DevicePathToText, DisplayOnly},
+            DevicePath, DevicePathNodeEnum,
+        },
         loaded_image::LoadedImage,
         media::{
-            file::{File, FileAttribute, FileInfo, FileMode},
+            file::{File, FileAttribute, FileInfo, FileMode, RegularFile},
             fs::SimpleFileSystem,
         },
         network::{
@@ -30,8 +47,12 @@ use uefi::{
         },
         ProtocolPointer,
     },
-    table::boot::{
-        AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol,
+    table::{
+        boot::{
+            AllocateType, MemoryDescriptor, MemoryType, OpenProtocolAttributes, OpenProtocolParams,
+            ScopedProtocol, SearchType,
+        },
+        cfg,
     },
     CStr16, CStr8,
 };
@@ -40,7 +61,34 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
+mod board_config;
+mod boot_counter;
+mod boot_log;
+mod boot_menu;
+mod boot_script;
+mod boot_slot;
+mod callback;
+mod deadline;
 mod memory_descriptor;
+mod network_fs;
+mod p9;
+mod pointer;
+mod rng;
+mod rollback;
+mod runtime;
+mod serial_load;
+mod signature;
+mod stub;
+mod tcp;
+mod tftp;
+mod usb_storage;
+mod virtio;
+mod watchdog;
+
+/// Describes the `BootInfo` layout for external tools, see [`BootInfoAbi`].
+#[used]
+#[link_section = ".bootabi"]
+static BOOT_INFO_ABI: [u8; BootInfoAbi::SERIALIZED_LEN] = BootInfoAbi::current().serialize();
 
 static SYSTEM_TABLE: RacyCell<Option<SystemTable<Boot>>> = RacyCell::new(None);
 
@@ -64,6 +112,8 @@ impl<T> core::ops::Deref for RacyCell<T> {
 
 #[entry]
 fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
+    // the UEFI specification guarantees 128KiB of stack to applications
+    unsafe { stack::poison_below(128 * 1024) };
     main_inner(image, st)
 }
 
@@ -72,15 +122,143 @@ fn main_inner(image: Handle, mut st: SystemTable<Boot>) -> Status {
     unsafe {
         *SYSTEM_TABLE.get() = Some(st.unsafe_clone());
     }
+    error::set_stage(BootStage::Uefi);
+    runtime::register_reset(&st);
+    // replace the 5 minute timeout of the firmware before loading anything
+    watchdog::restart(&st);
     st.stdout().clear().unwrap();
     writeln!(
         st.stdout(),
         "UEFI bootloader started; trying to load kernel"
     )
     .unwrap();
+    writeln!(
+        st.stdout(),
+        "Press F1 to toggle the high-contrast, large-text display"
+    )
+    .unwrap();
+
+    boot_log::start(image, &mut st);
+    let board_config = board_config::load(image, &mut st);
+
+    let mut keys = boot_script::PressedKeys::default();
+    let mut kernel_file = "kernel-x86_64";
+    let mut ramdisk_file = Some("ramdisk\0");
+    let mut boot_slot = None;
 
-    let mut boot_mode = BootMode::Disk;
+    // a kernel that is embedded into the bootloader executable takes precedence
+    let mut boot_mode = BootMode::Stub;
     let mut kernel = load_kernel(image, &mut st, boot_mode);
+    if kernel.is_none() && file_exists_on_disk("serial-load\0", image, &st) {
+        writeln!(
+            st.stdout(),
+            "Developer mode: waiting for the kernel on serial"
+        )
+        .unwrap();
+        boot_mode = BootMode::Serial;
+        kernel = load_kernel(image, &mut st, boot_mode);
+    }
+    if kernel.is_none() {
+        match load_file_from_disk("9p-server\0", image, &st).map(|c| p9::Server::parse(c)) {
+            Some(Some(server)) => {
+                writeln!(
+                    st.stdout(),
+                    "Developer mode: loading the kernel from 9P server {server:?}"
+                )
+                .unwrap();
+                boot_mode = BootMode::P9(server);
+                kernel = load_kernel(image, &mut st, boot_mode);
+            }
+            Some(None) => writeln!(st.stdout(), "Ignoring invalid `9p-server` file").unwrap(),
+            None => {}
+        }
+    }
+    if kernel.is_none() {
+        boot_mode = BootMode::Disk;
+        if let Some(slot) = boot_slot::select(&st) {
+            kernel = load_file_from_disk(boot_slot::kernel_file(slot.booted), image, &st)
+                .map(|k| parse_kernel(image, &mut st, k));
+            match kernel {
+                Some(_) => {
+                    kernel_file = boot_slot::kernel_file(slot.booted);
+                    ramdisk_file = Some(boot_slot::ramdisk_file(slot.booted));
+                    if let Err(err) = boot_slot::commit(&st, &slot) {
+                        writeln!(
+                            st.stdout(),
+                            "Failed to update the boot slot: {:?}",
+                            err.status()
+                        )
+                        .unwrap();
+                    }
+                    boot_slot = Some(slot);
+                }
+                None => writeln!(
+                    st.stdout(),
+                    "Kernel of boot slot {:?} not found",
+                    slot.booted
+                )
+                .unwrap(),
+            }
+        }
+    }
+    if kernel.is_none() {
+        if let Some(entry) = boot_script::run(image, &mut st, &mut keys) {
+            kernel = load_file_from_disk(entry.kernel, image, &st)
+                .map(|k| parse_kernel(image, &mut st, k));
+            match kernel {
+                Some(_) => {
+                    kernel_file = entry.kernel;
+                    ramdisk_file = entry.ramdisk;
+                }
+                None => writeln!(
+                    st.stdout(),
+                    "Kernel `{}` selected by the boot script not found",
+                    entry.kernel
+                )
+                .unwrap(),
+            }
+        }
+    }
+    if kernel.is_none() {
+        if let Some(entry) = boot_menu::run(image, &mut st) {
+            if entry.usb {
+                match usb_storage::find(image, &st) {
+                    Some(handle) => boot_mode = BootMode::Usb(handle),
+                    None => writeln!(st.stdout(), "No USB storage device found").unwrap(),
+                }
+            }
+            let files = entry.files;
+            kernel = match boot_mode {
+                BootMode::Usb(_) => {
+                    load_file_from_boot_method(image, &mut st, files.kernel, boot_mode)
+                }
+                _ => load_file_from_disk(files.kernel, image, &st),
+            }
+            .map(|k| parse_kernel(image, &mut st, k));
+            match kernel {
+                Some(_) => {
+                    kernel_file = files.kernel;
+                    ramdisk_file = files.ramdisk;
+                }
+                None => {
+                    writeln!(
+                        st.stdout(),
+                        "Kernel `{}` selected in the boot menu not found",
+                        files.kernel
+                    )
+                    .unwrap();
+                    boot_mode = BootMode::Disk;
+                }
+            }
+        }
+    }
+    if kernel.is_none() {
+        kernel = load_kernel(image, &mut st, boot_mode);
+    }
+    if kernel.is_none() {
+        boot_mode = BootMode::NetworkFs;
+        kernel = load_kernel(image, &mut st, boot_mode);
+    }
     if kernel.is_none() {
         writeln!(
             st.stdout(),
@@ -92,10 +270,46 @@ fn main_inner(image: Handle, mut st: SystemTable<Boot>) -> Status {
         boot_mode = BootMode::Tftp;
         kernel = load_kernel(image, &mut st, boot_mode);
     }
-    let kernel = kernel.expect("Failed to load kernel");
+    let kernel = kernel.unwrap_or_else(|| fail(BootError::KernelNotFound));
+    let (mut kernel, boot_counter) = apply_boot_counter(image, &mut st, kernel, boot_mode);
+    if let Some(board_config) = &board_config {
+        if signature::has_trusted_keys(image, &mut st) {
+            // board configs are not signed, so they must not change the config of signed kernels
+            writeln!(
+                st.stdout(),
+                "Ignoring board config overrides of signed kernel"
+            )
+            .unwrap();
+        } else {
+            board_config.apply(&mut kernel.config);
+        }
+    }
+    if boot_counter.map_or(false, |counter| counter.recovery) {
+        kernel_file = "kernel-recovery-x86_64";
+    }
+    boot_log::set_entry(&st, boot_mode, kernel_file);
+    error::configure(&kernel.config);
+    rollback::check(
+        &mut st,
+        &kernel.config,
+        boot_slot.as_ref().map_or(false, boot_slot::is_trial),
+    );
+    watchdog::configure(&st, &kernel.config);
+    deadline::configure(&kernel.config);
+    rng::collect_entropy(image, &st, &kernel.config);
+    if let Some(info) = load_file_from_boot_method(image, &mut st, "support-info\0", boot_mode) {
+        match core::str::from_utf8(info) {
+            Ok(info) => error::set_support_info(info),
+            Err(_) => writeln!(st.stdout(), "Ignoring support info that is not UTF-8").unwrap(),
+        }
+    }
     writeln!(st.stdout(), "Trying to load ramdisk via {:?}", boot_mode).unwrap();
     // Ramdisk must load from same source, or not at all.
-    let ramdisk = load_ramdisk(image, &mut st, boot_mode);
+    let ramdisk = ramdisk_file.and_then(|name| load_ramdisk(image, &mut st, name, boot_mode));
+    let ramdisk = match (ramdisk, kernel.config.dma_address_limit) {
+        (Some(ramdisk), Some(limit)) => Some(place_below(&st, ramdisk, limit, "the ramdisk")),
+        (ramdisk, _) => ramdisk,
+    };
 
     writeln!(
         st.stdout(),
@@ -107,70 +321,87 @@ fn main_inner(image: Handle, mut st: SystemTable<Boot>) -> Status {
     )
     .unwrap();
 
-    let framebuffer = init_logger(image, &st, kernel.config);
+    let mut heap = create_heap(&st, kernel.config.bootloader_heap_size);
+
+    // The metadata block is optional and only used for verification, so it is loaded from
+    // the same source as the kernel. Every kernel file has its own block.
+    let boot_metadata = match boot_mode {
+        BootMode::Serial => {
+            writeln!(
+                st.stdout(),
+                "Kernel received over serial has no boot metadata, so it gets no command line \
+                and extra mappings"
+            )
+            .unwrap();
+            None
+        }
+        _ => boot_metadata_file(&mut st, kernel_file)
+            .and_then(|name| load_file_from_boot_method(image, &mut st, name, boot_mode)),
+    };
+
+    let boot_device = match boot_mode {
+        BootMode::Stub | BootMode::Disk | BootMode::Serial => boot_device(image, &st, &mut heap),
+        BootMode::NetworkFs | BootMode::P9(_) | BootMode::Tftp | BootMode::Usb(_) => None,
+    };
+
+    let mut config = kernel.config;
+    keys.update(&mut st);
+    if keys.contains(Key::Function(1)) {
+        config.accessible_display = !config.accessible_display;
+    }
+    let framebuffer = init_logger(image, &st, config);
     unsafe {
         *SYSTEM_TABLE.get() = None;
     }
+    logger::event(Event::Stage("uefi"));
     log::info!("UEFI bootloader started");
     log::info!("Reading kernel and configuration from disk was successful");
     if let Some(framebuffer) = framebuffer {
         log::info!("Using framebuffer at {:#x}", framebuffer.addr);
     }
-    let mmap_storage = {
-        let mut memory_map_size = st.boot_services().memory_map_size();
-        loop {
-            let ptr = st
-                .boot_services()
-                .allocate_pool(MemoryType::LOADER_DATA, memory_map_size.map_size)
-                .expect("Failed to allocate memory for mmap storage");
-
-            let storage = unsafe { slice::from_raw_parts_mut(ptr, memory_map_size.map_size) };
-
-            if st.boot_services().memory_map(storage).is_ok() {
-                break storage;
-            }
-
-            // By measuring the size here, we can find out exactly how much we need.
-            // We may hit this code twice, if the map allocation ends up spanning more pages.
-            memory_map_size = st.boot_services().memory_map_size();
-            // allocated memory region was not big enough -> free it again
-            st.boot_services()
-                .free_pool(ptr)
-                .expect("Failed to free temporary memory for memory map!");
-        }
+    let boot_metadata = boot_metadata.and_then(|raw| verify_boot_metadata(raw, &kernel));
+    let (ramdisk_addr, ramdisk_len) = match ramdisk {
+        Some(rd) => (Some(rd.as_ptr() as usize as u64), rd.len() as u64),
+        None => (None, 0),
+    };
+    let mut system_info = SystemInfo {
+        framebuffer,
+        rsdp_addr: rsdp_addr(st.config_table()),
+        smbios_addr: smbios_addr(st.config_table()),
+        ramdisk_addr,
+        ramdisk_len,
+        boot_device,
+        boot_metadata,
+        bootloader_heap: None,
+        boot_counter,
+        boot_slot,
+        partition_keys: None,
+        uefi_runtime: None,
+        uefi_callback: None,
     };
 
+    watchdog::disable(&st);
+    if kernel.config.uefi_callback {
+        callback::switch_to_kernel(image, st, boot_mode, heap, kernel, system_info);
+    }
+
+    let mmap_storage = memory_map_storage(&st, &mut heap, 0);
     log::trace!("exiting boot services");
     let (system_table, memory_map) = st
         .exit_boot_services(image, mmap_storage)
-        .expect("Failed to exit boot services");
+        .unwrap_or_else(|_| error::fail(BootError::ExitBootServicesFailed));
 
+    for descriptor in memory_map.clone() {
+        log::trace!("{descriptor:x?}");
+    }
+    let uefi_runtime = runtime::collect(&system_table, memory_map.clone());
     let mut frame_allocator =
         LegacyFrameAllocator::new(memory_map.copied().map(UefiMemoryDescriptor));
+    frame_allocator.prefer_frames_above(PhysAddr::new(kernel.config.min_frame_address));
 
     let page_tables = create_page_tables(&mut frame_allocator);
-    let mut ramdisk_len = 0u64;
-    let ramdisk_addr = if let Some(rd) = ramdisk {
-        ramdisk_len = rd.len() as u64;
-        Some(rd.as_ptr() as usize as u64)
-    } else {
-        None
-    };
-    let system_info = SystemInfo {
-        framebuffer,
-        rsdp_addr: {
-            use uefi::table::cfg;
-            let mut config_entries = system_table.config_table().iter();
-            // look for an ACPI2 RSDP first
-            let acpi2_rsdp = config_entries.find(|entry| matches!(entry.guid, cfg::ACPI2_GUID));
-            // if no ACPI2 RSDP is found, look for a ACPI1 RSDP
-            let rsdp = acpi2_rsdp
-                .or_else(|| config_entries.find(|entry| matches!(entry.guid, cfg::ACPI_GUID)));
-            rsdp.map(|entry| PhysAddr::new(entry.address as u64))
-        },
-        ramdisk_addr: ramdisk_addr,
-        ramdisk_len: ramdisk_len,
-    };
+    system_info.bootloader_heap = Some(heap.usage());
+    system_info.uefi_runtime = Some(uefi_runtime);
 
     bootloader_x86_64_common::load_and_switch_to_kernel(
         kernel,
@@ -182,16 +413,84 @@ fn main_inner(image: Handle, mut st: SystemTable<Boot>) -> Status {
 
 #[derive(Clone, Copy, Debug)]
 pub enum BootMode {
+    /// The files are embedded as PE sections into the bootloader executable.
+    Stub,
     Disk,
+    /// The kernel is received over a serial I/O device, the other files are loaded from disk.
+    Serial,
+    /// The files are loaded from a file system that the firmware provides on a network device.
+    NetworkFs,
+    /// The files are loaded over TCP from the 9P server given in the `9p-server` file.
+    P9(p9::Server),
     Tftp,
+    /// The files are loaded from the USB mass-storage device with the given file system
+    /// handle, which was chosen in the boot menu.
+    Usb(Handle),
 }
 
 fn load_ramdisk(
     image: Handle,
     st: &mut SystemTable<Boot>,
+    name: &'static str,
     boot_mode: BootMode,
 ) -> Option<&'static mut [u8]> {
-    load_file_from_boot_method(image, st, "ramdisk\0", boot_mode)
+    load_file_from_boot_method(image, st, name, boot_mode)
+}
+
+/// Counts the boot attempt if the `boot_failure_limit` config option of the kernel is set.
+///
+/// Once the number of failed boots reaches the limit, the recovery kernel is loaded from the
+/// same source instead. If there is no recovery kernel, the regular kernel is started.
+fn apply_boot_counter(
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+    kernel: Kernel<'static>,
+    boot_mode: BootMode,
+) -> (Kernel<'static>, Option<BootCounter>) {
+    let Some(limit) = kernel.config.boot_failure_limit else {
+        return (kernel, None);
+    };
+
+    let failed_boots = boot_counter::read(st);
+    if let Err(err) = boot_counter::write(st, failed_boots.saturating_add(1)) {
+        writeln!(
+            st.stdout(),
+            "Failed to update the boot counter: {:?}",
+            err.status()
+        )
+        .unwrap();
+        return (kernel, None);
+    }
+    if failed_boots < limit {
+        let counter = BootCounter {
+            failed_boots,
+            limit,
+            recovery: false,
+        };
+        return (kernel, Some(counter));
+    }
+
+    writeln!(
+        st.stdout(),
+        "{failed_boots} failed boots, trying to load recovery kernel"
+    )
+    .unwrap();
+    let recovery_kernel =
+        load_file_from_boot_method(image, st, "kernel-recovery-x86_64\0", boot_mode);
+    let recovery = recovery_kernel.is_some();
+    let kernel = match recovery_kernel {
+        Some(slice) => parse_kernel(image, st, slice),
+        None => {
+            writeln!(st.stdout(), "Recovery kernel not found").unwrap();
+            kernel
+        }
+    };
+    let counter = BootCounter {
+        failed_boots,
+        limit,
+        recovery,
+    };
+    (kernel, Some(counter))
 }
 
 fn load_kernel(
@@ -199,22 +498,133 @@ fn load_kernel(
     st: &mut SystemTable<Boot>,
     boot_mode: BootMode,
 ) -> Option<Kernel<'static>> {
-    let kernel_slice = load_file_from_boot_method(image, st, "kernel-x86_64\0", boot_mode)?;
-    Some(Kernel::parse(kernel_slice))
+    let kernel_slice = match boot_mode {
+        BootMode::Serial => serial_load::receive_kernel(image, st)?,
+        _ => load_file_from_boot_method(image, st, "kernel-x86_64\0", boot_mode)?,
+    };
+    Some(parse_kernel(image, st, kernel_slice))
+}
+
+/// Parses the given kernel, after decompressing it if it was compressed by the disk image
+/// builder.
+///
+/// The signature is checked on the loaded bytes first, so that nothing of an untrusted kernel
+/// is decompressed or parsed. All boot sources must load their kernels through this function.
+fn parse_kernel(
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+    kernel: &'static [u8],
+) -> Kernel<'static> {
+    if let Err(reason) = signature::verify(kernel, image, st) {
+        fail(BootError::UntrustedKernel(reason));
+    }
+    // compressed kernels are signed after compression
+    let compressed = bootloader_x86_64_common::signature::content(kernel);
+    let Some(len) = compression::decompressed_len(compressed) else {
+        return Kernel::parse(kernel);
+    };
+    let ptr = st
+        .boot_services()
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            ((len.max(1) - 1) / 4096) + 1,
+        )
+        .unwrap_or_else(|_| {
+            fail_with_details(
+                BootError::OutOfMemory("the decompressed kernel"),
+                &format_args!("{len} bytes"),
+            )
+        }) as *mut u8;
+    let out = unsafe { slice::from_raw_parts_mut(ptr, len) };
+    if let Err(reason) = compression::decompress(compressed, out) {
+        fail(BootError::InvalidKernel(reason));
+    }
+    Kernel::parse(out)
+}
+
+/// Returns the name of the boot metadata file of the given kernel file.
+fn boot_metadata_file(st: &mut SystemTable<Boot>, kernel_file: &str) -> Option<&'static str> {
+    let buf = st
+        .boot_services()
+        .allocate_pool(MemoryType::LOADER_DATA, MAX_BOOT_METADATA_FILE_NAME_LEN)
+        .ok()?;
+    let buf = unsafe { &mut *buf.cast::<[u8; MAX_BOOT_METADATA_FILE_NAME_LEN]>() };
+    let name = boot_metadata_file_name(kernel_file, buf);
+    if name.is_none() {
+        writeln!(
+            st.stdout(),
+            "Kernel file name `{kernel_file}` is too long for a boot metadata file"
+        )
+        .unwrap();
+    }
+    name
 }
 
 fn load_file_from_boot_method(
     image: Handle,
     st: &mut SystemTable<Boot>,
-    filename: &str,
+    filename: &'static str,
     boot_mode: BootMode,
 ) -> Option<&'static mut [u8]> {
     match boot_mode {
-        BootMode::Disk => load_file_from_disk(filename, image, st),
+        BootMode::Stub => stub::load_file_from_image(filename, image, st),
+        BootMode::Disk | BootMode::Serial => load_file_from_disk(filename, image, st),
+        BootMode::NetworkFs => network_fs::load_file(filename, image, st),
+        BootMode::P9(server) => p9::load_file(&server, filename, image, st),
         BootMode::Tftp => load_file_from_tftp_boot_server(filename, image, st),
+        BootMode::Usb(handle) => usb_storage::load_file(handle, filename, image, st),
     }
 }
 
+/// Allocates a buffer for the current memory map from the bootloader heap.
+///
+/// The buffer has room for `extra` more bytes after the memory map.
+fn memory_map_storage(st: &SystemTable<Boot>, heap: &mut Heap, extra: usize) -> &'static mut [u8] {
+    let mut memory_map_size = st.boot_services().memory_map_size();
+    loop {
+        let storage = heap.allocate(
+            memory_map_size.map_size + extra,
+            mem::align_of::<MemoryDescriptor>(),
+            "the memory map",
+        );
+
+        if st.boot_services().memory_map(storage).is_ok() {
+            return storage;
+        }
+
+        // The heap memory is already part of the memory map, so the map should only
+        // change if the firmware allocated memory in the meantime.
+        memory_map_size = st.boot_services().memory_map_size();
+        // allocated memory region was not big enough -> free it again
+        heap.deallocate(storage);
+    }
+}
+
+fn rsdp_addr(config_entries: &[cfg::ConfigTableEntry]) -> Option<PhysAddr> {
+    let mut config_entries = config_entries.iter();
+    // look for an ACPI2 RSDP first
+    let acpi2_rsdp = config_entries.find(|entry| matches!(entry.guid, cfg::ACPI2_GUID));
+    // if no ACPI2 RSDP is found, look for a ACPI1 RSDP
+    let rsdp =
+        acpi2_rsdp.or_else(|| config_entries.find(|entry| matches!(entry.guid, cfg::ACPI_GUID)));
+    rsdp.map(|entry| PhysAddr::new(entry.address as u64))
+}
+
+/// Finds the SMBIOS entry point in the UEFI configuration table.
+fn smbios_addr(config_entries: &[cfg::ConfigTableEntry]) -> Option<PhysAddr> {
+    // prefer the 64-bit SMBIOS 3 entry point
+    let smbios3 = config_entries
+        .iter()
+        .find(|entry| matches!(entry.guid, cfg::SMBIOS3_GUID));
+    let smbios = smbios3.or_else(|| {
+        config_entries
+            .iter()
+            .find(|entry| matches!(entry.guid, cfg::SMBIOS_GUID))
+    });
+    smbios.map(|entry| PhysAddr::new(entry.address as u64))
+}
+
 fn open_device_path_protocol(
     image: Handle,
     st: &SystemTable<Boot>,
@@ -291,20 +701,151 @@ fn locate_and_open_protocol<P: ProtocolPointer>(
     Some(opened_handle.unwrap())
 }
 
+/// Identifies the disk partition that the bootloader was loaded from.
+///
+/// Returns `None` if the device path of the boot device contains no hard drive node.
+fn boot_device(image: Handle, st: &SystemTable<Boot>, heap: &mut Heap) -> Option<BootDevice> {
+    let device_path = open_device_path_protocol(image, st)?;
+    let hard_drive = device_path
+        .node_iter()
+        .find_map(|node| match node.as_enum() {
+            Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => Some(hard_drive),
+            _ => None,
+        })?;
+    let partition_signature = match hard_drive.partition_signature() {
+        media::PartitionSignature::Mbr(signature) => {
+            PartitionSignature::Mbr(u32::from_le_bytes(signature))
+        }
+        media::PartitionSignature::Guid(guid) => PartitionSignature::Gpt(guid.to_bytes()),
+        _ => PartitionSignature::None,
+    };
+
+    Some(BootDevice {
+        partition_number: hard_drive.partition_number(),
+        partition_signature,
+        device_path: device_path_text(image, st, heap, &device_path)
+            .map(Into::into)
+            .into(),
+    })
+}
+
+/// Returns the textual representation of the given device path.
+///
+/// The string is converted to UTF-8 and stored on the bootloader heap, so it stays
+/// accessible after exiting boot services.
+fn device_path_text(
+    image: Handle,
+    st: &SystemTable<Boot>,
+    heap: &mut Heap,
+    device_path: &DevicePath,
+) -> Option<&'static str> {
+    let to_text_handle = st
+        .boot_services()
+        .get_handle_for_protocol::<DevicePathToText>()
+        .ok()?;
+    let to_text = unsafe {
+        st.boot_services()
+            .open_protocol::<DevicePathToText>(
+                OpenProtocolParams {
+                    handle: to_text_handle,
+                    agent: image,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+            .ok()?
+    };
+    let text = to_text
+        .convert_device_path_to_text(
+            st.boot_services(),
+            device_path,
+            DisplayOnly(false),
+            AllowShortcuts(false),
+        )
+        .ok()?;
+
+    let len = text.iter().map(|&c| char::from(c).len_utf8()).sum();
+    let buf = heap.allocate(len, 1, "the boot device path");
+    let mut offset = 0;
+    for &c in text.iter() {
+        offset += char::from(c).encode_utf8(&mut buf[offset..]).len();
+    }
+    core::str::from_utf8(buf).ok()
+}
+
+/// Returns whether the given file exists on the boot partition.
+fn file_exists_on_disk(name: &str, image: Handle, st: &SystemTable<Boot>) -> bool {
+    let Some(mut file_system) = locate_and_open_protocol::<SimpleFileSystem>(image, st) else {
+        return false;
+    };
+    let Ok(mut root) = file_system.open_volume() else {
+        return false;
+    };
+    let mut buf = [0u16; 256];
+    let filename = CStr16::from_str_with_buf(name.trim_end_matches('\0'), &mut buf)
+        .expect("Failed to convert string to utf16");
+    root.open(filename, FileMode::Read, FileAttribute::empty())
+        .is_ok()
+}
+
 fn load_file_from_disk(
     name: &str,
     image: Handle,
     st: &SystemTable<Boot>,
 ) -> Option<&'static mut [u8]> {
+    let is_virtio = {
+        let device_path = open_device_path_protocol(image, st)?;
+        virtio::is_virtio_storage(image, st, device_path.deref())
+    };
+
     let mut file_system_raw = locate_and_open_protocol::<SimpleFileSystem>(image, st)?;
-    let file_system = file_system_raw.deref_mut();
+    read_file(file_system_raw.deref_mut(), name, st, is_virtio)
+}
 
+/// Loads a file from the first local file system that contains it.
+///
+/// Used as fallback when a network load doesn't finish before its deadline.
+fn load_file_from_local_disks(
+    name: &str,
+    image: Handle,
+    st: &SystemTable<Boot>,
+) -> Option<&'static mut [u8]> {
+    let handles = st
+        .boot_services()
+        .locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())
+        .ok()?;
+    handles.handles().iter().find_map(|&handle| {
+        let mut file_system = unsafe {
+            st.boot_services()
+                .open_protocol::<SimpleFileSystem>(
+                    OpenProtocolParams {
+                        handle,
+                        agent: image,
+                        controller: None,
+                    },
+                    OpenProtocolAttributes::Exclusive,
+                )
+                .ok()?
+        };
+        read_file(file_system.deref_mut(), name, st, false)
+    })
+}
+
+/// Reads the given file from the root directory of the file system into new memory.
+///
+/// Returns `None` if the file doesn't exist, is a directory, or can't be read completely.
+fn read_file(
+    file_system: &mut SimpleFileSystem,
+    name: &str,
+    st: &SystemTable<Boot>,
+    is_virtio: bool,
+) -> Option<&'static mut [u8]> {
     let mut root = file_system.open_volume().unwrap();
     let mut buf = [0u16; 256];
     assert!(name.len() < 256);
-    let filename = CStr16::from_str_with_buf(name.trim_end_matches('\0'), &mut buf)
-        .expect("Failed to convert string to utf16");
+    let filename = CStr16::from_str_with_buf(name.trim_end_matches('\0'), &mut buf).ok()?;
 
+    watchdog::restart(st);
     let file_handle_result = root.open(filename, FileMode::Read, FileAttribute::empty());
 
     let file_handle = match file_handle_result {
@@ -314,12 +855,15 @@ fn load_file_from_disk(
 
     let mut file = match file_handle.into_type().unwrap() {
         uefi::proto::media::file::FileType::Regular(f) => f,
-        uefi::proto::media::file::FileType::Dir(_) => panic!(),
+        uefi::proto::media::file::FileType::Dir(_) => return None,
     };
 
     let mut buf = [0; 500];
     let file_info: &mut FileInfo = file.get_info(&mut buf).unwrap();
     let file_size = usize::try_from(file_info.file_size()).unwrap();
+    if file_size == 0 {
+        return Some(&mut []);
+    }
 
     let file_ptr = st
         .boot_services()
@@ -328,21 +872,153 @@ fn load_file_from_disk(
             MemoryType::LOADER_DATA,
             ((file_size - 1) / 4096) + 1,
         )
-        .unwrap() as *mut u8;
-    unsafe { ptr::write_bytes(file_ptr, 0, file_size) };
+        .unwrap_or_else(|_| {
+            fail_with_details(
+                BootError::OutOfMemory("a boot file"),
+                &format_args!("{file_size} bytes for `{}`", name.trim_end_matches('\0')),
+            )
+        }) as *mut u8;
+    if is_virtio {
+        // virtio devices don't need the buffer to be zeroed up front
+        log::info!("Boot device is a virtio device, skipping the buffer initialization");
+    } else {
+        unsafe { ptr::write_bytes(file_ptr, 0, file_size) };
+    }
     let file_slice = unsafe { slice::from_raw_parts_mut(file_ptr, file_size) };
-    file.read(file_slice).unwrap();
+    if let Err(err) = read_to_end(&mut file, file_slice, st) {
+        log::error!(
+            "Failed to read `{}`: {:?}",
+            name.trim_end_matches('\0'),
+            err.status()
+        );
+        let _ = st
+            .boot_services()
+            .free_pages(file_ptr as u64, ((file_size - 1) / 4096) + 1);
+        return None;
+    }
 
     Some(file_slice)
 }
 
-/// Try to load a kernel from a TFTP boot server.
+/// Fills the given buffer from the current position of the file.
+///
+/// Each request asks for the whole remainder of the buffer. The buffer is page-aligned, so
+/// the firmware can issue aligned block reads that are as large as the device allows.
+/// Firmware may return fewer bytes than requested, so the read is repeated until the buffer
+/// is full. Fails if the file ends before that.
+fn read_to_end(
+    file: &mut RegularFile,
+    mut buffer: &mut [u8],
+    st: &SystemTable<Boot>,
+) -> uefi::Result {
+    while !buffer.is_empty() {
+        let read = file.read(buffer).map_err(|err| err.status())?;
+        if read == 0 {
+            return Err(Status::END_OF_FILE.into());
+        }
+        buffer = &mut buffer[read..];
+        watchdog::restart(st);
+    }
+    Ok(())
+}
+
+/// Copies the given file to memory that ends at or below `limit`, unless it already does.
+fn place_below(
+    st: &SystemTable<Boot>,
+    file: &'static mut [u8],
+    limit: u64,
+    purpose: &'static str,
+) -> &'static mut [u8] {
+    if file.as_ptr() as u64 + file.len() as u64 <= limit {
+        return file;
+    }
+    let ptr = st
+        .boot_services()
+        .allocate_pages(
+            AllocateType::MaxAddress(limit - 1),
+            MemoryType::LOADER_DATA,
+            ((file.len() - 1) / 4096) + 1,
+        )
+        .unwrap_or_else(|_| {
+            fail_with_details(
+                BootError::OutOfMemory(purpose),
+                &format_args!(
+                    "no {} contiguous bytes below the DMA address limit {limit:#x}",
+                    file.len()
+                ),
+            )
+        }) as *mut u8;
+    let copy = unsafe { slice::from_raw_parts_mut(ptr, file.len()) };
+    copy.copy_from_slice(file);
+    copy
+}
+
+/// Allocates the bootloader heap in `LOADER_DATA` memory, which the kernel can reuse.
+fn create_heap(st: &SystemTable<Boot>, size: u64) -> Heap {
+    let size = usize::try_from(size).expect("bootloader heap size does not fit into usize");
+    if size == 0 {
+        return Heap::new(&mut []);
+    }
+    let ptr = st
+        .boot_services()
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            ((size - 1) / 4096) + 1,
+        )
+        .unwrap_or_else(|_| fail(BootError::OutOfMemory("the bootloader heap")))
+        as *mut u8;
+    Heap::new(unsafe { slice::from_raw_parts_mut(ptr, size) })
+}
+
+/// Try to load a file from a TFTP boot server.
+///
+/// Failed transfers are retried until the network load deadline passes. After that, the file
+/// is loaded from a local file system, and the bootloader fails if there is no local copy.
 fn load_file_from_tftp_boot_server(
+    name: &'static str,
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+) -> Option<&'static mut [u8]> {
+    let deadline = Deadline::start(st);
+    let status = loop {
+        match try_load_file_from_tftp_boot_server(name, image, st) {
+            Ok(file) => return file,
+            Err(status) if deadline.passed() => break status,
+            Err(status) => {
+                log::warn!("TFTP transfer of `{name}` failed with {status:?}, retrying");
+                st.boot_services().stall(1_000_000);
+            }
+        }
+    };
+    drop(deadline);
+
+    let name = name.trim_end_matches('\0');
+    writeln!(
+        st.stdout(),
+        "Loading `{name}` over TFTP failed ({status:?}), looking for a local copy"
+    )
+    .unwrap();
+    match load_file_from_local_disks(name, image, st) {
+        Some(file) => Some(file),
+        None => fail_with_details(
+            BootError::LoadDeadlineExceeded(name),
+            &format_args!("the last TFTP transfer failed with {status:?}"),
+        ),
+    }
+}
+
+/// Loads a file from the TFTP boot server in a single attempt.
+///
+/// Returns `Ok(None)` if the server reports that the file doesn't exist.
+fn try_load_file_from_tftp_boot_server(
     name: &str,
     image: Handle,
     st: &SystemTable<Boot>,
-) -> Option<&'static mut [u8]> {
-    let mut base_code_raw = locate_and_open_protocol::<BaseCode>(image, st)?;
+) -> Result<Option<&'static mut [u8]>, Status> {
+    let Some(mut base_code_raw) = locate_and_open_protocol::<BaseCode>(image, st) else {
+        return Ok(None);
+    };
     let base_code = base_code_raw.deref_mut();
 
     // Find the TFTP boot server.
@@ -354,11 +1030,16 @@ fn load_file_from_tftp_boot_server(
 
     let filename = CStr8::from_bytes_with_nul(name.as_bytes()).unwrap();
 
-    // Determine the kernel file size.
-    let file_size = base_code.tftp_get_file_size(&server_ip, &filename).ok()?;
+    // Determine the file size.
+    let file_size = match base_code.tftp_get_file_size(&server_ip, &filename) {
+        Ok(size) => size,
+        // the server answered with an error packet, e.g. because the file doesn't exist
+        Err(err) if err.status() == Status::TFTP_ERROR => return Ok(None),
+        Err(err) => return Err(err.status()),
+    };
     let kernel_size = usize::try_from(file_size).expect("The file size should fit into usize");
 
-    // Allocate some memory for the kernel file.
+    // Allocate some memory for the file.
     let ptr = st
         .boot_services()
         .allocate_pages(
@@ -366,15 +1047,30 @@ fn load_file_from_tftp_boot_server(
             MemoryType::LOADER_DATA,
             ((kernel_size - 1) / 4096) + 1,
         )
-        .expect("Failed to allocate memory for the file") as *mut u8;
+        .unwrap_or_else(|_| {
+            fail_with_details(
+                BootError::OutOfMemory("a boot file"),
+                &format_args!("{kernel_size} bytes for `{}`", name.trim_end_matches('\0')),
+            )
+        }) as *mut u8;
     let slice = unsafe { slice::from_raw_parts_mut(ptr, kernel_size) };
 
-    // Load the kernel file.
-    base_code
-        .tftp_read_file(&server_ip, &filename, Some(slice))
-        .expect("Failed to read kernel file from the TFTP boot server");
+    // Load the file.
+    watchdog::restart(st);
+    if let Err(status) = tftp::read_file(
+        base_code,
+        &server_ip,
+        name.trim_end_matches('\0'),
+        slice,
+        st,
+    ) {
+        let _ = st
+            .boot_services()
+            .free_pages(ptr as u64, ((kernel_size - 1) / 4096) + 1);
+        return Err(status);
+    }
 
-    Some(slice)
+    Ok(Some(slice))
 }
 
 /// Creates page table abstraction types for both the bootloader and kernel page tables.
@@ -394,7 +1090,7 @@ fn create_page_tables(
         };
         let new_frame = frame_allocator
             .allocate_frame()
-            .expect("Failed to allocate frame for new level 4 table");
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the page tables")));
         let new_table: &mut PageTable = {
             let ptr: *mut PageTable =
                 (phys_offset + new_frame.start_address().as_u64()).as_mut_ptr();
@@ -422,7 +1118,9 @@ fn create_page_tables(
     // create a new page table hierarchy for the kernel
     let (kernel_page_table, kernel_level_4_frame) = {
         // get an unused frame for new level 4 page table
-        let frame: PhysFrame = frame_allocator.allocate_frame().expect("no unused frames");
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| error::fail(BootError::OutOfMemory("the page tables")));
         log::info!("New page table at: {:#?}", &frame);
         // get the corresponding virtual address
         let addr = phys_offset + frame.start_address().as_u64();
@@ -465,29 +1163,7 @@ fn init_logger(
             .ok()?
     };
 
-    let mode = {
-        let modes = gop.modes();
-        match (
-            config
-                .frame_buffer
-                .minimum_framebuffer_height
-                .map(|v| usize::try_from(v).unwrap()),
-            config
-                .frame_buffer
-                .minimum_framebuffer_width
-                .map(|v| usize::try_from(v).unwrap()),
-        ) {
-            (Some(height), Some(width)) => modes
-                .filter(|m| {
-                    let res = m.info().resolution();
-                    res.1 >= height && res.0 >= width
-                })
-                .last(),
-            (Some(height), None) => modes.filter(|m| m.info().resolution().1 >= height).last(),
-            (None, Some(width)) => modes.filter(|m| m.info().resolution().0 >= width).last(),
-            _ => None,
-        }
-    };
+    let mode = select_mode(&gop, &config);
     if let Some(mode) = mode {
         gop.set_mode(&mode)
             .expect("Failed to apply the desired display mode");
@@ -503,9 +1179,9 @@ fn init_logger(
         pixel_format: match mode_info.pixel_format() {
             PixelFormat::Rgb => bootloader_api::info::PixelFormat::Rgb,
             PixelFormat::Bgr => bootloader_api::info::PixelFormat::Bgr,
-            PixelFormat::Bitmask | PixelFormat::BltOnly => {
-                panic!("Bitmask and BltOnly framebuffers are not supported")
-            }
+            PixelFormat::Bitmask | PixelFormat::BltOnly => fail(BootError::UnsupportedFramebuffer(
+                "Bitmask and BltOnly framebuffers",
+            )),
         },
         bytes_per_pixel: 4,
         stride: mode_info.stride(),
@@ -513,13 +1189,8 @@ fn init_logger(
 
     log::info!("UEFI boot");
 
-    bootloader_x86_64_common::init_logger(
-        slice,
-        info,
-        config.log_level,
-        config.frame_buffer_logger_status,
-        config.serial_logger_status,
-    );
+    bootloader_x86_64_common::init_logger(slice, info, &config);
+    log::info!("Framebuffer mode: {}x{}", info.width, info.height);
 
     Some(RawFrameBufferInfo {
         addr: PhysAddr::new(framebuffer.as_mut_ptr() as u64),
@@ -527,23 +1198,107 @@ fn init_logger(
     })
 }
 
+/// Picks the graphics mode for the `frame_buffer` config, or `None` to keep the current mode.
+///
+/// Modes with a pixel format other than RGB or BGR and modes below the minimum resolution are
+/// never picked. If a preferred resolution or depth is set, the bootloader picks the mode
+/// whose resolution is closest to the preferred one, preferring the current resolution on
+/// ties. These modes all use 32 bits per pixel, so a different preferred depth can't be
+/// matched. With only minimums set, it picks the last mode in firmware order that satisfies
+/// them.
+fn select_mode(gop: &GraphicsOutput, config: &BootloaderConfig) -> Option<Mode> {
+    let frame_buffer = &config.frame_buffer;
+    let to_usize = |v: Option<u64>| v.map(|v| usize::try_from(v).unwrap_or(usize::MAX));
+    let (min_width, min_height) = (
+        to_usize(frame_buffer.minimum_framebuffer_width),
+        to_usize(frame_buffer.minimum_framebuffer_height),
+    );
+    let (width, height) = (
+        to_usize(frame_buffer.framebuffer_width),
+        to_usize(frame_buffer.framebuffer_height),
+    );
+
+    let modes = gop.modes().filter(|mode| {
+        let info = mode.info();
+        let (w, h) = info.resolution();
+        matches!(info.pixel_format(), PixelFormat::Rgb | PixelFormat::Bgr)
+            && min_width.map_or(true, |min| w >= min)
+            && min_height.map_or(true, |min| h >= min)
+    });
+    if width.is_none() && height.is_none() && frame_buffer.framebuffer_bpp.is_none() {
+        if min_width.is_none() && min_height.is_none() {
+            return None;
+        }
+        return modes.last();
+    }
+    let current = gop.current_mode_info().resolution();
+    modes.min_by_key(|mode| {
+        let (w, h) = mode.info().resolution();
+        let distance = |actual: usize, preferred: Option<usize>| {
+            preferred.map_or(0, |preferred| actual.abs_diff(preferred))
+        };
+        (distance(w, width) + distance(h, height), (w, h) != current)
+    })
+}
+
+/// Shows the given error on the error screen and, while boot services are active, on the UEFI
+/// console. Then waits until the user reboots or powers off the system.
+fn fail(error: BootError) -> ! {
+    print_error_to_console(error);
+    boot_log::set_error(error);
+    error::report(error, None);
+    wait_for_power_action()
+}
+
+/// Like [`fail`], but with additional details on the error screen.
+fn fail_with_details(error: BootError, details: &dyn core::fmt::Display) -> ! {
+    print_error_to_console(error);
+    boot_log::set_error(error);
+    error::report(error, Some(details));
+    wait_for_power_action()
+}
+
+/// Waits for the `R` or `P` key, see [`power::wait_for_action`].
+///
+/// While boot services are active, the firmware owns the keyboard and the serial port, so
+/// the keys are read from the UEFI console instead.
+fn wait_for_power_action() -> ! {
+    if let Some(st) = unsafe { &mut *SYSTEM_TABLE.get() } {
+        loop {
+            if let Ok(Some(uefi::proto::console::text::Key::Printable(c))) = st.stdin().read_key() {
+                match char::from(c).to_ascii_lowercase() {
+                    'r' => power::reboot(),
+                    'p' => power::power_off(),
+                    _ => {}
+                }
+            }
+            st.boot_services().stall(10_000);
+        }
+    }
+    power::wait_for_action()
+}
+
+fn print_error_to_console(error: BootError) {
+    if let Some(st) = unsafe { &mut *SYSTEM_TABLE.get() } {
+        let _ = writeln!(
+            st.stdout(),
+            "Boot error E{:04}: {}\n{}\n{}",
+            error.code(),
+            error,
+            error.hint(),
+            messages::ENGLISH.power
+        );
+    }
+}
+
 #[cfg(target_os = "uefi")]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    use core::arch::asm;
-
     if let Some(st) = unsafe { &mut *SYSTEM_TABLE.get() } {
         let _ = writeln!(st.stdout(), "{}", info);
     }
+    boot_log::set_error(BootError::Internal);
 
-    unsafe {
-        bootloader_x86_64_common::logger::LOGGER
-            .get()
-            .map(|l| l.force_unlock())
-    };
-    log::error!("{}", info);
-
-    loop {
-        unsafe { asm!("cli; hlt") };
-    }
+    error::report(BootError::Internal, Some(info));
+    wait_for_power_action()
 }
@@ -1,5 +1,6 @@
 use bootloader_api::info::MemoryRegionKind;
 use bootloader_x86_64_common::legacy_memory_region::LegacyMemoryRegion;
+use core::{mem::MaybeUninit, slice};
 use uefi::table::boot::{MemoryDescriptor, MemoryType};
 use x86_64::PhysAddr;
 
@@ -44,3 +45,77 @@ impl LegacyMemoryRegion for UefiMemoryDescriptor {
         }
     }
 }
+
+/// A region of the UEFI memory map while the boot services are still running, see
+/// [`BootloaderConfig::uefi_callback`](bootloader_api::BootloaderConfig::uefi_callback).
+///
+/// The firmware can still allocate memory at this point, so only the pool that the bootloader
+/// allocated for the kernel is usable. The bootloader memory stays reserved for the callback,
+/// and all other memory keeps its UEFI type.
+#[derive(Debug, Copy, Clone)]
+pub struct CallbackMemoryRegion {
+    start: u64,
+    len: u64,
+    ty: MemoryType,
+    pool: bool,
+}
+
+impl CallbackMemoryRegion {
+    /// Writes the regions of the given memory map to `out`, with the pool split out of the
+    /// region that contains it.
+    ///
+    /// `out` needs room for two more regions than the memory map has.
+    pub fn split<'a, 'b>(
+        memory_map: impl Iterator<Item = &'a MemoryDescriptor>,
+        pool: (PhysAddr, u64),
+        out: &'b mut [MaybeUninit<Self>],
+    ) -> &'b [Self] {
+        let (pool_start, pool_end) = (pool.0.as_u64(), pool.0.as_u64() + pool.1);
+        let mut len = 0;
+        let mut push = |start: u64, end: u64, ty: MemoryType, pool: bool| {
+            if start < end {
+                out[len].write(Self {
+                    start,
+                    len: end - start,
+                    ty,
+                    pool,
+                });
+                len += 1;
+            }
+        };
+        for descriptor in memory_map {
+            let start = descriptor.phys_start;
+            let end = start + descriptor.page_count * PAGE_SIZE;
+            if start <= pool_start && pool_end <= end {
+                push(start, pool_start, descriptor.ty, false);
+                push(pool_start, pool_end, descriptor.ty, true);
+                push(pool_end, end, descriptor.ty, false);
+            } else {
+                push(start, end, descriptor.ty, false);
+            }
+        }
+        unsafe { slice::from_raw_parts(out.as_ptr().cast(), len) }
+    }
+}
+
+impl LegacyMemoryRegion for CallbackMemoryRegion {
+    fn start(&self) -> PhysAddr {
+        PhysAddr::new(self.start)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn kind(&self) -> MemoryRegionKind {
+        match self.ty {
+            _ if self.pool => MemoryRegionKind::Usable,
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => MemoryRegionKind::Bootloader,
+            other => MemoryRegionKind::UnknownUefi(other.0),
+        }
+    }
+
+    fn usable_after_bootloader_exit(&self) -> bool {
+        false
+    }
+}
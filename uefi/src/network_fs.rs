@@ -0,0 +1,67 @@
+use crate::read_file;
+use core::ops::DerefMut;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::{
+        device_path::{DevicePath, DeviceSubType, DeviceType},
+        media::fs::SimpleFileSystem,
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams, SearchType},
+};
+
+/// Device path node types that identify a file system on a network device.
+const NETWORK_NODE_TYPES: [DeviceSubType; 4] = [
+    DeviceSubType::MESSAGING_MAC_ADDRESS,
+    DeviceSubType::MESSAGING_IPV4,
+    DeviceSubType::MESSAGING_IPV6,
+    DeviceSubType::MESSAGING_URI,
+];
+
+/// Loads a file from a file system that the firmware provides on a network device.
+///
+/// Some firmware exposes network file systems, e.g. NFS or HTTP boot shares, through the
+/// simple file system protocol. They are recognized by the network nodes in their device path.
+pub fn load_file(name: &str, image: Handle, st: &SystemTable<Boot>) -> Option<&'static mut [u8]> {
+    let handles = st
+        .boot_services()
+        .locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())
+        .ok()?;
+    handles
+        .handles()
+        .iter()
+        .filter(|&&handle| is_network_device(handle, image, st))
+        .find_map(|&handle| {
+            let mut file_system = unsafe {
+                st.boot_services()
+                    .open_protocol::<SimpleFileSystem>(
+                        OpenProtocolParams {
+                            handle,
+                            agent: image,
+                            controller: None,
+                        },
+                        OpenProtocolAttributes::Exclusive,
+                    )
+                    .ok()?
+            };
+            read_file(file_system.deref_mut(), name, st, false)
+        })
+}
+
+fn is_network_device(handle: Handle, image: Handle, st: &SystemTable<Boot>) -> bool {
+    let device_path = unsafe {
+        st.boot_services().open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+    let Ok(device_path) = device_path else {
+        return false;
+    };
+    device_path.node_iter().any(|node| {
+        node.device_type() == DeviceType::MESSAGING && NETWORK_NODE_TYPES.contains(&node.sub_type())
+    })
+}
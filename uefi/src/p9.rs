@@ -0,0 +1,367 @@
+use crate::{fail_with_details, tcp::TcpConnection, watchdog};
+use bootloader_x86_64_common::error::BootError;
+use core::{fmt, slice};
+use uefi::{
+    prelude::{Boot, Handle, Status, SystemTable},
+    table::boot::{AllocateType, MemoryType},
+};
+
+/// The port that 9P servers listen on by default.
+const DEFAULT_PORT: u16 = 564;
+/// The protocol version, which is the Linux variant of 9P that most servers speak.
+const VERSION: &[u8] = b"9P2000.L";
+/// The maximum message size that is proposed to the server.
+const MAX_MESSAGE_SIZE: u32 = 128 * 1024;
+
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+/// The tag of all requests, since there is only one request in flight at a time.
+const TAG: u16 = 1;
+const ROOT_FID: u32 = 0;
+const FILE_FID: u32 = 1;
+
+/// The size of the `size[4] type[1] tag[2]` header of all messages.
+const HEADER_LEN: usize = 7;
+/// The size of the header of `Rread` messages, including the `count[4]` field.
+const READ_HEADER_LEN: usize = HEADER_LEN + 4;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+/// The `P9_GETATTR_SIZE` bit of the `request_mask` of `Tgetattr`.
+const GETATTR_SIZE: u64 = 0x200;
+/// The offset of the `size` field in the body of `Rgetattr`.
+const GETATTR_SIZE_OFFSET: usize = 8 + 13 + 4 + 4 + 4 + 8 + 8;
+const O_RDONLY: u32 = 0;
+const ENOENT: u32 = 2;
+
+/// A 9P server that exports the boot files, as given in the `9p-server` file.
+#[derive(Clone, Copy)]
+pub struct Server {
+    address: [u8; 4],
+    port: u16,
+    /// The exported directory to attach to, empty for the default of the server.
+    export: &'static str,
+}
+
+impl Server {
+    /// Parses the contents of the `9p-server` file, e.g. `10.0.2.2:564 /srv/boot`.
+    ///
+    /// The port defaults to 564 and the export to the default of the server.
+    pub fn parse(contents: &'static [u8]) -> Option<Self> {
+        let mut fields = core::str::from_utf8(contents).ok()?.split_whitespace();
+        let address_and_port = fields.next()?;
+        let (address, port) = match address_and_port.split_once(':') {
+            Some((address, port)) => (address, port.parse().ok()?),
+            None => (address_and_port, DEFAULT_PORT),
+        };
+        let mut octets = address.split('.').map(|octet| octet.parse::<u8>());
+        let mut address = [0; 4];
+        for byte in &mut address {
+            *byte = octets.next()?.ok()?;
+        }
+        if octets.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            address,
+            port,
+            export: fields.next().unwrap_or_default(),
+        })
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.address;
+        write!(f, "{a}.{b}.{c}.{d}:{}", self.port)?;
+        if !self.export.is_empty() {
+            write!(f, " {}", self.export)?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads a file from the export of the given 9P server over TCP.
+///
+/// Returns `None` if the file doesn't exist or the server can't be reached.
+pub fn load_file(
+    server: &Server,
+    name: &str,
+    image: Handle,
+    st: &SystemTable<Boot>,
+) -> Option<&'static mut [u8]> {
+    let name = name.trim_end_matches('\0');
+    watchdog::restart(st);
+    let result = Client::connect(server, image, st).and_then(|mut client| client.load(name, st));
+    match result {
+        Ok(file) => Some(file),
+        Err(Status::NOT_FOUND) => None,
+        Err(status) => {
+            log::warn!("Failed to load `{name}` from 9P server {server:?}: {status:?}");
+            None
+        }
+    }
+}
+
+struct Client<'a> {
+    connection: TcpConnection<'a>,
+    /// The maximum size of a message, as negotiated with the server.
+    max_message_size: u32,
+}
+
+impl<'a> Client<'a> {
+    fn connect(server: &Server, image: Handle, st: &'a SystemTable<Boot>) -> Result<Self, Status> {
+        let connection = TcpConnection::connect(image, st, server.address, server.port)?;
+        let mut client = Self {
+            connection,
+            max_message_size: MAX_MESSAGE_SIZE,
+        };
+
+        let mut request = Message::new(TVERSION, NOTAG);
+        request.u32(MAX_MESSAGE_SIZE).bytes(VERSION);
+        let mut body = [0; 64];
+        let reply = client.request(&mut request, &mut body)?;
+        let max_message_size = read_u32(reply, 0)?;
+        if read_bytes(reply, 4)? != VERSION || (max_message_size as usize) <= READ_HEADER_LEN {
+            return Err(Status::INCOMPATIBLE_VERSION);
+        }
+        client.max_message_size = max_message_size.min(MAX_MESSAGE_SIZE);
+
+        let mut request = Message::new(TATTACH, TAG);
+        request
+            .u32(ROOT_FID)
+            .u32(NOFID)
+            .bytes(b"root")
+            .bytes(server.export.as_bytes())
+            .u32(0);
+        client.request(&mut request, &mut body)?;
+        Ok(client)
+    }
+
+    /// Reads the file with the given path into new memory.
+    fn load(&mut self, name: &str, st: &SystemTable<Boot>) -> Result<&'static mut [u8], Status> {
+        let mut body = [0; 256];
+
+        let mut request = Message::new(TWALK, TAG);
+        let components = name.split('/').filter(|c| !c.is_empty());
+        request
+            .u32(ROOT_FID)
+            .u32(FILE_FID)
+            .u16(components.clone().count() as u16);
+        for component in components.clone() {
+            request.bytes(component.as_bytes());
+        }
+        let reply = self.request(&mut request, &mut body)?;
+        // the server stops at the first component that doesn't exist
+        if usize::from(read_u16(reply, 0)?) != components.count() {
+            return Err(Status::NOT_FOUND);
+        }
+
+        let mut request = Message::new(TLOPEN, TAG);
+        request.u32(FILE_FID).u32(O_RDONLY);
+        let reply = self.request(&mut request, &mut body)?;
+        let io_unit = read_u32(reply, 13)?;
+
+        let mut request = Message::new(TGETATTR, TAG);
+        request.u32(FILE_FID).u64(GETATTR_SIZE);
+        let reply = self.request(&mut request, &mut body)?;
+        let file_size = usize::try_from(read_u64(reply, GETATTR_SIZE_OFFSET)?)
+            .map_err(|_| Status::BAD_BUFFER_SIZE)?;
+        if file_size == 0 {
+            return Err(Status::END_OF_FILE);
+        }
+
+        let pages = ((file_size - 1) / 4096) + 1;
+        let ptr = st
+            .boot_services()
+            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+            .unwrap_or_else(|_| {
+                fail_with_details(
+                    BootError::OutOfMemory("a boot file"),
+                    &format_args!("{file_size} bytes for `{name}`"),
+                )
+            }) as *mut u8;
+        let file = unsafe { slice::from_raw_parts_mut(ptr, file_size) };
+
+        let mut max_count = self.max_message_size as usize - READ_HEADER_LEN;
+        if io_unit != 0 {
+            max_count = max_count.min(io_unit as usize);
+        }
+        let mut offset = 0;
+        while offset < file_size {
+            let end = file_size.min(offset + max_count);
+            match self.read(offset as u64, &mut file[offset..end]) {
+                Ok(0) => {
+                    let _ = st.boot_services().free_pages(ptr as u64, pages);
+                    return Err(Status::END_OF_FILE);
+                }
+                Ok(count) => {
+                    offset += count;
+                    watchdog::restart(st);
+                }
+                Err(status) => {
+                    let _ = st.boot_services().free_pages(ptr as u64, pages);
+                    return Err(status);
+                }
+            }
+        }
+
+        let mut request = Message::new(TCLUNK, TAG);
+        request.u32(FILE_FID);
+        self.request(&mut request, &mut body)?;
+        Ok(file)
+    }
+
+    /// Reads from the open file at the given offset directly into `buffer`.
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, Status> {
+        let mut request = Message::new(TREAD, TAG);
+        request.u32(FILE_FID).u64(offset).u32(buffer.len() as u32);
+        self.connection.send(request.finish()?)?;
+
+        let body_len = self.receive_header(TREAD + 1)?;
+        let mut count = [0; 4];
+        self.connection.receive_exact(&mut count)?;
+        let count = u32::from_le_bytes(count) as usize;
+        if count > buffer.len() || body_len != 4 + count {
+            return Err(Status::PROTOCOL_ERROR);
+        }
+        self.connection.receive_exact(&mut buffer[..count])?;
+        Ok(count)
+    }
+
+    /// Sends the request and returns the body of the reply.
+    fn request<'b>(
+        &mut self,
+        request: &mut Message,
+        body: &'b mut [u8],
+    ) -> Result<&'b [u8], Status> {
+        // replies have the type of the request plus one
+        let reply_type = request.buffer[4] + 1;
+        self.connection.send(request.finish()?)?;
+        let body_len = self.receive_header(reply_type)?;
+        let body = body.get_mut(..body_len).ok_or(Status::PROTOCOL_ERROR)?;
+        self.connection.receive_exact(body)?;
+        Ok(body)
+    }
+
+    /// Receives the header of a reply and returns the length of its body.
+    ///
+    /// `Rlerror` replies are turned into errors, using `Status::NOT_FOUND` for `ENOENT`.
+    fn receive_header(&mut self, expected_type: u8) -> Result<usize, Status> {
+        let mut header = [0; HEADER_LEN];
+        self.connection.receive_exact(&mut header)?;
+        let size = read_u32(&header, 0)? as usize;
+        let body_len = size.checked_sub(HEADER_LEN).ok_or(Status::PROTOCOL_ERROR)?;
+        match header[4] {
+            RLERROR => {
+                let mut errno = [0; 4];
+                self.connection.receive_exact(&mut errno)?;
+                match u32::from_le_bytes(errno) {
+                    ENOENT => Err(Status::NOT_FOUND),
+                    errno => {
+                        log::warn!("9P server returned error {errno}");
+                        Err(Status::DEVICE_ERROR)
+                    }
+                }
+            }
+            ty if ty == expected_type => Ok(body_len),
+            _ => Err(Status::PROTOCOL_ERROR),
+        }
+    }
+}
+
+/// A request message, which is small enough for a fixed buffer.
+struct Message {
+    buffer: [u8; 512],
+    len: usize,
+    /// Set if a field didn't fit into the buffer.
+    overflow: bool,
+}
+
+impl Message {
+    fn new(ty: u8, tag: u16) -> Self {
+        let mut message = Self {
+            buffer: [0; 512],
+            len: 4,
+            overflow: false,
+        };
+        message.append(&[ty]).u16(tag);
+        message
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.append(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.append(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.append(&value.to_le_bytes())
+    }
+
+    /// Appends a string, which is prefixed with its length.
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        match u16::try_from(value.len()) {
+            Ok(len) => self.u16(len).append(value),
+            Err(_) => {
+                self.overflow = true;
+                self
+            }
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> &mut Self {
+        match self.buffer.get_mut(self.len..self.len + bytes.len()) {
+            Some(target) => {
+                target.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+        self
+    }
+
+    /// Fills in the size and returns the encoded message.
+    fn finish(&mut self) -> Result<&[u8], Status> {
+        if self.overflow {
+            return Err(Status::BAD_BUFFER_SIZE);
+        }
+        self.buffer[..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        Ok(&self.buffer[..self.len])
+    }
+}
+
+fn read_u16(body: &[u8], offset: usize) -> Result<u16, Status> {
+    read_array(body, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(body: &[u8], offset: usize) -> Result<u32, Status> {
+    read_array(body, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(body: &[u8], offset: usize) -> Result<u64, Status> {
+    read_array(body, offset).map(u64::from_le_bytes)
+}
+
+/// Reads a string, which is prefixed with its length.
+fn read_bytes(body: &[u8], offset: usize) -> Result<&[u8], Status> {
+    let len = usize::from(read_u16(body, offset)?);
+    body.get(offset + 2..offset + 2 + len)
+        .ok_or(Status::PROTOCOL_ERROR)
+}
+
+fn read_array<const N: usize>(body: &[u8], offset: usize) -> Result<[u8; N], Status> {
+    body.get(offset..)
+        .and_then(|rest| rest.get(..N))
+        .map(|slice| slice.try_into().unwrap())
+        .ok_or(Status::PROTOCOL_ERROR)
+}
@@ -0,0 +1,147 @@
+use uefi::{
+    prelude::{Boot, Handle, Status, SystemTable},
+    proto::{console::pointer::Pointer, Protocol},
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol},
+    unsafe_guid, Event,
+};
+
+/// Mouse movement in millimeters that moves the selection by one entry.
+const MM_PER_ENTRY: u64 = 5;
+
+/// The `EFI_ABSOLUTE_POINTER_PROTOCOL` of touchscreens and tablets.
+///
+/// The `uefi` crate only provides the simple pointer protocol, so this one is declared here.
+#[repr(C)]
+#[unsafe_guid("8d59d32b-c655-4ae9-9b15-f25904992a43")]
+#[derive(Protocol)]
+struct AbsolutePointer {
+    reset: unsafe extern "efiapi" fn(this: &mut AbsolutePointer, ext_verif: bool) -> Status,
+    get_state: unsafe extern "efiapi" fn(
+        this: &AbsolutePointer,
+        state: *mut AbsolutePointerState,
+    ) -> Status,
+    wait_for_input: Event,
+    mode: &'static AbsolutePointerMode,
+}
+
+#[repr(C)]
+struct AbsolutePointerMode {
+    min_x: u64,
+    min_y: u64,
+    min_z: u64,
+    max_x: u64,
+    max_y: u64,
+    max_z: u64,
+    attributes: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct AbsolutePointerState {
+    current_x: u64,
+    current_y: u64,
+    current_z: u64,
+    active_buttons: u32,
+}
+
+impl AbsolutePointer {
+    /// `EFI_ABSP_TouchActive`
+    const TOUCH_ACTIVE: u32 = 1;
+
+    fn read_state(&self) -> Option<AbsolutePointerState> {
+        let mut state = AbsolutePointerState::default();
+        let status = unsafe { (self.get_state)(self, &mut state) };
+        status.is_success().then_some(state)
+    }
+}
+
+/// An input of a pointer device, see [`Pointers::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerInput {
+    /// The mouse moved by the given number of entries, downwards if positive.
+    Move(isize),
+    /// The left mouse button was pressed.
+    Click,
+    /// The screen was touched on the given text row.
+    Touch(usize),
+}
+
+/// The mouse and touchscreen of the firmware console, if there are any.
+pub struct Pointers<'a> {
+    mouse: Option<ScopedProtocol<'a, Pointer<'a>>>,
+    touch: Option<ScopedProtocol<'a, AbsolutePointer>>,
+    /// Mouse movement that didn't add up to a whole entry yet.
+    movement: i64,
+    pressed: bool,
+    touched: bool,
+}
+
+impl<'a> Pointers<'a> {
+    /// Opens the first simple and absolute pointer devices.
+    ///
+    /// The firmware's console splitter usually combines all devices of each kind into one.
+    pub fn open(image: Handle, st: &'a SystemTable<Boot>) -> Self {
+        // mice without a vertical axis can't move the selection
+        let mouse = open::<Pointer>(image, st).filter(|mouse| mouse.mode().resolution.1 != 0);
+        Self {
+            mouse,
+            touch: open(image, st),
+            movement: 0,
+            pressed: false,
+            touched: false,
+        }
+    }
+
+    /// Returns the next input, if there is one.
+    ///
+    /// Touch positions are converted to the text rows of a console with the given height.
+    pub fn poll(&mut self, rows: usize) -> Option<PointerInput> {
+        if let Some(touch) = &self.touch {
+            if let Some(state) = touch.read_state() {
+                let touched = state.active_buttons & AbsolutePointer::TOUCH_ACTIVE != 0;
+                let new_touch = touched && !self.touched;
+                self.touched = touched;
+                let mode = touch.mode;
+                if new_touch && mode.max_y > mode.min_y {
+                    let y = state.current_y.clamp(mode.min_y, mode.max_y) - mode.min_y;
+                    let row = y * rows as u64 / (mode.max_y - mode.min_y + 1);
+                    return Some(PointerInput::Touch(row as usize));
+                }
+            }
+        }
+        if let Some(mouse) = &mut self.mouse {
+            if let Ok(Some(state)) = mouse.read_state() {
+                let pressed = state.button.0;
+                let click = pressed && !self.pressed;
+                self.pressed = pressed;
+                if click {
+                    self.movement = 0;
+                    return Some(PointerInput::Click);
+                }
+                let counts_per_entry = (mouse.mode().resolution.1 * MM_PER_ENTRY) as i64;
+                self.movement += i64::from(state.relative_movement.1);
+                let entries = self.movement / counts_per_entry;
+                if entries != 0 {
+                    self.movement -= entries * counts_per_entry;
+                    return Some(PointerInput::Move(entries as isize));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn open<P: Protocol>(image: Handle, st: &SystemTable<Boot>) -> Option<ScopedProtocol<'_, P>> {
+    let handle = st.boot_services().get_handle_for_protocol::<P>().ok()?;
+    let protocol = unsafe {
+        st.boot_services().open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+    protocol.ok()
+}
@@ -0,0 +1,38 @@
+use bootloader_api::BootloaderConfig;
+use bootloader_x86_64_common::entropy;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::rng::Rng,
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+};
+
+/// Adds random bytes of the firmware's `EFI_RNG_PROTOCOL` to the seed of the address space
+/// randomization, if the kernel config enables it.
+///
+/// Firmware without the protocol is skipped, the other entropy sources are still used.
+pub fn collect_entropy(image: Handle, st: &SystemTable<Boot>, config: &BootloaderConfig) {
+    if !config.mappings.aslr && !config.kernel_aslr {
+        return;
+    }
+    let Ok(handle) = st.boot_services().get_handle_for_protocol::<Rng>() else {
+        log::debug!("Firmware has no RNG protocol");
+        return;
+    };
+    let rng = unsafe {
+        st.boot_services().open_protocol::<Rng>(
+            OpenProtocolParams {
+                handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+    let mut seed = [0; 32];
+    match rng.map(|mut rng| rng.get_rng(None, &mut seed)) {
+        Ok(Ok(())) => entropy::set_firmware_entropy(seed),
+        Ok(Err(err)) | Err(err) => {
+            log::warn!("Failed to read the firmware RNG: {:?}", err.status())
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{boot_counter::VENDOR, fail_with_details};
+use bootloader_api::{config::RollbackProtection, BootloaderConfig};
+use bootloader_x86_64_common::error::BootError;
+use core::fmt::Write;
+use uefi::{
+    prelude::{cstr16, Boot, SystemTable},
+    table::runtime::VariableAttributes,
+    CStr16,
+};
+
+/// Stores the minimum security version as four little-endian bytes, followed by the strictest
+/// [`RollbackProtection`] that a kernel requested.
+///
+/// The variable is not accessible at runtime, so the OS can't lower the minimum version.
+const NAME: &CStr16 = cstr16!("BootloaderMinVersion");
+
+/// Checks the security version of the kernel against the stored minimum version.
+///
+/// Depending on the strictest of the configured and the stored policy, an older kernel is
+/// booted with a warning or refused with an error screen. A newer kernel raises the stored
+/// minimum, unless it is booted from an A/B slot that is still on trial, so that the bootloader
+/// can still fall back to the previous slot.
+pub fn check(st: &mut SystemTable<Boot>, config: &BootloaderConfig, trial: bool) {
+    let (min_version, stored_protection) = read(st);
+    let protection = config.rollback_protection.max(stored_protection);
+    if protection == RollbackProtection::Disabled {
+        return;
+    }
+
+    let version = config.security_version;
+    if version < min_version {
+        if protection == RollbackProtection::Enforce {
+            fail_with_details(
+                BootError::KernelRolledBack,
+                &format_args!("kernel version {version}, minimum version {min_version}"),
+            );
+        }
+        writeln!(
+            st.stdout(),
+            "Warning: kernel security version {version} is lower than the minimum version \
+             {min_version}"
+        )
+        .unwrap();
+        return;
+    }
+
+    let raise = version > min_version && !trial;
+    if raise || protection > stored_protection {
+        let min_version = if raise { version } else { min_version };
+        if let Err(err) = write(st, min_version, protection) {
+            writeln!(
+                st.stdout(),
+                "Failed to update the minimum security version: {:?}",
+                err.status()
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Returns `(0, RollbackProtection::Disabled)` if the variable does not exist yet.
+fn read(st: &mut SystemTable<Boot>) -> (u32, RollbackProtection) {
+    let mut buf = [0; 5];
+    match st.runtime_services().get_variable(NAME, &VENDOR, &mut buf) {
+        Ok((&[v0, v1, v2, v3, protection], _)) => {
+            let protection = RollbackProtection::from_u8(protection).unwrap_or_else(|| {
+                writeln!(
+                    st.stdout(),
+                    "Unknown stored rollback protection {protection}"
+                )
+                .unwrap();
+                RollbackProtection::Enforce
+            });
+            (u32::from_le_bytes([v0, v1, v2, v3]), protection)
+        }
+        _ => (0, RollbackProtection::Disabled),
+    }
+}
+
+fn write(st: &SystemTable<Boot>, min_version: u32, protection: RollbackProtection) -> uefi::Result {
+    let [v0, v1, v2, v3] = min_version.to_le_bytes();
+    st.runtime_services().set_variable(
+        NAME,
+        &VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS,
+        &[v0, v1, v2, v3, protection as u8],
+    )
+}
@@ -0,0 +1,211 @@
+use crate::RacyCell;
+use bootloader_api::info::{UefiRuntime, UefiRuntimeRegion, UefiRuntimeRegions};
+use bootloader_x86_64_common::{
+    power::{self, Action},
+    uefi_runtime,
+};
+use core::{
+    ffi::c_void,
+    mem::size_of,
+    ptr, slice,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use uefi::{
+    guid,
+    prelude::{Boot, Status},
+    table::{
+        boot::{MemoryAttribute, MemoryDescriptor, MemoryType},
+        runtime::{ResetType, RuntimeServices},
+        Runtime, SystemTable,
+    },
+    Guid,
+};
+
+/// The runtime services, which stay valid after exiting the boot services as long as the
+/// bootloader's identity mapping is active.
+static RUNTIME_SERVICES: AtomicPtr<RuntimeServices> = AtomicPtr::new(ptr::null_mut());
+
+/// The system table address and the runtime descriptors of the memory map, which are passed to
+/// `SetVirtualAddressMap`.
+static VIRTUAL_ADDRESS_MAP: RacyCell<Option<(u64, RuntimeDescriptors)>> = RacyCell::new(None);
+
+struct RuntimeDescriptors {
+    descriptors: [MemoryDescriptor; UefiRuntimeRegions::MAX_REGIONS],
+    len: usize,
+}
+
+const MEMORY_ATTRIBUTES_TABLE_GUID: Guid = guid!("dcfa911d-26eb-469f-a220-38b7dc461220");
+
+/// The header of the `EFI_MEMORY_ATTRIBUTES_TABLE`, which is followed by the memory
+/// descriptors.
+#[repr(C)]
+struct MemoryAttributesTable {
+    version: u32,
+    number_of_entries: u32,
+    descriptor_size: u32,
+    reserved: u32,
+}
+
+/// Makes the error screen and the boot menu reboot and power off the system through the
+/// `ResetSystem` runtime service.
+pub fn register_reset(st: &SystemTable<Boot>) {
+    let runtime_services = st.runtime_services() as *const RuntimeServices;
+    RUNTIME_SERVICES.store(runtime_services.cast_mut(), Ordering::Relaxed);
+    power::set_firmware_reset(reset);
+}
+
+fn reset(action: Action) -> ! {
+    let runtime_services = unsafe { &*RUNTIME_SERVICES.load(Ordering::Relaxed) };
+    let reset_type = match action {
+        Action::Reboot => ResetType::Cold,
+        Action::PowerOff => ResetType::Shutdown,
+    };
+    runtime_services.reset(reset_type, Status::SUCCESS, None)
+}
+
+/// Collects the memory regions of the runtime services from the memory map and the
+/// `EFI_MEMORY_ATTRIBUTES_TABLE`.
+///
+/// Without a memory attributes table, runtime services code regions must stay writable
+/// because they also contain the data sections of the runtime drivers. The table splits these
+/// regions into read-only code and non-executable data, so regions of the memory map that
+/// overlap a table entry are replaced by the table entries.
+pub fn collect<'a>(
+    system_table: &SystemTable<Runtime>,
+    memory_map: impl Iterator<Item = &'a MemoryDescriptor>,
+) -> UefiRuntime {
+    let attributes_table = system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == MEMORY_ATTRIBUTES_TABLE_GUID)
+        .and_then(|entry| unsafe { memory_attributes(entry.address.cast()) });
+
+    let mut regions = UefiRuntimeRegions::new();
+    let mut push = |region| {
+        if regions.push(region).is_err() {
+            log::warn!("Too many UEFI runtime regions, ignoring {region:x?}");
+        }
+    };
+    let mut runtime_descriptors = RuntimeDescriptors {
+        descriptors: [MemoryDescriptor::default(); UefiRuntimeRegions::MAX_REGIONS],
+        len: 0,
+    };
+    let mut complete = true;
+    for descriptor in memory_map.filter(|d| d.att.contains(MemoryAttribute::RUNTIME)) {
+        // `SetVirtualAddressMap` expects the descriptors of the memory map, not the ones of
+        // the memory attributes table
+        if let Some(slot) = runtime_descriptors
+            .descriptors
+            .get_mut(runtime_descriptors.len)
+        {
+            *slot = *descriptor;
+            runtime_descriptors.len += 1;
+        } else {
+            complete = false;
+        }
+        let overlaps_table_entry = attributes_table.map_or(false, |entries| {
+            descriptors(entries).any(|entry| overlaps(&entry, descriptor))
+        });
+        if overlaps_table_entry {
+            continue;
+        }
+        push(UefiRuntimeRegion {
+            phys_start: descriptor.phys_start,
+            page_count: descriptor.page_count,
+            memory_type: descriptor.ty.0,
+            attribute: descriptor.att.bits(),
+            writable: true,
+            executable: descriptor.ty == MemoryType::RUNTIME_SERVICES_CODE,
+        });
+    }
+    if let Some(entries) = attributes_table {
+        for entry in descriptors(entries) {
+            push(UefiRuntimeRegion {
+                phys_start: entry.phys_start,
+                page_count: entry.page_count,
+                memory_type: entry.ty.0,
+                attribute: entry.att.bits(),
+                writable: !entry.att.contains(MemoryAttribute::READ_ONLY),
+                executable: !entry.att.contains(MemoryAttribute::EXECUTE_PROTECT),
+            });
+        }
+    }
+
+    let system_table_addr = system_table.get_current_system_table_addr();
+    if complete {
+        unsafe {
+            *VIRTUAL_ADDRESS_MAP.get() = Some((system_table_addr, runtime_descriptors));
+        }
+        uefi_runtime::set_firmware_function(set_virtual_address_map);
+    } else {
+        log::warn!("Too many UEFI runtime regions to call SetVirtualAddressMap");
+    }
+
+    UefiRuntime {
+        system_table: system_table_addr,
+        memory_attributes_table: attributes_table.is_some(),
+        virtual_offset: None.into(),
+        virtual_system_table: None.into(),
+        regions,
+    }
+}
+
+/// Calls `SetVirtualAddressMap` with the runtime regions at the given offset from their
+/// physical addresses and returns the new address of the system table.
+///
+/// The firmware places the system table in a runtime services data region, so it moves by the
+/// same offset.
+fn set_virtual_address_map(offset: u64) -> Option<u64> {
+    let (system_table_addr, runtime_descriptors) =
+        unsafe { (*VIRTUAL_ADDRESS_MAP.get()).as_mut()? };
+    let descriptors = &mut runtime_descriptors.descriptors[..runtime_descriptors.len];
+    for descriptor in descriptors.iter_mut() {
+        descriptor.virt_start = descriptor.phys_start + offset;
+    }
+    let system_table =
+        unsafe { SystemTable::<Runtime>::from_ptr(*system_table_addr as *mut c_void)? };
+    let virtual_system_table = *system_table_addr + offset;
+    match unsafe { system_table.set_virtual_address_map(descriptors, virtual_system_table) } {
+        Ok(_) => Some(virtual_system_table),
+        Err(err) => {
+            log::warn!("SetVirtualAddressMap failed: {:?}", err.status());
+            None
+        }
+    }
+}
+
+/// Returns the descriptors of the memory attributes table and their size.
+///
+/// ## Safety
+///
+/// The given pointer must point to a valid `EFI_MEMORY_ATTRIBUTES_TABLE`.
+unsafe fn memory_attributes(table: *const MemoryAttributesTable) -> Option<(&'static [u8], usize)> {
+    let header = unsafe { ptr::read_unaligned(table) };
+    let descriptor_size = header.descriptor_size as usize;
+    if header.version < 1 || descriptor_size < size_of::<MemoryDescriptor>() {
+        log::warn!(
+            "Ignoring unsupported memory attributes table (version {})",
+            header.version
+        );
+        return None;
+    }
+    let start = unsafe { table.add(1) }.cast::<u8>();
+    let len = header.number_of_entries as usize * descriptor_size;
+    Some((
+        unsafe { slice::from_raw_parts(start, len) },
+        descriptor_size,
+    ))
+}
+
+fn descriptors(
+    (entries, descriptor_size): (&'static [u8], usize),
+) -> impl Iterator<Item = MemoryDescriptor> {
+    entries
+        .chunks_exact(descriptor_size)
+        .map(|entry| unsafe { ptr::read_unaligned(entry.as_ptr().cast()) })
+}
+
+fn overlaps(a: &MemoryDescriptor, b: &MemoryDescriptor) -> bool {
+    let end = |d: &MemoryDescriptor| d.phys_start + d.page_count * 4096;
+    a.phys_start < end(b) && b.phys_start < end(a)
+}
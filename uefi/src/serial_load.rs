@@ -0,0 +1,79 @@
+use crate::{fail, watchdog};
+use bootloader_x86_64_common::{
+    error::BootError,
+    serial_load::{self, SerialLink},
+};
+use core::slice;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::console::serial::{Parity, Serial, StopBits},
+    table::boot::{AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams},
+};
+
+/// The read timeout of the serial device in microseconds.
+const TIMEOUT_US: u32 = 1_000_000;
+
+/// Receives the kernel over the first serial I/O device of the firmware.
+///
+/// Besides UARTs, firmware commonly exposes USB serial adapters and USB CDC devices through
+/// the serial I/O protocol. The device is opened exclusively, which detaches the firmware
+/// console from it while the kernel is received.
+///
+/// Returns `None` if there is no usable serial I/O device.
+pub fn receive_kernel(image: Handle, st: &SystemTable<Boot>) -> Option<&'static mut [u8]> {
+    let handle = st
+        .boot_services()
+        .get_handle_for_protocol::<Serial>()
+        .ok()?;
+    let mut serial = unsafe {
+        st.boot_services()
+            .open_protocol::<Serial>(
+                OpenProtocolParams {
+                    handle,
+                    agent: image,
+                    controller: None,
+                },
+                OpenProtocolAttributes::Exclusive,
+            )
+            .ok()?
+    };
+
+    let mut mode = *serial.io_mode();
+    mode.baud_rate = 115_200;
+    mode.timeout = TIMEOUT_US;
+    mode.data_bits = 8;
+    mode.parity = Parity::None;
+    mode.stop_bits = StopBits::One;
+    serial.set_attributes(&mode).ok()?;
+
+    // the host might not push a kernel for a long time
+    watchdog::disable(st);
+    let kernel = serial_load::receive_kernel(&mut UefiSerial(&mut serial), |len| {
+        let ptr = st
+            .boot_services()
+            .allocate_pages(
+                AllocateType::AnyPages,
+                MemoryType::LOADER_DATA,
+                ((len - 1) / 4096) + 1,
+            )
+            .unwrap_or_else(|_| fail(BootError::OutOfMemory("the serial kernel")))
+            as *mut u8;
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    });
+    watchdog::restart(st);
+    Some(kernel)
+}
+
+struct UefiSerial<'a, 'boot>(&'a mut Serial<'boot>);
+
+impl SerialLink for UefiSerial<'_, '_> {
+    fn read(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        self.0.read(&mut byte).ok().map(|()| byte[0])
+    }
+
+    fn write(&mut self, byte: u8) {
+        // a lost byte is handled like a transmission error by the protocol
+        let _ = self.0.write(&[byte]);
+    }
+}
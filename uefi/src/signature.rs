@@ -0,0 +1,67 @@
+use crate::{boot_counter::VENDOR, stub};
+use bootloader_x86_64_common::signature::{self, KeyRole};
+use core::fmt::Write;
+use uefi::{
+    prelude::{cstr16, Boot, Handle, SystemTable},
+    table::runtime::VariableAttributes,
+    CStr16,
+};
+
+/// Enables developer mode if it contains a non-zero byte and is not accessible at runtime.
+const DEV_MODE: &CStr16 = cstr16!("BootloaderDevMode");
+
+/// Checks the signature of the kernel against the trusted keys that are embedded into the
+/// bootloader executable.
+///
+/// Kernels are accepted without a signature if the bootloader has no trusted keys. Kernels
+/// signed with a developer key are only accepted in developer mode. Returns the reason if the
+/// kernel is rejected.
+pub fn verify(
+    kernel: &[u8],
+    image: Handle,
+    st: &mut SystemTable<Boot>,
+) -> Result<(), &'static str> {
+    let Some(keys) = stub::load_file_from_image("trusted-keys", image, st) else {
+        return Ok(());
+    };
+    match signature::verify(kernel, keys)? {
+        KeyRole::Production => Ok(()),
+        KeyRole::Developer if dev_mode(st) => {
+            writeln!(st.stdout(), "Booting kernel signed with a developer key").unwrap();
+            Ok(())
+        }
+        KeyRole::Developer => {
+            Err("kernel is signed with a developer key, but developer mode is off")
+        }
+    }
+}
+
+/// Returns whether trusted keys are embedded into the bootloader executable, so that only
+/// signed kernels are booted.
+pub fn has_trusted_keys(image: Handle, st: &mut SystemTable<Boot>) -> bool {
+    stub::load_file_from_image("trusted-keys", image, st).is_some()
+}
+
+/// Returns whether developer mode is enabled through the `BootloaderDevMode` variable.
+///
+/// The variable is ignored if it is accessible at runtime, because the OS could set it then.
+fn dev_mode(st: &mut SystemTable<Boot>) -> bool {
+    let mut buf = [0; 1];
+    match st
+        .runtime_services()
+        .get_variable(DEV_MODE, &VENDOR, &mut buf)
+    {
+        Ok((&[enabled], attributes)) if enabled != 0 => {
+            if attributes.contains(VariableAttributes::RUNTIME_ACCESS) {
+                writeln!(
+                    st.stdout(),
+                    "Ignoring `BootloaderDevMode` variable with runtime access"
+                )
+                .unwrap();
+                return false;
+            }
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,91 @@
+use core::slice;
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::loaded_image::LoadedImage,
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+};
+
+/// Names of the PE sections that the disk image builder uses for embedding files.
+///
+/// Keep in sync with `src/uefi/stub.rs` of the `bootloader` crate.
+const EMBEDDED_FILES: [(&str, &[u8; 8]); 7] = [
+    ("kernel-x86_64", b".kernel\0"),
+    ("ramdisk", b".ramdisk"),
+    ("boot-metadata", b".bootmd\0"),
+    ("kernel-recovery-x86_64", b".recover"),
+    ("kernel-recovery-x86_64.metadata", b".recmd\0\0"),
+    ("support-info", b".support"),
+    ("trusted-keys", b".keys\0\0\0"),
+];
+
+/// Looks up a file that was embedded into the bootloader executable as a PE section.
+///
+/// Returns `None` if the bootloader was not built as a stub or the file was not embedded.
+pub fn load_file_from_image(
+    name: &str,
+    image: Handle,
+    st: &SystemTable<Boot>,
+) -> Option<&'static mut [u8]> {
+    let name = name.trim_end_matches('\0');
+    let (_, section_name) = EMBEDDED_FILES
+        .iter()
+        .find(|(file_name, _)| *file_name == name)?;
+
+    let loaded_image = unsafe {
+        st.boot_services().open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+    let (image_base, image_size) = loaded_image.info();
+    let image_base = image_base.cast::<u8>();
+    let image_size = usize::try_from(image_size).ok()?;
+
+    // The firmware loads the PE headers and all sections into a contiguous memory region,
+    // so the section table can be read directly from memory.
+    let headers = unsafe { slice::from_raw_parts(image_base, image_size) };
+    let (virtual_address, virtual_size) = find_section(headers, section_name)?;
+    if virtual_address.checked_add(virtual_size)? > image_size {
+        return None;
+    }
+    Some(unsafe {
+        slice::from_raw_parts_mut(image_base.add(virtual_address).cast_mut(), virtual_size)
+    })
+}
+
+/// Returns the virtual address and size of the PE section with the given name.
+fn find_section(image: &[u8], name: &[u8; 8]) -> Option<(usize, usize)> {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes(
+            image.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            image.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let pe_offset = usize::try_from(read_u32(0x3c)?).ok()?;
+    if image.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff_header = pe_offset + 4;
+    let number_of_sections = usize::from(read_u16(coff_header + 2)?);
+    let optional_header_size = usize::from(read_u16(coff_header + 16)?);
+    let section_table = coff_header + 20 + optional_header_size;
+
+    (0..number_of_sections)
+        .map(|index| section_table + index * 40)
+        .find(|&entry| image.get(entry..entry + 8) == Some(&name[..]))
+        .and_then(|entry| {
+            let virtual_size = usize::try_from(read_u32(entry + 8)?).ok()?;
+            let virtual_address = usize::try_from(read_u32(entry + 12)?).ok()?;
+            Some((virtual_address, virtual_size))
+        })
+}
@@ -0,0 +1,332 @@
+use core::{ffi::c_void, ptr};
+use uefi::{
+    prelude::{Boot, Handle, Status, SystemTable},
+    proto::Protocol,
+    table::boot::{
+        EventType, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType, Tpl,
+    },
+    unsafe_guid, Event,
+};
+
+/// How long to wait for the firmware to finish a TCP operation, in microseconds.
+const TIMEOUT_US: usize = 10_000_000;
+/// The interval in which the network stack is polled while waiting, in microseconds.
+const POLL_INTERVAL_US: usize = 100;
+/// The interval in which the configuration is retried while DHCP runs, in microseconds.
+const CONFIGURE_INTERVAL_US: usize = 100_000;
+
+/// The `EFI_TCP4_SERVICE_BINDING_PROTOCOL`, which creates TCP connection instances.
+#[repr(C)]
+#[unsafe_guid("00720665-67eb-4a99-baf7-d3c33a1c7c11")]
+#[derive(Protocol)]
+struct Tcp4ServiceBinding {
+    create_child: unsafe extern "efiapi" fn(
+        this: &mut Tcp4ServiceBinding,
+        child_handle: &mut Option<Handle>,
+    ) -> Status,
+    destroy_child:
+        unsafe extern "efiapi" fn(this: &mut Tcp4ServiceBinding, child_handle: Handle) -> Status,
+}
+
+/// The `EFI_TCP4_PROTOCOL` interface.
+///
+/// Only the functions for active connections are declared with their signatures.
+#[repr(C)]
+#[unsafe_guid("65530bc7-a359-410f-b010-5aadc7ec2b62")]
+#[derive(Protocol)]
+struct Tcp4 {
+    get_mode_data: usize,
+    configure: unsafe extern "efiapi" fn(this: &mut Tcp4, config: *const ConfigData) -> Status,
+    routes: usize,
+    connect: unsafe extern "efiapi" fn(this: &mut Tcp4, token: *mut CompletionToken) -> Status,
+    accept: usize,
+    transmit: unsafe extern "efiapi" fn(this: &mut Tcp4, token: *mut IoToken) -> Status,
+    receive: unsafe extern "efiapi" fn(this: &mut Tcp4, token: *mut IoToken) -> Status,
+    close: unsafe extern "efiapi" fn(this: &mut Tcp4, token: *mut CloseToken) -> Status,
+    cancel: unsafe extern "efiapi" fn(this: &mut Tcp4, token: *mut CompletionToken) -> Status,
+    poll: unsafe extern "efiapi" fn(this: &mut Tcp4) -> Status,
+}
+
+/// `EFI_TCP4_CONFIG_DATA` with an embedded `EFI_TCP4_ACCESS_POINT`.
+#[repr(C)]
+struct ConfigData {
+    type_of_service: u8,
+    time_to_live: u8,
+    use_default_address: bool,
+    station_address: [u8; 4],
+    subnet_mask: [u8; 4],
+    station_port: u16,
+    remote_address: [u8; 4],
+    remote_port: u16,
+    active_flag: bool,
+    control_option: *const c_void,
+}
+
+/// `EFI_TCP4_COMPLETION_TOKEN`, which is also used as `EFI_TCP4_CONNECTION_TOKEN`.
+#[repr(C)]
+struct CompletionToken {
+    event: Event,
+    status: Status,
+}
+
+/// `EFI_TCP4_IO_TOKEN`, whose packet points to a [`TransmitData`] or a [`ReceiveData`].
+#[repr(C)]
+struct IoToken {
+    completion: CompletionToken,
+    packet: *mut c_void,
+}
+
+#[repr(C)]
+struct CloseToken {
+    completion: CompletionToken,
+    abort_on_close: bool,
+}
+
+/// `EFI_TCP4_TRANSMIT_DATA` with a single fragment.
+#[repr(C)]
+struct TransmitData {
+    push: bool,
+    urgent: bool,
+    data_length: u32,
+    fragment_count: u32,
+    fragment: FragmentData,
+}
+
+/// `EFI_TCP4_RECEIVE_DATA` with a single fragment.
+#[repr(C)]
+struct ReceiveData {
+    urgent: bool,
+    data_length: u32,
+    fragment_count: u32,
+    fragment: FragmentData,
+}
+
+#[repr(C)]
+struct FragmentData {
+    length: u32,
+    buffer: *mut c_void,
+}
+
+/// An active TCP connection through the TCP4 protocol of the firmware.
+pub struct TcpConnection<'a> {
+    st: &'a SystemTable<Boot>,
+    service_binding: ScopedProtocol<'a, Tcp4ServiceBinding>,
+    child: Handle,
+    /// Always `Some` until the connection is dropped.
+    tcp: Option<ScopedProtocol<'a, Tcp4>>,
+    /// The event of all tokens, only one operation is pending at a time.
+    event: Event,
+}
+
+impl<'a> TcpConnection<'a> {
+    /// Connects to the given IPv4 address and port.
+    ///
+    /// The station address is configured by the firmware, usually through DHCP.
+    pub fn connect(
+        image: Handle,
+        st: &'a SystemTable<Boot>,
+        remote_address: [u8; 4],
+        remote_port: u16,
+    ) -> Result<Self, Status> {
+        let boot_services = st.boot_services();
+        let handles = boot_services
+            .locate_handle_buffer(SearchType::from_proto::<Tcp4ServiceBinding>())
+            .map_err(|err| err.status())?;
+        let handle = *handles.handles().first().ok_or(Status::NOT_FOUND)?;
+        let mut service_binding = open::<Tcp4ServiceBinding>(image, st, handle)?;
+
+        let mut child = None;
+        let status = unsafe { (service_binding.create_child)(&mut service_binding, &mut child) };
+        if status.is_error() {
+            return Err(status);
+        }
+        let child = child.ok_or(Status::DEVICE_ERROR)?;
+        let tcp = match open(image, st, child) {
+            Ok(tcp) => tcp,
+            Err(status) => {
+                let _ = unsafe { (service_binding.destroy_child)(&mut service_binding, child) };
+                return Err(status);
+            }
+        };
+        let event = match unsafe {
+            boot_services.create_event(EventType::empty(), Tpl::CALLBACK, None, None)
+        } {
+            Ok(event) => event,
+            Err(err) => {
+                drop(tcp);
+                let _ = unsafe { (service_binding.destroy_child)(&mut service_binding, child) };
+                return Err(err.status());
+            }
+        };
+        let mut connection = Self {
+            st,
+            service_binding,
+            child,
+            tcp: Some(tcp),
+            event,
+        };
+
+        connection.configure(remote_address, remote_port)?;
+        let mut token = connection.completion_token();
+        let status = unsafe { (connection.tcp().connect)(connection.tcp(), &mut token) };
+        connection.wait(status, &mut token)?;
+        Ok(connection)
+    }
+
+    /// Sends all bytes of `data`.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Status> {
+        let mut transmit_data = TransmitData {
+            push: true,
+            urgent: false,
+            data_length: data.len() as u32,
+            fragment_count: 1,
+            fragment: FragmentData {
+                length: data.len() as u32,
+                buffer: data.as_ptr() as *mut c_void,
+            },
+        };
+        let mut token = IoToken {
+            completion: self.completion_token(),
+            packet: ptr::addr_of_mut!(transmit_data).cast(),
+        };
+        let status = unsafe { (self.tcp().transmit)(self.tcp(), &mut token) };
+        self.wait(status, &mut token.completion)
+    }
+
+    /// Fills `buffer` with received bytes.
+    pub fn receive_exact(&mut self, mut buffer: &mut [u8]) -> Result<(), Status> {
+        while !buffer.is_empty() {
+            let mut receive_data = ReceiveData {
+                urgent: false,
+                data_length: buffer.len() as u32,
+                fragment_count: 1,
+                fragment: FragmentData {
+                    length: buffer.len() as u32,
+                    buffer: buffer.as_mut_ptr().cast(),
+                },
+            };
+            let mut token = IoToken {
+                completion: self.completion_token(),
+                packet: ptr::addr_of_mut!(receive_data).cast(),
+            };
+            let status = unsafe { (self.tcp().receive)(self.tcp(), &mut token) };
+            self.wait(status, &mut token.completion)?;
+
+            // the firmware updates the length to the number of received bytes
+            let received = unsafe { ptr::read_volatile(&receive_data.data_length) } as usize;
+            if received == 0 || received > buffer.len() {
+                return Err(Status::PROTOCOL_ERROR);
+            }
+            buffer = &mut buffer[received..];
+        }
+        Ok(())
+    }
+
+    fn configure(&mut self, remote_address: [u8; 4], remote_port: u16) -> Result<(), Status> {
+        let config = ConfigData {
+            type_of_service: 0,
+            time_to_live: 64,
+            use_default_address: true,
+            station_address: [0; 4],
+            subnet_mask: [0; 4],
+            station_port: 0,
+            remote_address,
+            remote_port,
+            active_flag: true,
+            control_option: ptr::null(),
+        };
+        // the firmware starts DHCP on the first configuration and reports `NO_MAPPING` until
+        // an address is assigned
+        let mut waited = 0;
+        loop {
+            let status = unsafe { (self.tcp().configure)(self.tcp(), &config) };
+            match status {
+                Status::SUCCESS => return Ok(()),
+                Status::NO_MAPPING if waited < TIMEOUT_US => {
+                    self.poll();
+                    self.st.boot_services().stall(CONFIGURE_INTERVAL_US);
+                    waited += CONFIGURE_INTERVAL_US;
+                }
+                status => return Err(status),
+            }
+        }
+    }
+
+    fn tcp(&mut self) -> &mut Tcp4 {
+        self.tcp.as_mut().unwrap()
+    }
+
+    fn poll(&mut self) {
+        let _ = unsafe { (self.tcp().poll)(self.tcp()) };
+    }
+
+    fn completion_token(&self) -> CompletionToken {
+        CompletionToken {
+            event: unsafe { self.event.unsafe_clone() },
+            status: Status::NOT_READY,
+        }
+    }
+
+    /// Waits until the operation of `token` completes, polling the network stack meanwhile.
+    ///
+    /// `status` is the return value of the function that started the operation.
+    fn wait(&mut self, status: Status, token: &mut CompletionToken) -> Result<(), Status> {
+        if status.is_error() {
+            return Err(status);
+        }
+        let mut waited = 0;
+        loop {
+            self.poll();
+            let event = unsafe { self.event.unsafe_clone() };
+            if self.st.boot_services().check_event(event) == Ok(true) {
+                break;
+            }
+            if waited >= TIMEOUT_US {
+                let _ = unsafe { (self.tcp().cancel)(self.tcp(), token) };
+                return Err(Status::TIMEOUT);
+            }
+            self.st.boot_services().stall(POLL_INTERVAL_US);
+            waited += POLL_INTERVAL_US;
+        }
+        match unsafe { ptr::read_volatile(&token.status) } {
+            Status::SUCCESS => Ok(()),
+            status => Err(status),
+        }
+    }
+}
+
+impl Drop for TcpConnection<'_> {
+    fn drop(&mut self) {
+        let mut token = CloseToken {
+            completion: self.completion_token(),
+            abort_on_close: true,
+        };
+        let status = unsafe { (self.tcp().close)(self.tcp(), &mut token) };
+        let _ = self.wait(status, &mut token.completion);
+
+        // the protocol must be closed before its handle is destroyed
+        self.tcp = None;
+        let _ =
+            unsafe { (self.service_binding.destroy_child)(&mut self.service_binding, self.child) };
+        let event = unsafe { self.event.unsafe_clone() };
+        let _ = self.st.boot_services().close_event(event);
+    }
+}
+
+fn open<P: Protocol>(
+    image: Handle,
+    st: &SystemTable<Boot>,
+    handle: Handle,
+) -> Result<ScopedProtocol<'_, P>, Status> {
+    unsafe {
+        st.boot_services().open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .map_err(|err| err.status())
+}
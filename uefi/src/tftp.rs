@@ -0,0 +1,239 @@
+use crate::watchdog;
+use uefi::{
+    prelude::{Boot, Status, SystemTable},
+    proto::network::{
+        pxe::{BaseCode, UdpOpFlags},
+        IpAddress,
+    },
+};
+
+/// The well-known port of TFTP servers.
+const SERVER_PORT: u16 = 69;
+/// The block size of servers that don't support the `blksize` option.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// The block size that is requested from the server, see RFC 2348.
+///
+/// A block of this size fits into a single Ethernet frame together with the IP, UDP, and TFTP
+/// headers.
+const BLOCK_SIZE: usize = 1468;
+/// The value of the `blksize` option in read requests, i.e. [`BLOCK_SIZE`] as text.
+const BLOCK_SIZE_OPTION: &[u8] = b"1468";
+/// How often a request or acknowledgement is sent again if the server doesn't answer.
+const MAX_RETRANSMISSIONS: u32 = 5;
+/// The number of blocks after which the watchdog timer is restarted.
+const WATCHDOG_INTERVAL: u16 = 1024;
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+
+/// Reads a file from a TFTP server into `buffer`, which must have the size of the file.
+///
+/// The `Mtftp` function of the firmware transfers a file in a single call. Some firmware
+/// doesn't service the rest of the network stack during such a transfer, so the machine stops
+/// answering ARP and ICMP requests and switches drop it while a big ramdisk is loaded. This
+/// function instead receives every block with a separate UDP read, which polls the network
+/// stack of the firmware while it waits, and restarts the watchdog timer as the transfer
+/// progresses.
+pub fn read_file(
+    base_code: &mut BaseCode,
+    server_ip: &IpAddress,
+    filename: &str,
+    buffer: &mut [u8],
+    st: &SystemTable<Boot>,
+) -> Result<(), Status> {
+    let mut transfer = Transfer {
+        base_code,
+        server_ip: *server_ip,
+        local_port: 0,
+        server_port: None,
+    };
+
+    let mut request = [0; 512];
+    let request_len = read_request(filename, &mut request)?;
+    let request = &request[..request_len];
+    transfer.send_request(request)?;
+
+    let mut packet = [0; 4 + BLOCK_SIZE];
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut block: u16 = 0;
+    let mut offset = 0;
+    let mut retransmissions = 0;
+    loop {
+        let len = match transfer.receive(&mut packet) {
+            Ok(len) => len,
+            Err(Status::TIMEOUT) if retransmissions < MAX_RETRANSMISSIONS => {
+                retransmissions += 1;
+                if transfer.server_port.is_none() {
+                    transfer.send_request(request)?;
+                } else {
+                    transfer.send_ack(block)?;
+                }
+                continue;
+            }
+            Err(status) => return Err(status),
+        };
+        retransmissions = 0;
+        let packet = packet.get(..len).ok_or(Status::PROTOCOL_ERROR)?;
+
+        match read_u16(packet, 0)? {
+            OPCODE_OACK if offset == 0 => {
+                block_size = block_size_option(&packet[2..]).unwrap_or(DEFAULT_BLOCK_SIZE);
+                if block_size > BLOCK_SIZE {
+                    return Err(Status::PROTOCOL_ERROR);
+                }
+                transfer.send_ack(0)?;
+            }
+            OPCODE_DATA => {
+                let number = read_u16(packet, 2)?;
+                if number != block.wrapping_add(1) {
+                    // a retransmitted block, our acknowledgement was probably lost
+                    transfer.send_ack(block)?;
+                    continue;
+                }
+                let data = &packet[4..];
+                if data.len() > block_size {
+                    return Err(Status::PROTOCOL_ERROR);
+                }
+                buffer
+                    .get_mut(offset..offset + data.len())
+                    .ok_or(Status::BUFFER_TOO_SMALL)?
+                    .copy_from_slice(data);
+                offset += data.len();
+                // block numbers wrap around for files with more than 65535 blocks
+                block = number;
+                transfer.send_ack(block)?;
+
+                if block % WATCHDOG_INTERVAL == 0 {
+                    watchdog::restart(st);
+                }
+                if data.len() < block_size {
+                    return if offset == buffer.len() {
+                        Ok(())
+                    } else {
+                        Err(Status::END_OF_FILE)
+                    };
+                }
+            }
+            OPCODE_ERROR => return Err(Status::TFTP_ERROR),
+            _ => return Err(Status::PROTOCOL_ERROR),
+        }
+    }
+}
+
+struct Transfer<'a> {
+    base_code: &'a mut BaseCode,
+    server_ip: IpAddress,
+    /// The UDP port of the client, chosen by the firmware when the request is sent.
+    local_port: u16,
+    /// The UDP port that the server answers from, known after the first answer.
+    server_port: Option<u16>,
+}
+
+impl Transfer<'_> {
+    fn send_request(&mut self, request: &[u8]) -> Result<(), Status> {
+        // let the firmware choose the local port for the first request
+        let flags = if self.local_port == 0 {
+            UdpOpFlags::ANY_SRC_PORT
+        } else {
+            UdpOpFlags::empty()
+        };
+        self.base_code
+            .udp_write(
+                flags,
+                &self.server_ip,
+                SERVER_PORT,
+                None,
+                None,
+                Some(&mut self.local_port),
+                None,
+                request,
+            )
+            .map_err(|err| err.status())
+    }
+
+    fn send_ack(&mut self, block: u16) -> Result<(), Status> {
+        let server_port = self.server_port.ok_or(Status::PROTOCOL_ERROR)?;
+        let [opcode_0, opcode_1] = OPCODE_ACK.to_be_bytes();
+        let [block_0, block_1] = block.to_be_bytes();
+        self.base_code
+            .udp_write(
+                UdpOpFlags::empty(),
+                &self.server_ip,
+                server_port,
+                None,
+                None,
+                Some(&mut self.local_port),
+                None,
+                &[opcode_0, opcode_1, block_0, block_1],
+            )
+            .map_err(|err| err.status())
+    }
+
+    /// Receives the next packet from the server.
+    ///
+    /// The UDP read of the firmware polls the network stack while it waits for the packet and
+    /// returns `Status::TIMEOUT` after a few seconds.
+    fn receive(&mut self, packet: &mut [u8]) -> Result<usize, Status> {
+        // the server answers from a new port, which identifies the transfer
+        let mut flags = UdpOpFlags::ANY_DEST_IP;
+        if self.server_port.is_none() {
+            flags |= UdpOpFlags::ANY_SRC_PORT;
+        }
+        let mut src_ip = self.server_ip;
+        let mut src_port = self.server_port.unwrap_or(0);
+        let mut dest_port = self.local_port;
+        let len = self
+            .base_code
+            .udp_read(
+                flags,
+                None,
+                Some(&mut dest_port),
+                Some(&mut src_ip),
+                Some(&mut src_port),
+                None,
+                packet,
+            )
+            .map_err(|err| err.status())?;
+        self.server_port = Some(src_port);
+        Ok(len)
+    }
+}
+
+/// Writes a read request for `filename` in binary mode that asks for [`BLOCK_SIZE`] blocks.
+fn read_request(filename: &str, request: &mut [u8]) -> Result<usize, Status> {
+    let parts: [&[u8]; 4] = [filename.as_bytes(), b"octet", b"blksize", BLOCK_SIZE_OPTION];
+
+    request[..2].copy_from_slice(&OPCODE_RRQ.to_be_bytes());
+    let mut len = 2;
+    for part in parts {
+        let end = len + part.len();
+        request
+            .get_mut(len..end)
+            .ok_or(Status::INVALID_PARAMETER)?
+            .copy_from_slice(part);
+        *request.get_mut(end).ok_or(Status::INVALID_PARAMETER)? = 0;
+        len = end + 1;
+    }
+    Ok(len)
+}
+
+/// Returns the value of the `blksize` option in the options of an OACK packet.
+fn block_size_option(options: &[u8]) -> Option<usize> {
+    let mut fields = options.split(|&b| b == 0);
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        if name.eq_ignore_ascii_case(b"blksize") {
+            return core::str::from_utf8(value).ok()?.parse().ok();
+        }
+    }
+    None
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16, Status> {
+    packet
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or(Status::PROTOCOL_ERROR)
+}
@@ -0,0 +1,94 @@
+use crate::read_file;
+use core::{ffi::c_void, ops::DerefMut};
+use uefi::{
+    prelude::{Boot, Handle, SystemTable},
+    proto::{
+        device_path::{DevicePath, DevicePathNodeEnum},
+        loaded_image::LoadedImage,
+        media::fs::SimpleFileSystem,
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams, SearchType},
+};
+
+/// Returns the first file system on a USB device that is not the boot device.
+///
+/// Besides USB sticks, this includes the storage that some devboards expose to a host as a
+/// USB mass-storage gadget, if their firmware provides a file system for it. The boot device
+/// is skipped because its files are already available through the regular boot entries.
+pub fn find(image: Handle, st: &SystemTable<Boot>) -> Option<Handle> {
+    let boot_device = unsafe {
+        st.boot_services()
+            .open_protocol::<LoadedImage>(
+                OpenProtocolParams {
+                    handle: image,
+                    agent: image,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+            .ok()?
+            .device()
+    };
+    let handles = st
+        .boot_services()
+        .locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())
+        .ok()?;
+    let handle = handles
+        .handles()
+        .iter()
+        .copied()
+        .find(|&handle| !same_handle(handle, boot_device) && is_usb(image, st, handle))?;
+    Some(handle)
+}
+
+/// Loads the given file from the root directory of the file system with the given handle.
+pub fn load_file(
+    handle: Handle,
+    name: &str,
+    image: Handle,
+    st: &SystemTable<Boot>,
+) -> Option<&'static mut [u8]> {
+    let mut file_system = unsafe {
+        st.boot_services()
+            .open_protocol::<SimpleFileSystem>(
+                OpenProtocolParams {
+                    handle,
+                    agent: image,
+                    controller: None,
+                },
+                OpenProtocolAttributes::Exclusive,
+            )
+            .ok()?
+    };
+    read_file(file_system.deref_mut(), name, st, false)
+}
+
+fn same_handle(a: Handle, b: Handle) -> bool {
+    // `Handle` is a transparent wrapper around the raw handle pointer
+    let raw = |handle: Handle| unsafe { core::mem::transmute::<Handle, *mut c_void>(handle) };
+    raw(a) == raw(b)
+}
+
+fn is_usb(image: Handle, st: &SystemTable<Boot>, handle: Handle) -> bool {
+    let device_path = unsafe {
+        st.boot_services().open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+    let Ok(device_path) = device_path else {
+        return false;
+    };
+    device_path.node_iter().any(|node| {
+        matches!(
+            node.as_enum(),
+            Ok(DevicePathNodeEnum::MessagingUsb(_)
+                | DevicePathNodeEnum::MessagingUsbWwid(_)
+                | DevicePathNodeEnum::MessagingUsbClass(_))
+        )
+    })
+}
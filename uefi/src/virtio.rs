@@ -0,0 +1,89 @@
+use core::ffi::c_void;
+use uefi::{
+    prelude::{Boot, Handle, Status, SystemTable},
+    proto::{device_path::DevicePath, Protocol},
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+    unsafe_guid,
+};
+
+/// PCI vendor ID used by all virtio devices.
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// PCI device IDs of virtio block and SCSI controllers (transitional and modern).
+const VIRTIO_STORAGE_DEVICE_IDS: [u16; 4] = [0x1001, 0x1004, 0x1042, 0x1048];
+
+/// The start of the `EFI_PCI_IO_PROTOCOL` interface.
+///
+/// Only the function pointers up to `Pci.Read` are declared because we never need the
+/// remaining ones.
+#[repr(C)]
+#[unsafe_guid("4cf5b200-68b8-4ca5-9eec-b23e3f50029a")]
+#[derive(Protocol)]
+struct PciIo {
+    poll_mem: usize,
+    poll_io: usize,
+    mem_read: usize,
+    mem_write: usize,
+    io_read: usize,
+    io_write: usize,
+    pci_read: unsafe extern "efiapi" fn(
+        this: &PciIo,
+        width: u32,
+        offset: u32,
+        count: usize,
+        buffer: *mut c_void,
+    ) -> Status,
+}
+
+impl PciIo {
+    /// `EfiPciIoWidthUint16`
+    const WIDTH_U16: u32 = 1;
+
+    fn read_config_u16(&self, offset: u32, buffer: &mut [u16]) -> uefi::Result {
+        unsafe {
+            (self.pci_read)(
+                self,
+                Self::WIDTH_U16,
+                offset,
+                buffer.len(),
+                buffer.as_mut_ptr().cast(),
+            )
+        }
+        .into()
+    }
+}
+
+/// Checks whether the given device path belongs to a virtio block or SCSI controller.
+///
+/// The check looks up the PCI controller that the device path is attached to and compares
+/// its vendor and device IDs.
+pub fn is_virtio_storage(image: Handle, st: &SystemTable<Boot>, device_path: &DevicePath) -> bool {
+    let mut device_path = device_path;
+    let Ok(pci_handle) = st
+        .boot_services()
+        .locate_device_path::<PciIo>(&mut device_path)
+    else {
+        return false;
+    };
+
+    let pci_io = unsafe {
+        st.boot_services().open_protocol::<PciIo>(
+            OpenProtocolParams {
+                handle: pci_handle,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+    let Ok(pci_io) = pci_io else {
+        return false;
+    };
+
+    let mut ids = [0u16; 2];
+    if pci_io.read_config_u16(0, &mut ids).is_err() {
+        return false;
+    }
+    let [vendor_id, device_id] = ids;
+    vendor_id == VIRTIO_VENDOR_ID && VIRTIO_STORAGE_DEVICE_IDS.contains(&device_id)
+}
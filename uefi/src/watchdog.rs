@@ -0,0 +1,36 @@
+use bootloader_api::BootloaderConfig;
+use core::sync::atomic::{AtomicU16, Ordering};
+use uefi::prelude::{Boot, SystemTable};
+
+/// The code that the firmware logs when the watchdog timer expires.
+///
+/// Codes up to `0xffff` are reserved for the firmware.
+const WATCHDOG_CODE: u64 = 0x1_0000;
+
+/// The timeout in seconds, see the `uefi_watchdog_timeout` config option.
+static TIMEOUT: AtomicU16 = AtomicU16::new(BootloaderConfig::new_default().uefi_watchdog_timeout);
+
+/// Applies the timeout of the kernel config and restarts the watchdog timer with it.
+pub fn configure(st: &SystemTable<Boot>, config: &BootloaderConfig) {
+    TIMEOUT.store(config.uefi_watchdog_timeout, Ordering::Relaxed);
+    restart(st);
+}
+
+/// Restarts the countdown of the watchdog timer, e.g. before a file is loaded.
+pub fn restart(st: &SystemTable<Boot>) {
+    set(st, TIMEOUT.load(Ordering::Relaxed));
+}
+
+/// Disables the watchdog timer until the next [`restart`].
+pub fn disable(st: &SystemTable<Boot>) {
+    set(st, 0);
+}
+
+fn set(st: &SystemTable<Boot>, timeout: u16) {
+    if let Err(err) =
+        st.boot_services()
+            .set_watchdog_timer(usize::from(timeout), WATCHDOG_CODE, None)
+    {
+        log::warn!("Failed to set the watchdog timer: {:?}", err.status());
+    }
+}